@@ -0,0 +1,34 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+use std::collections::BTreeSet;
+
+use ::{
+    Entid,
+    TypedValue,
+};
+
+/// In-memory copies of every datom of some attributes, so that reading them doesn't need the store.
+///
+/// A forward cache maps each entity to its values of the attribute; a reverse cache maps each
+/// value to the entities that have it. An attribute's cache is complete: an entity that doesn't
+/// appear in a forward cache has no value for the attribute.
+pub trait CachedAttributes {
+    fn is_attribute_cached_forward(&self, attribute: Entid) -> bool;
+    fn is_attribute_cached_reverse(&self, attribute: Entid) -> bool;
+
+    /// The values of `attribute` on `entity`, or `None` if it has none or the attribute isn't
+    /// cached forward.
+    fn get_values_for_entid(&self, attribute: Entid, entity: Entid) -> Option<&Vec<TypedValue>>;
+
+    /// The entities with `value` for `attribute`, or `None` if there are none or the attribute
+    /// isn't cached in reverse.
+    fn get_entids_for_value(&self, attribute: Entid, value: &TypedValue) -> Option<&BTreeSet<Entid>>;
+}
@@ -0,0 +1,185 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Conversion between EDN values and `TypedValue`s.
+//!
+//! Given the type it expects, `TypedValue::from_edn` coerces a value the way the query
+//! algebrizer coerces a constant argument: an integer can be a ref, or an instant in epoch
+//! milliseconds, and a string can be a UUID. Keywords are never resolved to entids, because
+//! that needs a schema: look the ident up yourself if you want a ref.
+
+use std::error;
+use std::fmt;
+use std::rc::Rc;
+
+use edn;
+
+use ::{
+    DateTime,
+    FromMillis,
+    SQLValueType,
+    TypedValue,
+    Utc,
+    Uuid,
+    ValueType,
+};
+
+/// Why an EDN value couldn't be converted to a `TypedValue`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum EdnConversionError {
+    /// Nil, symbols, plain keywords, and collections aren't the value of any type.
+    NotAValue(edn::Value),
+
+    /// The value can't be of the expected type.
+    TypeMismatch(edn::Value, ValueType),
+}
+
+impl fmt::Display for EdnConversionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &EdnConversionError::NotAValue(ref value) => write!(f, "{} is not a value", value),
+            &EdnConversionError::TypeMismatch(ref value, t) => write!(f, "{} is not a {}", value, t),
+        }
+    }
+}
+
+impl error::Error for EdnConversionError {
+    fn description(&self) -> &str {
+        match self {
+            &EdnConversionError::NotAValue(_) => "EDN value is not a value",
+            &EdnConversionError::TypeMismatch(_, _) => "EDN value is not of the expected type",
+        }
+    }
+}
+
+impl TypedValue {
+    /// The `TypedValue` for `value`. Without an `expected` type, values take their natural
+    /// type: integers are longs, floats doubles, and strings strings. With one, integers and
+    /// strings are coerced as described in the module documentation, and anything else must
+    /// already be of that type.
+    pub fn from_edn(value: &edn::Value, expected: Option<ValueType>) -> Result<TypedValue, EdnConversionError> {
+        let mismatch = |t| EdnConversionError::TypeMismatch(value.clone(), t);
+        let natural = match value {
+            &edn::Value::Boolean(x) => TypedValue::Boolean(x),
+            &edn::Value::Integer(x) => {
+                match expected {
+                    Some(ValueType::Ref) if ValueType::Ref.accommodates_integer(x) => return Ok(TypedValue::Ref(x)),
+                    Some(ValueType::Ref) => return Err(mismatch(ValueType::Ref)),
+                    Some(ValueType::Instant) => {
+                        return DateTime::<Utc>::from_millis(x)
+                            .map(TypedValue::Instant)
+                            .ok_or_else(|| mismatch(ValueType::Instant));
+                    },
+                    _ => TypedValue::Long(x),
+                }
+            },
+            &edn::Value::Instant(x) => TypedValue::Instant(x),
+            &edn::Value::BigInteger(ref x) => TypedValue::BigInteger(x.clone()),
+            &edn::Value::Float(x) => TypedValue::Double(x),
+            &edn::Value::Text(ref x) => {
+                if expected == Some(ValueType::Uuid) {
+                    return Uuid::parse_str(x.as_str())
+                        .map(TypedValue::Uuid)
+                        .map_err(|_| mismatch(ValueType::Uuid));
+                }
+                TypedValue::String(Rc::new(x.clone()))
+            },
+            &edn::Value::Uuid(x) => TypedValue::Uuid(x),
+            &edn::Value::Bytes(ref x) => TypedValue::Bytes(x.clone()),
+            &edn::Value::NamespacedKeyword(ref x) => TypedValue::Keyword(Rc::new(x.clone())),
+            &edn::Value::Nil |
+            &edn::Value::PlainSymbol(_) |
+            &edn::Value::NamespacedSymbol(_) |
+            &edn::Value::Keyword(_) |
+            &edn::Value::Vector(_) |
+            &edn::Value::List(_) |
+            &edn::Value::Set(_) |
+            &edn::Value::Map(_) => return Err(EdnConversionError::NotAValue(value.clone())),
+        };
+
+        match expected {
+            Some(t) if !natural.matches_type(t) => Err(mismatch(t)),
+            _ => Ok(natural),
+        }
+    }
+
+    /// The EDN form of this value, from which `from_edn` recovers it given the value's type.
+    pub fn to_edn(&self) -> edn::Value {
+        self.to_edn_value()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use edn::NamespacedKeyword;
+
+    fn assert_round_trips(value: TypedValue) {
+        let edn = value.to_edn();
+        assert_eq!(TypedValue::from_edn(&edn, Some(value.value_type())), Ok(value));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let instant = DateTime::<Utc>::from_millis(1493411985187).expect("instant");
+        let uuid = Uuid::parse_str("550e8400-e29b-41d4-a716-446655440000").expect("uuid");
+
+        assert_round_trips(TypedValue::Ref(65));
+        assert_round_trips(TypedValue::Boolean(true));
+        assert_round_trips(TypedValue::Long(-12));
+        assert_round_trips(TypedValue::Double(2.5.into()));
+        assert_round_trips(TypedValue::Instant(instant));
+        assert_round_trips(TypedValue::typed_string("hello"));
+        assert_round_trips(TypedValue::typed_ns_keyword("foo", "bar"));
+        assert_round_trips(TypedValue::Uuid(uuid));
+        assert_round_trips(TypedValue::BigInteger("123456789012345678901234567890".parse().expect("bigint")));
+        assert_round_trips(TypedValue::Bytes(vec![1, 2, 3]));
+    }
+
+    #[test]
+    fn test_natural_types() {
+        assert_eq!(TypedValue::from_edn(&edn::Value::Integer(65), None), Ok(TypedValue::Long(65)));
+        assert_eq!(TypedValue::from_edn(&edn::Value::Float(1.0.into()), None), Ok(TypedValue::Double(1.0.into())));
+        assert_eq!(TypedValue::from_edn(&edn::Value::Text("550e8400-e29b-41d4-a716-446655440000".to_string()), None),
+                   Ok(TypedValue::typed_string("550e8400-e29b-41d4-a716-446655440000")));
+
+        assert_eq!(TypedValue::from_edn(&edn::Value::Nil, None),
+                   Err(EdnConversionError::NotAValue(edn::Value::Nil)));
+        assert_eq!(TypedValue::from_edn(&edn::Value::Vector(vec![]), Some(ValueType::Long)),
+                   Err(EdnConversionError::NotAValue(edn::Value::Vector(vec![]))));
+    }
+
+    #[test]
+    fn test_coercion() {
+        // Integers can be instants, in epoch milliseconds…
+        assert_eq!(TypedValue::from_edn(&edn::Value::Integer(1493411985187), Some(ValueType::Instant)),
+                   Ok(TypedValue::Instant(DateTime::<Utc>::from_millis(1493411985187).expect("instant"))));
+
+        // … and strings UUIDs, but only if they parse.
+        let uuid = Uuid::parse_str("550e8400-e29b-41d4-a716-446655440000").expect("uuid");
+        assert_eq!(TypedValue::from_edn(&edn::Value::Text(uuid.hyphenated().to_string()), Some(ValueType::Uuid)),
+                   Ok(TypedValue::Uuid(uuid)));
+        let bad = edn::Value::Text("not a uuid".to_string());
+        assert_eq!(TypedValue::from_edn(&bad, Some(ValueType::Uuid)),
+                   Err(EdnConversionError::TypeMismatch(bad.clone(), ValueType::Uuid)));
+
+        // A ref can't be negative.
+        assert_eq!(TypedValue::from_edn(&edn::Value::Integer(-1), Some(ValueType::Ref)),
+                   Err(EdnConversionError::TypeMismatch(edn::Value::Integer(-1), ValueType::Ref)));
+
+        // Keywords aren't resolved, and nothing else is coerced.
+        let keyword = edn::Value::NamespacedKeyword(NamespacedKeyword::new("foo", "bar"));
+        assert_eq!(TypedValue::from_edn(&keyword, Some(ValueType::Ref)),
+                   Err(EdnConversionError::TypeMismatch(keyword.clone(), ValueType::Ref)));
+        assert_eq!(TypedValue::from_edn(&edn::Value::Integer(1), Some(ValueType::Double)),
+                   Err(EdnConversionError::TypeMismatch(edn::Value::Integer(1), ValueType::Double)));
+    }
+}
@@ -31,14 +31,20 @@ use enum_set::EnumSet;
 
 use self::ordered_float::OrderedFloat;
 use self::edn::{
+    BigInt,
     NamespacedKeyword,
 };
 
 pub use uuid::Uuid;
 
+pub use cache::CachedAttributes;
+
+pub use edn_conversion::EdnConversionError;
+
 pub use edn::{
     DateTime,
     FromMicros,
+    FromMillis,
     ToMicros,
     Utc,
 };
@@ -65,10 +71,31 @@ pub enum ValueType {
     String,
     Keyword,
     Uuid,
+    BigInt,
+    Bytes,
 }
 
 pub type ValueTypeTag = i32;
 
+/// How the values of one `ValueType` convert to another, as when an attribute's `:db/valueType`
+/// is altered. See `ValueType::can_coerce_to`.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialOrd, PartialEq)]
+pub enum CoercionKind {
+    /// The types are the same, so there's nothing to convert.
+    Identity,
+
+    /// Values convert unchanged, like `:db.type/long` 5 to `:db.type/double` 5.0. A long too large
+    /// to be represented exactly as a double is still refused rather than rounded.
+    Lossless,
+
+    /// Some values only convert by changing, like `:db.type/double` 5.5 truncated to
+    /// `:db.type/long` 5.
+    Lossy,
+
+    /// No value converts.
+    Impossible,
+}
+
 impl ValueType {
     pub fn all_enums() -> EnumSet<ValueType> {
         // TODO: lazy_static.
@@ -81,6 +108,8 @@ impl ValueType {
         s.insert(ValueType::String);
         s.insert(ValueType::Keyword);
         s.insert(ValueType::Uuid);
+        s.insert(ValueType::BigInt);
+        s.insert(ValueType::Bytes);
         s
     }
 }
@@ -107,8 +136,38 @@ impl ValueType {
             ValueType::String => values::DB_TYPE_STRING.clone(),
             ValueType::Keyword => values::DB_TYPE_KEYWORD.clone(),
             ValueType::Uuid => values::DB_TYPE_UUID.clone(),
+            ValueType::BigInt => values::DB_TYPE_BIGINT.clone(),
+            ValueType::Bytes => values::DB_TYPE_BYTES.clone(),
         }
     }
+
+    /// Whether values of this type can be converted to `other`. This is the table consulted when
+    /// an attribute's `:db/valueType` is altered: only numeric values convert, and every other
+    /// change of type requires that the attribute has no values at all.
+    pub fn can_coerce_to(self, other: ValueType) -> CoercionKind {
+        match (self, other) {
+            (x, y) if x == y => CoercionKind::Identity,
+            (ValueType::Long, ValueType::Double) => CoercionKind::Lossless,
+            (ValueType::Double, ValueType::Long) => CoercionKind::Lossy,
+            _ => CoercionKind::Impossible,
+        }
+    }
+
+    /// The `:db.type/*` keyword that names this type.
+    pub fn into_keyword(self) -> NamespacedKeyword {
+        NamespacedKeyword::new("db.type", match self {
+            ValueType::Ref => "ref",
+            ValueType::Boolean => "boolean",
+            ValueType::Instant => "instant",
+            ValueType::Long => "long",
+            ValueType::Double => "double",
+            ValueType::String => "string",
+            ValueType::Keyword => "keyword",
+            ValueType::Uuid => "uuid",
+            ValueType::BigInt => "bigint",
+            ValueType::Bytes => "bytes",
+        })
+    }
 }
 
 impl fmt::Display for ValueType {
@@ -122,13 +181,14 @@ impl fmt::Display for ValueType {
             ValueType::String =>  ":db.type/string",
             ValueType::Keyword => ":db.type/keyword",
             ValueType::Uuid =>    ":db.type/uuid",
+            ValueType::BigInt =>  ":db.type/bigint",
+            ValueType::Bytes =>   ":db.type/bytes",
         })
     }
 }
 
 /// Represents a Mentat value in a particular value set.
 // TODO: expand to include :db.type/{instant,url,uuid}.
-// TODO: BigInt?
 #[derive(Clone,Debug,Eq,Hash,Ord,PartialOrd,PartialEq)]
 pub enum TypedValue {
     Ref(Entid),
@@ -140,6 +200,8 @@ pub enum TypedValue {
     String(Rc<String>),
     Keyword(Rc<NamespacedKeyword>),
     Uuid(Uuid),                        // It's only 128 bits, so this should be acceptable to clone.
+    BigInteger(BigInt),
+    Bytes(Vec<u8>),
 }
 
 impl TypedValue {
@@ -165,6 +227,8 @@ impl TypedValue {
             &TypedValue::String(_) => ValueType::String,
             &TypedValue::Keyword(_) => ValueType::Keyword,
             &TypedValue::Uuid(_) => ValueType::Uuid,
+            &TypedValue::BigInteger(_) => ValueType::BigInt,
+            &TypedValue::Bytes(_) => ValueType::Bytes,
         }
     }
 
@@ -185,6 +249,22 @@ impl TypedValue {
     pub fn current_instant() -> TypedValue {
         TypedValue::Instant(Utc::now())
     }
+
+    /// The EDN form of this value. Refs are plain integers.
+    pub fn to_edn_value(&self) -> edn::Value {
+        match self {
+            &TypedValue::Ref(x) => edn::Value::Integer(x),
+            &TypedValue::Boolean(x) => edn::Value::Boolean(x),
+            &TypedValue::Long(x) => edn::Value::Integer(x),
+            &TypedValue::Double(x) => edn::Value::Float(x),
+            &TypedValue::Instant(x) => edn::Value::Instant(x),
+            &TypedValue::String(ref x) => edn::Value::Text(x.as_ref().clone()),
+            &TypedValue::Keyword(ref x) => edn::Value::NamespacedKeyword(x.as_ref().clone()),
+            &TypedValue::Uuid(x) => edn::Value::Uuid(x),
+            &TypedValue::BigInteger(ref x) => edn::Value::BigInteger(x.clone()),
+            &TypedValue::Bytes(ref x) => edn::Value::Bytes(x.clone()),
+        }
+    }
 }
 
 // We don't do From<i64> or From<Entid> 'cos it's ambiguous.
@@ -207,6 +287,18 @@ impl From<Uuid> for TypedValue {
     }
 }
 
+impl From<BigInt> for TypedValue {
+    fn from(value: BigInt) -> TypedValue {
+        TypedValue::BigInteger(value)
+    }
+}
+
+impl From<Vec<u8>> for TypedValue {
+    fn from(value: Vec<u8>) -> TypedValue {
+        TypedValue::Bytes(value)
+    }
+}
+
 impl From<String> for TypedValue {
     fn from(value: String) -> TypedValue {
         TypedValue::String(Rc::new(value))
@@ -231,6 +323,129 @@ impl From<i32> for TypedValue {
     }
 }
 
+impl From<f64> for TypedValue {
+    fn from(value: f64) -> TypedValue {
+        TypedValue::Double(OrderedFloat(value))
+    }
+}
+
+/// A value in the results of a query. Most are single values, but pull expressions produce maps
+/// of attributes to values, and cardinality-many attributes produce vectors of values.
+#[derive(Clone, Debug, Eq, Hash, Ord, PartialOrd, PartialEq)]
+pub enum Binding {
+    Scalar(TypedValue),
+    Vec(Vec<Binding>),
+    Map(StructuredMap),
+}
+
+/// The attributes and values of an entity, keyed by attribute ident. Reversed idents --
+/// `:foo/_bar` -- key the entities that refer to this one.
+#[derive(Clone, Debug, Default, Eq, Hash, Ord, PartialOrd, PartialEq)]
+pub struct StructuredMap(pub BTreeMap<NamespacedKeyword, Binding>);
+
+impl From<TypedValue> for Binding {
+    fn from(value: TypedValue) -> Binding {
+        Binding::Scalar(value)
+    }
+}
+
+impl From<StructuredMap> for Binding {
+    fn from(value: StructuredMap) -> Binding {
+        Binding::Map(value)
+    }
+}
+
+/// Allows results to be compared directly with the values they hold.
+impl PartialEq<TypedValue> for Binding {
+    fn eq(&self, other: &TypedValue) -> bool {
+        match self {
+            &Binding::Scalar(ref v) => v == other,
+            _ => false,
+        }
+    }
+}
+
+impl Binding {
+    /// Returns true if this is a scalar of the given type.
+    pub fn matches_type(&self, t: ValueType) -> bool {
+        self.value_type() == Some(t)
+    }
+
+    /// The type of the value, if this is a `Binding::Scalar`.
+    pub fn value_type(&self) -> Option<ValueType> {
+        self.as_scalar().map(|v| v.value_type())
+    }
+
+    /// Returns the value, if this is a `Binding::Scalar`.
+    pub fn into_scalar(self) -> Option<TypedValue> {
+        match self {
+            Binding::Scalar(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    pub fn as_scalar(&self) -> Option<&TypedValue> {
+        match self {
+            &Binding::Scalar(ref v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// The EDN form of this binding, as a pull expression would write it: maps are keyed by
+    /// attribute keyword, and a ref is written as a map of its `:db/id`, like `{:db/id 65536}`.
+    pub fn to_edn(&self) -> edn::Value {
+        match self {
+            &Binding::Scalar(TypedValue::Ref(e)) => {
+                let mut map = BTreeMap::new();
+                map.insert(values::DB_ID.clone(), edn::Value::Integer(e));
+                edn::Value::Map(map)
+            },
+            &Binding::Scalar(ref v) => v.to_edn_value(),
+            &Binding::Vec(ref vs) => edn::Value::Vector(vs.iter().map(|v| v.to_edn()).collect()),
+            &Binding::Map(ref m) => m.to_edn(),
+        }
+    }
+
+    pub fn into_map(self) -> Option<StructuredMap> {
+        match self {
+            Binding::Map(m) => Some(m),
+            _ => None,
+        }
+    }
+
+    pub fn into_vec(self) -> Option<Vec<Binding>> {
+        match self {
+            Binding::Vec(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
+impl StructuredMap {
+    pub fn get(&self, attribute: &NamespacedKeyword) -> Option<&Binding> {
+        self.0.get(attribute)
+    }
+
+    pub fn insert<B>(&mut self, attribute: NamespacedKeyword, value: B) where B: Into<Binding> {
+        self.0.insert(attribute, value.into());
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// The EDN map of these attributes and values. See `Binding::to_edn`.
+    pub fn to_edn(&self) -> edn::Value {
+        edn::Value::Map(self.0.iter()
+                              .map(|(k, v)| (edn::Value::NamespacedKeyword(k.clone()), v.to_edn()))
+                              .collect())
+    }
+}
+
 // Put this here rather than in `db` simply because it's widely needed.
 pub trait SQLValueType {
     fn value_type_tag(&self) -> i32;
@@ -249,6 +464,9 @@ impl SQLValueType for ValueType {
             ValueType::String =>  10,
             ValueType::Uuid =>    11,
             ValueType::Keyword => 13,
+            // Arbitrary-precision integers don't fit in a SQLite integer, so we store them as text.
+            ValueType::BigInt =>  14,
+            ValueType::Bytes =>   15,
         }
     }
 
@@ -274,6 +492,8 @@ impl SQLValueType for ValueType {
             ValueType::String       => false,
             Keyword                 => false,
             Uuid                    => false,
+            ValueType::BigInt       => false,          // Stored as text.
+            Bytes                   => false,
         }
     }
 }
@@ -358,16 +578,86 @@ impl ValueTypeSet {
         ValueTypeSet(self.0.union(other.0))
     }
 
+    /// Returns a set containing only the types in both this set and `other`.
+    ///
+    /// Sets with no types in common intersect to the empty set:
+    ///
+    /// ```
+    /// use mentat_core::{ValueType, ValueTypeSet};
+    ///
+    /// let longs = ValueTypeSet::of_longs();
+    /// assert_eq!(longs.intersection(&ValueTypeSet::of_keywords()), ValueTypeSet::of_one(ValueType::Ref));
+    /// assert!(longs.intersection(&ValueTypeSet::of_one(ValueType::String)).is_empty());
+    /// ```
     pub fn intersection(&self, other: &ValueTypeSet) -> ValueTypeSet {
         ValueTypeSet(self.0.intersection(other.0))
     }
 
+    /// Returns a set containing the types in this set that aren't in `other`.
+    ///
+    /// ```
+    /// use mentat_core::{ValueType, ValueTypeSet};
+    ///
+    /// assert_eq!(ValueTypeSet::of_longs().difference(&ValueTypeSet::of_keywords()),
+    ///            ValueTypeSet::of_one(ValueType::Long));
+    /// ```
+    pub fn difference(&self, other: &ValueTypeSet) -> ValueTypeSet {
+        ValueTypeSet(self.0.difference(other.0))
+    }
+
+    /// Iterate over the types in this set. The order is always that in which `ValueType` declares
+    /// its variants, regardless of the order in which types were added.
+    ///
+    /// ```
+    /// use mentat_core::{ValueType, ValueTypeSet};
+    ///
+    /// let mut set = ValueTypeSet::none();
+    /// set.insert(ValueType::String);
+    /// set.insert(ValueType::Long);
+    /// set.insert(ValueType::Ref);
+    /// assert_eq!(set.iter().collect::<Vec<ValueType>>(),
+    ///            vec![ValueType::Ref, ValueType::Long, ValueType::String]);
+    /// ```
+    pub fn iter(&self) -> ::enum_set::Iter<ValueType> {
+        self.0.iter()
+    }
+
     /// Return an arbitrary type that's part of this set.
     /// For a set containing a single type, this will be that type.
     pub fn exemplar(&self) -> Option<ValueType> {
         self.0.iter().next()
     }
 
+    /// Return the most specific type in this set, or `None` if it's empty.
+    ///
+    /// From most to least specific, the order is `Ref`, `Instant`, `Uuid`, `Boolean`, `Long`,
+    /// `BigInt`, `Double`, `Keyword`, `String`, `Bytes`. Broadly, a type comes before those that
+    /// could stand in for it: a ref is a long that names an entity, and an instant a long count of
+    /// microseconds; a UUID could be a string, and a keyword a string that names something.
+    ///
+    /// ```
+    /// use mentat_core::{ValueType, ValueTypeSet};
+    ///
+    /// assert_eq!(ValueTypeSet::of_longs().most_specific(), Some(ValueType::Ref));
+    /// assert_eq!(ValueTypeSet::of_numeric_types().most_specific(), Some(ValueType::Long));
+    /// assert_eq!(ValueTypeSet::none().most_specific(), None);
+    /// ```
+    pub fn most_specific(&self) -> Option<ValueType> {
+        const SPECIFICITY: [ValueType; 10] = [
+            ValueType::Ref,
+            ValueType::Instant,
+            ValueType::Uuid,
+            ValueType::Boolean,
+            ValueType::Long,
+            ValueType::BigInt,
+            ValueType::Double,
+            ValueType::Keyword,
+            ValueType::String,
+            ValueType::Bytes,
+        ];
+        SPECIFICITY.iter().cloned().find(|t| self.contains(*t))
+    }
+
     pub fn is_subset(&self, other: &ValueTypeSet) -> bool {
         self.0.is_subset(&other.0)
     }
@@ -468,6 +758,7 @@ pub enum AttributeBitFlags {
     IndexVAET     = 1 << 1,
     IndexFulltext = 1 << 2,
     UniqueValue   = 1 << 3,
+    NoHistory     = 1 << 4,
 }
 
 pub mod attribute {
@@ -524,6 +815,12 @@ pub struct Attribute {
     /// They are used to compose entities from component sub-entities: they are fetched recursively
     /// by pull expressions, and they are automatically recursively deleted where appropriate.
     pub component: bool,
+
+    /// `true` if this attribute doesn't keep history, i.e., it is `:db/noHistory true`.
+    ///
+    /// Values that are retracted or replaced are deleted outright rather than being kept in the
+    /// transaction log.
+    pub no_history: bool,
 }
 
 impl Attribute {
@@ -543,6 +840,9 @@ impl Attribute {
         if self.unique.is_some() {
             flags |= AttributeBitFlags::UniqueValue as u8;
         }
+        if self.no_history {
+            flags |= AttributeBitFlags::NoHistory as u8;
+        }
         flags
     }
 
@@ -574,6 +874,10 @@ impl Attribute {
             attribute_map.insert(values::DB_IS_COMPONENT.clone(), edn::Value::Boolean(true));
         }
 
+        if self.no_history {
+            attribute_map.insert(values::DB_NO_HISTORY.clone(), edn::Value::Boolean(true));
+        }
+
         edn::Value::Map(attribute_map)
     }
 }
@@ -588,6 +892,7 @@ impl Default for Attribute {
             multival: false,
             unique: None,
             component: false,
+            no_history: false,
         }
     }
 }
@@ -686,6 +991,7 @@ mod test {
             unique: None,
             multival: false,
             component: false,
+            no_history: false,
         };
 
         assert!(attr1.flags() & AttributeBitFlags::IndexAVET as u8 != 0);
@@ -700,6 +1006,7 @@ mod test {
             unique: Some(attribute::Unique::Value),
             multival: false,
             component: false,
+            no_history: false,
         };
 
         assert!(attr2.flags() & AttributeBitFlags::IndexAVET as u8 == 0);
@@ -714,6 +1021,7 @@ mod test {
             unique: Some(attribute::Unique::Identity),
             multival: false,
             component: false,
+            no_history: false,
         };
 
         assert!(attr3.flags() & AttributeBitFlags::IndexAVET as u8 == 0);
@@ -722,6 +1030,64 @@ mod test {
         assert!(attr3.flags() & AttributeBitFlags::UniqueValue as u8 != 0);
     }
 
+    #[test]
+    fn test_can_coerce_to() {
+        for from in ValueType::all_enums().iter() {
+            for to in ValueType::all_enums().iter() {
+                let expected = match (from, to) {
+                    _ if from == to => CoercionKind::Identity,
+                    (ValueType::Long, ValueType::Double) => CoercionKind::Lossless,
+                    (ValueType::Double, ValueType::Long) => CoercionKind::Lossy,
+                    _ => CoercionKind::Impossible,
+                };
+                assert_eq!(from.can_coerce_to(to), expected, "{} to {}", from, to);
+            }
+        }
+
+        assert_eq!(ValueType::Long.can_coerce_to(ValueType::Long), CoercionKind::Identity);
+        assert_eq!(ValueType::Long.can_coerce_to(ValueType::Double), CoercionKind::Lossless);
+        assert_eq!(ValueType::Double.can_coerce_to(ValueType::Long), CoercionKind::Lossy);
+        assert_eq!(ValueType::String.can_coerce_to(ValueType::Long), CoercionKind::Impossible);
+        assert_eq!(ValueType::Ref.can_coerce_to(ValueType::Long), CoercionKind::Impossible);
+        assert_eq!(ValueType::Instant.can_coerce_to(ValueType::Long), CoercionKind::Impossible);
+    }
+
+    #[test]
+    fn test_most_specific() {
+        let expected = vec![
+            ValueType::Ref,
+            ValueType::Instant,
+            ValueType::Uuid,
+            ValueType::Boolean,
+            ValueType::Long,
+            ValueType::BigInt,
+            ValueType::Double,
+            ValueType::Keyword,
+            ValueType::String,
+            ValueType::Bytes,
+        ];
+
+        // Removing the most specific type from the set of all types each time yields every type,
+        // once, in order.
+        let mut set = ValueTypeSet::any();
+        let mut order = vec![];
+        while let Some(t) = set.most_specific() {
+            order.push(t);
+            set = set.difference(&ValueTypeSet::of_one(t));
+        }
+        assert_eq!(order, expected);
+
+        // A single type is its own most specific type.
+        for t in ValueType::all_enums().iter() {
+            assert_eq!(ValueTypeSet::of_one(t).most_specific(), Some(t));
+        }
+
+        assert_eq!(ValueTypeSet::of_longs().most_specific(), Some(ValueType::Ref));
+        assert_eq!(ValueTypeSet::of_keywords().most_specific(), Some(ValueType::Ref));
+        assert_eq!(ValueTypeSet::of_numeric_types().most_specific(), Some(ValueType::Long));
+        assert_eq!(ValueTypeSet::none().most_specific(), None);
+    }
+
     #[test]
     fn test_as_edn_value() {
         let mut schema = Schema::default();
@@ -733,6 +1099,7 @@ mod test {
             unique: None,
             multival: false,
             component: false,
+            no_history: false,
         };
         associate_ident(&mut schema, NamespacedKeyword::new("foo", "bar"), 97);
         add_attribute(&mut schema, 97, attr1);
@@ -744,6 +1111,7 @@ mod test {
             unique: Some(attribute::Unique::Value),
             multival: true,
             component: false,
+            no_history: false,
         };
         associate_ident(&mut schema, NamespacedKeyword::new("foo", "bas"), 98);
         add_attribute(&mut schema, 98, attr2);
@@ -755,6 +1123,7 @@ mod test {
             unique: Some(attribute::Unique::Identity),
             multival: false,
             component: true,
+            no_history: false,
         };
 
         associate_ident(&mut schema, NamespacedKeyword::new("foo", "bat"), 99);
@@ -788,3 +1157,5 @@ mod test {
 pub mod intern_set;
 pub mod counter;
 pub mod util;
+mod cache;
+pub mod edn_conversion;
@@ -43,13 +43,17 @@ lazy_static_namespaced_keyword_value!(DB_CARDINALITY, "db", "cardinality");
 lazy_static_namespaced_keyword_value!(DB_CARDINALITY_MANY, "db.cardinality", "many");
 lazy_static_namespaced_keyword_value!(DB_CARDINALITY_ONE, "db.cardinality", "one");
 lazy_static_namespaced_keyword_value!(DB_FULLTEXT, "db", "fulltext");
+lazy_static_namespaced_keyword_value!(DB_ID, "db", "id");
 lazy_static_namespaced_keyword_value!(DB_IDENT, "db", "ident");
 lazy_static_namespaced_keyword_value!(DB_INDEX, "db", "index");
 lazy_static_namespaced_keyword_value!(DB_INSTALL_ATTRIBUTE, "db.install", "attribute");
 lazy_static_namespaced_keyword_value!(DB_IS_COMPONENT, "db", "component");
+lazy_static_namespaced_keyword_value!(DB_NO_HISTORY, "db", "noHistory");
 lazy_static_namespaced_keyword_value!(DB_PART_DB, "db.part", "db");
 lazy_static_namespaced_keyword_value!(DB_RETRACT, "db", "retract");
+lazy_static_namespaced_keyword_value!(DB_TYPE_BIGINT, "db.type", "bigint");
 lazy_static_namespaced_keyword_value!(DB_TYPE_BOOLEAN, "db.type", "boolean");
+lazy_static_namespaced_keyword_value!(DB_TYPE_BYTES, "db.type", "bytes");
 lazy_static_namespaced_keyword_value!(DB_TYPE_DOUBLE, "db.type", "double");
 lazy_static_namespaced_keyword_value!(DB_TYPE_INSTANT, "db.type", "instant");
 lazy_static_namespaced_keyword_value!(DB_TYPE_KEYWORD, "db.type", "keyword");
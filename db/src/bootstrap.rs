@@ -79,10 +79,10 @@ lazy_static! {
         ]
     };
 
-    static ref V1_PARTS: Vec<(symbols::NamespacedKeyword, i64, i64)> = {
-        vec![(ns_keyword!("db.part", "db"), 0, (1 + V1_IDENTS.len()) as i64),
-             (ns_keyword!("db.part", "user"), USER0, USER0),
-             (ns_keyword!("db.part", "tx"), TX0, TX0),
+    static ref V1_PARTS: Vec<(symbols::NamespacedKeyword, i64, i64, i64)> = {
+        vec![(ns_keyword!("db.part", "db"), 0, USER0, (1 + V1_IDENTS.len()) as i64),
+             (ns_keyword!("db.part", "user"), USER0, TX0, USER0),
+             (ns_keyword!("db.part", "tx"), TX0, i64::max_value(), TX0),
         ]
     };
 
@@ -230,7 +230,7 @@ fn symbolic_schema_to_assertions(symbolic_schema: &Value) -> Result<Vec<Value>>
 
 pub fn bootstrap_partition_map() -> PartitionMap {
     V1_PARTS[..].iter()
-        .map(|&(ref part, start, index)| (part.to_string(), Partition::new(start, index)))
+        .map(|&(ref part, start, end, index)| (part.to_string(), Partition::new(start, end, index)))
         .collect()
 }
 
@@ -0,0 +1,181 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
+
+use rusqlite;
+use rusqlite::types::ToSql;
+
+use mentat_core::{
+    CachedAttributes,
+    Entid,
+    TypedValue,
+};
+
+use db::TypedSQLValue;
+use errors::Result;
+use types::TxReport;
+
+/// Which ways an attribute is cached: from entities to values, from values to entities, or both.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CacheDirection {
+    Forward,
+    Reverse,
+    Both,
+}
+
+impl CacheDirection {
+    fn is_forward(&self) -> bool {
+        *self != CacheDirection::Reverse
+    }
+
+    fn is_reverse(&self) -> bool {
+        *self != CacheDirection::Forward
+    }
+}
+
+/// The in-memory caches of a store's attributes. Each cache holds every datom of its attribute,
+/// read from the store when the attribute is added; the owner must call `update` with the report
+/// of each transaction to keep it current.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct AttributeCaches {
+    forward: BTreeMap<Entid, BTreeMap<Entid, Vec<TypedValue>>>,
+    reverse: BTreeMap<Entid, BTreeMap<TypedValue, BTreeSet<Entid>>>,
+}
+
+impl AttributeCaches {
+    pub fn is_empty(&self) -> bool {
+        self.forward.is_empty() && self.reverse.is_empty()
+    }
+
+    /// Every attribute cached in either direction.
+    pub fn attributes(&self) -> BTreeSet<Entid> {
+        self.forward.keys().chain(self.reverse.keys()).cloned().collect()
+    }
+
+    /// Cache `attribute` in `direction`, in addition to any existing caching, reading its datoms
+    /// from `sqlite`.
+    pub fn add(&mut self, sqlite: &rusqlite::Connection, attribute: Entid, direction: CacheDirection) -> Result<()> {
+        let datoms = read_attribute(sqlite, attribute)?;
+        if direction.is_forward() {
+            self.forward.insert(attribute, forward_cache(&datoms));
+        }
+        if direction.is_reverse() {
+            self.reverse.insert(attribute, reverse_cache(&datoms));
+        }
+        Ok(())
+    }
+
+    /// Stop caching `attribute` in `direction`.
+    pub fn remove(&mut self, attribute: Entid, direction: CacheDirection) {
+        if direction.is_forward() {
+            self.forward.remove(&attribute);
+        }
+        if direction.is_reverse() {
+            self.reverse.remove(&attribute);
+        }
+    }
+
+    /// Apply the datoms asserted and retracted by the transaction of `report` to the caches of
+    /// any attributes they touch.
+    pub fn update(&mut self, report: &TxReport) -> Result<()> {
+        if self.is_empty() {
+            return Ok(());
+        }
+
+        for &(e, a, ref v, added) in report.datoms.iter() {
+            if let Some(cache) = self.forward.get_mut(&a) {
+                if added {
+                    let values = cache.entry(e).or_insert(vec![]);
+                    if !values.contains(v) {
+                        values.push(v.clone());
+                    }
+                } else {
+                    let now_empty = match cache.get_mut(&e) {
+                        Some(values) => {
+                            values.retain(|x| x != v);
+                            values.is_empty()
+                        },
+                        None => false,
+                    };
+                    if now_empty {
+                        cache.remove(&e);
+                    }
+                }
+            }
+
+            if let Some(cache) = self.reverse.get_mut(&a) {
+                if added {
+                    cache.entry(v.clone()).or_insert(BTreeSet::new()).insert(e);
+                } else {
+                    let now_empty = match cache.get_mut(v) {
+                        Some(entids) => {
+                            entids.remove(&e);
+                            entids.is_empty()
+                        },
+                        None => false,
+                    };
+                    if now_empty {
+                        cache.remove(&v);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl CachedAttributes for AttributeCaches {
+    fn is_attribute_cached_forward(&self, attribute: Entid) -> bool {
+        self.forward.contains_key(&attribute)
+    }
+
+    fn is_attribute_cached_reverse(&self, attribute: Entid) -> bool {
+        self.reverse.contains_key(&attribute)
+    }
+
+    fn get_values_for_entid(&self, attribute: Entid, entity: Entid) -> Option<&Vec<TypedValue>> {
+        self.forward.get(&attribute).and_then(|cache| cache.get(&entity))
+    }
+
+    fn get_entids_for_value(&self, attribute: Entid, value: &TypedValue) -> Option<&BTreeSet<Entid>> {
+        self.reverse.get(&attribute).and_then(|cache| cache.get(value))
+    }
+}
+
+fn read_attribute(sqlite: &rusqlite::Connection, attribute: Entid) -> Result<Vec<(Entid, TypedValue)>> {
+    // `all_datoms` gives us the text, rather than the rowid, of fulltext values.
+    let mut stmt = sqlite.prepare_cached("SELECT e, v, value_type_tag FROM all_datoms WHERE a = ? ORDER BY e, v")?;
+    let params = [&attribute as &ToSql];
+    let m: Result<Vec<(Entid, TypedValue)>> = stmt.query_and_then(&params[..], |row| -> Result<(Entid, TypedValue)> {
+        Ok((row.get_checked(0)?,
+            TypedValue::from_sql_value_pair(row.get_checked(1)?, row.get_checked(2)?)?))
+    })?.collect();
+    m
+}
+
+fn forward_cache(datoms: &[(Entid, TypedValue)]) -> BTreeMap<Entid, Vec<TypedValue>> {
+    let mut cache: BTreeMap<Entid, Vec<TypedValue>> = BTreeMap::new();
+    for &(e, ref v) in datoms {
+        cache.entry(e).or_insert(vec![]).push(v.clone());
+    }
+    cache
+}
+
+fn reverse_cache(datoms: &[(Entid, TypedValue)]) -> BTreeMap<TypedValue, BTreeSet<Entid>> {
+    let mut cache: BTreeMap<TypedValue, BTreeSet<Entid>> = BTreeMap::new();
+    for &(e, ref v) in datoms {
+        cache.entry(v.clone()).or_insert(BTreeSet::new()).insert(e);
+    }
+    cache
+}
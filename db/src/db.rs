@@ -20,7 +20,9 @@ use std::rc::Rc;
 
 use itertools;
 use itertools::Itertools;
+use regex::Regex;
 use rusqlite;
+use rusqlite::functions::Context;
 use rusqlite::TransactionBehavior;
 use rusqlite::limits::Limit;
 use rusqlite::types::{ToSql, ToSqlOutput};
@@ -29,6 +31,7 @@ use ::{repeat_values, to_namespaced_keyword};
 use bootstrap;
 
 use edn::{
+    BigInt,
     DateTime,
     Utc,
     Uuid,
@@ -77,9 +80,84 @@ pub fn new_connection<T>(uri: T) -> rusqlite::Result<rusqlite::Connection> where
         PRAGMA foreign_keys=ON;
     ")?;
 
+    register_regexp(&conn)?;
+    register_rule_depth_exceeded(&conn)?;
+
     Ok(conn)
 }
 
+/// Give SQLite the `regexp` function behind its `REGEXP` operator: `text REGEXP pattern` is true
+/// if `pattern`, in the syntax of the `regex` crate, matches anywhere in `text`.
+///
+/// Queries use the same pattern for every row, so we keep the last one compiled.
+fn register_regexp(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+    let mut compiled: Option<(String, Regex)> = None;
+    conn.create_scalar_function("regexp", 2, true, move |ctx: &Context| {
+        let pattern: String = ctx.get(0)?;
+        let text = match ctx.get::<rusqlite::types::Value>(1)? {
+            rusqlite::types::Value::Text(text) => text,
+
+            // Type constraints might be checked after us, so this needn't be an error.
+            _ => return Ok(false),
+        };
+
+        let stale = compiled.as_ref().map_or(true, |&(ref p, _)| p != &pattern);
+        if stale {
+            let regex = Regex::new(pattern.as_str())
+                .map_err(|e| rusqlite::Error::UserFunctionError(Box::new(e)))?;
+            compiled = Some((pattern, regex));
+        }
+        Ok(compiled.as_ref().map_or(false, |&(_, ref regex)| regex.is_match(text.as_str())))
+    })
+}
+
+/// Give SQLite the `rule_depth_exceeded` function, which a query calls when a recursive rule would
+/// derive a row further from its anchor than the query allows. It fails, and so the query fails.
+fn register_rule_depth_exceeded(conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+    // Not deterministic: SQLite mustn't evaluate this once, up front, just because its arguments
+    // are constant.
+    conn.create_scalar_function("rule_depth_exceeded", 2, false, |ctx: &Context| -> rusqlite::Result<i64> {
+        let rule: String = ctx.get(0)?;
+        let limit: i64 = ctx.get(1)?;
+        let message = format!("rule {} recursed more than {} times; is there a cycle in the data?", rule, limit);
+        Err(rusqlite::Error::UserFunctionError(message.into()))
+    })
+}
+
+/// An xorshift64* pseudo-random number generator: good enough for spot-checking data, but not for
+/// anything that needs unpredictability.
+pub struct Xorshift(u64);
+
+impl Xorshift {
+    pub fn new(seed: u64) -> Xorshift {
+        // Xorshift gets stuck at zero.
+        Xorshift(if seed == 0 { 0x9e3779b97f4a7c15 } else { seed })
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545f4914f6cdd1d)
+    }
+
+    /// A double in [0, 1).
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Replace SQLite's `random()` on `conn` with an `Xorshift` seeded by `seed`, so that sampling
+/// queries return the same results each time they're run against the same data. SQLite's own
+/// `random()` can't be seeded.
+pub fn seed_random(conn: &rusqlite::Connection, seed: u64) -> Result<()> {
+    let mut generator = Xorshift::new(seed);
+    conn.create_scalar_function("random", 0, false, move |_: &Context| {
+        Ok(generator.next_u64() as i64)
+    })?;
+    Ok(())
+}
+
 /// Version history:
 ///
 /// 1: initial Rust Mentat schema.
@@ -181,7 +259,7 @@ lazy_static! {
         r#"CREATE TABLE schema (e INTEGER NOT NULL, a SMALLINT NOT NULL, v BLOB NOT NULL, value_type_tag SMALLINT NOT NULL)"#,
         r#"CREATE INDEX idx_schema_unique ON schema (e, a, v, value_type_tag)"#,
         // TODO: store entid instead of ident for partition name.
-        r#"CREATE TABLE parts (part TEXT NOT NULL PRIMARY KEY, start INTEGER NOT NULL, idx INTEGER NOT NULL)"#,
+        r#"CREATE TABLE parts (part TEXT NOT NULL PRIMARY KEY, start INTEGER NOT NULL, end_idx INTEGER NOT NULL, idx INTEGER NOT NULL)"#,
         ]
     };
 }
@@ -222,7 +300,7 @@ pub fn create_current_version(conn: &mut rusqlite::Connection) -> Result<DB> {
     // This is necessary: `transact` will only UPDATE parts, not INSERT them if they're missing.
     for (part, partition) in bootstrap_partition_map.iter() {
         // TODO: Convert "keyword" part to SQL using Value conversion.
-        tx.execute("INSERT INTO parts VALUES (?, ?, ?)", &[part, &partition.start, &partition.index])?;
+        tx.execute("INSERT INTO parts VALUES (?, ?, ?, ?)", &[part, &partition.start, &partition.end, &partition.index])?;
     }
 
     // TODO: return to transact_internal to self-manage the encompassing SQLite transaction.
@@ -378,6 +456,14 @@ impl TypedSQLValue for TypedValue {
             (13, rusqlite::types::Value::Text(x)) => {
                 to_namespaced_keyword(&x).map(|k| TypedValue::Keyword(Rc::new(k)))
             },
+            (15, rusqlite::types::Value::Blob(x)) => Ok(TypedValue::Bytes(x)),
+            (14, rusqlite::types::Value::Text(x)) => {
+                match x.parse::<BigInt>() {
+                    Ok(b) => Ok(TypedValue::BigInteger(b)),
+                    Err(_) => bail!(ErrorKind::BadSQLValuePair(rusqlite::types::Value::Text(x),
+                                                               value_type_tag)),
+                }
+            },
             (_, value) => bail!(ErrorKind::BadSQLValuePair(value, value_type_tag)),
         }
     }
@@ -394,7 +480,9 @@ impl TypedSQLValue for TypedValue {
             &Value::Boolean(x) => Some(TypedValue::Boolean(x)),
             &Value::Instant(x) => Some(TypedValue::Instant(x)),
             &Value::Integer(x) => Some(TypedValue::Long(x)),
+            &Value::BigInteger(ref x) => Some(TypedValue::BigInteger(x.clone())),
             &Value::Uuid(x) => Some(TypedValue::Uuid(x)),
+            &Value::Bytes(ref x) => Some(TypedValue::Bytes(x.clone())),
             &Value::Float(ref x) => Some(TypedValue::Double(x.clone())),
             &Value::Text(ref x) => Some(TypedValue::String(Rc::new(x.clone()))),
             &Value::NamespacedKeyword(ref x) => Some(TypedValue::Keyword(Rc::new(x.clone()))),
@@ -414,6 +502,8 @@ impl TypedSQLValue for TypedValue {
             &TypedValue::String(ref x) => (rusqlite::types::ValueRef::Text(x.as_str()).into(), 10),
             &TypedValue::Uuid(ref u) => (rusqlite::types::Value::Blob(u.as_bytes().to_vec()).into(), 11),
             &TypedValue::Keyword(ref x) => (rusqlite::types::ValueRef::Text(&x.to_string()).into(), 13),
+            &TypedValue::BigInteger(ref x) => (rusqlite::types::Value::Text(x.to_string()).into(), 14),
+            &TypedValue::Bytes(ref x) => (rusqlite::types::ValueRef::Blob(x.as_slice()).into(), 15),
         }
     }
 
@@ -428,6 +518,8 @@ impl TypedSQLValue for TypedValue {
             &TypedValue::String(ref x) => (Value::Text(x.as_ref().clone()), ValueType::String),
             &TypedValue::Uuid(ref u) => (Value::Uuid(u.clone()), ValueType::Uuid),
             &TypedValue::Keyword(ref x) => (Value::NamespacedKeyword(x.as_ref().clone()), ValueType::Keyword),
+            &TypedValue::BigInteger(ref x) => (Value::BigInteger(x.clone()), ValueType::BigInt),
+            &TypedValue::Bytes(ref x) => (Value::Bytes(x.clone()), ValueType::Bytes),
         }
     }
 }
@@ -449,9 +541,9 @@ fn read_materialized_view(conn: &rusqlite::Connection, table: &str) -> Result<Ve
 
 /// Read the partition map materialized view from the given SQL store.
 fn read_partition_map(conn: &rusqlite::Connection) -> Result<PartitionMap> {
-    let mut stmt: rusqlite::Statement = conn.prepare("SELECT part, start, idx FROM parts")?;
+    let mut stmt: rusqlite::Statement = conn.prepare("SELECT part, start, end_idx, idx FROM parts")?;
     let m = stmt.query_and_then(&[], |row| -> Result<(String, Partition)> {
-        Ok((row.get_checked(0)?, Partition::new(row.get_checked(1)?, row.get_checked(2)?)))
+        Ok((row.get_checked(0)?, Partition::new(row.get_checked(1)?, row.get_checked(2)?, row.get_checked(3)?)))
     })?.collect();
     m
 }
@@ -515,6 +607,12 @@ pub trait MentatStoring {
     /// are exactly those (a, v) pairs that have an assertion [e a v] in the store.
     fn resolve_avs<'a>(&self, avs: &'a [&'a AVPair]) -> Result<AVMap<'a>>;
 
+    /// Look up the value of the cardinality-one attribute `a` for entity `e`, if it has one.
+    fn resolve_ea(&self, e: Entid, a: Entid) -> Result<Option<TypedValue>>;
+
+    /// Look up every [a v] pair asserted for entity `e`.
+    fn resolve_e(&self, e: Entid) -> Result<Vec<AVPair>>;
+
     /// Begin (or prepare) the underlying storage layer for a new Mentat transaction.
     ///
     /// Use this to create temporary tables, prepare indices, set pragmas, etc, before the initial
@@ -529,7 +627,10 @@ pub trait MentatStoring {
     ///
     /// Use this to finalize temporary tables, complete indices, revert pragmas, etc, after the
     /// final `insert_non_fts_searches` invocation.
-    fn commit_transaction(&self, tx_id: Entid) -> Result<()>;
+    ///
+    /// Returns the [e a typed_value added] datoms the transaction actually changed, retractions
+    /// first.
+    fn commit_transaction(&self, tx_id: Entid) -> Result<Vec<(Entid, Entid, TypedValue, bool)>>;
 
     /// Extract metadata-related [e a typed_value added] datoms committed in the given transaction.
     fn committed_metadata_assertions(&self, tx_id: Entid) -> Result<Vec<(Entid, Entid, TypedValue, bool)>>;
@@ -643,6 +744,68 @@ fn update_datoms(conn: &rusqlite::Connection, tx: Entid) -> Result<()> {
     Ok(())
 }
 
+/// Forget the history of any `:db/noHistory` attributes touched by the new transaction.
+///
+/// Values that are no longer in `datoms` -- because they were retracted or replaced -- are
+/// deleted from the `transactions` table outright, as are the retractions that removed them.  This
+/// must run after `update_datoms`.
+fn delete_no_history(conn: &rusqlite::Connection) -> Result<()> {
+    let s = format!(r#"
+      DELETE FROM transactions
+      WHERE EXISTS (SELECT 1 FROM temp.search_results AS s
+                    WHERE s.e0 = transactions.e AND
+                          s.a0 = transactions.a AND
+                          s.flags0 & {} IS NOT 0) AND
+            ((added IS 0) OR
+             NOT EXISTS (SELECT 1 FROM datoms AS d
+                         WHERE d.e = transactions.e AND
+                               d.a = transactions.a AND
+                               d.value_type_tag = transactions.value_type_tag AND
+                               d.v = transactions.v))"#,
+      AttributeBitFlags::NoHistory as u8);
+
+    let mut stmt = conn.prepare_cached(&s)?;
+    stmt.execute(&[])
+        .map(|_c| ())
+        .chain_err(|| "Could not delete history of :db/noHistory attributes")
+}
+
+/// The [e a v added] datoms of transaction `tx`, retractions first, as the transactor applies them.
+pub fn committed_datoms(conn: &rusqlite::Connection, tx_id: Entid) -> Result<Vec<(Entid, Entid, TypedValue, bool)>> {
+    // The transaction log holds the rowids of fulltext values, which are the only integers with
+    // the string type tag.
+    let mut stmt = conn.prepare_cached(
+        "SELECT t.e, t.a, CASE WHEN f.text IS NULL THEN t.v ELSE f.text END, t.value_type_tag, t.added \
+         FROM transactions AS t \
+         LEFT JOIN fulltext_values AS f \
+         ON t.value_type_tag = 10 AND typeof(t.v) = 'integer' AND f.rowid = t.v \
+         WHERE t.tx = ? \
+         ORDER BY t.added, t.e, t.a")?;
+    let params = [&tx_id as &ToSql];
+    let m: Result<Vec<(Entid, Entid, TypedValue, bool)>> = stmt.query_and_then(&params[..], |row| -> Result<(Entid, Entid, TypedValue, bool)> {
+        Ok((row.get_checked(0)?,
+            row.get_checked(1)?,
+            TypedValue::from_sql_value_pair(row.get_checked(2)?, row.get_checked(3)?)?,
+            row.get_checked(4)?))
+    })?.collect();
+    m
+}
+
+/// The entity that has `value` for the unique attribute `a`, if there is one. This is how the
+/// transactor resolves the lookup ref `(lookup-ref a value)`: with a single search of the AVET
+/// index. A value of some type other than the attribute's never matches.
+pub fn entid_for_lookup_ref(conn: &rusqlite::Connection, schema: &Schema, a: Entid, value: TypedValue) -> Result<Option<Entid>> {
+    let attribute = schema.require_attribute_for_entid(a)?;
+    if attribute.unique.is_none() {
+        bail!(ErrorKind::NonUniqueLookupRefAttribute(a));
+    }
+
+    let av = (a, value);
+    let avs = [&av];
+    let entids = conn.resolve_avs(&avs[..])?;
+    Ok(entids.get(&av).cloned())
+}
+
 impl MentatStoring for rusqlite::Connection {
     fn resolve_avs<'a>(&self, avs: &'a [&'a AVPair]) -> Result<AVMap<'a>> {
         // Start search_id's at some identifiable number.
@@ -710,6 +873,26 @@ impl MentatStoring for rusqlite::Connection {
         Ok(m)
     }
 
+    fn resolve_ea(&self, e: Entid, a: Entid) -> Result<Option<TypedValue>> {
+        // `all_datoms` gives us the text, rather than the rowid, of fulltext values.
+        let mut stmt = self.prepare_cached("SELECT v, value_type_tag FROM all_datoms WHERE e = ? AND a = ? LIMIT 1")?;
+        let params = [&e as &ToSql, &a as &ToSql];
+        let m: Result<Vec<TypedValue>> = stmt.query_and_then(&params[..], |row| -> Result<TypedValue> {
+            TypedValue::from_sql_value_pair(row.get_checked(0)?, row.get_checked(1)?)
+        })?.collect();
+        Ok(m?.into_iter().next())
+    }
+
+    fn resolve_e(&self, e: Entid) -> Result<Vec<AVPair>> {
+        let mut stmt = self.prepare_cached("SELECT a, v, value_type_tag FROM all_datoms WHERE e = ? ORDER BY a, v")?;
+        let params = [&e as &ToSql];
+        let m: Result<Vec<AVPair>> = stmt.query_and_then(&params[..], |row| -> Result<AVPair> {
+            Ok((row.get_checked(0)?,
+                TypedValue::from_sql_value_pair(row.get_checked(1)?, row.get_checked(2)?)?))
+        })?.collect();
+        m
+    }
+
     /// Create empty temporary tables for search parameters and search results.
     fn begin_tx_application(&self) -> Result<()> {
         // We can't do this in one shot, since we can't prepare a batch statement.
@@ -928,11 +1111,14 @@ impl MentatStoring for rusqlite::Connection {
         results.map(|_| ())
     }
 
-    fn commit_transaction(&self, tx_id: Entid) -> Result<()> {
+    fn commit_transaction(&self, tx_id: Entid) -> Result<Vec<(Entid, Entid, TypedValue, bool)>> {
         search(&self)?;
         insert_transaction(&self, tx_id)?;
         update_datoms(&self, tx_id)?;
-        Ok(())
+        // Read the changes before the log forgets those to :db/noHistory attributes.
+        let datoms = committed_datoms(&self, tx_id)?;
+        delete_no_history(&self)?;
+        Ok(datoms)
     }
 
     fn committed_metadata_assertions(&self, tx_id: Entid) -> Result<Vec<(Entid, Entid, TypedValue, bool)>> {
@@ -949,6 +1135,25 @@ impl MentatStoring for rusqlite::Connection {
     }
 }
 
+/// Add the partition `name`, from which the entids in `[start, end)` can be allocated, to both
+/// `partition_map` and the partition map materialized view.
+pub fn add_partition(conn: &rusqlite::Connection, partition_map: &mut PartitionMap, name: &str, start: Entid, end: Entid) -> Result<()> {
+    if partition_map.contains_key(name) {
+        bail!(ErrorKind::BadPartition(format!("partition {} already exists", name)));
+    }
+    if start < 0 || start >= end {
+        bail!(ErrorKind::BadPartition(format!("partition {} has no entids: [{}, {})", name, start, end)));
+    }
+    let partition = Partition::new(start, end, start);
+    if let Some((other, _)) = partition_map.iter().find(|&(_, p)| p.overlaps(&partition)) {
+        bail!(ErrorKind::BadPartition(format!("partition {} would overlap partition {}", name, other)));
+    }
+
+    conn.execute("INSERT INTO parts VALUES (?, ?, ?, ?)", &[&name, &partition.start, &partition.end, &partition.index])?;
+    partition_map.insert(name.to_string(), partition);
+    Ok(())
+}
+
 /// Update the current partition map materialized view.
 // TODO: only update changed partitions.
 pub fn update_partition_map(conn: &rusqlite::Connection, partition_map: &PartitionMap) -> Result<()> {
@@ -977,11 +1182,91 @@ pub fn update_partition_map(conn: &rusqlite::Connection, partition_map: &Partiti
         .chain_err(|| "Could not update partition map")
 }
 
+/// How the existing values of an attribute are converted when its `:db/valueType` is altered.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ValueTypeCoercion {
+    /// Only convert values that survive the conversion unchanged, like `:db.type/long` 5 to
+    /// `:db.type/double` 5.0.
+    Exact,
+
+    /// Also convert values that don't, like `:db.type/double` 5.5 truncated to `:db.type/long` 5.
+    AllowLossy,
+}
+
+impl Default for ValueTypeCoercion {
+    fn default() -> ValueTypeCoercion {
+        ValueTypeCoercion::Exact
+    }
+}
+
+/// Convert `value` to `value_type`, or return `None` if that can't be done with `coercion`.
+///
+/// Only numeric values convert; every other change of value type requires that the attribute has
+/// no values at all. `ValueType::can_coerce_to` describes the same table to consumers.
+fn coerce_value(value: TypedValue, value_type: ValueType, coercion: ValueTypeCoercion) -> Option<TypedValue> {
+    let lossy = coercion == ValueTypeCoercion::AllowLossy;
+    match (value, value_type) {
+        (value, value_type) if value.value_type() == value_type => Some(value),
+        (TypedValue::Long(x), ValueType::Double) => {
+            let d = x as f64;
+            if lossy || (d as i64 == x && d != i64::max_value() as f64) {
+                Some(TypedValue::Double(d.into()))
+            } else {
+                None
+            }
+        },
+        (TypedValue::Double(d), ValueType::Long) => {
+            let d = d.into_inner();
+            // Out of range values, infinities, and NaN have no `i64` equivalent at all.
+            if !(d >= i64::min_value() as f64 && d < i64::max_value() as f64) {
+                None
+            } else if lossy || d.fract() == 0.0 {
+                Some(TypedValue::Long(d.trunc() as i64))
+            } else {
+                None
+            }
+        },
+        _ => None,
+    }
+}
+
+/// Convert every value of `attribute` in the datoms table to `value_type`, failing on the first
+/// value that can't be converted.
+///
+/// The transactions table is left alone: it records the values as they were asserted.
+fn alter_value_type(conn: &rusqlite::Connection, attribute: Entid, value_type: ValueType, coercion: ValueTypeCoercion) -> Result<()> {
+    let mut converted: Vec<(i64, TypedValue)> = vec![];
+    {
+        let mut stmt = conn.prepare("SELECT rowid, e, v, value_type_tag FROM datoms WHERE a = ?")?;
+        let mut rows = stmt.query(&[&attribute as &ToSql])?;
+        while let Some(row) = rows.next() {
+            let row = row?;
+            let rowid: i64 = row.get_checked(0)?;
+            let e: Entid = row.get_checked(1)?;
+            let v: rusqlite::types::Value = row.get_checked(2)?;
+            let value_type_tag: i32 = row.get_checked(3)?;
+            let value = TypedValue::from_sql_value_pair(v, value_type_tag)?;
+            match coerce_value(value.clone(), value_type, coercion) {
+                Some(coerced) => converted.push((rowid, coerced)),
+                None => bail!(ErrorKind::CannotAlterValueType(attribute, value_type, e, value)),
+            }
+        }
+    }
+
+    let mut stmt = conn.prepare("UPDATE datoms SET v = ?, value_type_tag = ? WHERE rowid = ?")?;
+    for (rowid, value) in converted {
+        let (v, value_type_tag) = value.to_sql_value_pair();
+        stmt.execute(&[&v as &ToSql, &value_type_tag, &rowid])?;
+    }
+    Ok(())
+}
+
 /// Update the metadata materialized views based on the given metadata report.
 ///
 /// This updates the "entids", "idents", and "schema" materialized views, copying directly from the
-/// "datoms" and "transactions" table as appropriate.
-pub fn update_metadata(conn: &rusqlite::Connection, _old_schema: &Schema, new_schema: &Schema, metadata_report: &metadata::MetadataReport) -> Result<()>
+/// "datoms" and "transactions" table as appropriate.  Altering an attribute's `:db/valueType`
+/// also converts its existing values, as `coercion` allows.
+pub fn update_metadata(conn: &rusqlite::Connection, _old_schema: &Schema, new_schema: &Schema, metadata_report: &metadata::MetadataReport, coercion: ValueTypeCoercion) -> Result<()>
 {
     use metadata::AttributeAlteration::*;
 
@@ -1003,18 +1288,35 @@ pub fn update_metadata(conn: &rusqlite::Connection, _old_schema: &Schema, new_sc
         stmt.execute(&[&entid as &ToSql])?;
     }
 
+    // An attribute can only be retracted once no datom uses it, which includes any datoms retracted
+    // in the same transaction, and once every one of its characteristics is gone: otherwise a later
+    // install of the same entid would find stale characteristics in the store.
+    let characteristics_sql = format!("SELECT COUNT(*) FROM datoms WHERE e = ? AND a IN {}", entids::SCHEMA_SQL_LIST.as_str());
+    let mut retract_stmt = conn.prepare(format!("DELETE FROM schema WHERE e = ? AND a IN {}", entids::SCHEMA_SQL_LIST.as_str()).as_str())?;
+    for &entid in &metadata_report.attributes_retracted {
+        let live: i64 = conn.query_row("SELECT COUNT(*) FROM datoms WHERE a = ?", &[&entid as &ToSql], |row| row.get(0))?;
+        if live > 0 {
+            bail!(ErrorKind::CannotRetractAttributeInUse(entid, live));
+        }
+        let characteristics: i64 = conn.query_row(characteristics_sql.as_str(), &[&entid as &ToSql], |row| row.get(0))?;
+        if characteristics > 0 {
+            bail!(ErrorKind::BadSchemaAssertion(format!("Retracting schema attribute {} requires retracting all of its characteristics", entid)));
+        }
+        retract_stmt.execute(&[&entid as &ToSql])?;
+    }
+
     let mut delete_stmt = conn.prepare(format!("DELETE FROM schema WHERE e = ? AND a IN {}", entids::SCHEMA_SQL_LIST.as_str()).as_str())?;
     let mut insert_stmt = conn.prepare(format!("INSERT INTO schema SELECT e, a, v, value_type_tag FROM datoms WHERE e = ? AND a IN {}", entids::SCHEMA_SQL_LIST.as_str()).as_str())?;
     let mut index_stmt = conn.prepare("UPDATE datoms SET index_avet = ? WHERE a = ?")?;
     let mut unique_value_stmt = conn.prepare("UPDATE datoms SET unique_value = ? WHERE a = ?")?;
     let mut cardinality_stmt = conn.prepare(r#"
-SELECT EXISTS
-    (SELECT 1
-        FROM datoms AS left, datoms AS right
-        WHERE left.a = ? AND
-        left.a = right.a AND
-        left.e = right.e AND
-        left.v <> right.v)"#)?;
+SELECT left.e
+    FROM datoms AS left, datoms AS right
+    WHERE left.a = ? AND
+    left.a = right.a AND
+    left.e = right.e AND
+    left.v <> right.v
+    LIMIT 1"#)?;
 
     for (&entid, alterations) in &metadata_report.attributes_altered {
         delete_stmt.execute(&[&entid as &ToSql])?;
@@ -1025,6 +1327,8 @@ SELECT EXISTS
         for alteration in alterations {
             match alteration {
                 &Index => {
+                    // `idx_datoms_avet` only includes datoms with `index_avet` set, so this adds
+                    // the attribute's existing datoms to the AVET index, or removes them from it.
                     // This should always succeed.
                     index_stmt.execute(&[&attribute.index, &entid as &ToSql])?;
                 },
@@ -1041,20 +1345,23 @@ SELECT EXISTS
                 },
                 &Cardinality => {
                     // We can always go from :db.cardinality/one to :db.cardinality many.  It's
-                    // :db.cardinality/many to :db.cardinality/one that can fail.
-                    //
-                    // TODO: improve the failure message.  Perhaps try to mimic what Datomic says in
-                    // this case?
+                    // :db.cardinality/many to :db.cardinality/one that can fail: only if no entity
+                    // has more than one value.  The datoms themselves don't record cardinality, so
+                    // there's nothing else to change on disk.
                     if !attribute.multival {
                         let mut rows = cardinality_stmt.query(&[&entid as &ToSql])?;
-                        if rows.next().is_some() {
-                            bail!(ErrorKind::NotYetImplemented(format!("Cannot alter schema attribute {} to be :db.cardinality/one", entid)));
+                        if let Some(row) = rows.next() {
+                            let e: Entid = row?.get_checked(0)?;
+                            bail!(ErrorKind::CannotAlterCardinalityToOne(entid, e));
                         }
                     }
                 },
                 &NoHistory | &IsComponent => {
                     // There's no on disk change required for either of these.
                 },
+                &ValueType => {
+                    alter_value_type(conn, entid, attribute.value_type, coercion)?;
+                },
             }
         }
     }
@@ -1063,27 +1370,30 @@ SELECT EXISTS
 }
 
 pub trait PartitionMapping {
-    fn allocate_entid<S: ?Sized + Ord + Display>(&mut self, partition: &S) -> i64 where String: Borrow<S>;
-    fn allocate_entids<S: ?Sized + Ord + Display>(&mut self, partition: &S, n: usize) -> Range<i64> where String: Borrow<S>;
+    fn allocate_entid<S: ?Sized + Ord + Display>(&mut self, partition: &S) -> Result<i64> where String: Borrow<S>;
+    fn allocate_entids<S: ?Sized + Ord + Display>(&mut self, partition: &S, n: usize) -> Result<Range<i64>> where String: Borrow<S>;
     fn contains_entid(&self, entid: Entid) -> bool;
 }
 
 impl PartitionMapping for PartitionMap {
     /// Allocate a single fresh entid in the given `partition`.
-    fn allocate_entid<S: ?Sized + Ord + Display>(&mut self, partition: &S) -> i64 where String: Borrow<S> {
-        self.allocate_entids(partition, 1).start
+    fn allocate_entid<S: ?Sized + Ord + Display>(&mut self, partition: &S) -> Result<i64> where String: Borrow<S> {
+        Ok(self.allocate_entids(partition, 1)?.start)
     }
 
-    /// Allocate `n` fresh entids in the given `partition`.
-    fn allocate_entids<S: ?Sized + Ord + Display>(&mut self, partition: &S, n: usize) -> Range<i64> where String: Borrow<S> {
+    /// Allocate `n` fresh entids in the given `partition`, failing if it doesn't have that many
+    /// left.
+    fn allocate_entids<S: ?Sized + Ord + Display>(&mut self, partition: &S, n: usize) -> Result<Range<i64>> where String: Borrow<S> {
         match self.get_mut(partition) {
-            Some(partition) => {
-                let idx = partition.index;
-                partition.index += n as i64;
-                idx..partition.index
+            Some(p) => {
+                if (n as i64) > p.remaining() {
+                    bail!(ErrorKind::PartitionExhausted(partition.to_string(), n, p.remaining()));
+                }
+                let idx = p.index;
+                p.index += n as i64;
+                Ok(idx..p.index)
             },
-            // This is a programming error.
-            None => panic!("Cannot allocate entid from unknown partition: {}", partition),
+            None => bail!(ErrorKind::UnknownPartition(partition.to_string())),
         }
     }
 
@@ -1105,6 +1415,12 @@ mod tests {
     use rusqlite;
     use std::collections::{
         BTreeMap,
+        BTreeSet,
+    };
+    use mentat_tx::entities::Entity;
+    use tx::{
+        attribute_retraction,
+        transact_with_coercion,
     };
     use types::TxReport;
 
@@ -1158,16 +1474,27 @@ mod tests {
         }
 
         fn transact<I>(&mut self, transaction: I) -> Result<TxReport> where I: Borrow<str> {
+            self.transact_with_coercion(transaction, ValueTypeCoercion::default())
+        }
+
+        fn transact_with_coercion<I>(&mut self, transaction: I, coercion: ValueTypeCoercion) -> Result<TxReport> where I: Borrow<str> {
             // Failure to parse the transaction is a coding error, so we unwrap.
             let assertions = edn::parse::value(transaction.borrow()).expect(format!("to be able to parse {} into EDN", transaction.borrow()).as_str());
             let entities: Vec<_> = mentat_tx_parser::Tx::parse(&assertions).expect(format!("to be able to parse {} into entities", assertions).as_str());
+            self.transact_entities_with_coercion(entities, coercion)
+        }
+
+        fn transact_entities(&mut self, entities: Vec<Entity>) -> Result<TxReport> {
+            self.transact_entities_with_coercion(entities, ValueTypeCoercion::default())
+        }
 
+        fn transact_entities_with_coercion(&mut self, entities: Vec<Entity>, coercion: ValueTypeCoercion) -> Result<TxReport> {
             let details = {
                 // The block scopes the borrow of self.sqlite.
                 // We're about to write, so go straight ahead and get an IMMEDIATE transaction.
                 let tx = self.sqlite.transaction_with_behavior(TransactionBehavior::Immediate)?;
                 // Applying the transaction can fail, so we don't unwrap.
-                let details = transact(&tx, self.partition_map.clone(), &self.schema, &self.schema, entities)?;
+                let details = transact_with_coercion(&tx, self.partition_map.clone(), &self.schema, &self.schema, entities, coercion)?;
                 tx.commit()?;
                 details
             };
@@ -1220,7 +1547,7 @@ mod tests {
             // Add a fake partition to allow tests to do things like
             // [:db/add 111 :foo/bar 222]
             {
-                let fake_partition = Partition { start: 100, index: 1000 };
+                let fake_partition = Partition { start: 100, end: 1000, index: 1000 };
                 parts.insert(":db.part/fake".into(), fake_partition);
             }
 
@@ -1433,7 +1760,7 @@ mod tests {
         // Conflicting upserts fail.
         assert_transact!(conn, "[[:db/add \"t1\" :db/ident :name/Ivan]
                                 [:db/add \"t1\" :db/ident :name/Petr]]",
-                         Err("not yet implemented: Conflicting upsert: tempid \'t1\' resolves to more than one entid: 100, 101"));
+                         Err("conflicting upsert: tempid \'t1\' resolves to more than one entid via [a e]: [(1, 100), (1, 101)]"));
 
         // tempids in :db/retract that don't upsert fail.
         assert_transact!(conn, "[[:db/retract \"t1\" :db/ident :name/Anonymous]]",
@@ -1534,6 +1861,86 @@ mod tests {
                                  [:db/add 101 :db/cardinality :db.cardinality/many]]");
     }
 
+    #[test]
+    fn test_db_reinstall() {
+        let mut conn = TestConn::default();
+
+        let install = "[{:db/id \"i\" :db/ident :test/ident :db/valueType :db.type/long :db/cardinality :db.cardinality/many}
+                        {:db/id \"n\" :db/ident :test/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}]";
+        let report = assert_transact!(conn, install);
+        assert_matches!(tempids(&report),
+                        "{\"i\" 65536
+                          \"n\" 65537}");
+        let schema = conn.schema.clone();
+
+        // Re-installing an identical schema upserts each tempid to the installed attribute, and
+        // changes nothing.
+        let report = assert_transact!(conn, install);
+        assert_matches!(tempids(&report),
+                        "{\"i\" 65536
+                          \"n\" 65537}");
+        assert_matches!(conn.last_transaction(),
+                        "[[?tx :db/txInstant ?ms ?tx true]]");
+        assert_eq!(conn.schema, schema);
+
+        // But re-installing with a different value type is rejected...
+        assert_transact!(conn, "[{:db/id \"n\" :db/ident :test/name :db/valueType :db.type/keyword :db/cardinality :db.cardinality/one}]",
+                         Err("conflicting attribute definition: schema attribute 65537 already has :db/valueType String"));
+        assert_eq!(conn.schema, schema);
+
+        // ... even though the value type can be altered by naming the attribute directly.
+        assert_transact!(conn, "[[:db/add :test/name :db/valueType :db.type/keyword]]");
+        assert_eq!(conn.schema.attribute_for_entid(65537).map(|a| a.value_type), Some(ValueType::Keyword));
+    }
+
+    #[test]
+    fn test_db_retract_attribute() {
+        let mut conn = TestConn::default();
+
+        assert_transact!(conn, "[[:db/add 100 :db/ident :test/unused]
+                                 [:db/add 100 :db/valueType :db.type/long]
+                                 [:db/add 100 :db/cardinality :db.cardinality/one]
+                                 [:db/add 100 :db/doc \"Never used.\"]
+                                 [:db/add 101 :db/ident :test/used]
+                                 [:db/add 101 :db/valueType :db.type/string]
+                                 [:db/add 101 :db/cardinality :db.cardinality/many]
+                                 [:db/add 101 :db/index true]]");
+        assert_transact!(conn, "[[:db/add 200 :test/used \"a\"]
+                                 [:db/add 200 :test/used \"b\"]]");
+
+        // An attribute that nothing uses can be retracted.
+        let entities = attribute_retraction(&conn.sqlite, &conn.schema, 100, false).expect("entities");
+        assert!(conn.transact_entities(entities).is_ok());
+        assert_eq!(conn.schema.attribute_for_entid(100), None);
+        assert_eq!(conn.schema.ident_map.get(&to_namespaced_keyword(":test/unused").unwrap()), None);
+        assert_matches!(conn.datoms(),
+                        "[[101 :db/ident :test/used]
+                          [101 :db/valueType :db.type/string]
+                          [101 :db/cardinality :db.cardinality/many]
+                          [101 :db/index true]
+                          [200 :test/used \"a\"]
+                          [200 :test/used \"b\"]]");
+
+        // An attribute in use can't be retracted.
+        let entities = attribute_retraction(&conn.sqlite, &conn.schema, 101, false).expect("entities");
+        assert_eq!(conn.transact_entities(entities).map_err(|e| e.to_string()),
+                   Err("cannot retract schema attribute 101: 2 datoms use it".to_string()));
+        assert!(conn.schema.attribute_for_entid(101).is_some());
+
+        // Nor can an attribute be retracted piecemeal.
+        assert_transact!(conn, "[[:db/retract 101 :db/valueType :db.type/string]
+                                 [:db/retract 200 :test/used \"a\"]
+                                 [:db/retract 200 :test/used \"b\"]]",
+                         Err("bad schema assertion: Retracting schema attribute 101 requires retracting all of its characteristics"));
+
+        // But forcing the retraction retracts the datoms that use the attribute first.
+        let entities = attribute_retraction(&conn.sqlite, &conn.schema, 101, true).expect("entities");
+        assert!(conn.transact_entities(entities).is_ok());
+        assert_eq!(conn.schema.attribute_for_entid(101), None);
+        assert_matches!(conn.datoms(),
+                        "[]");
+    }
+
     #[test]
     fn test_db_alter() {
         let mut conn = TestConn::default();
@@ -1543,11 +1950,7 @@ mod tests {
                                  [:db/add 100 :db/valueType :db.type/keyword]
                                  [:db/add 100 :db/cardinality :db.cardinality/one]]");
 
-        // Trying to alter the :db/valueType will fail.
-        assert_transact!(conn, "[[:db/add 100 :db/valueType :db.type/long]]",
-                         Err("bad schema assertion: Schema alteration for existing attribute with entid 100 is not valid"));
-
-        // But we can alter the cardinality.
+        // We can alter the cardinality.
         assert_transact!(conn, "[[:db/add 100 :db/cardinality :db.cardinality/many]]");
 
         assert_matches!(conn.last_transaction(),
@@ -1575,6 +1978,52 @@ mod tests {
                           [?tx :db/txInstant ?ms ?tx true]]");
     }
 
+    #[test]
+    fn test_db_alter_index() {
+        let mut conn = TestConn::default();
+
+        assert_transact!(conn, "[[:db/add 100 :db/ident :test/name]
+                                 [:db/add 100 :db/valueType :db.type/string]
+                                 [:db/add 100 :db/cardinality :db.cardinality/one]]");
+        assert_transact!(conn, "[[:db/add 200 :test/name \"Alice\"]
+                                 [:db/add 201 :test/name \"Bob\"]]");
+
+        // Look up a value by way of the AVET index, and without it.
+        let lookup = |conn: &TestConn, sql: &str| -> Vec<Entid> {
+            let mut stmt = conn.sqlite.prepare(sql).expect("prepared");
+            let rows = stmt.query_map(&[], |row| row.get(0)).expect("queried");
+            rows.map(|e| e.expect("entid")).collect()
+        };
+        let by_index = "SELECT e FROM datoms INDEXED BY idx_datoms_avet WHERE a = 100 AND value_type_tag = 10 AND v = 'Alice' AND index_avet IS NOT 0 ORDER BY e";
+        let by_scan = "SELECT e FROM datoms WHERE a = 100 AND value_type_tag = 10 AND v = 'Alice' ORDER BY e";
+
+        assert_eq!(lookup(&conn, by_index), vec![]);
+        assert_eq!(lookup(&conn, by_scan), vec![200]);
+
+        // Indexing an attribute indexes its existing values.
+        assert_transact!(conn, "[[:db/add 100 :db/index true]]");
+        assert_eq!(conn.schema.attribute_for_entid(100).map(|a| a.index), Some(true));
+        assert_eq!(lookup(&conn, by_index), vec![200]);
+        assert_eq!(lookup(&conn, by_scan), vec![200]);
+
+        // And new ones.
+        assert_transact!(conn, "[[:db/add 202 :test/name \"Alice\"]]");
+        assert_eq!(lookup(&conn, by_index), vec![200, 202]);
+
+        // Retracting `:db/index` drops the attribute from the index, but leaves its values alone.
+        assert_transact!(conn, "[[:db/retract 100 :db/index true]]");
+        assert_eq!(conn.schema.attribute_for_entid(100).map(|a| a.index), Some(false));
+        assert_matches!(conn.datoms(),
+                        "[[100 :db/ident :test/name]
+                          [100 :db/valueType :db.type/string]
+                          [100 :db/cardinality :db.cardinality/one]
+                          [200 :test/name \"Alice\"]
+                          [201 :test/name \"Bob\"]
+                          [202 :test/name \"Alice\"]]");
+        assert_eq!(lookup(&conn, by_index), vec![]);
+        assert_eq!(lookup(&conn, by_scan), vec![200, 202]);
+    }
+
     #[test]
     fn test_db_ident() {
         let mut conn = TestConn::default();
@@ -1661,8 +2110,82 @@ mod tests {
 
         // We can't always go from :db.cardinality/many to :db.cardinality/one.
         assert_transact!(conn, "[[:db/add 100 :db/cardinality :db.cardinality/one]]",
-                         // TODO: give more helpful error details.
-                         Err("not yet implemented: Cannot alter schema attribute 100 to be :db.cardinality/one"));
+                         Err("cannot alter schema attribute 100 to be :db.cardinality/one: entity 200 has more than one value"));
+
+        // But we can once each entity has at most one value.
+        assert_transact!(conn, "[[:db/retract 200 :test/ident 1]
+                                 [:db/add 201 :test/ident 3]]");
+        assert_transact!(conn, "[[:db/add 100 :db/cardinality :db.cardinality/one]]");
+        assert_eq!(conn.schema.attribute_for_entid(100).map(|a| a.multival), Some(false));
+
+        // And the attribute then behaves as :db.cardinality/one.
+        assert_transact!(conn, "[[:db/add 200 :test/ident 4]]");
+        assert_matches!(conn.datoms(),
+                        "[[100 :db/ident :test/ident]
+                          [100 :db/valueType :db.type/long]
+                          [100 :db/cardinality :db.cardinality/one]
+                          [200 :test/ident 4]
+                          [201 :test/ident 3]]");
+    }
+
+    #[test]
+    fn test_db_alter_value_type() {
+        let mut conn = TestConn::default();
+
+        assert_transact!(conn, "[[:db/add 100 :db/ident :test/number]
+                                 [:db/add 100 :db/valueType :db.type/long]
+                                 [:db/add 100 :db/cardinality :db.cardinality/one]
+                                 [:db/add 101 :db/ident :test/name]
+                                 [:db/add 101 :db/valueType :db.type/string]
+                                 [:db/add 101 :db/cardinality :db.cardinality/one]]");
+
+        assert_transact!(conn, "[[:db/add 200 :test/number 1]
+                                 [:db/add 201 :test/number -3]]");
+
+        // Every long is exactly some double.
+        assert_transact!(conn, "[[:db/add 100 :db/valueType :db.type/double]]");
+        assert_eq!(conn.schema.attribute_for_entid(100).map(|a| a.value_type), Some(ValueType::Double));
+        assert_matches!(conn.datoms(),
+                        "[[100 :db/ident :test/number]
+                          [100 :db/valueType :db.type/double]
+                          [100 :db/cardinality :db.cardinality/one]
+                          [101 :db/ident :test/name]
+                          [101 :db/valueType :db.type/string]
+                          [101 :db/cardinality :db.cardinality/one]
+                          [200 :test/number 1.0]
+                          [201 :test/number -3.0]]");
+
+        // Not every double is exactly some long.
+        assert_transact!(conn, "[[:db/add 202 :test/number 2.5]]");
+        assert_transact!(conn, "[[:db/add 100 :db/valueType :db.type/long]]",
+                         Err("cannot alter schema attribute 100 to have :db/valueType Long: entity 202 has value Double(OrderedFloat(2.5))"));
+        assert_eq!(conn.schema.attribute_for_entid(100).map(|a| a.value_type), Some(ValueType::Double));
+
+        // Unless we're willing to truncate.
+        conn.transact_with_coercion("[[:db/add 100 :db/valueType :db.type/long]]", ValueTypeCoercion::AllowLossy)
+            .expect("truncated");
+        assert_eq!(conn.schema.attribute_for_entid(100).map(|a| a.value_type), Some(ValueType::Long));
+        assert_matches!(conn.datoms(),
+                        "[[100 :db/ident :test/number]
+                          [100 :db/valueType :db.type/long]
+                          [100 :db/cardinality :db.cardinality/one]
+                          [101 :db/ident :test/name]
+                          [101 :db/valueType :db.type/string]
+                          [101 :db/cardinality :db.cardinality/one]
+                          [200 :test/number 1]
+                          [201 :test/number -3]
+                          [202 :test/number 2]]");
+
+        // Strings don't convert at all, so the offending datom is reported.
+        assert_transact!(conn, "[[:db/add 200 :test/name \"Ivan\"]]");
+        assert_transact!(conn, "[[:db/add 101 :db/valueType :db.type/long]]",
+                         Err("cannot alter schema attribute 101 to have :db/valueType Long: entity 200 has value String(\"Ivan\")"));
+
+        // But the value type of an attribute without values can be changed freely.
+        assert_transact!(conn, "[[:db/retract 200 :test/name \"Ivan\"]]");
+        assert_transact!(conn, "[[:db/add 101 :db/valueType :db.type/keyword]]");
+        assert_eq!(conn.schema.attribute_for_entid(101).map(|a| a.value_type), Some(ValueType::Keyword));
+        assert_transact!(conn, "[[:db/add 200 :test/name :name/Ivan]]");
     }
 
     #[test]
@@ -1718,6 +2241,11 @@ mod tests {
         assert_transact!(conn,
                          "[[:db/add 222 :db/fulltext true]]",
                          Err("bad schema assertion: Schema alteration for existing attribute with entid 222 is not valid"));
+
+        // Fulltext values are always strings.
+        assert_transact!(conn,
+                         "[[:db/add 111 :db/valueType :db.type/keyword]]",
+                         Err("bad schema assertion: :db/fulltext true without :db/valueType :db.type/string for entid: 111"));
     }
 
     #[test]
@@ -2262,4 +2790,260 @@ mod tests {
                          "[{:test/_dangling 1.23}]",
                          Err("EDN value \'1.23\' is not the expected Mentat value type Ref"));
     }
+
+    #[test]
+    fn test_cas() {
+        let mut conn = TestConn::default();
+
+        assert_transact!(conn, "[[:db/add 100 :db/ident :test/one]
+                                 [:db/add 100 :db/valueType :db.type/long]
+                                 [:db/add 100 :db/cardinality :db.cardinality/one]
+                                 [:db/add 101 :db/ident :test/many]
+                                 [:db/add 101 :db/valueType :db.type/long]
+                                 [:db/add 101 :db/cardinality :db.cardinality/many]]");
+
+        assert_transact!(conn, "[[:db/add 200 :test/one 1]]");
+
+        // The value is what we expect, so we swap it.
+        assert_transact!(conn, "[[:db.fn/cas 200 :test/one 1 2]]");
+        assert_matches!(conn.last_transaction(),
+                        "[[200 :test/one 1 ?tx false]
+                          [200 :test/one 2 ?tx true]
+                          [?tx :db/txInstant ?ms ?tx true]]");
+
+        // The value has moved on, so the whole transaction aborts.
+        assert_transact!(conn, "[[:db/add 201 :test/one 5]
+                                 [:db.fn/cas 200 :test/one 1 3]]",
+                         Err("compare-and-swap failed for [200 100]: expected Some(Long(1)), found Some(Long(2))"));
+        assert_matches!(conn.datoms(),
+                        "[[100 :db/ident :test/one]
+                          [100 :db/valueType :db.type/long]
+                          [100 :db/cardinality :db.cardinality/one]
+                          [101 :db/ident :test/many]
+                          [101 :db/valueType :db.type/long]
+                          [101 :db/cardinality :db.cardinality/many]
+                          [200 :test/one 2]]");
+
+        // nil expects no value at all.
+        assert_transact!(conn, "[[:db.fn/cas 201 :test/one nil 5]]");
+        assert_matches!(conn.last_transaction(),
+                        "[[201 :test/one 5 ?tx true]
+                          [?tx :db/txInstant ?ms ?tx true]]");
+        assert_transact!(conn, "[[:db.fn/cas 201 :test/one nil 6]]",
+                         Err("compare-and-swap failed for [201 100]: expected None, found Some(Long(5))"));
+
+        // Values are checked against the attribute's value type.
+        assert_transact!(conn, "[[:db.fn/cas 200 :test/one \"2\" 3]]",
+                         Err("EDN value \'\"2\"\' is not the expected Mentat value type Long"));
+
+        // There's no single value to compare against for :db.cardinality/many.
+        assert_transact!(conn, "[[:db.fn/cas 200 :test/many nil 1]]",
+                         Err("not yet implemented: Cannot :db.fn/cas attribute 101 that is not :db.cardinality :db.cardinality/one"));
+    }
+
+    #[test]
+    fn test_retract_entity() {
+        let mut conn = TestConn::default();
+
+        assert_transact!(conn, "[[:db/add 100 :db/ident :test/name]
+                                 [:db/add 100 :db/valueType :db.type/string]
+                                 [:db/add 101 :db/ident :test/component]
+                                 [:db/add 101 :db/valueType :db.type/ref]
+                                 [:db/add 101 :db/isComponent true]
+                                 [:db/add 101 :db/cardinality :db.cardinality/many]
+                                 [:db/add 102 :db/ident :test/friend]
+                                 [:db/add 102 :db/valueType :db.type/ref]]");
+
+        // A flat entity.
+        assert_transact!(conn, "[[:db/add 200 :test/name \"flat\"]
+                                 [:db/add 201 :test/name \"untouched\"]]");
+        let report = assert_transact!(conn, "[[:db.fn/retractEntity 200]]");
+        assert_eq!(report.retracted,
+                   vec![(200, 100, TypedValue::String(Rc::new("flat".into())))].into_iter().collect::<BTreeSet<_>>());
+        assert_matches!(conn.last_transaction(),
+                        "[[200 :test/name \"flat\" ?tx false]
+                          [?tx :db/txInstant ?ms ?tx true]]");
+
+        // A component tree two levels deep, with a friend that isn't a component.
+        assert_transact!(conn, "[[:db/add 300 :test/name \"root\"]
+                                 [:db/add 300 :test/component 301]
+                                 [:db/add 300 :test/friend 400]
+                                 [:db/add 301 :test/name \"child\"]
+                                 [:db/add 301 :test/component 302]
+                                 [:db/add 302 :test/name \"grandchild\"]
+                                 [:db/add 400 :test/name \"friend\"]]");
+        let report = assert_transact!(conn, "[[:db.fn/retractEntity 300]]");
+        assert_eq!(report.retracted.len(), 6);
+        assert_matches!(conn.last_transaction(),
+                        "[[300 :test/name \"root\" ?tx false]
+                          [300 :test/component 301 ?tx false]
+                          [300 :test/friend 400 ?tx false]
+                          [301 :test/name \"child\" ?tx false]
+                          [301 :test/component 302 ?tx false]
+                          [302 :test/name \"grandchild\" ?tx false]
+                          [?tx :db/txInstant ?ms ?tx true]]");
+        assert_matches!(conn.datoms(),
+                        "[[100 :db/ident :test/name]
+                          [100 :db/valueType :db.type/string]
+                          [101 :db/ident :test/component]
+                          [101 :db/valueType :db.type/ref]
+                          [101 :db/cardinality :db.cardinality/many]
+                          [101 :db/isComponent true]
+                          [102 :db/ident :test/friend]
+                          [102 :db/valueType :db.type/ref]
+                          [201 :test/name \"untouched\"]
+                          [400 :test/name \"friend\"]]");
+    }
+
+    #[test]
+    fn test_tx_metadata() {
+        let mut conn = TestConn::default();
+
+        assert_transact!(conn, "[[:db/add 100 :db/ident :test/name]
+                                 [:db/add 100 :db/valueType :db.type/string]
+                                 [:db/add 101 :db/ident :tx/source]
+                                 [:db/add 101 :db/valueType :db.type/string]
+                                 [:db/add 102 :db/ident :tx/previous]
+                                 [:db/add 102 :db/valueType :db.type/ref]]");
+
+        // :db/tx is the transaction being transacted, as an entity...
+        let report = assert_transact!(conn, "[[:db/add 200 :test/name \"imported\"]
+                                              [:db/add :db/tx :tx/source \"importer\"]]");
+        assert_matches!(conn.last_transaction(),
+                        "[[200 :test/name \"imported\" ?tx true]
+                          [?tx :db/txInstant ?ms ?tx true]
+                          [?tx :tx/source \"importer\" ?tx true]]");
+
+        // ... including in map notation ...
+        assert_transact!(conn, "[{:db/id :db/tx :tx/source \"importer\"}]");
+        assert_matches!(conn.last_transaction(),
+                        "[[?tx :db/txInstant ?ms ?tx true]
+                          [?tx :tx/source \"importer\" ?tx true]]");
+
+        // ... and as a value.
+        assert_transact!(conn, format!("[[:db/add {} :tx/previous :db/tx]]", report.tx_id).as_str());
+        assert_matches!(conn.last_transaction(),
+                        format!("[[{} :tx/previous ?tx ?tx true]
+                                  [?tx :db/txInstant ?ms ?tx true]]", report.tx_id).as_str());
+    }
+
+    #[test]
+    fn test_upsert_multiple_unique_attributes() {
+        let mut conn = TestConn::default();
+
+        assert_transact!(conn, "[[:db/add 100 :db/ident :test/email]
+                                 [:db/add 100 :db/valueType :db.type/string]
+                                 [:db/add 100 :db/unique :db.unique/identity]
+                                 [:db/add 100 :db/index true]
+                                 [:db/add 101 :db/ident :test/handle]
+                                 [:db/add 101 :db/valueType :db.type/string]
+                                 [:db/add 101 :db/unique :db.unique/identity]
+                                 [:db/add 101 :db/index true]]");
+
+        assert_transact!(conn, "[[:db/add 200 :test/email \"a@example.com\"]
+                                 [:db/add 200 :test/handle \"a\"]
+                                 [:db/add 201 :test/email \"b@example.com\"]
+                                 [:db/add 201 :test/handle \"b\"]
+                                 [:db/add 202 :test/email \"c@example.com\"]]");
+
+        // Both unique attributes agree, so we upsert to that single entity.
+        let report = assert_transact!(conn, "[{:db/id \"t\" :test/email \"a@example.com\" :test/handle \"a\"}]");
+        assert_matches!(conn.last_transaction(),
+                        "[[?tx :db/txInstant ?ms ?tx true]]");
+        assert_matches!(tempids(&report),
+                        "{\"t\" 200}");
+
+        // They identify different entities, so we can't tell which is meant.
+        assert_transact!(conn, "[{:db/id \"t\" :test/email \"a@example.com\" :test/handle \"b\"}]",
+                         Err("conflicting upsert: tempid \'t\' resolves to more than one entid via [a e]: [(100, 200), (101, 201)]"));
+
+        // Only one identifies an entity, so that's the one we upsert to.
+        let report = assert_transact!(conn, "[{:db/id \"t\" :test/email \"c@example.com\" :test/handle \"c\"}]");
+        assert_matches!(conn.last_transaction(),
+                        "[[202 :test/handle \"c\" ?tx true]
+                          [?tx :db/txInstant ?ms ?tx true]]");
+        assert_matches!(tempids(&report),
+                        "{\"t\" 202}");
+    }
+
+    #[test]
+    fn test_no_history() {
+        let mut conn = TestConn::default();
+
+        assert_transact!(conn, "[[:db/add 100 :db/ident :test/seen]
+                                 [:db/add 100 :db/valueType :db.type/long]
+                                 [:db/add 100 :db/noHistory true]
+                                 [:db/add 101 :db/ident :test/name]
+                                 [:db/add 101 :db/valueType :db.type/string]]");
+        assert_eq!(conn.schema.attribute_for_entid(100).map(|a| a.no_history), Some(true));
+        let installed = conn.last_tx_id();
+
+        assert_transact!(conn, "[[:db/add 200 :test/seen 1]
+                                 [:db/add 200 :test/name \"a\"]]");
+        assert_transact!(conn, "[[:db/add 200 :test/seen 2]
+                                 [:db/add 200 :test/name \"b\"]]");
+
+        // The replaced :test/name is retracted; the replaced :test/seen is simply gone.
+        assert_matches!(conn.last_transaction(),
+                        "[[200 :test/name \"a\" ?tx false]
+                          [200 :test/name \"b\" ?tx true]
+                          [200 :test/seen 2 ?tx true]
+                          [?tx :db/txInstant ?ms ?tx true]]");
+
+        // Even from the transaction that asserted it.
+        assert_matches!(debug::transactions_after(&conn.sqlite, &conn.schema, installed).expect("transactions").into_edn(),
+                        "[[[200 :test/name \"a\" ?tx1 true]
+                           [?tx1 :db/txInstant ?ms1 ?tx1 true]]
+                          [[200 :test/name \"a\" ?tx2 false]
+                           [200 :test/name \"b\" ?tx2 true]
+                           [200 :test/seen 2 ?tx2 true]
+                           [?tx2 :db/txInstant ?ms2 ?tx2 true]]]");
+
+        // Retracting leaves no trace at all.
+        assert_transact!(conn, "[[:db/retract 200 :test/seen 2]]");
+        assert_matches!(conn.last_transaction(),
+                        "[[?tx :db/txInstant ?ms ?tx true]]");
+        assert_matches!(conn.datoms(),
+                        "[[100 :db/ident :test/seen]
+                          [100 :db/valueType :db.type/long]
+                          [100 :db/noHistory true]
+                          [101 :db/ident :test/name]
+                          [101 :db/valueType :db.type/string]
+                          [200 :test/name \"b\"]]");
+    }
+
+    #[test]
+    fn test_tx_report() {
+        // Every transaction also asserts its own :db/txInstant.
+        fn user_datoms(report: &TxReport) -> Vec<(Entid, Entid, TypedValue, bool)> {
+            report.datoms.iter().filter(|d| d.0 != report.tx_id).cloned().collect()
+        }
+
+        let mut conn = TestConn::default();
+
+        assert_transact!(conn, "[[:db/add 100 :db/ident :test/name]
+                                 [:db/add 100 :db/valueType :db.type/string]
+                                 [:db/add 100 :db/unique :db.unique/identity]
+                                 [:db/add 101 :db/ident :test/age]
+                                 [:db/add 101 :db/valueType :db.type/long]]");
+
+        // A new entity is allocated an entid, and its datoms are reported.
+        let report = assert_transact!(conn, "[[:db/add \"a\" :test/name \"Alice\"]
+                                              [:db/add \"a\" :test/age 30]]");
+        assert_matches!(tempids(&report),
+                        "{\"a\" 65536}");
+        assert_eq!(user_datoms(&report),
+                   vec![(65536, 100, TypedValue::typed_string("Alice"), true),
+                        (65536, 101, TypedValue::Long(30), true)]);
+
+        // An upsert maps the tempid to the existing entity.  Only what changed is reported: the
+        // name was already there, and the old age is retracted.
+        let report = assert_transact!(conn, "[[:db/add \"b\" :test/name \"Alice\"]
+                                              [:db/add \"b\" :test/age 31]]");
+        assert_matches!(tempids(&report),
+                        "{\"b\" 65536}");
+        assert_eq!(user_datoms(&report),
+                   vec![(65536, 101, TypedValue::Long(30), false),
+                        (65536, 101, TypedValue::Long(31), true)]);
+    }
 }
@@ -14,7 +14,7 @@ use edn;
 use rusqlite;
 
 use mentat_tx_parser;
-use types::{Entid, ValueType};
+use types::{Entid, TypedValue, ValueType};
 
 error_chain! {
     types {
@@ -82,5 +82,83 @@ error_chain! {
             description("unrecognized or no ident found for entid")
             display("unrecognized or no ident found for entid: {}", entid)
         }
+
+        /// An attribute can't be altered to be :db.cardinality/one, because `entity` has more than
+        /// one value for it.
+        CannotAlterCardinalityToOne(attribute: Entid, entity: Entid) {
+            description("cannot alter attribute to be :db.cardinality/one")
+            display("cannot alter schema attribute {} to be :db.cardinality/one: entity {} has more than one value", attribute, entity)
+        }
+
+        /// An attribute can't be retracted, because `count` datoms still use it.
+        CannotRetractAttributeInUse(attribute: Entid, count: i64) {
+            description("cannot retract attribute that is in use")
+            display("cannot retract schema attribute {}: {} datoms use it", attribute, count)
+        }
+
+        /// An attribute's :db/valueType can't be altered, because `entity` has a value for it that
+        /// can't be converted to `value_type`.
+        CannotAlterValueType(attribute: Entid, value_type: ValueType, entity: Entid, value: TypedValue) {
+            description("cannot alter attribute :db/valueType")
+            display("cannot alter schema attribute {} to have :db/valueType {:?}: entity {} has value {:?}", attribute, value_type, entity, value)
+        }
+
+        /// A schema install redefines `attribute`, which it names by a tempid that upserts via
+        /// `:db/ident`, with a `:db/valueType` other than its existing `value_type`.
+        ConflictingAttributeDefinition(attribute: Entid, value_type: ValueType) {
+            description("conflicting attribute definition")
+            display("conflicting attribute definition: schema attribute {} already has :db/valueType {:?}", attribute, value_type)
+        }
+
+        /// Lookup refs can only use unique attributes.
+        NonUniqueLookupRefAttribute(attribute: Entid) {
+            description("lookup ref attribute is not unique")
+            display("cannot resolve a lookup ref with attribute {}, which is not :db/unique", attribute)
+        }
+
+        /// A tempid upserts to more than one existing entity, each via a different unique attribute.
+        /// `conflicts` lists each [attribute entid] pair by which the tempid resolved.
+        ConflictingUpsert(tempid: String, conflicts: Vec<(Entid, Entid)>) {
+            description("conflicting upsert")
+            display("conflicting upsert: tempid '{}' resolves to more than one entid via [a e]: {:?}", tempid, conflicts)
+        }
+
+        /// An entid was to be allocated from a partition we don't know.
+        UnknownPartition(partition: String) {
+            description("unknown partition")
+            display("unknown partition: {}", partition)
+        }
+
+        /// A partition doesn't have as many entids left as were asked for.
+        PartitionExhausted(partition: String, requested: usize, remaining: i64) {
+            description("partition exhausted")
+            display("cannot allocate {} entids in partition {}: only {} remain", requested, partition, remaining)
+        }
+
+        /// A new partition couldn't be added.
+        BadPartition(t: String) {
+            description("bad partition")
+            display("bad partition: {}", t)
+        }
+
+        /// A datom given to `bulk_import` can't be imported.
+        BadImport(t: String) {
+            description("bad import")
+            display("bad import: {}", t)
+        }
+
+        /// A bulk import would leave these datoms violating `:db/unique` or
+        /// `:db.cardinality/one`, so it was rolled back.
+        ConflictingImport(conflicts: Vec<(Entid, Entid, TypedValue)>) {
+            description("conflicting import")
+            display("import rolled back: conflicting datoms [e a v]: {:?}", conflicts)
+        }
+
+        /// A `:db.fn/cas` didn't find the value it expected, so the transaction was aborted.
+        /// `None` means no value at all.
+        CasFailed(e: Entid, a: Entid, expected: Option<TypedValue>, actual: Option<TypedValue>) {
+            description("compare-and-swap failed")
+            display("compare-and-swap failed for [{} {}]: expected {:?}, found {:?}", e, a, expected, actual)
+        }
     }
 }
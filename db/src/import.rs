@@ -0,0 +1,210 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Loading many datoms at once, without maintaining the secondary indexes as we go.
+//!
+//! The transactor does a great deal of work for each transaction: it resolves tempids and lookup
+//! refs, upserts, replaces cardinality-one values, and updates every index on `datoms`, one
+//! statement at a time. A bulk import does none of that. It takes datoms whose entities have
+//! already been allocated, drops the opt-in indexes on `datoms`, inserts every datom, checks
+//! uniqueness and cardinality once, over the whole table, and then rebuilds the indexes.
+
+use std::collections::BTreeSet;
+
+use itertools::Itertools;
+
+use rusqlite;
+use rusqlite::types::ToSql;
+
+use mentat_core::{
+    Attribute,
+    Entid,
+    Schema,
+    TypedValue,
+    ValueType,
+};
+
+use db;
+use db::{
+    PartitionMapping,
+    TypedSQLValue,
+};
+use entids;
+use schema::SchemaBuilding;
+use errors::{
+    ErrorKind,
+    Result,
+};
+use types::{
+    PartitionMap,
+    TxReport,
+};
+
+/// The indexes on `datoms` that a bulk import drops and rebuilds. The covering indexes, which
+/// identify each datom, are maintained throughout: we rely on them to skip datoms that are
+/// already present.
+const DEFERRED_INDEXES: [&'static str; 4] = [
+    "idx_datoms_avet",
+    "idx_datoms_vaet",
+    "idx_datoms_fulltext",
+    "idx_datoms_unique_value",
+];
+
+/// Drop the deferred indexes, returning the SQL with which to create them again.
+fn drop_deferred_indexes(conn: &rusqlite::Connection) -> Result<Vec<String>> {
+    let mut creates = Vec::with_capacity(DEFERRED_INDEXES.len());
+    for name in DEFERRED_INDEXES.iter() {
+        let sql: String = conn.query_row("SELECT sql FROM sqlite_master WHERE type = 'index' AND name = ?",
+                                         &[name as &ToSql], |row| row.get(0))?;
+        conn.execute(format!("DROP INDEX {}", name).as_str(), &[])?;
+        creates.push(sql);
+    }
+    Ok(creates)
+}
+
+/// The datoms in `datoms` that share a unique attribute and value with another datom, or a
+/// cardinality-one attribute and entity with another datom.
+fn conflicting_datoms(conn: &rusqlite::Connection, cardinality_one: &BTreeSet<Entid>) -> Result<Vec<(Entid, Entid, TypedValue)>> {
+    let mut conflicts = vec![];
+
+    let mut stmt = conn.prepare(
+        "SELECT d.e, d.a, d.v, d.value_type_tag \
+         FROM datoms AS d, \
+              (SELECT a, value_type_tag, v FROM datoms WHERE unique_value IS NOT 0 \
+               GROUP BY a, value_type_tag, v HAVING COUNT(*) > 1) AS dup \
+         WHERE d.a = dup.a AND d.value_type_tag = dup.value_type_tag AND d.v = dup.v \
+         ORDER BY d.a, d.v, d.e")?;
+    let mut rows = stmt.query(&[])?;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        conflicts.push((row.get_checked(0)?,
+                        row.get_checked(1)?,
+                        TypedValue::from_sql_value_pair(row.get_checked(2)?, row.get_checked(3)?)?));
+    }
+
+    if !cardinality_one.is_empty() {
+        let s = format!(
+            "SELECT d.e, d.a, d.v, d.value_type_tag \
+             FROM datoms AS d, \
+                  (SELECT e, a FROM datoms WHERE a IN ({}) GROUP BY e, a HAVING COUNT(*) > 1) AS dup \
+             WHERE d.e = dup.e AND d.a = dup.a \
+             ORDER BY d.e, d.a, d.v",
+            cardinality_one.iter().join(", "));
+        let mut stmt = conn.prepare(s.as_str())?;
+        let mut rows = stmt.query(&[])?;
+        while let Some(row) = rows.next() {
+            let row = row?;
+            conflicts.push((row.get_checked(0)?,
+                            row.get_checked(1)?,
+                            TypedValue::from_sql_value_pair(row.get_checked(2)?, row.get_checked(3)?)?));
+        }
+    }
+
+    Ok(conflicts)
+}
+
+fn check_datom<'s>(schema: &'s Schema, partition_map: &PartitionMap, e: Entid, a: Entid, v: &TypedValue) -> Result<&'s Attribute> {
+    if !partition_map.contains_entid(e) {
+        bail!(ErrorKind::UnrecognizedEntid(e));
+    }
+    let attribute = match schema.attribute_for_entid(a) {
+        Some(attribute) => attribute,
+        None => bail!(ErrorKind::UnrecognizedEntid(a)),
+    };
+    if entids::might_update_metadata(a) {
+        bail!(ErrorKind::BadImport(format!("schema attribute {} can only be transacted", a)));
+    }
+    if attribute.fulltext {
+        bail!(ErrorKind::BadImport(format!("fulltext attribute {} can only be transacted", a)));
+    }
+    if attribute.value_type != v.value_type() {
+        bail!(ErrorKind::BadImport(format!("value {:?} of [{} {}] is not of type {:?}", v, e, a, attribute.value_type)));
+    }
+    Ok(attribute)
+}
+
+/// Assert each `[e a v]` of `datoms` in a single new transaction, deferring index maintenance
+/// until every datom is inserted.
+///
+/// Each entity must already have been allocated, say with `PartitionMapping::allocate_entids`,
+/// and schema and fulltext attributes aren't accepted. Datoms that are already present are
+/// skipped, but nothing is ever replaced or retracted: if, once everything is inserted, two
+/// entities share a `:db/unique` value, or an entity has two values for a cardinality-one
+/// attribute, we fail with `ConflictingImport`, listing the conflicting datoms. `conn` should be
+/// in a SQLite transaction, so that the caller can roll the import back.
+pub fn bulk_import<I>(conn: &rusqlite::Connection,
+                      mut partition_map: PartitionMap,
+                      schema: &Schema,
+                      datoms: I) -> Result<(TxReport, PartitionMap)>
+    where I: IntoIterator<Item=(Entid, Entid, TypedValue)> {
+    let tx_instant = ::now();
+    let tx_id = partition_map.allocate_entid(":db.part/tx")?;
+
+    let creates = drop_deferred_indexes(conn)?;
+
+    let mut insert_datom = conn.prepare(
+        "INSERT OR IGNORE INTO datoms (e, a, v, tx, value_type_tag, index_avet, index_vaet, index_fulltext, unique_value) \
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)")?;
+    let mut insert_transaction = conn.prepare(
+        "INSERT INTO transactions (e, a, v, tx, added, value_type_tag) VALUES (?, ?, ?, ?, 1, ?)")?;
+
+    // Like the transactor, we begin with the transaction's instant, which needs no checking.
+    let tx_instant_attribute = schema.require_attribute_for_entid(entids::DB_TX_INSTANT)?;
+    let tx_datom = (tx_id, entids::DB_TX_INSTANT, TypedValue::Instant(tx_instant));
+
+    let mut cardinality_one: BTreeSet<Entid> = BTreeSet::default();
+    let mut inserted = vec![];
+    for (i, (e, a, v)) in ::std::iter::once(tx_datom).chain(datoms.into_iter()).enumerate() {
+        let attribute = if i == 0 {
+            tx_instant_attribute
+        } else {
+            check_datom(schema, &partition_map, e, a, &v)?
+        };
+        if !attribute.multival {
+            cardinality_one.insert(a);
+        }
+
+        let changed = {
+            let (value, value_type_tag) = v.to_sql_value_pair();
+            let changed = insert_datom.execute(&[&e as &ToSql, &a, &value, &tx_id, &value_type_tag,
+                                                 &attribute.index, &(attribute.value_type == ValueType::Ref),
+                                                 &attribute.fulltext, &attribute.unique.is_some()])?;
+            if changed > 0 && !attribute.no_history {
+                insert_transaction.execute(&[&e as &ToSql, &a, &value, &tx_id, &value_type_tag])?;
+            }
+            changed > 0
+        };
+        if changed {
+            inserted.push((e, a, v, true));
+        }
+    }
+
+    // The transaction's own instant never conflicts.
+    cardinality_one.remove(&entids::DB_TX_INSTANT);
+    let conflicts = conflicting_datoms(conn, &cardinality_one)?;
+    if !conflicts.is_empty() {
+        bail!(ErrorKind::ConflictingImport(conflicts));
+    }
+
+    for create in creates {
+        conn.execute(create.as_str(), &[])?;
+    }
+    db::update_partition_map(conn, &partition_map)?;
+
+    inserted.sort();
+    let report = TxReport {
+        tx_id: tx_id,
+        tx_instant: tx_instant,
+        tempids: Default::default(),
+        retracted: Default::default(),
+        datoms: inserted,
+    };
+    Ok((report, partition_map))
+}
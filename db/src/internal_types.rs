@@ -58,6 +58,15 @@ pub enum LookupRefOrTempId {
     TempId(TempIdHandle)
 }
 
+/// A `:db.fn/cas` precondition: entity `e` must currently have exactly the given value for
+/// attribute `a`, or no value at all if `None`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CasCheck<E> {
+    pub e: E,
+    pub a: Entid,
+    pub expected: Option<TypedValue>,
+}
+
 pub type TermWithTempIdsAndLookupRefs = Term<KnownEntidOr<LookupRefOrTempId>, TypedValueOr<LookupRefOrTempId>>;
 pub type TermWithTempIds = Term<KnownEntidOr<TempIdHandle>, TypedValueOr<TempIdHandle>>;
 pub type TermWithoutTempIds = Term<KnownEntid, TypedValue>;
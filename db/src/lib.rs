@@ -14,6 +14,7 @@ extern crate itertools;
 
 #[macro_use]
 extern crate lazy_static;
+extern crate regex;
 extern crate rusqlite;
 extern crate tabwriter;
 extern crate time;
@@ -37,12 +38,16 @@ pub use errors::{Error, ErrorKind, ResultExt, Result};
 
 pub mod db;
 mod bootstrap;
+mod cache;
 pub mod debug;
 mod add_retract_alter_set;
-mod entids;
+pub mod entids;
 pub mod errors;
+mod import;
+mod tx_log;
 mod metadata;
 mod schema;
+mod schema_diff;
 pub mod types;
 mod internal_types;
 mod upsert_resolution;
@@ -57,12 +62,40 @@ pub use bootstrap::{
 
 pub use db::{
     TypedSQLValue,
+    ValueTypeCoercion,
+    Xorshift,
+    entid_for_lookup_ref,
     new_connection,
+    seed_random,
 };
 
-pub use tx::transact;
+pub use cache::{
+    AttributeCaches,
+    CacheDirection,
+};
+
+pub use import::bulk_import;
+
+pub use tx_log::{
+    TxLog,
+    transactions_after,
+};
+
+pub use schema_diff::{
+    AttributeChange,
+    AttributeFacet,
+    SchemaChange,
+    schema_diff,
+};
+
+pub use tx::{
+    attribute_retraction,
+    transact,
+    transact_with_coercion,
+};
 pub use types::{
     DB,
+    Partition,
     PartitionMap,
     TxReport,
 };
@@ -15,8 +15,8 @@
 //! - they can add (and, eventually, retract and alter) recognized idents using the `:db/ident`
 //!   attribute;
 //!
-//! - they can add (and, eventually, retract and alter) schema attributes using various `:db/*`
-//!   attributes;
+//! - they can add, alter, and retract schema attributes using various `:db/*` attributes, although
+//!   an attribute can only be retracted as a whole, and only once nothing uses it;
 //!
 //! - eventually, they will be able to add (and possibly retract) entid partitions using a Mentat
 //!   equivalent (perhaps :db/partition or :db.partition/start) to Datomic's `:db.install/partition`
@@ -67,6 +67,8 @@ pub enum AttributeAlteration {
     NoHistory,
     /// - change whether an attribute is treated as a component
     IsComponent,
+    /// - change the value type of an attribute, so long as its existing values can be converted
+    ValueType,
 }
 
 /// An alteration to an ident.
@@ -87,6 +89,10 @@ pub struct MetadataReport {
 
     // Idents that were installed into the `SchemaMap`.
     pub idents_altered: BTreeMap<Entid, IdentAlteration>,
+
+    // Entids that were present in the original `SchemaMap` that was mutated, and whose definitions
+    // were retracted.
+    pub attributes_retracted: BTreeSet<Entid>,
 }
 
 /// Update a `SchemaMap` in place from the given `[e a typed_value]` triples.
@@ -116,6 +122,7 @@ pub fn update_schema_map_from_entid_triples<U>(schema_map: &mut SchemaMap, asser
             entids::DB_VALUE_TYPE => {
                 match *value {
                     TypedValue::Ref(entids::DB_TYPE_BOOLEAN) => { builder.value_type(ValueType::Boolean); },
+                    TypedValue::Ref(entids::DB_TYPE_BYTES)   => { builder.value_type(ValueType::Bytes); },
                     TypedValue::Ref(entids::DB_TYPE_DOUBLE)  => { builder.value_type(ValueType::Double); },
                     TypedValue::Ref(entids::DB_TYPE_INSTANT) => { builder.value_type(ValueType::Instant); },
                     TypedValue::Ref(entids::DB_TYPE_KEYWORD) => { builder.value_type(ValueType::Keyword); },
@@ -169,6 +176,13 @@ pub fn update_schema_map_from_entid_triples<U>(schema_map: &mut SchemaMap, asser
                 }
             },
 
+            entids::DB_NO_HISTORY => {
+                match *value {
+                    TypedValue::Boolean(x) => { builder.no_history(x); },
+                    _ => bail!(ErrorKind::BadSchemaAssertion(format!("Expected [... :db/noHistory true|false] but got [... :db/noHistory {:?}]", value)))
+                }
+            },
+
             _ => {
                 bail!(ErrorKind::BadSchemaAssertion(format!("Do not recognize attribute {} for entid {}", attr, entid)))
             }
@@ -190,6 +204,15 @@ pub fn update_schema_map_from_entid_triples<U>(schema_map: &mut SchemaMap, asser
                 builder.validate_alter_attribute()
                     .chain_err(|| ErrorKind::BadSchemaAssertion(format!("Schema alteration for existing attribute with entid {} is not valid", entid)))?;
                 let mutations = builder.mutate(entry.get_mut());
+                if mutations.contains(&AttributeAlteration::ValueType) {
+                    let attribute = entry.get();
+                    if attribute.fulltext && attribute.value_type != ValueType::String {
+                        bail!(ErrorKind::BadSchemaAssertion(format!(":db/fulltext true without :db/valueType :db.type/string for entid: {}", entid)));
+                    }
+                    if attribute.component && attribute.value_type != ValueType::Ref {
+                        bail!(ErrorKind::BadSchemaAssertion(format!(":db/isComponent true without :db/valueType :db.type/ref for entid: {}", entid)));
+                    }
+                }
                 attributes_altered.insert(entid, mutations);
             },
         }
@@ -199,6 +222,7 @@ pub fn update_schema_map_from_entid_triples<U>(schema_map: &mut SchemaMap, asser
         attributes_installed: attributes_installed,
         attributes_altered: attributes_altered,
         idents_altered: BTreeMap::default(),
+        attributes_retracted: BTreeSet::default(),
     })
 }
 
@@ -235,17 +259,45 @@ pub fn update_schema_from_entid_quadruples<U>(schema: &mut Schema, assertions: U
         attribute_set.witness((e, a), typed_value, added);
     }
 
-    // Datomic does not allow to retract attributes or idents.  For now, Mentat follows suit.
-    if !attribute_set.retracted.is_empty() {
+    // Retracting an attribute's `:db/valueType` retracts the attribute itself, along with whatever
+    // other characteristics are retracted alongside it.  Whether that's safe depends on the store:
+    // see `db::update_metadata`.  Retracting `:db/index` on its own turns indexing off, just as
+    // asserting `:db/index false` does.  Retracting any other characteristic on its own is not yet
+    // supported; Datomic doesn't allow it at all.
+    let attributes_retracted: BTreeSet<Entid> = attribute_set.retracted.keys()
+        .filter(|&&(e, a)| a == entids::DB_VALUE_TYPE && schema.schema_map.contains_key(&e))
+        .map(|&(e, _)| e)
+        .collect();
+
+    let unindexed_triples: Vec<(Entid, Entid, TypedValue)> = attribute_set.retracted.keys()
+        .filter(|&&(e, a)| a == entids::DB_INDEX && !attributes_retracted.contains(&e) && schema.schema_map.contains_key(&e))
+        .map(|&(e, a)| (e, a, TypedValue::Boolean(false)))
+        .collect();
+
+    let unsupported: Vec<(Entid, Entid)> = attribute_set.retracted.keys()
+        .filter(|&&(e, a)| !attributes_retracted.contains(&e) && !unindexed_triples.iter().any(|&(ue, ua, _)| (ue, ua) == (e, a)))
+        .cloned()
+        .collect();
+    if !unsupported.is_empty() {
         bail!(ErrorKind::NotYetImplemented(format!("Retracting metadata attribute assertions not yet implemented: retracted [e a] pairs [{}]",
-                                                   attribute_set.retracted.keys().map(|&(e, a)| format!("[{} {}]", e, a)).join(", "))));
+                                                   unsupported.iter().map(|&(e, a)| format!("[{} {}]", e, a)).join(", "))));
+    }
+
+    for &(e, a) in attribute_set.asserted.keys().chain(attribute_set.altered.keys()) {
+        if attributes_retracted.contains(&e) {
+            bail!(ErrorKind::BadSchemaAssertion(format!("Cannot retract schema attribute {} while asserting its attribute {}", e, a)));
+        }
+    }
+
+    for e in &attributes_retracted {
+        schema.schema_map.remove(e);
     }
 
     // Collect triples.
     let asserted_triples = attribute_set.asserted.into_iter().map(|((e, a), typed_value)| (e, a, typed_value));
     let altered_triples = attribute_set.altered.into_iter().map(|((e, a), (_old_value, new_value))| (e, a, new_value));
 
-    let report = update_schema_map_from_entid_triples(&mut schema.schema_map, asserted_triples.chain(altered_triples))?;
+    let report = update_schema_map_from_entid_triples(&mut schema.schema_map, asserted_triples.chain(altered_triples).chain(unindexed_triples))?;
 
     let mut idents_altered: BTreeMap<Entid, IdentAlteration> = BTreeMap::new();
 
@@ -271,6 +323,7 @@ pub fn update_schema_from_entid_quadruples<U>(schema: &mut Schema, assertions: U
 
     Ok(MetadataReport {
         idents_altered: idents_altered,
+        attributes_retracted: attributes_retracted,
         .. report
     })
 }
@@ -65,6 +65,7 @@ pub struct AttributeBuilder {
     index: Option<bool>,
     fulltext: Option<bool>,
     component: Option<bool>,
+    no_history: Option<bool>,
 }
 
 impl AttributeBuilder {
@@ -98,6 +99,11 @@ impl AttributeBuilder {
         self
     }
 
+    pub fn no_history<'a>(&'a mut self, no_history: bool) -> &'a mut Self {
+        self.no_history = Some(no_history);
+        self
+    }
+
     pub fn validate_install_attribute(&self) -> Result<()> {
         if self.value_type.is_none() {
             bail!(ErrorKind::BadSchemaAssertion("Schema attribute for new attribute does not set :db/valueType".into()));
@@ -106,9 +112,6 @@ impl AttributeBuilder {
     }
 
     pub fn validate_alter_attribute(&self) -> Result<()> {
-        if self.value_type.is_some() {
-            bail!(ErrorKind::BadSchemaAssertion("Schema alteration must not set :db/valueType".into()));
-        }
         if self.fulltext.is_some() {
             bail!(ErrorKind::BadSchemaAssertion("Schema alteration must not set :db/fulltext".into()));
         }
@@ -135,12 +138,21 @@ impl AttributeBuilder {
         if let Some(component) = self.component {
             attribute.component = component;
         }
+        if let Some(no_history) = self.no_history {
+            attribute.no_history = no_history;
+        }
 
         attribute
     }
 
     pub fn mutate(&self, attribute: &mut Attribute) -> Vec<AttributeAlteration> {
         let mut mutations = Vec::new();
+        if let Some(value_type) = self.value_type {
+            if value_type != attribute.value_type {
+                attribute.value_type = value_type;
+                mutations.push(AttributeAlteration::ValueType);
+            }
+        }
         if let Some(multival) = self.multival {
             if multival != attribute.multival {
                 attribute.multival = multival;
@@ -165,6 +177,12 @@ impl AttributeBuilder {
                 mutations.push(AttributeAlteration::IsComponent);
             }
         }
+        if let Some(no_history) = self.no_history {
+            if no_history != attribute.no_history {
+                attribute.no_history = no_history;
+                mutations.push(AttributeAlteration::NoHistory);
+            }
+        }
 
         mutations
     }
@@ -245,6 +263,8 @@ impl SchemaTypeChecking for Schema {
                 (ValueType::Uuid, tv @ TypedValue::Uuid(_)) => Ok(tv),
                 (ValueType::Instant, tv @ TypedValue::Instant(_)) => Ok(tv),
                 (ValueType::Keyword, tv @ TypedValue::Keyword(_)) => Ok(tv),
+                (ValueType::BigInt, tv @ TypedValue::BigInteger(_)) => Ok(tv),
+                (ValueType::Bytes, tv @ TypedValue::Bytes(_)) => Ok(tv),
                 // Ref coerces a little: we interpret some things depending on the schema as a Ref.
                 (ValueType::Ref, TypedValue::Long(x)) => Ok(TypedValue::Ref(x)),
                 (ValueType::Ref, TypedValue::Keyword(ref x)) => self.require_entid(&x).map(|entid| TypedValue::Ref(entid)),
@@ -260,6 +280,8 @@ impl SchemaTypeChecking for Schema {
                 (vt @ ValueType::Uuid, _) |
                 (vt @ ValueType::Instant, _) |
                 (vt @ ValueType::Keyword, _) |
+                (vt @ ValueType::BigInt, _) |
+                (vt @ ValueType::Bytes, _) |
                 (vt @ ValueType::Ref, _)
                 => bail!(ErrorKind::BadEDNValuePair(value.clone(), vt)),
             }
@@ -297,6 +319,7 @@ mod test {
             unique: None,
             multival: false,
             component: false,
+            no_history: false,
         });
         // attribute is unique by value and an index
         add_attribute(&mut schema, NamespacedKeyword::new("foo", "baz"), 98, Attribute {
@@ -306,6 +329,7 @@ mod test {
             unique: Some(attribute::Unique::Value),
             multival: false,
             component: false,
+            no_history: false,
         });
         // attribue is unique by identity and an index
         add_attribute(&mut schema, NamespacedKeyword::new("foo", "bat"), 99, Attribute {
@@ -315,6 +339,7 @@ mod test {
             unique: Some(attribute::Unique::Identity),
             multival: false,
             component: false,
+            no_history: false,
         });
         // attribute is a components and a `Ref`
         add_attribute(&mut schema, NamespacedKeyword::new("foo", "bak"), 100, Attribute {
@@ -324,6 +349,7 @@ mod test {
             unique: None,
             multival: false,
             component: true,
+            no_history: false,
         });
         // fulltext attribute is a string and an index
         add_attribute(&mut schema, NamespacedKeyword::new("foo", "bap"), 101, Attribute {
@@ -333,6 +359,7 @@ mod test {
             unique: None,
             multival: false,
             component: false,
+            no_history: false,
         });
 
         assert!(validate_schema_map(&schema.entid_map, &schema.schema_map).is_ok());
@@ -350,6 +377,7 @@ mod test {
             unique: Some(attribute::Unique::Value),
             multival: false,
             component: false,
+            no_history: false,
         });
         
         let err = validate_schema_map(&schema.entid_map, &schema.schema_map).err();
@@ -372,6 +400,7 @@ mod test {
             unique: Some(attribute::Unique::Identity),
             multival: false,
             component: false,
+            no_history: false,
         });
         
         let err = validate_schema_map(&schema.entid_map, &schema.schema_map).err();
@@ -394,6 +423,7 @@ mod test {
             unique: None,
             multival: false,
             component: true,
+            no_history: false,
         });
         
         let err = validate_schema_map(&schema.entid_map, &schema.schema_map).err();
@@ -416,6 +446,7 @@ mod test {
             unique: None,
             multival: false,
             component: false,
+            no_history: false,
         });
         
         let err = validate_schema_map(&schema.entid_map, &schema.schema_map).err();
@@ -437,6 +468,7 @@ mod test {
             unique: None,
             multival: false,
             component: false,
+            no_history: false,
         });
         
         let err = validate_schema_map(&schema.entid_map, &schema.schema_map).err();
@@ -0,0 +1,231 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Comparing two schemas, as when planning a migration.
+//!
+//! Attributes are matched by ident rather than by entid, so that a proposed schema built
+//! elsewhere -- with its own entids -- can be compared against the schema of a store.
+
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
+
+use edn::symbols;
+
+use mentat_core::{
+    Attribute,
+    Schema,
+};
+
+/// A property of an attribute that can differ between two schemas.
+#[derive(Clone, Copy, Debug, Eq, Hash, Ord, PartialOrd, PartialEq)]
+pub enum AttributeFacet {
+    ValueType,
+    Cardinality,
+    Unique,
+    Index,
+    Fulltext,
+    IsComponent,
+    NoHistory,
+}
+
+/// An attribute present in both schemas, with differing facets.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AttributeChange {
+    pub current: Attribute,
+    pub proposed: Attribute,
+    pub facets: BTreeSet<AttributeFacet>,
+}
+
+impl AttributeChange {
+    fn between(current: &Attribute, proposed: &Attribute) -> AttributeChange {
+        let mut facets = BTreeSet::new();
+        if current.value_type != proposed.value_type {
+            facets.insert(AttributeFacet::ValueType);
+        }
+        if current.multival != proposed.multival {
+            facets.insert(AttributeFacet::Cardinality);
+        }
+        if current.unique != proposed.unique {
+            facets.insert(AttributeFacet::Unique);
+        }
+        if current.index != proposed.index {
+            facets.insert(AttributeFacet::Index);
+        }
+        if current.fulltext != proposed.fulltext {
+            facets.insert(AttributeFacet::Fulltext);
+        }
+        if current.component != proposed.component {
+            facets.insert(AttributeFacet::IsComponent);
+        }
+        if current.no_history != proposed.no_history {
+            facets.insert(AttributeFacet::NoHistory);
+        }
+        AttributeChange {
+            current: current.clone(),
+            proposed: proposed.clone(),
+            facets: facets,
+        }
+    }
+
+    /// Existing values can't, in general, be converted to a different value type, so changing it
+    /// might lose data.
+    pub fn is_potentially_lossy(&self) -> bool {
+        self.facets.contains(&AttributeFacet::ValueType)
+    }
+}
+
+/// The differences between two schemas.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct SchemaChange {
+    /// Attributes in the proposed schema but not the current one.
+    pub added: BTreeMap<symbols::NamespacedKeyword, Attribute>,
+
+    /// Attributes in the current schema but not the proposed one.
+    pub removed: BTreeMap<symbols::NamespacedKeyword, Attribute>,
+
+    /// Attributes in both schemas whose facets differ.
+    pub altered: BTreeMap<symbols::NamespacedKeyword, AttributeChange>,
+}
+
+impl SchemaChange {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.altered.is_empty()
+    }
+
+    /// The altered attributes whose changes might lose data.
+    pub fn potentially_lossy(&self) -> BTreeSet<&symbols::NamespacedKeyword> {
+        self.altered
+            .iter()
+            .filter(|&(_, change)| change.is_potentially_lossy())
+            .map(|(ident, _)| ident)
+            .collect()
+    }
+}
+
+fn attributes_by_ident(schema: &Schema) -> BTreeMap<&symbols::NamespacedKeyword, &Attribute> {
+    schema.schema_map
+          .iter()
+          .filter_map(|(entid, attribute)| schema.get_ident(*entid).map(|ident| (ident, attribute)))
+          .collect()
+}
+
+/// Compute what it would take to turn `current` into `proposed`.
+pub fn schema_diff(current: &Schema, proposed: &Schema) -> SchemaChange {
+    let current = attributes_by_ident(current);
+    let proposed = attributes_by_ident(proposed);
+
+    let mut change = SchemaChange::default();
+    for (&ident, &attribute) in current.iter() {
+        match proposed.get(ident) {
+            None => {
+                change.removed.insert(ident.clone(), attribute.clone());
+            },
+            Some(&other) if other != attribute => {
+                change.altered.insert(ident.clone(), AttributeChange::between(attribute, other));
+            },
+            Some(_) => {},
+        }
+    }
+    for (&ident, &attribute) in proposed.iter() {
+        if !current.contains_key(ident) {
+            change.added.insert(ident.clone(), attribute.clone());
+        }
+    }
+    change
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use mentat_core::{
+        Entid,
+        ValueType,
+    };
+
+    fn add_attribute(schema: &mut Schema, ident: symbols::NamespacedKeyword, entid: Entid, attribute: Attribute) {
+        schema.entid_map.insert(entid, ident.clone());
+        schema.ident_map.insert(ident, entid);
+        schema.schema_map.insert(entid, attribute);
+    }
+
+    fn string_attribute() -> Attribute {
+        Attribute {
+            value_type: ValueType::String,
+            ..Default::default()
+        }
+    }
+
+    fn base_schema() -> Schema {
+        let mut schema = Schema::default();
+        add_attribute(&mut schema, symbols::NamespacedKeyword::new("foo", "name"), 65536, string_attribute());
+        add_attribute(&mut schema, symbols::NamespacedKeyword::new("foo", "tag"), 65537, string_attribute());
+        schema
+    }
+
+    #[test]
+    fn test_schema_diff_identical() {
+        assert!(schema_diff(&base_schema(), &base_schema()).is_empty());
+    }
+
+    #[test]
+    fn test_schema_diff_added_and_removed() {
+        let current = base_schema();
+
+        // Entids don't matter: only idents do.
+        let mut proposed = Schema::default();
+        add_attribute(&mut proposed, symbols::NamespacedKeyword::new("foo", "name"), 70000, string_attribute());
+        add_attribute(&mut proposed, symbols::NamespacedKeyword::new("foo", "age"), 70001, Attribute {
+            value_type: ValueType::Long,
+            ..Default::default()
+        });
+
+        let change = schema_diff(&current, &proposed);
+        assert_eq!(change.added.keys().collect::<Vec<_>>(), vec![&symbols::NamespacedKeyword::new("foo", "age")]);
+        assert_eq!(change.removed.keys().collect::<Vec<_>>(), vec![&symbols::NamespacedKeyword::new("foo", "tag")]);
+        assert!(change.altered.is_empty());
+    }
+
+    #[test]
+    fn test_schema_diff_cardinality() {
+        let current = base_schema();
+        let mut proposed = base_schema();
+        proposed.schema_map.get_mut(&65537).unwrap().multival = true;
+
+        let change = schema_diff(&current, &proposed);
+        let tag = symbols::NamespacedKeyword::new("foo", "tag");
+        assert_eq!(change.altered.keys().collect::<Vec<_>>(), vec![&tag]);
+        assert_eq!(change.altered[&tag].facets, vec![AttributeFacet::Cardinality].into_iter().collect());
+        assert!(!change.altered[&tag].is_potentially_lossy());
+        assert!(change.potentially_lossy().is_empty());
+    }
+
+    #[test]
+    fn test_schema_diff_value_type() {
+        let current = base_schema();
+        let mut proposed = base_schema();
+        {
+            let name = proposed.schema_map.get_mut(&65536).unwrap();
+            name.value_type = ValueType::Long;
+            name.index = true;
+        }
+
+        let change = schema_diff(&current, &proposed);
+        let name = symbols::NamespacedKeyword::new("foo", "name");
+        assert_eq!(change.altered[&name].facets,
+                   vec![AttributeFacet::ValueType, AttributeFacet::Index].into_iter().collect());
+        assert_eq!(change.altered[&name].current.value_type, ValueType::String);
+        assert_eq!(change.altered[&name].proposed.value_type, ValueType::Long);
+        assert!(change.altered[&name].is_potentially_lossy());
+        assert_eq!(change.potentially_lossy(), vec![&name].into_iter().collect());
+    }
+}
@@ -57,13 +57,17 @@ use db;
 use db::{
     MentatStoring,
     PartitionMapping,
+    TypedSQLValue,
+    ValueTypeCoercion,
 };
+use edn;
 use edn::{
     NamespacedKeyword,
 };
 use entids;
 use errors::{ErrorKind, Result};
 use internal_types::{
+    CasCheck,
     KnownEntid,
     KnownEntidOr,
     LookupRef,
@@ -96,6 +100,7 @@ use mentat_tx::entities::{
 use mentat_tx_parser;
 use metadata;
 use rusqlite;
+use rusqlite::types::ToSql;
 use schema::{
     SchemaBuilding,
     SchemaTypeChecking,
@@ -112,6 +117,12 @@ use types::{
 };
 use upsert_resolution::Generation;
 
+/// `:db/tx` stands for the transaction itself, wherever an entity is expected, so that a
+/// transaction can describe itself: `[:db/add :db/tx :tx/source "importer"]`.
+fn is_tx_placeholder(ident: &NamespacedKeyword) -> bool {
+    ident.namespace == "db" && ident.name == "tx"
+}
+
 /// A transaction on its way to being applied.
 #[derive(Debug)]
 pub struct Tx<'conn, 'a> {
@@ -140,6 +151,9 @@ pub struct Tx<'conn, 'a> {
 
     /// The timestamp when the transaction began to be committed.
     tx_instant: DateTime<Utc>,
+
+    /// How to convert existing values if the transaction alters an attribute's `:db/valueType`.
+    value_type_coercion: ValueTypeCoercion,
 }
 
 impl<'conn, 'a> Tx<'conn, 'a> {
@@ -157,6 +171,7 @@ impl<'conn, 'a> Tx<'conn, 'a> {
             schema: schema,
             tx_id: tx_id,
             tx_instant: tx_instant,
+            value_type_coercion: ValueTypeCoercion::default(),
         }
     }
 
@@ -177,20 +192,27 @@ impl<'conn, 'a> Tx<'conn, 'a> {
         // Lookup in the store.
         let av_map: AVMap = self.store.resolve_avs(&av_pairs[..])?;
 
-        // Map id->entid.
-        let mut temp_id_map: TempIdMap = TempIdMap::default();
+        // Map id->[a entid], keeping the attribute so that we can explain any conflict.  BTreeMap
+        // rather than HashMap so that we report conflicts deterministically.
+        let mut upserts: BTreeMap<TempIdHandle, BTreeSet<(Entid, Entid)>> = BTreeMap::default();
         for &(ref temp_id, ref av_pair) in temp_id_avs {
             if let Some(n) = av_map.get(&av_pair) {
-                if let Some(&KnownEntid(previous_n)) = temp_id_map.get(&*temp_id) {
-                    if *n != previous_n {
-                        // Conflicting upsert!  TODO: collect conflicts and give more details on what failed this transaction.
-                        bail!(ErrorKind::NotYetImplemented(format!("Conflicting upsert: tempid '{}' resolves to more than one entid: {:?}, {:?}", temp_id, previous_n, n))) // XXX
-                    }
-                }
-                temp_id_map.insert(temp_id.clone(), KnownEntid(*n));
+                upserts.entry(temp_id.clone()).or_insert_with(BTreeSet::default).insert((av_pair.0, *n));
             }
         }
 
+        // Map id->entid.
+        let mut temp_id_map: TempIdMap = TempIdMap::default();
+        for (temp_id, resolutions) in upserts {
+            let entids: BTreeSet<Entid> = resolutions.iter().map(|&(_, e)| e).collect();
+            if entids.len() > 1 {
+                // Conflicting upsert!
+                bail!(ErrorKind::ConflictingUpsert(temp_id.to_string(), resolutions.into_iter().collect()));
+            }
+            let n = *entids.iter().next().expect("every tempid in upserts resolved to something");
+            temp_id_map.insert(temp_id, KnownEntid(n));
+        }
+
         Ok((temp_id_map))
     }
 
@@ -199,20 +221,26 @@ impl<'conn, 'a> Tx<'conn, 'a> {
     ///
     /// The `Term` instances produce share interned TempId and LookupRef handles, and we return the
     /// interned handle sets so that consumers can ensure all handles are used appropriately.
-    fn entities_into_terms_with_temp_ids_and_lookup_refs<I>(&self, entities: I) -> Result<(Vec<TermWithTempIdsAndLookupRefs>, intern_set::InternSet<TempId>, intern_set::InternSet<AVPair>)> where I: IntoIterator<Item=Entity> {
+    ///
+    /// We also return the preconditions of any `:db.fn/cas` entities and the targets of any
+    /// `:db.fn/retractEntity` entities, which can only be dealt with against the store once lookup
+    /// refs are resolved.
+    fn entities_into_terms_with_temp_ids_and_lookup_refs<I>(&self, entities: I) -> Result<(Vec<TermWithTempIdsAndLookupRefs>, Vec<CasCheck<KnownEntidOr<LookupRefOrTempId>>>, Vec<KnownEntidOr<LookupRefOrTempId>>, intern_set::InternSet<TempId>, intern_set::InternSet<AVPair>)> where I: IntoIterator<Item=Entity> {
         struct InProcess<'a> {
             partition_map: &'a PartitionMap,
             schema: &'a Schema,
+            tx_id: Entid,
             mentat_id_count: i64,
             temp_ids: intern_set::InternSet<TempId>,
             lookup_refs: intern_set::InternSet<AVPair>,
         }
 
         impl<'a> InProcess<'a> {
-            fn with_schema_and_partition_map(schema: &'a Schema, partition_map: &'a PartitionMap) -> InProcess<'a> {
+            fn with_schema_and_partition_map(schema: &'a Schema, partition_map: &'a PartitionMap, tx_id: Entid) -> InProcess<'a> {
                 InProcess {
                     partition_map,
                     schema,
+                    tx_id,
                     mentat_id_count: 0,
                     temp_ids: intern_set::InternSet::new(),
                     lookup_refs: intern_set::InternSet::new(),
@@ -263,6 +291,7 @@ impl<'conn, 'a> Tx<'conn, 'a> {
                     entmod::EntidOrLookupRefOrTempId::Entid(e) => {
                         let e = match e {
                             entmod::Entid::Entid(ref e) => self.ensure_entid_exists(*e)?,
+                            entmod::Entid::Ident(ref e) if is_tx_placeholder(e) => KnownEntid(self.tx_id),
                             entmod::Entid::Ident(ref e) => self.ensure_ident_exists(&e)?,
                         };
                         Ok(Either::Left(e))
@@ -333,7 +362,7 @@ impl<'conn, 'a> Tx<'conn, 'a> {
             }
         }
 
-        let mut in_process = InProcess::with_schema_and_partition_map(&self.schema, &self.partition_map);
+        let mut in_process = InProcess::with_schema_and_partition_map(&self.schema, &self.partition_map, self.tx_id);
 
         // We want to handle entities in the order they're given to us, while also "exploding" some
         // entities into many.  We therefore push the initial entities onto the back of the deque,
@@ -342,6 +371,8 @@ impl<'conn, 'a> Tx<'conn, 'a> {
         deque.extend(entities);
 
         let mut terms: Vec<TermWithTempIdsAndLookupRefs> = Vec::with_capacity(deque.len());
+        let mut cas_checks: Vec<CasCheck<KnownEntidOr<LookupRefOrTempId>>> = vec![];
+        let mut retract_entities: Vec<KnownEntidOr<LookupRefOrTempId>> = vec![];
 
         while let Some(entity) = deque.pop_front() {
             match entity {
@@ -376,6 +407,8 @@ impl<'conn, 'a> Tx<'conn, 'a> {
                             entmod::AtomOrLookupRefOrVectorOrMapNotation::Atom(v) => {
                                 if attribute.value_type == ValueType::Ref && v.inner.is_text() {
                                     Either::Right(LookupRefOrTempId::TempId(in_process.intern_temp_id(v.inner.as_text().cloned().map(TempId::External).unwrap())))
+                                } else if attribute.value_type == ValueType::Ref && v.inner.as_namespaced_keyword().map_or(false, is_tx_placeholder) {
+                                    Either::Left(TypedValue::Ref(self.tx_id))
                                 } else {
                                     // Here is where we do schema-aware typechecking: we either assert that
                                     // the given value is in the attribute's value set, or (in limited
@@ -483,9 +516,48 @@ impl<'conn, 'a> Tx<'conn, 'a> {
                         terms.push(Term::AddOrRetract(op, e, a, v));
                     }
                 },
+
+                Entity::Cas { e, a, old, new } => {
+                    let a = in_process.entity_a_into_term_a(a)?;
+                    let attribute = self.schema.require_attribute_for_entid(a)?;
+                    if attribute.multival {
+                        bail!(ErrorKind::NotYetImplemented(format!("Cannot :db.fn/cas attribute {} that is not :db.cardinality :db.cardinality/one", a)));
+                    }
+
+                    // Just as for :db/add, both values must be in -- or coerce into -- the
+                    // attribute's value set.  nil is the exception: it means "no value".
+                    let old = old.without_spans();
+                    let expected: Option<TypedValue> = if old.is_nil() {
+                        None
+                    } else {
+                        Some(self.schema.to_typed_value(&old, attribute.value_type)?)
+                    };
+                    let new: TypedValue = self.schema.to_typed_value(&new.without_spans(), attribute.value_type)?;
+
+                    let e = in_process.entity_e_into_term_e(e)?;
+                    if let Either::Right(LookupRefOrTempId::TempId(_)) = e {
+                        // An entity that doesn't exist yet has no value to compare against.
+                        bail!(ErrorKind::NotYetImplemented(format!("Cannot :db.fn/cas attribute {} of a tempid", a)));
+                    }
+
+                    cas_checks.push(CasCheck {
+                        e: e.clone(),
+                        a: a,
+                        expected: expected,
+                    });
+                    terms.push(Term::AddOrRetract(OpType::Add, e, a, Either::Left(new)));
+                },
+
+                Entity::RetractEntity { e } => {
+                    let e = in_process.entity_e_into_term_e(e)?;
+                    if let Either::Right(LookupRefOrTempId::TempId(ref tempid)) = e {
+                        bail!(ErrorKind::NotYetImplemented(format!("Cannot :db.fn/retractEntity tempid {}", tempid)));
+                    }
+                    retract_entities.push(e);
+                },
             }
         };
-        Ok((terms, in_process.temp_ids, in_process.lookup_refs))
+        Ok((terms, cas_checks, retract_entities, in_process.temp_ids, in_process.lookup_refs))
     }
 
     /// Pipeline stage 2: rewrite `Term` instances with lookup refs into `Term` instances without
@@ -504,6 +576,83 @@ impl<'conn, 'a> Tx<'conn, 'a> {
         }).collect::<Result<Vec<_>>>()
     }
 
+    /// Verify each `:db.fn/cas` precondition against the store, failing with `CasFailed` for the
+    /// first that doesn't hold.
+    fn check_cas<I>(&self, lookup_ref_map: &AVMap, checks: I) -> Result<()> where I: IntoIterator<Item=CasCheck<KnownEntidOr<LookupRefOrTempId>>> {
+        for check in checks {
+            let e = match replace_lookup_ref(lookup_ref_map, check.e, |x| KnownEntid(x))? {
+                Either::Left(e) => e,
+                // Tempids were rejected when we first saw the :db.fn/cas.
+                Either::Right(_) => unreachable!(),
+            };
+
+            let actual = self.store.resolve_ea(e.0, check.a)?;
+            if actual != check.expected {
+                bail!(ErrorKind::CasFailed(e.0, check.a, check.expected, actual));
+            }
+        }
+        Ok(())
+    }
+
+    /// Find every [e a v] datom asserted for each of `entities`, and -- following `:db/isComponent`
+    /// attributes, but not other refs -- for each of their component entities, recursively.
+    fn expand_retract_entities<I>(&self, lookup_ref_map: &AVMap, entities: I) -> Result<BTreeSet<(Entid, Entid, TypedValue)>> where I: IntoIterator<Item=KnownEntidOr<LookupRefOrTempId>> {
+        let mut retracted: BTreeSet<(Entid, Entid, TypedValue)> = BTreeSet::default();
+        let mut seen: BTreeSet<Entid> = BTreeSet::default();
+        let mut pending: Vec<Entid> = vec![];
+
+        for e in entities {
+            match replace_lookup_ref(lookup_ref_map, e, |x| KnownEntid(x))? {
+                Either::Left(KnownEntid(e)) => pending.push(e),
+                // Tempids were rejected when we first saw the :db.fn/retractEntity.
+                Either::Right(_) => unreachable!(),
+            }
+        }
+
+        while let Some(e) = pending.pop() {
+            // A component entity might be reachable more than once.
+            if !seen.insert(e) {
+                continue;
+            }
+
+            for (a, v) in self.store.resolve_e(e)? {
+                if let TypedValue::Ref(child) = v {
+                    if self.schema.attribute_for_entid(a).map_or(false, |attribute| attribute.component) {
+                        pending.push(child);
+                    }
+                }
+                retracted.insert((e, a, v));
+            }
+        }
+
+        Ok(retracted)
+    }
+
+    /// Re-running a schema install names each attribute by a tempid, which upserts via its
+    /// `:db/ident` to the attribute that's already installed.  Re-asserting the same facets is then
+    /// a no-op, but asserting a different `:db/valueType` is almost certainly a mistake, so we
+    /// reject it.  An attribute's value type can still be altered by naming it by entid or ident.
+    fn check_attribute_redefinitions(&self, upserted: &BTreeSet<Entid>, terms: &[TermWithoutTempIds]) -> Result<()> {
+        for term in terms {
+            match term {
+                &Term::AddOrRetract(OpType::Add, KnownEntid(e), entids::DB_VALUE_TYPE, TypedValue::Ref(value_type)) if upserted.contains(&e) => {
+                    let attribute = match self.schema.attribute_for_entid(e) {
+                        Some(attribute) => attribute,
+                        None => continue,
+                    };
+                    let same = self.schema.get_ident(value_type).map_or(false, |ident| {
+                        attribute.value_type.to_edn_value() == edn::Value::NamespacedKeyword(ident.clone())
+                    });
+                    if !same {
+                        bail!(ErrorKind::ConflictingAttributeDefinition(e, attribute.value_type));
+                    }
+                },
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+
     /// Transact the given `entities` against the store.
     ///
     /// This approach is explained in https://github.com/mozilla/mentat/wiki/Transacting.
@@ -513,18 +662,29 @@ impl<'conn, 'a> Tx<'conn, 'a> {
         let mut tempids: BTreeMap<TempId, KnownEntid> = BTreeMap::default();
 
         // Pipeline stage 1: entities -> terms with tempids and lookup refs.
-        let (terms_with_temp_ids_and_lookup_refs, tempid_set, lookup_ref_set) = self.entities_into_terms_with_temp_ids_and_lookup_refs(entities)?;
+        let (terms_with_temp_ids_and_lookup_refs, cas_checks, retract_entities, tempid_set, lookup_ref_set) = self.entities_into_terms_with_temp_ids_and_lookup_refs(entities)?;
 
         // Pipeline stage 2: resolve lookup refs -> terms with tempids.
         let lookup_ref_avs: Vec<&(i64, TypedValue)> = lookup_ref_set.inner.iter().map(|rc| &**rc).collect();
         let lookup_ref_map: AVMap = self.store.resolve_avs(&lookup_ref_avs[..])?;
 
-        let terms_with_temp_ids = self.resolve_lookup_refs(&lookup_ref_map, terms_with_temp_ids_and_lookup_refs)?;
+        let mut terms_with_temp_ids = self.resolve_lookup_refs(&lookup_ref_map, terms_with_temp_ids_and_lookup_refs)?;
+
+        // Now that we know the entities involved, abort if any :db.fn/cas doesn't find the value
+        // it expects.
+        self.check_cas(&lookup_ref_map, cas_checks)?;
+
+        // Likewise, we can now find the datoms that :db.fn/retractEntity retracts.
+        let retracted = self.expand_retract_entities(&lookup_ref_map, retract_entities)?;
+        terms_with_temp_ids.extend(retracted.iter().map(|&(e, a, ref v)| Term::AddOrRetract(OpType::Retract, Either::Left(KnownEntid(e)), a, Either::Left(v.clone()))));
 
         // Pipeline stage 3: upsert tempids -> terms without tempids or lookup refs.
         // Now we can collect upsert populations.
         let (mut generation, inert_terms) = Generation::from(terms_with_temp_ids, &self.schema)?;
 
+        // The existing entities to which tempids upserted.  See `check_attribute_redefinitions`.
+        let mut upserted: BTreeSet<Entid> = BTreeSet::default();
+
         // And evolve them forward.
         while generation.can_evolve() {
             // Evolve further.
@@ -545,6 +705,7 @@ impl<'conn, 'a> Tx<'conn, 'a> {
                 // generation step; in this case, the transaction will fail.)
                 let previous = tempids.insert((*tempid).clone(), entid);
                 assert!(previous.is_none());
+                upserted.insert(entid.0);
             }
         }
 
@@ -552,7 +713,7 @@ impl<'conn, 'a> Tx<'conn, 'a> {
         let unresolved_temp_ids: BTreeSet<TempIdHandle> = generation.temp_ids_in_allocations();
 
         // TODO: track partitions for temporary IDs.
-        let entids = self.partition_map.allocate_entids(":db.part/user", unresolved_temp_ids.len());
+        let entids = self.partition_map.allocate_entids(":db.part/user", unresolved_temp_ids.len())?;
 
         let temp_id_allocations: TempIdMap = unresolved_temp_ids.into_iter()
                                                                 .zip(entids.map(|e| KnownEntid(e)))
@@ -588,7 +749,9 @@ impl<'conn, 'a> Tx<'conn, 'a> {
                                                     final_populations.allocated,
                                                     inert_terms.into_iter().map(|term| term.unwrap()).collect()].concat();
 
-        { // TODO: Don't use this block to scope borrowing the schema; instead, extract a helper function.
+        self.check_attribute_redefinitions(&upserted, &final_terms)?;
+
+        let datoms = { // TODO: Don't use this block to scope borrowing the schema; instead, extract a helper function.
 
         // Assertions that are :db.cardinality/one and not :db.fulltext.
         let mut non_fts_one: Vec<db::ReducedEntity> = vec![];
@@ -652,8 +815,8 @@ impl<'conn, 'a> Tx<'conn, 'a> {
             self.store.insert_fts_searches(&fts_many[..], db::SearchType::Exact)?;
         }
 
-        self.store.commit_transaction(self.tx_id)?;
-        }
+        self.store.commit_transaction(self.tx_id)?
+        };
 
         db::update_partition_map(self.store, &self.partition_map)?;
 
@@ -672,7 +835,7 @@ impl<'conn, 'a> Tx<'conn, 'a> {
             if new_schema != *self.schema_for_mutation {
                 let old_schema = (*self.schema_for_mutation).clone(); // Clone the original Schema for comparison.
                 *self.schema_for_mutation.to_mut() = new_schema; // Store the new Schema.
-                db::update_metadata(self.store, &old_schema, &*self.schema_for_mutation, &metadata_report)?;
+                db::update_metadata(self.store, &old_schema, &*self.schema_for_mutation, &metadata_report, self.value_type_coercion)?;
             }
         }
 
@@ -680,6 +843,8 @@ impl<'conn, 'a> Tx<'conn, 'a> {
             tx_id: self.tx_id,
             tx_instant: self.tx_instant,
             tempids: tempids,
+            retracted: retracted,
+            datoms: datoms,
         })
     }
 }
@@ -690,19 +855,32 @@ impl<'conn, 'a> Tx<'conn, 'a> {
 // TODO: move this to the transactor layer.
 pub fn transact<'conn, 'a, I>(
     conn: &'conn rusqlite::Connection,
-    mut partition_map: PartitionMap,
+    partition_map: PartitionMap,
     schema_for_mutation: &'a Schema,
     schema: &'a Schema,
     entities: I) -> Result<(TxReport, PartitionMap, Option<Schema>)> where I: IntoIterator<Item=Entity> {
+    transact_with_coercion(conn, partition_map, schema_for_mutation, schema, entities, ValueTypeCoercion::default())
+}
+
+/// Like `transact`, but converting the existing values of any attribute whose `:db/valueType` is
+/// altered as `coercion` allows.
+pub fn transact_with_coercion<'conn, 'a, I>(
+    conn: &'conn rusqlite::Connection,
+    mut partition_map: PartitionMap,
+    schema_for_mutation: &'a Schema,
+    schema: &'a Schema,
+    entities: I,
+    coercion: ValueTypeCoercion) -> Result<(TxReport, PartitionMap, Option<Schema>)> where I: IntoIterator<Item=Entity> {
     // Eventually, this function will be responsible for managing a SQLite transaction.  For
     // now, it's just about the tx details.
 
     let tx_instant = ::now(); // Label the transaction with the timestamp when we first see it: leading edge.
-    let tx_id = partition_map.allocate_entid(":db.part/tx");
+    let tx_id = partition_map.allocate_entid(":db.part/tx")?;
 
     conn.begin_tx_application()?;
 
     let mut tx = Tx::new(conn, partition_map, schema_for_mutation, schema, tx_id, tx_instant);
+    tx.value_type_coercion = coercion;
 
     let report = tx.transact_entities(entities)?;
 
@@ -713,3 +891,39 @@ pub fn transact<'conn, 'a, I>(
     };
     Ok((report, tx.partition_map, next_schema))
 }
+
+/// The entities that retract the attribute `attribute`: its `:db/ident` and every one of its
+/// schema characteristics.  Transacting them fails with `CannotRetractAttributeInUse` if any datom
+/// still uses the attribute, unless `force` is true, in which case the entities also retract every
+/// such datom first.
+pub fn attribute_retraction(conn: &rusqlite::Connection, schema: &Schema, attribute: Entid, force: bool) -> Result<Vec<Entity>> {
+    schema.require_attribute_for_entid(attribute)?;
+
+    // `all_datoms` gives us the text, rather than the rowid, of fulltext values.
+    let mut datoms: Vec<(Entid, Entid, TypedValue)> = vec![];
+    if force {
+        datoms.extend(attribute_datoms(conn, "SELECT e, a, v, value_type_tag FROM all_datoms WHERE a = ? ORDER BY e, v", attribute)?);
+    }
+    let s = format!("SELECT e, a, v, value_type_tag FROM all_datoms WHERE e = ? AND a IN {} ORDER BY a", entids::METADATA_SQL_LIST.as_str());
+    datoms.extend(attribute_datoms(conn, s.as_str(), attribute)?);
+
+    Ok(datoms.into_iter().map(|(e, a, v)| Entity::AddOrRetract {
+        op: OpType::Retract,
+        e: entmod::EntidOrLookupRefOrTempId::Entid(entmod::Entid::Entid(e)),
+        a: entmod::Entid::Entid(a),
+        v: entmod::AtomOrLookupRefOrVectorOrMapNotation::Atom(v.to_edn_value_pair().0.with_spans()),
+    }).collect())
+}
+
+fn attribute_datoms(conn: &rusqlite::Connection, sql: &str, attribute: Entid) -> Result<Vec<(Entid, Entid, TypedValue)>> {
+    let mut stmt = conn.prepare(sql)?;
+    let mut rows = stmt.query(&[&attribute as &ToSql])?;
+    let mut datoms = vec![];
+    while let Some(row) = rows.next() {
+        let row = row?;
+        datoms.push((row.get_checked(0)?,
+                     row.get_checked(1)?,
+                     TypedValue::from_sql_value_pair(row.get_checked(2)?, row.get_checked(3)?)?));
+    }
+    Ok(datoms)
+}
@@ -0,0 +1,110 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Reading back the transaction log, one transaction at a time, for consumers like a sync layer
+//! that need to replay what's changed since some point.
+
+use rusqlite;
+use rusqlite::types::ToSql;
+
+use mentat_core::{
+    Entid,
+    TypedValue,
+};
+
+use db;
+use entids;
+use errors::Result;
+use types::TxReport;
+
+/// An iterator over the transactions committed after a given transaction, in order. See
+/// `transactions_after`.
+pub struct TxLog<'conn> {
+    conn: &'conn rusqlite::Connection,
+
+    /// The last transaction we've yielded, or the one we were asked to start after.
+    after: Entid,
+
+    /// The last transaction committed when the log was opened. Nothing after it is yielded.
+    last: Option<Entid>,
+}
+
+impl<'conn> TxLog<'conn> {
+    fn next_tx(&self, last: Entid) -> Result<Option<Entid>> {
+        let tx: Option<Entid> = self.conn.query_row("SELECT MIN(tx) FROM transactions WHERE tx > ? AND tx <= ?",
+                                                    &[&self.after as &ToSql, &last],
+                                                    |row| row.get(0))?;
+        Ok(tx)
+    }
+
+    fn report(&self, tx_id: Entid) -> Result<TxReport> {
+        let datoms = db::committed_datoms(self.conn, tx_id)?;
+        let tx_instant = datoms.iter()
+                               .filter_map(|&(e, a, ref v, added)| match v {
+                                   &TypedValue::Instant(ref instant) if e == tx_id && a == entids::DB_TX_INSTANT && added => Some(instant.clone()),
+                                   _ => None,
+                               })
+                               .next();
+        let tx_instant = match tx_instant {
+            Some(tx_instant) => tx_instant,
+            None => bail!(format!("Transaction {} has no :db/txInstant in the log", tx_id)),
+        };
+        Ok(TxReport {
+            tx_id: tx_id,
+            tx_instant: tx_instant,
+            tempids: Default::default(),
+            retracted: Default::default(),
+            datoms: datoms,
+        })
+    }
+}
+
+impl<'conn> Iterator for TxLog<'conn> {
+    type Item = Result<TxReport>;
+
+    fn next(&mut self) -> Option<Result<TxReport>> {
+        let last = match self.last {
+            Some(last) => last,
+            None => return None,
+        };
+        match self.next_tx(last) {
+            Ok(Some(tx_id)) => {
+                self.after = tx_id;
+                Some(self.report(tx_id))
+            },
+            Ok(None) => {
+                self.last = None;
+                None
+            },
+            Err(e) => {
+                // Don't keep failing.
+                self.last = None;
+                Some(Err(e))
+            },
+        }
+    }
+}
+
+/// Every transaction committed after `tx_id`, in order, each read from the log only as the
+/// iterator reaches it.
+///
+/// The log is fixed as of this call: transactions committed while iterating, even on `conn`
+/// itself, aren't yielded. Each report's datoms are those recorded in the log, retractions
+/// first, including the transaction's own `:db/txInstant`; the log doesn't record tempids, or
+/// which datoms were retracted by `:db.fn/retractEntity`, and it forgets the values of
+/// `:db/noHistory` attributes, so none of those are reported.
+pub fn transactions_after<'conn>(conn: &'conn rusqlite::Connection, tx_id: Entid) -> Result<TxLog<'conn>> {
+    let last: Option<Entid> = conn.query_row("SELECT MAX(tx) FROM transactions", &[], |row| row.get(0))?;
+    Ok(TxLog {
+        conn: conn,
+        after: tx_id,
+        last: last,
+    })
+}
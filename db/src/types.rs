@@ -11,7 +11,10 @@
 #![allow(dead_code)]
 
 use std::collections::HashMap;
-use std::collections::BTreeMap;
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
 
 extern crate mentat_core;
 
@@ -31,19 +34,32 @@ pub use self::mentat_core::{
 pub struct Partition {
     /// The first entid in the partition.
     pub start: i64,
+    /// One past the last entid that can be allocated in the partition.
+    pub end: i64,
     /// The next entid to be allocated in the partition.
     pub index: i64,
 }
 
 impl Partition {
-    pub fn new(start: i64, next: i64) -> Partition {
-        assert!(start <= next, "A partition represents a monotonic increasing sequence of entids.");
-        Partition { start: start, index: next }
+    pub fn new(start: i64, end: i64, next: i64) -> Partition {
+        assert!(start <= next && next <= end, "A partition represents a monotonic increasing sequence of entids.");
+        Partition { start: start, end: end, index: next }
     }
 
+    /// Has `e` been allocated from this partition?
     pub fn contains_entid(&self, e: i64) -> bool {
         (e >= self.start) && (e < self.index)
     }
+
+    /// Could an entid be allocated from both this partition and `other`?
+    pub fn overlaps(&self, other: &Partition) -> bool {
+        (self.start < other.end) && (other.start < self.end)
+    }
+
+    /// How many entids are left to allocate.
+    pub fn remaining(&self) -> i64 {
+        self.end - self.index
+    }
 }
 
 /// Map partition names to `Partition` instances.
@@ -83,7 +99,6 @@ pub type AVPair = (Entid, TypedValue);
 pub type AVMap<'a> = HashMap<&'a AVPair, Entid>;
 
 /// A transaction report summarizes an applied transaction.
-// TODO: include map of resolved tempids.
 #[derive(Clone, Debug, Eq, Hash, Ord, PartialOrd, PartialEq)]
 pub struct TxReport {
     /// The transaction ID of the transaction.
@@ -98,4 +113,13 @@ pub struct TxReport {
     /// existing entid, or is allocated a new entid.  (It is possible for multiple distinct string
     /// literal tempids to all unify to a single freshly allocated entid.)
     pub tempids: BTreeMap<String, Entid>,
+
+    /// The [e a v] datoms retracted by `:db.fn/retractEntity`, including those of any component
+    /// entities that were retracted along with their parent.
+    pub retracted: BTreeSet<(Entid, Entid, TypedValue)>,
+
+    /// The [e a v added] datoms this transaction changed, retractions first.  These are the
+    /// effective changes, after tempids are resolved and upserts unified: asserting a datom that
+    /// already exists, or retracting one that doesn't, changes nothing and so isn't listed.
+    pub datoms: Vec<(Entid, Entid, TypedValue, bool)>,
 }
@@ -20,6 +20,7 @@ pub mod types;
 pub mod pretty_print;
 pub mod utils;
 pub mod matcher;
+mod tags;
 
 pub mod parse {
     include!(concat!(env!("OUT_DIR"), "/edn.rs"));
@@ -35,6 +36,7 @@ pub use uuid::Uuid;
 pub use parse::ParseError;
 pub use types::{
     FromMicros,
+    FromMillis,
     Span,
     SpannedValue,
     ToMicros,
@@ -42,3 +44,9 @@ pub use types::{
     ValueAndSpan,
 };
 pub use symbols::{Keyword, NamespacedKeyword, PlainSymbol, NamespacedSymbol};
+pub use tags::{
+    TagError,
+    TagHandler,
+    TagHandlerRegistry,
+    parse_with_tags,
+};
@@ -13,6 +13,7 @@ use pretty;
 
 use std::io;
 use std::borrow::Cow;
+use std::cmp::Ordering;
 
 use types::Value;
 
@@ -29,6 +30,21 @@ impl Value {
         self.as_doc(&pretty::BoxAllocator).1.render(width, out)
     }
 
+    /// Return a pretty string representation of this `Value` that's suitable for comparing
+    /// textually: see `write_pretty_sorted`.
+    pub fn to_pretty_sorted(&self, width: usize) -> Result<String, io::Error> {
+        let mut out = Vec::new();
+        self.write_pretty_sorted(width, &mut out)?;
+        Ok(String::from_utf8_lossy(&out).into_owned())
+    }
+
+    /// Write a pretty representation of this `Value` to the given writer, with keyword and string
+    /// keys of maps and members of sets ordered by their text, and with numbers and instants written so that
+    /// they parse back to exactly the same value.
+    pub fn write_pretty_sorted<W>(&self, width: usize, out: &mut W) -> Result<(), io::Error> where W: io::Write {
+        self.doc(&pretty::BoxAllocator, true).1.render(width, out)
+    }
+
     /// Bracket a collection of values.
     ///
     /// We aim for
@@ -37,26 +53,70 @@ impl Value {
     /// [1,
     ///  2,
     ///  3].
-    fn bracket<'a, A, T, I>(&'a self, allocator: &'a A, open: T, vs: I, close: T) -> pretty::DocBuilder<'a, A>
+    fn bracket<'a, A, T, I>(&'a self, allocator: &'a A, open: T, vs: I, close: T, sorted: bool) -> pretty::DocBuilder<'a, A>
     where A: pretty::DocAllocator<'a>, T: Into<Cow<'a, str>>, I: IntoIterator<Item=&'a Value> {
         let open = open.into();
         let n = open.len();
-        let i = vs.into_iter().map(|v| v.as_doc(allocator)).intersperse(allocator.space());
+        let i = vs.into_iter().map(|v| v.doc(allocator, sorted)).intersperse(allocator.space());
         allocator.text(open)
             .append(allocator.concat(i).nest(n))
             .append(allocator.text(close))
             .group()
     }
 
+    /// The text by which `write_pretty_sorted` orders strings, symbols, and keywords.
+    fn sort_text(&self) -> Option<Cow<str>> {
+        match *self {
+            Value::Text(ref v) => Some(Cow::Borrowed(v.as_str())),
+            Value::PlainSymbol(_) |
+            Value::NamespacedSymbol(_) |
+            Value::Keyword(_) |
+            Value::NamespacedKeyword(_) => Some(Cow::Owned(self.to_string())),
+            _ => None,
+        }
+    }
+
+    /// Strings, symbols, and keywords are ordered among one another by their text, and together
+    /// fall where strings alone usually would. Everything else is ordered as usual.
+    fn cmp_sorted(a: &Value, b: &Value) -> Ordering {
+        let text = Value::Text(String::new());
+        match (a.sort_text(), b.sort_text()) {
+            // Fall back to the usual ordering when texts collide, like the string "a" and the symbol a.
+            (Some(x), Some(y)) => x.cmp(&y).then_with(|| a.cmp(b)),
+            (Some(_), None) => text.cmp(b),
+            (None, Some(_)) => a.cmp(&text),
+            (None, None) => a.cmp(b),
+        }
+    }
+
     /// Recursively traverses this value and creates a pretty.rs document.
     /// This pretty printing implementation is optimized for edn queries
     /// readability and limited whitespace expansion.
     pub fn as_doc<'a, A>(&'a self, pp: &'a A) -> pretty::DocBuilder<'a, A>
+        where A: pretty::DocAllocator<'a> {
+        self.doc(pp, false)
+    }
+
+    fn doc<'a, A>(&'a self, pp: &'a A, sorted: bool) -> pretty::DocBuilder<'a, A>
         where A: pretty::DocAllocator<'a> {
         match *self {
-            Value::Vector(ref vs) => self.bracket(pp, "[", vs, "]"),
-            Value::List(ref vs) => self.bracket(pp, "(", vs, ")"),
-            Value::Set(ref vs) => self.bracket(pp, "#{", vs, "}"),
+            Value::Vector(ref vs) => self.bracket(pp, "[", vs, "]", sorted),
+            Value::List(ref vs) => self.bracket(pp, "(", vs, ")", sorted),
+            Value::Set(ref vs) if sorted => {
+                let mut vs: Vec<&Value> = vs.iter().collect();
+                vs.sort_by(|a, b| Value::cmp_sorted(a, b));
+                self.bracket(pp, "#{", vs, "}", sorted)
+            },
+            Value::Set(ref vs) => self.bracket(pp, "#{", vs, "}", sorted),
+            Value::Map(ref vs) if sorted => {
+                let mut entries: Vec<(&Value, &Value)> = vs.iter().collect();
+                entries.sort_by(|&(a, _), &(b, _)| Value::cmp_sorted(a, b));
+                let xs = entries.into_iter().map(|(k, v)| k.doc(pp, sorted).append(pp.space()).append(v.doc(pp, sorted)).group()).intersperse(pp.space());
+                pp.text("{")
+                    .append(pp.concat(xs).nest(1))
+                    .append(pp.text("}"))
+                    .group()
+            },
             Value::Map(ref vs) => {
                 let xs = vs.iter().rev().map(|(k, v)| k.as_doc(pp).append(pp.space()).append(v.as_doc(pp)).group()).intersperse(pp.space());
                 pp.text("{")
@@ -70,6 +130,12 @@ impl Value {
             Value::Keyword(ref v) => pp.text(":").append(v.0.as_ref()),
             Value::Text(ref v) => pp.text("\"").append(v.as_ref()).append("\""),
             Value::Uuid(ref u) => pp.text("#uuid \"").append(u.hyphenated().to_string()).append("\""),
+            // `Display` writes 1.0 as 1, which reads back as an integer, and writes very large and
+            // very small floats out in full. The shortest representation that reads back exactly is
+            // what `Debug` writes.
+            Value::Float(ref v) if sorted && v.into_inner().is_finite() => pp.text(format!("{:?}", v.into_inner())),
+            // `Display` writes instants as "2017-01-01 12:00:00 UTC", which isn't EDN at all.
+            Value::Instant(ref v) if sorted => pp.text(format!("#inst \"{}\"", v.format("%Y-%m-%dT%H:%M:%S%.fZ"))),
             _ => pp.text(self.to_string())
         }
     }
@@ -188,4 +254,52 @@ mod test {
   [?id]
   [?id :session/keyword-bar _])]");
     }
+
+    #[test]
+    fn test_pp_sorted_map() {
+        let data = parse::value("{c 4 :b 1 \"a\" 2 :a/c 3}").unwrap().without_spans();
+
+        // Keywords, strings, and symbols are ordered by their text.
+        assert_eq!(data.to_pretty_sorted(40).unwrap(), "{:a/c 3 :b 1 \"a\" 2 c 4}");
+        assert_eq!(data.to_pretty_sorted(10).unwrap(), "\
+{:a/c 3
+ :b 1
+ \"a\" 2
+ c 4}");
+    }
+
+    #[test]
+    fn test_pp_sorted_nested() {
+        let data = parse::value("{:b #{:z :y} :a {:d 1 :c 2}}").unwrap().without_spans();
+
+        assert_eq!(data.to_pretty_sorted(20).unwrap(), "\
+{:a {:c 2 :d 1}
+ :b #{:y :z}}");
+    }
+
+    #[test]
+    fn test_pp_sorted_stable() {
+        let one = parse::value("{:b 1 :a 2 \"c\" {:y 3 :x 4}}").unwrap().without_spans();
+        let two = parse::value("{\"c\" {:x 4 :y 3} :a 2 :b 1}").unwrap().without_spans();
+
+        let expected = one.to_pretty_sorted(40).unwrap();
+        assert_eq!(one.to_pretty_sorted(40).unwrap(), expected);
+        assert_eq!(two.to_pretty_sorted(40).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_pp_sorted_round_trip() {
+        let string = "[1.0 -0.5 1e300 1.5e-7 42 -7 123456789012345678901234567890N #f NaN #f -Infinity \
+                       #inst \"2017-04-28T20:23:05.187123Z\" #inst \"2017-04-28T20:23:05Z\" \
+                       {:x {:z 1 :y 2.0} \"s\" #{3 1 2}}]";
+        let data = parse::value(string).unwrap().without_spans();
+
+        let pretty = data.to_pretty_sorted(40).unwrap();
+        assert!(pretty.contains("1.0"));
+        assert!(pretty.contains("#inst \"2017-04-28T20:23:05.187123Z\""));
+        assert!(pretty.contains("#inst \"2017-04-28T20:23:05Z\""));
+
+        // `OrderedFloat` considers NaN equal to itself.
+        assert_eq!(parse::value(pretty.as_str()).unwrap().without_spans(), data);
+    }
 }
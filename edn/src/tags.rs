@@ -0,0 +1,254 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Tagged literals, like `#geo/point [51.5 -0.12]`.
+//!
+//! The parser understands the tags that EDN and Mentat define -- `#inst`, `#uuid`, and so on. To
+//! read any other tag, register a handler for it that turns the value following the tag into the
+//! value the literal stands for, and parse with `parse_with_tags`.
+//!
+//! The generated parser can't be handed the registry directly, so `parse_with_tags` installs it in
+//! a thread local for the duration of the parse.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::mem;
+use std::rc::Rc;
+
+use parse;
+use types::{
+    Span,
+    Value,
+    ValueAndSpan,
+};
+
+/// Turns the value following a tag into the value the tagged literal stands for, or explains why
+/// it can't.
+pub type TagHandler = Rc<Fn(Value) -> Result<Value, String>>;
+
+/// The handlers for tagged literals, keyed by tag.
+#[derive(Clone, Default)]
+pub struct TagHandlerRegistry {
+    handlers: BTreeMap<String, TagHandler>,
+}
+
+impl TagHandlerRegistry {
+    pub fn new() -> TagHandlerRegistry {
+        TagHandlerRegistry::default()
+    }
+
+    /// Handle literals tagged with `tag`, which is written without its `#`, like "geo/point".
+    /// This replaces any handler already registered for `tag`.
+    pub fn register<F>(&mut self, tag: &str, handler: F) where F: Fn(Value) -> Result<Value, String> + 'static {
+        self.handlers.insert(tag.to_string(), Rc::new(handler));
+    }
+
+    pub fn is_registered(&self, tag: &str) -> bool {
+        self.handlers.contains_key(tag)
+    }
+}
+
+/// Why `parse_with_tags` failed.
+#[derive(Debug, PartialEq)]
+pub enum TagError {
+    /// The input isn't EDN.
+    ParseError(parse::ParseError),
+
+    /// No handler is registered for `tag`, which begins at `offset`.
+    UnknownTag {
+        tag: String,
+        line: usize,
+        column: usize,
+        offset: usize,
+    },
+
+    /// The handler for `tag` rejected the value that followed it.
+    InvalidTaggedValue {
+        tag: String,
+        message: String,
+        line: usize,
+        column: usize,
+        offset: usize,
+    },
+}
+
+impl fmt::Display for TagError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &TagError::ParseError(ref e) => write!(f, "{}", e),
+            &TagError::UnknownTag { ref tag, line, column, .. } => {
+                write!(f, "unknown tag #{} at line {}, column {}", tag, line, column)
+            },
+            &TagError::InvalidTaggedValue { ref tag, ref message, line, column, .. } => {
+                write!(f, "invalid value for tag #{} at line {}, column {}: {}", tag, line, column, message)
+            },
+        }
+    }
+}
+
+impl error::Error for TagError {
+    fn description(&self) -> &str {
+        match self {
+            &TagError::ParseError(_) => "EDN parse error",
+            &TagError::UnknownTag { .. } => "unknown tag",
+            &TagError::InvalidTaggedValue { .. } => "invalid tagged value",
+        }
+    }
+}
+
+/// The first tagged literal that we couldn't make sense of: where it starts, its tag, and, if the
+/// tag has a handler, the handler's complaint.
+type Failure = (usize, String, Option<String>);
+
+struct Tagging {
+    registry: TagHandlerRegistry,
+    failure: Option<Failure>,
+}
+
+thread_local! {
+    static TAGGING: RefCell<Option<Tagging>> = RefCell::new(None);
+}
+
+/// Parse `input` as a single EDN value, using `registry` to read tagged literals.
+pub fn parse_with_tags(input: &str, registry: &TagHandlerRegistry) -> Result<ValueAndSpan, TagError> {
+    // Handlers might themselves parse, so restore whatever was installed before us.
+    let tagging = Tagging {
+        registry: registry.clone(),
+        failure: None,
+    };
+    let previous = TAGGING.with(|t| mem::replace(&mut *t.borrow_mut(), Some(tagging)));
+    let result = parse::value(input);
+    let failure = TAGGING.with(|t| {
+        let mut t = t.borrow_mut();
+        let failure = t.take().and_then(|tagging| tagging.failure);
+        *t = previous;
+        failure
+    });
+
+    match (result, failure) {
+        (Ok(value), _) => Ok(value),
+        (Err(_), Some((offset, tag, message))) => {
+            let (line, column) = line_and_column(input, offset);
+            Err(match message {
+                None => TagError::UnknownTag { tag: tag, line: line, column: column, offset: offset },
+                Some(message) => TagError::InvalidTaggedValue { tag: tag, message: message, line: line, column: column, offset: offset },
+            })
+        },
+        (Err(e), None) => Err(TagError::ParseError(e)),
+    }
+}
+
+/// One-based, like the positions in a `ParseError`.
+fn line_and_column(input: &str, offset: usize) -> (usize, usize) {
+    let before = &input[..offset];
+    let line = before.matches('\n').count() + 1;
+    let column = before.chars().rev().take_while(|&c| c != '\n').count() + 1;
+    (line, column)
+}
+
+/// Called by the parser for each tagged literal it doesn't know itself.
+pub fn apply(tag: &str, value: ValueAndSpan, start: usize) -> Result<ValueAndSpan, &'static str> {
+    let span = Span::new(start, value.span.1 as usize);
+
+    // Don't hold the borrow while the handler runs.
+    let handler = TAGGING.with(|t| t.borrow().as_ref().and_then(|tagging| tagging.registry.handlers.get(tag).cloned()));
+    let outcome = match handler {
+        None => Err(None),
+        Some(handler) => handler(value.without_spans()).map_err(Some),
+    };
+
+    match outcome {
+        Ok(handled) => Ok(handled.with_span(span)),
+        Err(message) => {
+            TAGGING.with(|t| {
+                if let Some(ref mut tagging) = *t.borrow_mut() {
+                    if tagging.failure.is_none() {
+                        tagging.failure = Some((start, tag.to_string(), message));
+                    }
+                }
+            });
+            Err("tag with a handler")
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn geo_registry() -> TagHandlerRegistry {
+        let mut registry = TagHandlerRegistry::new();
+        registry.register("geo/point", |value| {
+            match value {
+                Value::Vector(ref coordinates) if coordinates.len() == 2 && coordinates.iter().all(|c| c.is_float()) => {
+                    Ok(Value::Vector(coordinates.clone()))
+                },
+                _ => Err(format!("expected [lat lng], got {}", value)),
+            }
+        });
+        registry
+    }
+
+    #[test]
+    fn test_registered_tag() {
+        let registry = geo_registry();
+        assert!(registry.is_registered("geo/point"));
+
+        let value = parse_with_tags("[:place/location #geo/point [51.5 -0.12]]", &registry).expect("parsed");
+        assert_eq!(value.without_spans(),
+                   parse::value("[:place/location [51.5 -0.12]]").unwrap().without_spans());
+    }
+
+    #[test]
+    fn test_tagged_span() {
+        let value = parse_with_tags("#geo/point [51.5 -0.12]", &geo_registry()).expect("parsed");
+        assert_eq!(value.span, Span::new(0, 23));
+    }
+
+    #[test]
+    fn test_unknown_tag() {
+        let input = "[:place/location\n #geo/polygon [[0.0 0.0]]]";
+        assert_eq!(parse_with_tags(input, &geo_registry()).unwrap_err(),
+                   TagError::UnknownTag {
+                       tag: "geo/polygon".to_string(),
+                       line: 2,
+                       column: 2,
+                       offset: 18,
+                   });
+        assert_eq!(parse_with_tags(input, &geo_registry()).unwrap_err().to_string(),
+                   "unknown tag #geo/polygon at line 2, column 2");
+
+        // Without a registry, tagged literals don't parse at all.
+        assert!(parse::value(input).is_err());
+        assert!(parse::value("#geo/point [51.5 -0.12]").is_err());
+    }
+
+    #[test]
+    fn test_invalid_tagged_value() {
+        assert_eq!(parse_with_tags("#geo/point :nowhere", &geo_registry()).unwrap_err(),
+                   TagError::InvalidTaggedValue {
+                       tag: "geo/point".to_string(),
+                       message: "expected [lat lng], got :nowhere".to_string(),
+                       line: 1,
+                       column: 1,
+                       offset: 0,
+                   });
+    }
+
+    #[test]
+    fn test_builtin_tags() {
+        // The tags the parser knows take precedence.
+        let value = parse_with_tags("[#f NaN #uuid \"550e8400-e29b-41d4-a716-446655440000\"]", &geo_registry()).expect("parsed");
+        assert_eq!(value.without_spans(),
+                   parse::value("[#f NaN #uuid \"550e8400-e29b-41d4-a716-446655440000\"]").unwrap().without_spans());
+    }
+}
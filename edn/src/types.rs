@@ -37,6 +37,7 @@ pub enum Value {
     Float(OrderedFloat<f64>),
     Text(String),
     Uuid(Uuid),
+    Bytes(Vec<u8>),
     PlainSymbol(symbols::PlainSymbol),
     NamespacedSymbol(symbols::NamespacedSymbol),
     Keyword(symbols::Keyword),
@@ -66,6 +67,7 @@ pub enum SpannedValue {
     Float(OrderedFloat<f64>),
     Text(String),
     Uuid(Uuid),
+    Bytes(Vec<u8>),
     PlainSymbol(symbols::PlainSymbol),
     NamespacedSymbol(symbols::NamespacedSymbol),
     Keyword(symbols::Keyword),
@@ -151,6 +153,7 @@ impl From<SpannedValue> for Value {
             SpannedValue::Float(v) => Value::Float(v),
             SpannedValue::Text(v) => Value::Text(v),
             SpannedValue::Uuid(v) => Value::Uuid(v),
+            SpannedValue::Bytes(v) => Value::Bytes(v),
             SpannedValue::PlainSymbol(v) => Value::PlainSymbol(v),
             SpannedValue::NamespacedSymbol(v) => Value::NamespacedSymbol(v),
             SpannedValue::Keyword(v) => Value::Keyword(v),
@@ -163,6 +166,36 @@ impl From<SpannedValue> for Value {
     }
 }
 
+impl Value {
+    /// Attach `span` to this value and to every value within it, as when a single span of the
+    /// input stands for the whole value.
+    pub fn with_span(self, span: Span) -> ValueAndSpan {
+        let inner = match self {
+            Value::Nil => SpannedValue::Nil,
+            Value::Boolean(v) => SpannedValue::Boolean(v),
+            Value::Integer(v) => SpannedValue::Integer(v),
+            Value::Instant(v) => SpannedValue::Instant(v),
+            Value::BigInteger(v) => SpannedValue::BigInteger(v),
+            Value::Float(v) => SpannedValue::Float(v),
+            Value::Text(v) => SpannedValue::Text(v),
+            Value::Uuid(v) => SpannedValue::Uuid(v),
+            Value::Bytes(v) => SpannedValue::Bytes(v),
+            Value::PlainSymbol(v) => SpannedValue::PlainSymbol(v),
+            Value::NamespacedSymbol(v) => SpannedValue::NamespacedSymbol(v),
+            Value::Keyword(v) => SpannedValue::Keyword(v),
+            Value::NamespacedKeyword(v) => SpannedValue::NamespacedKeyword(v),
+            Value::Vector(v) => SpannedValue::Vector(v.into_iter().map(|x| x.with_span(span)).collect()),
+            Value::List(v) => SpannedValue::List(v.into_iter().map(|x| x.with_span(span)).collect()),
+            Value::Set(v) => SpannedValue::Set(v.into_iter().map(|x| x.with_span(span)).collect()),
+            Value::Map(v) => SpannedValue::Map(v.into_iter().map(|(x, y)| (x.with_span(span), y.with_span(span))).collect()),
+        };
+        ValueAndSpan {
+            inner: inner,
+            span: span,
+        }
+    }
+}
+
 /// Creates `from_$TYPE` helper functions for Value and SpannedValue,
 /// like `from_float()` or `from_ordered_float()`.
 macro_rules! def_from {
@@ -298,6 +331,7 @@ macro_rules! def_common_value_methods {
         def_is!(is_float, $t::Float(_));
         def_is!(is_text, $t::Text(_));
         def_is!(is_uuid, $t::Uuid(_));
+        def_is!(is_bytes, $t::Bytes(_));
         def_is!(is_symbol, $t::PlainSymbol(_));
         def_is!(is_namespaced_symbol, $t::NamespacedSymbol(_));
         def_is!(is_keyword, $t::Keyword(_));
@@ -322,6 +356,7 @@ macro_rules! def_common_value_methods {
         def_as_ref!(as_ordered_float, $t::Float, OrderedFloat<f64>);
         def_as_ref!(as_text, $t::Text, String);
         def_as_ref!(as_uuid, $t::Uuid, Uuid);
+        def_as_ref!(as_bytes, $t::Bytes, Vec<u8>);
         def_as_ref!(as_symbol, $t::PlainSymbol, symbols::PlainSymbol);
         def_as_ref!(as_namespaced_symbol, $t::NamespacedSymbol, symbols::NamespacedSymbol);
         def_as_ref!(as_keyword, $t::Keyword, symbols::Keyword);
@@ -339,6 +374,7 @@ macro_rules! def_common_value_methods {
         def_into!(into_float, $t::Float, f64, |v: OrderedFloat<f64>| v.into_inner());
         def_into!(into_text, $t::Text, String,);
         def_into!(into_uuid, $t::Uuid, Uuid,);
+        def_into!(into_bytes, $t::Bytes, Vec<u8>,);
         def_into!(into_symbol, $t::PlainSymbol, symbols::PlainSymbol,);
         def_into!(into_namespaced_symbol, $t::NamespacedSymbol, symbols::NamespacedSymbol,);
         def_into!(into_keyword, $t::Keyword, symbols::Keyword,);
@@ -370,14 +406,15 @@ macro_rules! def_common_value_methods {
                 $t::Instant(_) => 5,
                 $t::Text(_) => 6,
                 $t::Uuid(_) => 7,
-                $t::PlainSymbol(_) => 8,
-                $t::NamespacedSymbol(_) => 9,
-                $t::Keyword(_) => 10,
-                $t::NamespacedKeyword(_) => 11,
-                $t::Vector(_) => 12,
-                $t::List(_) => 13,
-                $t::Set(_) => 14,
-                $t::Map(_) => 15,
+                $t::Bytes(_) => 8,
+                $t::PlainSymbol(_) => 9,
+                $t::NamespacedSymbol(_) => 10,
+                $t::Keyword(_) => 11,
+                $t::NamespacedKeyword(_) => 12,
+                $t::Vector(_) => 13,
+                $t::List(_) => 14,
+                $t::Set(_) => 15,
+                $t::Map(_) => 16,
             }
         }
 
@@ -391,6 +428,7 @@ macro_rules! def_common_value_methods {
                 $t::Float(_) => false,
                 $t::Text(_) => false,
                 $t::Uuid(_) => false,
+                $t::Bytes(_) => false,
                 $t::PlainSymbol(_) => false,
                 $t::NamespacedSymbol(_) => false,
                 $t::Keyword(_) => false,
@@ -429,6 +467,7 @@ macro_rules! def_common_value_ord {
             (&$t::Float(ref a), &$t::Float(ref b)) => b.cmp(a),
             (&$t::Text(ref a), &$t::Text(ref b)) => b.cmp(a),
             (&$t::Uuid(ref a), &$t::Uuid(ref b)) => b.cmp(a),
+            (&$t::Bytes(ref a), &$t::Bytes(ref b)) => b.cmp(a),
             (&$t::PlainSymbol(ref a), &$t::PlainSymbol(ref b)) => b.cmp(a),
             (&$t::NamespacedSymbol(ref a), &$t::NamespacedSymbol(ref b)) => b.cmp(a),
             (&$t::Keyword(ref a), &$t::Keyword(ref b)) => b.cmp(a),
@@ -468,6 +507,13 @@ macro_rules! def_common_value_display {
             // TODO: EDN escaping.
             $t::Text(ref v) => write!($f, "\"{}\"", v),
             $t::Uuid(ref u) => write!($f, "#uuid \"{}\"", u.hyphenated().to_string()),
+            $t::Bytes(ref v) => {
+                write!($f, "#bytes \"")?;
+                for b in v {
+                    write!($f, "{:02x}", b)?;
+                }
+                write!($f, "\"")
+            },
             $t::PlainSymbol(ref v) => v.fmt($f),
             $t::NamespacedSymbol(ref v) => v.fmt($f),
             $t::Keyword(ref v) => v.fmt($f),
@@ -567,6 +613,24 @@ impl FromMicros for DateTime<Utc> {
     }
 }
 
+pub trait FromMillis: Sized {
+    /// Returns `None` if `ts` is outside the representable range.
+    fn from_millis(ts: i64) -> Option<Self>;
+}
+
+impl FromMillis for DateTime<Utc> {
+    fn from_millis(ts: i64) -> Option<Self> {
+        // Round towards negative infinity, so that the sub-second part is never negative.
+        let mut secs = ts / 1_000;
+        let mut millis = ts % 1_000;
+        if millis < 0 {
+            secs -= 1;
+            millis += 1_000;
+        }
+        Utc.timestamp_opt(secs, (millis as u32) * 1_000_000).single()
+    }
+}
+
 pub trait ToMicros {
     fn to_micros(&self) -> i64;
 }
@@ -608,6 +672,17 @@ mod test {
         assert_eq!(dt.to_micros(), ts_micros);
     }
 
+    #[test]
+    fn test_from_millis() {
+        let dt = DateTime::<Utc>::from_millis(1493399581314).expect("in range");
+        assert_eq!(dt.to_micros(), 1493399581314000);
+
+        let before_epoch = DateTime::<Utc>::from_millis(-1).expect("in range");
+        assert_eq!(before_epoch.to_micros(), -1000);
+
+        assert!(DateTime::<Utc>::from_millis(i64::max_value()).is_none());
+    }
+
     #[test]
     fn test_value_from() {
         assert_eq!(Value::from_float(42f64), Value::Float(OrderedFloat::from(42f64)));
@@ -195,6 +195,38 @@ fn test_integer() {
     assert!(integer("nil").is_err());
 }
 
+#[test]
+fn test_invalid_integer() {
+    // Too large for 64 bits.  Such integers must be written as bigints.
+    assert!(integer("99999999999999999999").is_err());
+    assert!(hexinteger("0xFFFFFFFFFFFFFFFFFF").is_err());
+    assert!(octalinteger("01777777777777777777777").is_err());
+    assert!(parse::bigint("99999999999999999999N").is_ok());
+
+    // Digits must be valid in their base.
+    assert!(basedinteger("2r102").is_err());
+
+    // And a number can't run into a symbol.
+    assert!(parse::value("[12abc]").is_err());
+    assert!(parse::value("[2r102]").is_err());
+    assert!(parse::value("[1.5x]").is_err());
+    assert!(parse::value("[0xFG]").is_err());
+}
+
+#[test]
+fn test_parse_error_position() {
+    // A missing closing bracket is reported where the input ends.
+    let e = parse::value("[[:db/add 1 :foo/bar 2]\n [:db/add 1 :foo/baz 3]").unwrap_err();
+    assert_eq!((e.line, e.column, e.offset), (2, 24, 47));
+
+    // An invalid number literal is reported where it goes wrong.
+    let e = parse::value("[:db/add 1 :foo/bar\n 12abc]").unwrap_err();
+    assert_eq!((e.line, e.column, e.offset), (2, 4, 23));
+
+    let e = parse::value("[:db/add 1 :foo/bar\n 99999999999999999999]").unwrap_err();
+    assert_eq!((e.line, e.column, e.offset), (2, 22, 41));
+}
+
 #[test]
 fn test_hexinteger() {
     use self::Value::*;
@@ -271,6 +303,24 @@ fn test_uuid() {
     assert_eq!(self::Value::Uuid(expected), actual);
 }
 
+#[test]
+fn test_bytes() {
+    assert!(parse::bytes("#bytes\"deadbeef\"").is_err());     // No whitespace.
+    assert!(parse::bytes("#bytes \"deadbee\"").is_err());     // Odd number of digits.
+    assert!(parse::bytes("#bytes \"zz\"").is_err());          // Not hex.
+    assert!(parse::bytes("\"deadbeef\"").is_err());           // No tag.
+
+    let actual: Value = parse::bytes("#bytes \"deadBEEF\"")
+                            .expect("parse success")
+                            .inner
+                            .into();
+    assert_eq!(self::Value::Bytes(vec![0xde, 0xad, 0xbe, 0xef]), actual);
+    assert_eq!(actual.to_string(), "#bytes \"deadbeef\"");
+
+    let empty: Value = parse::value("#bytes \"\"").expect("parse success").inner.into();
+    assert_eq!(self::Value::Bytes(vec![]), empty);
+}
+
 #[test]
 fn test_bigint() {
     use self::Value::*;
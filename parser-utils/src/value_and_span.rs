@@ -83,6 +83,7 @@ pub enum Iter<'a> {
     Atom(std::iter::Once<&'a edn::ValueAndSpan>),
     Vector(std::slice::Iter<'a, edn::ValueAndSpan>),
     List(std::collections::linked_list::Iter<'a, edn::ValueAndSpan>),
+    Set(std::collections::btree_set::Iter<'a, edn::ValueAndSpan>),
     /// Iterates a map {:k1 v1, :k2 v2, ...} as a single `flat_map` slice [k1, v1, k2, v2, ...].
     Map(std::iter::FlatMap<std::collections::btree_map::Iter<'a, edn::ValueAndSpan, edn::ValueAndSpan>,
                            std::iter::Chain<std::iter::Once<&'a edn::ValueAndSpan>, std::iter::Once<&'a edn::ValueAndSpan>>,
@@ -92,10 +93,9 @@ pub enum Iter<'a> {
     KeywordMap(std::iter::FlatMap<std::collections::btree_map::Iter<'a, edn::ValueAndSpan, edn::ValueAndSpan>,
                                   std::iter::Chain<std::iter::Once<&'a edn::ValueAndSpan>, Box<Iter<'a>>>,
                                   fn((&'a edn::ValueAndSpan, &'a edn::ValueAndSpan)) -> std::iter::Chain<std::iter::Once<&'a edn::ValueAndSpan>, Box<Iter<'a>>>>),
-    // TODO: Support Set and Map more naturally.  This is significantly more work because the
-    // existing BTreeSet and BTreeMap iterators do not implement Clone, and implementing Clone for
-    // them is involved.  Since we don't really need to parse sets and maps at this time, this will
-    // do for now.
+    // TODO: Support Map more naturally.  This is significantly more work because the existing
+    // BTreeMap iterators do not implement Clone, and implementing Clone for them is involved.
+    // Since we don't really need to parse maps at this time, this will do for now.
 }
 
 impl<'a> Iterator for Iter<'a> {
@@ -107,6 +107,7 @@ impl<'a> Iterator for Iter<'a> {
             Iter::Atom(ref mut i) => i.next(),
             Iter::Vector(ref mut i) => i.next(),
             Iter::List(ref mut i) => i.next(),
+            Iter::Set(ref mut i) => i.next(),
             Iter::Map(ref mut i) => i.next(),
             Iter::KeywordMap(ref mut i) => i.next(),
         }
@@ -174,6 +175,7 @@ impl<'a> Item<'a> for edn::ValueAndSpan {
         match self.inner {
             edn::SpannedValue::Vector(ref values) => Iter::Vector(values.iter()),
             edn::SpannedValue::List(ref values) => Iter::List(values.iter()),
+            edn::SpannedValue::Set(ref values) => Iter::Set(values.iter()),
             // Parsing pairs with `combine` is tricky; parsing sequences is easy.
             edn::SpannedValue::Map(ref map) => Iter::Map(map.iter().flat_map(flatten_k_v)),
             _ => Iter::Empty,
@@ -372,6 +374,22 @@ pub fn seq<'a>() -> Expected<FnParser<Stream<'a>, fn(Stream<'a>) -> ParseResult<
     parser(seq_ as fn(Stream<'a>) -> ParseResult<Stream<'a>, Stream<'a>>).expected("vector|list")
 }
 
+pub fn set_<'a>(input: Stream<'a>) -> ParseResult<Stream<'a>, Stream<'a>> {
+    satisfy_map(|v: &'a edn::ValueAndSpan| {
+        if v.inner.is_set() {
+            Some(v.child_stream())
+        } else {
+            None
+        }
+    })
+        .parse_lazy(input)
+        .into()
+}
+
+pub fn set<'a>() -> Expected<FnParser<Stream<'a>, fn(Stream<'a>) -> ParseResult<Stream<'a>, Stream<'a>>>> {
+    parser(set_ as fn(Stream<'a>) -> ParseResult<Stream<'a>, Stream<'a>>).expected("set")
+}
+
 pub fn map_<'a>(input: Stream<'a>) -> ParseResult<Stream<'a>, Stream<'a>> {
     satisfy_map(|v: &'a edn::ValueAndSpan| {
         if v.inner.is_map() {
@@ -0,0 +1,60 @@
+#![feature(test)]
+
+// These benchmarks can be run from the project root with:
+// > cargo bench --package mentat_query_algebrizer
+
+extern crate test;
+extern crate mentat_core;
+extern crate mentat_query;
+extern crate mentat_query_algebrizer;
+extern crate mentat_query_parser;
+
+use test::Bencher;
+
+use mentat_core::{
+    Attribute,
+    Entid,
+    Schema,
+    ValueType,
+};
+
+use mentat_query::NamespacedKeyword;
+
+use mentat_query_algebrizer::algebrize;
+use mentat_query_parser::parse_find_string;
+
+fn associate_ident(schema: &mut Schema, i: NamespacedKeyword, e: Entid) {
+    schema.entid_map.insert(e, i.clone());
+    schema.ident_map.insert(i.clone(), e);
+}
+
+fn add_attribute(schema: &mut Schema, e: Entid, a: Attribute) {
+    schema.schema_map.insert(e, a);
+}
+
+fn prepopulated_schema() -> Schema {
+    let mut schema = Schema::default();
+    associate_ident(&mut schema, NamespacedKeyword::new("foo", "kind"), 65);
+    add_attribute(&mut schema, 65, Attribute {
+        value_type: ValueType::Ref,
+        ..Default::default()
+    });
+
+    // Plenty of enum idents, so that lookups aren't trivially cheap.
+    for i in 0..5000 {
+        associate_ident(&mut schema, NamespacedKeyword::new("foo.kind", &format!("k{}", i)), 1000 + i);
+    }
+    schema
+}
+
+/// Ground the same enum ident 500 times.
+#[bench]
+fn bench_repeated_ident_grounds(b: &mut Bencher) {
+    let schema = prepopulated_schema();
+    let idents: Vec<&str> = (0..500).map(|_| ":foo.kind/k2500").collect();
+    let query = format!("[:find ?x :where [?x :foo/kind ?k] [(ground [{}]) [?k ...]]]", idents.join(" "));
+    b.iter(|| {
+        let parsed = parse_find_string(query.as_str()).expect("query to parse");
+        algebrize(&schema, parsed).expect("query to algebrize")
+    });
+}
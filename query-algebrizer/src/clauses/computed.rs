@@ -0,0 +1,292 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+use std::rc::Rc;
+
+use mentat_core::{
+    TypedValue,
+    ValueType,
+    ValueTypeSet,
+};
+
+use mentat_query::{
+    Binding,
+    FnArg,
+    PlainSymbol,
+    Variable,
+    WhereFn,
+};
+
+use clauses::{
+    ConjoiningClauses,
+};
+
+use errors::{
+    BindingError,
+    ErrorKind,
+    Result,
+};
+
+use types::{
+    ArithmeticOperator,
+    ColumnConstraint,
+    Computation,
+    EmptyBecause,
+    Inequality,
+    InstantUnit,
+    Operand,
+    QueryValue,
+};
+
+impl ConjoiningClauses {
+    /// The single variable to which a computing function binds its result.
+    fn computed_target(&self, where_fn: &WhereFn) -> Result<Variable> {
+        let target = match where_fn.binding {
+            Binding::BindScalar(ref var) => var.clone(),
+            Binding::BindColl(_) |
+            Binding::BindRel(_) |
+            Binding::BindTuple(_) => {
+                if where_fn.binding.is_empty() {
+                    bail!(ErrorKind::InvalidBinding(where_fn.operator.clone(), BindingError::NoBoundVariable));
+                }
+                bail!(ErrorKind::InvalidBinding(where_fn.operator.clone(), BindingError::ExpectedBindScalar));
+            },
+        };
+
+        if self.column_bindings.contains_key(&target) ||
+           self.is_value_bound(&target) ||
+           self.type_of_bindings.contains_key(&target) ||
+           self.fallback_bindings.contains_key(&target) ||
+           self.computed_bindings.contains_key(&target) {
+            bail!(ErrorKind::ComputedCanOnlyBeProjected(target.name()));
+        }
+        Ok(target)
+    }
+
+    /// The operand for argument `position` of `operator`. A variable must already be bound, and
+    /// its type known, by an earlier clause.
+    fn operand_from_arg(&self, operator: &PlainSymbol, position: usize, arg: FnArg) -> Result<Operand> {
+        match arg {
+            FnArg::Variable(var) => {
+                if let Some(value) = self.bound_value(&var) {
+                    return Ok(Operand::Value(value));
+                }
+                let column = match self.column_bindings.get(&var) {
+                    Some(columns) => columns[0].clone(),
+                    None => bail!(ErrorKind::UnboundVariable(var.name())),
+                };
+                match self.known_type(&var) {
+                    Some(t) => Ok(Operand::Column(column, t)),
+                    None => bail!(ErrorKind::UntypedOperand(operator.clone(), var.name())),
+                }
+            },
+            FnArg::EntidOrInteger(x) => Ok(Operand::Value(TypedValue::Long(x))),
+            FnArg::IdentOrKeyword(x) => Ok(Operand::Value(TypedValue::Keyword(Rc::new(x)))),
+            FnArg::Constant(x) => Ok(Operand::Value(x.into_typed_value())),
+            FnArg::SrcVar(_) |
+            FnArg::Keyword(_) |
+            FnArg::Vector(_) |
+            FnArg::Set(_) |
+            FnArg::Map(_) |
+            FnArg::Nil => bail!(ErrorKind::InvalidArgument(operator.clone(), "variable or constant", position)),
+        }
+    }
+
+    /// `[(str ?first " " ?last) ?name]` binds `?name` to the concatenation of the string forms of
+    /// its arguments, each either a variable or a constant. Strings are used as they are, keywords
+    /// are written with their leading colon, booleans are `true` or `false`, instants are written
+    /// as RFC 3339 timestamps in UTC with milliseconds, like `2017-04-28T20:23:05.187Z`, and numbers
+    /// and entids are written as SQLite writes them.
+    ///
+    /// The concatenation is done in SQL, so each variable must be bound, with a single known type,
+    /// by a clause that precedes this one. A row in which an attribute that supplies an argument is
+    /// absent doesn't match that clause, and so yields no result at all, rather than a string with
+    /// a piece missing. Use `get-else` to supply a default if you'd rather keep the row.
+    ///
+    /// Like `type-of`, the result can only be projected.
+    pub fn apply_str(&mut self, where_fn: WhereFn) -> Result<()> {
+        if where_fn.args.is_empty() {
+            bail!(ErrorKind::InvalidNumberOfArguments(where_fn.operator.clone(), 0, 1));
+        }
+
+        let target = self.computed_target(&where_fn)?;
+
+        let mut operands = Vec::with_capacity(where_fn.args.len());
+        for (i, arg) in where_fn.args.into_iter().enumerate() {
+            let operand = self.operand_from_arg(&where_fn.operator, i, arg)?;
+            match operand.value_type() {
+                ValueType::Uuid |
+                ValueType::Bytes |
+                ValueType::BigInt => {
+                    bail!(ErrorKind::InvalidArgument(where_fn.operator.clone(), "string, keyword, number, boolean, instant, or entity", i));
+                },
+                _ => operands.push(operand),
+            }
+        }
+
+        self.constrain_var_to_type(target.clone(), ValueType::String);
+        self.computed_bindings.insert(target, Computation::Str(operands));
+        Ok(())
+    }
+
+    /// `[(+ ?price ?tax) ?total]` binds `?total` to the sum of its two arguments, and likewise
+    /// for `-`, `*`, and `/`. Each argument is a number or a variable bound, with a single known
+    /// numeric type, by a preceding clause. The result is a long if both arguments are longs, in
+    /// which case division truncates towards zero, and a double otherwise.
+    ///
+    /// A variable that can't be a number makes the query known-empty. Division by zero has no
+    /// result, so we exclude any row in which the divisor is zero, and a constant divisor of zero
+    /// makes the query known-empty.
+    pub fn apply_arithmetic(&mut self, operator: ArithmeticOperator, where_fn: WhereFn) -> Result<()> {
+        if where_fn.args.len() != 2 {
+            bail!(ErrorKind::InvalidNumberOfArguments(where_fn.operator.clone(), where_fn.args.len(), 2));
+        }
+
+        let target = self.computed_target(&where_fn)?;
+
+        let mut operands = Vec::with_capacity(2);
+        for (i, arg) in where_fn.args.into_iter().enumerate() {
+            if let FnArg::Variable(ref var) = arg {
+                let existing = self.known_type_set(var);
+                if existing.intersection(&ValueTypeSet::of_numeric_types()).is_empty() {
+                    self.mark_known_empty(EmptyBecause::TypeMismatch {
+                        var: var.clone(),
+                        existing: existing,
+                        desired: ValueTypeSet::of_numeric_types(),
+                    });
+                    return Ok(());
+                }
+            }
+            let operand = self.operand_from_arg(&where_fn.operator, i, arg)?;
+            match operand.value_type() {
+                ValueType::Long | ValueType::Double => operands.push(operand),
+                _ => bail!(ErrorKind::InvalidArgument(where_fn.operator.clone(), "number", i)),
+            }
+        }
+
+        let right = operands.pop().unwrap();
+        let left = operands.pop().unwrap();
+
+        if operator == ArithmeticOperator::Divide {
+            match right {
+                Operand::Value(TypedValue::Long(0)) => {
+                    self.mark_known_empty(EmptyBecause::DivisionByZero);
+                    return Ok(());
+                },
+                Operand::Value(TypedValue::Double(d)) if d.into_inner() == 0.0 => {
+                    self.mark_known_empty(EmptyBecause::DivisionByZero);
+                    return Ok(());
+                },
+                Operand::Value(_) => {},
+                Operand::Column(ref column, _) => {
+                    self.wheres.add_intersection(ColumnConstraint::Inequality {
+                        operator: Inequality::NotEquals,
+                        left: QueryValue::Column(column.clone()),
+                        right: QueryValue::TypedValue(TypedValue::Long(0)),
+                    });
+                },
+            }
+        }
+
+        let value_type = match (left.value_type(), right.value_type()) {
+            (ValueType::Long, ValueType::Long) => ValueType::Long,
+            _ => ValueType::Double,
+        };
+
+        self.constrain_var_to_type(target.clone(), value_type);
+        self.computed_bindings.insert(target, Computation::Arithmetic {
+            operator: operator,
+            left: left,
+            right: right,
+        });
+        Ok(())
+    }
+
+    /// The operand for the instant argument of an instant function. A variable that can't be an
+    /// instant makes the query known-empty, in which case we return `None`.
+    fn instant_operand(&mut self, operator: &PlainSymbol, arg: FnArg) -> Result<Option<Operand>> {
+        if let FnArg::Variable(ref var) = arg {
+            let existing = self.known_type_set(var);
+            if !existing.contains(ValueType::Instant) {
+                self.mark_known_empty(EmptyBecause::TypeMismatch {
+                    var: var.clone(),
+                    existing: existing,
+                    desired: ValueTypeSet::of_one(ValueType::Instant),
+                });
+                return Ok(None);
+            }
+        }
+        let operand = self.operand_from_arg(operator, 0, arg)?;
+        if operand.value_type() != ValueType::Instant {
+            bail!(ErrorKind::InvalidArgument(operator.clone(), "instant", 0));
+        }
+        Ok(Some(operand))
+    }
+
+    /// `[(plus-instant ?t 3600000) ?later]` binds `?later` to the instant that many milliseconds
+    /// after `?t`. The duration is a long, or a variable bound to one by a preceding clause, and
+    /// can be negative.
+    pub fn apply_plus_instant(&mut self, where_fn: WhereFn) -> Result<()> {
+        if where_fn.args.len() != 2 {
+            bail!(ErrorKind::InvalidNumberOfArguments(where_fn.operator.clone(), where_fn.args.len(), 2));
+        }
+
+        let target = self.computed_target(&where_fn)?;
+
+        let mut args = where_fn.args.into_iter();
+        let instant = match self.instant_operand(&where_fn.operator, args.next().unwrap())? {
+            Some(instant) => instant,
+            None => return Ok(()),
+        };
+        let millis = self.operand_from_arg(&where_fn.operator, 1, args.next().unwrap())?;
+        if millis.value_type() != ValueType::Long {
+            bail!(ErrorKind::InvalidArgument(where_fn.operator.clone(), "long", 1));
+        }
+
+        self.constrain_var_to_type(target.clone(), ValueType::Instant);
+        self.computed_bindings.insert(target, Computation::PlusInstant {
+            instant: instant,
+            millis: millis,
+        });
+        Ok(())
+    }
+
+    /// `[(truncate-instant ?t :day) ?day]` binds `?day` to the start, in UTC, of the day that
+    /// contains `?t`. The unit is one of `:minute`, `:hour`, `:day`, and `:month`.
+    pub fn apply_truncate_instant(&mut self, where_fn: WhereFn) -> Result<()> {
+        if where_fn.args.len() != 2 {
+            bail!(ErrorKind::InvalidNumberOfArguments(where_fn.operator.clone(), where_fn.args.len(), 2));
+        }
+
+        let target = self.computed_target(&where_fn)?;
+
+        let mut args = where_fn.args.into_iter();
+        let instant = args.next().unwrap();
+        let unit = match args.next().unwrap() {
+            FnArg::Keyword(ref unit) => InstantUnit::from_name(unit.0.as_str()),
+            _ => None,
+        };
+        let unit = match unit {
+            Some(unit) => unit,
+            None => bail!(ErrorKind::InvalidArgument(where_fn.operator.clone(), ":minute, :hour, :day, or :month", 1)),
+        };
+        let instant = match self.instant_operand(&where_fn.operator, instant)? {
+            Some(instant) => instant,
+            None => return Ok(()),
+        };
+
+        self.constrain_var_to_type(target.clone(), ValueType::Instant);
+        self.computed_bindings.insert(target, Computation::TruncateInstant {
+            instant: instant,
+            unit: unit,
+        });
+        Ok(())
+    }
+}
@@ -11,9 +11,13 @@
 use std::rc::Rc;
 
 use mentat_core::{
+    DateTime,
+    FromMillis,
     Schema,
     SQLValueType,
     TypedValue,
+    Utc,
+    Uuid,
     ValueType,
     ValueTypeSet,
 };
@@ -79,17 +83,18 @@ impl ValueTypes for FnArg {
                     ValueTypeSet::any()
                 },
 
-                &FnArg::Constant(NonIntegerConstant::BigInteger(_)) => {
-                    // Not yet implemented.
-                    bail!(ErrorKind::UnsupportedArgument)
-                },
-
                 // These don't make sense here. TODO: split FnArg into scalar and non-scalar…
+                &FnArg::Keyword(_) |
                 &FnArg::Vector(_) |
+                &FnArg::Set(_) |
+                &FnArg::Map(_) |
+                &FnArg::Nil |
                 &FnArg::SrcVar(_) => bail!(ErrorKind::UnsupportedArgument),
 
                 // These are all straightforward.
+                &FnArg::Constant(NonIntegerConstant::BigInteger(_)) => ValueTypeSet::of_one(ValueType::BigInt),
                 &FnArg::Constant(NonIntegerConstant::Boolean(_)) => ValueTypeSet::of_one(ValueType::Boolean),
+                &FnArg::Constant(NonIntegerConstant::Bytes(_)) => ValueTypeSet::of_one(ValueType::Bytes),
                 &FnArg::Constant(NonIntegerConstant::Instant(_)) => ValueTypeSet::of_one(ValueType::Instant),
                 &FnArg::Constant(NonIntegerConstant::Uuid(_)) => ValueTypeSet::of_one(ValueType::Uuid),
                 &FnArg::Constant(NonIntegerConstant::Float(_)) => ValueTypeSet::of_one(ValueType::Double),
@@ -100,9 +105,28 @@ impl ValueTypes for FnArg {
 
 pub enum ValueConversion {
     Val(TypedValue),
+    ValColl(Vec<TypedValue>),
     Impossible(EmptyBecause),
 }
 
+impl ValueConversion {
+    /// The reason this conversion failed, if it did.
+    pub fn impossible_reason(&self) -> Option<&EmptyBecause> {
+        match self {
+            &ValueConversion::Impossible(ref because) => Some(because),
+            _ => None,
+        }
+    }
+
+    /// The single converted value, if there is one. Collections and failures yield `None`.
+    pub fn into_value(self) -> Option<TypedValue> {
+        match self {
+            ValueConversion::Val(v) => Some(v),
+            _ => None,
+        }
+    }
+}
+
 /// Conversion of FnArgs to TypedValues.
 impl ConjoiningClauses {
     /// Convert the provided `FnArg` to a `TypedValue`.
@@ -123,13 +147,30 @@ impl ConjoiningClauses {
         match arg {
             // Longs are potentially ambiguous: they might be longs or entids.
             FnArg::EntidOrInteger(x) => {
+                if known_types == ValueTypeSet::of_one(ValueType::Instant) {
+                    // Only an instant will do, so treat the integer as epoch milliseconds.
+                    // If a long or ref is also possible we leave it alone: that's less surprising.
+                    return Ok(match DateTime::<Utc>::from_millis(x) {
+                        Some(instant) => Val(TypedValue::Instant(instant)),
+                        None => Impossible(EmptyBecause::NonInstantArgument),
+                    });
+                }
+
                 match (ValueType::Ref.accommodates_integer(x),
                        known_types.contains(ValueType::Ref),
                        known_types.contains(ValueType::Long)) {
                     (true, true, true) => {
                         // Ambiguous: this arg could be an entid or a long.
-                        // We default to long.
-                        Ok(Val(TypedValue::Long(x)))
+                        // We default to long, unless asked for the most specific type.
+                        let preferred = if self.prefer_most_specific_types {
+                            known_types.intersection(&ValueTypeSet::of_longs()).most_specific()
+                        } else {
+                            None
+                        };
+                        match preferred {
+                            Some(ValueType::Ref) => Ok(Val(TypedValue::Ref(x))),
+                            _ => Ok(Val(TypedValue::Long(x))),
+                        }
                     },
                     (true, true, false) => {
                         // This can only be a ref.
@@ -141,11 +182,7 @@ impl ConjoiningClauses {
                     },
                     (false, true, _) => {
                         // This isn't a valid ref, but that's the type to which this must conform!
-                        Ok(Impossible(EmptyBecause::TypeMismatch {
-                            var: var.clone(),
-                            existing: known_types,
-                            desired: ValueTypeSet::of_longs(),
-                        }))
+                        Ok(Impossible(EmptyBecause::NonPositiveEntid(x)))
                     },
                     (_, false, false) => {
                         // Non-overlapping type sets.
@@ -164,14 +201,23 @@ impl ConjoiningClauses {
                        known_types.contains(ValueType::Keyword)) {
                     (true, true) => {
                         // Ambiguous: this could be a keyword or an ident.
-                        // Default to keyword.
+                        // Default to keyword, unless asked for the most specific type, in which
+                        // case we use the entid if the ident names one.
+                        if self.prefer_most_specific_types &&
+                           known_types.intersection(&ValueTypeSet::of_keywords()).most_specific() == Some(ValueType::Ref) {
+                            let ident = self.resolve_ident_alias(&x);
+                            if let Some(e) = self.cached_entid_for_ident(schema, ident) {
+                                return Ok(Val(TypedValue::Ref(e)));
+                            }
+                        }
                         Ok(Val(TypedValue::Keyword(Rc::new(x))))
                     },
                     (true, false) => {
-                        // This can only be an ident. Look it up!
-                        match schema.get_entid(&x).map(TypedValue::Ref) {
+                        // This can only be an ident. Look it up, respecting any aliases!
+                        let ident = self.resolve_ident_alias(&x);
+                        match self.cached_entid_for_ident(schema, ident).map(TypedValue::Ref) {
                             Some(e) => Ok(Val(e)),
-                            None => Ok(Impossible(EmptyBecause::UnresolvedIdent(x.clone()))),
+                            None => Ok(Impossible(EmptyBecause::UnresolvedIdent(ident.clone()))),
                         }
                     },
                     (false, true) => {
@@ -199,19 +245,30 @@ impl ConjoiningClauses {
                         // The variable is present in `:in`, but it hasn't yet been provided.
                         // This is a restriction we will eventually relax: we don't yet have a way
                         // to collect variables as part of a computed table or substitution.
-                        bail!(ErrorKind::UnboundVariable((*in_var.0).clone()))
+                        if self.unbound_inputs_are_empty {
+                            Ok(Impossible(EmptyBecause::UnboundVariable(in_var.clone())))
+                        } else {
+                            bail!(ErrorKind::UnboundVariable((*in_var.0).clone()))
+                        }
                     },
                 }
             },
 
-            // This isn't implemented yet.
-            FnArg::Constant(NonIntegerConstant::BigInteger(_)) => unimplemented!(),
+            // These don't make sense here. Lookup refs can only be resolved by a pattern:
+            // see `apply_ground_lookup_ref`.
+            arg @ FnArg::Keyword(_) |
+            arg @ FnArg::Vector(_) |
+            arg @ FnArg::Set(_) |
+            arg @ FnArg::Map(_) |
+            arg @ FnArg::SrcVar(_) => bail!(ErrorKind::InvalidGroundConstant(format!("{:?}", arg))),
 
-            // These don't make sense here.
-            FnArg::Vector(_) |
-            FnArg::SrcVar(_) => bail!(ErrorKind::InvalidGroundConstant),
+            // Nothing matches the absence of a value.
+            FnArg::Nil => Ok(Impossible(EmptyBecause::ValueIsNil)),
 
             // These are all straightforward.
+            FnArg::Constant(NonIntegerConstant::BigInteger(x)) => {
+                coerce_to_typed_value!(var, x, known_types, ValueType::BigInt, TypedValue::BigInteger)
+            },
             FnArg::Constant(NonIntegerConstant::Boolean(x)) => {
                 coerce_to_typed_value!(var, x, known_types, ValueType::Boolean, TypedValue::Boolean)
             },
@@ -222,11 +279,556 @@ impl ConjoiningClauses {
                 coerce_to_typed_value!(var, x, known_types, ValueType::Uuid, TypedValue::Uuid)
             },
             FnArg::Constant(NonIntegerConstant::Float(x)) => {
+                if self.integral_floats_as_longs && known_types == ValueTypeSet::of_one(ValueType::Long) {
+                    // Only a long will do, and we've been asked to accept floats like `42.0`.
+                    // Anything we'd have to round is an error, rather than a silent truncation.
+                    let f = x.into_inner();
+                    if f.trunc() != f || f.abs() >= 9223372036854775808.0 {
+                        bail!(ErrorKind::NonIntegralFloat(var.name(), f));
+                    }
+                    return Ok(Val(TypedValue::Long(f as i64)));
+                }
                 coerce_to_typed_value!(var, x, known_types, ValueType::Double, TypedValue::Double)
             },
             FnArg::Constant(NonIntegerConstant::Text(x)) => {
+                if known_types == ValueTypeSet::of_one(ValueType::Uuid) {
+                    // Only a UUID will do, so parse the string. As for instants, if a string is
+                    // also possible we leave it alone.
+                    return Ok(match Uuid::parse_str(x.as_str()) {
+                        Ok(uuid) => Val(TypedValue::Uuid(uuid)),
+                        Err(_) => Impossible(EmptyBecause::MalformedUuid((*x).clone())),
+                    });
+                }
                 coerce_to_typed_value!(var, x, known_types, ValueType::String, TypedValue::String)
             },
+            FnArg::Constant(NonIntegerConstant::Bytes(x)) => {
+                coerce_to_typed_value!(var, x, known_types, ValueType::Bytes, TypedValue::Bytes)
+            },
+        }
+    }
+
+    /// Convert the provided `FnArg::Vector` to a homogeneous collection of `TypedValue`s, each
+    /// of which is converted as if by `typed_value_from_arg`.
+    ///
+    /// The first element to convert fixes the type of the collection. If any element can't be
+    /// converted to that type, the whole conversion is impossible, and the reason refers to the
+    /// first offending element. An empty vector converts to an empty collection.
+    pub fn typed_values_from_arg<'s>(&self, schema: &'s Schema, var: &Variable, arg: FnArg, known_types: ValueTypeSet) -> Result<ValueConversion> {
+        use self::ValueConversion::*;
+        let children = match arg {
+            FnArg::Vector(children) => children,
+            arg => bail!(ErrorKind::InvalidGroundConstant(format!("{:?}", arg))),
+        };
+
+        let mut types = known_types;
+        let mut values = Vec::with_capacity(children.len());
+        for child in children.into_iter() {
+            if let FnArg::Vector(_) = child {
+                // We don't support nested collections.
+                bail!(ErrorKind::InvalidGroundConstant(format!("{:?}", child)));
+            }
+            match self.typed_value_from_arg(schema, var, child, types)? {
+                Val(tv) => {
+                    // Every subsequent element must have the same type as this one.
+                    types = ValueTypeSet::of_one(tv.value_type());
+                    values.push(tv);
+                },
+                ValColl(_) => unreachable!(),
+                Impossible(because) => return Ok(Impossible(because)),
+            }
+        }
+        Ok(ValColl(values))
+    }
+
+    /// Convert the `values` provided for the collection input `var` to a homogeneous collection
+    /// of `TypedValue`s, each of one of the `known_types`.
+    ///
+    /// A value of a known type is used as it is. Otherwise a long, a keyword, or a string is
+    /// converted as if by `typed_value_from_arg`, so that a long can be an entid and a keyword
+    /// an ident. As for `typed_values_from_arg`, the first value fixes the type of the rest.
+    /// Unlike a `ground` argument, though, an input is supplied by the caller, so a value that
+    /// can't be converted is an error, which names the first such value.
+    pub fn typed_values_from_input<'s>(&self, schema: &'s Schema, var: &Variable, values: Vec<TypedValue>, known_types: ValueTypeSet) -> Result<Vec<TypedValue>> {
+        let mut types = known_types;
+        let mut converted = Vec::with_capacity(values.len());
+        for value in values.into_iter() {
+            let provided = value.value_type();
+            let value = if types.contains(provided) {
+                Some(value)
+            } else {
+                let arg = match value {
+                    TypedValue::Long(x) => Some(FnArg::EntidOrInteger(x)),
+                    TypedValue::Keyword(x) => Some(FnArg::IdentOrKeyword((*x).clone())),
+                    TypedValue::String(x) => Some(FnArg::Constant(NonIntegerConstant::Text(x))),
+                    _ => None,
+                };
+                match arg {
+                    Some(arg) => match self.typed_value_from_arg(schema, var, arg, types)? {
+                        ValueConversion::Val(tv) => Some(tv),
+                        _ => None,
+                    },
+                    None => None,
+                }
+            };
+            match value {
+                Some(tv) => {
+                    // Every subsequent value must have the same type as this one.
+                    types = ValueTypeSet::of_one(tv.value_type());
+                    converted.push(tv);
+                },
+                None => bail!(ErrorKind::InputTypeMismatch(var.name(), types, provided)),
+            }
+        }
+        Ok(converted)
+    }
+
+    /// Convert the provided `FnArg` to a `TypedValue` of exactly the `forced` type.
+    ///
+    /// This skips the defaulting that `typed_value_from_arg` applies to ambiguous arguments: an
+    /// integer can be forced to be a `Ref` even if `Long` is also possible, and vice versa.
+    /// If `forced` isn't one of the `known_types`, the conversion is impossible.
+    pub fn typed_value_from_arg_as<'s>(&self, schema: &'s Schema, var: &Variable, arg: FnArg, known_types: ValueTypeSet, forced: ValueType) -> Result<ValueConversion> {
+        if !known_types.contains(forced) {
+            return Ok(ValueConversion::Impossible(EmptyBecause::TypeMismatch {
+                var: var.clone(),
+                existing: known_types,
+                desired: ValueTypeSet::of_one(forced),
+            }));
+        }
+        self.typed_value_from_arg(schema, var, arg, ValueTypeSet::of_one(forced))
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    use std::collections::{
+        BTreeSet,
+        HashMap,
+    };
+
+    use mentat_core::{
+        Attribute,
+        FromMicros,
+    };
+
+    use mentat_query::{
+        Keyword,
+        NamespacedKeyword,
+        SrcVar,
+    };
+
+    use clauses::{
+        QueryInputs,
+        add_attribute,
+        associate_ident,
+    };
+
+    use errors::Error;
+
+    fn prepopulated_schema() -> Schema {
+        let mut schema = Schema::default();
+        associate_ident(&mut schema, NamespacedKeyword::new("db", "ident"), 1);
+        associate_ident(&mut schema, NamespacedKeyword::new("foo", "bar"), 99);
+        add_attribute(&mut schema, 99, Attribute {
+            value_type: ValueType::Long,
+            ..Default::default()
+        });
+        schema
+    }
+
+    #[test]
+    fn test_value_conversion_accessors() {
+        let val = ValueConversion::Val(TypedValue::Long(5));
+        assert!(val.impossible_reason().is_none());
+        assert_eq!(val.into_value(), Some(TypedValue::Long(5)));
+
+        let coll = ValueConversion::ValColl(vec![TypedValue::Long(5)]);
+        assert!(coll.impossible_reason().is_none());
+        assert_eq!(coll.into_value(), None);
+
+        let impossible = ValueConversion::Impossible(EmptyBecause::NonInstantArgument);
+        assert_eq!(impossible.impossible_reason(), Some(&EmptyBecause::NonInstantArgument));
+        assert_eq!(impossible.into_value(), None);
+    }
+
+    #[test]
+    fn test_typed_value_from_arg_as_ref() {
+        let schema = prepopulated_schema();
+        let cc = ConjoiningClauses::default();
+        let x = Variable::from_valid_name("?x");
+
+        // By default an ambiguous integer is a long…
+        match cc.typed_value_from_arg(&schema, &x, FnArg::EntidOrInteger(99), ValueTypeSet::of_longs()).expect("conversion") {
+            ValueConversion::Val(v) => assert_eq!(v, TypedValue::Long(99)),
+            _ => panic!("expected a value"),
+        }
+
+        // … but it can be forced to be a ref.
+        match cc.typed_value_from_arg_as(&schema, &x, FnArg::EntidOrInteger(99), ValueTypeSet::of_longs(), ValueType::Ref).expect("conversion") {
+            ValueConversion::Val(v) => assert_eq!(v, TypedValue::Ref(99)),
+            _ => panic!("expected a value"),
+        }
+    }
+
+    #[test]
+    fn test_typed_value_from_arg_as_long() {
+        let schema = prepopulated_schema();
+        let cc = ConjoiningClauses::default();
+        let x = Variable::from_valid_name("?x");
+
+        match cc.typed_value_from_arg_as(&schema, &x, FnArg::EntidOrInteger(99), ValueTypeSet::of_longs(), ValueType::Long).expect("conversion") {
+            ValueConversion::Val(v) => assert_eq!(v, TypedValue::Long(99)),
+            _ => panic!("expected a value"),
+        }
+
+        // We can't force a type that isn't known to be possible.
+        match cc.typed_value_from_arg_as(&schema, &x, FnArg::EntidOrInteger(99), ValueTypeSet::of_one(ValueType::Ref), ValueType::Long).expect("conversion") {
+            ValueConversion::Impossible(because) => {
+                assert_eq!(because, EmptyBecause::TypeMismatch {
+                    var: x.clone(),
+                    existing: ValueTypeSet::of_one(ValueType::Ref),
+                    desired: ValueTypeSet::of_one(ValueType::Long),
+                });
+            },
+            _ => panic!("expected an impossible conversion"),
+        }
+    }
+
+    #[test]
+    fn test_typed_value_from_arg_most_specific() {
+        let schema = prepopulated_schema();
+        let cc = ConjoiningClauses::default().with_most_specific_types();
+        let x = Variable::from_valid_name("?x");
+
+        // An ambiguous integer is a ref…
+        match cc.typed_value_from_arg(&schema, &x, FnArg::EntidOrInteger(99), ValueTypeSet::of_longs()).expect("conversion") {
+            ValueConversion::Val(v) => assert_eq!(v, TypedValue::Ref(99)),
+            _ => panic!("expected a value"),
+        }
+
+        // … as is an ambiguous keyword that names an entity…
+        match cc.typed_value_from_arg(&schema, &x, FnArg::IdentOrKeyword(NamespacedKeyword::new("foo", "bar")), ValueTypeSet::of_keywords()).expect("conversion") {
+            ValueConversion::Val(v) => assert_eq!(v, TypedValue::Ref(99)),
+            _ => panic!("expected a value"),
+        }
+
+        // … but one that doesn't can only be a keyword.
+        match cc.typed_value_from_arg(&schema, &x, FnArg::IdentOrKeyword(NamespacedKeyword::new("foo", "missing")), ValueTypeSet::of_keywords()).expect("conversion") {
+            ValueConversion::Val(v) => assert_eq!(v, TypedValue::typed_ns_keyword("foo", "missing")),
+            _ => panic!("expected a value"),
+        }
+
+        // Unambiguous arguments are unaffected.
+        match cc.typed_value_from_arg(&schema, &x, FnArg::EntidOrInteger(99), ValueTypeSet::of_numeric_types()).expect("conversion") {
+            ValueConversion::Val(v) => assert_eq!(v, TypedValue::Long(99)),
+            _ => panic!("expected a value"),
+        }
+        match cc.typed_value_from_arg(&schema, &x, FnArg::EntidOrInteger(-5), ValueTypeSet::of_longs()).expect("conversion") {
+            ValueConversion::Val(v) => assert_eq!(v, TypedValue::Long(-5)),
+            _ => panic!("expected a value"),
+        }
+
+        // Without the preference, both default to the least specific type.
+        let cc = ConjoiningClauses::default();
+        match cc.typed_value_from_arg(&schema, &x, FnArg::IdentOrKeyword(NamespacedKeyword::new("foo", "bar")), ValueTypeSet::of_keywords()).expect("conversion") {
+            ValueConversion::Val(v) => assert_eq!(v, TypedValue::typed_ns_keyword("foo", "bar")),
+            _ => panic!("expected a value"),
+        }
+
+        // The preference can be given with the inputs to a query.
+        let cc = ConjoiningClauses::with_inputs(BTreeSet::new(), QueryInputs::default().with_most_specific_types());
+        match cc.typed_value_from_arg(&schema, &x, FnArg::EntidOrInteger(99), ValueTypeSet::of_longs()).expect("conversion") {
+            ValueConversion::Val(v) => assert_eq!(v, TypedValue::Ref(99)),
+            _ => panic!("expected a value"),
+        }
+    }
+
+    #[test]
+    fn test_typed_value_from_arg_integral_float() {
+        let schema = prepopulated_schema();
+        let cc = ConjoiningClauses::default().with_integral_floats_as_longs();
+        let x = Variable::from_valid_name("?x");
+        let long = ValueTypeSet::of_one(ValueType::Long);
+
+        // A whole float is a long where only a long will do…
+        match cc.typed_value_from_arg(&schema, &x, FnArg::Constant(NonIntegerConstant::Float(42.0.into())), long).expect("conversion") {
+            ValueConversion::Val(v) => assert_eq!(v, TypedValue::Long(42)),
+            _ => panic!("expected a value"),
+        }
+
+        // … but we won't truncate one that isn't…
+        match cc.typed_value_from_arg(&schema, &x, FnArg::Constant(NonIntegerConstant::Float(42.5.into())), long) {
+            Err(Error(ErrorKind::NonIntegralFloat(var, value), _)) => {
+                assert_eq!(var, x.name());
+                assert_eq!(value, 42.5);
+            },
+            _ => panic!("expected an error"),
+        }
+
+        // … and a float stays a double where a double will do.
+        match cc.typed_value_from_arg(&schema, &x, FnArg::Constant(NonIntegerConstant::Float(42.0.into())), ValueTypeSet::of_numeric_types()).expect("conversion") {
+            ValueConversion::Val(v) => assert_eq!(v, TypedValue::Double(42.0.into())),
+            _ => panic!("expected a value"),
+        }
+
+        // By default a float can't be a long at all.
+        let cc = ConjoiningClauses::default();
+        match cc.typed_value_from_arg(&schema, &x, FnArg::Constant(NonIntegerConstant::Float(42.0.into())), long).expect("conversion") {
+            ValueConversion::Impossible(because) => {
+                assert_eq!(because, EmptyBecause::TypeMismatch {
+                    var: x.clone(),
+                    existing: long,
+                    desired: ValueTypeSet::of_one(ValueType::Double),
+                });
+            },
+            _ => panic!("expected an impossible conversion"),
+        }
+
+        // The option can be given with the inputs to a query.
+        let cc = ConjoiningClauses::with_inputs(BTreeSet::new(), QueryInputs::default().with_integral_floats_as_longs());
+        match cc.typed_value_from_arg(&schema, &x, FnArg::Constant(NonIntegerConstant::Float(7.0.into())), long).expect("conversion") {
+            ValueConversion::Val(v) => assert_eq!(v, TypedValue::Long(7)),
+            _ => panic!("expected a value"),
+        }
+    }
+
+    #[test]
+    fn test_typed_value_from_arg_errors() {
+        let schema = prepopulated_schema();
+        let x = Variable::from_valid_name("?x");
+        let y = Variable::from_valid_name("?y");
+
+        // Arguments that can't be values say what they were.
+        let cc = ConjoiningClauses::default();
+        let invalid = vec![
+            FnArg::Keyword(Keyword::new("day")),
+            FnArg::Vector(vec![FnArg::EntidOrInteger(1)]),
+            FnArg::Set(vec![FnArg::EntidOrInteger(1)]),
+            FnArg::Map(vec![(FnArg::IdentOrKeyword(NamespacedKeyword::new("foo", "bar")), FnArg::EntidOrInteger(1))]),
+            FnArg::SrcVar(SrcVar::DefaultSrc),
+        ];
+        for arg in invalid {
+            let expected = format!("{:?}", arg);
+            match cc.typed_value_from_arg(&schema, &x, arg, ValueTypeSet::any()) {
+                Err(Error(ErrorKind::InvalidGroundConstant(description), _)) => assert_eq!(description, expected),
+                _ => panic!("expected an invalid ground constant"),
+            }
+        }
+
+        // A variable must be an input…
+        match cc.typed_value_from_arg(&schema, &x, FnArg::Variable(y.clone()), ValueTypeSet::any()) {
+            Err(Error(ErrorKind::UnboundVariable(name), _)) => assert_eq!(name, y.name()),
+            _ => panic!("expected an unbound variable"),
+        }
+
+        // … and, unless unbound inputs are empty, it must have a value.
+        let cc = ConjoiningClauses::with_inputs(vec![y.clone()].into_iter().collect(), QueryInputs::default());
+        match cc.typed_value_from_arg(&schema, &x, FnArg::Variable(y.clone()), ValueTypeSet::any()) {
+            Err(Error(ErrorKind::UnboundVariable(name), _)) => assert_eq!(name, y.name()),
+            _ => panic!("expected an unbound variable"),
+        }
+
+        // A float that must be a long has to be a whole number.
+        let cc = ConjoiningClauses::default().with_integral_floats_as_longs();
+        match cc.typed_value_from_arg(&schema, &x, FnArg::Constant(NonIntegerConstant::Float(0.5.into())), ValueTypeSet::of_one(ValueType::Long)) {
+            Err(Error(ErrorKind::NonIntegralFloat(var, value), _)) => {
+                assert_eq!(var, x.name());
+                assert_eq!(value, 0.5);
+            },
+            _ => panic!("expected a non-integral float"),
+        }
+
+        // An ident that must be a ref, but names nothing, isn't an error, but the reason the
+        // conversion is impossible names it.
+        let missing = NamespacedKeyword::new("foo", "missing");
+        match cc.typed_value_from_arg(&schema, &x, FnArg::IdentOrKeyword(missing.clone()), ValueTypeSet::of_one(ValueType::Ref)).expect("conversion") {
+            ValueConversion::Impossible(because) => assert_eq!(because, EmptyBecause::UnresolvedIdent(missing)),
+            _ => panic!("expected an impossible conversion"),
+        }
+    }
+
+    #[test]
+    fn test_typed_value_from_arg_ident_alias() {
+        let schema = prepopulated_schema();
+        let mut aliases = HashMap::new();
+        aliases.insert(NamespacedKeyword::new("my", "foo"), NamespacedKeyword::new("db", "ident"));
+        aliases.insert(NamespacedKeyword::new("my", "bar"), NamespacedKeyword::new("db", "missing"));
+
+        let cc = ConjoiningClauses::default().with_ident_aliases(aliases);
+        let x = Variable::from_valid_name("?x");
+
+        // An alias resolves to the entid of the keyword it points at.
+        match cc.typed_value_from_arg(&schema, &x, FnArg::IdentOrKeyword(NamespacedKeyword::new("my", "foo")), ValueTypeSet::of_one(ValueType::Ref)).expect("conversion") {
+            ValueConversion::Val(v) => assert_eq!(v, TypedValue::Ref(1)),
+            _ => panic!("expected a value"),
+        }
+
+        // An alias that points at an unknown keyword reports the resolved keyword.
+        match cc.typed_value_from_arg(&schema, &x, FnArg::IdentOrKeyword(NamespacedKeyword::new("my", "bar")), ValueTypeSet::of_one(ValueType::Ref)).expect("conversion") {
+            ValueConversion::Impossible(because) => {
+                assert_eq!(because, EmptyBecause::UnresolvedIdent(NamespacedKeyword::new("db", "missing")));
+            },
+            _ => panic!("expected an impossible conversion"),
+        }
+
+        // Keywords that aren't aliased are looked up as usual.
+        match cc.typed_value_from_arg(&schema, &x, FnArg::IdentOrKeyword(NamespacedKeyword::new("foo", "bar")), ValueTypeSet::of_one(ValueType::Ref)).expect("conversion") {
+            ValueConversion::Val(v) => assert_eq!(v, TypedValue::Ref(99)),
+            _ => panic!("expected a value"),
+        }
+    }
+
+    #[test]
+    fn test_typed_values_from_arg() {
+        let schema = prepopulated_schema();
+        let cc = ConjoiningClauses::default();
+        let x = Variable::from_valid_name("?x");
+
+        // Homogeneous integers.
+        let ints = FnArg::Vector(vec![FnArg::EntidOrInteger(1), FnArg::EntidOrInteger(2), FnArg::EntidOrInteger(3)]);
+        match cc.typed_values_from_arg(&schema, &x, ints, ValueTypeSet::any()).expect("conversion") {
+            ValueConversion::ValColl(vs) => {
+                assert_eq!(vs, vec![TypedValue::Long(1), TypedValue::Long(2), TypedValue::Long(3)]);
+            },
+            _ => panic!("expected a collection"),
+        }
+
+        // Mixed strings and integers: the first string fixes the type, so the integer fails.
+        let mixed = FnArg::Vector(vec![FnArg::Constant(NonIntegerConstant::Text(Rc::new("foo".to_string()))),
+                                       FnArg::EntidOrInteger(2)]);
+        match cc.typed_values_from_arg(&schema, &x, mixed, ValueTypeSet::any()).expect("conversion") {
+            ValueConversion::Impossible(because) => {
+                assert_eq!(because, EmptyBecause::TypeMismatch {
+                    var: x.clone(),
+                    existing: ValueTypeSet::of_one(ValueType::String),
+                    desired: ValueTypeSet::of_longs(),
+                });
+            },
+            _ => panic!("expected an impossible conversion"),
+        }
+
+        // Empty vectors produce empty collections.
+        match cc.typed_values_from_arg(&schema, &x, FnArg::Vector(vec![]), ValueTypeSet::any()).expect("conversion") {
+            ValueConversion::ValColl(vs) => assert!(vs.is_empty()),
+            _ => panic!("expected a collection"),
+        }
+    }
+
+    #[test]
+    fn test_non_positive_entid() {
+        let schema = prepopulated_schema();
+        let cc = ConjoiningClauses::default();
+        let x = Variable::from_valid_name("?x");
+
+        // A negative integer can't be a ref.
+        match cc.typed_value_from_arg(&schema, &x, FnArg::EntidOrInteger(-5), ValueTypeSet::of_one(ValueType::Ref)).expect("conversion") {
+            ValueConversion::Impossible(because) => {
+                assert_eq!(because, EmptyBecause::NonPositiveEntid(-5));
+                assert!(because.is_type_mismatch());
+            },
+            _ => panic!("expected an impossible conversion"),
+        }
+
+        // A positive integer can't be a string, which is a different problem.
+        match cc.typed_value_from_arg(&schema, &x, FnArg::EntidOrInteger(5), ValueTypeSet::of_one(ValueType::String)).expect("conversion") {
+            ValueConversion::Impossible(because) => {
+                assert_eq!(because, EmptyBecause::TypeMismatch {
+                    var: x.clone(),
+                    existing: ValueTypeSet::of_one(ValueType::String),
+                    desired: ValueTypeSet::of_longs(),
+                });
+                assert!(because.is_type_mismatch());
+            },
+            _ => panic!("expected an impossible conversion"),
+        }
+    }
+
+    #[test]
+    fn test_typed_value_from_arg_instant_millis() {
+        let schema = prepopulated_schema();
+        let cc = ConjoiningClauses::default();
+        let x = Variable::from_valid_name("?x");
+
+        // If only an instant will do, an integer is taken to be epoch millis.
+        match cc.typed_value_from_arg(&schema, &x, FnArg::EntidOrInteger(1700000000000), ValueTypeSet::of_one(ValueType::Instant)).expect("conversion") {
+            ValueConversion::Val(v) => assert_eq!(v, TypedValue::Instant(DateTime::<Utc>::from_micros(1700000000000000))),
+            _ => panic!("expected a value"),
+        }
+
+        // If a long is also possible, we don't guess.
+        let mut ambiguous = ValueTypeSet::of_one(ValueType::Instant);
+        ambiguous.insert(ValueType::Long);
+        match cc.typed_value_from_arg(&schema, &x, FnArg::EntidOrInteger(1700000000000), ambiguous).expect("conversion") {
+            ValueConversion::Val(v) => assert_eq!(v, TypedValue::Long(1700000000000)),
+            _ => panic!("expected a value"),
+        }
+
+        // Some integers are too large to be instants.
+        match cc.typed_value_from_arg(&schema, &x, FnArg::EntidOrInteger(i64::max_value()), ValueTypeSet::of_one(ValueType::Instant)).expect("conversion") {
+            ValueConversion::Impossible(because) => assert_eq!(because, EmptyBecause::NonInstantArgument),
+            _ => panic!("expected an impossible conversion"),
+        }
+    }
+
+    #[test]
+    fn test_ident_lookups_are_memoized() {
+        let schema = prepopulated_schema();
+        let cc = ConjoiningClauses::default();
+        let x = Variable::from_valid_name("?x");
+        let refs = ValueTypeSet::of_one(ValueType::Ref);
+
+        for _ in 0..3 {
+            match cc.typed_value_from_arg(&schema, &x, FnArg::IdentOrKeyword(NamespacedKeyword::new("foo", "bar")), refs).expect("conversion") {
+                ValueConversion::Val(v) => assert_eq!(v, TypedValue::Ref(99)),
+                _ => panic!("expected a value"),
+            }
+        }
+
+        // Failed lookups are remembered, too.
+        match cc.typed_value_from_arg(&schema, &x, FnArg::IdentOrKeyword(NamespacedKeyword::new("foo", "missing")), refs).expect("conversion") {
+            ValueConversion::Impossible(_) => {},
+            _ => panic!("expected an impossible conversion"),
+        }
+
+        assert_eq!(cc.ident_cache.borrow().len(), 2);
+        assert_eq!(cc.ident_cache.borrow().get(&NamespacedKeyword::new("foo", "missing")), Some(&None));
+
+        // Derived CCs share the cache; fresh ones don't.
+        assert_eq!(cc.make_receptacle().ident_cache.borrow().len(), 2);
+        assert!(ConjoiningClauses::default().ident_cache.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_typed_value_from_arg_uuid_string() {
+        let schema = prepopulated_schema();
+        let cc = ConjoiningClauses::default();
+        let x = Variable::from_valid_name("?x");
+        let text = |s: &str| FnArg::Constant(NonIntegerConstant::Text(Rc::new(s.to_string())));
+        let canonical = "550e8400-e29b-41d4-a716-446655440000";
+
+        // If only a UUID will do, a string is parsed as one.
+        match cc.typed_value_from_arg(&schema, &x, text(canonical), ValueTypeSet::of_one(ValueType::Uuid)).expect("conversion") {
+            ValueConversion::Val(v) => assert_eq!(v, TypedValue::Uuid(Uuid::parse_str(canonical).unwrap())),
+            _ => panic!("expected a value"),
+        }
+
+        // A string that isn't a UUID says so.
+        match cc.typed_value_from_arg(&schema, &x, text("550e8400-nope"), ValueTypeSet::of_one(ValueType::Uuid)).expect("conversion") {
+            ValueConversion::Impossible(because) => {
+                assert_eq!(because, EmptyBecause::MalformedUuid("550e8400-nope".to_string()));
+                assert!(because.is_type_mismatch());
+            },
+            _ => panic!("expected an impossible conversion"),
+        }
+
+        // If a string is also possible, we don't guess.
+        let mut ambiguous = ValueTypeSet::of_one(ValueType::Uuid);
+        ambiguous.insert(ValueType::String);
+        match cc.typed_value_from_arg(&schema, &x, text(canonical), ambiguous).expect("conversion") {
+            ValueConversion::Val(v) => assert_eq!(v, TypedValue::typed_string(canonical)),
+            _ => panic!("expected a value"),
         }
     }
 }
@@ -0,0 +1,216 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+use mentat_core::{
+    Attribute,
+    Entid,
+    Schema,
+    ValueTypeSet,
+};
+
+use mentat_query::{
+    Binding,
+    FnArg,
+    PlainSymbol,
+    SrcVar,
+    Variable,
+    WhereFn,
+};
+
+use clauses::{
+    ConjoiningClauses,
+};
+
+use clauses::convert::ValueConversion;
+
+use errors::{
+    BindingError,
+    ErrorKind,
+    Result,
+};
+
+use types::{
+    ColumnAlternation,
+    ColumnConstraint,
+    ColumnConstraintOrAlternation,
+    ColumnIntersection,
+    DatomsColumn,
+    DatomsTable,
+    Fallback,
+    OuterJoin,
+    QualifiedAlias,
+    QueryValue,
+    SourceAlias,
+};
+
+/// `get-else` and `get-some` read attributes that an entity might not have. We `LEFT JOIN` a
+/// datoms table for each attribute, so rows survive whether or not it's present, and the projector
+/// reads the first value that isn't NULL. Because the bound variable has no column of its own, it
+/// can only be projected.
+impl ConjoiningClauses {
+    /// `[(get-else $ ?e :foo/bar default) ?v]` binds `?v` to the value of `:foo/bar` on `?e`, or
+    /// to `default` if `?e` has no such value. `default` must be of the attribute's value type.
+    pub fn apply_get_else(&mut self, schema: &Schema, where_fn: WhereFn) -> Result<()> {
+        if where_fn.args.len() != 4 {
+            bail!(ErrorKind::InvalidNumberOfArguments(where_fn.operator.clone(), where_fn.args.len(), 4));
+        }
+
+        let target = self.fallback_target(&where_fn.operator, where_fn.binding)?;
+        let mut args = where_fn.args.into_iter();
+        let e = self.fallback_entity(&where_fn.operator, args.next().unwrap(), args.next().unwrap())?;
+        let (a, attribute) = self.fallback_attribute(schema, &where_fn.operator, args.next().unwrap(), 2)?;
+
+        let default = match self.typed_value_from_arg(schema, &target, args.next().unwrap(), ValueTypeSet::of_one(attribute.value_type))? {
+            ValueConversion::Val(value) => value,
+            ValueConversion::ValColl(_) |
+            ValueConversion::Impossible(_) => {
+                let name = schema.get_ident(a).map_or_else(|| a.to_string(), |ident| ident.to_string());
+                bail!(ErrorKind::InvalidGetElseDefault(name, attribute.value_type));
+            },
+        };
+
+        let entity_column = self.fallback_entity_column(&e)?;
+        self.check_fallback_target(&target)?;
+
+        let column = self.outer_join_attribute(entity_column, a, &attribute);
+        self.constrain_var_to_type(target.clone(), attribute.value_type);
+        self.fallback_bindings.insert(target, Fallback {
+            columns: vec![column],
+            default: Some(default),
+        });
+        Ok(())
+    }
+
+    /// `[(get-some $ ?e :foo/bar :foo/baz …) ?v]` binds `?v` to the value of the first of the
+    /// attributes that `?e` has. Entities with none of them don't match. The attributes must all
+    /// have the same value type.
+    pub fn apply_get_some(&mut self, schema: &Schema, where_fn: WhereFn) -> Result<()> {
+        if where_fn.args.len() < 3 {
+            bail!(ErrorKind::InvalidNumberOfArguments(where_fn.operator.clone(), where_fn.args.len(), 3));
+        }
+
+        let target = self.fallback_target(&where_fn.operator, where_fn.binding)?;
+        let mut args = where_fn.args.into_iter();
+        let e = self.fallback_entity(&where_fn.operator, args.next().unwrap(), args.next().unwrap())?;
+
+        let mut attributes: Vec<(Entid, Attribute)> = vec![];
+        for (i, arg) in args.enumerate() {
+            attributes.push(self.fallback_attribute(schema, &where_fn.operator, arg, i + 2)?);
+        }
+
+        let types: ValueTypeSet = attributes.iter().map(|&(_, ref attribute)| attribute.value_type).collect();
+        let value_type = match types.exemplar() {
+            Some(value_type) if types.is_unit() => value_type,
+            _ => bail!(ErrorKind::IncompatibleFallbackAttributes(where_fn.operator.clone(), types)),
+        };
+
+        let entity_column = self.fallback_entity_column(&e)?;
+        self.check_fallback_target(&target)?;
+
+        let columns: Vec<QualifiedAlias> =
+            attributes.iter()
+                      .map(|&(a, ref attribute)| self.outer_join_attribute(entity_column.clone(), a, attribute))
+                      .collect();
+
+        // At least one of the attributes must be present.
+        let present = ColumnAlternation(columns.iter()
+                                               .map(|qa| vec![ColumnConstraint::NotNull(qa.clone())].into())
+                                               .collect());
+        self.wheres.add(ColumnConstraintOrAlternation::Alternation(present));
+
+        self.constrain_var_to_type(target.clone(), value_type);
+        self.fallback_bindings.insert(target, Fallback {
+            columns: columns,
+            default: None,
+        });
+        Ok(())
+    }
+
+    fn fallback_target(&self, operator: &PlainSymbol, binding: Binding) -> Result<Variable> {
+        if binding.is_empty() {
+            // The binding must introduce at least one bound variable.
+            bail!(ErrorKind::InvalidBinding(operator.clone(), BindingError::NoBoundVariable));
+        }
+
+        match binding {
+            Binding::BindScalar(var) => Ok(var),
+            Binding::BindColl(_) |
+            Binding::BindRel(_) |
+            Binding::BindTuple(_) => bail!(ErrorKind::InvalidBinding(operator.clone(), BindingError::ExpectedBindScalar)),
+        }
+    }
+
+    fn fallback_entity(&self, operator: &PlainSymbol, source: FnArg, entity: FnArg) -> Result<Variable> {
+        // TODO: process source variables.
+        match source {
+            FnArg::SrcVar(SrcVar::DefaultSrc) => {},
+            _ => bail!(ErrorKind::InvalidArgument(operator.clone(), "source variable".into(), 0)),
+        }
+
+        match entity {
+            FnArg::Variable(var) => Ok(var),
+            _ => bail!(ErrorKind::InvalidArgument(operator.clone(), "variable".into(), 1)),
+        }
+    }
+
+    fn fallback_attribute(&self, schema: &Schema, operator: &PlainSymbol, arg: FnArg, position: usize) -> Result<(Entid, Attribute)> {
+        let a = match arg {
+            FnArg::IdentOrKeyword(i) => self.entid_for_ident(schema, &i),
+            FnArg::EntidOrInteger(a) => Some(a),
+            _ => None,
+        };
+        let a = a.ok_or(ErrorKind::InvalidArgument(operator.clone(), "attribute".into(), position))?;
+        let attribute = schema.attribute_for_entid(a).cloned().ok_or(ErrorKind::InvalidArgument(operator.clone(), "attribute".into(), position))?;
+
+        // With several values there's no single one to fall back from.
+        if attribute.multival {
+            bail!(ErrorKind::InvalidArgument(operator.clone(), "cardinality-one attribute".into(), position));
+        }
+        Ok((a, attribute))
+    }
+
+    /// The entity must already be joined into the query for us to join against it.
+    fn fallback_entity_column(&self, e: &Variable) -> Result<QualifiedAlias> {
+        match self.column_bindings.get(e) {
+            Some(columns) => Ok(columns[0].clone()),
+            None => bail!(ErrorKind::UnboundVariable(e.name())),
+        }
+    }
+
+    fn check_fallback_target(&self, target: &Variable) -> Result<()> {
+        if self.column_bindings.contains_key(target) ||
+           self.is_value_bound(target) ||
+           self.type_of_bindings.contains_key(target) ||
+           self.fallback_bindings.contains_key(target) ||
+           self.computed_bindings.contains_key(target) {
+            bail!(ErrorKind::FallbackCanOnlyBeProjected(target.name()));
+        }
+        Ok(())
+    }
+
+    /// `LEFT JOIN` a table holding attribute `a` of the entity in `entity_column`, returning the
+    /// column that holds its value.
+    fn outer_join_attribute(&mut self, entity_column: QualifiedAlias, a: Entid, attribute: &Attribute) -> QualifiedAlias {
+        let table = if attribute.fulltext { DatomsTable::FulltextDatoms } else { DatomsTable::Datoms };
+        let alias = self.next_alias_for_table(table);
+        let on: ColumnIntersection = vec![
+            ColumnConstraint::Equals(QualifiedAlias::new(alias.clone(), DatomsColumn::Entity),
+                                     QueryValue::Column(entity_column)),
+            ColumnConstraint::Equals(QualifiedAlias::new(alias.clone(), DatomsColumn::Attribute),
+                                     QueryValue::Entid(a)),
+        ].into();
+
+        self.outer_joins.push(OuterJoin {
+            table: SourceAlias(table, alias.clone()),
+            on: on,
+        });
+        QualifiedAlias::new(alias, DatomsColumn::Value)
+    }
+}
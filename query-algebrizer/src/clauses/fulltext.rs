@@ -40,7 +40,6 @@ use types::{
     ColumnConstraint,
     DatomsColumn,
     DatomsTable,
-    EmptyBecause,
     FulltextColumn,
     QualifiedAlias,
     QueryValue,
@@ -127,10 +126,9 @@ impl ConjoiningClauses {
         let attribute = schema.attribute_for_entid(a).cloned().ok_or(ErrorKind::InvalidArgument(where_fn.operator.clone(), "attribute".into(), 1))?;
 
         if !attribute.fulltext {
-            // We can never get results from a non-fulltext attribute!
-            println!("Can't run fulltext on non-fulltext attribute {}.", a);
-            self.mark_known_empty(EmptyBecause::InvalidAttributeEntid(a));
-            return Ok(());
+            // We can never get results -- or scores -- from a non-fulltext attribute!
+            let name = schema.get_ident(a).map_or_else(|| a.to_string(), |ident| ident.to_string());
+            bail!(ErrorKind::NonFulltextAttribute(name));
         }
 
         let fulltext_values_alias = self.next_alias_for_table(DatomsTable::FulltextValues);
@@ -240,9 +238,8 @@ impl ConjoiningClauses {
                 bail!(ErrorKind::InvalidBinding(var.name(), BindingError::UnexpectedBinding));
             }
 
-            // We bind the value ourselves. This handily takes care of substituting into existing uses.
-            // TODO: produce real scores using SQLite's matchinfo.
-            self.bind_value(var, TypedValue::Double(0.0.into()));
+            // The score is computed by SQLite from the match itself.
+            self.bind_column_to_var(schema, fulltext_values_alias.clone(), Column::Fulltext(FulltextColumn::Score), var.clone());
         }
 
         Ok(())
@@ -273,6 +270,8 @@ mod testing {
         associate_ident,
     };
 
+    use errors::Error;
+
     #[test]
     fn test_apply_fulltext() {
         let mut cc = ConjoiningClauses::default();
@@ -324,7 +323,7 @@ mod testing {
                                                            QueryValue::TypedValue(TypedValue::String(Rc::new("needle".into())))).into());
 
         let bindings = cc.column_bindings;
-        assert_eq!(bindings.len(), 3);
+        assert_eq!(bindings.len(), 4);
 
         assert_eq!(bindings.get(&Variable::from_valid_name("?entity")).expect("column binding for ?entity").clone(),
                    vec![QualifiedAlias("datoms01".to_string(), Column::Fixed(DatomsColumn::Entity))]);
@@ -333,10 +332,8 @@ mod testing {
         assert_eq!(bindings.get(&Variable::from_valid_name("?tx")).expect("column binding for ?tx").clone(),
                    vec![QualifiedAlias("datoms01".to_string(), Column::Fixed(DatomsColumn::Tx))]);
 
-        // Score is a value binding.
-        let values = cc.value_bindings;
-        assert_eq!(values.get(&Variable::from_valid_name("?score")).expect("column binding for ?score").clone(),
-                   TypedValue::Double(0.0.into()));
+        assert_eq!(bindings.get(&Variable::from_valid_name("?score")).expect("column binding for ?score").clone(),
+                   vec![QualifiedAlias("fulltext_values00".to_string(), Column::Fulltext(FulltextColumn::Score))]);
 
         let known_types = cc.known_types;
         assert_eq!(known_types.len(), 4);
@@ -352,7 +349,7 @@ mod testing {
 
         let mut cc = ConjoiningClauses::default();
         let op = PlainSymbol::new("fulltext");
-        cc.apply_fulltext(&schema, WhereFn {
+        let result = cc.apply_fulltext(&schema, WhereFn {
             operator: op,
             args: vec![
                 FnArg::SrcVar(SrcVar::DefaultSrc),
@@ -363,9 +360,12 @@ mod testing {
                                            VariableOrPlaceholder::Variable(Variable::from_valid_name("?value")),
                                            VariableOrPlaceholder::Variable(Variable::from_valid_name("?tx")),
                                            VariableOrPlaceholder::Variable(Variable::from_valid_name("?score"))]),
-        }).expect("to be able to apply_fulltext");
+        });
 
-        // It's not a fulltext attribute, so the CC cannot yield results.
-        assert!(cc.is_known_empty());
+        // It's not a fulltext attribute, so we can neither search it nor score it.
+        match result {
+            Err(Error(ErrorKind::NonFulltextAttribute(name), _)) => assert_eq!(name, ":foo/bar"),
+            _ => panic!("Expected a non-fulltext attribute to fail."),
+        }
     }
 }
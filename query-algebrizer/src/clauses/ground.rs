@@ -8,6 +8,9 @@
 // CONDITIONS OF ANY KIND, either express or implied. See the License for the
 // specific language governing permissions and limitations under the License.
 
+use std::collections::BTreeSet;
+use std::rc::Rc;
+
 use mentat_core::{
     Schema,
     TypedValue,
@@ -18,6 +21,9 @@ use mentat_core::{
 use mentat_query::{
     Binding,
     FnArg,
+    Pattern,
+    PatternNonValuePlace,
+    PatternValuePlace,
     Variable,
     VariableOrPlaceholder,
     WhereFn,
@@ -82,12 +88,55 @@ impl ConjoiningClauses {
         }
     }
 
+    /// Convert a ground argument for `var`, just as `typed_value_from_arg` does, but also
+    /// compare the result against any value to which `var` is already bound -- by an input or
+    /// by an earlier `ground`. A differing value is `Impossible`; an equal one is harmless.
+    ///
+    /// Grounding a bound input passes its value through, so the input's type must agree with what
+    /// we know of both the input and `var`.
+    fn ground_value_from_arg<'s>(&self, schema: &'s Schema, var: &Variable, arg: FnArg, known_types: ValueTypeSet) -> Result<ValueConversion> {
+        if let FnArg::Variable(ref in_var) = arg {
+            if let Some(in_value) = self.bound_value(in_var) {
+                let provided = in_value.value_type();
+                let in_types = self.known_type_set(in_var);
+                for &(v, existing) in [(in_var, in_types), (var, known_types)].iter() {
+                    if !existing.contains(provided) {
+                        return Ok(ValueConversion::Impossible(EmptyBecause::TypeMismatch {
+                            var: v.clone(),
+                            existing: existing,
+                            desired: ValueTypeSet::of_one(provided),
+                        }));
+                    }
+                }
+            }
+        }
+
+        let conversion = self.typed_value_from_arg(schema, var, arg, known_types)?;
+        if let ValueConversion::Val(ref value) = conversion {
+            if let Some(existing) = self.bound_value(var) {
+                if &existing != value {
+                    return Ok(ValueConversion::Impossible(EmptyBecause::ConflictingBindings {
+                        var: var.clone(),
+                        existing: existing,
+                        desired: value.clone(),
+                    }));
+                }
+            }
+        }
+        Ok(conversion)
+    }
+
     /// Constrain the CC to associate the given var with the given ground argument.
     /// Marks known-empty on failure.
     fn apply_ground_var<'s>(&mut self, schema: &'s Schema, var: Variable, arg: FnArg) -> Result<()> {
+        if let FnArg::Map(entries) = arg {
+            return self.apply_ground_lookup_ref(schema, var, entries);
+        }
+
         let known_types = self.known_type_set(&var);
-        match self.typed_value_from_arg(schema, &var, arg, known_types)? {
+        match self.ground_value_from_arg(schema, &var, arg, known_types)? {
             ValueConversion::Val(value) => self.apply_ground_value(var, value),
+            ValueConversion::ValColl(values) => bail!(ErrorKind::InvalidGroundConstant(format!("{:?}", values))),
             ValueConversion::Impossible(because) => {
                 self.mark_known_empty(because);
                 Ok(())
@@ -95,24 +144,217 @@ impl ConjoiningClauses {
         }
     }
 
-    /// Marks known-empty on failure.
+    /// Bind `var` to the entity identified by a lookup ref like `{:person/email "a@b.com"}`.
+    ///
+    /// We can't resolve the lookup ref to an entid without the store, so we instead join against
+    /// the datoms table, just as if the query included `[?var :person/email "a@b.com"]`. The
+    /// attribute is unique, so that pattern matches at most one entity.
+    ///
+    /// Marks known-empty if the attribute is unknown or the value can't match it; fails if the
+    /// attribute isn't unique.
+    fn apply_ground_lookup_ref<'s>(&mut self, schema: &'s Schema, var: Variable, entries: Vec<(FnArg, FnArg)>) -> Result<()> {
+        if entries.len() != 1 {
+            bail!(ErrorKind::InvalidGroundConstant(format!("{:?}", FnArg::Map(entries))));
+        }
+        let (attribute, value) = entries.into_iter().next().unwrap();
+
+        let attribute = match attribute {
+            FnArg::IdentOrKeyword(ident) => ident,
+            arg => bail!(ErrorKind::InvalidGroundConstant(format!("{:?}", arg))),
+        };
+
+        let entid = match schema.get_entid(&attribute) {
+            Some(entid) => entid,
+            None => {
+                self.mark_known_empty(EmptyBecause::UnresolvedIdent(attribute));
+                return Ok(());
+            },
+        };
+
+        match schema.attribute_for_entid(entid) {
+            Some(a) if a.unique.is_some() => {},
+            Some(_) => bail!(ErrorKind::NonUniqueLookupRefAttribute(attribute)),
+            None => {
+                self.mark_known_empty(EmptyBecause::InvalidAttributeEntid(entid));
+                return Ok(());
+            },
+        }
+
+        let value = match value {
+            FnArg::EntidOrInteger(x) => PatternValuePlace::EntidOrInteger(x),
+            FnArg::IdentOrKeyword(x) => PatternValuePlace::IdentOrKeyword(Rc::new(x)),
+            FnArg::Constant(c) => PatternValuePlace::Constant(c),
+            FnArg::Variable(in_var) => {
+                // As elsewhere in `ground`, variables must be inputs.
+                if !self.input_variables.contains(&in_var) {
+                    bail!(ErrorKind::UnboundVariable((*in_var.0).clone()));
+                }
+                PatternValuePlace::Variable(in_var)
+            },
+            arg @ FnArg::SrcVar(_) |
+            arg @ FnArg::Keyword(_) |
+            arg @ FnArg::Vector(_) |
+            arg @ FnArg::Set(_) |
+            arg @ FnArg::Map(_) |
+            arg @ FnArg::Nil => bail!(ErrorKind::InvalidGroundConstant(format!("{:?}", arg))),
+        };
+
+        // The pattern takes care of any existing binding or type constraint on `var`.
+        self.apply_pattern(schema, Pattern {
+            source: None,
+            entity: PatternNonValuePlace::Variable(var),
+            attribute: PatternNonValuePlace::Entid(entid),
+            value: value,
+            tx: PatternNonValuePlace::Placeholder,
+        });
+        Ok(())
+    }
+
+    /// `value` has already been checked against any existing binding, so if `var` is bound
+    /// it's bound to this same value, and there's nothing more to constrain.
     fn apply_ground_value(&mut self, var: Variable, value: TypedValue) -> Result<()> {
-        if let Some(existing) = self.bound_value(&var) {
-            if existing != value {
-                self.mark_known_empty(EmptyBecause::ConflictingBindings {
-                    var: var.clone(),
-                    existing: existing.clone(),
-                    desired: value,
-                });
-                return Ok(())
+        if !self.is_value_bound(&var) {
+            self.bind_value(&var, value);
+        }
+        Ok(())
+    }
+
+    /// Bind `var` to each of the values in `children`, which must all have the same type.
+    /// If `distinct` is true, repeated values are bound only once.
+    ///
+    /// Where `var` must be a ref, keywords are idents, like `[:color/red :color/green]` for an
+    /// enumeration, and are resolved to their entids. Values that can't match are skipped, but an
+    /// ident that isn't in the schema fails with `UnknownGroundIdent`.
+    fn apply_ground_coll<'s>(&mut self, schema: &'s Schema, var: Variable, children: Vec<FnArg>, distinct: bool) -> Result<()> {
+        if children.is_empty() {
+            let arg = if distinct { FnArg::Set(children) } else { FnArg::Vector(children) };
+            bail!(ErrorKind::InvalidGroundConstant(format!("{:?}", arg)));
+        }
+
+        // Turn a collection of arguments into a Vec of `TypedValue`s of the same type.
+        let known_types = self.known_type_set(&var);
+        // Check that every value has the same type.
+        let mut accumulated_types = ValueTypeSet::none();
+        let mut skip: Option<EmptyBecause> = None;
+        let mut values = children.into_iter()
+                                 .filter_map(|arg| -> Option<Result<TypedValue>> {
+                                     // We need to get conversion errors out.
+                                     // We also want to mark known-empty on impossibilty, but
+                                     // still detect serious errors.
+                                     match self.ground_value_from_arg(schema, &var, arg, known_types) {
+                                         Ok(ValueConversion::Val(tv)) => {
+                                             if accumulated_types.insert(tv.value_type()) &&
+                                                !accumulated_types.is_unit() {
+                                                 // Values not all of the same type.
+                                                 Some(Err(ErrorKind::InvalidGroundConstant(format!("{:?}", tv)).into()))
+                                             } else {
+                                                 Some(Ok(tv))
+                                             }
+                                         },
+                                         Ok(ValueConversion::ValColl(values)) => {
+                                             Some(Err(ErrorKind::InvalidGroundConstant(format!("{:?}", values)).into()))
+                                         },
+                                         Ok(ValueConversion::Impossible(EmptyBecause::UnresolvedIdent(ident))) => {
+                                             // An enum value we don't know is surely a typo.
+                                             Some(Err(ErrorKind::UnknownGroundIdent(ident).into()))
+                                         },
+                                         Ok(ValueConversion::Impossible(because)) => {
+                                             // Skip this value.
+                                             skip = Some(because);
+                                             None
+                                         },
+                                         Err(e) => Some(Err(e.into())),
+                                     }
+                                 })
+                                 .collect::<Result<Vec<TypedValue>>>()?;
+
+        if distinct {
+            // Distinct arguments can still convert to the same value -- an ident and its entid,
+            // say. Collapse those, keeping the first occurrence.
+            let mut seen = BTreeSet::new();
+            values.retain(|v| seen.insert(v.clone()));
+        }
+
+        if values.is_empty() {
+            let because = skip.expect("we skipped all rows for a reason");
+            self.mark_known_empty(because);
+            return Ok(());
+        }
+
+        // Otherwise, we now have the values and the type.
+        let types = vec![accumulated_types.exemplar().unwrap()];
+        let names = vec![var.clone()];
+
+        self.collect_named_bindings(schema, names, types, values);
+        Ok(())
+    }
+
+    /// Bind `var`, from a collection binding like `:in [?e ...]`, to each of `values`, much as
+    /// `[(ground [...]) [?e ...]]` would. See `typed_values_from_input`. An empty collection
+    /// makes the query known-empty.
+    pub fn apply_input_collection<'s>(&mut self, schema: &'s Schema, var: Variable, values: Vec<TypedValue>) -> Result<()> {
+        if values.is_empty() {
+            self.mark_known_empty(EmptyBecause::EmptyInputCollection(var));
+            return Ok(());
+        }
+
+        let known_types = self.known_type_set(&var);
+        let values = self.typed_values_from_input(schema, &var, values, known_types)?;
+        let types = vec![values[0].value_type()];
+        self.collect_named_bindings(schema, vec![var], types, values);
+        Ok(())
+    }
+
+    /// Bind the variables of a relation binding like `:in [[?e ?name]]` to the columns of `rows`,
+    /// much as `[(ground [[...] ...]) [[?e ?name]]]` would. Every row must have a value for each
+    /// of `places`, and the values of each column are converted as for `apply_input_collection`.
+    pub fn apply_input_relation<'s>(&mut self, schema: &'s Schema, places: Vec<VariableOrPlaceholder>, rows: Vec<Vec<TypedValue>>) -> Result<()> {
+        let width = places.len();
+        if let Some(row) = rows.iter().find(|row| row.len() != width) {
+            bail!(ErrorKind::InputRowLengthMismatch(width, row.len()));
+        }
+
+        let names: Vec<Variable> = places.iter().filter_map(|p| p.var().cloned()).collect();
+        if rows.is_empty() {
+            self.mark_known_empty(EmptyBecause::EmptyInputCollection(names[0].clone()));
+            return Ok(());
+        }
+
+        // Split the rows into a column for each variable, convert each column, and then stitch
+        // them back together into the row-major matrix that a computed table expects.
+        let mut columns: Vec<Vec<TypedValue>> = vec![Vec::with_capacity(rows.len()); names.len()];
+        for row in rows.into_iter() {
+            let named = row.into_iter()
+                           .zip(places.iter())
+                           .filter_map(|(value, place)| place.var().map(|_| value));
+            for (value, column) in named.zip(columns.iter_mut()) {
+                column.push(value);
             }
-        } else {
-            self.bind_value(&var, value.clone());
         }
 
+        let mut converted = Vec::with_capacity(names.len());
+        for (name, column) in names.iter().zip(columns.into_iter()) {
+            let known_types = self.known_type_set(name);
+            converted.push(self.typed_values_from_input(schema, name, column, known_types)?);
+        }
+
+        let types = converted.iter().map(|column| column[0].value_type()).collect();
+        let height = converted[0].len();
+        let mut matrix = Vec::with_capacity(names.len() * height);
+        for i in 0..height {
+            for column in converted.iter() {
+                matrix.push(column[i].clone());
+            }
+        }
+
+        self.collect_named_bindings(schema, names, types, matrix);
         Ok(())
     }
 
+    /// `nil` is the absence of a value, and nothing matches it: `[(ground nil) ?x]` makes the
+    /// query known-empty, and `nil` elements of a collection or relation are skipped. Inside an
+    /// `or` that's enough to express an alternative in which `?x` is absent: the arm drops out,
+    /// and `?x`, if it isn't a join variable, stays unbound outside the `or`.
     pub fn apply_ground<'s>(&mut self, schema: &'s Schema, where_fn: WhereFn) -> Result<()> {
         if where_fn.args.len() != 1 {
             bail!(ErrorKind::InvalidNumberOfArguments(where_fn.operator.clone(), where_fn.args.len(), 1));
@@ -153,58 +395,16 @@ impl ConjoiningClauses {
             // implemented as a subquery with a projection list and a set of values.
             // The difference is that BindColl has only a single variable, and its values
             // are all in a single structure. That makes it substantially simpler!
-            (Binding::BindColl(var), FnArg::Vector(children)) => {
-                if children.is_empty() {
-                    bail!(ErrorKind::InvalidGroundConstant);
-                }
-
-                // Turn a collection of arguments into a Vec of `TypedValue`s of the same type.
-                let known_types = self.known_type_set(&var);
-                // Check that every value has the same type.
-                let mut accumulated_types = ValueTypeSet::none();
-                let mut skip: Option<EmptyBecause> = None;
-                let values = children.into_iter()
-                                     .filter_map(|arg| -> Option<Result<TypedValue>> {
-                                         // We need to get conversion errors out.
-                                         // We also want to mark known-empty on impossibilty, but
-                                         // still detect serious errors.
-                                         match self.typed_value_from_arg(schema, &var, arg, known_types) {
-                                             Ok(ValueConversion::Val(tv)) => {
-                                                 if accumulated_types.insert(tv.value_type()) &&
-                                                    !accumulated_types.is_unit() {
-                                                     // Values not all of the same type.
-                                                     Some(Err(ErrorKind::InvalidGroundConstant.into()))
-                                                 } else {
-                                                     Some(Ok(tv))
-                                                 }
-                                             },
-                                             Ok(ValueConversion::Impossible(because)) => {
-                                                 // Skip this value.
-                                                 skip = Some(because);
-                                                 None
-                                             },
-                                             Err(e) => Some(Err(e.into())),
-                                         }
-                                     })
-                                     .collect::<Result<Vec<TypedValue>>>()?;
-
-                if values.is_empty() {
-                    let because = skip.expect("we skipped all rows for a reason");
-                    self.mark_known_empty(because);
-                    return Ok(());
-                }
-
-                // Otherwise, we now have the values and the type.
-                let types = vec![accumulated_types.exemplar().unwrap()];
-                let names = vec![var.clone()];
+            (Binding::BindColl(var), FnArg::Vector(children)) =>
+                self.apply_ground_coll(schema, var, children, false),
 
-                self.collect_named_bindings(schema, names, types, values);
-                Ok(())
-            },
+            // A set is just like a vector, but its values must be distinct.
+            (Binding::BindColl(var), FnArg::Set(children)) =>
+                self.apply_ground_coll(schema, var, children, true),
 
             (Binding::BindRel(places), FnArg::Vector(rows)) => {
                 if rows.is_empty() {
-                    bail!(ErrorKind::InvalidGroundConstant);
+                    bail!(ErrorKind::InvalidGroundConstant(format!("{:?}", FnArg::Vector(rows))));
                 }
 
                 // Grab the known types to which these args must conform, and track
@@ -225,7 +425,7 @@ impl ConjoiningClauses {
 
                 if expected_width == 0 {
                     // They can't all be placeholders.
-                    bail!(ErrorKind::InvalidGroundConstant);
+                    bail!(ErrorKind::InvalidGroundConstant(format!("{:?}", FnArg::Vector(rows))));
                 }
 
                 // Accumulate values into `matrix` and types into `a_t_f_c`.
@@ -241,7 +441,7 @@ impl ConjoiningClauses {
                         FnArg::Vector(cols) => {
                             // Make sure that every row is the same length.
                             if cols.len() != full_width {
-                                bail!(ErrorKind::InvalidGroundConstant);
+                                bail!(ErrorKind::InvalidGroundConstant(format!("{:?}", FnArg::Vector(cols))));
                             }
 
                             // TODO: don't accumulate twice.
@@ -254,8 +454,9 @@ impl ConjoiningClauses {
                                 // If any value in the row is impossible, then skip the row.
                                 // If all rows are impossible, fail the entire CC.
                                 if let &Some(ref pair) = pair {
-                                    match self.typed_value_from_arg(schema, &pair.0, col, pair.1)? {
+                                    match self.ground_value_from_arg(schema, &pair.0, col, pair.1)? {
                                         ValueConversion::Val(tv) => vals.push(tv),
+                                        ValueConversion::ValColl(values) => bail!(ErrorKind::InvalidGroundConstant(format!("{:?}", values))),
                                         ValueConversion::Impossible(because) => {
                                             // Skip this row. It cannot produce bindings.
                                             skip = Some(because);
@@ -276,13 +477,13 @@ impl ConjoiningClauses {
                                 let inserted = acc.insert(val.value_type());
                                 if inserted && !acc.is_unit() {
                                     // Heterogeneous types.
-                                    bail!(ErrorKind::InvalidGroundConstant);
+                                    bail!(ErrorKind::InvalidGroundConstant(format!("{:?}", val)));
                                 }
                                 matrix.push(val);
                             }
 
                         },
-                        _ => bail!(ErrorKind::InvalidGroundConstant),
+                        row => bail!(ErrorKind::InvalidGroundConstant(format!("{:?}", row))),
                     }
                 }
 
@@ -308,7 +509,7 @@ impl ConjoiningClauses {
                 self.collect_named_bindings(schema, names, types, matrix);
                 Ok(())
             },
-            (_, _) => bail!(ErrorKind::InvalidGroundConstant),
+            (_, arg) => bail!(ErrorKind::InvalidGroundConstant(format!("{:?}", arg))),
         }
     }
 }
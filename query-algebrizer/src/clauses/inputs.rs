@@ -8,17 +8,25 @@
 // CONDITIONS OF ANY KIND, either express or implied. See the License for the
 // specific language governing permissions and limitations under the License.
 
-use std::collections::BTreeMap;
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
 
 use mentat_core::{
+    Schema,
     TypedValue,
     ValueType,
+    ValueTypeSet,
 };
 
 use mentat_query::{
+    FindQuery,
     Variable,
 };
 
+use algebrize;
+
 use errors::{
     ErrorKind,
     Result,
@@ -30,10 +38,20 @@ use errors::{
 /// the bindings that will be used at execution time.
 /// When built correctly, `types` is guaranteed to contain the types of `values` -- use
 /// `QueryInputs::new` or `QueryInputs::with_values` to construct an instance.
+///
+/// `collections` and `relations` hold the values for collection bindings like `:in [?e ...]`
+/// and relation bindings like `:in [[?e ?name]]`: see `QueryInputs::with_collection` and
+/// `QueryInputs::with_relation`. `prefer_most_specific_types` changes how the query resolves
+/// ambiguous arguments: see `QueryInputs::with_most_specific_types`. `integral_floats_as_longs`
+/// lets whole floats be longs: see `QueryInputs::with_integral_floats_as_longs`.
 pub struct QueryInputs {
     // These should be crate-private.
     pub types: BTreeMap<Variable, ValueType>,
     pub values: BTreeMap<Variable, TypedValue>,
+    pub collections: BTreeMap<Variable, Vec<TypedValue>>,
+    pub relations: BTreeMap<Vec<Variable>, Vec<Vec<TypedValue>>>,
+    pub prefer_most_specific_types: bool,
+    pub integral_floats_as_longs: bool,
 }
 
 impl Default for QueryInputs {
@@ -41,6 +59,10 @@ impl Default for QueryInputs {
         QueryInputs {
             types: BTreeMap::default(),
             values: BTreeMap::default(),
+            collections: BTreeMap::default(),
+            relations: BTreeMap::default(),
+            prefer_most_specific_types: false,
+            integral_floats_as_longs: false,
         }
     }
 }
@@ -55,6 +77,10 @@ impl QueryInputs {
         QueryInputs {
             types: values.iter().map(|(var, val)| (var.clone(), val.value_type())).collect(),
             values: values,
+            collections: BTreeMap::default(),
+            relations: BTreeMap::default(),
+            prefer_most_specific_types: false,
+            integral_floats_as_longs: false,
         }
     }
 
@@ -70,6 +96,103 @@ impl QueryInputs {
                 }
             }
         }
-        Ok(QueryInputs { types: types, values: values })
+        Ok(QueryInputs {
+            types: types,
+            values: values,
+            collections: BTreeMap::default(),
+            relations: BTreeMap::default(),
+            prefer_most_specific_types: false,
+            integral_floats_as_longs: false,
+        })
+    }
+
+    /// Provide `values` for the collection binding `[var ...]` in the query's `:in`. Each value
+    /// is converted to the type the query requires of `var` when the query is algebrized, just as
+    /// a `ground` argument would be: a long can be an entid, a keyword an ident, and so on. All of
+    /// the values must end up with the same type. No values at all means no results.
+    pub fn with_collection(mut self, var: Variable, values: Vec<TypedValue>) -> QueryInputs {
+        self.collections.insert(var, values);
+        self
+    }
+
+    /// Provide `rows` for the relation binding of `vars` in the query's `:in`, like
+    /// `[[?e ?name]]`. `vars` are the binding's variables, in order, without its placeholders,
+    /// but each row has a value for every place, placeholders included. The values of each column
+    /// are converted to the type the query requires of its variable, as for `with_collection`.
+    pub fn with_relation(mut self, vars: Vec<Variable>, rows: Vec<Vec<TypedValue>>) -> QueryInputs {
+        self.relations.insert(vars, rows);
+        self
+    }
+
+    /// Take an integer argument that could be a ref or a long to be a ref, and a keyword that
+    /// could be a keyword or an ident to be an ident, rather than defaulting to a long or a
+    /// keyword. See `ValueTypeSet::most_specific`.
+    pub fn with_most_specific_types(self) -> QueryInputs {
+        QueryInputs {
+            prefer_most_specific_types: true,
+            ..self
+        }
+    }
+
+    /// Take a float argument with no fractional part, like `42.0`, to be a long where the query
+    /// can only use a long, and fail on one like `42.5`. See
+    /// `ConjoiningClauses::with_integral_floats_as_longs`.
+    pub fn with_integral_floats_as_longs(self) -> QueryInputs {
+        QueryInputs {
+            integral_floats_as_longs: true,
+            ..self
+        }
+    }
+}
+
+/// Build `QueryInputs` for a particular query, checking each value as it's bound: the variable
+/// must be named in the query's `:in`, and the value must have a type the query allows for that
+/// variable.
+///
+/// ```ignore
+/// let inputs = QueryInputsBuilder::new(&schema, &query)?
+///                  .bind(Variable::from_valid_name("?name"), TypedValue::typed_string("Alice"))?
+///                  .build();
+/// ```
+pub struct QueryInputsBuilder {
+    input_variables: BTreeSet<Variable>,
+    known_types: BTreeMap<Variable, ValueTypeSet>,
+    values: BTreeMap<Variable, TypedValue>,
+}
+
+impl QueryInputsBuilder {
+    /// Algebrize `query` against `schema`, without inputs, to find the types its `:in` variables
+    /// can take.
+    pub fn new(schema: &Schema, query: &FindQuery) -> Result<QueryInputsBuilder> {
+        let algebrized = algebrize(schema, query.clone())?;
+        let input_variables = algebrized.cc.input_variables.clone();
+        let known_types = input_variables.iter()
+                                         .map(|var| (var.clone(), algebrized.cc.known_type_set(var)))
+                                         .collect();
+        Ok(QueryInputsBuilder {
+            input_variables: input_variables,
+            known_types: known_types,
+            values: BTreeMap::default(),
+        })
+    }
+
+    pub fn bind<T>(mut self, var: Variable, value: T) -> Result<QueryInputsBuilder> where T: Into<TypedValue> {
+        let value = value.into();
+        if !self.input_variables.contains(&var) {
+            bail!(ErrorKind::UnknownInputVariable(var.name()));
+        }
+
+        let expected = self.known_types.get(&var).cloned().unwrap_or(ValueTypeSet::any());
+        let provided = value.value_type();
+        if !expected.contains(provided) {
+            bail!(ErrorKind::InputTypeMismatch(var.name(), expected, provided));
+        }
+
+        self.values.insert(var, value);
+        Ok(self)
+    }
+
+    pub fn build(self) -> QueryInputs {
+        QueryInputs::with_values(self.values)
     }
 }
@@ -8,11 +8,14 @@
 // CONDITIONS OF ANY KIND, either express or implied. See the License for the
 // specific language governing permissions and limitations under the License.
 
+use std::cell::RefCell;
+
 use std::cmp;
 
 use std::collections::{
     BTreeMap,
     BTreeSet,
+    HashMap,
 };
 
 use std::collections::btree_map::Entry;
@@ -22,8 +25,12 @@ use std::fmt::{
     Formatter,
 };
 
+use std::rc::Rc;
+use std::sync::Arc;
+
 use mentat_core::{
     Attribute,
+    CachedAttributes,
     Entid,
     Schema,
     TypedValue,
@@ -39,6 +46,8 @@ use mentat_query::{
     Pattern,
     PatternNonValuePlace,
     PatternValuePlace,
+    SrcVar,
+    SrcVarName,
     Variable,
     WhereClause,
 };
@@ -47,15 +56,20 @@ use errors::{
     Result,
 };
 
+use rules::RuleSet;
+
 use types::{
     ColumnConstraint,
     ColumnIntersection,
     ComputedTable,
     Column,
+    Computation,
     DatomsColumn,
     DatomsTable,
     EmptyBecause,
+    Fallback,
     FulltextColumn,
+    OuterJoin,
     QualifiedAlias,
     QueryValue,
     SourceAlias,
@@ -69,9 +83,14 @@ mod not;
 mod pattern;
 mod predicate;
 mod resolve;
+mod rule;
 
 mod ground;
 mod fulltext;
+mod fallback;
+mod computed;
+mod type_of;
+mod tx_log;
 mod where_fn;
 
 use validate::{
@@ -79,7 +98,10 @@ use validate::{
     validate_or_join,
 };
 
-pub use self::inputs::QueryInputs;
+pub use self::inputs::{
+    QueryInputs,
+    QueryInputsBuilder,
+};
 
 // We do this a lot for errors.
 trait RcCloned<T> {
@@ -176,6 +198,10 @@ pub struct ConjoiningClauses {
     /// A vector of source/alias pairs used to construct a SQL `FROM` list.
     pub from: Vec<SourceAlias>,
 
+    /// The named source, like `db2` for `$db2`, that each table alias in `from` reads from, if
+    /// it isn't the default source. Each named source is an attached SQLite database of that name.
+    pub table_sources: BTreeMap<TableAlias, SrcVarName>,
+
     /// A vector of computed tables (typically subqueries). The index into this vector is used as
     /// an identifier in a `DatomsTable::Computed(c)` table reference.
     pub computed_tables: Vec<ComputedTable>,
@@ -212,19 +238,75 @@ pub struct ConjoiningClauses {
     /// A mapping, similar to `column_bindings`, but used to pull type tags out of the store at runtime.
     /// If a var isn't unit in `known_types`, it should be present here.
     pub extracted_types: BTreeMap<Variable, QualifiedAlias>,
+
+    /// A map from each variable bound by `type-of` to the variable whose type it names. The
+    /// projector computes these from the value and type tag of the named variable, so they can
+    /// only be projected.
+    pub type_of_bindings: BTreeMap<Variable, Variable>,
+
+    /// Tables joined to those in `from` with `LEFT JOIN`, in order.
+    pub outer_joins: Vec<OuterJoin>,
+
+    /// A map from each variable bound by `get-else`, `get-some`, or as the value of `tx-data` to
+    /// the columns from which its value is taken. Like `type-of` bindings, these can only be
+    /// projected.
+    pub fallback_bindings: BTreeMap<Variable, Fallback>,
+
+    /// A map from each variable bound by a computing function like `str` to the computation
+    /// that yields its value. These, too, can only be projected.
+    pub computed_bindings: BTreeMap<Variable, Computation>,
+
+    /// Caller-supplied keyword aliases. When an ident argument is resolved to an entid, it is
+    /// first rewritten through this map, allowing user-facing names that aren't in the schema.
+    ident_aliases: HashMap<NamespacedKeyword, NamespacedKeyword>,
+
+    /// If `true`, an `:in` variable that is used before a value has been bound to it makes the
+    /// enclosing clause known-empty, rather than failing algebrization.
+    unbound_inputs_are_empty: bool,
+
+    /// If `true`, an argument that could be of more than one of the types its variable might take,
+    /// like an integer that could be a ref or a long, is taken to be of the most specific of
+    /// them, per `ValueTypeSet::most_specific`, rather than of the least.
+    prefer_most_specific_types: bool,
+
+    /// If `true`, a float argument with no fractional part, like `42.0`, can be a long, where
+    /// only a long will do. A float that isn't a whole number is then an error.
+    integral_floats_as_longs: bool,
+
+    /// Memoized ident lookups. A query that repeatedly grounds the same keyword only consults
+    /// the schema once. This is shared with every CC derived from this one, so it lives exactly
+    /// as long as a single algebrization.
+    ident_cache: Rc<RefCell<HashMap<NamespacedKeyword, Option<Entid>>>>,
+
+    /// In-memory attribute values, consulted by patterns whose entity (or, in reverse, value) is
+    /// known. Only the top-level CC uses these: the CCs made for `or` and `not` don't get a copy.
+    attribute_cache: Option<Arc<CachedAttributes>>,
+
+    /// The rules that the query was given, for invocations of recursive rules, which are left
+    /// in place when the others are expanded.
+    rules: Option<Rc<RuleSet>>,
 }
 
 impl PartialEq for ConjoiningClauses {
     fn eq(&self, other: &ConjoiningClauses) -> bool {
         self.empty_because.eq(&other.empty_because) &&
         self.from.eq(&other.from) &&
+        self.table_sources.eq(&other.table_sources) &&
         self.computed_tables.eq(&other.computed_tables) &&
         self.wheres.eq(&other.wheres) &&
         self.column_bindings.eq(&other.column_bindings) &&
         self.input_variables.eq(&other.input_variables) &&
         self.value_bindings.eq(&other.value_bindings) &&
         self.known_types.eq(&other.known_types) &&
-        self.extracted_types.eq(&other.extracted_types)
+        self.extracted_types.eq(&other.extracted_types) &&
+        self.type_of_bindings.eq(&other.type_of_bindings) &&
+        self.outer_joins.eq(&other.outer_joins) &&
+        self.fallback_bindings.eq(&other.fallback_bindings) &&
+        self.computed_bindings.eq(&other.computed_bindings) &&
+        self.ident_aliases.eq(&other.ident_aliases) &&
+        self.unbound_inputs_are_empty.eq(&other.unbound_inputs_are_empty) &&
+        self.prefer_most_specific_types.eq(&other.prefer_most_specific_types) &&
+        self.integral_floats_as_longs.eq(&other.integral_floats_as_longs)
     }
 }
 
@@ -235,6 +317,7 @@ impl Debug for ConjoiningClauses {
         fmt.debug_struct("ConjoiningClauses")
             .field("empty_because", &self.empty_because)
             .field("from", &self.from)
+            .field("table_sources", &self.table_sources)
             .field("computed_tables", &self.computed_tables)
             .field("wheres", &self.wheres)
             .field("column_bindings", &self.column_bindings)
@@ -242,6 +325,15 @@ impl Debug for ConjoiningClauses {
             .field("value_bindings", &self.value_bindings)
             .field("known_types", &self.known_types)
             .field("extracted_types", &self.extracted_types)
+            .field("type_of_bindings", &self.type_of_bindings)
+            .field("outer_joins", &self.outer_joins)
+            .field("fallback_bindings", &self.fallback_bindings)
+            .field("computed_bindings", &self.computed_bindings)
+            .field("ident_aliases", &self.ident_aliases)
+            .field("unbound_inputs_are_empty", &self.unbound_inputs_are_empty)
+            .field("prefer_most_specific_types", &self.prefer_most_specific_types)
+            .field("integral_floats_as_longs", &self.integral_floats_as_longs)
+            .field("ident_cache", &self.ident_cache)
             .finish()
     }
 }
@@ -253,6 +345,7 @@ impl Default for ConjoiningClauses {
             empty_because: None,
             alias_counter: RcCounter::new(),
             from: vec![],
+            table_sources: BTreeMap::new(),
             computed_tables: vec![],
             wheres: ColumnIntersection::default(),
             input_variables: BTreeSet::new(),
@@ -260,6 +353,17 @@ impl Default for ConjoiningClauses {
             value_bindings: BTreeMap::new(),
             known_types: BTreeMap::new(),
             extracted_types: BTreeMap::new(),
+            type_of_bindings: BTreeMap::new(),
+            outer_joins: vec![],
+            fallback_bindings: BTreeMap::new(),
+            computed_bindings: BTreeMap::new(),
+            ident_aliases: HashMap::new(),
+            unbound_inputs_are_empty: false,
+            prefer_most_specific_types: false,
+            integral_floats_as_longs: false,
+            ident_cache: Rc::new(RefCell::new(HashMap::new())),
+            attribute_cache: None,
+            rules: None,
         }
     }
 }
@@ -286,7 +390,7 @@ impl ConjoiningClauses {
     where T: Into<Option<QueryInputs>> {
         match inputs.into() {
             None => ConjoiningClauses::with_alias_counter(alias_counter),
-            Some(QueryInputs { mut types, mut values }) => {
+            Some(QueryInputs { mut types, mut values, prefer_most_specific_types, integral_floats_as_longs, .. }) => {
                 // Discard any bindings not mentioned in our :in clause.
                 types.keep_intersected_keys(&in_variables);
                 values.keep_intersected_keys(&in_variables);
@@ -295,6 +399,8 @@ impl ConjoiningClauses {
                     alias_counter: alias_counter,
                     input_variables: in_variables,
                     value_bindings: values,
+                    prefer_most_specific_types: prefer_most_specific_types,
+                    integral_floats_as_longs: integral_floats_as_longs,
                     ..Default::default()
                 };
 
@@ -306,6 +412,60 @@ impl ConjoiningClauses {
             },
         }
     }
+
+    /// Install a map of keyword aliases to consult when resolving ident arguments.
+    pub fn with_ident_aliases(self, aliases: HashMap<NamespacedKeyword, NamespacedKeyword>) -> ConjoiningClauses {
+        ConjoiningClauses {
+            ident_aliases: aliases,
+            ..self
+        }
+    }
+
+    /// Answer patterns from `cache` where we can, rather than from the store.
+    pub fn with_attribute_cache(self, cache: Arc<CachedAttributes>) -> ConjoiningClauses {
+        ConjoiningClauses {
+            attribute_cache: Some(cache),
+            ..self
+        }
+    }
+
+    /// Algebrize invocations of the recursive rules in `rules`.
+    pub fn with_rules(self, rules: Rc<RuleSet>) -> ConjoiningClauses {
+        ConjoiningClauses {
+            rules: Some(rules),
+            ..self
+        }
+    }
+
+    /// Treat uses of unbound `:in` variables as making the enclosing clause known-empty, rather
+    /// than as errors. This allows, for example, one arm of an `or` to refer to an input that
+    /// hasn't been provided.
+    pub fn with_unbound_inputs_as_empty(self) -> ConjoiningClauses {
+        ConjoiningClauses {
+            unbound_inputs_are_empty: true,
+            ..self
+        }
+    }
+
+    /// Resolve ambiguous arguments to the most specific type they could be, rather than the
+    /// least: an integer that could be a ref or a long is a ref, and a keyword that could be a
+    /// keyword or an ident is an ident, if it names an entity.
+    pub fn with_most_specific_types(self) -> ConjoiningClauses {
+        ConjoiningClauses {
+            prefer_most_specific_types: true,
+            ..self
+        }
+    }
+
+    /// Accept a float with no fractional part, like `42.0`, as a long where only a long will do,
+    /// and fail on one like `42.5`, rather than making the clause known-empty. This is off by
+    /// default so that a float is never quietly taken to be a long.
+    pub fn with_integral_floats_as_longs(self) -> ConjoiningClauses {
+        ConjoiningClauses {
+            integral_floats_as_longs: true,
+            ..self
+        }
+    }
 }
 
 /// Cloning.
@@ -318,6 +478,12 @@ impl ConjoiningClauses {
             value_bindings: self.value_bindings.clone(),
             known_types: self.known_types.clone(),
             extracted_types: self.extracted_types.clone(),
+            ident_aliases: self.ident_aliases.clone(),
+            unbound_inputs_are_empty: self.unbound_inputs_are_empty,
+            prefer_most_specific_types: self.prefer_most_specific_types,
+            integral_floats_as_longs: self.integral_floats_as_longs,
+            ident_cache: self.ident_cache.clone(),
+            rules: self.rules.clone(),
             ..Default::default()
         }
     }
@@ -332,6 +498,12 @@ impl ConjoiningClauses {
             value_bindings: self.value_bindings.with_intersected_keys(&vars),
             known_types: self.known_types.with_intersected_keys(&vars),
             extracted_types: self.extracted_types.with_intersected_keys(&vars),
+            ident_aliases: self.ident_aliases.clone(),
+            unbound_inputs_are_empty: self.unbound_inputs_are_empty,
+            prefer_most_specific_types: self.prefer_most_specific_types,
+            integral_floats_as_longs: self.integral_floats_as_longs,
+            ident_cache: self.ident_cache.clone(),
+            rules: self.rules.clone(),
             ..Default::default()
         }
     }
@@ -409,9 +581,11 @@ impl ConjoiningClauses {
                 },
 
                 Column::Fulltext(FulltextColumn::Rowid) |
-                Column::Fulltext(FulltextColumn::Text) => {
+                Column::Fulltext(FulltextColumn::Text) |
+                Column::Fulltext(FulltextColumn::Score) => {
                     // We never expose `rowid` via queries.  We do expose `text`, but only
-                    // indirectly, by joining against `datoms`.  Therefore, these are meaningless.
+                    // indirectly, by joining against `datoms`.  Scores can't be bound in advance.
+                    // Therefore, these are meaningless.
                     unimplemented!()
                 },
 
@@ -428,7 +602,8 @@ impl ConjoiningClauses {
                 },
 
                 // TODO: recognize when the valueType might be a ref and also translate entids there.
-                Column::Fixed(DatomsColumn::Value) => {
+                Column::Fixed(DatomsColumn::Value) |
+                Column::Fixed(DatomsColumn::Added) => {
                     self.constrain_column_to_constant(table, column, bound_val);
                 },
 
@@ -652,6 +827,22 @@ impl ConjoiningClauses {
         schema.get_entid(&ident)
     }
 
+    /// Like `entid_for_ident`, but remembers the answer -- including a failed lookup -- for the
+    /// rest of this algebrization.
+    fn cached_entid_for_ident<'s, 'a>(&self, schema: &'s Schema, ident: &'a NamespacedKeyword) -> Option<Entid> {
+        if let Some(cached) = self.ident_cache.borrow().get(ident) {
+            return *cached;
+        }
+        let entid = self.entid_for_ident(schema, ident);
+        self.ident_cache.borrow_mut().insert(ident.clone(), entid);
+        entid
+    }
+
+    /// Rewrite `ident` through the caller-supplied alias map, if it has an entry.
+    fn resolve_ident_alias<'a>(&'a self, ident: &'a NamespacedKeyword) -> &'a NamespacedKeyword {
+        self.ident_aliases.get(ident).unwrap_or(ident)
+    }
+
     fn table_for_attribute_and_value<'s, 'a>(&self, attribute: &'s Attribute, value: &'a PatternValuePlace) -> ::std::result::Result<DatomsTable, EmptyBecause> {
         if attribute.fulltext {
             match value {
@@ -760,13 +951,21 @@ impl ConjoiningClauses {
     /// This is a mutating method because it mutates the aliaser function!
     /// Note that if this function decides that a pattern cannot match, it will flip
     /// `empty_because`.
+    ///
+    /// A pattern against a named source reads from the same table in the attached database of
+    /// that name. We interpret it using our own schema, so that database must share it.
     fn alias_table<'s, 'a>(&mut self, schema: &'s Schema, pattern: &'a Pattern) -> Option<SourceAlias> {
-        self.table_for_places(schema, &pattern.attribute, &pattern.value)
+        let source_alias = self.table_for_places(schema, &pattern.attribute, &pattern.value)
             .map_err(|reason| {
                 self.mark_known_empty(reason);
             })
             .map(|table: DatomsTable| SourceAlias(table, self.next_alias_for_table(table)))
-            .ok()
+            .ok();
+
+        if let (Some(&SrcVar::NamedSrc(ref name)), Some(&SourceAlias(_, ref alias))) = (pattern.source.as_ref(), source_alias.as_ref()) {
+            self.table_sources.insert(alias.clone(), name.clone());
+        }
+        source_alias
     }
 
     fn get_attribute_for_value<'s>(&self, schema: &'s Schema, value: &TypedValue) -> Option<&'s Attribute> {
@@ -891,7 +1090,10 @@ impl ConjoiningClauses {
                 validate_not_join(&n)?;
                 self.apply_not_join(schema, n)
             },
-            _ => unimplemented!(),
+            // Only invocations of recursive rules survive expansion.
+            WhereClause::RuleExpr(r) => {
+                self.apply_recursive_rule(schema, r)
+            },
         }
     }
 }
@@ -32,10 +32,12 @@ use mentat_query::{
 
 use clauses::{
     ConjoiningClauses,
+    Intersection,
     PushComputed,
 };
 
 use errors::{
+    ErrorKind,
     Result,
 };
 
@@ -572,6 +574,10 @@ impl ConjoiningClauses {
                     receptacle.apply_clause(&schema, clause)?;
                 },
             }
+            if let Some(var) = receptacle.type_of_bindings.keys().find(|v| projected.contains(v)) {
+                // Only the outermost projector can compute these.
+                bail!(ErrorKind::TypeOfCanOnlyBeProjected(var.name()));
+            }
             if receptacle.is_known_empty() {
                 empty_because = receptacle.empty_because;
             } else {
@@ -652,16 +658,23 @@ impl ConjoiningClauses {
         // Collect the new type information from the arms. There's some redundant work here --
         // they already have all of the information from the parent.
         // Note that we start with the first clause's type information.
+        //
+        // Only the projected variables escape the union: a variable that's internal to an arm
+        // means nothing outside it, so its types must not constrain the enclosing CC.
+        //
+        // Each arm started out with our types for the projected variables, so the union of the
+        // arms' types can only be narrower than what we already knew. We intersect rather than
+        // broaden to keep that precision.
         {
             let mut clauses = acc.iter();
             let mut additional_types = clauses.next()
                                               .expect("there to be at least one clause")
                                               .known_types
-                                              .clone();
+                                              .with_intersected_keys(&projection);
             for cc in clauses {
-                union_types(&mut additional_types, &cc.known_types);
+                union_types(&mut additional_types, &cc.known_types.with_intersected_keys(&projection));
             }
-            self.broaden_types(additional_types);
+            self.narrow_types(additional_types);
         }
 
         let union = ComputedTable::Union {
@@ -743,10 +756,16 @@ mod testing {
     };
 
     use clauses::{
+        QueryInputs,
         add_attribute,
         associate_ident,
     };
 
+    use errors::{
+        Error,
+        ErrorKind,
+    };
+
     use types::{
         ColumnConstraint,
         DatomsColumn,
@@ -811,9 +830,28 @@ mod testing {
             multival: false,
             ..Default::default()
         });
+        associate_ident(&mut schema, NamespacedKeyword::new("role", "admin"), 70);
+        associate_ident(&mut schema, NamespacedKeyword::new("role", "owner"), 71);
+        add_attribute(&mut schema, 70, Attribute {
+            value_type: ValueType::Boolean,
+            multival: false,
+            ..Default::default()
+        });
+        add_attribute(&mut schema, 71, Attribute {
+            value_type: ValueType::Boolean,
+            multival: false,
+            ..Default::default()
+        });
         schema
     }
 
+    fn union_arm_count(cc: &ConjoiningClauses) -> usize {
+        match cc.computed_tables.first() {
+            Some(&ComputedTable::Union { ref arms, .. }) => arms.len(),
+            _ => panic!("Expected a union."),
+        }
+    }
+
     /// Test that if all the attributes in an `or` fail to resolve, the entire thing fails.
     #[test]
     fn test_schema_based_failure() {
@@ -828,6 +866,45 @@ mod testing {
         assert_eq!(cc.empty_because, Some(EmptyBecause::InvalidAttributeIdent(NamespacedKeyword::new("foo", "nope3"))));
     }
 
+    /// Algebrize the where clauses of `input` into a CC that treats unbound inputs as empty.
+    fn alg_unbound_inputs_as_empty(schema: &Schema, input: &str) -> Result<ConjoiningClauses> {
+        let parsed = parse_find_string(input).expect("parse failed");
+        let mut cc = ConjoiningClauses::with_inputs(parsed.in_vars, QueryInputs::default())
+            .with_unbound_inputs_as_empty();
+        for clause in parsed.where_clauses {
+            cc.apply_clause(schema, clause)?;
+        }
+        Ok(cc)
+    }
+
+    /// Test that an `or` arm that refers to an unbound input can be discarded rather than failing
+    /// the whole query.
+    #[test]
+    fn test_unbound_input_arm_is_empty() {
+        let schema = prepopulated_schema();
+        let query = r#"
+            [:find ?x
+             :in ?v
+             :where (or [?x :foo/age ?v]
+                        [(ground ?v) ?x])]"#;
+
+        // By default this is an error.
+        let parsed = parse_find_string(query).expect("parse failed");
+        match algebrize(&schema, parsed) {
+            Err(Error(ErrorKind::UnboundVariable(v), _)) => assert_eq!(v.0, "?v"),
+            _ => panic!("expected an unbound variable error"),
+        }
+
+        // If we ask for soft failure, the `ground` arm is dropped and the query still algebrizes.
+        let cc = alg_unbound_inputs_as_empty(&schema, query).expect("algebrize to succeed");
+        assert!(!cc.is_known_empty());
+        assert_eq!(cc.computed_tables.len(), 1);
+        match cc.computed_tables[0] {
+            ComputedTable::Union { ref arms, .. } => assert_eq!(arms.len(), 1),
+            _ => panic!("expected a union"),
+        }
+    }
+
     /// Test that if only one of the attributes in an `or` resolves, it's equivalent to a simple query.
     #[test]
     fn test_only_one_arm_succeeds() {
@@ -1111,4 +1188,78 @@ mod testing {
                     [_ :foo/height ?x]]"#;
         compare_ccs(alg(&schema, query), alg(&schema, simple));
     }
+
+    /// Two arms over different attributes join on the entity. Variables that only appear inside
+    /// an arm don't leak out into the enclosing CC.
+    #[test]
+    fn test_or_join_across_attributes() {
+        let schema = prepopulated_schema();
+        let query = r#"
+            [:find ?person
+             :where (or-join [?person]
+                      (and [?person :role/admin true]
+                           [?person :foo/age ?age])
+                      [?person :role/owner true])]"#;
+        let cc = alg(&schema, query);
+        assert!(!cc.is_known_empty());
+        assert_eq!(union_arm_count(&cc), 2);
+
+        let vperson = Variable::from_valid_name("?person");
+        let vage = Variable::from_valid_name("?age");
+        assert_eq!(cc.known_type(&vperson), Some(ValueType::Ref));
+        assert!(!cc.known_types.contains_key(&vage));
+        assert!(!cc.column_bindings.contains_key(&vage));
+    }
+
+    /// An arm that can't bind the join variable to a type the enclosing CC allows is pruned.
+    /// If no arm can, the whole `or-join` is known-empty.
+    #[test]
+    fn test_or_join_incompatible_types() {
+        let schema = prepopulated_schema();
+        let query = r#"
+            [:find ?x
+             :where [_ :foo/age ?x]
+                    (or-join [?x]
+                      (and [_ :foo/name ?x]
+                           [?y :foo/knows ?x])
+                      [_ :foo/height ?x])]"#;
+        let cc = alg(&schema, query);
+        assert!(!cc.is_known_empty());
+        assert_eq!(union_arm_count(&cc), 1);
+        assert_eq!(cc.known_type(&Variable::from_valid_name("?x")), Some(ValueType::Long));
+
+        let query = r#"
+            [:find ?x
+             :where [_ :foo/age ?x]
+                    (or-join [?x]
+                      (and [_ :foo/name ?x]
+                           [?y :foo/knows ?x])
+                      [_ :foo/parent ?x])]"#;
+        let cc = alg(&schema, query);
+        assert!(cc.is_known_empty());
+        match cc.empty_because {
+            Some(EmptyBecause::TypeMismatch { ref var, .. }) => {
+                assert_eq!(var, &Variable::from_valid_name("?x"));
+            },
+            ref e => panic!("Expected a type mismatch, got {:?}", e),
+        }
+    }
+
+    /// An arm that is itself impossible is dropped without affecting the others.
+    #[test]
+    fn test_or_join_impossible_arm() {
+        let schema = prepopulated_schema();
+        let query = r#"
+            [:find ?person
+             :where (or-join [?person]
+                      (and [?person :role/admin true]
+                           [?person :foo/nope ?v])
+                      [?person :role/owner true]
+                      (and [?person :role/admin true]
+                           [?person :foo/age ?age]))]"#;
+        let cc = alg(&schema, query);
+        assert!(!cc.is_known_empty());
+        assert_eq!(union_arm_count(&cc), 2);
+        assert_eq!(cc.known_type(&Variable::from_valid_name("?person")), Some(ValueType::Ref));
+    }
 }
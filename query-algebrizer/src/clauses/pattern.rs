@@ -9,9 +9,12 @@
 // specific language governing permissions and limitations under the License.
 
 use mentat_core::{
+    Attribute,
+    Entid,
     Schema,
     TypedValue,
     ValueType,
+    ValueTypeSet,
 };
 
 use mentat_query::{
@@ -19,6 +22,8 @@ use mentat_query::{
     PatternValuePlace,
     PatternNonValuePlace,
     SrcVar,
+    Variable,
+    WhereClause,
 };
 
 use super::RcCloned;
@@ -262,12 +267,16 @@ impl ConjoiningClauses {
     }
 
     pub fn apply_pattern<'s, 'p>(&mut self, schema: &'s Schema, pattern: Pattern) {
-        // For now we only support the default source.
-        match pattern.source {
-            Some(SrcVar::DefaultSrc) | None => (),
-            _ => unimplemented!(),
+        // The attribute cache only knows about the default source.
+        let default_source = match pattern.source {
+            Some(SrcVar::DefaultSrc) | None => true,
+            Some(SrcVar::NamedSrc(_)) => false,
         };
 
+        if default_source && self.apply_pattern_from_cache(schema, &pattern) {
+            return;
+        }
+
         if let Some(alias) = self.alias_table(schema, &pattern) {
             self.apply_pattern_clause_for_alias(schema, &pattern, &alias);
             self.from.push(alias);
@@ -279,6 +288,211 @@ impl ConjoiningClauses {
             return;
         }
     }
+
+    /// Each of `clauses` must hold, so the entity of each top-level pattern must be a ref, and
+    /// the value of a pattern with a known attribute must be of that attribute's type, wherever
+    /// else those variables appear. Narrowing their types before any clause is applied means that
+    /// an earlier clause, like `[(ground 5) ?v]` before `[?x :foo/ref ?v]`, treats an ambiguous
+    /// integer as an entid rather than a long.
+    ///
+    /// Variables that are already bound to values are left alone: the patterns that use them will
+    /// check those values, and explain any mismatch better than we could.
+    pub fn derive_types_from_patterns(&mut self, schema: &Schema, clauses: &[WhereClause]) {
+        for clause in clauses {
+            let pattern = match clause {
+                &WhereClause::Pattern(ref pattern) => pattern,
+                _ => continue,
+            };
+
+            if let PatternNonValuePlace::Variable(ref var) = pattern.entity {
+                self.narrow_unbound_var(var, ValueType::Ref);
+            }
+
+            let value_type = self.known_entid(schema, &pattern.attribute)
+                                 .and_then(|a| schema.attribute_for_entid(a))
+                                 .map(|attribute| attribute.value_type);
+            if let (Some(value_type), &PatternValuePlace::Variable(ref var)) = (value_type, &pattern.value) {
+                self.narrow_unbound_var(var, value_type);
+            }
+        }
+    }
+
+    fn narrow_unbound_var(&mut self, var: &Variable, value_type: ValueType) {
+        if !self.is_value_bound(var) {
+            self.narrow_types_for_var(var.clone(), ValueTypeSet::of_one(value_type));
+        }
+    }
+
+    /// Move the most selective patterns of each run of adjacent patterns in `clauses` to the
+    /// front of the run, so that the query starts from the fewest rows. Patterns are conjoined,
+    /// so their order doesn't change the results. Every other clause stays where it is, and no
+    /// pattern moves past one: a predicate, function, `or`, or `not` can depend on the variables
+    /// bound by the patterns before it. Patterns of the same selectivity keep their order.
+    pub fn order_patterns_by_selectivity(&self, schema: &Schema, clauses: Vec<WhereClause>) -> Vec<WhereClause> {
+        let mut ordered = Vec::with_capacity(clauses.len());
+        let mut run: Vec<(Selectivity, WhereClause)> = vec![];
+        for clause in clauses.into_iter() {
+            let selectivity = match &clause {
+                &WhereClause::Pattern(ref pattern) => Some(self.pattern_selectivity(schema, pattern)),
+                _ => None,
+            };
+            match selectivity {
+                Some(selectivity) => run.push((selectivity, clause)),
+                None => {
+                    run.sort_by_key(|&(selectivity, _)| selectivity);
+                    ordered.extend(run.drain(..).map(|(_, clause)| clause));
+                    ordered.push(clause);
+                },
+            }
+        }
+        run.sort_by_key(|&(selectivity, _)| selectivity);
+        ordered.extend(run.into_iter().map(|(_, clause)| clause));
+        ordered
+    }
+
+    fn pattern_selectivity(&self, schema: &Schema, pattern: &Pattern) -> Selectivity {
+        let attribute = match self.known_entid(schema, &pattern.attribute)
+                                  .and_then(|a| schema.attribute_for_entid(a)) {
+            Some(attribute) => attribute,
+            None => return Selectivity::Scan,
+        };
+        if self.known_value(schema, attribute, &pattern.value).is_none() {
+            return Selectivity::Scan;
+        }
+        if attribute.unique.is_some() {
+            Selectivity::Unique
+        } else if attribute.index {
+            Selectivity::IndexedBound
+        } else {
+            Selectivity::Scan
+        }
+    }
+}
+
+/// How few rows a pattern is likely to match, most selective first.
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum Selectivity {
+    /// A unique attribute with a known value matches at most one entity.
+    Unique,
+
+    /// An indexed attribute with a known value can be looked up in the AVET index.
+    IndexedBound,
+
+    /// Anything else.
+    Scan,
+}
+
+/// Answering patterns from the attribute cache.
+impl ConjoiningClauses {
+    /// If the attribute of `pattern` is cached and the pattern names one entity (forward) or one
+    /// value (reverse), we can look up the other side without joining a table. When there's
+    /// exactly one answer we bind the free variable to it; when there's none the pattern can't
+    /// match. Returns `true` if the pattern was handled.
+    fn apply_pattern_from_cache(&mut self, schema: &Schema, pattern: &Pattern) -> bool {
+        let cache = match self.attribute_cache {
+            Some(ref cache) => cache.clone(),
+            None => return false,
+        };
+
+        // A cache doesn't know about transactions.
+        if pattern.tx != PatternNonValuePlace::Placeholder {
+            return false;
+        }
+
+        let a = match self.known_entid(schema, &pattern.attribute) {
+            Some(a) => a,
+            None => return false,
+        };
+        let attribute = match schema.attribute_for_entid(a) {
+            Some(attribute) => attribute.clone(),
+            None => return false,
+        };
+
+        if cache.is_attribute_cached_forward(a) {
+            if let (Some(e), &PatternValuePlace::Variable(ref var)) = (self.known_entid(schema, &pattern.entity), &pattern.value) {
+                if self.is_free_variable(var) {
+                    match cache.get_values_for_entid(a, e) {
+                        None => {
+                            self.mark_known_empty(EmptyBecause::CachedAttributeHasNoValue { entity: e, attr: a });
+                            return true;
+                        },
+                        Some(values) if values.len() == 1 => {
+                            self.bind_value(var, values[0].clone());
+                            return true;
+                        },
+                        // Several values: the store can produce the rows.
+                        Some(_) => {},
+                    }
+                }
+            }
+        }
+
+        if cache.is_attribute_cached_reverse(a) {
+            if let (&PatternNonValuePlace::Variable(ref var), Some(value)) = (&pattern.entity, self.known_value(schema, &attribute, &pattern.value)) {
+                if self.is_free_variable(var) {
+                    match cache.get_entids_for_value(a, &value) {
+                        None => {
+                            self.mark_known_empty(EmptyBecause::CachedAttributeHasNoEntity { value: value.clone(), attr: a });
+                            return true;
+                        },
+                        Some(entids) if entids.len() == 1 => {
+                            let e = *entids.iter().next().unwrap();
+                            self.bind_value(var, TypedValue::Ref(e));
+                            return true;
+                        },
+                        Some(_) => {},
+                    }
+                }
+            }
+        }
+
+        false
+    }
+
+    /// A variable that nothing has bound yet, and which isn't waiting for an input.
+    fn is_free_variable(&self, var: &Variable) -> bool {
+        !self.column_bindings.contains_key(var) &&
+        !self.is_value_bound(var) &&
+        !self.input_variables.contains(var)
+    }
+
+    fn known_entid(&self, schema: &Schema, place: &PatternNonValuePlace) -> Option<Entid> {
+        match place {
+            &PatternNonValuePlace::Entid(entid) => Some(entid),
+            &PatternNonValuePlace::Ident(ref ident) => self.entid_for_ident(schema, ident.as_ref()),
+            &PatternNonValuePlace::Variable(ref var) => {
+                match self.bound_value(var) {
+                    Some(TypedValue::Ref(entid)) => Some(entid),
+                    _ => None,
+                }
+            },
+            &PatternNonValuePlace::Placeholder => None,
+        }
+    }
+
+    /// The value in `place`, if it's known and of the attribute's type.
+    fn known_value(&self, schema: &Schema, attribute: &Attribute, place: &PatternValuePlace) -> Option<TypedValue> {
+        let value = match place {
+            &PatternValuePlace::Variable(ref var) => self.bound_value(var),
+            &PatternValuePlace::EntidOrInteger(i) => {
+                match attribute.value_type {
+                    ValueType::Ref => Some(TypedValue::Ref(i)),
+                    ValueType::Long => Some(TypedValue::Long(i)),
+                    _ => None,
+                }
+            },
+            &PatternValuePlace::IdentOrKeyword(ref kw) => {
+                match attribute.value_type {
+                    ValueType::Ref => self.entid_for_ident(schema, kw.as_ref()).map(TypedValue::Ref),
+                    ValueType::Keyword => Some(TypedValue::Keyword(kw.clone())),
+                    _ => None,
+                }
+            },
+            &PatternValuePlace::Constant(ref c) => Some(c.clone().into_typed_value()),
+            &PatternValuePlace::Placeholder => None,
+        };
+        value.and_then(|v| if v.value_type() == attribute.value_type { Some(v) } else { None })
+    }
 }
 
 #[cfg(test)]
@@ -290,10 +504,12 @@ mod testing {
     use std::collections::BTreeMap;
     use std::collections::BTreeSet;
     use std::rc::Rc;
+    use std::sync::Arc;
 
     use mentat_core::attribute::Unique;
     use mentat_core::{
         Attribute,
+        CachedAttributes,
         ValueTypeSet,
     };
 
@@ -317,13 +533,17 @@ mod testing {
     use types::{
         Column,
         ColumnConstraint,
+        ColumnConstraintOrAlternation,
         DatomsTable,
         QualifiedAlias,
         QueryValue,
         SourceAlias,
     };
 
-    use algebrize;
+    use {
+        algebrize,
+        algebrize_with_cache,
+    };
 
     fn alg(schema: &Schema, input: &str) -> ConjoiningClauses {
         let parsed = parse_find_string(input).expect("parse failed");
@@ -710,6 +930,73 @@ mod testing {
                    &vec![d0_e.clone()]);
     }
 
+    /// A forward and reverse cache of a single attribute.
+    struct SingleAttributeCache {
+        attribute: Entid,
+        forward: BTreeMap<Entid, Vec<TypedValue>>,
+        reverse: BTreeMap<TypedValue, BTreeSet<Entid>>,
+    }
+
+    impl CachedAttributes for SingleAttributeCache {
+        fn is_attribute_cached_forward(&self, attribute: Entid) -> bool {
+            attribute == self.attribute
+        }
+
+        fn is_attribute_cached_reverse(&self, attribute: Entid) -> bool {
+            attribute == self.attribute
+        }
+
+        fn get_values_for_entid(&self, _attribute: Entid, entity: Entid) -> Option<&Vec<TypedValue>> {
+            self.forward.get(&entity)
+        }
+
+        fn get_entids_for_value(&self, _attribute: Entid, value: &TypedValue) -> Option<&BTreeSet<Entid>> {
+            self.reverse.get(value)
+        }
+    }
+
+    #[test]
+    fn test_apply_pattern_from_cache() {
+        let mut schema = Schema::default();
+        associate_ident(&mut schema, NamespacedKeyword::new("foo", "name"), 99);
+        add_attribute(&mut schema, 99, Attribute {
+            value_type: ValueType::String,
+            ..Default::default()
+        });
+
+        let alice = TypedValue::typed_string("Alice");
+        let cache = Arc::new(SingleAttributeCache {
+            attribute: 99,
+            forward: vec![(100, vec![alice.clone()])].into_iter().collect(),
+            reverse: vec![(alice.clone(), vec![100].into_iter().collect())].into_iter().collect(),
+        });
+
+        let x = Variable::from_valid_name("?x");
+        let name = Variable::from_valid_name("?name");
+
+        // The entity is known, so ?name is read from the cache. No table is needed.
+        let parsed = parse_find_string("[:find ?name :where [100 :foo/name ?name]]").expect("parse failed");
+        let cc = algebrize_with_cache(&schema, cache.clone(), parsed, QueryInputs::default()).expect("algebrize failed").cc;
+        assert!(cc.from.is_empty());
+        assert_eq!(cc.bound_value(&name), Some(alice.clone()));
+
+        // The value is known, so ?x is too.
+        let parsed = parse_find_string(r#"[:find ?x :where [?x :foo/name "Alice"]]"#).expect("parse failed");
+        let cc = algebrize_with_cache(&schema, cache.clone(), parsed, QueryInputs::default()).expect("algebrize failed").cc;
+        assert!(cc.from.is_empty());
+        assert_eq!(cc.bound_value(&x), Some(TypedValue::Ref(100)));
+
+        // An entity with no name can't match.
+        let parsed = parse_find_string("[:find ?name :where [101 :foo/name ?name]]").expect("parse failed");
+        let cc = algebrize_with_cache(&schema, cache.clone(), parsed, QueryInputs::default()).expect("algebrize failed").cc;
+        assert_eq!(cc.empty_because, Some(EmptyBecause::CachedAttributeHasNoValue { entity: 101, attr: 99 }));
+
+        // Without a known entity or value, we use the store.
+        let parsed = parse_find_string("[:find ?x ?name :where [?x :foo/name ?name]]").expect("parse failed");
+        let cc = algebrize_with_cache(&schema, cache, parsed, QueryInputs::default()).expect("algebrize failed").cc;
+        assert_eq!(cc.from, vec![SourceAlias(DatomsTable::Datoms, "datoms00".to_string())]);
+    }
+
     #[test]
     /// Bind a value to a variable in a query where the type of the value disagrees with the type of
     /// the variable inferred from known attributes.
@@ -868,6 +1155,46 @@ mod testing {
                    });
     }
 
+    #[test]
+    fn test_order_patterns_by_selectivity() {
+        let mut schema = Schema::default();
+        associate_ident(&mut schema, NamespacedKeyword::new("foo", "bar"), 99);
+        associate_ident(&mut schema, NamespacedKeyword::new("foo", "roz"), 98);
+        associate_ident(&mut schema, NamespacedKeyword::new("foo", "idx"), 97);
+        add_attribute(&mut schema, 99, Attribute {
+            value_type: ValueType::Long,
+            ..Default::default()
+        });
+        add_attribute(&mut schema, 98, Attribute {
+            value_type: ValueType::String,
+            unique: Some(Unique::Identity),
+            ..Default::default()
+        });
+        add_attribute(&mut schema, 97, Attribute {
+            value_type: ValueType::String,
+            index: true,
+            ..Default::default()
+        });
+
+        let query = r#"[:find ?x :where [?x :foo/bar ?y] [?x :foo/idx "i"] [?x :foo/roz "r"] [(> ?y 5)] [?x :foo/roz ?r] [?x :foo/roz "s"]]"#;
+        let parsed = parse_find_string(query).expect("parse failed");
+        let cc = ConjoiningClauses::default();
+        let ordered = cc.order_patterns_by_selectivity(&schema, parsed.where_clauses);
+
+        // The unique pattern comes first, then the indexed one, and then the scan. Nothing moves
+        // past the predicate, and a unique attribute without a value is just a scan.
+        let expected = r#"[:find ?x :where [?x :foo/roz "r"] [?x :foo/idx "i"] [?x :foo/bar ?y] [(> ?y 5)] [?x :foo/roz "s"] [?x :foo/roz ?r]]"#;
+        assert_eq!(ordered, parse_find_string(expected).expect("parse failed").where_clauses);
+
+        // The algebrized query joins the unique pattern's table first.
+        let cc = alg(&schema, query);
+        assert!(!cc.is_known_empty());
+        let d0_a = QualifiedAlias::new("datoms00".to_string(), DatomsColumn::Attribute);
+        let d0_v = QualifiedAlias::new("datoms00".to_string(), DatomsColumn::Value);
+        assert_eq!(cc.wheres.0[0], ColumnConstraintOrAlternation::Constraint(ColumnConstraint::Equals(d0_a, QueryValue::Entid(98))));
+        assert_eq!(cc.wheres.0[1], ColumnConstraintOrAlternation::Constraint(ColumnConstraint::Equals(d0_v, QueryValue::TypedValue(TypedValue::typed_string("r")))));
+    }
+
     #[test]
     fn ensure_extracted_types_is_cleared() {
         let query = r#"[:find ?e ?v :where [_ _ ?v] [?e :foo/bar ?v]]"#;
@@ -14,14 +14,29 @@ use mentat_core::{
     ValueTypeSet,
 };
 
+use regex::Regex;
+
 use mentat_query::{
     FnArg,
+    NotJoin,
+    Pattern,
+    PatternNonValuePlace,
+    PatternValuePlace,
     Predicate,
+    SrcVar,
+    UnifyVars,
+    Variable,
+    WhereClause,
 };
 
+use std::mem;
+
 use clauses::ConjoiningClauses;
 
-use clauses::convert::ValueTypes;
+use clauses::convert::{
+    ValueConversion,
+    ValueTypes,
+};
 
 use errors::{
     Result,
@@ -30,23 +45,98 @@ use errors::{
 
 use types::{
     ColumnConstraint,
+    ColumnConstraintOrAlternation,
     EmptyBecause,
     Inequality,
+    QualifiedAlias,
+    QueryValue,
 };
 
+/// One side of a range: the column, the value bounding it, whether the column may equal that
+/// value, and whether the value bounds it from below.
+struct Bound {
+    column: QualifiedAlias,
+    value: QueryValue,
+    inclusive: bool,
+    lower: bool,
+}
+
+impl Bound {
+    /// The bound that an inequality between a column and a value places on the column, if it's
+    /// one of `<`, `<=`, `>`, or `>=`.
+    fn from_constraint(constraint: &ColumnConstraintOrAlternation) -> Option<Bound> {
+        let (operator, left, right) = match constraint {
+            &ColumnConstraintOrAlternation::Constraint(ColumnConstraint::Inequality { operator, ref left, ref right }) => (operator, left, right),
+            _ => return None,
+        };
+
+        // Put the column on the left, flipping the comparison if need be.
+        let (column, value, operator) = match (left, right) {
+            (&QueryValue::Column(_), &QueryValue::Column(_)) => return None,
+            (&QueryValue::Column(ref column), value) => (column, value, operator),
+            (value, &QueryValue::Column(ref column)) => {
+                let flipped = match operator {
+                    Inequality::LessThan => Inequality::GreaterThan,
+                    Inequality::LessThanOrEquals => Inequality::GreaterThanOrEquals,
+                    Inequality::GreaterThan => Inequality::LessThan,
+                    Inequality::GreaterThanOrEquals => Inequality::LessThanOrEquals,
+                    Inequality::NotEquals => return None,
+                };
+                (column, value, flipped)
+            },
+            _ => return None,
+        };
+
+        let (inclusive, lower) = match operator {
+            Inequality::LessThan => (false, false),
+            Inequality::LessThanOrEquals => (true, false),
+            Inequality::GreaterThan => (false, true),
+            Inequality::GreaterThanOrEquals => (true, true),
+            Inequality::NotEquals => return None,
+        };
+        Some(Bound {
+            column: column.clone(),
+            value: value.clone(),
+            inclusive: inclusive,
+            lower: lower,
+        })
+    }
+}
+
 /// Application of predicates.
 impl ConjoiningClauses {
     /// There are several kinds of predicates in our Datalog:
     /// - A limited set of binary comparison operators: < > <= >= !=.
     ///   These are converted into SQLite binary comparisons and some type constraints.
+    /// - `=`, which equates two variables, and `!=` between a variable and anything, of any type.
+    /// - `missing?`, which is converted into a `NOT EXISTS` subquery.
+    /// - `tx-ids` and `tx-data`, which bind their variables from the transaction log, like patterns.
+    /// - `like` and `like-ci`, which match strings against SQL `LIKE` patterns.
+    /// - `re-matches` and `re-find`, which match strings against regular expressions.
     /// - In the future, some predicates that are implemented via function calls in SQLite.
-    ///
-    /// At present we have implemented only the five built-in comparison binary operators.
     pub fn apply_predicate<'s>(&mut self, schema: &'s Schema, predicate: Predicate) -> Result<()> {
         // Because we'll be growing the set of built-in predicates, handling each differently,
         // and ultimately allowing user-specified predicates, we match on the predicate name first.
-        if let Some(op) = Inequality::from_datalog_operator(predicate.operator.0.as_str()) {
+        if predicate.operator.0.as_str() == "!=" && predicate.args.iter().any(|arg| arg.as_variable().is_some()) {
+            self.apply_not_equals(schema, predicate)
+        } else if let Some(op) = Inequality::from_datalog_operator(predicate.operator.0.as_str()) {
             self.apply_inequality(schema, op, predicate)
+        } else if predicate.operator.0.as_str() == "=" {
+            self.apply_equality(predicate)
+        } else if predicate.operator.0.as_str() == "missing?" {
+            self.apply_missing(schema, predicate)
+        } else if predicate.operator.0.as_str() == "tx-ids" {
+            self.apply_tx_ids(schema, predicate)
+        } else if predicate.operator.0.as_str() == "tx-data" {
+            self.apply_tx_data(schema, predicate)
+        } else if predicate.operator.0.as_str() == "like" {
+            self.apply_like(predicate, false)
+        } else if predicate.operator.0.as_str() == "like-ci" {
+            self.apply_like(predicate, true)
+        } else if predicate.operator.0.as_str() == "re-matches" {
+            self.apply_regex(predicate, true)
+        } else if predicate.operator.0.as_str() == "re-find" {
+            self.apply_regex(predicate, false)
         } else {
             bail!(ErrorKind::UnknownFunction(predicate.operator.clone()))
         }
@@ -144,6 +234,372 @@ impl ConjoiningClauses {
         self.wheres.add_intersection(constraint);
         Ok(())
     }
+
+    /// `[(= ?a ?b)]` is true when the two variables have the same value, as if they were one
+    /// variable, but without their having to share a name in the patterns that bind them. Each
+    /// must be bound by a preceding clause, or be a bound input. They can only be equal if they
+    /// can be of the same type, so we narrow each to the types they share, and if there are none
+    /// the query is known-empty.
+    pub fn apply_equality(&mut self, predicate: Predicate) -> Result<()> {
+        if predicate.args.len() != 2 {
+            bail!(ErrorKind::InvalidNumberOfArguments(predicate.operator.clone(), predicate.args.len(), 2));
+        }
+
+        let mut vars = Vec::with_capacity(2);
+        for (i, arg) in predicate.args.into_iter().enumerate() {
+            match arg {
+                FnArg::Variable(var) => vars.push(var),
+                _ => bail!(ErrorKind::InvalidArgument(predicate.operator.clone(), "variable", i)),
+            }
+        }
+        let right = vars.pop().unwrap();
+        let left = vars.pop().unwrap();
+
+        let mut values = Vec::with_capacity(2);
+        for var in [&left, &right].iter() {
+            let value = match self.bound_value(var) {
+                Some(value) => QueryValue::TypedValue(value),
+                None => match self.column_bindings.get(var).and_then(|cols| cols.first()) {
+                    Some(column) => QueryValue::Column(column.clone()),
+                    None => bail!(ErrorKind::UnboundVariable(var.name())),
+                },
+            };
+            values.push(value);
+        }
+
+        let left_types = self.known_type_set(&left);
+        let right_types = self.known_type_set(&right);
+        let shared_types = left_types.intersection(&right_types);
+        if shared_types.is_empty() {
+            self.mark_known_empty(EmptyBecause::TypeMismatch {
+                var: right,
+                existing: right_types,
+                desired: left_types,
+            });
+            return Ok(());
+        }
+        self.narrow_types_for_var(left.clone(), shared_types);
+        self.narrow_types_for_var(right.clone(), shared_types);
+
+        let right_v = values.pop().unwrap();
+        let left_v = values.pop().unwrap();
+        match (left_v, right_v) {
+            (QueryValue::Column(column), value) |
+            (value, QueryValue::Column(column)) => {
+                self.wheres.add_intersection(ColumnConstraint::Equals(column, value));
+            },
+            (QueryValue::TypedValue(l), QueryValue::TypedValue(r)) => {
+                if l != r {
+                    self.mark_known_empty(EmptyBecause::ConflictingBindings {
+                        var: right,
+                        existing: r,
+                        desired: l,
+                    });
+                }
+            },
+            // We only made columns and values.
+            _ => unreachable!(),
+        }
+        Ok(())
+    }
+
+    /// `[(!= ?status :status/closed)]` is true when the two arguments differ. Unlike `<` and
+    /// friends, `!=` compares values of any type, so long as at least one argument is a variable.
+    /// A constant is converted to the types of the variable it's compared with, just as a `ground`
+    /// argument would be: here `:status/closed` is an entid if `?status` is a ref. Two variables
+    /// are compared column to column. Longs and doubles compare numerically. Values that can't be
+    /// of the same type are never equal, so comparing them doesn't constrain the query at all.
+    ///
+    /// Each variable must be bound by a preceding clause, so neither side is ever absent: an
+    /// entity without a status doesn't match `[?e :foo/status ?status]`, and so isn't a result,
+    /// `!=` or not. Use `not` to include it. Were a side NULL, SQL's three-valued logic would make
+    /// `NULL <> x` unknown rather than true, and the row would be excluded just the same.
+    pub fn apply_not_equals(&mut self, schema: &Schema, predicate: Predicate) -> Result<()> {
+        if predicate.args.len() != 2 {
+            bail!(ErrorKind::InvalidNumberOfArguments(predicate.operator.clone(), predicate.args.len(), 2));
+        }
+
+        let mut args = predicate.args.into_iter();
+        let left = args.next().unwrap();
+        let right = args.next().unwrap();
+
+        // Put a variable on the left.
+        let (var, other, other_position) = match (left, right) {
+            (FnArg::Variable(var), other) => (var, other, 1),
+            (other, FnArg::Variable(var)) => (var, other, 0),
+            _ => bail!(ErrorKind::InvalidArgument(predicate.operator.clone(), "variable", 0)),
+        };
+
+        let mut var_types = self.known_type_set(&var);
+        if var_types.contains(ValueType::Long) || var_types.contains(ValueType::Double) {
+            var_types.insert(ValueType::Long);
+            var_types.insert(ValueType::Double);
+        }
+        let left_v = self.not_equals_operand(&var)?;
+
+        let right_v = match other {
+            FnArg::Variable(other_var) => {
+                let mut other_types = self.known_type_set(&other_var);
+                if other_types.contains(ValueType::Long) || other_types.contains(ValueType::Double) {
+                    other_types.insert(ValueType::Long);
+                    other_types.insert(ValueType::Double);
+                }
+                let right_v = self.not_equals_operand(&other_var)?;
+                if var_types.intersection(&other_types).is_empty() {
+                    return Ok(());
+                }
+                right_v
+            },
+            FnArg::SrcVar(_) |
+            FnArg::Keyword(_) |
+            FnArg::Vector(_) |
+            FnArg::Set(_) |
+            FnArg::Map(_) => {
+                bail!(ErrorKind::InvalidArgument(predicate.operator.clone(), "variable or constant", other_position));
+            },
+            constant => {
+                match self.typed_value_from_arg(schema, &var, constant, var_types)? {
+                    ValueConversion::Val(value) => QueryValue::TypedValue(value),
+                    // Nothing of the variable's type is equal to this value.
+                    _ => return Ok(()),
+                }
+            },
+        };
+
+        match (left_v, right_v) {
+            (QueryValue::TypedValue(l), QueryValue::TypedValue(r)) => {
+                // Both are known, so there's nothing to ask of the store.
+                if l == r {
+                    self.mark_known_empty(EmptyBecause::ConflictingBindings {
+                        var: var,
+                        existing: l,
+                        desired: r,
+                    });
+                }
+            },
+            (left_v, right_v) => {
+                self.wheres.add_intersection(ColumnConstraint::Inequality {
+                    operator: Inequality::NotEquals,
+                    left: left_v,
+                    right: right_v,
+                });
+            },
+        }
+        Ok(())
+    }
+
+    /// The column or bound value of a variable compared by `!=`.
+    fn not_equals_operand(&self, var: &Variable) -> Result<QueryValue> {
+        if let Some(value) = self.bound_value(var) {
+            return Ok(QueryValue::TypedValue(value));
+        }
+        match self.column_bindings.get(var).and_then(|cols| cols.first()) {
+            Some(column) => Ok(QueryValue::Column(column.clone())),
+            None => bail!(ErrorKind::UnboundVariable(var.name())),
+        }
+    }
+
+    /// `[(like ?name "Ab%")]` is true when the string `?name` matches the pattern, in which `%`
+    /// matches any run of characters and `_` any one character. `like` is case-sensitive;
+    /// `like-ci` ignores the case of ASCII letters, just as SQLite's `LIKE` does.
+    ///
+    /// To match a literal `%` or `_`, choose an escape character and pass it as a third argument:
+    /// in `[(like ?discount "100!%" "!")]`, `!%` matches only `%`. An escape character must be
+    /// followed by another character, and there's no escape character unless one is given.
+    ///
+    /// The pattern and escape character must be strings known while algebrizing: constants, or
+    /// bound inputs.
+    pub fn apply_like(&mut self, predicate: Predicate, case_insensitive: bool) -> Result<()> {
+        let count = predicate.args.len();
+        if count != 2 && count != 3 {
+            bail!(ErrorKind::InvalidNumberOfArguments(predicate.operator.clone(), count, 2));
+        }
+
+        let mut args = predicate.args.into_iter();
+        let var = match args.next().unwrap() {
+            FnArg::Variable(v) => v,
+            _ => bail!(ErrorKind::InvalidArgument(predicate.operator.clone(), "variable", 0)),
+        };
+        let pattern = self.resolve_string_constant(&predicate.operator, 1, args.next().unwrap())?;
+        let escape = match args.next() {
+            None => None,
+            Some(arg) => {
+                let escape = self.resolve_string_constant(&predicate.operator, 2, arg)?;
+                let mut chars = escape.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => Some(c),
+                    _ => bail!(ErrorKind::InvalidArgument(predicate.operator.clone(), "single character", 2)),
+                }
+            },
+        };
+
+        if let Some(e) = escape {
+            let mut chars = pattern.chars();
+            while let Some(c) = chars.next() {
+                if c == e && chars.next().is_none() {
+                    bail!(ErrorKind::InvalidArgument(predicate.operator.clone(), "pattern", 1));
+                }
+            }
+        }
+
+        if !self.known_type_set(&var).contains(ValueType::String) {
+            bail!(ErrorKind::InvalidArgument(predicate.operator.clone(), "string", 0));
+        }
+        let column = match self.column_bindings.get(&var).and_then(|cols| cols.first()) {
+            Some(column) => column.clone(),
+            None => bail!(ErrorKind::UnboundVariable(var.name())),
+        };
+        self.constrain_var_to_type(var, ValueType::String);
+        self.wheres.add_intersection(ColumnConstraint::Like {
+            column: column,
+            pattern: pattern,
+            escape: escape,
+            case_insensitive: case_insensitive,
+        });
+        Ok(())
+    }
+
+    /// `[(re-find ?phone "\\d{3}-\\d{4}")]` is true when the string `?phone` contains a match for the
+    /// regular expression, and `re-matches` when the whole string matches. Patterns use the syntax
+    /// of the `regex` crate, and are run by the `regexp` function that `new_connection` gives
+    /// SQLite.
+    ///
+    /// We compile the pattern here, so that a bad pattern fails the query rather than the
+    /// evaluation of some row. Like `like`'s, it must be a constant or a bound input.
+    pub fn apply_regex(&mut self, predicate: Predicate, whole: bool) -> Result<()> {
+        if predicate.args.len() != 2 {
+            bail!(ErrorKind::InvalidNumberOfArguments(predicate.operator.clone(), predicate.args.len(), 2));
+        }
+
+        let mut args = predicate.args.into_iter();
+        let var = match args.next().unwrap() {
+            FnArg::Variable(v) => v,
+            _ => bail!(ErrorKind::InvalidArgument(predicate.operator.clone(), "variable", 0)),
+        };
+        let pattern = self.resolve_string_constant(&predicate.operator, 1, args.next().unwrap())?;
+        if let Err(e) = Regex::new(pattern.as_str()) {
+            bail!(ErrorKind::InvalidRegex(predicate.operator.clone(), pattern, e.to_string()));
+        }
+        let pattern = if whole {
+            format!(r"\A(?:{})\z", pattern)
+        } else {
+            pattern
+        };
+
+        if !self.known_type_set(&var).contains(ValueType::String) {
+            bail!(ErrorKind::InvalidArgument(predicate.operator.clone(), "string", 0));
+        }
+        let column = match self.column_bindings.get(&var).and_then(|cols| cols.first()) {
+            Some(column) => column.clone(),
+            None => bail!(ErrorKind::UnboundVariable(var.name())),
+        };
+        self.constrain_var_to_type(var, ValueType::String);
+        self.wheres.add_intersection(ColumnConstraint::Regex(column, pattern));
+        Ok(())
+    }
+
+    /// Fold each pair of inequalities that bound the same column from below and from above, like
+    /// `[(> ?age 18)] [(< ?age 65)]`, into a single range, which we can express to SQLite as one
+    /// range scan. The range replaces the first of the pair.
+    pub fn fold_ranges(&mut self) {
+        let mut bounds: Vec<Option<Bound>> = self.wheres.0.iter().map(Bound::from_constraint).collect();
+
+        // For each constraint, the index of its other half, if it's the first of a pair.
+        let mut partners: Vec<Option<usize>> = vec![None; bounds.len()];
+        let mut paired = vec![false; bounds.len()];
+        for i in 0..bounds.len() {
+            if paired[i] {
+                continue;
+            }
+            if let Some(ref first) = bounds[i] {
+                let partner = (i + 1..bounds.len()).find(|&j| {
+                    !paired[j] && bounds[j].as_ref().map_or(false, |second| {
+                        second.column == first.column && second.lower != first.lower
+                    })
+                });
+                if let Some(j) = partner {
+                    partners[i] = Some(j);
+                    paired[i] = true;
+                    paired[j] = true;
+                }
+            }
+        }
+        if partners.iter().all(|p| p.is_none()) {
+            return;
+        }
+
+        let constraints = mem::replace(&mut self.wheres.0, vec![]);
+        for (i, constraint) in constraints.into_iter().enumerate() {
+            match partners[i] {
+                Some(j) => {
+                    let first = bounds[i].take().expect("bound");
+                    let second = bounds[j].take().expect("bound");
+                    let (lower, upper) = if first.lower { (first, second) } else { (second, first) };
+                    self.wheres.add_intersection(ColumnConstraint::Range {
+                        column: lower.column,
+                        lower: lower.value,
+                        lower_inclusive: lower.inclusive,
+                        upper: upper.value,
+                        upper_inclusive: upper.inclusive,
+                    });
+                },
+                None if paired[i] => {},      // Folded into an earlier range.
+                None => self.wheres.0.push(constraint),
+            }
+        }
+    }
+
+    /// `[(missing? $ ?e :foo/bar)]` is true when `?e` has no value for `:foo/bar`. It's
+    /// equivalent to `(not-join [?e] [?e :foo/bar _])`, and is implemented in exactly that way.
+    pub fn apply_missing<'s>(&mut self, schema: &'s Schema, predicate: Predicate) -> Result<()> {
+        if predicate.args.len() != 3 {
+            bail!(ErrorKind::InvalidNumberOfArguments(predicate.operator.clone(), predicate.args.len(), 3));
+        }
+
+        let mut args = predicate.args.into_iter();
+
+        match args.next().unwrap() {
+            FnArg::SrcVar(SrcVar::DefaultSrc) => {},
+            _ => bail!(ErrorKind::InvalidArgument(predicate.operator.clone(), "source variable", 0)),
+        }
+
+        let e = match args.next().unwrap() {
+            FnArg::Variable(v) => v,
+            _ => bail!(ErrorKind::InvalidArgument(predicate.operator.clone(), "variable", 1)),
+        };
+
+        let a = match args.next().unwrap() {
+            FnArg::IdentOrKeyword(i) => {
+                match schema.get_entid(&i) {
+                    Some(a) => a,
+                    None => {
+                        self.mark_known_empty(EmptyBecause::UnresolvedIdent(i));
+                        return Ok(());
+                    },
+                }
+            },
+            FnArg::EntidOrInteger(a) => a,
+            _ => bail!(ErrorKind::InvalidArgument(predicate.operator.clone(), "attribute", 2)),
+        };
+
+        if !schema.is_attribute(a) {
+            self.mark_known_empty(EmptyBecause::InvalidAttributeEntid(a));
+            return Ok(());
+        }
+
+        let pattern = Pattern {
+            source: None,
+            entity: PatternNonValuePlace::Variable(e.clone()),
+            attribute: PatternNonValuePlace::Entid(a),
+            value: PatternValuePlace::Placeholder,
+            tx: PatternNonValuePlace::Placeholder,
+        };
+
+        self.apply_not_join(schema, NotJoin {
+            unify_vars: UnifyVars::Explicit(::std::iter::once(e).collect()),
+            clauses: vec![WhereClause::Pattern(pattern)],
+        })
+    }
 }
 
 #[cfg(test)]
@@ -292,4 +748,47 @@ mod testing {
                        desired: ValueTypeSet::of_one(ValueType::String),
                    });
     }
+
+    #[test]
+    /// `missing?` requires an attribute that we know about.
+    fn test_apply_missing_unknown_attribute() {
+        let mut schema = Schema::default();
+
+        associate_ident(&mut schema, NamespacedKeyword::new("foo", "bar"), 99);
+        add_attribute(&mut schema, 99, Attribute {
+            value_type: ValueType::Long,
+            ..Default::default()
+        });
+
+        let x = Variable::from_valid_name("?x");
+        let with_pattern = || {
+            let mut cc = ConjoiningClauses::default();
+            cc.apply_pattern(&schema, Pattern {
+                source: None,
+                entity: PatternNonValuePlace::Variable(x.clone()),
+                attribute: ident("foo", "bar"),
+                value: PatternValuePlace::Placeholder,
+                tx: PatternNonValuePlace::Placeholder,
+            });
+            cc
+        };
+
+        let missing = |attribute: FnArg| Predicate {
+            operator: PlainSymbol::new("missing?"),
+            args: vec![FnArg::SrcVar(SrcVar::DefaultSrc), FnArg::Variable(x.clone()), attribute],
+        };
+
+        // A known attribute adds a constraint.
+        let mut known = with_pattern();
+        known.apply_predicate(&schema, missing(FnArg::IdentOrKeyword(NamespacedKeyword::new("foo", "bar")))).unwrap();
+        assert!(!known.is_known_empty());
+        assert_eq!(known.wheres.len(), 1);
+
+        // An unknown one can't be resolved.
+        let kw = NamespacedKeyword::new("foo", "nope");
+        let mut cc = with_pattern();
+        cc.apply_predicate(&schema, missing(FnArg::IdentOrKeyword(kw.clone()))).unwrap();
+        assert!(cc.is_known_empty());
+        assert_eq!(cc.empty_because.unwrap(), EmptyBecause::UnresolvedIdent(kw));
+    }
 }
@@ -52,13 +52,18 @@ impl ConjoiningClauses {
             // Can't be an entid.
             EntidOrInteger(i) => Ok(QueryValue::TypedValue(TypedValue::Long(i))),
             IdentOrKeyword(_) |
+            Keyword(_) |
             SrcVar(_) |
             Constant(NonIntegerConstant::Boolean(_)) |
             Constant(NonIntegerConstant::Text(_)) |
             Constant(NonIntegerConstant::Uuid(_)) |
             Constant(NonIntegerConstant::Instant(_)) |        // Instants are covered below.
             Constant(NonIntegerConstant::BigInteger(_)) |
-            Vector(_) => {
+            Constant(NonIntegerConstant::Bytes(_)) |
+            Vector(_) |
+            Set(_) |
+            Map(_) |
+            Nil => {
                 self.mark_known_empty(EmptyBecause::NonNumericArgument);
                 bail!(ErrorKind::InvalidArgument(function.clone(), "numeric", position));
             },
@@ -84,19 +89,40 @@ impl ConjoiningClauses {
             // TODO: should we allow integers if they seem to be timestamps? It's ambiguous…
             EntidOrInteger(_) |
             IdentOrKeyword(_) |
+            Keyword(_) |
             SrcVar(_) |
             Constant(NonIntegerConstant::Boolean(_)) |
             Constant(NonIntegerConstant::Float(_)) |
             Constant(NonIntegerConstant::Text(_)) |
             Constant(NonIntegerConstant::Uuid(_)) |
             Constant(NonIntegerConstant::BigInteger(_)) |
-            Vector(_) => {
+            Constant(NonIntegerConstant::Bytes(_)) |
+            Vector(_) |
+            Set(_) |
+            Map(_) |
+            Nil => {
                 self.mark_known_empty(EmptyBecause::NonInstantArgument);
                 bail!(ErrorKind::InvalidArgument(function.clone(), "instant", position));
             },
         }
     }
 
+    /// Resolve an argument that must be a string known while algebrizing: a string constant, or
+    /// an input variable already bound to a string.
+    pub fn resolve_string_constant(&self, function: &PlainSymbol, position: usize, arg: FnArg) -> Result<String> {
+        match arg {
+            FnArg::Constant(NonIntegerConstant::Text(s)) => Ok((*s).clone()),
+            FnArg::Variable(ref var) => {
+                match self.bound_value(var) {
+                    Some(TypedValue::String(s)) => Ok((*s).clone()),
+                    Some(_) => bail!(ErrorKind::InvalidArgument(function.clone(), "string", position)),
+                    None => bail!(ErrorKind::UnboundVariable(var.name())),
+                }
+            },
+            _ => bail!(ErrorKind::InvalidArgument(function.clone(), "string", position)),
+        }
+    }
+
     /// Take a function argument and turn it into a `QueryValue` suitable for use in a concrete
     /// constraint.
     #[allow(dead_code)]
@@ -111,14 +137,19 @@ impl ConjoiningClauses {
             },
             EntidOrInteger(i) => Ok(QueryValue::PrimitiveLong(i)),
             IdentOrKeyword(_) => unimplemented!(),     // TODO
+            Keyword(_) => unimplemented!(),
             Constant(NonIntegerConstant::Boolean(val)) => Ok(QueryValue::TypedValue(TypedValue::Boolean(val))),
             Constant(NonIntegerConstant::Float(f)) => Ok(QueryValue::TypedValue(TypedValue::Double(f))),
             Constant(NonIntegerConstant::Text(s)) => Ok(QueryValue::TypedValue(TypedValue::typed_string(s.as_str()))),
             Constant(NonIntegerConstant::Uuid(u)) => Ok(QueryValue::TypedValue(TypedValue::Uuid(u))),
             Constant(NonIntegerConstant::Instant(u)) => Ok(QueryValue::TypedValue(TypedValue::Instant(u))),
-            Constant(NonIntegerConstant::BigInteger(_)) => unimplemented!(),
+            Constant(NonIntegerConstant::Bytes(b)) => Ok(QueryValue::TypedValue(TypedValue::Bytes(b))),
+            Constant(NonIntegerConstant::BigInteger(b)) => Ok(QueryValue::TypedValue(TypedValue::BigInteger(b))),
             SrcVar(_) => unimplemented!(),
             Vector(_) => unimplemented!(),    // TODO
+            Set(_) => unimplemented!(),       // TODO
+            Map(_) => unimplemented!(),       // TODO
+            Nil => unimplemented!(),
         }
     }
 }
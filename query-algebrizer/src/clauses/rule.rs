@@ -0,0 +1,178 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+use mentat_core::{
+    Schema,
+    ValueType,
+};
+
+use mentat_query::{
+    FnArg,
+    RuleExpr,
+    WhereClause,
+};
+
+use clauses::{
+    ConjoiningClauses,
+    PushComputed,
+};
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+
+use rules::prepare_recursive_rule;
+
+use types::{
+    ComputedTable,
+    DatomsTable,
+    EmptyBecause,
+    SourceAlias,
+    VariableColumn,
+};
+
+impl ConjoiningClauses {
+    /// A CC for one member of a recursive rule's CTE. The rule's variables mean nothing outside
+    /// it, so this shares only our aliases and how we resolve idents.
+    fn make_rule_member(&self) -> ConjoiningClauses {
+        ConjoiningClauses {
+            alias_counter: self.alias_counter.clone(),
+            ident_aliases: self.ident_aliases.clone(),
+            prefer_most_specific_types: self.prefer_most_specific_types,
+            integral_floats_as_longs: self.integral_floats_as_longs,
+            ident_cache: self.ident_cache.clone(),
+            rules: self.rules.clone(),
+            ..Default::default()
+        }
+    }
+
+    fn apply_rule_member(&mut self, schema: &Schema, clauses: Vec<WhereClause>) -> Result<()> {
+        for clause in clauses {
+            self.apply_clause(schema, clause)?;
+        }
+        self.expand_column_bindings();
+        self.prune_extracted_types();
+        Ok(())
+    }
+
+    /// An invocation of a recursive rule, like `(ancestor ?x ?y)`, joins against a recursive CTE:
+    ///
+    /// ```sql
+    /// (WITH RECURSIVE `rule`(`?a`, `?b`, depth) AS
+    ///    (SELECT `datoms00`.e AS `?a`, `datoms00`.v AS `?b`, 1 AS depth
+    ///     FROM `datoms` AS `datoms00` WHERE `datoms00`.a = 65
+    ///     UNION
+    ///     SELECT `datoms02`.e AS `?a`, `rule01`.`?b` AS `?b`, <the depth of rule01, plus one> AS depth
+    ///     FROM `rule` AS `rule01`, `datoms` AS `datoms02`
+    ///     WHERE `datoms02`.a = 65 AND `datoms02`.v = `rule01`.`?a`)
+    ///  SELECT DISTINCT `?a`, `?b` FROM `rule`) AS `c00`
+    /// ```
+    ///
+    /// The definitions of the rule that don't invoke it are the anchor, and the one that does is
+    /// the recursive step, in which that invocation reads the rows derived so far. SQLite stores
+    /// nothing but the rows themselves, so each of the rule's variables must have the same single
+    /// type in every anchor.
+    pub fn apply_recursive_rule(&mut self, schema: &Schema, invocation: RuleExpr) -> Result<()> {
+        let rules = match self.rules {
+            Some(ref rules) => rules.clone(),
+            None => bail!(ErrorKind::UnknownRule(invocation.name)),
+        };
+
+        let RuleExpr { name, args } = invocation;
+        let mut vars = Vec::with_capacity(args.len());
+        for (i, arg) in args.into_iter().enumerate() {
+            match arg {
+                FnArg::Variable(var) => vars.push(var),
+                _ => bail!(ErrorKind::InvalidArgument(name, "variable", i)),
+            }
+        }
+
+        let rule = prepare_recursive_rule(rules.rules(), &name)?;
+        if vars.len() != rule.vars.len() {
+            bail!(ErrorKind::InvalidNumberOfArguments(name, vars.len(), rule.vars.len()));
+        }
+
+        let mut anchors = Vec::with_capacity(rule.anchors.len());
+        let mut empty_because: Option<EmptyBecause> = None;
+        for clauses in rule.anchors {
+            let mut anchor = self.make_rule_member();
+            anchor.apply_rule_member(schema, clauses)?;
+            if anchor.is_known_empty() {
+                empty_because = anchor.empty_because;
+            } else {
+                anchors.push(anchor);
+            }
+        }
+
+        // Without an anchor, there's nothing for the step to start from.
+        if anchors.is_empty() {
+            self.mark_known_empty(empty_because.expect("empty for a reason"));
+            return Ok(());
+        }
+
+        let mut types: Vec<ValueType> = Vec::with_capacity(rule.vars.len());
+        for var in rule.vars.iter() {
+            let mut known: Option<ValueType> = None;
+            for anchor in anchors.iter() {
+                if !anchor.column_bindings.contains_key(var) && !anchor.is_value_bound(var) {
+                    bail!(ErrorKind::UnboundVariable(var.name()));
+                }
+                match (anchor.known_type(var), known) {
+                    (Some(t), None) => known = Some(t),
+                    (Some(t), Some(k)) if t == k => {},
+                    _ => bail!(ErrorKind::UntypedRuleVariable(name, var.name())),
+                }
+            }
+            types.push(known.expect("at least one anchor"));
+        }
+
+        // The step's invocation of the rule is a join against the rows derived so far, whose
+        // columns are named for the rule's variables.
+        let mut step = self.make_rule_member();
+        let derived = step.next_alias_for_table(DatomsTable::Rule);
+        step.from.push(SourceAlias(DatomsTable::Rule, derived.clone()));
+        for ((arg, var), t) in rule.step_args.into_iter().zip(rule.vars.iter()).zip(types.iter()) {
+            step.constrain_var_to_type(arg.clone(), *t);
+            step.bind_column_to_var(schema, derived.clone(), VariableColumn::Variable(var.clone()), arg);
+        }
+        for (var, t) in rule.vars.iter().zip(types.iter()) {
+            step.constrain_var_to_type(var.clone(), *t);
+        }
+        step.apply_rule_member(schema, rule.step)?;
+
+        let step = if step.is_known_empty() {
+            None
+        } else {
+            for var in rule.vars.iter() {
+                if !step.column_bindings.contains_key(var) && !step.is_value_bound(var) {
+                    bail!(ErrorKind::UnboundVariable(var.name()));
+                }
+            }
+            Some(Box::new(step))
+        };
+
+        let table = self.computed_tables.push_computed(ComputedTable::RecursiveRule {
+            name: name,
+            vars: rule.vars.clone(),
+            anchors: anchors,
+            step: step,
+            derived: derived,
+            max_depth: rules.max_depth(),
+        });
+        let alias = self.next_alias_for_table(table);
+        for ((arg, var), t) in vars.into_iter().zip(rule.vars.into_iter()).zip(types.into_iter()) {
+            self.constrain_var_to_type(arg.clone(), t);
+            self.bind_column_to_var(schema, alias.clone(), VariableColumn::Variable(var), arg);
+        }
+        self.from.push(SourceAlias(table, alias));
+        Ok(())
+    }
+}
@@ -0,0 +1,348 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+use mentat_core::{
+    Schema,
+    TypedValue,
+    ValueType,
+    ValueTypeSet,
+};
+
+use mentat_query::{
+    FnArg,
+    NamespacedKeyword,
+    Predicate,
+    SrcVar,
+    Variable,
+};
+
+use clauses::ConjoiningClauses;
+
+use clauses::convert::ValueConversion;
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+
+use types::{
+    Column,
+    ColumnAlternation,
+    ColumnConstraint,
+    ColumnConstraintOrAlternation,
+    ColumnIntersection,
+    DatomsColumn,
+    DatomsTable,
+    EmptyBecause,
+    Fallback,
+    FulltextColumn,
+    Inequality,
+    OuterJoin,
+    QualifiedAlias,
+    QueryValue,
+    SourceAlias,
+};
+
+impl ConjoiningClauses {
+    /// `[(tx-ids $ ?tx start end)]` binds `?tx` to each transaction between `start` and `end`,
+    /// inclusive. Each bound is either an instant, compared to the transaction's `:db/txInstant`,
+    /// or a transaction entid.
+    ///
+    /// Every transaction asserts exactly one `:db/txInstant`, so we scan the transactions table
+    /// for those datoms: one row per transaction.
+    pub fn apply_tx_ids<'s>(&mut self, schema: &'s Schema, predicate: Predicate) -> Result<()> {
+        if predicate.args.len() != 4 {
+            bail!(ErrorKind::InvalidNumberOfArguments(predicate.operator.clone(), predicate.args.len(), 4));
+        }
+
+        let mut args = predicate.args.into_iter();
+
+        match args.next().unwrap() {
+            FnArg::SrcVar(SrcVar::DefaultSrc) => {},
+            _ => bail!(ErrorKind::InvalidArgument(predicate.operator.clone(), "source variable", 0)),
+        }
+
+        let tx = match args.next().unwrap() {
+            FnArg::Variable(v) => v,
+            _ => bail!(ErrorKind::InvalidArgument(predicate.operator.clone(), "variable", 1)),
+        };
+
+        let mut bounds = Vec::with_capacity(2);
+        for (position, arg) in args.enumerate() {
+            let position = position + 2;
+            let bound = match self.transaction_bound(schema, &tx, arg)? {
+                ValueConversion::Val(TypedValue::Ref(e)) |
+                ValueConversion::Val(TypedValue::Long(e)) => TypedValue::Ref(e),
+                ValueConversion::Val(instant @ TypedValue::Instant(_)) => instant,
+                ValueConversion::Val(_) |
+                ValueConversion::ValColl(_) |
+                ValueConversion::Impossible(EmptyBecause::TypeMismatch { .. }) => {
+                    bail!(ErrorKind::InvalidArgument(predicate.operator.clone(), "instant or transaction", position));
+                },
+                ValueConversion::Impossible(because) => {
+                    self.mark_known_empty(because);
+                    return Ok(());
+                },
+            };
+            bounds.push(bound);
+        }
+
+        let tx_instant = NamespacedKeyword::new("db", "txInstant");
+        let a = match schema.get_entid(&tx_instant) {
+            Some(a) => a,
+            None => {
+                self.mark_known_empty(EmptyBecause::UnresolvedIdent(tx_instant));
+                return Ok(());
+            },
+        };
+
+        let alias = self.next_alias_for_table(DatomsTable::Transactions);
+        self.from.push(SourceAlias(DatomsTable::Transactions, alias.clone()));
+        self.constrain_attribute(alias.clone(), a);
+
+        let operators = [Inequality::GreaterThanOrEquals, Inequality::LessThanOrEquals];
+        for (bound, &operator) in bounds.into_iter().zip(operators.iter()) {
+            let column = match bound {
+                TypedValue::Instant(_) => DatomsColumn::Value,
+                _ => DatomsColumn::Entity,
+            };
+            self.wheres.add_intersection(ColumnConstraint::Inequality {
+                operator: operator,
+                left: QueryValue::Column(QualifiedAlias::new(alias.clone(), column)),
+                right: QueryValue::TypedValue(bound),
+            });
+        }
+
+        self.constrain_var_to_type(tx.clone(), ValueType::Ref);
+        self.bind_column_to_var(schema, alias, DatomsColumn::Entity, tx);
+        Ok(())
+    }
+
+    /// `[(tx-data $ ?tx ?e ?a ?v ?added)]` binds the remaining variables to each datom asserted
+    /// or retracted by the transaction `?tx`, which can be bound earlier in the query or given as
+    /// an entid.
+    ///
+    /// The log stores fulltext values as rowids, so `?v` is the text of the matching
+    /// `fulltext_values` row if there is one, and the stored value otherwise. Like a `get-else`
+    /// binding, it can therefore only be projected.
+    pub fn apply_tx_data<'s>(&mut self, schema: &'s Schema, predicate: Predicate) -> Result<()> {
+        if predicate.args.len() != 6 {
+            bail!(ErrorKind::InvalidNumberOfArguments(predicate.operator.clone(), predicate.args.len(), 6));
+        }
+
+        let mut args = predicate.args.into_iter();
+
+        match args.next().unwrap() {
+            FnArg::SrcVar(SrcVar::DefaultSrc) => {},
+            _ => bail!(ErrorKind::InvalidArgument(predicate.operator.clone(), "source variable", 0)),
+        }
+
+        let alias = self.next_alias_for_table(DatomsTable::Transactions);
+
+        match args.next().unwrap() {
+            FnArg::Variable(tx) => {
+                self.constrain_var_to_type(tx.clone(), ValueType::Ref);
+                self.bind_column_to_var(schema, alias.clone(), DatomsColumn::Tx, tx);
+            },
+            FnArg::EntidOrInteger(tx) => {
+                self.constrain_column_to_entity(alias.clone(), DatomsColumn::Tx, tx);
+            },
+            _ => bail!(ErrorKind::InvalidArgument(predicate.operator.clone(), "transaction", 1)),
+        }
+
+        let mut vars = Vec::with_capacity(4);
+        for (position, arg) in args.enumerate() {
+            match arg {
+                FnArg::Variable(var) => vars.push(var),
+                _ => bail!(ErrorKind::InvalidArgument(predicate.operator.clone(), "variable", position + 2)),
+            }
+        }
+        let mut vars = vars.into_iter();
+        let e = vars.next().unwrap();
+        let a = vars.next().unwrap();
+        let v = vars.next().unwrap();
+        let added = vars.next().unwrap();
+
+        self.from.push(SourceAlias(DatomsTable::Transactions, alias.clone()));
+
+        self.constrain_var_to_type(e.clone(), ValueType::Ref);
+        self.bind_column_to_var(schema, alias.clone(), DatomsColumn::Entity, e);
+        self.constrain_var_to_type(a.clone(), ValueType::Ref);
+        self.bind_column_to_var(schema, alias.clone(), DatomsColumn::Attribute, a);
+        self.constrain_var_to_type(added.clone(), ValueType::Boolean);
+        self.bind_column_to_var(schema, alias.clone(), DatomsColumn::Added, added);
+
+        let value = QualifiedAlias::new(alias.clone(), DatomsColumn::Value);
+        let mut columns = vec![];
+
+        let fulltext: Vec<ColumnIntersection> =
+            schema.schema_map
+                  .iter()
+                  .filter(|&(_, attribute)| attribute.fulltext)
+                  .map(|(&attr, _)| {
+                      vec![ColumnConstraint::Equals(QualifiedAlias::new(alias.clone(), DatomsColumn::Attribute),
+                                                    QueryValue::Entid(attr))].into()
+                  })
+                  .collect();
+        if !fulltext.is_empty() {
+            let fulltext_values = self.next_alias_for_table(DatomsTable::FulltextValues);
+            let mut on = ColumnIntersection::default();
+            on.add_intersection(ColumnConstraint::Equals(
+                QualifiedAlias(fulltext_values.clone(), Column::Fulltext(FulltextColumn::Rowid)),
+                QueryValue::Column(value.clone())));
+            on.add(ColumnConstraintOrAlternation::Alternation(ColumnAlternation(fulltext)));
+            self.outer_joins.push(OuterJoin {
+                table: SourceAlias(DatomsTable::FulltextValues, fulltext_values.clone()),
+                on: on,
+            });
+            columns.push(QualifiedAlias(fulltext_values, Column::Fulltext(FulltextColumn::Text)));
+        }
+        columns.push(value);
+
+        self.extracted_types.insert(v.clone(), QualifiedAlias::new(alias, DatomsColumn::ValueTypeTag));
+        self.fallback_bindings.insert(v, Fallback {
+            columns: columns,
+            default: None,
+        });
+        Ok(())
+    }
+
+    fn transaction_bound(&self, schema: &Schema, tx: &Variable, arg: FnArg) -> Result<ValueConversion> {
+        let types = ValueTypeSet::of_one(ValueType::Instant).union(&ValueTypeSet::of_one(ValueType::Ref));
+        self.typed_value_from_arg(schema, tx, arg, types)
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    use std::rc::Rc;
+
+    use mentat_core::{
+        DateTime,
+        FromMicros,
+        Utc,
+    };
+
+    use mentat_query::{
+        NonIntegerConstant,
+        PlainSymbol,
+    };
+
+    use mentat_core::Attribute;
+
+    use clauses::{
+        add_attribute,
+        associate_ident,
+    };
+
+    fn tx_ids(args: Vec<FnArg>) -> Predicate {
+        Predicate {
+            operator: PlainSymbol::new("tx-ids"),
+            args: args,
+        }
+    }
+
+    fn tx_schema() -> Schema {
+        let mut schema = Schema::default();
+        associate_ident(&mut schema, NamespacedKeyword::new("db", "txInstant"), 3);
+        schema
+    }
+
+    #[test]
+    fn test_apply_tx_ids() {
+        let schema = tx_schema();
+        let tx = Variable::from_valid_name("?tx");
+        let start = DateTime::<Utc>::from_micros(1_000_000);
+
+        let mut cc = ConjoiningClauses::default();
+        cc.apply_tx_ids(&schema, tx_ids(vec![
+            FnArg::SrcVar(SrcVar::DefaultSrc),
+            FnArg::Variable(tx.clone()),
+            FnArg::Constant(NonIntegerConstant::Instant(start)),
+            FnArg::EntidOrInteger(268435460),
+        ])).expect("to apply");
+
+        assert!(!cc.is_known_empty());
+        assert_eq!(cc.known_type(&tx), Some(ValueType::Ref));
+
+        let transactions = "transactions00".to_string();
+        assert_eq!(cc.from, vec![SourceAlias(DatomsTable::Transactions, transactions.clone())]);
+        assert_eq!(cc.column_bindings.get(&tx), Some(&vec![QualifiedAlias::new(transactions.clone(), DatomsColumn::Entity)]));
+        assert_eq!(cc.wheres, vec![
+            ColumnConstraint::Equals(QualifiedAlias::new(transactions.clone(), DatomsColumn::Attribute),
+                                     QueryValue::Entid(3)),
+            ColumnConstraint::Inequality {
+                operator: Inequality::GreaterThanOrEquals,
+                left: QueryValue::Column(QualifiedAlias::new(transactions.clone(), DatomsColumn::Value)),
+                right: QueryValue::TypedValue(TypedValue::Instant(start)),
+            },
+            ColumnConstraint::Inequality {
+                operator: Inequality::LessThanOrEquals,
+                left: QueryValue::Column(QualifiedAlias::new(transactions.clone(), DatomsColumn::Entity)),
+                right: QueryValue::TypedValue(TypedValue::Ref(268435460)),
+            },
+        ].into());
+    }
+
+    #[test]
+    fn test_apply_tx_ids_invalid_bound() {
+        let schema = tx_schema();
+        let mut cc = ConjoiningClauses::default();
+        let result = cc.apply_tx_ids(&schema, tx_ids(vec![
+            FnArg::SrcVar(SrcVar::DefaultSrc),
+            FnArg::Variable(Variable::from_valid_name("?tx")),
+            FnArg::Constant(NonIntegerConstant::Text(Rc::new("yesterday".to_string()))),
+            FnArg::EntidOrInteger(268435460),
+        ]));
+        match result {
+            Err(::errors::Error(ErrorKind::InvalidArgument(_, "instant or transaction", 2), _)) => {},
+            x => panic!("expected an invalid argument error, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_apply_tx_data() {
+        let mut schema = tx_schema();
+        associate_ident(&mut schema, NamespacedKeyword::new("foo", "bio"), 99);
+        add_attribute(&mut schema, 99, Attribute {
+            value_type: ValueType::String,
+            fulltext: true,
+            ..Default::default()
+        });
+
+        let vars: Vec<Variable> = ["?e", "?a", "?v", "?added"].iter().map(|v| Variable::from_valid_name(v)).collect();
+        let mut args = vec![FnArg::SrcVar(SrcVar::DefaultSrc), FnArg::EntidOrInteger(268435460)];
+        args.extend(vars.iter().cloned().map(FnArg::Variable));
+
+        let mut cc = ConjoiningClauses::default();
+        cc.apply_tx_data(&schema, Predicate {
+            operator: PlainSymbol::new("tx-data"),
+            args: args,
+        }).expect("to apply");
+
+        assert_eq!(cc.known_type(&vars[0]), Some(ValueType::Ref));
+        assert_eq!(cc.known_type(&vars[1]), Some(ValueType::Ref));
+        assert_eq!(cc.known_type(&vars[2]), None);
+        assert_eq!(cc.known_type(&vars[3]), Some(ValueType::Boolean));
+
+        // The value is read from the fulltext values table if it's there.
+        let transactions = "transactions00".to_string();
+        let fulltext_values = "fulltext_values01".to_string();
+        assert_eq!(cc.fallback_bindings.get(&vars[2]), Some(&Fallback {
+            columns: vec![QualifiedAlias(fulltext_values.clone(), Column::Fulltext(FulltextColumn::Text)),
+                          QualifiedAlias::new(transactions.clone(), DatomsColumn::Value)],
+            default: None,
+        }));
+        assert_eq!(cc.extracted_types.get(&vars[2]), Some(&QualifiedAlias::new(transactions.clone(), DatomsColumn::ValueTypeTag)));
+        assert_eq!(cc.outer_joins.len(), 1);
+    }
+}
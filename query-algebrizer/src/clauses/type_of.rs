@@ -0,0 +1,81 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+use mentat_core::{
+    TypedValue,
+    ValueType,
+};
+
+use mentat_query::{
+    Binding,
+    FnArg,
+    WhereFn,
+};
+
+use clauses::{
+    ConjoiningClauses,
+};
+
+use errors::{
+    BindingError,
+    ErrorKind,
+    Result,
+};
+
+impl ConjoiningClauses {
+    /// `[(type-of ?v) ?t]` binds `?t` to the keyword naming the type of `?v`, like
+    /// `:db.type/string`.
+    ///
+    /// If `?v` is bound to a value, `?t` is simply bound to the keyword for its type. Otherwise
+    /// we can't know the type of each row until we run the query, so we record the association
+    /// and leave it to the projector, which reads the value and type tag of `?v` for each row.
+    /// That means `?t` can only be projected: it can't be used in any other clause.
+    pub fn apply_type_of(&mut self, where_fn: WhereFn) -> Result<()> {
+        if where_fn.args.len() != 1 {
+            bail!(ErrorKind::InvalidNumberOfArguments(where_fn.operator.clone(), where_fn.args.len(), 1));
+        }
+
+        if where_fn.binding.is_empty() {
+            // The binding must introduce at least one bound variable.
+            bail!(ErrorKind::InvalidBinding(where_fn.operator.clone(), BindingError::NoBoundVariable));
+        }
+
+        let target = match where_fn.binding {
+            Binding::BindScalar(var) => var,
+            Binding::BindColl(_) |
+            Binding::BindRel(_) |
+            Binding::BindTuple(_) => bail!(ErrorKind::InvalidBinding(where_fn.operator.clone(), BindingError::ExpectedBindScalar)),
+        };
+
+        let var = match where_fn.args.into_iter().next().unwrap() {
+            FnArg::Variable(var) => var,
+            _ => bail!(ErrorKind::InvalidArgument(where_fn.operator.clone(), "variable".into(), 0)),
+        };
+
+        if let Some(value) = self.bound_value(&var) {
+            self.bind_value(&target, TypedValue::from(value.value_type().into_keyword()));
+            return Ok(());
+        }
+
+        if !self.column_bindings.contains_key(&var) {
+            bail!(ErrorKind::UnboundVariable(var.name()));
+        }
+
+        if self.column_bindings.contains_key(&target) ||
+           self.bound_value(&target).is_some() ||
+           self.type_of_bindings.contains_key(&target) {
+            bail!(ErrorKind::TypeOfCanOnlyBeProjected(target.name()));
+        }
+
+        self.constrain_var_to_type(target.clone(), ValueType::Keyword);
+        self.type_of_bindings.insert(target, var);
+        Ok(())
+    }
+}
@@ -25,6 +25,10 @@ use errors::{
     Result,
 };
 
+use types::{
+    ArithmeticOperator,
+};
+
 /// Application of `where` functions.
 impl ConjoiningClauses {
     /// There are several kinds of functions binding variables in our Datalog:
@@ -38,8 +42,17 @@ impl ConjoiningClauses {
         // ultimately allowing user-specified functions, we match on the function name first.
         match where_fn.operator.0.as_str() {
             "fulltext" => self.apply_fulltext(schema, where_fn),
+            "get-else" => self.apply_get_else(schema, where_fn),
+            "get-some" => self.apply_get_some(schema, where_fn),
             "ground" => self.apply_ground(schema, where_fn),
-            _ => bail!(ErrorKind::UnknownFunction(where_fn.operator.clone())),
+            "plus-instant" => self.apply_plus_instant(where_fn),
+            "str" => self.apply_str(where_fn),
+            "truncate-instant" => self.apply_truncate_instant(where_fn),
+            "type-of" => self.apply_type_of(where_fn),
+            op => match ArithmeticOperator::from_datalog_operator(op) {
+                Some(operator) => self.apply_arithmetic(operator, where_fn),
+                None => bail!(ErrorKind::UnknownFunction(where_fn.operator.clone())),
+            },
         }
     }
 }
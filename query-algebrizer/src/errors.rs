@@ -12,9 +12,11 @@ extern crate mentat_query;
 
 use mentat_core::{
     ValueType,
+    ValueTypeSet,
 };
 
 use self::mentat_query::{
+    NamespacedKeyword,
     PlainSymbol,
 };
 
@@ -28,6 +30,9 @@ pub enum BindingError {
     /// than Datomic: we won't try to make sense of non-obvious (and potentially erroneous) bindings.
     ExpectedBindRel,
 
+    /// Expected `?x` but got some other type of binding.
+    ExpectedBindScalar,
+
     /// Expected `[?x1 … ?xN]` or `[[?x1 … ?xN]]` but got some other number of bindings.  Mentat is
     /// deliberately more strict than Datomic: we prefer placeholders to omission.
     InvalidNumberOfBindings { number: usize, expected: usize },
@@ -49,6 +54,21 @@ error_chain! {
             display("value of type {} provided for var {}, expected {}", provided, var, declared)
         }
 
+        InputTypeMismatch(var: PlainSymbol, expected: ValueTypeSet, provided: ValueType) {
+            description("input value can't take the type required by the query")
+            display("value of type {} provided for var {}, but the query requires one of {:?}", provided, var, expected)
+        }
+
+        InputRowLengthMismatch(expected: usize, provided: usize) {
+            description("input relation row has the wrong number of values")
+            display("input relation row has {} values, but the query binds {}", provided, expected)
+        }
+
+        UnknownInputVariable(var: PlainSymbol) {
+            description("input variable not declared in :in")
+            display("var {} is not declared in :in", var)
+        }
+
         UnknownFunction(name: PlainSymbol) {
             description("no such function")
             display("no function named {}", name)
@@ -74,10 +94,19 @@ error_chain! {
             display("mismatched bindings in ground")
         }
 
-        InvalidGroundConstant {
-            // TODO: flesh this out.
+        InvalidGroundConstant(arg: String) {
             description("invalid expression in ground constant")
-            display("invalid expression in ground constant")
+            display("invalid expression in ground constant: {}", arg)
+        }
+
+        UnknownGroundIdent(ident: NamespacedKeyword) {
+            description("unknown ident in ground collection")
+            display("unknown ident {} in ground collection", ident)
+        }
+
+        NonUniqueLookupRefAttribute(attribute: NamespacedKeyword) {
+            description("lookup ref attribute is not unique")
+            display("cannot use non-unique attribute {} in a lookup ref.", attribute)
         }
 
         InvalidArgument(function: PlainSymbol, expected_type: &'static str, position: usize) {
@@ -85,11 +114,86 @@ error_chain! {
             display("invalid argument to {}: expected {} in position {}.", function, expected_type, position)
         }
 
+        InvalidRegex(function: PlainSymbol, pattern: String, message: String) {
+            description("invalid regular expression")
+            display("invalid regular expression {:?} passed to {}: {}", pattern, function, message)
+        }
+
+        NonFulltextAttribute(attribute: String) {
+            description("fulltext search on a non-fulltext attribute")
+            display("cannot run fulltext search on non-fulltext attribute {}.", attribute)
+        }
+
         InvalidLimit(val: String, kind: ValueType) {
             description("invalid limit")
             display("invalid limit {} of type {}: expected natural number.", val, kind)
         }
 
+        InvalidOffset(val: String, kind: ValueType) {
+            description("invalid offset")
+            display("invalid offset {} of type {}: expected non-negative integer.", val, kind)
+        }
+
+        UnorderedCursorQuery {
+            description("pagination cursor for a query without :order")
+            display("a query must have an :order to be paged with a cursor")
+        }
+
+        NonIntegralFloat(var: PlainSymbol, value: f64) {
+            description("float argument for a long has a fractional part")
+            display("{} must be a long, but {} isn't a whole number", var, value)
+        }
+
+        InvalidCursorValue(var: PlainSymbol) {
+            description("pagination cursor has no usable value for an ordered variable")
+            display("the cursor has no value of the single known type of ordered variable {}", var)
+        }
+
+        TypeOfCanOnlyBeProjected(var: PlainSymbol) {
+            description("type-of binding used other than in projection")
+            display("the type-of binding {} can only be projected", var)
+        }
+
+        FallbackCanOnlyBeProjected(var: PlainSymbol) {
+            description("get-else, get-some, or tx-data value binding used other than in projection")
+            display("the get-else, get-some, or tx-data value binding {} can only be projected", var)
+        }
+
+        ComputedCanOnlyBeProjected(var: PlainSymbol) {
+            description("computed binding used other than in projection")
+            display("the computed binding {} can only be projected", var)
+        }
+
+        UntypedOperand(function: PlainSymbol, var: PlainSymbol) {
+            description("function operand without a single known type")
+            display("the type of {} must be known before it's given to {}", var, function)
+        }
+
+        InvalidGetElseDefault(attribute: String, value_type: ValueType) {
+            description("get-else default doesn't match the attribute's value type")
+            display("get-else default for {} must be of type {}", attribute, value_type)
+        }
+
+        IncompatibleFallbackAttributes(function: PlainSymbol, types: ValueTypeSet) {
+            description("attributes of different value types")
+            display("the attributes given to {} must all have the same value type, not {:?}", function, types)
+        }
+
+        UnknownRule(name: PlainSymbol) {
+            description("no such rule")
+            display("no rule named {}", name)
+        }
+
+        UnsupportedRecursiveRule(name: PlainSymbol) {
+            description("unsupported recursive rule")
+            display("rule {} is recursive, but not simply: it needs at least one definition that doesn't invoke it, and only one that does, once, outside any or or not", name)
+        }
+
+        UntypedRuleVariable(rule: PlainSymbol, var: PlainSymbol) {
+            description("recursive rule variable without a single known type")
+            display("variable {} of recursive rule {} must have the same single type in each definition", var, rule)
+        }
+
         NonMatchingVariablesInOrClause {
             // TODO: flesh out.
             description("non-matching variables in 'or' clause")
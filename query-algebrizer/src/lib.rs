@@ -17,17 +17,26 @@ extern crate maplit;
 
 extern crate mentat_core;
 extern crate mentat_query;
+extern crate regex;
 
-use std::collections::BTreeSet;
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
 use std::ops::Sub;
+use std::rc::Rc;
+use std::sync::Arc;
 
 mod errors;
 mod types;
 mod validate;
 mod clauses;
+mod rules;
 
 use mentat_core::{
+    CachedAttributes,
     Schema,
+    SQLValueTypeSet,
     TypedValue,
     ValueType,
 };
@@ -35,9 +44,12 @@ use mentat_core::{
 use mentat_core::counter::RcCounter;
 
 use mentat_query::{
+    Binding,
+    Direction,
     FindQuery,
     FindSpec,
     Limit,
+    Offset,
     Order,
     SrcVar,
     Variable,
@@ -52,12 +64,21 @@ pub use errors::{
 
 pub use clauses::{
     QueryInputs,
+    QueryInputsBuilder,
 };
 
 pub use types::{
     EmptyBecause,
 };
 
+use types::Inequality;
+
+pub use rules::{
+    DEFAULT_MAX_RULE_DEPTH,
+    RuleSet,
+    expand_rules,
+};
+
 #[derive(Debug)]
 pub struct AlgebraicQuery {
     default_source: SrcVar,
@@ -66,6 +87,7 @@ pub struct AlgebraicQuery {
     pub with: BTreeSet<Variable>,
     pub order: Option<Vec<OrderBy>>,
     pub limit: Limit,
+    pub offset: Offset,
     pub cc: clauses::ConjoiningClauses,
 }
 
@@ -75,6 +97,12 @@ impl AlgebraicQuery {
         self.cc.is_known_empty()
     }
 
+    /// Return true if the find spec includes any aggregates, like `(count ?x)`.
+    #[inline]
+    pub fn has_aggregates(&self) -> bool {
+        self.has_aggregates
+    }
+
     /// Return a set of the input variables mentioned in the `:in` clause that have not yet been
     /// bound. We do this by looking at the CC.
     pub fn unbound_variables(&self) -> BTreeSet<Variable> {
@@ -113,8 +141,12 @@ fn validate_and_simplify_order(cc: &ConjoiningClauses, order: Option<Vec<Order>>
                     bail!(ErrorKind::UnboundVariable(var.name()));
                 }
 
-                // Otherwise, determine if we also need to order by type…
-                if cc.known_type(&var).is_none() {
+                // Otherwise, determine if we also need to order by type… SQLite compares values
+                // of different storage classes -- say, strings and instants -- in an order that
+                // has nothing to do with Mentat's types, so ordering by tag first keeps each type
+                // together. Numbers of different types share a tag and compare numerically, so
+                // they need no help.
+                if !cc.known_type_set(&var).has_unique_type_code() {
                     order_bys.push(OrderBy(direction.clone(), VariableColumn::VariableTypeTag(var.clone())));
                 }
                 order_bys.push(OrderBy(direction, VariableColumn::Variable(var.clone())));
@@ -127,7 +159,7 @@ fn validate_and_simplify_order(cc: &ConjoiningClauses, order: Option<Vec<Order>>
 }
 
 
-fn simplify_limit(mut query: AlgebraicQuery) -> Result<AlgebraicQuery> {
+fn simplify_limit_and_offset(mut query: AlgebraicQuery) -> Result<AlgebraicQuery> {
     // Unpack any limit variables in place.
     let refined_limit =
         match query.limit {
@@ -162,6 +194,35 @@ fn simplify_limit(mut query: AlgebraicQuery) -> Result<AlgebraicQuery> {
     if let Some(lim) = refined_limit {
         query.limit = lim;
     }
+
+    // Offsets work just the same, except that zero is allowed, and means no offset at all.
+    let refined_offset =
+        match query.offset {
+            Offset::Variable(ref v) => {
+                match query.cc.bound_value(v) {
+                    Some(TypedValue::Long(n)) => {
+                        if n < 0 {
+                            bail!(ErrorKind::InvalidOffset(n.to_string(), ValueType::Long));
+                        } else if n == 0 {
+                            Some(Offset::None)
+                        } else {
+                            Some(Offset::Fixed(n as u64))
+                        }
+                    },
+                    Some(val) => {
+                        bail!(ErrorKind::InvalidOffset(format!("{:?}", val), val.value_type()));
+                    },
+                    None => None,
+                }
+            },
+            Offset::Fixed(0) => Some(Offset::None),
+            Offset::None => None,
+            Offset::Fixed(_) => None,
+        };
+
+    if let Some(offset) = refined_offset {
+        query.offset = offset;
+    }
     Ok(query)
 }
 
@@ -169,40 +230,212 @@ pub fn algebrize_with_inputs(schema: &Schema,
                              parsed: FindQuery,
                              counter: usize,
                              inputs: QueryInputs) -> Result<AlgebraicQuery> {
+    algebrize_with_cache_and_inputs(schema, None, None, parsed, counter, inputs)
+}
+
+/// Like `algebrize_with_inputs`, but first expand the invocations of `rules` in `parsed`.
+/// Invocations of recursive rules become recursive CTEs.
+pub fn algebrize_with_rules(schema: &Schema,
+                            parsed: FindQuery,
+                            rules: &RuleSet,
+                            inputs: QueryInputs) -> Result<AlgebraicQuery> {
+    let expanded = expand_rules(rules.rules(), parsed)?;
+    algebrize_with_cache_and_inputs(schema, None, Some(Rc::new(rules.clone())), expanded, 0, inputs)
+}
+
+/// Like `algebrize_with_inputs`, but answer patterns from `cache` where possible. The result
+/// embeds values read from the cache, so it's only good for as long as the cache is.
+pub fn algebrize_with_cache(schema: &Schema,
+                            cache: Arc<CachedAttributes>,
+                            parsed: FindQuery,
+                            inputs: QueryInputs) -> Result<AlgebraicQuery> {
+    algebrize_with_cache_and_inputs(schema, Some(cache), None, parsed, 0, inputs)
+}
+
+fn algebrize_with_cache_and_inputs(schema: &Schema,
+                                   cache: Option<Arc<CachedAttributes>>,
+                                   rules: Option<Rc<RuleSet>>,
+                                   parsed: FindQuery,
+                                   counter: usize,
+                                   mut inputs: QueryInputs) -> Result<AlgebraicQuery> {
     let alias_counter = RcCounter::with_initial(counter);
+    let mut collections = ::std::mem::replace(&mut inputs.collections, BTreeMap::new());
+    let mut relations = ::std::mem::replace(&mut inputs.relations, BTreeMap::new());
     let mut cc = ConjoiningClauses::with_inputs_and_alias_counter(parsed.in_vars, inputs, alias_counter);
+    if let Some(cache) = cache {
+        cc = cc.with_attribute_cache(cache);
+    }
+    if let Some(rules) = rules {
+        cc = cc.with_rules(rules);
+    }
 
     // Do we have a variable limit? If so, tell the CC that the var must be numeric.
     if let &Limit::Variable(ref var) = &parsed.limit {
         cc.constrain_var_to_long(var.clone());
     }
 
+    // Same for a variable offset.
+    if let &Offset::Variable(ref var) = &parsed.offset {
+        cc.constrain_var_to_long(var.clone());
+    }
+
     // TODO: integrate default source into pattern processing.
     // TODO: flesh out the rest of find-into-context.
     let where_clauses = parsed.where_clauses;
-    for where_clause in where_clauses {
+    cc.derive_types_from_patterns(schema, &where_clauses);
+
+    // Bind each collection and relation input, now that we know the types its variables can take.
+    for binding in parsed.in_bindings {
+        match binding {
+            Binding::BindColl(var) => {
+                let values = match collections.remove(&var) {
+                    Some(values) => values,
+                    None => bail!(ErrorKind::UnboundVariable(var.name())),
+                };
+                cc.apply_input_collection(schema, var, values)?;
+            },
+            Binding::BindRel(places) => {
+                let names: Vec<Variable> = places.iter().filter_map(|p| p.var().cloned()).collect();
+                let rows = match relations.remove(&names) {
+                    Some(rows) => rows,
+                    None => bail!(ErrorKind::UnboundVariable(names[0].name())),
+                };
+                cc.apply_input_relation(schema, places, rows)?;
+            },
+            // The parser only accepts collection and relation bindings in `:in`.
+            _ => unreachable!(),
+        }
+    }
+
+    for where_clause in cc.order_patterns_by_selectivity(schema, where_clauses) {
         cc.apply_clause(schema, where_clause)?;
     }
     cc.expand_column_bindings();
     cc.prune_extracted_types();
+    cc.fold_ranges();
 
     let (order, extra_vars) = validate_and_simplify_order(&cc, parsed.order)?;
     let with: BTreeSet<Variable> = parsed.with.into_iter().chain(extra_vars.into_iter()).collect();
 
+    // The projector computes `type-of` bindings, so nothing else can refer to them.
+    for var in cc.type_of_bindings.keys() {
+        if cc.column_bindings.contains_key(var) || cc.bound_value(var).is_some() || with.contains(var) {
+            bail!(ErrorKind::TypeOfCanOnlyBeProjected(var.name()));
+        }
+    }
+
+    // Nor to `get-else`, `get-some`, and `tx-data` value bindings, which only exist as projected
+    // expressions.
+    for var in cc.fallback_bindings.keys() {
+        if cc.column_bindings.contains_key(var) || cc.bound_value(var).is_some() {
+            bail!(ErrorKind::FallbackCanOnlyBeProjected(var.name()));
+        }
+    }
+
+    // The same goes for the values of computing functions like `str`.
+    for var in cc.computed_bindings.keys() {
+        if cc.column_bindings.contains_key(var) || cc.bound_value(var).is_some() {
+            bail!(ErrorKind::ComputedCanOnlyBeProjected(var.name()));
+        }
+    }
+
     // This might leave us with an unused `:in` variable.
     let limit = if parsed.find_spec.is_unit_limited() { Limit::Fixed(1) } else { parsed.limit };
+    let has_aggregates = parsed.find_spec.has_aggregates();
     let q = AlgebraicQuery {
         default_source: parsed.default_source,
         find_spec: parsed.find_spec,
-        has_aggregates: false,           // TODO: we don't parse them yet.
+        has_aggregates: has_aggregates,
         with: with,
         order: order,
         limit: limit,
+        offset: parsed.offset,
         cc: cc,
     };
 
     // Substitute in any fixed values and fail if they're out of range.
-    simplify_limit(q)
+    simplify_limit_and_offset(q)
+}
+
+/// Algebrize `parsed`, leaving the `:in` variables that have a type in `inputs` but no value to be
+/// bound when the query is run. Each of those variables must be bound to a column by the query;
+/// we constrain the column to equal a parameter named for the variable, so the SQL generated from
+/// the result can be reused, rebinding only the parameters.
+pub fn algebrize_with_parameters(schema: &Schema,
+                                 parsed: FindQuery,
+                                 inputs: QueryInputs) -> Result<AlgebraicQuery> {
+    let mut q = algebrize_with_inputs(schema, parsed, 0, inputs)?;
+    if q.is_known_empty() {
+        return Ok(q);
+    }
+
+    for var in q.unbound_variables() {
+        // An unbound limit or offset is already a parameter.
+        if q.limit == Limit::Variable(var.clone()) || q.offset == Offset::Variable(var.clone()) {
+            continue;
+        }
+
+        let column = match q.cc.column_bindings.get(&var) {
+            Some(columns) => columns[0].clone(),
+            None => bail!(ErrorKind::UnboundVariable(var.name())),
+        };
+        q.cc.wheres.add_intersection(ColumnConstraint::Equals(column, QueryValue::Parameter(var)));
+    }
+    Ok(q)
+}
+
+/// Keep only the rows of `query` that follow, in its `:order`, a row in which each ordered
+/// variable has the value given in `after`: with `:order ?a (desc ?b)`, the rows in which
+/// `?a > a`, or `?a = a` and `?b < b`. This is keyset pagination. Unlike `:offset`, it doesn't
+/// make the store read, and discard, the rows of every previous page.
+///
+/// Each ordered variable that isn't fixed by the query must have a single known type and a value
+/// of that type in `after`.
+pub fn constrain_after(query: &mut AlgebraicQuery, after: &BTreeMap<Variable, TypedValue>) -> Result<()> {
+    if query.is_known_empty() {
+        return Ok(());
+    }
+
+    let mut keys: Vec<(Inequality, QualifiedAlias, TypedValue)> = vec![];
+    match query.order {
+        None => bail!(ErrorKind::UnorderedCursorQuery),
+        Some(ref order) => {
+            for &OrderBy(ref direction, ref column) in order.iter() {
+                let var = match column {
+                    &VariableColumn::Variable(ref var) => var,
+                    // We only order by type tag when a variable can take more than one type.
+                    &VariableColumn::VariableTypeTag(ref var) => bail!(ErrorKind::InvalidCursorValue(var.name())),
+                };
+                let value = match after.get(var) {
+                    Some(value) if query.cc.known_type(var) == Some(value.value_type()) => value.clone(),
+                    _ => bail!(ErrorKind::InvalidCursorValue(var.name())),
+                };
+                // `validate_and_simplify_order` checked that each ordered variable is bound.
+                let column = query.cc.column_bindings[var][0].clone();
+                let operator = match direction {
+                    &Direction::Ascending => Inequality::GreaterThan,
+                    &Direction::Descending => Inequality::LessThan,
+                };
+                keys.push((operator, column, value));
+            }
+        },
+    }
+
+    let mut alternation = ColumnAlternation::default();
+    for (i, &(operator, ref column, ref value)) in keys.iter().enumerate() {
+        let mut intersection = ColumnIntersection::default();
+        for &(_, ref earlier, ref earlier_value) in keys[..i].iter() {
+            intersection.add_intersection(ColumnConstraint::Equals(earlier.clone(), QueryValue::TypedValue(earlier_value.clone())));
+        }
+        intersection.add_intersection(ColumnConstraint::Inequality {
+            operator: operator,
+            left: QueryValue::Column(column.clone()),
+            right: QueryValue::TypedValue(value.clone()),
+        });
+        alternation.add_alternate(intersection);
+    }
+    query.cc.wheres.add(ColumnConstraintOrAlternation::Alternation(alternation));
+    Ok(())
 }
 
 pub use clauses::{
@@ -210,6 +443,7 @@ pub use clauses::{
 };
 
 pub use types::{
+    ArithmeticOperator,
     Column,
     ColumnAlternation,
     ColumnConstraint,
@@ -217,10 +451,15 @@ pub use types::{
     ColumnIntersection,
     ColumnName,
     ComputedTable,
+    Computation,
     DatomsColumn,
     DatomsTable,
+    Fallback,
     FulltextColumn,
+    InstantUnit,
+    Operand,
     OrderBy,
+    OuterJoin,
     QualifiedAlias,
     QueryValue,
     SourceAlias,
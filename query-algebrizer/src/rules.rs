@@ -0,0 +1,469 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Rules are expanded away before we algebrize a query. Each invocation, like `(ancestor ?a ?b)`,
+//! is replaced by the body of the rule it names: the rule's own variables become the invocation's
+//! arguments, and every other variable in the body is given a fresh name, so that it can't
+//! capture a variable of the query. A rule with several definitions becomes an `or-join` on the
+//! invocation's arguments, with one arm per definition.
+//!
+//! A rule that invokes itself can't be expanded like this. We leave its invocations in place, and
+//! the algebrizer turns each into a recursive CTE: see `prepare_recursive_rule`.
+
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
+
+use mentat_query::{
+    Binding,
+    ContainsVariables,
+    FindQuery,
+    FnArg,
+    NotJoin,
+    OrJoin,
+    OrWhereClause,
+    Pattern,
+    PatternNonValuePlace,
+    PatternValuePlace,
+    PlainSymbol,
+    Predicate,
+    Rule,
+    RuleExpr,
+    UnifyVars,
+    Variable,
+    VariableOrPlaceholder,
+    WhereClause,
+    WhereFn,
+};
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+
+/// How many steps a recursive rule may take from its anchor, unless a `RuleSet` says otherwise.
+pub const DEFAULT_MAX_RULE_DEPTH: usize = 100;
+
+/// The rules a query can invoke. A recursive rule derives rows until it derives no more; if the
+/// data has a cycle, that would be forever, so a query fails if a rule derives a row more than
+/// `max_depth` steps from one of its anchors.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RuleSet {
+    rules: Vec<Rule>,
+    max_depth: usize,
+}
+
+impl RuleSet {
+    pub fn new(rules: Vec<Rule>) -> RuleSet {
+        RuleSet {
+            rules: rules,
+            max_depth: DEFAULT_MAX_RULE_DEPTH,
+        }
+    }
+
+    pub fn with_max_depth(self, max_depth: usize) -> RuleSet {
+        RuleSet {
+            max_depth: max_depth,
+            ..self
+        }
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    pub fn max_depth(&self) -> usize {
+        self.max_depth
+    }
+}
+
+impl From<Vec<Rule>> for RuleSet {
+    fn from(rules: Vec<Rule>) -> RuleSet {
+        RuleSet::new(rules)
+    }
+}
+
+type Renames = BTreeMap<Variable, Variable>;
+
+struct Expander<'a> {
+    rules: &'a [Rule],
+
+    /// The variables we mustn't introduce: those of the query, and those we've already made up.
+    taken: BTreeSet<Variable>,
+    counter: usize,
+
+    /// The rules we're in the middle of expanding.
+    expanding: Vec<PlainSymbol>,
+}
+
+impl<'a> Expander<'a> {
+    fn fresh(&mut self, rule: &PlainSymbol, var: &Variable) -> Variable {
+        loop {
+            self.counter += 1;
+            let name = format!("{}__{}{}", var.as_str(), rule.plain_name(), self.counter);
+            let var = Variable::from_valid_name(name.as_str());
+            if self.taken.insert(var.clone()) {
+                return var;
+            }
+        }
+    }
+
+    fn expand_clauses(&mut self, clauses: Vec<WhereClause>) -> Result<Vec<WhereClause>> {
+        let mut expanded = Vec::with_capacity(clauses.len());
+        for clause in clauses {
+            match clause {
+                WhereClause::RuleExpr(invocation) => {
+                    expanded.extend(self.expand_invocation(invocation)?);
+                },
+                WhereClause::OrJoin(or_join) => {
+                    let (clauses, unify_vars, _) = or_join.dismember();
+                    let mut arms = Vec::with_capacity(clauses.len());
+                    for clause in clauses {
+                        let clauses = match clause {
+                            OrWhereClause::Clause(clause) => vec![clause],
+                            OrWhereClause::And(clauses) => clauses,
+                        };
+                        let mut clauses = self.expand_clauses(clauses)?;
+                        arms.push(if clauses.len() == 1 {
+                            OrWhereClause::Clause(clauses.pop().unwrap())
+                        } else {
+                            OrWhereClause::And(clauses)
+                        });
+                    }
+                    expanded.push(WhereClause::OrJoin(OrJoin::new(unify_vars, arms)));
+                },
+                WhereClause::NotJoin(not_join) => {
+                    expanded.push(WhereClause::NotJoin(NotJoin {
+                        unify_vars: not_join.unify_vars,
+                        clauses: self.expand_clauses(not_join.clauses)?,
+                    }));
+                },
+                clause => expanded.push(clause),
+            }
+        }
+        Ok(expanded)
+    }
+
+    fn expand_invocation(&mut self, invocation: RuleExpr) -> Result<Vec<WhereClause>> {
+        if self.expanding.contains(&invocation.name) {
+            bail!(ErrorKind::UnsupportedRecursiveRule(invocation.name));
+        }
+        let (name, vars) = invocation_vars(invocation)?;
+
+        let rules = self.rules;
+        let definitions: Vec<&Rule> = rules.iter().filter(|rule| rule.name == name).collect();
+        let expected = match definitions.first() {
+            None => bail!(ErrorKind::UnknownRule(name)),
+            Some(rule) => rule.vars.len(),
+        };
+        if definitions.iter().any(|rule| rule.vars.len() != vars.len()) {
+            bail!(ErrorKind::InvalidNumberOfArguments(name, vars.len(), expected));
+        }
+
+        // The algebrizer handles this once it knows the types of the arguments.
+        if split_recursive_rule(rules, &name)?.is_some() {
+            let args = vars.into_iter().map(FnArg::Variable).collect();
+            return Ok(vec![WhereClause::RuleExpr(RuleExpr { name: name, args: args })]);
+        }
+
+        self.expanding.push(name.clone());
+        let mut bodies = Vec::with_capacity(definitions.len());
+        for rule in definitions {
+            let body = self.instantiate(rule, &vars);
+            bodies.push(self.expand_clauses(body)?);
+        }
+        self.expanding.pop();
+
+        if bodies.len() == 1 {
+            return Ok(bodies.pop().unwrap());
+        }
+        let unify_vars = UnifyVars::Explicit(vars.into_iter().collect());
+        let arms = bodies.into_iter().map(OrWhereClause::And).collect();
+        Ok(vec![WhereClause::OrJoin(OrJoin::new(unify_vars, arms))])
+    }
+
+    /// The body of `rule`, with its variables renamed to `args`, and the rest made fresh.
+    fn instantiate(&mut self, rule: &Rule, args: &[Variable]) -> Vec<WhereClause> {
+        let mut renames: Renames = rule.vars.iter().cloned().zip(args.iter().cloned()).collect();
+        let mut locals = BTreeSet::new();
+        for clause in rule.clauses.iter() {
+            clause.accumulate_mentioned_variables(&mut locals);
+        }
+        for var in locals {
+            if !renames.contains_key(&var) {
+                let fresh = self.fresh(&rule.name, &var);
+                renames.insert(var, fresh);
+            }
+        }
+        rule.clauses.iter().map(|clause| rename_clause(clause, &renames)).collect()
+    }
+}
+
+fn invocation_vars(invocation: RuleExpr) -> Result<(PlainSymbol, Vec<Variable>)> {
+    let RuleExpr { name, args } = invocation;
+    let mut vars = Vec::with_capacity(args.len());
+    for (i, arg) in args.into_iter().enumerate() {
+        match arg {
+            FnArg::Variable(var) => vars.push(var),
+            _ => bail!(ErrorKind::InvalidArgument(name, "variable", i)),
+        }
+    }
+    Ok((name, vars))
+}
+
+fn invokes(clauses: &[WhereClause], name: &PlainSymbol) -> bool {
+    clauses.iter().any(|clause| clause_invokes(clause, name))
+}
+
+/// Does `clause` invoke the rule `name`, at any depth?
+fn clause_invokes(clause: &WhereClause, name: &PlainSymbol) -> bool {
+    match clause {
+        &WhereClause::RuleExpr(ref invocation) => invocation.name == *name,
+        &WhereClause::OrJoin(ref or_join) => {
+            or_join.clauses.iter().any(|arm| match arm {
+                &OrWhereClause::Clause(ref clause) => clause_invokes(clause, name),
+                &OrWhereClause::And(ref clauses) => invokes(clauses, name),
+            })
+        },
+        &WhereClause::NotJoin(ref not_join) => invokes(&not_join.clauses, name),
+        _ => false,
+    }
+}
+
+/// If the rule `name` invokes itself, split its definitions into those that don't, which are the
+/// anchor, and the one that does, which is the recursive step. That's the only shape of recursion
+/// we support: the step must invoke the rule exactly once, and not from within `or` or `not`.
+fn split_recursive_rule<'a>(rules: &'a [Rule], name: &PlainSymbol) -> Result<Option<(Vec<&'a Rule>, &'a Rule)>> {
+    let (mut steps, anchors): (Vec<&Rule>, Vec<&Rule>) =
+        rules.iter()
+             .filter(|rule| rule.name == *name)
+             .partition(|rule| invokes(&rule.clauses, name));
+    if steps.is_empty() {
+        return Ok(None);
+    }
+    if anchors.is_empty() || steps.len() > 1 {
+        bail!(ErrorKind::UnsupportedRecursiveRule(name.clone()));
+    }
+
+    let step = steps.pop().unwrap();
+    let mut invocations = 0;
+    for clause in step.clauses.iter() {
+        match clause {
+            &WhereClause::RuleExpr(ref invocation) if invocation.name == *name => {
+                if invocation.args.len() != step.vars.len() {
+                    bail!(ErrorKind::InvalidNumberOfArguments(name.clone(), invocation.args.len(), step.vars.len()));
+                }
+                invocations += 1;
+            },
+            clause if clause_invokes(clause, name) => {
+                bail!(ErrorKind::UnsupportedRecursiveRule(name.clone()));
+            },
+            _ => {},
+        }
+    }
+    if invocations != 1 || step.vars.is_empty() {
+        bail!(ErrorKind::UnsupportedRecursiveRule(name.clone()));
+    }
+    Ok(Some((anchors, step)))
+}
+
+/// A recursive rule, ready to become a recursive CTE. Each definition has been renamed to use
+/// `vars`, and any non-recursive rules that it invokes have been expanded.
+pub struct RecursiveRule {
+    pub vars: Vec<Variable>,
+    pub anchors: Vec<Vec<WhereClause>>,
+
+    /// The clauses of the step, other than its invocation of the rule...
+    pub step: Vec<WhereClause>,
+
+    /// ... and the arguments of that invocation.
+    pub step_args: Vec<Variable>,
+}
+
+pub fn prepare_recursive_rule(rules: &[Rule], name: &PlainSymbol) -> Result<RecursiveRule> {
+    let (anchors, step) = match split_recursive_rule(rules, name)? {
+        Some(split) => split,
+        None => bail!(ErrorKind::UnknownRule(name.clone())),
+    };
+
+    // The member queries of the CTE each have their own scope, so only the rule's own variables
+    // need to be kept apart.
+    let vars = step.vars.clone();
+    let mut taken: BTreeSet<Variable> = vars.iter().cloned().collect();
+    for rule in anchors.iter().chain(::std::iter::once(&step)) {
+        for clause in rule.clauses.iter() {
+            clause.accumulate_mentioned_variables(&mut taken);
+        }
+    }
+    let mut expander = Expander {
+        rules: rules,
+        taken: taken,
+        counter: 0,
+        expanding: vec![name.clone()],
+    };
+
+    let mut bodies = Vec::with_capacity(anchors.len());
+    for rule in anchors {
+        let body = expander.instantiate(rule, &vars);
+        bodies.push(expander.expand_clauses(body)?);
+    }
+
+    let mut step_args = vec![];
+    let mut clauses = Vec::with_capacity(step.clauses.len());
+    for clause in expander.instantiate(step, &vars) {
+        match clause {
+            WhereClause::RuleExpr(invocation) => {
+                if invocation.name == *name {
+                    step_args = invocation_vars(invocation)?.1;
+                } else {
+                    clauses.push(WhereClause::RuleExpr(invocation));
+                }
+            },
+            clause => clauses.push(clause),
+        }
+    }
+
+    Ok(RecursiveRule {
+        vars: vars,
+        anchors: bodies,
+        step: expander.expand_clauses(clauses)?,
+        step_args: step_args,
+    })
+}
+
+fn rename_var(var: &Variable, renames: &Renames) -> Variable {
+    renames.get(var).cloned().unwrap_or_else(|| var.clone())
+}
+
+fn rename_arg(arg: &FnArg, renames: &Renames) -> FnArg {
+    match arg {
+        &FnArg::Variable(ref var) => FnArg::Variable(rename_var(var, renames)),
+        &FnArg::Vector(ref args) => FnArg::Vector(rename_args(args, renames)),
+        &FnArg::Set(ref args) => FnArg::Set(rename_args(args, renames)),
+        &FnArg::Map(ref entries) => {
+            FnArg::Map(entries.iter().map(|&(ref k, ref v)| (rename_arg(k, renames), rename_arg(v, renames))).collect())
+        },
+        arg => arg.clone(),
+    }
+}
+
+fn rename_args(args: &[FnArg], renames: &Renames) -> Vec<FnArg> {
+    args.iter().map(|arg| rename_arg(arg, renames)).collect()
+}
+
+fn rename_non_value_place(place: &PatternNonValuePlace, renames: &Renames) -> PatternNonValuePlace {
+    match place {
+        &PatternNonValuePlace::Variable(ref var) => PatternNonValuePlace::Variable(rename_var(var, renames)),
+        place => place.clone(),
+    }
+}
+
+fn rename_variable_or_placeholder(place: &VariableOrPlaceholder, renames: &Renames) -> VariableOrPlaceholder {
+    match place {
+        &VariableOrPlaceholder::Variable(ref var) => VariableOrPlaceholder::Variable(rename_var(var, renames)),
+        &VariableOrPlaceholder::Placeholder => VariableOrPlaceholder::Placeholder,
+    }
+}
+
+fn rename_binding(binding: &Binding, renames: &Renames) -> Binding {
+    match binding {
+        &Binding::BindScalar(ref var) => Binding::BindScalar(rename_var(var, renames)),
+        &Binding::BindColl(ref var) => Binding::BindColl(rename_var(var, renames)),
+        &Binding::BindRel(ref vars) => Binding::BindRel(vars.iter().map(|v| rename_variable_or_placeholder(v, renames)).collect()),
+        &Binding::BindTuple(ref vars) => Binding::BindTuple(vars.iter().map(|v| rename_variable_or_placeholder(v, renames)).collect()),
+    }
+}
+
+fn rename_unify_vars(unify_vars: &UnifyVars, renames: &Renames) -> UnifyVars {
+    match unify_vars {
+        &UnifyVars::Implicit => UnifyVars::Implicit,
+        &UnifyVars::Explicit(ref vars) => UnifyVars::Explicit(vars.iter().map(|v| rename_var(v, renames)).collect()),
+    }
+}
+
+fn rename_clauses(clauses: &[WhereClause], renames: &Renames) -> Vec<WhereClause> {
+    clauses.iter().map(|clause| rename_clause(clause, renames)).collect()
+}
+
+fn rename_clause(clause: &WhereClause, renames: &Renames) -> WhereClause {
+    match clause {
+        &WhereClause::Pattern(ref pattern) => {
+            let value = match pattern.value {
+                PatternValuePlace::Variable(ref var) => PatternValuePlace::Variable(rename_var(var, renames)),
+                ref place => place.clone(),
+            };
+            WhereClause::Pattern(Pattern {
+                source: pattern.source.clone(),
+                entity: rename_non_value_place(&pattern.entity, renames),
+                attribute: rename_non_value_place(&pattern.attribute, renames),
+                value: value,
+                tx: rename_non_value_place(&pattern.tx, renames),
+            })
+        },
+        &WhereClause::Pred(ref predicate) => {
+            WhereClause::Pred(Predicate {
+                operator: predicate.operator.clone(),
+                args: rename_args(&predicate.args, renames),
+            })
+        },
+        &WhereClause::WhereFn(ref function) => {
+            WhereClause::WhereFn(WhereFn {
+                operator: function.operator.clone(),
+                args: rename_args(&function.args, renames),
+                binding: rename_binding(&function.binding, renames),
+            })
+        },
+        &WhereClause::OrJoin(ref or_join) => {
+            let arms = or_join.clauses.iter().map(|arm| match arm {
+                &OrWhereClause::Clause(ref clause) => OrWhereClause::Clause(rename_clause(clause, renames)),
+                &OrWhereClause::And(ref clauses) => OrWhereClause::And(rename_clauses(clauses, renames)),
+            }).collect();
+            WhereClause::OrJoin(OrJoin::new(rename_unify_vars(&or_join.unify_vars, renames), arms))
+        },
+        &WhereClause::NotJoin(ref not_join) => {
+            WhereClause::NotJoin(NotJoin {
+                unify_vars: rename_unify_vars(&not_join.unify_vars, renames),
+                clauses: rename_clauses(&not_join.clauses, renames),
+            })
+        },
+        &WhereClause::RuleExpr(ref invocation) => {
+            WhereClause::RuleExpr(RuleExpr {
+                name: invocation.name.clone(),
+                args: rename_args(&invocation.args, renames),
+            })
+        },
+    }
+}
+
+/// Replace each rule invocation in the `:where` clauses of `query` with the body of the rule it
+/// names in `rules`. Only variables can be passed to a rule. Invocations of a recursive rule are
+/// left in place; other than a rule invoking itself, rules can't invoke each other in a cycle.
+pub fn expand_rules(rules: &[Rule], mut query: FindQuery) -> Result<FindQuery> {
+    let mut taken = BTreeSet::new();
+    for clause in query.where_clauses.iter() {
+        clause.accumulate_mentioned_variables(&mut taken);
+    }
+    taken.extend(query.in_vars.iter().cloned());
+    for binding in query.in_bindings.iter() {
+        taken.extend(binding.variables().into_iter().filter_map(|v| v));
+    }
+    taken.extend(query.with.iter().cloned());
+
+    let mut expander = Expander {
+        rules: rules,
+        taken: taken,
+        counter: 0,
+        expanding: vec![],
+    };
+    let clauses = ::std::mem::replace(&mut query.where_clauses, vec![]);
+    query.where_clauses = expander.expand_clauses(clauses)?;
+    Ok(query)
+}
@@ -26,6 +26,7 @@ use mentat_query::{
     Direction,
     NamespacedKeyword,
     Order,
+    PlainSymbol,
     Variable,
 };
 
@@ -37,6 +38,8 @@ pub enum DatomsTable {
     FulltextValues,     // The virtual table mapping IDs to strings.
     FulltextDatoms,     // The fulltext-datoms view.
     AllDatoms,          // Fulltext and non-fulltext datoms.
+    Transactions,       // The transaction log.
+    Rule,               // The rows derived so far by the recursive rule enclosing this reference.
     Computed(usize),    // A computed table, tracked elsewhere in the query.
 }
 
@@ -53,6 +56,19 @@ pub enum ComputedTable {
         names: Vec<Variable>,
         values: Vec<TypedValue>,
     },
+
+    /// The rows derived by a recursive rule: those of each anchor, and then those of the step,
+    /// repeatedly, joined against `derived`, the rows derived so far. `None` if the step can't
+    /// derive anything. Each member projects `vars`, and the step fails if it would derive a row
+    /// more than `max_depth` steps from an anchor.
+    RecursiveRule {
+        name: PlainSymbol,
+        vars: Vec<Variable>,
+        anchors: Vec<::clauses::ConjoiningClauses>,
+        step: Option<Box<::clauses::ConjoiningClauses>>,
+        derived: TableAlias,
+        max_depth: usize,
+    },
 }
 
 impl DatomsTable {
@@ -62,6 +78,8 @@ impl DatomsTable {
             DatomsTable::FulltextValues => "fulltext_values",
             DatomsTable::FulltextDatoms => "fulltext_datoms",
             DatomsTable::AllDatoms => "all_datoms",
+            DatomsTable::Transactions => "transactions",
+            DatomsTable::Rule => "rule",
             DatomsTable::Computed(_) => "c",
         }
     }
@@ -79,6 +97,9 @@ pub enum DatomsColumn {
     Value,
     Tx,
     ValueTypeTag,
+
+    /// Only present in the transactions table.
+    Added,
 }
 
 /// One of the named columns of our fulltext values table.
@@ -86,6 +107,10 @@ pub enum DatomsColumn {
 pub enum FulltextColumn {
     Rowid,
     Text,
+
+    /// Not a real column: a relevance score computed by SQLite for the current match.
+    /// Higher is better.
+    Score,
 }
 
 #[derive(PartialEq, Eq, Clone)]
@@ -122,6 +147,7 @@ impl DatomsColumn {
             Value => "v",
             Tx => "tx",
             ValueTypeTag => "value_type_tag",
+            Added => "added",
         }
     }
 }
@@ -173,6 +199,7 @@ impl FulltextColumn {
         match *self {
             Rowid => "rowid",
             Text => "text",
+            Score => "score",
         }
     }
 }
@@ -202,6 +229,128 @@ impl Debug for SourceAlias {
     }
 }
 
+/// A table that is `LEFT JOIN`ed to the rest of the query using the `on` constraints. Its columns
+/// are NULL in any row for which nothing matches.
+#[derive(PartialEq, Eq, Debug)]
+pub struct OuterJoin {
+    pub table: SourceAlias,
+    pub on: ColumnIntersection,
+}
+
+/// The value of a variable bound by `get-else` or `get-some`: the first of `columns` that isn't
+/// NULL, or `default` if they all are.
+#[derive(PartialEq, Eq, Debug)]
+pub struct Fallback {
+    pub columns: Vec<QualifiedAlias>,
+    pub default: Option<TypedValue>,
+}
+
+/// A value that the projector computes from other values in the same row, like that of
+/// `[(str ?first " " ?last) ?name]`.
+#[derive(PartialEq, Eq, Debug)]
+pub enum Computation {
+    /// The concatenation of the string forms of each operand.
+    Str(Vec<Operand>),
+
+    /// The result of applying `operator` to two numbers.
+    Arithmetic {
+        operator: ArithmeticOperator,
+        left: Operand,
+        right: Operand,
+    },
+
+    /// An instant plus a long number of milliseconds.
+    PlusInstant {
+        instant: Operand,
+        millis: Operand,
+    },
+
+    /// An instant truncated to the start of its `unit`, in UTC.
+    TruncateInstant {
+        instant: Operand,
+        unit: InstantUnit,
+    },
+}
+
+/// The units to which `truncate-instant` can truncate.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum InstantUnit {
+    Minute,
+    Hour,
+    Day,
+    Month,
+}
+
+impl InstantUnit {
+    pub fn from_name(name: &str) -> Option<InstantUnit> {
+        match name {
+            "minute" => Some(InstantUnit::Minute),
+            "hour"   => Some(InstantUnit::Hour),
+            "day"    => Some(InstantUnit::Day),
+            "month"  => Some(InstantUnit::Month),
+            _        => None,
+        }
+    }
+
+    /// The `strftime` format of the date and time at the start of the unit that contains an
+    /// instant, which SQLite can read back as a date and time.
+    pub fn to_strftime_format(self) -> &'static str {
+        use self::InstantUnit::*;
+        match self {
+            Minute => "%Y-%m-%d %H:%M:00",
+            Hour   => "%Y-%m-%d %H:00:00",
+            Day    => "%Y-%m-%d",
+            Month  => "%Y-%m-01",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ArithmeticOperator {
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+}
+
+impl ArithmeticOperator {
+    pub fn to_sql_operator(self) -> &'static str {
+        use self::ArithmeticOperator::*;
+        match self {
+            Add      => "+",
+            Subtract => "-",
+            Multiply => "*",
+            Divide   => "/",
+        }
+    }
+
+    pub fn from_datalog_operator(s: &str) -> Option<ArithmeticOperator> {
+        match s {
+            "+" => Some(ArithmeticOperator::Add),
+            "-" => Some(ArithmeticOperator::Subtract),
+            "*" => Some(ArithmeticOperator::Multiply),
+            "/" => Some(ArithmeticOperator::Divide),
+            _   => None,
+        }
+    }
+}
+
+/// An input to a `Computation`: either a column holding values of a known type, or a constant.
+#[derive(PartialEq, Eq, Debug)]
+pub enum Operand {
+    Column(QualifiedAlias, ValueType),
+    Value(TypedValue),
+}
+
+impl Operand {
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            &Operand::Column(_, t) => t,
+            &Operand::Value(ref v) => v.value_type(),
+        }
+    }
+}
+
 /// A particular column of a particular aliased table. E.g., "datoms123", Attribute.
 #[derive(PartialEq, Eq, Clone)]
 pub struct QualifiedAlias(pub TableAlias, pub Column);
@@ -234,6 +383,10 @@ pub enum QueryValue {
     // cannot be a boolean, so `datoms00.value_type_tag` must be in the set `#{0, 4, 5}`.
     // Note that `5 = 5.0` in SQLite, and we preserve that here.
     PrimitiveLong(i64),
+
+    // An input variable whose value isn't known until the query is run. It's rendered as a bind
+    // parameter, so the same SQL can be run with different inputs.
+    Parameter(Variable),
 }
 
 impl Debug for QueryValue {
@@ -252,6 +405,9 @@ impl Debug for QueryValue {
             &PrimitiveLong(value) => {
                 write!(f, "primitive({:?})", value)
             },
+            &Parameter(ref var) => {
+                write!(f, "parameter({:?})", var)
+            },
 
         }
     }
@@ -337,6 +493,27 @@ pub enum ColumnConstraint {
     HasType(TableAlias, ValueType),
     NotExists(ComputedTable),
     Matches(QualifiedAlias, QueryValue),
+    NotNull(QualifiedAlias),
+
+    /// The string in the column matches a SQL `LIKE` pattern, written with `escape`, if given.
+    Like {
+        column: QualifiedAlias,
+        pattern: String,
+        escape: Option<char>,
+        case_insensitive: bool,
+    },
+
+    /// The string in the column has a match for a regular expression.
+    Regex(QualifiedAlias, String),
+
+    /// The column lies between `lower` and `upper`, each of which it may equal if it's inclusive.
+    Range {
+        column: QualifiedAlias,
+        lower: QueryValue,
+        lower_inclusive: bool,
+        upper: QueryValue,
+        upper_inclusive: bool,
+    },
 }
 
 #[derive(PartialEq, Eq, Debug)]
@@ -457,6 +634,25 @@ impl Debug for ColumnConstraint {
             &NotExists(ref ct) => {
                 write!(f, "NOT EXISTS {:?}", ct)
             },
+            &NotNull(ref qa) => {
+                write!(f, "{:?} IS NOT NULL", qa)
+            },
+            &Like { ref column, ref pattern, escape, case_insensitive } => {
+                write!(f, "{:?} {} {:?}", column, if case_insensitive { "LIKE-CI" } else { "LIKE" }, pattern)?;
+                match escape {
+                    Some(e) => write!(f, " ESCAPE {:?}", e),
+                    None => Ok(()),
+                }
+            },
+            &Regex(ref qa, ref pattern) => {
+                write!(f, "{:?} REGEXP {:?}", qa, pattern)
+            },
+            &Range { ref column, ref lower, lower_inclusive, ref upper, upper_inclusive } => {
+                write!(f, "{:?} {} {:?} {} {:?}",
+                       lower, if lower_inclusive { "<=" } else { "<" },
+                       column,
+                       if upper_inclusive { "<=" } else { "<" }, upper)
+            },
         }
     }
 }
@@ -471,15 +667,23 @@ pub enum EmptyBecause {
     // The same, but for non-variables.
     KnownTypeMismatch { left: ValueTypeSet, right: ValueTypeSet },
     NoValidTypes(Variable),
+    UnboundVariable(Variable),
+    EmptyInputCollection(Variable),
+    NonPositiveEntid(i64),
+    MalformedUuid(String),
     NonAttributeArgument,
     NonInstantArgument,
     NonNumericArgument,
+    DivisionByZero,
     NonStringFulltextValue,
+    ValueIsNil,
     UnresolvedIdent(NamespacedKeyword),
     InvalidAttributeIdent(NamespacedKeyword),
     InvalidAttributeEntid(Entid),
     InvalidBinding(Column, TypedValue),
     ValueTypeMismatch(ValueType, TypedValue),
+    CachedAttributeHasNoValue { entity: Entid, attr: Entid },
+    CachedAttributeHasNoEntity { value: TypedValue, attr: Entid },
     AttributeLookupFailed,         // Catch-all, because the table lookup code is lazy. TODO
 }
 
@@ -502,6 +706,18 @@ impl Debug for EmptyBecause {
             &NoValidTypes(ref var) => {
                 write!(f, "Type mismatch: {:?} has no valid types", var)
             },
+            &UnboundVariable(ref var) => {
+                write!(f, "Input variable {:?} has no bound value", var)
+            },
+            &EmptyInputCollection(ref var) => {
+                write!(f, "Input collection {:?} has no values", var)
+            },
+            &NonPositiveEntid(x) => {
+                write!(f, "{} is not a valid entity id", x)
+            },
+            &MalformedUuid(ref s) => {
+                write!(f, "{:?} is not a valid UUID", s)
+            },
             &NonAttributeArgument => {
                 write!(f, "Non-attribute argument in attribute place")
             },
@@ -511,9 +727,15 @@ impl Debug for EmptyBecause {
             &NonNumericArgument => {
                 write!(f, "Non-numeric argument in numeric place")
             },
+            &DivisionByZero => {
+                write!(f, "Division by zero")
+            },
             &NonStringFulltextValue => {
                 write!(f, "Non-string argument for fulltext attribute")
             },
+            &ValueIsNil => {
+                write!(f, "nil is not a value")
+            },
             &UnresolvedIdent(ref kw) => {
                 write!(f, "Couldn't resolve keyword {}", kw)
             },
@@ -530,9 +752,30 @@ impl Debug for EmptyBecause {
                 write!(f, "Type mismatch: {:?} doesn't match attribute type {:?}",
                        typed_value, value_type)
             },
+            &CachedAttributeHasNoValue { entity, attr } => {
+                write!(f, "Entity {} has no value for cached attribute {}", entity, attr)
+            },
+            &CachedAttributeHasNoEntity { ref value, attr } => {
+                write!(f, "No entity has value {:?} for cached attribute {}", value, attr)
+            },
             &AttributeLookupFailed => {
                 write!(f, "Attribute lookup failed")
             },
         }
     }
 }
+
+impl EmptyBecause {
+    /// Whether this is some flavor of type mismatch. An integer that can't be an entid used to
+    /// be reported as a plain `TypeMismatch`, so callers that only care about the broad category
+    /// should use this rather than matching on the variant.
+    pub fn is_type_mismatch(&self) -> bool {
+        match self {
+            &EmptyBecause::TypeMismatch { .. } |
+            &EmptyBecause::KnownTypeMismatch { .. } |
+            &EmptyBecause::NonPositiveEntid(_) |
+            &EmptyBecause::MalformedUuid(_) => true,
+            _ => false,
+        }
+    }
+}
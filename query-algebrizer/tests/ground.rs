@@ -13,14 +13,19 @@ extern crate mentat_query;
 extern crate mentat_query_algebrizer;
 extern crate mentat_query_parser;
 
-use std::collections::BTreeMap;
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
 
+use mentat_core::attribute::Unique;
 use mentat_core::{
     Attribute,
     Entid,
     Schema,
     ValueType,
     TypedValue,
+    ValueTypeSet,
 };
 
 use mentat_query_parser::{
@@ -37,6 +42,7 @@ use mentat_query_algebrizer::{
     BindingError,
     ConjoiningClauses,
     ComputedTable,
+    EmptyBecause,
     Error,
     ErrorKind,
     QueryInputs,
@@ -63,6 +69,7 @@ fn prepopulated_schema() -> Schema {
     associate_ident(&mut schema, NamespacedKeyword::new("foo", "parent"), 67);
     associate_ident(&mut schema, NamespacedKeyword::new("foo", "age"), 68);
     associate_ident(&mut schema, NamespacedKeyword::new("foo", "height"), 69);
+    associate_ident(&mut schema, NamespacedKeyword::new("foo", "email"), 70);
     add_attribute(&mut schema, 65, Attribute {
         value_type: ValueType::String,
         multival: false,
@@ -88,6 +95,12 @@ fn prepopulated_schema() -> Schema {
         multival: false,
         ..Default::default()
     });
+    add_attribute(&mut schema, 70, Attribute {
+        value_type: ValueType::String,
+        multival: false,
+        unique: Some(Unique::Identity),
+        ..Default::default()
+    });
     schema
 }
 
@@ -106,6 +119,11 @@ fn alg(schema: &Schema, input: &str) -> ConjoiningClauses {
     algebrize(schema.into(), parsed).expect("algebrizing to have succeeded").cc
 }
 
+fn alg_with_inputs(schema: &Schema, input: &str, inputs: QueryInputs) -> ConjoiningClauses {
+    let parsed = parse_find_string(input).expect("query input to have parsed");
+    algebrize_with_inputs(schema, parsed, 0, inputs).expect("algebrizing to have succeeded").cc
+}
+
 #[test]
 fn test_ground_doesnt_bail_for_type_conflicts() {
     // We know `?x` to be a ref, but we're attempting to ground it to a Double.
@@ -217,6 +235,91 @@ fn test_ground_rel_placeholders() {
     });
 }
 
+#[test]
+fn test_ground_bigint() {
+    let q = r#"[:find ?x :where [(ground 9999999999999999999N) ?x]]"#;
+    let schema = prepopulated_schema();
+    let cc = alg(&schema, &q);
+    assert!(cc.empty_because.is_none());
+    match cc.bound_value(&Variable::from_valid_name("?x")) {
+        Some(TypedValue::BigInteger(ref b)) => {
+            assert_eq!(b.to_string(), "9999999999999999999");
+        },
+        _ => {
+            panic!();
+        },
+    }
+}
+
+#[test]
+fn test_ground_bigint_type_mismatch() {
+    // `?v` is known to be a long, so it can't be grounded to a bignum.
+    let q = r#"[:find ?x :where [?x :foo/age ?v] [(ground 9999999999999999999N) ?v]]"#;
+    let schema = prepopulated_schema();
+    let cc = alg(&schema, &q);
+    assert_eq!(cc.empty_because, Some(EmptyBecause::TypeMismatch {
+        var: Variable::from_valid_name("?v"),
+        existing: ValueTypeSet::of_one(ValueType::Long),
+        desired: ValueTypeSet::of_one(ValueType::BigInt),
+    }));
+}
+
+#[test]
+fn test_ground_bytes() {
+    let q = r#"[:find ?x :where [(ground #bytes "deadbeef") ?x]]"#;
+    let schema = prepopulated_schema();
+    let cc = alg(&schema, &q);
+    assert!(cc.empty_because.is_none());
+    assert_eq!(cc.bound_value(&Variable::from_valid_name("?x")),
+               Some(TypedValue::Bytes(vec![0xde, 0xad, 0xbe, 0xef])));
+}
+
+#[test]
+fn test_ground_bytes_type_mismatch() {
+    // `?v` is known to be a string, so it can't be grounded to bytes.
+    let q = r#"[:find ?x :where [?x :foo/name ?v] [(ground #bytes "deadbeef") ?v]]"#;
+    let schema = prepopulated_schema();
+    let cc = alg(&schema, &q);
+    assert_eq!(cc.empty_because, Some(EmptyBecause::TypeMismatch {
+        var: Variable::from_valid_name("?v"),
+        existing: ValueTypeSet::of_one(ValueType::String),
+        desired: ValueTypeSet::of_one(ValueType::Bytes),
+    }));
+}
+
+#[test]
+fn test_ground_non_positive_entid() {
+    // `?p` must be a ref, and no entity has a negative id.
+    let q = r#"[:find ?x :where [?x :foo/knows ?p] [(ground -3) ?p]]"#;
+    let schema = prepopulated_schema();
+    let cc = alg(&schema, &q);
+    assert_eq!(cc.empty_because, Some(EmptyBecause::NonPositiveEntid(-3)));
+}
+
+#[test]
+fn test_ground_input_agrees_with_inferred_type() {
+    let q = r#"[:find ?x :in ?in :where [?x :foo/name ?v] [(ground ?in) ?v]]"#;
+    let schema = prepopulated_schema();
+    let inputs = QueryInputs::with_value_sequence(vec![(Variable::from_valid_name("?in"), TypedValue::typed_string("Alice"))]);
+    let cc = alg_with_inputs(&schema, &q, inputs);
+    assert!(cc.empty_because.is_none());
+    assert_eq!(cc.bound_value(&Variable::from_valid_name("?v")), Some(TypedValue::typed_string("Alice")));
+}
+
+#[test]
+fn test_ground_input_type_mismatch() {
+    // `?v` is known to be a string, so it can't be grounded to a long input.
+    let q = r#"[:find ?x :in ?in :where [?x :foo/name ?v] [(ground ?in) ?v]]"#;
+    let schema = prepopulated_schema();
+    let inputs = QueryInputs::with_value_sequence(vec![(Variable::from_valid_name("?in"), TypedValue::Long(5))]);
+    let cc = alg_with_inputs(&schema, &q, inputs);
+    assert_eq!(cc.empty_because, Some(EmptyBecause::TypeMismatch {
+        var: Variable::from_valid_name("?v"),
+        existing: ValueTypeSet::of_one(ValueType::String),
+        desired: ValueTypeSet::of_one(ValueType::Long),
+    }));
+}
+
 // Nothing to do with ground, but while we're here…
 #[test]
 fn test_multiple_reference_type_failure() {
@@ -254,7 +357,7 @@ fn test_ground_coll_heterogeneous_types() {
     let schema = prepopulated_schema();
     let e = bails(&schema, &q);
     match e {
-        Error(ErrorKind::InvalidGroundConstant, _) => {
+        Error(ErrorKind::InvalidGroundConstant(_), _) => {
         },
         _ => {
             panic!();
@@ -268,7 +371,67 @@ fn test_ground_rel_heterogeneous_types() {
     let schema = prepopulated_schema();
     let e = bails(&schema, &q);
     match e {
-        Error(ErrorKind::InvalidGroundConstant, _) => {
+        // The error names the first value that differs in type.
+        Error(ErrorKind::InvalidGroundConstant(arg), _) => {
+            assert_eq!(arg, "Long(5)");
+        },
+        _ => {
+            panic!();
+        },
+    }
+}
+
+/// The values bound by the first computed table, in no particular order.
+fn ground_set_values(cc: &ConjoiningClauses) -> BTreeSet<TypedValue> {
+    match cc.computed_tables[0] {
+        ComputedTable::NamedValues { ref values, .. } => values.iter().cloned().collect(),
+        _ => panic!("Expected named values."),
+    }
+}
+
+#[test]
+fn test_ground_set_of_integers() {
+    let q = r#"[:find ?x :where [(ground #{1 2 3}) [?x ...]]]"#;
+    let schema = prepopulated_schema();
+    let cc = alg(&schema, &q);
+    assert!(cc.empty_because.is_none());
+    assert_eq!(ground_set_values(&cc),
+               vec![TypedValue::Long(1), TypedValue::Long(2), TypedValue::Long(3)].into_iter().collect());
+}
+
+#[test]
+fn test_ground_set_of_idents() {
+    // `?p` is a ref, so the keywords are looked up in the schema.
+    let q = r#"[:find ?p :where [?x :foo/knows ?p] [(ground #{:foo/name :foo/age}) [?p ...]]]"#;
+    let schema = prepopulated_schema();
+    let cc = alg(&schema, &q);
+    assert!(cc.empty_because.is_none());
+    assert_eq!(ground_set_values(&cc),
+               vec![TypedValue::Ref(65), TypedValue::Ref(68)].into_iter().collect());
+}
+
+#[test]
+fn test_ground_vector_of_idents() {
+    // An enumeration, resolved whether the pattern that makes `?p` a ref comes before or after.
+    let schema = prepopulated_schema();
+    for q in [r#"[:find ?x :where [?x :foo/knows ?p] [(ground [:foo/name :foo/age]) [?p ...]]]"#,
+              r#"[:find ?x :where [(ground [:foo/name :foo/age]) [?p ...]] [?x :foo/knows ?p]]"#].iter() {
+        let cc = alg(&schema, q);
+        assert!(cc.empty_because.is_none());
+        assert_eq!(cc.computed_tables[0], ComputedTable::NamedValues {
+            names: vec![Variable::from_valid_name("?p")],
+            values: vec![TypedValue::Ref(65), TypedValue::Ref(68)],
+        });
+    }
+}
+
+#[test]
+fn test_ground_vector_of_idents_unknown_ident() {
+    let q = r#"[:find ?x :where [?x :foo/knows ?p] [(ground [:foo/name :foo/nope]) [?p ...]]]"#;
+    let schema = prepopulated_schema();
+    match bails(&schema, &q) {
+        Error(ErrorKind::UnknownGroundIdent(ident), _) => {
+            assert_eq!(ident, NamespacedKeyword::new("foo", "nope"));
         },
         _ => {
             panic!();
@@ -276,6 +439,68 @@ fn test_ground_rel_heterogeneous_types() {
     }
 }
 
+#[test]
+fn test_ground_nil_against_required_pattern() {
+    let q = r#"[:find ?e :where [?e :foo/name ?x] [(ground nil) ?x]]"#;
+    let schema = prepopulated_schema();
+    let cc = alg(&schema, &q);
+    assert_eq!(cc.empty_because, Some(EmptyBecause::ValueIsNil));
+}
+
+#[test]
+fn test_ground_nil_against_optional_pattern() {
+    let q = r#"[:find ?e
+                :where
+                [?e :foo/age _]
+                (or-join [?e]
+                  [?e :foo/name ?x]
+                  (and [?e :foo/knows _] [(ground nil) ?x]))]"#;
+    let schema = prepopulated_schema();
+    let cc = alg(&schema, &q);
+    assert!(cc.empty_because.is_none());
+    assert!(!cc.column_bindings.contains_key(&Variable::from_valid_name("?x")));
+    assert!(!cc.is_value_bound(&Variable::from_valid_name("?x")));
+}
+
+#[test]
+fn test_ground_set_collapses_duplicates() {
+    // `65` and `:foo/name` are distinct in EDN, but are the same entity.
+    let q = r#"[:find ?p :where [?x :foo/knows ?p] [(ground #{65 :foo/name 66}) [?p ...]]]"#;
+    let schema = prepopulated_schema();
+    let cc = alg(&schema, &q);
+    assert!(cc.empty_because.is_none());
+    match cc.computed_tables[0] {
+        ComputedTable::NamedValues { ref values, .. } => {
+            assert_eq!(values.len(), 2);
+        },
+        _ => panic!("Expected named values."),
+    }
+    assert_eq!(ground_set_values(&cc),
+               vec![TypedValue::Ref(65), TypedValue::Ref(66)].into_iter().collect());
+}
+
+#[test]
+fn test_ground_set_defaults_each_element() {
+    // Each element is converted on its own. Where `?v` could be anything, integers default to
+    // longs and keywords to keywords, and those can't share a column.
+    let q = r#"[:find ?x :where [?x _ ?v] [(ground #{65 :foo/name}) [?v ...]]]"#;
+    let schema = prepopulated_schema();
+    match bails(&schema, &q) {
+        Error(ErrorKind::InvalidGroundConstant(_), _) => {
+        },
+        _ => {
+            panic!();
+        },
+    }
+
+    // Where `?v` must be a long, integers that could be entids are longs.
+    let q = r#"[:find ?x :where [?x :foo/age ?v] [(ground #{65 7}) [?v ...]]]"#;
+    let cc = alg(&schema, &q);
+    assert!(cc.empty_because.is_none());
+    assert_eq!(ground_set_values(&cc),
+               vec![TypedValue::Long(7), TypedValue::Long(65)].into_iter().collect());
+}
+
 #[test]
 fn test_ground_tuple_duplicate_vars() {
     let q = r#"[:find ?x :where [?x :foo/age ?v] [(ground [8 10]) [?x ?x]]]"#;
@@ -345,3 +570,125 @@ fn test_unbound_input_variable_invalid() {
         },
     }
 }
+
+#[test]
+fn test_ground_lookup_ref() {
+    let schema = prepopulated_schema();
+    let q = r#"[:find ?x :where [(ground {:foo/email "a@b.com"}) ?x] [?x :foo/age ?age]]"#;
+    let cc = alg(&schema, &q);
+    assert!(cc.empty_because.is_none());
+    assert_eq!(cc.known_type(&Variable::from_valid_name("?x")), Some(ValueType::Ref));
+
+    // The lookup ref becomes a join against datoms, not a constant.
+    assert!(cc.computed_tables.is_empty());
+    assert_eq!(cc.column_bindings.get(&Variable::from_valid_name("?x")).map(|b| b.len()), Some(2));
+}
+
+#[test]
+fn test_ground_lookup_ref_type_mismatch() {
+    // `:foo/email` is a string, so no entity can be identified by a number.
+    let schema = prepopulated_schema();
+    let q = r#"[:find ?x :where [(ground {:foo/email 5.5}) ?x]]"#;
+    let cc = alg(&schema, &q);
+    assert!(cc.empty_because.is_some());
+}
+
+#[test]
+fn test_ground_lookup_ref_unknown_attribute() {
+    let schema = prepopulated_schema();
+    let q = r#"[:find ?x :where [(ground {:foo/nope "a@b.com"}) ?x]]"#;
+    let cc = alg(&schema, &q);
+    assert_eq!(cc.empty_because, Some(EmptyBecause::UnresolvedIdent(NamespacedKeyword::new("foo", "nope"))));
+}
+
+#[test]
+fn test_ground_lookup_ref_non_unique_attribute() {
+    let schema = prepopulated_schema();
+    let q = r#"[:find ?x :where [(ground {:foo/name "Alice"}) ?x]]"#;
+    let e = bails(&schema, &q);
+    match e {
+        Error(ErrorKind::NonUniqueLookupRefAttribute(attribute), _) => {
+            assert_eq!(attribute, NamespacedKeyword::new("foo", "name"));
+        },
+        _ => {
+            panic!();
+        },
+    }
+}
+
+#[test]
+fn test_ground_same_value_twice() {
+    // Grounding a variable to the same value twice is redundant, not contradictory.
+    let schema = prepopulated_schema();
+    let once = alg(&schema, r#"[:find ?x :where [?x :foo/age ?a] [(ground 5) ?a]]"#);
+    let twice = alg(&schema, r#"[:find ?x :where [?x :foo/age ?a] [(ground 5) ?a] [(ground 5) ?a]]"#);
+    assert!(twice.empty_because.is_none());
+    assert_eq!(twice.wheres, once.wheres);
+    assert_eq!(twice.bound_value(&Variable::from_valid_name("?a")), Some(TypedValue::Long(5)));
+}
+
+#[test]
+fn test_ground_different_values_impossible() {
+    let schema = prepopulated_schema();
+    let cc = alg(&schema, r#"[:find ?x :where [?x :foo/age ?a] [(ground 5) ?a] [(ground 6) ?a]]"#);
+    assert_eq!(cc.empty_because, Some(EmptyBecause::ConflictingBindings {
+        var: Variable::from_valid_name("?a"),
+        existing: TypedValue::Long(5),
+        desired: TypedValue::Long(6),
+    }));
+}
+
+#[test]
+fn test_ground_coll_skips_values_conflicting_with_earlier_ground() {
+    let schema = prepopulated_schema();
+    let cc = alg(&schema, r#"[:find ?a :where [(ground 5) ?a] [(ground [5 6]) [?a ...]]]"#);
+    assert!(cc.empty_because.is_none());
+    assert_eq!(cc.computed_tables[0], ComputedTable::NamedValues {
+        names: vec![Variable::from_valid_name("?a")],
+        values: vec![TypedValue::Long(5)],
+    });
+
+    let cc = alg(&schema, r#"[:find ?a :where [(ground 5) ?a] [(ground [6 7]) [?a ...]]]"#);
+    assert!(cc.empty_because.is_some());
+}
+
+#[test]
+fn test_ground_integer_after_ref_pattern_is_entid() {
+    let schema = prepopulated_schema();
+    let cc = alg(&schema, r#"[:find ?x :where [?x :foo/knows ?v] [(ground 5) ?v]]"#);
+    assert!(cc.empty_because.is_none());
+    assert_eq!(cc.known_type(&Variable::from_valid_name("?v")), Some(ValueType::Ref));
+    assert_eq!(cc.bound_value(&Variable::from_valid_name("?v")), Some(TypedValue::Ref(5)));
+}
+
+#[test]
+fn test_ground_integer_before_ref_pattern_is_entid() {
+    // `?v` is narrowed to a ref by the pattern before the ground is applied.
+    let schema = prepopulated_schema();
+    let cc = alg(&schema, r#"[:find ?x :where [(ground 5) ?v] [?x :foo/knows ?v]]"#);
+    assert!(cc.empty_because.is_none());
+    assert_eq!(cc.known_type(&Variable::from_valid_name("?v")), Some(ValueType::Ref));
+    assert_eq!(cc.bound_value(&Variable::from_valid_name("?v")), Some(TypedValue::Ref(5)));
+
+    // Likewise in entity position.
+    let cc = alg(&schema, r#"[:find ?a :where [(ground 5) ?x] [?x :foo/age ?a]]"#);
+    assert!(cc.empty_because.is_none());
+    assert_eq!(cc.bound_value(&Variable::from_valid_name("?x")), Some(TypedValue::Ref(5)));
+}
+
+#[test]
+fn test_ground_integer_before_long_pattern_is_long() {
+    let schema = prepopulated_schema();
+    let cc = alg(&schema, r#"[:find ?x :where [(ground 5) ?v] [?x :foo/age ?v]]"#);
+    assert!(cc.empty_because.is_none());
+    assert_eq!(cc.known_type(&Variable::from_valid_name("?v")), Some(ValueType::Long));
+    assert_eq!(cc.bound_value(&Variable::from_valid_name("?v")), Some(TypedValue::Long(5)));
+}
+
+#[test]
+fn test_ground_before_pattern_type_mismatch() {
+    // `?v` must be a string, so grounding it to a long can't succeed, whichever comes first.
+    let schema = prepopulated_schema();
+    let cc = alg(&schema, r#"[:find ?x :where [(ground 5) ?v] [?x :foo/name ?v]]"#);
+    assert!(cc.empty_because.is_some());
+}
@@ -0,0 +1,120 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+extern crate mentat_core;
+extern crate mentat_query;
+extern crate mentat_query_algebrizer;
+extern crate mentat_query_parser;
+
+use mentat_core::{
+    Attribute,
+    Entid,
+    Schema,
+    TypedValue,
+    ValueType,
+    ValueTypeSet,
+};
+
+use mentat_query_parser::{
+    parse_find_string,
+};
+
+use mentat_query::{
+    NamespacedKeyword,
+    PlainSymbol,
+    Variable,
+};
+
+use mentat_query_algebrizer::{
+    Error,
+    ErrorKind,
+    QueryInputsBuilder,
+    algebrize_with_inputs,
+};
+
+// These are helpers that tests use to build Schema instances.
+#[cfg(test)]
+fn associate_ident(schema: &mut Schema, i: NamespacedKeyword, e: Entid) {
+    schema.entid_map.insert(e, i.clone());
+    schema.ident_map.insert(i.clone(), e);
+}
+
+#[cfg(test)]
+fn add_attribute(schema: &mut Schema, e: Entid, a: Attribute) {
+    schema.schema_map.insert(e, a);
+}
+
+fn prepopulated_schema() -> Schema {
+    let mut schema = Schema::default();
+    associate_ident(&mut schema, NamespacedKeyword::new("foo", "name"), 65);
+    associate_ident(&mut schema, NamespacedKeyword::new("foo", "age"), 66);
+    add_attribute(&mut schema, 65, Attribute {
+        value_type: ValueType::String,
+        multival: false,
+        ..Default::default()
+    });
+    add_attribute(&mut schema, 66, Attribute {
+        value_type: ValueType::Long,
+        multival: false,
+        ..Default::default()
+    });
+    schema
+}
+
+const QUERY: &'static str = r#"[:find ?x :in ?name :where [?x :foo/name ?name] [?x :foo/age ?age]]"#;
+
+#[test]
+fn test_inputs_builder_accepts_typed_binding() {
+    let schema = prepopulated_schema();
+    let parsed = parse_find_string(QUERY).expect("parsed");
+
+    let inputs = QueryInputsBuilder::new(&schema, &parsed).expect("builder")
+                     .bind(Variable::from_valid_name("?name"), TypedValue::typed_string("Alice"))
+                     .expect("string is a valid name")
+                     .build();
+
+    let algebrized = algebrize_with_inputs(&schema, parsed, 0, inputs).expect("algebrized");
+    assert!(algebrized.unbound_variables().is_empty());
+    assert_eq!(algebrized.cc.bound_value(&Variable::from_valid_name("?name")),
+               Some(TypedValue::typed_string("Alice")));
+}
+
+#[test]
+fn test_inputs_builder_rejects_wrong_type() {
+    let schema = prepopulated_schema();
+    let parsed = parse_find_string(QUERY).expect("parsed");
+
+    let builder = QueryInputsBuilder::new(&schema, &parsed).expect("builder");
+    match builder.bind(Variable::from_valid_name("?name"), TypedValue::Long(5)) {
+        Err(Error(ErrorKind::InputTypeMismatch(var, expected, provided), _)) => {
+            assert_eq!(var, PlainSymbol::new("?name"));
+            assert_eq!(expected, ValueTypeSet::of_one(ValueType::String));
+            assert_eq!(provided, ValueType::Long);
+        },
+        Err(e) => panic!("unexpected error {:?}", e),
+        Ok(_) => panic!("expected a long to be rejected for a string variable"),
+    }
+}
+
+#[test]
+fn test_inputs_builder_rejects_unknown_variable() {
+    let schema = prepopulated_schema();
+    let parsed = parse_find_string(QUERY).expect("parsed");
+
+    // `?age` is used in the query, but isn't an input.
+    let builder = QueryInputsBuilder::new(&schema, &parsed).expect("builder");
+    match builder.bind(Variable::from_valid_name("?age"), TypedValue::Long(5)) {
+        Err(Error(ErrorKind::UnknownInputVariable(var), _)) => {
+            assert_eq!(var, PlainSymbol::new("?age"));
+        },
+        Err(e) => panic!("unexpected error {:?}", e),
+        Ok(_) => panic!("expected ?age to be rejected"),
+    }
+}
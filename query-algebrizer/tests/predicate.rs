@@ -135,3 +135,113 @@ fn test_instant_predicates_require_instants() {
     assert_eq!(cc.known_type(&Variable::from_valid_name("?t")).expect("?t is known"),
                ValueType::Double);
 }
+
+#[test]
+fn test_like_requires_string() {
+    let schema = prepopulated_schema();
+
+    let query = r#"[:find ?e :where [?e :foo/double ?v] [(like ?v "1%")]]"#;
+    match bails(&schema, query).0 {
+        ErrorKind::InvalidArgument(op, why, idx) => {
+            assert_eq!(op, PlainSymbol::new("like"));
+            assert_eq!(why, "string");
+            assert_eq!(idx, 0);
+        },
+        _ => panic!("Expected InvalidArgument."),
+    }
+
+    // A variable of unknown type must be a string.
+    let query = r#"[:find ?e :where [?e _ ?v] [(like-ci ?v "ab%")]]"#;
+    let cc = alg(&schema, query);
+    assert!(!cc.is_known_empty());
+    assert_eq!(cc.known_type(&Variable::from_valid_name("?v")), Some(ValueType::String));
+}
+
+#[test]
+fn test_like_escape() {
+    let schema = prepopulated_schema();
+
+    // An escape character must be a single character…
+    let query = r#"[:find ?e :where [?e _ ?v] [(like ?v "100!%" "!!")]]"#;
+    match bails(&schema, query).0 {
+        ErrorKind::InvalidArgument(_, why, idx) => {
+            assert_eq!(why, "single character");
+            assert_eq!(idx, 2);
+        },
+        _ => panic!("Expected InvalidArgument."),
+    }
+
+    // … that escapes something.
+    let query = r#"[:find ?e :where [?e _ ?v] [(like ?v "100%!" "!")]]"#;
+    match bails(&schema, query).0 {
+        ErrorKind::InvalidArgument(_, why, idx) => {
+            assert_eq!(why, "pattern");
+            assert_eq!(idx, 1);
+        },
+        _ => panic!("Expected InvalidArgument."),
+    }
+}
+
+#[test]
+fn test_invalid_regex() {
+    let schema = prepopulated_schema();
+
+    let query = r#"[:find ?e :where [?e _ ?v] [(re-find ?v "(unclosed")]]"#;
+    match bails(&schema, query).0 {
+        ErrorKind::InvalidRegex(op, pattern, _) => {
+            assert_eq!(op, PlainSymbol::new("re-find"));
+            assert_eq!(pattern, "(unclosed");
+        },
+        _ => panic!("Expected InvalidRegex."),
+    }
+
+    let query = r#"[:find ?e :where [?e :foo/double ?v] [(re-matches ?v "\\d+")]]"#;
+    match bails(&schema, query).0 {
+        ErrorKind::InvalidArgument(_, why, idx) => {
+            assert_eq!(why, "string");
+            assert_eq!(idx, 0);
+        },
+        _ => panic!("Expected InvalidArgument."),
+    }
+}
+
+#[test]
+fn test_equality_between_variables() {
+    let schema = prepopulated_schema();
+    let v = Variable::from_valid_name("?v");
+    let d = Variable::from_valid_name("?d");
+
+    // Equal variables share their types: `?v` could have been anything, but now it's a double.
+    let query = r#"[:find ?e ?f :where [?e _ ?v] [?f :foo/double ?d] [(= ?v ?d)]]"#;
+    let cc = alg(&schema, query);
+    assert!(!cc.is_known_empty());
+    assert_eq!(cc.known_type(&v), Some(ValueType::Double));
+    assert_eq!(cc.known_type(&d), Some(ValueType::Double));
+
+    // An instant can't be equal to a double.
+    let query = r#"[:find ?e ?f :where [?e :foo/date ?t] [?f :foo/double ?d] [(= ?t ?d)]]"#;
+    let cc = alg(&schema, query);
+    assert!(cc.is_known_empty());
+    assert_eq!(cc.empty_because.unwrap(),
+               EmptyBecause::TypeMismatch {
+                   var: d.clone(),
+                   existing: ValueTypeSet::of_one(ValueType::Double),
+                   desired: ValueTypeSet::of_one(ValueType::Instant),
+               });
+
+    // Both sides must be variables bound by a preceding clause.
+    let query = r#"[:find ?e :where [?e :foo/double ?d] [(= ?d 1.5)]]"#;
+    match bails(&schema, query).0 {
+        ErrorKind::InvalidArgument(op, why, idx) => {
+            assert_eq!(op, PlainSymbol::new("="));
+            assert_eq!(why, "variable");
+            assert_eq!(idx, 1);
+        },
+        _ => panic!("Expected InvalidArgument."),
+    }
+    let query = r#"[:find ?e :where [(= ?d ?x)] [?e :foo/double ?d]]"#;
+    match bails(&schema, query).0 {
+        ErrorKind::UnboundVariable(var) => assert_eq!(var, PlainSymbol::new("?d")),
+        _ => panic!("Expected UnboundVariable."),
+    }
+}
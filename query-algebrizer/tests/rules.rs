@@ -0,0 +1,226 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+extern crate mentat_core;
+extern crate mentat_query;
+extern crate mentat_query_algebrizer;
+extern crate mentat_query_parser;
+
+use mentat_core::{
+    Attribute,
+    Entid,
+    Schema,
+    ValueType,
+};
+
+use mentat_query_parser::{
+    parse_find_string,
+    parse_rules_string,
+};
+
+use mentat_query::{
+    FindQuery,
+    NamespacedKeyword,
+    PlainSymbol,
+    Rule,
+    Variable,
+};
+
+use mentat_query_algebrizer::{
+    AlgebraicQuery,
+    ComputedTable,
+    DEFAULT_MAX_RULE_DEPTH,
+    Error,
+    ErrorKind,
+    QueryInputs,
+    Result,
+    RuleSet,
+    algebrize,
+    algebrize_with_rules,
+    expand_rules,
+};
+
+fn associate_ident(schema: &mut Schema, i: NamespacedKeyword, e: Entid) {
+    schema.entid_map.insert(e, i.clone());
+    schema.ident_map.insert(i.clone(), e);
+}
+
+fn add_attribute(schema: &mut Schema, e: Entid, a: Attribute) {
+    schema.schema_map.insert(e, a);
+}
+
+fn prepopulated_schema() -> Schema {
+    let mut schema = Schema::default();
+    associate_ident(&mut schema, NamespacedKeyword::new("foo", "name"), 65);
+    associate_ident(&mut schema, NamespacedKeyword::new("foo", "parent"), 66);
+    associate_ident(&mut schema, NamespacedKeyword::new("foo", "mother"), 67);
+    associate_ident(&mut schema, NamespacedKeyword::new("foo", "father"), 68);
+    add_attribute(&mut schema, 65, Attribute {
+        value_type: ValueType::String,
+        multival: false,
+        ..Default::default()
+    });
+    for e in 66..69 {
+        add_attribute(&mut schema, e, Attribute {
+            value_type: ValueType::Ref,
+            multival: false,
+            ..Default::default()
+        });
+    }
+    schema
+}
+
+fn rules() -> Vec<Rule> {
+    parse_rules_string(r#"[[(parent-name ?child ?name)
+                             [?child :foo/parent ?p]
+                             [?p :foo/name ?name]]
+                           [(parent ?a ?b) [?a :foo/mother ?b]]
+                           [(parent ?a ?b) [?a :foo/father ?b]]
+                           [(grandparent ?a ?c) (parent ?a ?b) (parent ?b ?c)]
+                           [(loop ?a) (loop ?a)]
+                           [(ancestor ?a ?b) [?a :foo/parent ?b]]
+                           [(ancestor ?x ?y) [?x :foo/parent ?z] (ancestor ?z ?y)]
+                           [(twice ?a) [?a :foo/name _]]
+                           [(twice ?a) (twice ?a) (twice ?a)]
+                           [(ping ?a) [?a :foo/name _] (pong ?a)]
+                           [(pong ?a) (ping ?a)]
+                           [(linked ?a ?v) [?a _ ?v]]
+                           [(linked ?a ?v) [?a :foo/parent ?p] (linked ?p ?v)]]"#).expect("rules to parse")
+}
+
+fn alg(input: &str) -> Result<AlgebraicQuery> {
+    let parsed = parse_find_string(input).expect("query input to have parsed");
+    algebrize_with_rules(&prepopulated_schema(), parsed, &RuleSet::new(rules()), QueryInputs::default())
+}
+
+fn expand(input: &str) -> FindQuery {
+    let parsed = parse_find_string(input).expect("query input to have parsed");
+    expand_rules(&rules(), parsed).expect("rules to have expanded")
+}
+
+fn expands_to(input: &str, expected: &str) {
+    assert_eq!(expand(input), parse_find_string(expected).expect("expected query to have parsed"));
+}
+
+#[test]
+fn test_simple_rule() {
+    expands_to(r#"[:find ?n :where [?c :foo/name "Alice"] (parent-name ?c ?n)]"#,
+               r#"[:find ?n :where [?c :foo/name "Alice"] [?c :foo/parent ?p__parent-name1] [?p__parent-name1 :foo/name ?n]]"#);
+
+    let algebrized = alg(r#"[:find ?n :where [?c :foo/name "Alice"] (parent-name ?c ?n)]"#).expect("algebrized");
+    assert!(!algebrized.is_known_empty());
+    assert_eq!(algebrized.cc.from.len(), 3);
+}
+
+#[test]
+fn test_rule_called_twice() {
+    // Each invocation gets its own intermediate variable.
+    expands_to(r#"[:find ?x ?y :where (parent-name ?a ?x) (parent-name ?b ?y)]"#,
+               r#"[:find ?x ?y :where
+                   [?a :foo/parent ?p__parent-name1] [?p__parent-name1 :foo/name ?x]
+                   [?b :foo/parent ?p__parent-name2] [?p__parent-name2 :foo/name ?y]]"#);
+}
+
+#[test]
+fn test_rule_variable_capture() {
+    // The query's `?p` isn't the rule's `?p`, and nor is a variable that looks like a fresh one.
+    expands_to(r#"[:find ?p ?n :where [?p :foo/name ?p__parent-name1] (parent-name ?p ?n)]"#,
+               r#"[:find ?p ?n :where [?p :foo/name ?p__parent-name1] [?p :foo/parent ?p__parent-name2] [?p__parent-name2 :foo/name ?n]]"#);
+}
+
+#[test]
+fn test_rule_with_several_definitions() {
+    expands_to(r#"[:find ?x :where (parent ?x ?y)]"#,
+               r#"[:find ?x :where (or-join [?x ?y] (and [?x :foo/mother ?y]) (and [?x :foo/father ?y]))]"#);
+
+    // Rules can invoke other rules.
+    expands_to(r#"[:find ?x :where (grandparent ?x ?z)]"#,
+               r#"[:find ?x :where
+                   (or-join [?x ?b__grandparent1] (and [?x :foo/mother ?b__grandparent1]) (and [?x :foo/father ?b__grandparent1]))
+                   (or-join [?b__grandparent1 ?z] (and [?b__grandparent1 :foo/mother ?z]) (and [?b__grandparent1 :foo/father ?z]))]"#);
+}
+
+#[test]
+fn test_rule_errors() {
+    let bails = |input: &str| {
+        let parsed = parse_find_string(input).expect("query input to have parsed");
+        expand_rules(&rules(), parsed).expect_err("expansion to have failed")
+    };
+
+    match bails(r#"[:find ?x :where (sibling ?x ?y)]"#) {
+        Error(ErrorKind::UnknownRule(name), _) => assert_eq!(name, PlainSymbol::new("sibling")),
+        e => panic!("Unexpected error: {:?}", e),
+    }
+    match bails(r#"[:find ?x :where (parent ?x)]"#) {
+        Error(ErrorKind::InvalidNumberOfArguments(name, 1, 2), _) => assert_eq!(name, PlainSymbol::new("parent")),
+        e => panic!("Unexpected error: {:?}", e),
+    }
+    match bails(r#"[:find ?x :where (parent ?x "Bob")]"#) {
+        Error(ErrorKind::InvalidArgument(name, "variable", 1), _) => assert_eq!(name, PlainSymbol::new("parent")),
+        e => panic!("Unexpected error: {:?}", e),
+    }
+    match bails(r#"[:find ?x :where (loop ?x)]"#) {
+        Error(ErrorKind::UnsupportedRecursiveRule(name), _) => assert_eq!(name, PlainSymbol::new("loop")),
+        e => panic!("Unexpected error: {:?}", e),
+    }
+    match bails(r#"[:find ?x :where (twice ?x)]"#) {
+        Error(ErrorKind::UnsupportedRecursiveRule(name), _) => assert_eq!(name, PlainSymbol::new("twice")),
+        e => panic!("Unexpected error: {:?}", e),
+    }
+    match bails(r#"[:find ?x :where (ping ?x)]"#) {
+        Error(ErrorKind::UnsupportedRecursiveRule(name), _) => assert_eq!(name, PlainSymbol::new("ping")),
+        e => panic!("Unexpected error: {:?}", e),
+    }
+
+    // Without the rules, an invocation names nothing.
+    let parsed = parse_find_string(r#"[:find ?x :where (parent ?x ?y)]"#).unwrap();
+    match algebrize(&prepopulated_schema(), parsed) {
+        Err(Error(ErrorKind::UnknownRule(name), _)) => assert_eq!(name, PlainSymbol::new("parent")),
+        r => panic!("Unexpected result: {:?}", r),
+    }
+}
+
+#[test]
+fn test_recursive_rule() {
+    // Recursive rules are left for the algebrizer.
+    expands_to(r#"[:find ?y :where [?x :foo/name "Alice"] (ancestor ?x ?y)]"#,
+               r#"[:find ?y :where [?x :foo/name "Alice"] (ancestor ?x ?y)]"#);
+
+    let algebrized = alg(r#"[:find ?y :where [?x :foo/name "Alice"] (ancestor ?x ?y)]"#).expect("algebrized");
+    assert!(!algebrized.is_known_empty());
+    match algebrized.cc.computed_tables.first() {
+        Some(&ComputedTable::RecursiveRule { ref name, ref vars, ref anchors, ref step, max_depth, .. }) => {
+            assert_eq!(name, &PlainSymbol::new("ancestor"));
+
+            // Each definition is renamed to use the variables of the recursive one.
+            assert_eq!(vars, &vec![Variable::from_valid_name("?x"), Variable::from_valid_name("?y")]);
+            assert_eq!(anchors.len(), 1);
+            assert!(step.is_some());
+            assert_eq!(max_depth, DEFAULT_MAX_RULE_DEPTH);
+        },
+        t => panic!("Unexpected computed table: {:?}", t),
+    }
+}
+
+#[test]
+fn test_recursive_rule_errors() {
+    // SQLite can't tell us the type of a rule's variable, so we must know it.
+    match alg(r#"[:find ?x :where (linked ?x ?y)]"#) {
+        Err(Error(ErrorKind::UntypedRuleVariable(rule, var), _)) => {
+            assert_eq!(rule, PlainSymbol::new("linked"));
+            assert_eq!(var, PlainSymbol::new("?v"));
+        },
+        r => panic!("Unexpected result: {:?}", r),
+    }
+    match alg(r#"[:find ?x :where (ancestor ?x)]"#) {
+        Err(Error(ErrorKind::InvalidNumberOfArguments(name, 1, 2), _)) => assert_eq!(name, PlainSymbol::new("ancestor")),
+        r => panic!("Unexpected result: {:?}", r),
+    }
+}
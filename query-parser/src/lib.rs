@@ -32,4 +32,5 @@ pub use parse::{
     Result,
     ResultExt,
     parse_find_string,
+    parse_rules_string,
 };
@@ -34,10 +34,12 @@ use self::mentat_parser_utils::value_and_span::{
     list,
     map,
     seq,
+    set,
     vector,
 };
 
 use self::mentat_query::{
+    Aggregate,
     Binding,
     Direction,
     Element,
@@ -46,6 +48,7 @@ use self::mentat_query::{
     FnArg,
     FromValue,
     Limit,
+    Offset,
     Order,
     OrJoin,
     OrWhereClause,
@@ -54,7 +57,11 @@ use self::mentat_query::{
     PatternNonValuePlace,
     PatternValuePlace,
     Predicate,
+    Pull,
+    PullAttributeSpec,
     QueryFunction,
+    Rule,
+    RuleExpr,
     SrcVar,
     UnifyVars,
     Variable,
@@ -93,6 +100,11 @@ error_chain! {
             display(":where parse error")
         }
 
+        RuleParseError(e: ValueParseError) {
+            description("rule parse error")
+            display("rule parse error")
+        }
+
         // Not yet used.
         WithParseError {
             description(":with parse error")
@@ -118,6 +130,21 @@ error_chain! {
             description("limit value not valid")
             display("expected natural number, got {}", val)
         }
+
+        UnknownOffsetVar(var: edn::PlainSymbol) {
+            description("offset var not present in :in")
+            display("offset var {} not present in :in", var)
+        }
+
+        InvalidOffset(val: edn::Value) {
+            description("offset value not valid")
+            display("expected non-negative integer, got {}", val)
+        }
+
+        UnknownSource(name: String) {
+            description("source not present in :in")
+            display("source ${} not present in :in", name)
+        }
     }
 }
 
@@ -137,7 +164,10 @@ def_parser!(Query, query_function, QueryFunction, {
 });
 
 def_parser!(Query, fn_arg, FnArg, {
-    satisfy_map(FnArg::from_value).or(vector().of_exactly(many::<Vec<FnArg>, _>(Query::fn_arg())).map(FnArg::Vector))
+    satisfy_map(FnArg::from_value)
+        .or(vector().of_exactly(many::<Vec<FnArg>, _>(Query::fn_arg())).map(FnArg::Vector))
+        .or(set().of_exactly(many::<Vec<FnArg>, _>(Query::fn_arg())).map(FnArg::Set))
+        .or(map().of_exactly(many::<Vec<(FnArg, FnArg)>, _>((Query::fn_arg(), Query::fn_arg()))).map(FnArg::Map))
 });
 
 def_parser!(Query, arguments, Vec<FnArg>, {
@@ -186,6 +216,20 @@ def_parser!(Query, natural_number, u64, {
     })
 });
 
+def_parser!(Query, offset_number, u64, {
+    any().and_then(|v: &edn::ValueAndSpan| {
+        match v.inner {
+            edn::SpannedValue::Integer(x) if (x >= 0) => {
+                Ok(x as u64)
+            },
+            ref spanned => {
+                let e = Box::new(Error::from_kind(ErrorKind::InvalidOffset(spanned.clone().into())));
+                Err(combine::primitives::Error::Other(e))
+            },
+        }
+    })
+});
+
 def_parser!(Where, pattern_non_value_place, PatternNonValuePlace, {
     satisfy_map(PatternNonValuePlace::from_value)
 });
@@ -305,6 +349,35 @@ def_parser!(Where, where_fn, WhereClause, {
                     }))
 });
 
+/// Any symbol that isn't a variable, a source, or one of our own keywords can name a rule. Without
+/// this, `(and …)` in an `or` would look like a rule invocation.
+def_parser!(Where, rule_name, edn::PlainSymbol, {
+    satisfy_map(|v: &edn::ValueAndSpan| {
+        match v.inner {
+            edn::SpannedValue::PlainSymbol(ref s) if !s.is_var_symbol() && !s.is_src_symbol() => {
+                match s.0.as_str() {
+                    "and" | "or" | "or-join" | "not" | "not-join" => None,
+                    _ => Some(s.clone()),
+                }
+            },
+            _ => None,
+        }
+    })
+});
+
+/// A rule invocation is a bare list: `(ancestor ?a ?b)`.
+def_parser!(Where, rule_expr, WhereClause, {
+    list()
+        .of_exactly((Where::rule_name(), Query::arguments())
+            .map(|(name, args)| {
+                WhereClause::RuleExpr(
+                    RuleExpr {
+                        name: name,
+                        args: args,
+                    })
+            }))
+});
+
 def_parser!(Where, pattern, WhereClause, {
     vector()
         .of_exactly(
@@ -358,6 +431,7 @@ def_parser!(Where, clause, WhereClause, {
 
             try(Where::pred()),
             try(Where::where_fn()),
+            try(Where::rule_expr()),
     ])
 });
 
@@ -374,8 +448,40 @@ def_matches_plain_symbol!(Find, ellipsis, "...");
 
 def_matches_plain_symbol!(Find, placeholder, "_");
 
+def_parser!(Find, aggregate, Aggregate, {
+    seq().of_exactly(Query::func())
+        .map(|(func, args)| Aggregate {
+            func: func,
+            args: args,
+        })
+});
+
+def_matches_plain_symbol!(Find, pull, "pull");
+
+def_parser!(Find, pull_attribute, PullAttributeSpec, {
+    satisfy_map(|v: &edn::ValueAndSpan| {
+        match v.inner {
+            edn::SpannedValue::PlainSymbol(ref s) if s.0.as_str() == "*" => Some(PullAttributeSpec::Wildcard),
+            edn::SpannedValue::NamespacedKeyword(ref k) => Some(PullAttributeSpec::Attribute(k.clone())),
+            _ => None,
+        }
+    })
+});
+
+def_parser!(Find, pull_element, Pull, {
+    seq().of_exactly(Find::pull()
+        .with(Query::variable())
+        .and(vector().of_exactly(many1::<Vec<PullAttributeSpec>, _>(Find::pull_attribute())))
+        .map(|(var, patterns)| Pull {
+            var: var,
+            patterns: patterns,
+        }))
+});
+
 def_parser!(Find, elem, Element, {
-    Query::variable().map(Element::Variable)
+    try(Query::variable().map(Element::Variable))
+        .or(try(Find::pull_element().map(Element::Pull)))
+        .or(Find::aggregate().map(Element::Aggregate))
 });
 
 def_parser!(Find, find_scalar, FindSpec, {
@@ -403,9 +509,8 @@ def_parser!(Find, find_tuple, FindSpec, {
 
 /// Parse a stream of values into one of four find specs.
 ///
-/// `:find` must be an array of plain var symbols (?foo), pull expressions, and aggregates.  For now
-/// we only support variables and the annotations necessary to declare which flavor of :find we
-/// want:
+/// `:find` must be an array of plain var symbols (?foo), pull expressions, and aggregates, followed
+/// by the annotations necessary to declare which flavor of :find we want:
 ///
 ///
 ///     `?x ?y ?z  `     = FindRel
@@ -422,9 +527,9 @@ def_parser!(Find, spec, FindSpec, {
           &mut try(Find::find_rel())])
 });
 
-fn unique_vars<T, E>(vars: Vec<Variable>) -> std::result::Result<BTreeSet<Variable>, combine::primitives::Error<T, E>> {
+fn unique_vars<V: Ord, T, E>(vars: Vec<V>) -> std::result::Result<BTreeSet<V>, combine::primitives::Error<T, E>> {
     let given = vars.len();
-    let set: BTreeSet<Variable> = vars.into_iter().collect();
+    let set: BTreeSet<V> = vars.into_iter().collect();
     if given != set.len() {
         // TODO: find out what the variable is!
         let e = Box::new(Error::from_kind(ErrorKind::DuplicateVariableError));
@@ -438,14 +543,79 @@ def_parser!(Find, vars, BTreeSet<Variable>, {
     many(Query::variable()).and_then(unique_vars)
 });
 
+/// One of the names in `:in`.
+enum Input {
+    Source(SrcVar),
+    Variable(Variable),
+    Binding(Binding),
+}
+
+/// The sources, variables, and collection and relation bindings of `:in`, in any order:
+/// `$ $db2 ?name [?e ...] [[?f ?age]]`.
+def_parser!(Find, inputs, (BTreeSet<SrcVar>, BTreeSet<Variable>, Vec<Binding>), {
+    many::<Vec<Input>, _>(Query::source_var().map(Input::Source)
+                              .or(Query::variable().map(Input::Variable))
+                              .or(try(Bind::bind_coll()).map(Input::Binding))
+                              .or(try(Bind::bind_rel()).map(Input::Binding)))
+        .and_then(|inputs| -> std::result::Result<(BTreeSet<SrcVar>, BTreeSet<Variable>, Vec<Binding>), combine::primitives::Error<&edn::ValueAndSpan, &edn::ValueAndSpan>> {
+            let mut sources = vec![];
+            let mut vars = vec![];
+            let mut bindings = vec![];
+            for input in inputs {
+                match input {
+                    Input::Source(source) => sources.push(source),
+                    Input::Variable(var) => vars.push(var),
+                    Input::Binding(binding) => bindings.push(binding),
+                }
+            }
+
+            // A relation must bind something.
+            if bindings.iter().any(|b| b.is_empty()) {
+                return Err(combine::primitives::Error::Unexpected("expected a variable in :in relation".into()));
+            }
+
+            // A variable can only be bound once, whether alone or as part of a binding.
+            let bound: Vec<Variable> = vars.iter()
+                                           .cloned()
+                                           .chain(bindings.iter().flat_map(|b| b.variables().into_iter().filter_map(|v| v)))
+                                           .collect();
+            unique_vars(bound)?;
+            Ok((unique_vars(sources)?, unique_vars(vars)?, bindings))
+        })
+});
+
+/// Add the sources named by the patterns in `clause`, including those in `or` and `not`, to `acc`.
+fn accumulate_sources(clause: &WhereClause, acc: &mut BTreeSet<SrcVar>) {
+    match clause {
+        &WhereClause::Pattern(Pattern { source: Some(ref source), .. }) => {
+            acc.insert(source.clone());
+        },
+        &WhereClause::OrJoin(ref or_join) => {
+            for clause in or_join.clauses.iter() {
+                match clause {
+                    &OrWhereClause::Clause(ref clause) => accumulate_sources(clause, acc),
+                    &OrWhereClause::And(ref clauses) => for clause in clauses { accumulate_sources(clause, acc) },
+                }
+            }
+        },
+        &WhereClause::NotJoin(ref not_join) => {
+            for clause in not_join.clauses.iter() {
+                accumulate_sources(clause, acc);
+            }
+        },
+        _ => {},
+    }
+}
+
 /// This is awkward, but will do for now.  We use `keyword_map()` to optionally accept vector find
 /// queries, then we use `FindQueryPart` to collect parts that have heterogeneous types; and then we
 /// construct a `FindQuery` from them.
 def_parser!(Find, query, FindQuery, {
     let find_map = keyword_map_of!(
         ("find", Find::spec()),
-        ("in", Find::vars()),
+        ("in", Find::inputs()),
         ("limit", Query::variable().map(Limit::Variable).or(Query::natural_number().map(Limit::Fixed))),
+        ("offset", Query::variable().map(Offset::Variable).or(Query::offset_number().map(Offset::Fixed))),
         ("order", many1(Query::order())),
         ("where", Where::clauses()),
         ("with", Find::vars()) // Note: no trailing comma allowed!
@@ -453,11 +623,12 @@ def_parser!(Find, query, FindQuery, {
 
     (or(keyword_map(), vector()))
         .of_exactly(find_map)
-        .and_then(|(find_spec, in_vars, limit, order_clauses, where_clauses, with_vars) | -> std::result::Result<FindQuery, combine::primitives::Error<&edn::ValueAndSpan, &edn::ValueAndSpan>>  {
+        .and_then(|(find_spec, inputs, limit, offset, order_clauses, where_clauses, with_vars) | -> std::result::Result<FindQuery, combine::primitives::Error<&edn::ValueAndSpan, &edn::ValueAndSpan>>  {
             let limit = limit.unwrap_or(Limit::None);
+            let offset = offset.unwrap_or(Offset::None);
 
             // Make sure that if we have `:limit ?x`, `?x` appears in `:in`.
-            let in_vars = in_vars.unwrap_or(BTreeSet::default());
+            let (in_sources, in_vars, in_bindings) = inputs.unwrap_or((BTreeSet::default(), BTreeSet::default(), vec![]));
             if let Limit::Variable(ref v) = limit {
                 if !in_vars.contains(v) {
                     let e = Box::new(Error::from_kind(ErrorKind::UnknownLimitVar(v.name())));
@@ -465,19 +636,64 @@ def_parser!(Find, query, FindQuery, {
                 }
             }
 
+            // Likewise for `:offset ?x`.
+            if let Offset::Variable(ref v) = offset {
+                if !in_vars.contains(v) {
+                    let e = Box::new(Error::from_kind(ErrorKind::UnknownOffsetVar(v.name())));
+                    return Err(combine::primitives::Error::Other(e));
+                }
+            }
+
+            let find_spec = find_spec.ok_or(combine::primitives::Error::Unexpected("expected :find".into()))?;
+            let where_clauses = where_clauses.ok_or(combine::primitives::Error::Unexpected("expected :where".into()))?;
+
+            // And that each named source that a pattern uses appears in `:in`.
+            let mut used_sources = BTreeSet::default();
+            for clause in where_clauses.iter() {
+                accumulate_sources(clause, &mut used_sources);
+            }
+            for source in used_sources.difference(&in_sources) {
+                if let &SrcVar::NamedSrc(ref name) = source {
+                    let e = Box::new(Error::from_kind(ErrorKind::UnknownSource(name.clone())));
+                    return Err(combine::primitives::Error::Other(e));
+                }
+            }
+
             Ok(FindQuery {
                 default_source: SrcVar::DefaultSrc,
-                find_spec: find_spec.ok_or(combine::primitives::Error::Unexpected("expected :find".into()))?,
-                in_sources: BTreeSet::default(),    // TODO
+                find_spec: find_spec,
+                in_sources: in_sources,
                 in_vars: in_vars,
+                in_bindings: in_bindings,
                 limit: limit,
+                offset: offset,
                 order: order_clauses,
-                where_clauses: where_clauses.ok_or(combine::primitives::Error::Unexpected("expected :where".into()))?,
+                where_clauses: where_clauses,
                 with: with_vars.unwrap_or(BTreeSet::default()),
             })
         })
 });
 
+/// A rule definition: `[(ancestor ?a ?b) [?a :person/parent ?b]]`.
+def_parser!(Find, rule, Rule, {
+    vector()
+        .of_exactly((list().of_exactly((Where::rule_name(), many1::<Vec<Variable>, _>(Query::variable()))),
+                     many1::<Vec<WhereClause>, _>(Where::clause())))
+        .and_then(|((name, vars), clauses)| -> std::result::Result<Rule, combine::primitives::Error<&edn::ValueAndSpan, &edn::ValueAndSpan>> {
+            // The head's variables must be distinct, but their order matters.
+            unique_vars(vars.clone())?;
+            Ok(Rule {
+                name: name,
+                vars: vars,
+                clauses: clauses,
+            })
+        })
+});
+
+def_parser!(Find, rules, Vec<Rule>, {
+    vector().of_exactly(many::<Vec<Rule>, _>(Find::rule()))
+});
+
 pub struct Bind<'a>(std::marker::PhantomData<&'a ()>);
 
 def_parser!(Bind, bind_scalar, Binding, {
@@ -528,6 +744,20 @@ pub fn parse_find_string(string: &str) -> Result<FindQuery> {
         .map_err(|e| Error::from_kind(ErrorKind::FindParseError(e.into())))
 }
 
+/// Parse a rule set, like
+///
+/// ```edn
+/// [[(parent ?a ?b) [?a :person/mother ?b]]
+///  [(parent ?a ?b) [?a :person/father ?b]]]
+/// ```
+pub fn parse_rules_string(string: &str) -> Result<Vec<Rule>> {
+    let expr = edn::parse::value(string)?;
+    Find::rules()
+        .parse(expr.atom_stream())
+        .map(|x| x.0)
+        .map_err(|e| Error::from_kind(ErrorKind::RuleParseError(e.into())))
+}
+
 #[cfg(test)]
 mod test {
     extern crate combine;
@@ -772,6 +1002,41 @@ mod test {
                               }));
     }
 
+    #[test]
+    fn test_rule_expr() {
+        let a = edn::PlainSymbol::new("?a");
+        let b = edn::PlainSymbol::new("?b");
+        assert_edn_parses_to!(Where::clause, "(ancestor ?a ?b)",
+                              WhereClause::RuleExpr(RuleExpr {
+                                  name: edn::PlainSymbol::new("ancestor"),
+                                  args: vec![FnArg::Variable(variable(a)), FnArg::Variable(variable(b))],
+                              }));
+
+        // `and` is only meaningful inside `or`.
+        assert!(Where::rule_expr().parse(edn::parse::value("(and [?a :foo/bar ?b])").unwrap().atom_stream()).is_err());
+    }
+
+    #[test]
+    fn test_rules() {
+        let a = edn::PlainSymbol::new("?a");
+        let b = edn::PlainSymbol::new("?b");
+        assert_eq!(parse_rules_string("[[(parent ?a ?b) [?a :foo/mother ?b]]]").expect("rules to parse"),
+                   vec![Rule {
+                       name: edn::PlainSymbol::new("parent"),
+                       vars: vec![variable(a.clone()), variable(b.clone())],
+                       clauses: vec![WhereClause::Pattern(Pattern {
+                           source: None,
+                           entity: PatternNonValuePlace::Variable(variable(a)),
+                           attribute: ident("foo", "mother"),
+                           value: PatternValuePlace::Variable(variable(b)),
+                           tx: PatternNonValuePlace::Placeholder,
+                       })],
+                   }]);
+
+        // A rule's variables must be distinct.
+        assert!(parse_rules_string("[[(parent ?a ?a) [?a :foo/mother ?a]]]").is_err());
+    }
+
     #[test]
     fn test_find_sp_variable() {
         let sym = edn::PlainSymbol::new("?x");
@@ -868,6 +1133,24 @@ mod test {
                           ]));
     }
 
+    #[test]
+    fn test_fn_arg_maps() {
+        assert_edn_parses_to!(|| Query::fn_arg(),
+                              r#"{:person/email "a@b.com"}"#,
+                              FnArg::Map(vec![(FnArg::IdentOrKeyword(edn::NamespacedKeyword::new("person", "email")),
+                                               FnArg::Constant(NonIntegerConstant::Text(Rc::new("a@b.com".to_string()))))]));
+    }
+
+    #[test]
+    fn test_fn_arg_sets() {
+        assert_edn_parses_to!(|| Query::fn_arg(),
+                              "#{1 2 :foo/bar}",
+                              // In EDN's order, which puts larger integers first.
+                              FnArg::Set(vec![FnArg::EntidOrInteger(2),
+                                              FnArg::EntidOrInteger(1),
+                                              FnArg::IdentOrKeyword(edn::NamespacedKeyword::new("foo", "bar"))]));
+    }
+
     #[test]
     fn test_bind_scalar() {
         let vx = edn::PlainSymbol::new("?x");
@@ -24,11 +24,14 @@ use edn::{
 };
 
 use mentat_query::{
+    Aggregate,
+    Binding,
     Direction,
     Element,
     FindSpec,
     FnArg,
     Limit,
+    Offset,
     NonIntegerConstant,
     Order,
     OrJoin,
@@ -37,8 +40,13 @@ use mentat_query::{
     PatternNonValuePlace,
     PatternValuePlace,
     Predicate,
+    Pull,
+    PullAttributeSpec,
+    QueryFunction,
+    SrcVar,
     UnifyVars,
     Variable,
+    VariableOrPlaceholder,
     WhereClause,
 };
 
@@ -275,6 +283,86 @@ fn can_parse_limit() {
     assert!(parse_find_string(variable_without_in).is_err());
 }
 
+#[test]
+fn can_parse_offset() {
+    let none = "[:find ?x :where [?x :foo/baz ?y]]";
+    assert_eq!(parse_find_string(none).unwrap().offset,
+               Offset::None);
+
+    // Unlike limits, offsets can be zero.
+    let zero = "[:find ?x :where [?x :foo/baz ?y] :offset 0]";
+    assert_eq!(parse_find_string(zero).unwrap().offset,
+               Offset::Fixed(0));
+
+    let ten = "[:find ?x :where [?x :foo/baz ?y] :limit 5 :offset 10]";
+    let parsed = parse_find_string(ten).unwrap();
+    assert_eq!(parsed.limit, Limit::Fixed(5));
+    assert_eq!(parsed.offset, Offset::Fixed(10));
+
+    let negative = "[:find ?x :where [?x :foo/baz ?y] :offset -1]";
+    assert!(parse_find_string(negative).is_err());
+
+    let variable_with_in = "[:find ?x :in ?offset :where [?x :foo/baz ?y] :offset ?offset]";
+    assert_eq!(parse_find_string(variable_with_in).unwrap().offset,
+               Offset::Variable(Variable::from_valid_name("?offset")));
+
+    let variable_without_in = "[:find ?x :where [?x :foo/baz ?y] :offset ?offset]";
+    assert!(parse_find_string(variable_without_in).is_err());
+}
+
+#[test]
+fn can_parse_in_sources() {
+    let s = "[:find ?x :in $ $db2 ?name :where [?x :foo/name ?name] [$db2 ?y :foo/name ?name]]";
+    let p = parse_find_string(s).unwrap();
+    assert_eq!(p.in_sources, btreeset!{SrcVar::DefaultSrc, SrcVar::NamedSrc("db2".to_string())});
+    assert_eq!(p.in_vars, btreeset!{Variable::from_valid_name("?name")});
+
+    // Sources and variables can come in any order.
+    let s = "[:find ?x :in ?name $db2 :where [$db2 ?x :foo/name ?name]]";
+    let p = parse_find_string(s).unwrap();
+    assert_eq!(p.in_sources, btreeset!{SrcVar::NamedSrc("db2".to_string())});
+    assert_eq!(p.in_vars, btreeset!{Variable::from_valid_name("?name")});
+
+    let duplicate = "[:find ?x :in $db2 $db2 :where [$db2 ?x :foo/name _]]";
+    assert!(parse_find_string(duplicate).is_err());
+
+    // Named sources must be declared, even inside `or` and `not`.
+    let undeclared = "[:find ?x :where [$db2 ?x :foo/name _]]";
+    assert!(parse_find_string(undeclared).is_err());
+    let undeclared = "[:find ?x :in $db3 :where [?x :foo/name _] (not [$db2 ?x :foo/age _])]";
+    assert!(parse_find_string(undeclared).is_err());
+
+    // The default source needn't be.
+    let default = "[:find ?x :where [$ ?x :foo/name _]]";
+    assert!(parse_find_string(default).is_ok());
+}
+
+#[test]
+fn can_parse_in_collections() {
+    let s = "[:find ?x :in $ ?name [?x ...] :where [?x :foo/name ?name]]";
+    let p = parse_find_string(s).unwrap();
+    assert_eq!(p.in_vars, btreeset!{Variable::from_valid_name("?name")});
+    assert_eq!(p.in_bindings, vec![Binding::BindColl(Variable::from_valid_name("?x"))]);
+
+    // A variable can't be bound both alone and in a collection.
+    let duplicate = "[:find ?x :in ?x [?x ...] :where [?x :foo/name _]]";
+    assert!(parse_find_string(duplicate).is_err());
+}
+
+#[test]
+fn can_parse_in_relations() {
+    let s = "[:find ?x :in [[?x _ ?name]] :where [?x :foo/name ?name]]";
+    let p = parse_find_string(s).unwrap();
+    assert!(p.in_vars.is_empty());
+    assert_eq!(p.in_bindings, vec![Binding::BindRel(vec![VariableOrPlaceholder::Variable(Variable::from_valid_name("?x")),
+                                                         VariableOrPlaceholder::Placeholder,
+                                                         VariableOrPlaceholder::Variable(Variable::from_valid_name("?name"))])]);
+
+    // A relation must bind at least one variable, and each only once.
+    assert!(parse_find_string("[:find ?x :in [[_ _]] :where [?x :foo/name _]]").is_err());
+    assert!(parse_find_string("[:find ?x :in [[?x ?x]] :where [?x :foo/name _]]").is_err());
+}
+
 #[test]
 fn can_parse_uuid() {
     let expected = edn::Uuid::parse_str("4cb3f828-752d-497a-90c9-b1fd516d5644").expect("valid uuid");
@@ -288,3 +376,58 @@ fn can_parse_uuid() {
                                 PatternNonValuePlace::Placeholder)
                        .expect("valid pattern")));
 }
+
+#[test]
+fn can_parse_aggregates() {
+    let s = "[:find ?x (count-distinct ?y) :where [?x _ ?y]]";
+    let p = parse_find_string(s).unwrap();
+
+    assert_eq!(p.find_spec,
+               FindSpec::FindRel(vec![
+                   Element::Variable(Variable::from_valid_name("?x")),
+                   Element::Aggregate(Aggregate {
+                       func: QueryFunction(PlainSymbol::new("count-distinct")),
+                       args: vec![FnArg::Variable(Variable::from_valid_name("?y"))],
+                   }),
+               ]));
+    assert!(p.find_spec.has_aggregates());
+
+    // Aggregates can be scalars, too.
+    let s = "[:find (count ?y) . :where [_ _ ?y]]";
+    let p = parse_find_string(s).unwrap();
+    assert_eq!(p.find_spec,
+               FindSpec::FindScalar(Element::Aggregate(Aggregate {
+                   func: QueryFunction(PlainSymbol::new("count")),
+                   args: vec![FnArg::Variable(Variable::from_valid_name("?y"))],
+               })));
+}
+
+#[test]
+fn can_parse_pull() {
+    let s = "[:find ?x (pull ?y [:foo/bar * :foo/_baz]) :where [?x :foo/knows ?y]]";
+    let p = parse_find_string(s).unwrap();
+
+    assert_eq!(p.find_spec,
+               FindSpec::FindRel(vec![
+                   Element::Variable(Variable::from_valid_name("?x")),
+                   Element::Pull(Pull {
+                       var: Variable::from_valid_name("?y"),
+                       patterns: vec![
+                           PullAttributeSpec::Attribute(NamespacedKeyword::new("foo", "bar")),
+                           PullAttributeSpec::Wildcard,
+                           PullAttributeSpec::Attribute(NamespacedKeyword::new("foo", "_baz")),
+                       ],
+                   }),
+               ]));
+    assert!(p.find_spec.has_pull());
+    assert!(!p.find_spec.has_aggregates());
+
+    // Pull expressions can be scalars, too.
+    let s = "[:find (pull ?y [*]) . :where [?y _ _]]";
+    let p = parse_find_string(s).unwrap();
+    assert_eq!(p.find_spec,
+               FindSpec::FindScalar(Element::Pull(Pull {
+                   var: Variable::from_valid_name("?y"),
+                   patterns: vec![PullAttributeSpec::Wildcard],
+               })));
+}
@@ -0,0 +1,463 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+
+use mentat_core::{
+    Binding,
+    TypedValue,
+    ValueType,
+    ValueTypeSet,
+};
+
+use mentat_query::{
+    Aggregate,
+    FnArg,
+    NonIntegerConstant,
+    QueryFunction,
+    Variable,
+};
+
+use mentat_query_sql::{
+    ColumnOrExpression,
+    Expression,
+    Name,
+};
+
+use super::{
+    ErrorKind,
+    Result,
+};
+
+/// An aggregate over a single variable.
+///
+/// Some of these can be computed by SQLite. The others -- those for which `sql_op` returns
+/// `None` -- are computed by the projector from the rows of the query.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SimpleAggregationOp {
+    Count,
+    CountDistinct,
+
+    /// The greatest and least values, of the variable's own type.
+    Max,
+    Min,
+
+    Median,
+
+    /// A long if every value is a long, and a double otherwise.
+    Sum,
+
+    /// The nearest-rank percentile. The argument is clamped to [0, 100].
+    Percentile(f64),
+
+    /// Up to this many distinct values, chosen at random. The query's rows arrive in random
+    /// order, so the first values we see are a fair sample.
+    Sample(usize),
+}
+
+impl SimpleAggregationOp {
+    fn for_function(function: &QueryFunction, args: &[FnArg]) -> Result<SimpleAggregationOp> {
+        let name = function.0.plain_name();
+        let bad_arguments = || ErrorKind::InvalidAggregateArguments(name.to_string());
+        match name {
+            "count" | "count-distinct" | "max" | "min" | "median" | "sum" => {
+                if args.len() != 1 {
+                    bail!(bad_arguments());
+                }
+                Ok(match name {
+                    "count" => SimpleAggregationOp::Count,
+                    "count-distinct" => SimpleAggregationOp::CountDistinct,
+                    "max" => SimpleAggregationOp::Max,
+                    "min" => SimpleAggregationOp::Min,
+                    "sum" => SimpleAggregationOp::Sum,
+                    _ => SimpleAggregationOp::Median,
+                })
+            },
+            "percentile" => {
+                if args.len() != 2 {
+                    bail!(bad_arguments());
+                }
+                let p = match args[1] {
+                    FnArg::EntidOrInteger(p) => p as f64,
+                    FnArg::Constant(NonIntegerConstant::Float(p)) => p.into_inner(),
+                    _ => bail!(bad_arguments()),
+                };
+                Ok(SimpleAggregationOp::Percentile(p.max(0f64).min(100f64)))
+            },
+            "sample" => {
+                if args.len() != 2 {
+                    bail!(bad_arguments());
+                }
+                match args[0] {
+                    FnArg::EntidOrInteger(n) if n > 0 => Ok(SimpleAggregationOp::Sample(n as usize)),
+                    _ => bail!(bad_arguments()),
+                }
+            },
+            _ => bail!(ErrorKind::UnknownAggregateFunction(name.to_string())),
+        }
+    }
+
+    /// The SQL aggregate function that computes this aggregate, if there is one.
+    pub fn sql_op(&self) -> Option<&'static str> {
+        use self::SimpleAggregationOp::*;
+        match self {
+            &Count | &CountDistinct => Some("COUNT"),
+            &Max => Some("MAX"),
+            &Min => Some("MIN"),
+            // SQLite's `SUM` mixes integers and reals as it sees fit, so we add up for ourselves.
+            &Median | &Percentile(_) | &Sample(_) | &Sum => None,
+        }
+    }
+
+    /// True if this aggregate yields a vector of values rather than a single value.
+    pub fn is_sample(&self) -> bool {
+        match self {
+            &SimpleAggregationOp::Sample(_) => true,
+            _ => false,
+        }
+    }
+
+    /// The position of the aggregated variable among the arguments: `(sample 10 ?x)` takes the
+    /// size of the sample first.
+    fn var_index(&self) -> usize {
+        if self.is_sample() { 1 } else { 0 }
+    }
+
+    fn is_distinct(&self) -> bool {
+        match self {
+            &SimpleAggregationOp::CountDistinct => true,
+            _ => false,
+        }
+    }
+
+    /// True if this aggregate picks one of the values it's given, and so has no value at all
+    /// when it's given none.
+    pub fn is_extreme(&self) -> bool {
+        match self {
+            &SimpleAggregationOp::Max | &SimpleAggregationOp::Min => true,
+            _ => false,
+        }
+    }
+
+    /// The type of the value this aggregate yields when applied to a variable with the given
+    /// possible types. The values in a sample can be of any of those types, so a sample only has
+    /// a result type if the variable's type is known.
+    pub fn result_type(&self, possibilities: ValueTypeSet) -> Result<ValueType> {
+        use self::SimpleAggregationOp::*;
+        match self {
+            &Sample(_) => {
+                match possibilities.exemplar() {
+                    Some(t) if possibilities.is_unit() => Ok(t),
+                    _ => bail!(ErrorKind::CannotApplyAggregateOperationToTypes(*self, possibilities)),
+                }
+            },
+
+            // Anything can be counted…
+            &Count | &CountDistinct => Ok(ValueType::Long),
+
+            // … and most things compared. SQLite compares the values we store, which order as the
+            // values themselves do for every type but bigints, which we store as text. Longs and
+            // doubles compare as numbers, and share a type tag, so the value itself tells us which
+            // of the two we found.
+            &Max | &Min => {
+                match possibilities.exemplar() {
+                    Some(ValueType::BigInt) => bail!(ErrorKind::CannotApplyAggregateOperationToTypes(*self, possibilities)),
+                    Some(t) if possibilities.is_unit() => Ok(t),
+                    _ if possibilities.is_subset(&ValueTypeSet::of_numeric_types()) => Ok(ValueType::Double),
+                    _ => bail!(ErrorKind::CannotApplyAggregateOperationToTypes(*self, possibilities)),
+                }
+            },
+
+            // Only numbers can be ranked.
+            &Median | &Percentile(_) => {
+                if possibilities.is_subset(&ValueTypeSet::of_numeric_types()) {
+                    Ok(ValueType::Double)
+                } else {
+                    bail!(ErrorKind::CannotApplyAggregateOperationToTypes(*self, possibilities))
+                }
+            },
+
+            // … and summed.
+            &Sum => {
+                if possibilities == ValueTypeSet::of_one(ValueType::Long) {
+                    Ok(ValueType::Long)
+                } else if possibilities.is_subset(&ValueTypeSet::of_numeric_types()) {
+                    Ok(ValueType::Double)
+                } else {
+                    bail!(ErrorKind::CannotApplyAggregateOperationToTypes(*self, possibilities))
+                }
+            },
+        }
+    }
+
+    /// Compute this aggregate over `values`. Returns `None` if the aggregate has no value, as
+    /// is the case for the median of nothing.
+    pub fn compute(&self, values: Vec<TypedValue>) -> Option<TypedValue> {
+        use self::SimpleAggregationOp::*;
+        match self {
+            &Count => Some(TypedValue::Long(values.len() as i64)),
+            &CountDistinct => {
+                let distinct: BTreeSet<TypedValue> = values.into_iter().collect();
+                Some(TypedValue::Long(distinct.len() as i64))
+            },
+            &Max => values.into_iter().max_by(compare_values),
+            &Min => values.into_iter().min_by(compare_values),
+            &Median => {
+                let sorted = sorted_numbers(values);
+                let n = sorted.len();
+                if n == 0 {
+                    None
+                } else if n % 2 == 1 {
+                    Some(TypedValue::from(sorted[n / 2]))
+                } else {
+                    Some(TypedValue::from((sorted[n / 2 - 1] + sorted[n / 2]) / 2f64))
+                }
+            },
+            &Percentile(p) => {
+                let sorted = sorted_numbers(values);
+                let n = sorted.len();
+                if n == 0 {
+                    return None;
+                }
+                let rank = ((p / 100f64) * (n as f64)).ceil() as usize;
+                let rank = ::std::cmp::max(1, ::std::cmp::min(rank, n));
+                Some(TypedValue::from(sorted[rank - 1]))
+            },
+
+            &Sum => {
+                if values.is_empty() {
+                    None
+                } else if values.iter().all(|v| v.value_type() == ValueType::Long) {
+                    Some(TypedValue::Long(values.into_iter().filter_map(|v| match v {
+                        TypedValue::Long(x) => Some(x),
+                        _ => None,
+                    }).sum()))
+                } else {
+                    Some(TypedValue::from(sorted_numbers(values).into_iter().sum::<f64>()))
+                }
+            },
+
+            // A sample isn't a single value: see `compute_binding`.
+            &Sample(_) => None,
+        }
+    }
+
+    /// Like `compute`, but a sample yields a vector of the values it chose. Sampling nothing
+    /// yields no value at all.
+    pub fn compute_binding(&self, values: Vec<TypedValue>) -> Option<Binding> {
+        match self {
+            &SimpleAggregationOp::Sample(n) => {
+                let mut seen = BTreeSet::new();
+                let sample: Vec<Binding> = values.into_iter()
+                                                 .filter(|v| seen.insert(v.clone()))
+                                                 .take(n)
+                                                 .map(Binding::Scalar)
+                                                 .collect();
+                if sample.is_empty() {
+                    None
+                } else {
+                    Some(Binding::Vec(sample))
+                }
+            },
+            _ => self.compute(values).map(Binding::Scalar),
+        }
+    }
+}
+
+/// Order values as SQLite orders the values we store: numbers as numbers, whether longs or
+/// doubles, and everything else within its own type.
+fn compare_values(a: &TypedValue, b: &TypedValue) -> Ordering {
+    match (a, b) {
+        (&TypedValue::Long(x), &TypedValue::Double(y)) => (x as f64).partial_cmp(&y.into_inner()).unwrap_or(Ordering::Equal),
+        (&TypedValue::Double(x), &TypedValue::Long(y)) => x.into_inner().partial_cmp(&(y as f64)).unwrap_or(Ordering::Equal),
+        _ => a.cmp(b),
+    }
+}
+
+fn sorted_numbers(values: Vec<TypedValue>) -> Vec<f64> {
+    let mut numbers: Vec<f64> = values.into_iter()
+                                      .filter_map(|v| match v {
+                                          TypedValue::Long(x) => Some(x as f64),
+                                          TypedValue::Double(x) => Some(x.into_inner()),
+                                          _ => None,
+                                      })
+                                      .collect();
+    numbers.sort_by(|a, b| a.partial_cmp(b).unwrap_or(Ordering::Equal));
+    numbers
+}
+
+/// An aggregate over a single variable, like `(count ?x)`.
+pub struct SimpleAggregate {
+    pub op: SimpleAggregationOp,
+    pub var: Variable,
+}
+
+impl SimpleAggregate {
+    pub fn column_name(&self) -> Name {
+        use self::SimpleAggregationOp::*;
+        match self.op {
+            Count => format!("(count {})", self.var.as_str()),
+            CountDistinct => format!("(count-distinct {})", self.var.as_str()),
+            Max => format!("(max {})", self.var.as_str()),
+            Min => format!("(min {})", self.var.as_str()),
+            Median => format!("(median {})", self.var.as_str()),
+            Sum => format!("(sum {})", self.var.as_str()),
+            Percentile(p) => format!("(percentile {} {})", self.var.as_str(), p),
+            Sample(n) => format!("(sample {} {})", n, self.var.as_str()),
+        }
+    }
+
+    /// Wrap `column`, which should be the column bound to our variable, in the SQL aggregate.
+    /// Returns `None` if SQLite can't compute this aggregate.
+    pub fn to_expression(&self, column: ColumnOrExpression) -> Option<ColumnOrExpression> {
+        self.op.sql_op().map(|sql_op| {
+            ColumnOrExpression::Expression(Box::new(Expression::Aggregate {
+                sql_op: sql_op,
+                distinct: self.op.is_distinct(),
+                arg: column,
+            }))
+        })
+    }
+}
+
+/// `(rand)` looks like an aggregate, but isn't one: it yields a random double for each result.
+pub fn is_rand(aggregate: &Aggregate) -> Result<bool> {
+    if aggregate.func.0.plain_name() != "rand" {
+        return Ok(false);
+    }
+    if !aggregate.args.is_empty() {
+        bail!(ErrorKind::InvalidAggregateArguments("rand".to_string()));
+    }
+    Ok(true)
+}
+
+pub trait SimpleAggregation {
+    fn to_simple(&self) -> Result<SimpleAggregate>;
+}
+
+impl SimpleAggregation for Aggregate {
+    fn to_simple(&self) -> Result<SimpleAggregate> {
+        let op = SimpleAggregationOp::for_function(&self.func, &self.args)?;
+        match self.args.get(op.var_index()) {
+            Some(&FnArg::Variable(ref var)) => Ok(SimpleAggregate {
+                op: op,
+                var: var.clone(),
+            }),
+            _ => bail!(ErrorKind::InvalidAggregateArguments(self.func.0.to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod testing {
+    use super::*;
+
+    use mentat_query::PlainSymbol;
+
+    fn longs(xs: Vec<i64>) -> Vec<TypedValue> {
+        xs.into_iter().map(TypedValue::Long).collect()
+    }
+
+    #[test]
+    fn test_median() {
+        let median = SimpleAggregationOp::Median;
+        assert_eq!(median.compute(longs(vec![5, 1, 3])), Some(TypedValue::from(3f64)));
+        assert_eq!(median.compute(longs(vec![4, 1, 3, 2])), Some(TypedValue::from(2.5f64)));
+        assert_eq!(median.compute(vec![TypedValue::from(1.5f64), TypedValue::Long(2)]),
+                   Some(TypedValue::from(1.75f64)));
+        assert_eq!(median.compute(vec![]), None);
+    }
+
+    #[test]
+    fn test_sum() {
+        let sum = SimpleAggregationOp::Sum;
+        assert_eq!(sum.compute(longs(vec![5, 1, 5])), Some(TypedValue::Long(11)));
+        assert_eq!(sum.compute(vec![TypedValue::from(1.5f64), TypedValue::Long(2)]),
+                   Some(TypedValue::from(3.5f64)));
+        assert_eq!(sum.compute(vec![]), None);
+        assert_eq!(sum.result_type(ValueTypeSet::of_one(ValueType::Long)).unwrap(), ValueType::Long);
+        assert_eq!(sum.result_type(ValueTypeSet::of_numeric_types()).unwrap(), ValueType::Double);
+        assert!(sum.result_type(ValueTypeSet::of_one(ValueType::String)).is_err());
+    }
+
+    #[test]
+    fn test_percentile() {
+        let values = longs(vec![15, 20, 35, 40, 50]);
+        let percentile = |p| SimpleAggregationOp::Percentile(p).compute(values.clone());
+        assert_eq!(percentile(0f64), Some(TypedValue::from(15f64)));
+        assert_eq!(percentile(30f64), Some(TypedValue::from(20f64)));
+        assert_eq!(percentile(40f64), Some(TypedValue::from(20f64)));
+        assert_eq!(percentile(50f64), Some(TypedValue::from(35f64)));
+        assert_eq!(percentile(100f64), Some(TypedValue::from(50f64)));
+        assert_eq!(SimpleAggregationOp::Percentile(50f64).compute(vec![]), None);
+    }
+
+    #[test]
+    fn test_percentile_clamping() {
+        let func = QueryFunction(PlainSymbol::new("percentile"));
+        let percentile = |p| {
+            let args = vec![FnArg::Variable(Variable::from_valid_name("?x")), FnArg::EntidOrInteger(p)];
+            SimpleAggregationOp::for_function(&func, &args).expect("valid percentile")
+        };
+        assert_eq!(percentile(-5), SimpleAggregationOp::Percentile(0f64));
+        assert_eq!(percentile(0), SimpleAggregationOp::Percentile(0f64));
+        assert_eq!(percentile(100), SimpleAggregationOp::Percentile(100f64));
+        assert_eq!(percentile(150), SimpleAggregationOp::Percentile(100f64));
+    }
+
+    #[test]
+    fn test_sample() {
+        let func = QueryFunction(PlainSymbol::new("sample"));
+        let sample = |n| {
+            let args = vec![FnArg::EntidOrInteger(n), FnArg::Variable(Variable::from_valid_name("?x"))];
+            SimpleAggregationOp::for_function(&func, &args)
+        };
+        assert_eq!(sample(2).expect("valid sample"), SimpleAggregationOp::Sample(2));
+        assert!(sample(0).is_err());
+
+        // The first distinct values, up to the size of the sample.
+        let scalars = |xs: Vec<i64>| Binding::Vec(xs.into_iter().map(|x| Binding::Scalar(TypedValue::Long(x))).collect());
+        let op = SimpleAggregationOp::Sample(2);
+        assert_eq!(op.compute_binding(longs(vec![3, 3, 1, 2])), Some(scalars(vec![3, 1])));
+        assert_eq!(op.compute_binding(longs(vec![3, 3])), Some(scalars(vec![3])));
+        assert_eq!(op.compute_binding(vec![]), None);
+    }
+
+    #[test]
+    fn test_extremes() {
+        let max = SimpleAggregationOp::Max;
+        let min = SimpleAggregationOp::Min;
+        assert_eq!(max.compute(longs(vec![5, 1, 3])), Some(TypedValue::Long(5)));
+        assert_eq!(min.compute(vec![TypedValue::typed_string("b"), TypedValue::typed_string("ab")]),
+                   Some(TypedValue::typed_string("ab")));
+
+        // Longs and doubles compare as numbers, and keep their own type.
+        assert_eq!(max.compute(vec![TypedValue::from(2.5f64), TypedValue::Long(3)]), Some(TypedValue::Long(3)));
+        assert_eq!(min.compute(vec![TypedValue::from(2.5f64), TypedValue::Long(3)]), Some(TypedValue::from(2.5f64)));
+        assert_eq!(max.compute(vec![]), None);
+
+        assert_eq!(max.result_type(ValueTypeSet::of_one(ValueType::Instant)).unwrap(), ValueType::Instant);
+        assert_eq!(min.result_type(ValueTypeSet::of_one(ValueType::Ref)).unwrap(), ValueType::Ref);
+        assert_eq!(max.result_type(ValueTypeSet::of_numeric_types()).unwrap(), ValueType::Double);
+        assert!(max.result_type(ValueTypeSet::of_one(ValueType::BigInt)).is_err());
+        assert!(min.result_type(ValueTypeSet::any()).is_err());
+    }
+
+    #[test]
+    fn test_ranked_aggregates_require_numbers() {
+        let median = SimpleAggregationOp::Median;
+        assert_eq!(median.result_type(ValueTypeSet::of_one(ValueType::Long)).unwrap(), ValueType::Double);
+        assert_eq!(median.result_type(ValueTypeSet::of_numeric_types()).unwrap(), ValueType::Double);
+        assert!(median.result_type(ValueTypeSet::of_one(ValueType::String)).is_err());
+        assert!(SimpleAggregationOp::Percentile(95f64).result_type(ValueTypeSet::any()).is_err());
+        assert_eq!(SimpleAggregationOp::Count.result_type(ValueTypeSet::any()).unwrap(), ValueType::Long);
+    }
+}
@@ -0,0 +1,476 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! A compact binary encoding of query results, for handing them across an FFI boundary without
+//! formatting them as text.
+//!
+//! Each value is a one-byte type tag followed by its payload. Integers are little-endian, and
+//! strings and byte arrays are prefixed with their length as a `u32`.
+//!
+//! | tag | type    | payload                                        |
+//! |-----|---------|------------------------------------------------|
+//! | 0   | ref     | `i64` entid                                    |
+//! | 1   | boolean | `u8`, 0 or 1                                   |
+//! | 2   | long    | `i64`                                          |
+//! | 3   | double  | `f64` bits, as a `u64`                         |
+//! | 4   | instant | `i64` microseconds since the epoch             |
+//! | 5   | string  | UTF-8                                          |
+//! | 6   | keyword | namespace, then name, each as a string         |
+//! | 7   | uuid    | 16 bytes                                       |
+//! | 8   | bigint  | decimal digits, as a string                    |
+//! | 9   | bytes   | bytes                                          |
+//! | 10  | vector  | `u32` count, then that many values             |
+//! | 11  | map     | `u32` count, then that many keyword-value pairs |
+//!
+//! Vectors and maps only occur in pulled results; map keys are written as the payload of a
+//! keyword, without its tag.
+//!
+//! A row is a `u32` count followed by that many values, and a result set is a `u32` count
+//! followed by that many rows.
+
+use std::rc::Rc;
+use std::str;
+
+use mentat_core::{
+    Binding,
+    DateTime,
+    FromMicros,
+    StructuredMap,
+    ToMicros,
+    TypedValue,
+    Utc,
+    Uuid,
+};
+
+use mentat_query::{
+    NamespacedKeyword,
+};
+
+use super::{
+    ErrorKind,
+    QueryResults,
+    Result,
+};
+
+const TAG_REF: u8 = 0;
+const TAG_BOOLEAN: u8 = 1;
+const TAG_LONG: u8 = 2;
+const TAG_DOUBLE: u8 = 3;
+const TAG_INSTANT: u8 = 4;
+const TAG_STRING: u8 = 5;
+const TAG_KEYWORD: u8 = 6;
+const TAG_UUID: u8 = 7;
+const TAG_BIGINT: u8 = 8;
+const TAG_BYTES: u8 = 9;
+const TAG_VECTOR: u8 = 10;
+const TAG_MAP: u8 = 11;
+
+fn write_u32(n: u32, out: &mut Vec<u8>) {
+    for i in 0..4 {
+        out.push((n >> (8 * i)) as u8);
+    }
+}
+
+fn write_u64(n: u64, out: &mut Vec<u8>) {
+    for i in 0..8 {
+        out.push((n >> (8 * i)) as u8);
+    }
+}
+
+fn write_len(len: usize, out: &mut Vec<u8>) {
+    assert!(len <= u32::max_value() as usize, "too long to encode");
+    write_u32(len as u32, out);
+}
+
+fn write_bytes(bytes: &[u8], out: &mut Vec<u8>) {
+    write_len(bytes.len(), out);
+    out.extend_from_slice(bytes);
+}
+
+fn write_keyword(keyword: &NamespacedKeyword, out: &mut Vec<u8>) {
+    write_bytes(keyword.namespace.as_bytes(), out);
+    write_bytes(keyword.name.as_bytes(), out);
+}
+
+/// Things that can be written in the binary encoding.
+pub trait BinaryEncode {
+    fn write_binary(&self, out: &mut Vec<u8>);
+}
+
+impl BinaryEncode for TypedValue {
+    fn write_binary(&self, out: &mut Vec<u8>) {
+        match self {
+            &TypedValue::Ref(e) => {
+                out.push(TAG_REF);
+                write_u64(e as u64, out);
+            },
+            &TypedValue::Boolean(b) => {
+                out.push(TAG_BOOLEAN);
+                out.push(if b { 1 } else { 0 });
+            },
+            &TypedValue::Long(x) => {
+                out.push(TAG_LONG);
+                write_u64(x as u64, out);
+            },
+            &TypedValue::Double(x) => {
+                out.push(TAG_DOUBLE);
+                write_u64(x.into_inner().to_bits(), out);
+            },
+            &TypedValue::Instant(ref t) => {
+                out.push(TAG_INSTANT);
+                write_u64(t.to_micros() as u64, out);
+            },
+            &TypedValue::String(ref s) => {
+                out.push(TAG_STRING);
+                write_bytes(s.as_bytes(), out);
+            },
+            &TypedValue::Keyword(ref k) => {
+                out.push(TAG_KEYWORD);
+                write_keyword(k, out);
+            },
+            &TypedValue::Uuid(ref u) => {
+                out.push(TAG_UUID);
+                out.extend_from_slice(u.as_bytes());
+            },
+            &TypedValue::BigInteger(ref x) => {
+                out.push(TAG_BIGINT);
+                write_bytes(x.to_string().as_bytes(), out);
+            },
+            &TypedValue::Bytes(ref b) => {
+                out.push(TAG_BYTES);
+                write_bytes(b, out);
+            },
+        }
+    }
+}
+
+impl BinaryEncode for Binding {
+    fn write_binary(&self, out: &mut Vec<u8>) {
+        match self {
+            &Binding::Scalar(ref value) => value.write_binary(out),
+            &Binding::Vec(ref values) => {
+                out.push(TAG_VECTOR);
+                write_len(values.len(), out);
+                for value in values {
+                    value.write_binary(out);
+                }
+            },
+            &Binding::Map(ref map) => {
+                out.push(TAG_MAP);
+                write_len(map.0.len(), out);
+                for (keyword, value) in map.0.iter() {
+                    write_keyword(keyword, out);
+                    value.write_binary(out);
+                }
+            },
+        }
+    }
+}
+
+/// Write `row` as a count followed by each of its values.
+pub fn write_binary_row<T>(row: &[T], out: &mut Vec<u8>) where T: BinaryEncode {
+    write_len(row.len(), out);
+    for value in row {
+        value.write_binary(out);
+    }
+}
+
+impl QueryResults {
+    /// Append these results to `out` as a result set. A scalar or a tuple is a single row, if
+    /// present, and each member of a collection is a row of one value.
+    pub fn write_binary(&self, out: &mut Vec<u8>) {
+        match self {
+            &QueryResults::Scalar(ref value) => {
+                write_len(value.iter().count(), out);
+                for value in value {
+                    write_len(1, out);
+                    value.write_binary(out);
+                }
+            },
+            &QueryResults::Tuple(ref row) => {
+                write_len(row.iter().count(), out);
+                for row in row {
+                    write_binary_row(row.as_slice(), out);
+                }
+            },
+            &QueryResults::Coll(ref values) => {
+                write_len(values.len(), out);
+                for value in values {
+                    write_len(1, out);
+                    value.write_binary(out);
+                }
+            },
+            &QueryResults::Rel(ref rows) => {
+                write_len(rows.len(), out);
+                for row in rows {
+                    write_binary_row(row.as_slice(), out);
+                }
+            },
+        }
+    }
+}
+
+/// Reads values from the binary encoding, keeping track of how far it's got so that errors can say
+/// where they happened.
+pub struct BinaryReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> BinaryReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> BinaryReader<'a> {
+        BinaryReader {
+            bytes: bytes,
+            offset: 0,
+        }
+    }
+
+    /// The number of bytes read so far.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.offset == self.bytes.len()
+    }
+
+    fn invalid<T>(&self, reason: &'static str) -> Result<T> {
+        bail!(ErrorKind::InvalidBinaryEncoding(self.offset, reason))
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.bytes.len() - self.offset < n {
+            return self.invalid("unexpected end of input");
+        }
+        let taken = &self.bytes[self.offset..self.offset + n];
+        self.offset += n;
+        Ok(taken)
+    }
+
+    fn read_u8(&mut self) -> Result<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn read_u32(&mut self) -> Result<u32> {
+        let b = self.take(4)?;
+        Ok((0..4).fold(0, |n, i| n | ((b[i] as u32) << (8 * i))))
+    }
+
+    fn read_u64(&mut self) -> Result<u64> {
+        let b = self.take(8)?;
+        Ok((0..8).fold(0, |n, i| n | ((b[i] as u64) << (8 * i))))
+    }
+
+    fn read_bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.read_u32()? as usize;
+        self.take(len)
+    }
+
+    fn read_str(&mut self) -> Result<&'a str> {
+        let bytes = self.read_bytes()?;
+        match str::from_utf8(bytes) {
+            Ok(s) => Ok(s),
+            Err(_) => self.invalid("string is not UTF-8"),
+        }
+    }
+
+    fn read_keyword(&mut self) -> Result<NamespacedKeyword> {
+        let namespace = self.read_str()?;
+        let name = self.read_str()?;
+        Ok(NamespacedKeyword::new(namespace, name))
+    }
+
+    fn read_value_with_tag(&mut self, tag: u8) -> Result<TypedValue> {
+        Ok(match tag {
+            TAG_REF => TypedValue::Ref(self.read_u64()? as i64),
+            TAG_BOOLEAN => match self.read_u8()? {
+                0 => TypedValue::Boolean(false),
+                1 => TypedValue::Boolean(true),
+                _ => return self.invalid("boolean is neither 0 nor 1"),
+            },
+            TAG_LONG => TypedValue::Long(self.read_u64()? as i64),
+            TAG_DOUBLE => TypedValue::Double(f64::from_bits(self.read_u64()?).into()),
+            TAG_INSTANT => TypedValue::Instant(DateTime::<Utc>::from_micros(self.read_u64()? as i64)),
+            TAG_STRING => TypedValue::String(Rc::new(self.read_str()?.to_string())),
+            TAG_KEYWORD => TypedValue::Keyword(Rc::new(self.read_keyword()?)),
+            TAG_UUID => {
+                let bytes = self.take(16)?;
+                match Uuid::from_bytes(bytes) {
+                    Ok(u) => TypedValue::Uuid(u),
+                    Err(_) => return self.invalid("invalid uuid"),
+                }
+            },
+            TAG_BIGINT => match self.read_str()?.parse() {
+                Ok(x) => TypedValue::BigInteger(x),
+                Err(_) => return self.invalid("invalid bigint"),
+            },
+            TAG_BYTES => TypedValue::Bytes(self.read_bytes()?.to_vec()),
+            _ => return self.invalid("unknown type tag"),
+        })
+    }
+
+    /// Read a single scalar value.
+    pub fn read_value(&mut self) -> Result<TypedValue> {
+        let tag = self.read_u8()?;
+        self.read_value_with_tag(tag)
+    }
+
+    /// Read a value, which might be a pulled vector or map.
+    pub fn read_binding(&mut self) -> Result<Binding> {
+        let tag = self.read_u8()?;
+        match tag {
+            TAG_VECTOR => {
+                let count = self.read_u32()?;
+                let mut values = Vec::with_capacity(count as usize);
+                for _ in 0..count {
+                    values.push(self.read_binding()?);
+                }
+                Ok(Binding::Vec(values))
+            },
+            TAG_MAP => {
+                let count = self.read_u32()?;
+                let mut map = StructuredMap::default();
+                for _ in 0..count {
+                    let keyword = self.read_keyword()?;
+                    let value = self.read_binding()?;
+                    map.0.insert(keyword, value);
+                }
+                Ok(Binding::Map(map))
+            },
+            tag => self.read_value_with_tag(tag).map(Binding::Scalar),
+        }
+    }
+
+    /// Read a row of scalar values, as written by `write_binary_row`.
+    pub fn read_row(&mut self) -> Result<Vec<TypedValue>> {
+        let count = self.read_u32()?;
+        (0..count).map(|_| self.read_value()).collect()
+    }
+
+    /// Read a row that might contain pulled values.
+    pub fn read_binding_row(&mut self) -> Result<Vec<Binding>> {
+        let count = self.read_u32()?;
+        (0..count).map(|_| self.read_binding()).collect()
+    }
+
+    /// Read a result set, as written by `QueryResults::write_binary`.
+    pub fn read_results(&mut self) -> Result<Vec<Vec<Binding>>> {
+        let count = self.read_u32()?;
+        (0..count).map(|_| self.read_binding_row()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::collections::BTreeMap;
+
+    use mentat_core::{
+        FromMillis,
+    };
+
+    fn values() -> Vec<TypedValue> {
+        vec![
+            TypedValue::Ref(65536),
+            TypedValue::Boolean(true),
+            TypedValue::Long(-1),
+            TypedValue::Long(i64::min_value()),
+            TypedValue::Double((-0.5).into()),
+            TypedValue::Instant(DateTime::<Utc>::from_millis(1_500_000_000_123).unwrap()),
+            TypedValue::typed_string("héllo"),
+            TypedValue::typed_string(""),
+            TypedValue::typed_ns_keyword("foo", "bar"),
+            TypedValue::Uuid(Uuid::parse_str("550e8400-e29b-41d4-a716-446655440000").unwrap()),
+            TypedValue::BigInteger("-123456789012345678901234567890".parse().unwrap()),
+            TypedValue::Bytes(vec![0, 1, 254, 255]),
+        ]
+    }
+
+    #[test]
+    fn test_value_round_trip() {
+        let mut out = Vec::new();
+        write_binary_row(values().as_slice(), &mut out);
+
+        let mut reader = BinaryReader::new(out.as_slice());
+        assert_eq!(reader.read_row().expect("decoded"), values());
+        assert!(reader.is_empty());
+    }
+
+    #[test]
+    fn test_type_tags() {
+        // Refs and longs, and instants and longs, are distinguished.
+        let mut out = Vec::new();
+        TypedValue::Ref(5).write_binary(&mut out);
+        TypedValue::Long(5).write_binary(&mut out);
+        assert_eq!(out[0], TAG_REF);
+        assert_eq!(out[9], TAG_LONG);
+        assert_eq!(&out[1..9], &out[10..18]);
+
+        let mut reader = BinaryReader::new(out.as_slice());
+        assert_eq!(reader.read_value().unwrap(), TypedValue::Ref(5));
+        assert_eq!(reader.read_value().unwrap(), TypedValue::Long(5));
+    }
+
+    #[test]
+    fn test_binding_round_trip() {
+        let mut attributes = BTreeMap::new();
+        attributes.insert(NamespacedKeyword::new("foo", "name"), Binding::Scalar(TypedValue::typed_string("Alice")));
+        attributes.insert(NamespacedKeyword::new("foo", "friend"),
+                          Binding::Vec(vec![Binding::Scalar(TypedValue::Ref(10)), Binding::Scalar(TypedValue::Ref(11))]));
+        let row = vec![Binding::Scalar(TypedValue::Ref(9)), Binding::Map(StructuredMap(attributes))];
+
+        let mut out = Vec::new();
+        write_binary_row(row.as_slice(), &mut out);
+        let mut reader = BinaryReader::new(out.as_slice());
+        assert_eq!(reader.read_binding_row().expect("decoded"), row);
+        assert!(reader.is_empty());
+
+        // Pulled maps aren't scalar values.
+        let mut reader = BinaryReader::new(out.as_slice());
+        match reader.read_row() {
+            Err(::Error(ErrorKind::InvalidBinaryEncoding(14, "unknown type tag"), _)) => {},
+            x => panic!("expected an invalid encoding, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_results_round_trip() {
+        let scalar = |v| Binding::Scalar(TypedValue::Long(v));
+        let cases = vec![
+            (QueryResults::Scalar(Some(scalar(1))), vec![vec![scalar(1)]]),
+            (QueryResults::Scalar(None), vec![]),
+            (QueryResults::Tuple(Some(vec![scalar(1), scalar(2)])), vec![vec![scalar(1), scalar(2)]]),
+            (QueryResults::Coll(vec![scalar(1), scalar(2)]), vec![vec![scalar(1)], vec![scalar(2)]]),
+            (QueryResults::Rel(vec![vec![scalar(1), scalar(2)], vec![scalar(3), scalar(4)]]),
+             vec![vec![scalar(1), scalar(2)], vec![scalar(3), scalar(4)]]),
+        ];
+        for (results, expected) in cases {
+            let mut out = Vec::new();
+            results.write_binary(&mut out);
+            let mut reader = BinaryReader::new(out.as_slice());
+            assert_eq!(reader.read_results().expect("decoded"), expected);
+            assert!(reader.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_truncated() {
+        let mut out = Vec::new();
+        TypedValue::typed_string("hello").write_binary(&mut out);
+        out.pop();
+
+        let mut reader = BinaryReader::new(out.as_slice());
+        match reader.read_value() {
+            Err(::Error(ErrorKind::InvalidBinaryEncoding(5, "unexpected end of input"), _)) => {},
+            x => panic!("expected an invalid encoding, got {:?}", x),
+        }
+    }
+}
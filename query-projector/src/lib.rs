@@ -19,6 +19,12 @@ extern crate mentat_query_algebrizer;
 extern crate mentat_query_sql;
 extern crate mentat_sql;
 
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+    HashMap,
+};
+use std::collections::hash_map::Entry;
 use std::iter;
 use rusqlite::{
     Row,
@@ -26,37 +32,76 @@ use rusqlite::{
 };
 
 use mentat_core::{
+    Binding,
+    Entid,
+    Schema,
     SQLValueType,
+    StructuredMap,
     TypedValue,
     ValueType,
+    ValueTypeSet,
     ValueTypeTag,
 };
 
 use mentat_db::{
     TypedSQLValue,
+    Xorshift,
 };
 
 use mentat_query::{
     Element,
     FindSpec,
     Limit,
+    NamespacedKeyword,
+    Offset,
+    PlainSymbol,
+    Pull,
     Variable,
 };
 
 use mentat_query_algebrizer::{
     AlgebraicQuery,
     ColumnName,
+    Computation,
     ConjoiningClauses,
+    EmptyBecause,
+    Operand,
+    QualifiedAlias,
     VariableColumn,
 };
 
 use mentat_query_sql::{
     ColumnOrExpression,
+    Expression,
+    GroupBy,
     Name,
     Projection,
     ProjectedColumn,
 };
 
+mod aggregates;
+mod binary;
+mod pull;
+
+use aggregates::{
+    SimpleAggregation,
+    is_rand,
+};
+
+pub use aggregates::{
+    SimpleAggregationOp,
+};
+
+pub use binary::{
+    BinaryEncode,
+    BinaryReader,
+    write_binary_row,
+};
+
+use pull::{
+    PullTemplate,
+};
+
 error_chain! {
     types {
         Error, ErrorKind, ResultExt, Result;
@@ -75,15 +120,55 @@ error_chain! {
             description("unexpected query results type")
             display("expected {}, got {}", expected, actual)
         }
+
+        UnknownAggregateFunction(name: String) {
+            description("unknown aggregate function")
+            display("unknown aggregate function: {}", name)
+        }
+
+        InvalidAggregateArguments(name: String) {
+            description("invalid arguments to aggregate function")
+            display("aggregate function {} takes a single variable argument", name)
+        }
+
+        UnboundVariable(name: PlainSymbol) {
+            description("unbound variable in aggregate")
+            display("unbound variable: {}", name)
+        }
+
+        CannotApplyAggregateOperationToTypes(op: SimpleAggregationOp, types: ValueTypeSet) {
+            description("cannot apply aggregate operation to types")
+            display("cannot apply {:?} to a variable of types {:?}; it must be a long or a double", op, types)
+        }
+
+        UnknownPullAttribute(attribute: NamespacedKeyword) {
+            description("unknown attribute in pull expression")
+            display("cannot pull {}: not an attribute", attribute)
+        }
+
+        CannotPullReverseNonRef(attribute: NamespacedKeyword) {
+            description("cannot pull a reversed non-ref attribute")
+            display("cannot pull {}: only ref attributes can be reversed", attribute)
+        }
+
+        CannotPullNonEntity(name: PlainSymbol) {
+            description("cannot pull from a non-entity")
+            display("cannot pull from {}: it is not bound to an entity", name)
+        }
+
+        InvalidBinaryEncoding(offset: usize, reason: &'static str) {
+            description("invalid binary encoding of query results")
+            display("invalid binary encoding of query results at byte {}: {}", offset, reason)
+        }
     }
 }
 
 #[derive(Debug, PartialEq, Eq)]
 pub enum QueryResults {
-    Scalar(Option<TypedValue>),
-    Tuple(Option<Vec<TypedValue>>),
-    Coll(Vec<TypedValue>),
-    Rel(Vec<Vec<TypedValue>>),
+    Scalar(Option<Binding>),
+    Tuple(Option<Vec<Binding>>),
+    Coll(Vec<Binding>),
+    Rel(Vec<Vec<Binding>>),
 }
 
 impl QueryResults {
@@ -127,7 +212,7 @@ impl QueryResults {
         }
     }
 
-    pub fn into_scalar(self) -> Result<Option<TypedValue>> {
+    pub fn into_scalar(self) -> Result<Option<Binding>> {
         match self {
             QueryResults::Scalar(o) => Ok(o),
             QueryResults::Coll(_) => bail!(ErrorKind::UnexpectedResultsType("coll", "scalar")),
@@ -136,7 +221,7 @@ impl QueryResults {
         }
     }
 
-    pub fn into_coll(self) -> Result<Vec<TypedValue>> {
+    pub fn into_coll(self) -> Result<Vec<Binding>> {
         match self {
             QueryResults::Scalar(_) => bail!(ErrorKind::UnexpectedResultsType("scalar", "coll")),
             QueryResults::Coll(c) => Ok(c),
@@ -145,7 +230,7 @@ impl QueryResults {
         }
     }
 
-    pub fn into_tuple(self) -> Result<Option<Vec<TypedValue>>> {
+    pub fn into_tuple(self) -> Result<Option<Vec<Binding>>> {
         match self {
             QueryResults::Scalar(_) => bail!(ErrorKind::UnexpectedResultsType("scalar", "tuple")),
             QueryResults::Coll(_) => bail!(ErrorKind::UnexpectedResultsType("coll", "tuple")),
@@ -154,7 +239,7 @@ impl QueryResults {
         }
     }
 
-    pub fn into_rel(self) -> Result<Vec<Vec<TypedValue>>> {
+    pub fn into_rel(self) -> Result<Vec<Vec<Binding>>> {
         match self {
             QueryResults::Scalar(_) => bail!(ErrorKind::UnexpectedResultsType("scalar", "rel")),
             QueryResults::Coll(_) => bail!(ErrorKind::UnexpectedResultsType("coll", "rel")),
@@ -162,12 +247,123 @@ impl QueryResults {
             QueryResults::Rel(r) => Ok(r),
         }
     }
+
+    /// Like `into_scalar`, but as a plain value. It's an error if the find spec is a pull
+    /// expression, since its result isn't one.
+    pub fn into_scalar_value(self) -> Result<Option<TypedValue>> {
+        match self.into_scalar()? {
+            Some(binding) => Ok(Some(binding_into_value(binding)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Like `into_coll`, but as plain values. See `into_scalar_value`.
+    pub fn into_coll_values(self) -> Result<Vec<TypedValue>> {
+        self.into_coll()?.into_iter().map(binding_into_value).collect()
+    }
+
+    /// Like `into_tuple`, but as plain values. See `into_scalar_value`.
+    pub fn into_tuple_values(self) -> Result<Option<Vec<TypedValue>>> {
+        match self.into_tuple()? {
+            Some(bindings) => Ok(Some(bindings.into_iter().map(binding_into_value).collect::<Result<Vec<TypedValue>>>()?)),
+            None => Ok(None),
+        }
+    }
+}
+
+fn binding_into_value(binding: Binding) -> Result<TypedValue> {
+    match binding {
+        Binding::Scalar(value) => Ok(value),
+        Binding::Vec(_) => bail!(ErrorKind::UnexpectedResultsType("vector", "value")),
+        Binding::Map(_) => bail!(ErrorKind::UnexpectedResultsType("map", "value")),
+    }
+}
+
+/// The results of running a query, along with what we learned about it on the way.
+#[derive(Debug, PartialEq)]
+pub struct QueryOutput {
+    pub results: QueryResults,
+
+    /// If the query was proven to be empty before it was run -- because it can never match
+    /// anything, rather than because the store happened not to -- this is why.
+    pub proven_empty: Option<EmptyBecause>,
+
+    /// Each variable in the find spec, in the order they're projected, with the types it might
+    /// take. These are known even when there are no results, so that, say, an empty table can
+    /// still be given typed columns. See `projected_columns`.
+    pub columns: Vec<(Variable, ValueTypeSet)>,
+}
+
+impl From<QueryResults> for QueryOutput {
+    fn from(results: QueryResults) -> QueryOutput {
+        QueryOutput {
+            results: results,
+            proven_empty: None,
+            columns: vec![],
+        }
+    }
+}
+
+impl QueryOutput {
+    /// The output of a query that was proven to be empty for the given reason.
+    pub fn proven_empty(spec: &FindSpec, because: EmptyBecause) -> QueryOutput {
+        QueryOutput {
+            results: QueryResults::empty(spec),
+            proven_empty: Some(because),
+            columns: vec![],
+        }
+    }
+
+    pub fn with_columns(mut self, columns: Vec<(Variable, ValueTypeSet)>) -> QueryOutput {
+        self.columns = columns;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.results.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.results.is_empty()
+    }
+
+    pub fn into_scalar(self) -> Result<Option<Binding>> {
+        self.results.into_scalar()
+    }
+
+    pub fn into_coll(self) -> Result<Vec<Binding>> {
+        self.results.into_coll()
+    }
+
+    pub fn into_tuple(self) -> Result<Option<Vec<Binding>>> {
+        self.results.into_tuple()
+    }
+
+    pub fn into_rel(self) -> Result<Vec<Vec<Binding>>> {
+        self.results.into_rel()
+    }
+
+    pub fn into_scalar_value(self) -> Result<Option<TypedValue>> {
+        self.results.into_scalar_value()
+    }
+
+    pub fn into_coll_values(self) -> Result<Vec<TypedValue>> {
+        self.results.into_coll_values()
+    }
+
+    pub fn into_tuple_values(self) -> Result<Option<Vec<TypedValue>>> {
+        self.results.into_tuple_values()
+    }
 }
 
 type Index = i32;            // See rusqlite::RowIndex.
+#[derive(Clone, Copy)]
 enum TypedIndex {
     Known(Index, ValueTypeTag),
     Unknown(Index, Index),
+
+    /// The value and type tag of a variable whose type, rather than value, we want.
+    TypeOf(Index, Index),
 }
 
 impl TypedIndex {
@@ -198,11 +394,79 @@ impl TypedIndex {
                 let value_type_tag: i32 = row.get(type_index);
                 TypedValue::from_sql_value_pair(v, value_type_tag).map_err(|e| e.into())
             },
+            &TypeOf(value_index, type_index) => {
+                // Longs and doubles share a type tag, so we need the value to tell them apart.
+                let v: rusqlite::types::Value = row.get(value_index);
+                let value_type_tag: i32 = row.get(type_index);
+                let value = TypedValue::from_sql_value_pair(v, value_type_tag)?;
+                Ok(TypedValue::from(value.value_type().into_keyword()))
+            },
         }
     }
+
+    fn lookup_binding<'a, 'stmt>(&self, row: &Row<'a, 'stmt>) -> Result<Binding> {
+        self.lookup(row).map(Binding::Scalar)
+    }
+}
+
+/// Look up each of `templates` in the provided row.
+fn project_row<'a, 'stmt>(templates: &[TypedIndex], row: &Row<'a, 'stmt>) -> Result<Vec<TypedValue>> {
+    templates.iter()
+             .map(|ti| ti.lookup(row))
+             .collect()
+}
+
+/// Turns a single SQL row into the values of the find spec's elements, in order. Unlike a
+/// `Projector`, this doesn't need to see every row, so it can be used to stream results.
+#[derive(Clone)]
+pub struct RowProjector {
+    templates: Vec<TypedIndex>,
+}
+
+impl RowProjector {
+    fn with_templates(templates: Vec<TypedIndex>) -> RowProjector {
+        RowProjector {
+            templates: templates,
+        }
+    }
+
+    pub fn project_row<'a, 'stmt>(&self, row: &Row<'a, 'stmt>) -> Result<Vec<TypedValue>> {
+        project_row(self.templates.as_slice(), row)
+    }
+
+    /// Project `row` and append it to `out` in the binary encoding, as `write_binary_row` would.
+    pub fn write_binary_row<'a, 'stmt>(&self, row: &Row<'a, 'stmt>, out: &mut Vec<u8>) -> Result<()> {
+        let values = self.project_row(row)?;
+        write_binary_row(values.as_slice(), out);
+        Ok(())
+    }
 }
 
 fn candidate_column(cc: &ConjoiningClauses, var: &Variable) -> (ColumnOrExpression, Name) {
+    let name = VariableColumn::Variable(var.clone()).column_name();
+
+    // A `get-else` or `get-some` binding reads columns that are NULL when an attribute is absent.
+    if let Some(fallback) = cc.fallback_bindings.get(var) {
+        let mut args: Vec<ColumnOrExpression> =
+            fallback.columns
+                    .iter()
+                    .map(|qa| ColumnOrExpression::Column(qa.clone()))
+                    .chain(fallback.default.iter().map(|v| ColumnOrExpression::Value(v.clone())))
+                    .collect();
+
+        // SQLite insists that COALESCE has at least two arguments.
+        let column = if args.len() == 1 {
+            args.pop().unwrap()
+        } else {
+            ColumnOrExpression::Expression(Box::new(Expression::Coalesce(args)))
+        };
+        return (column, name);
+    }
+
+    if let Some(computation) = cc.computed_bindings.get(var) {
+        return (computed_column(computation), name);
+    }
+
     // Every variable should be bound by the top-level CC to at least
     // one column in the query. If that constraint is violated it's a
     // bug in our code, so it's appropriate to panic here.
@@ -211,10 +475,55 @@ fn candidate_column(cc: &ConjoiningClauses, var: &Variable) -> (ColumnOrExpressi
                     .expect(format!("Every variable should have a binding, but {:?} does not", var).as_str());
 
     let qa = columns[0].clone();
-    let name = VariableColumn::Variable(var.clone()).column_name();
     (ColumnOrExpression::Column(qa), name)
 }
 
+fn operand_column(operand: &Operand) -> ColumnOrExpression {
+    match operand {
+        &Operand::Column(ref qa, _) => ColumnOrExpression::Column(qa.clone()),
+        &Operand::Value(ref v) => ColumnOrExpression::Value(v.clone()),
+    }
+}
+
+/// The SQL expression that yields the value of a computed binding.
+fn computed_column(computation: &Computation) -> ColumnOrExpression {
+    match computation {
+        &Computation::Str(ref operands) => {
+            let args = operands.iter()
+                               .map(|o| {
+                                   let text = Expression::Text(operand_column(o), o.value_type());
+                                   ColumnOrExpression::Expression(Box::new(text))
+                               })
+                               .collect();
+            ColumnOrExpression::Expression(Box::new(Expression::Concat(args)))
+        },
+        &Computation::Arithmetic { operator, ref left, ref right } => {
+            ColumnOrExpression::Expression(Box::new(Expression::Arithmetic {
+                sql_op: operator.to_sql_operator(),
+                left: operand_column(left),
+                right: operand_column(right),
+            }))
+        },
+        // Instants are stored as microseconds since the epoch.
+        &Computation::PlusInstant { ref instant, ref millis } => {
+            let micros = Expression::Arithmetic {
+                sql_op: "*",
+                left: operand_column(millis),
+                right: ColumnOrExpression::Long(1000),
+            };
+            ColumnOrExpression::Expression(Box::new(Expression::Arithmetic {
+                sql_op: "+",
+                left: operand_column(instant),
+                right: ColumnOrExpression::Expression(Box::new(micros)),
+            }))
+        },
+        &Computation::TruncateInstant { ref instant, unit } => {
+            let truncated = Expression::TruncateInstant(operand_column(instant), unit.to_strftime_format());
+            ColumnOrExpression::Expression(Box::new(truncated))
+        },
+    }
+}
+
 fn candidate_type_column(cc: &ConjoiningClauses, var: &Variable) -> (ColumnOrExpression, Name) {
     let extracted_alias = cc.extracted_types
                             .get(var)
@@ -239,10 +548,113 @@ pub fn projected_column_for_var(var: &Variable, cc: &ConjoiningClauses) -> (Proj
     }
 }
 
+/// Only entities can be pulled.
+fn check_pullable(var: &Variable, cc: &ConjoiningClauses) -> Result<()> {
+    match cc.known_type(var) {
+        Some(ValueType::Ref) | None => Ok(()),
+        Some(_) => bail!(ErrorKind::CannotPullNonEntity(var.name())),
+    }
+}
+
+/// The alias given to the subquery over which we aggregate. There's only ever one of these.
+pub const PRE_AGGREGATE_ALIAS: &'static str = "preag";
+
+fn pre_aggregate_column(column: VariableColumn) -> ColumnOrExpression {
+    ColumnOrExpression::Column(QualifiedAlias::new(PRE_AGGREGATE_ALIAS.to_string(), column))
+}
+
+/// The variables projected by the query over which we aggregate, and how to retrieve their
+/// values from its rows.
+struct PreAggregateColumns {
+    vars: Vec<Variable>,
+    types: Vec<Option<ValueType>>,
+    cols: Vec<ProjectedColumn>,
+    templates: Vec<TypedIndex>,
+    next_index: Index,
+}
+
+impl PreAggregateColumns {
+    fn new() -> PreAggregateColumns {
+        PreAggregateColumns {
+            vars: vec![],
+            types: vec![],
+            cols: vec![],
+            templates: vec![],
+            next_index: 0,
+        }
+    }
+
+    /// Add `var` to the projection list, unless it's already present. Returns the position of
+    /// the variable in the list and its type, if known.
+    fn add(&mut self, var: &Variable, cc: &ConjoiningClauses) -> Result<(usize, Option<ValueType>)> {
+        if let Some(position) = self.vars.iter().position(|v| v == var) {
+            return Ok((position, self.types[position]));
+        }
+
+        if cc.bound_value(var).is_none() &&
+           !cc.column_bindings.contains_key(var) &&
+           !cc.fallback_bindings.contains_key(var) &&
+           !cc.computed_bindings.contains_key(var) {
+            bail!(ErrorKind::UnboundVariable(var.name()));
+        }
+
+        let (projected_column, maybe_type) = projected_column_for_var(var, cc);
+        self.cols.push(projected_column);
+        if let Some(ty) = maybe_type {
+            self.templates.push(TypedIndex::Known(self.next_index, ty.value_type_tag()));
+            self.next_index += 1;
+        } else {
+            self.templates.push(TypedIndex::Unknown(self.next_index, self.next_index + 1));
+            self.next_index += 2;
+            let (type_column, type_name) = candidate_type_column(cc, var);
+            self.cols.push(ProjectedColumn(type_column, type_name));
+        }
+
+        self.vars.push(var.clone());
+        self.types.push(maybe_type);
+        Ok((self.vars.len() - 1, maybe_type))
+    }
+}
+
+/// The output of `project_elements`.
+struct ProjectedElements {
+    /// The SQL projection, which should always be a `Projection::Columns`.
+    sql_projection: Projection,
+
+    /// If the query aggregates, the projection of the subquery that feeds the aggregates.
+    pre_aggregate_projection: Option<Projection>,
+
+    /// 'Keys' to use when looking up values in each row.
+    templates: Vec<TypedIndex>,
+
+    /// Columns to group by. Only non-empty if the query aggregates.
+    group_by: Vec<GroupBy>,
+}
+
+impl ProjectedElements {
+    fn take_templates(&mut self) -> Vec<TypedIndex> {
+        ::std::mem::replace(&mut self.templates, vec![])
+    }
+
+    fn combine(self, projector: Box<Projector>, distinct: bool) -> CombinedProjection {
+        CombinedProjection {
+            sql_projection: self.sql_projection,
+            pre_aggregate_projection: self.pre_aggregate_projection,
+            datalog_projector: projector,
+            distinct: distinct,
+            group_by_cols: self.group_by,
+            projector_aggregates: false,
+            random_order: false,
+            row_limit: None,
+        }
+    }
+}
+
 /// Walk an iterator of `Element`s, collecting projector templates and columns.
 ///
-/// Returns a pair: the SQL projection (which should always be a `Projection::Columns`)
-/// and a `Vec` of `TypedIndex` 'keys' to use when looking up values.
+/// If any element is an aggregate, the query itself is run as a subquery: every variable
+/// mentioned in the find spec or `:with` is projected from it, and the outer query groups by
+/// the non-aggregated variables and applies the aggregates.
 ///
 /// Callers must ensure that every `Element` is distinct -- a query like
 ///
@@ -254,19 +666,75 @@ pub fn projected_column_for_var(var: &Variable, cc: &ConjoiningClauses) -> (Proj
 fn project_elements<'a, I: IntoIterator<Item = &'a Element>>(
     count: usize,
     elements: I,
-    query: &AlgebraicQuery) -> Result<(Projection, Vec<TypedIndex>)> {
+    query: &AlgebraicQuery) -> Result<ProjectedElements> {
+
+    let aggregating = query.has_aggregates();
 
     let mut cols = Vec::with_capacity(count);
     let mut i: i32 = 0;
     let mut templates = vec![];
     let mut with = query.with.clone();
 
+    let mut group_by = vec![];
+    let mut inner = PreAggregateColumns::new();
+    let mut extremes = false;
+
     for e in elements {
+        if let &Element::Pull(ref pull) = e {
+            check_pullable(&pull.var, &query.cc)?;
+        }
+
         match e {
+            // A `type-of` binding has no column of its own: we project the value and type tag of
+            // the variable it describes, unless by now we know that variable's type.
+            &Element::Variable(ref var) if !aggregating && query.cc.type_of_bindings.contains_key(var) => {
+                let described = &query.cc.type_of_bindings[var];
+                let name = VariableColumn::Variable(var.clone()).column_name();
+                if let Some(ty) = query.cc.known_type(described) {
+                    let keyword = TypedValue::from(ty.into_keyword());
+                    cols.push(ProjectedColumn(ColumnOrExpression::Value(keyword), name));
+                    templates.push(TypedIndex::Known(i, ValueType::Keyword.value_type_tag()));
+                    i += 1;
+                } else {
+                    let (column, _) = candidate_column(&query.cc, described);
+                    let (type_column, _) = candidate_type_column(&query.cc, described);
+                    let type_name = VariableColumn::VariableTypeTag(var.clone()).column_name();
+                    cols.push(ProjectedColumn(column, name));
+                    cols.push(ProjectedColumn(type_column, type_name));
+                    templates.push(TypedIndex::TypeOf(i, i + 1));
+                    i += 2;
+                }
+            },
+
             // Each time we come across a variable, we push a SQL column
             // into the SQL projection, aliased to the name of the variable,
             // and we push an annotated index into the projector.
-            &Element::Variable(ref var) => {
+            // A pull expression projects its entity; the projector pulls the rest.
+            &Element::Variable(ref var) |
+            &Element::Pull(Pull { ref var, .. }) if aggregating => {
+                with.remove(var);
+
+                // Pass the variable through the subquery, and group by it.
+                let (_, maybe_type) = inner.add(var, &query.cc)?;
+                let name = VariableColumn::Variable(var.clone()).column_name();
+                cols.push(ProjectedColumn(pre_aggregate_column(VariableColumn::Variable(var.clone())), name.clone()));
+                group_by.push(GroupBy::ProjectedColumn(name));
+
+                if let Some(ty) = maybe_type {
+                    templates.push(TypedIndex::Known(i, ty.value_type_tag()));
+                    i += 1;
+                } else {
+                    templates.push(TypedIndex::Unknown(i, i + 1));
+                    i += 2;
+
+                    let type_name = VariableColumn::VariableTypeTag(var.clone()).column_name();
+                    cols.push(ProjectedColumn(pre_aggregate_column(VariableColumn::VariableTypeTag(var.clone())), type_name.clone()));
+                    group_by.push(GroupBy::ProjectedColumn(type_name));
+                }
+            },
+
+            &Element::Variable(ref var) |
+            &Element::Pull(Pull { ref var, .. }) => {
                 // If we're projecting this, we don't need it in :with.
                 with.remove(var);
 
@@ -284,10 +752,45 @@ fn project_elements<'a, I: IntoIterator<Item = &'a Element>>(
                     let (type_column, type_name) = candidate_type_column(&query.cc, &var);
                     cols.push(ProjectedColumn(type_column, type_name));
                 }
-            }
+            },
+
+            &Element::Aggregate(ref aggregate) => {
+                let simple = aggregate.to_simple()?;
+                let result_type = simple.op.result_type(query.cc.known_type_set(&simple.var))?;
+                extremes |= simple.op.is_extreme();
+
+                inner.add(&simple.var, &query.cc)?;
+                let column = pre_aggregate_column(VariableColumn::Variable(simple.var.clone()));
+                let expression = simple.to_expression(column)
+                                       .expect("Aggregates that SQL can't compute are handled by the projector");
+                cols.push(ProjectedColumn(expression, simple.column_name()));
+                templates.push(TypedIndex::Known(i, result_type.value_type_tag()));
+                i += 1;
+            },
         }
     }
 
+    if aggregating {
+        // `:with` variables only need to reach the subquery, where they keep otherwise identical
+        // rows distinct.
+        for var in with {
+            inner.add(&var, &query.cc)?;
+        }
+
+        // The greatest of no values is NULL, which isn't a value of any type. Grouping keeps SQL
+        // from giving us a row at all.
+        if extremes && group_by.is_empty() {
+            group_by.push(GroupBy::Everything);
+        }
+
+        return Ok(ProjectedElements {
+            sql_projection: Projection::Columns(cols),
+            pre_aggregate_projection: Some(Projection::Columns(inner.cols)),
+            templates: templates,
+            group_by: group_by,
+        });
+    }
+
     for var in with {
         // We need to collect these into the SQL column list, but they don't affect projection.
         // If a variable is of a non-fixed type, also project the type tag column, so we don't
@@ -300,11 +803,24 @@ fn project_elements<'a, I: IntoIterator<Item = &'a Element>>(
         }
     }
 
-    Ok((Projection::Columns(cols), templates))
+    Ok(ProjectedElements {
+        sql_projection: Projection::Columns(cols),
+        pre_aggregate_projection: None,
+        templates: templates,
+        group_by: vec![],
+    })
 }
 
 pub trait Projector {
-    fn project<'stmt>(&self, rows: Rows<'stmt>) -> Result<QueryResults>;
+    /// Turn the rows of the SQL query into results. Pull expressions need to fetch more data,
+    /// so the schema and store are provided too.
+    fn project<'stmt>(&self, schema: &Schema, sqlite: &rusqlite::Connection, rows: Rows<'stmt>) -> Result<QueryResults>;
+
+    /// A `RowProjector` that projects each row independently, if this projector's results can be
+    /// computed a row at a time. Aggregates and pull expressions can't be.
+    fn row_projector(&self) -> Option<RowProjector> {
+        None
+    }
 }
 
 /// A projector that produces a `QueryResult` containing fixed data.
@@ -320,7 +836,7 @@ impl ConstantProjector {
 }
 
 impl Projector for ConstantProjector {
-    fn project<'stmt>(&self, _: Rows<'stmt>) -> Result<QueryResults> {
+    fn project<'stmt>(&self, _: &Schema, _: &rusqlite::Connection, _: Rows<'stmt>) -> Result<QueryResults> {
         Ok((self.results_factory)())
     }
 }
@@ -336,26 +852,26 @@ impl ScalarProjector {
         }
     }
 
-    fn combine(sql: Projection, mut templates: Vec<TypedIndex>) -> Result<CombinedProjection> {
-        let template = templates.pop().expect("Expected a single template");
-        Ok(CombinedProjection {
-            sql_projection: sql,
-            datalog_projector: Box::new(ScalarProjector::with_template(template)),
-            distinct: false,
-        })
+    fn combine(mut elements: ProjectedElements) -> Result<CombinedProjection> {
+        let template = elements.templates.pop().expect("Expected a single template");
+        Ok(elements.combine(Box::new(ScalarProjector::with_template(template)), false))
     }
 }
 
 impl Projector for ScalarProjector {
-    fn project<'stmt>(&self, mut rows: Rows<'stmt>) -> Result<QueryResults> {
+    fn project<'stmt>(&self, _: &Schema, _: &rusqlite::Connection, mut rows: Rows<'stmt>) -> Result<QueryResults> {
         if let Some(r) = rows.next() {
             let row = r?;
-            let binding = self.template.lookup(&row)?;
+            let binding = self.template.lookup_binding(&row)?;
             Ok(QueryResults::Scalar(Some(binding)))
         } else {
             Ok(QueryResults::Scalar(None))
         }
     }
+
+    fn row_projector(&self) -> Option<RowProjector> {
+        Some(RowProjector::with_templates(vec![self.template]))
+    }
 }
 
 /// A tuple projector produces a single vector. It's the single-result version of rel.
@@ -373,26 +889,19 @@ impl TupleProjector {
     }
 
     // This is exactly the same as for rel.
-    fn collect_bindings<'a, 'stmt>(&self, row: Row<'a, 'stmt>) -> Result<Vec<TypedValue>> {
+    fn collect_bindings<'a, 'stmt>(&self, row: Row<'a, 'stmt>) -> Result<Vec<Binding>> {
         assert_eq!(row.column_count(), self.len as i32);
-        self.templates
-            .iter()
-            .map(|ti| ti.lookup(&row))
-            .collect::<Result<Vec<TypedValue>>>()
+        project_row(self.templates.as_slice(), &row).map(|values| values.into_iter().map(Binding::Scalar).collect())
     }
 
-    fn combine(column_count: usize, sql: Projection, templates: Vec<TypedIndex>) -> Result<CombinedProjection> {
-        let p = TupleProjector::with_templates(column_count, templates);
-        Ok(CombinedProjection {
-            sql_projection: sql,
-            datalog_projector: Box::new(p),
-            distinct: false,
-        })
+    fn combine(column_count: usize, mut elements: ProjectedElements) -> Result<CombinedProjection> {
+        let p = TupleProjector::with_templates(column_count, elements.take_templates());
+        Ok(elements.combine(Box::new(p), false))
     }
 }
 
 impl Projector for TupleProjector {
-    fn project<'stmt>(&self, mut rows: Rows<'stmt>) -> Result<QueryResults> {
+    fn project<'stmt>(&self, _: &Schema, _: &rusqlite::Connection, mut rows: Rows<'stmt>) -> Result<QueryResults> {
         if let Some(r) = rows.next() {
             let row = r?;
             let bindings = self.collect_bindings(row)?;
@@ -401,6 +910,10 @@ impl Projector for TupleProjector {
             Ok(QueryResults::Tuple(None))
         }
     }
+
+    fn row_projector(&self) -> Option<RowProjector> {
+        Some(RowProjector::with_templates(self.templates.clone()))
+    }
 }
 
 /// A rel projector produces a vector of vectors.
@@ -421,27 +934,20 @@ impl RelProjector {
         }
     }
 
-    fn collect_bindings<'a, 'stmt>(&self, row: Row<'a, 'stmt>) -> Result<Vec<TypedValue>> {
+    fn collect_bindings<'a, 'stmt>(&self, row: Row<'a, 'stmt>) -> Result<Vec<Binding>> {
         assert_eq!(row.column_count(), self.len as i32);
-        self.templates
-            .iter()
-            .map(|ti| ti.lookup(&row))
-            .collect::<Result<Vec<TypedValue>>>()
+        project_row(self.templates.as_slice(), &row).map(|values| values.into_iter().map(Binding::Scalar).collect())
     }
 
-    fn combine(column_count: usize, sql: Projection, templates: Vec<TypedIndex>) -> Result<CombinedProjection> {
-        let p = RelProjector::with_templates(column_count, templates);
-        Ok(CombinedProjection {
-            sql_projection: sql,
-            datalog_projector: Box::new(p),
-            distinct: true,
-        })
+    fn combine(column_count: usize, mut elements: ProjectedElements) -> Result<CombinedProjection> {
+        let p = RelProjector::with_templates(column_count, elements.take_templates());
+        Ok(elements.combine(Box::new(p), true))
     }
 }
 
 impl Projector for RelProjector {
-    fn project<'stmt>(&self, mut rows: Rows<'stmt>) -> Result<QueryResults> {
-        let mut out: Vec<Vec<TypedValue>> = vec![];
+    fn project<'stmt>(&self, _: &Schema, _: &rusqlite::Connection, mut rows: Rows<'stmt>) -> Result<QueryResults> {
+        let mut out: Vec<Vec<Binding>> = vec![];
         while let Some(r) = rows.next() {
             let row = r?;
             let bindings = self.collect_bindings(row)?;
@@ -449,6 +955,10 @@ impl Projector for RelProjector {
         }
         Ok(QueryResults::Rel(out))
     }
+
+    fn row_projector(&self) -> Option<RowProjector> {
+        Some(RowProjector::with_templates(self.templates.clone()))
+    }
 }
 
 /// A coll projector produces a vector of values.
@@ -464,26 +974,340 @@ impl CollProjector {
         }
     }
 
-    fn combine(sql: Projection, mut templates: Vec<TypedIndex>) -> Result<CombinedProjection> {
-        let template = templates.pop().expect("Expected a single template");
-        Ok(CombinedProjection {
-            sql_projection: sql,
-            datalog_projector: Box::new(CollProjector::with_template(template)),
-            distinct: true,
-        })
+    fn combine(mut elements: ProjectedElements) -> Result<CombinedProjection> {
+        let template = elements.templates.pop().expect("Expected a single template");
+        Ok(elements.combine(Box::new(CollProjector::with_template(template)), true))
     }
 }
 
 impl Projector for CollProjector {
-    fn project<'stmt>(&self, mut rows: Rows<'stmt>) -> Result<QueryResults> {
-        let mut out: Vec<TypedValue> = vec![];
+    fn project<'stmt>(&self, _: &Schema, _: &rusqlite::Connection, mut rows: Rows<'stmt>) -> Result<QueryResults> {
+        let mut out: Vec<Binding> = vec![];
         while let Some(r) = rows.next() {
             let row = r?;
-            let binding = self.template.lookup(&row)?;
+            let binding = self.template.lookup_binding(&row)?;
             out.push(binding);
         }
         Ok(QueryResults::Coll(out))
     }
+
+    fn row_projector(&self) -> Option<RowProjector> {
+        Some(RowProjector::with_templates(vec![self.template]))
+    }
+}
+
+/// The kind of `QueryResults` an `AggregatingProjector` produces.
+#[derive(Clone, Copy)]
+enum ResultShape {
+    Scalar,
+    Tuple,
+    Coll,
+    Rel,
+}
+
+/// Where an `AggregatingProjector` finds each value in its output.
+enum AggregateSlot {
+    /// The value of the variable at this position in each row: the results are grouped by it.
+    Group(usize),
+
+    /// The aggregate over the variable at this position.
+    Aggregate(SimpleAggregationOp, usize),
+
+    /// A random double: `(rand)`.
+    Random,
+}
+
+/// A projector that computes aggregates itself, for aggregates that SQLite can't compute. It
+/// consumes the distinct rows of the query, groups them by the non-aggregated variables, and
+/// yields one result per group.
+struct AggregatingProjector {
+    shape: ResultShape,
+    templates: Vec<TypedIndex>,
+    slots: Vec<AggregateSlot>,
+    limit: Option<usize>,
+    offset: usize,
+}
+
+impl AggregatingProjector {
+    fn is_grouped(&self) -> bool {
+        self.slots.iter().any(|slot| match slot {
+            &AggregateSlot::Group(_) => true,
+            &AggregateSlot::Aggregate(_, _) | &AggregateSlot::Random => false,
+        })
+    }
+
+    fn is_random(&self) -> bool {
+        self.slots.iter().any(|slot| match slot {
+            &AggregateSlot::Random => true,
+            _ => false,
+        })
+    }
+
+    fn collect_groups<'stmt>(&self, random: &mut Xorshift, mut rows: Rows<'stmt>) -> Result<Vec<Vec<Binding>>> {
+        // Keep groups in the order in which we first see them, so that `:order` is respected.
+        let mut keys: Vec<Vec<TypedValue>> = vec![];
+        let mut groups: HashMap<Vec<TypedValue>, Vec<Vec<TypedValue>>> = HashMap::new();
+
+        while let Some(r) = rows.next() {
+            let row = r?;
+            let values = project_row(self.templates.as_slice(), &row)?;
+            let key: Vec<TypedValue> = self.slots.iter().filter_map(|slot| match slot {
+                &AggregateSlot::Group(i) => Some(values[i].clone()),
+                &AggregateSlot::Aggregate(_, _) | &AggregateSlot::Random => None,
+            }).collect();
+
+            match groups.entry(key) {
+                Entry::Occupied(mut e) => e.get_mut().push(values),
+                Entry::Vacant(e) => {
+                    keys.push(e.key().clone());
+                    e.insert(vec![values]);
+                },
+            }
+        }
+
+        // Aggregating over nothing still yields a result, unless we're grouping.
+        if keys.is_empty() && !self.is_grouped() {
+            keys.push(vec![]);
+            groups.insert(vec![], vec![]);
+        }
+
+        let mut out = Vec::with_capacity(keys.len());
+        'groups: for key in keys {
+            let rows = groups.remove(&key).expect("Every key has a group");
+            let mut group_values = key.into_iter();
+            let mut result = Vec::with_capacity(self.slots.len());
+            for slot in self.slots.iter() {
+                match slot {
+                    &AggregateSlot::Group(_) => {
+                        result.push(Binding::Scalar(group_values.next().expect("A value for each group slot")));
+                    },
+                    &AggregateSlot::Aggregate(op, i) => {
+                        match op.compute_binding(rows.iter().map(|row| row[i].clone()).collect()) {
+                            Some(v) => result.push(v),
+                            // No value for this aggregate, so no result for this group.
+                            None => continue 'groups,
+                        }
+                    },
+                    &AggregateSlot::Random => {
+                        result.push(Binding::Scalar(TypedValue::from(random.next_f64())));
+                    },
+                }
+            }
+            out.push(result);
+        }
+
+        let skipped = ::std::cmp::min(self.offset, out.len());
+        out.drain(..skipped);
+        if let Some(limit) = self.limit {
+            out.truncate(limit);
+        }
+        Ok(out)
+    }
+}
+
+impl Projector for AggregatingProjector {
+    fn project<'stmt>(&self, _: &Schema, sqlite: &rusqlite::Connection, rows: Rows<'stmt>) -> Result<QueryResults> {
+        // Draw our seed from the store, so that `seed_random` makes `(rand)` reproducible.
+        let seed = if self.is_random() {
+            sqlite.query_row("SELECT random()", &[], |row| row.get::<_, i64>(0))? as u64
+        } else {
+            0
+        };
+        let mut results = self.collect_groups(&mut Xorshift::new(seed), rows)?;
+        Ok(match self.shape {
+            ResultShape::Scalar => QueryResults::Scalar(results.into_iter().next().and_then(|r| r.into_iter().next())),
+            ResultShape::Tuple => QueryResults::Tuple(results.into_iter().next()),
+            ResultShape::Coll => QueryResults::Coll(results.iter_mut().filter_map(|r| r.pop()).collect()),
+            ResultShape::Rel => QueryResults::Rel(results),
+        })
+    }
+}
+
+/// A projector that wraps another, replacing the entities it projects for pull expressions with
+/// maps of their pulled attributes.
+struct PullingProjector {
+    inner: Box<Projector>,
+
+    /// The position of each pull expression in the find spec, and the expression itself.
+    pulls: Vec<(usize, Pull)>,
+}
+
+impl PullingProjector {
+    fn pulled_entity(binding: &Binding, pull: &Pull) -> Result<Entid> {
+        match binding {
+            &Binding::Scalar(TypedValue::Ref(e)) => Ok(e),
+            _ => bail!(ErrorKind::CannotPullNonEntity(pull.var.name())),
+        }
+    }
+
+    /// Pull for every entity in `column` of `rows`, and swap the entities for the results.
+    fn pull_column(&self, schema: &Schema, sqlite: &rusqlite::Connection, column: usize, pull: &Pull, rows: &mut Vec<&mut Vec<Binding>>) -> Result<()> {
+        let template = PullTemplate::new(schema, pull)?;
+        let entities = rows.iter()
+                           .map(|row| PullingProjector::pulled_entity(&row[column], pull))
+                           .collect::<Result<BTreeSet<Entid>>>()?;
+        let mut pulled: BTreeMap<Entid, StructuredMap> = template.pull(schema, sqlite, &entities)?;
+        for row in rows.iter_mut() {
+            let e = PullingProjector::pulled_entity(&row[column], pull)?;
+            // An entity might appear in many rows, so we can't move its map out.
+            let map = pulled.entry(e).or_insert_with(StructuredMap::default).clone();
+            row[column] = Binding::Map(map);
+        }
+        Ok(())
+    }
+}
+
+impl Projector for PullingProjector {
+    fn project<'stmt>(&self, schema: &Schema, sqlite: &rusqlite::Connection, rows: Rows<'stmt>) -> Result<QueryResults> {
+        let mut results = self.inner.project(schema, sqlite, rows)?;
+        for &(column, ref pull) in self.pulls.iter() {
+            match results {
+                QueryResults::Scalar(ref mut o) => {
+                    let mut row: Vec<Binding> = o.take().into_iter().collect();
+                    self.pull_column(schema, sqlite, 0, pull, &mut vec![&mut row])?;
+                    *o = row.pop();
+                },
+                QueryResults::Coll(ref mut values) => {
+                    let mut rows: Vec<Vec<Binding>> = values.drain(..).map(|v| vec![v]).collect();
+                    self.pull_column(schema, sqlite, 0, pull, &mut rows.iter_mut().collect())?;
+                    *values = rows.into_iter().filter_map(|mut row| row.pop()).collect();
+                },
+                QueryResults::Tuple(ref mut o) => {
+                    if let Some(ref mut row) = *o {
+                        self.pull_column(schema, sqlite, column, pull, &mut vec![row])?;
+                    }
+                },
+                QueryResults::Rel(ref mut rows) => {
+                    self.pull_column(schema, sqlite, column, pull, &mut rows.iter_mut().collect())?;
+                },
+            }
+        }
+        Ok(results)
+    }
+}
+
+/// Wrap `projection` so that its projector pulls for any pull expressions in the find spec.
+fn add_pulls(spec: &FindSpec, mut projection: CombinedProjection) -> CombinedProjection {
+    let pulls: Vec<(usize, Pull)> =
+        find_spec_elements(spec).into_iter()
+                                .enumerate()
+                                .filter_map(|(i, element)| match element {
+                                    &Element::Pull(ref pull) => Some((i, pull.clone())),
+                                    _ => None,
+                                })
+                                .collect();
+    if !pulls.is_empty() {
+        projection.datalog_projector = Box::new(PullingProjector {
+            inner: projection.datalog_projector,
+            pulls: pulls,
+        });
+    }
+    projection
+}
+
+fn find_spec_elements(spec: &FindSpec) -> Vec<&Element> {
+    use self::FindSpec::*;
+    match spec {
+        &FindScalar(ref element) | &FindColl(ref element) => vec![element],
+        &FindTuple(ref elements) | &FindRel(ref elements) => elements.iter().collect(),
+    }
+}
+
+/// Returns true if the find spec includes an aggregate that SQLite can't compute for us.
+fn requires_computed_aggregates(spec: &FindSpec) -> Result<bool> {
+    for element in find_spec_elements(spec) {
+        if let &Element::Aggregate(ref aggregate) = element {
+            if is_rand(aggregate)? || aggregate.to_simple()?.op.sql_op().is_none() {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Project a query whose aggregates are computed by an `AggregatingProjector`. Every variable
+/// mentioned in the find spec or `:with` is projected from the distinct rows of the query.
+fn project_computed_aggregates(query: &AlgebraicQuery) -> Result<CombinedProjection> {
+    use self::FindSpec::*;
+
+    let mut inner = PreAggregateColumns::new();
+    let mut slots = vec![];
+    let mut with = query.with.clone();
+    let mut samples = 0;
+
+    for element in find_spec_elements(&query.find_spec) {
+        match element {
+            &Element::Variable(ref var) |
+            &Element::Pull(Pull { ref var, .. }) => {
+                if element.is_pull() {
+                    check_pullable(var, &query.cc)?;
+                }
+                with.remove(var);
+                let (position, _) = inner.add(var, &query.cc)?;
+                slots.push(AggregateSlot::Group(position));
+            },
+            &Element::Aggregate(ref aggregate) if is_rand(aggregate)? => {
+                slots.push(AggregateSlot::Random);
+            },
+            &Element::Aggregate(ref aggregate) => {
+                let simple = aggregate.to_simple()?;
+                if simple.op.is_sample() {
+                    // A sample can hold values of any type.
+                    samples += 1;
+                } else {
+                    simple.op.result_type(query.cc.known_type_set(&simple.var))?;
+                }
+                let (position, _) = inner.add(&simple.var, &query.cc)?;
+                slots.push(AggregateSlot::Aggregate(simple.op, position));
+            },
+        }
+    }
+
+    // If all we're doing is sampling one variable, the sample is just the first few of the
+    // shuffled rows, and SQL might as well stop there.
+    let row_limit = match slots.first() {
+        Some(&AggregateSlot::Aggregate(SimpleAggregationOp::Sample(n), _)) if slots.len() == 1 && with.is_empty() => Some(n as u64),
+        _ => None,
+    };
+
+    for var in with {
+        inner.add(&var, &query.cc)?;
+    }
+
+    let shape = match query.find_spec {
+        FindScalar(_) => ResultShape::Scalar,
+        FindTuple(_) => ResultShape::Tuple,
+        FindColl(_) => ResultShape::Coll,
+        FindRel(_) => ResultShape::Rel,
+    };
+
+    let limit = match query.limit {
+        Limit::Fixed(n) => Some(n as usize),
+        Limit::None | Limit::Variable(_) => None,
+    };
+    let offset = match query.offset {
+        Offset::Fixed(n) => n as usize,
+        Offset::None | Offset::Variable(_) => 0,
+    };
+
+    let projector = AggregatingProjector {
+        shape: shape,
+        templates: inner.templates,
+        slots: slots,
+        limit: limit,
+        offset: offset,
+    };
+
+    Ok(CombinedProjection {
+        sql_projection: Projection::Columns(inner.cols),
+        pre_aggregate_projection: None,
+        datalog_projector: Box::new(projector),
+        distinct: true,
+        group_by_cols: vec![],
+        projector_aggregates: true,
+        random_order: samples > 0,
+        row_limit: row_limit,
+    })
 }
 
 /// Combines the two things you need to turn a query into SQL and turn its results into
@@ -493,17 +1317,39 @@ pub struct CombinedProjection {
     /// output.
     pub sql_projection: Projection,
 
+    /// If present, the query aggregates: the body of the query should be run as a subquery with
+    /// this projection, and `sql_projection` applied to its results.
+    pub pre_aggregate_projection: Option<Projection>,
+
     /// A Datalog projection. This consumes rows of the appropriate shape (as defined by
     /// the SQL projection) to yield one of the four kinds of Datalog query result.
     pub datalog_projector: Box<Projector>,
 
     /// True if this query requires the SQL query to include DISTINCT.
     pub distinct: bool,
+
+    /// The columns, if any, by which to group the results of an aggregating query.
+    pub group_by_cols: Vec<GroupBy>,
+
+    /// True if the Datalog projector computes aggregates itself. The SQL query must then return
+    /// every row, so any limit is applied by the projector rather than in SQL.
+    pub projector_aggregates: bool,
+
+    /// True if the SQL query should return its rows in random order, after any `:order`. This is
+    /// how we sample.
+    pub random_order: bool,
+
+    /// The number of rows the Datalog projector needs, if it doesn't need every row.
+    pub row_limit: Option<u64>,
 }
 
 impl CombinedProjection {
-    fn flip_distinct_for_limit(mut self, limit: &Limit) -> Self {
-        if *limit == Limit::Fixed(1) {
+    /// A single result can't have duplicates, so it needs no `DISTINCT`… unless we're skipping
+    /// results, in which case duplicates would count towards the offset.
+    fn flip_distinct_for_limit(mut self, limit: &Limit, offset: &Offset) -> Self {
+        if *offset != Offset::None {
+            self.distinct = true;
+        } else if *limit == Limit::Fixed(1) {
             self.distinct = false;
         }
         self
@@ -513,11 +1359,26 @@ impl CombinedProjection {
 /// Compute a suitable SQL projection for an algebrized query.
 /// This takes into account a number of things:
 /// - The variable list in the find spec.
-/// - The presence of any aggregate operations in the find spec. TODO: for now we only handle
-///   simple variables
+/// - The presence of any aggregate operations in the find spec.
 /// - The bindings established by the topmost CC.
 /// - The types known at algebrizing time.
 /// - The types extracted from the store for unknown attributes.
+/// Each variable in the find spec of `query`, in the order they're projected, with the types the
+/// algebrizer inferred for it. A variable whose type the query doesn't pin down has more than one
+/// type in its set. Pull expressions and aggregates aren't included.
+pub fn projected_columns(query: &AlgebraicQuery) -> Vec<(Variable, ValueTypeSet)> {
+    let elements: Vec<&Element> = match query.find_spec {
+        FindSpec::FindScalar(ref elem) | FindSpec::FindColl(ref elem) => vec![elem],
+        FindSpec::FindTuple(ref elems) | FindSpec::FindRel(ref elems) => elems.iter().collect(),
+    };
+    elements.into_iter()
+            .filter_map(|elem| match elem {
+                &Element::Variable(ref var) => Some((var.clone(), query.cc.known_type_set(var))),
+                _ => None,
+            })
+            .collect()
+}
+
 pub fn query_projection(query: &AlgebraicQuery) -> Result<CombinedProjection> {
     use self::FindSpec::*;
 
@@ -527,32 +1388,40 @@ pub fn query_projection(query: &AlgebraicQuery) -> Result<CombinedProjection> {
         let constant_projector = ConstantProjector::new(empty);
         Ok(CombinedProjection {
             sql_projection: Projection::One,
+            pre_aggregate_projection: None,
             datalog_projector: Box::new(constant_projector),
             distinct: false,
+            group_by_cols: vec![],
+            projector_aggregates: false,
+            random_order: false,
+            row_limit: None,
         })
+    } else if requires_computed_aggregates(&query.find_spec)? {
+        project_computed_aggregates(query).map(|p| add_pulls(&query.find_spec, p))
     } else {
-        match query.find_spec {
+        let projection = match query.find_spec {
             FindColl(ref element) => {
-                let (cols, templates) = project_elements(1, iter::once(element), query)?;
-                CollProjector::combine(cols, templates).map(|p| p.flip_distinct_for_limit(&query.limit))
+                let elements = project_elements(1, iter::once(element), query)?;
+                CollProjector::combine(elements).map(|p| p.flip_distinct_for_limit(&query.limit, &query.offset))
             },
 
             FindScalar(ref element) => {
-                let (cols, templates) = project_elements(1, iter::once(element), query)?;
-                ScalarProjector::combine(cols, templates)
+                let elements = project_elements(1, iter::once(element), query)?;
+                ScalarProjector::combine(elements).map(|p| p.flip_distinct_for_limit(&query.limit, &query.offset))
             },
 
             FindRel(ref elements) => {
                 let column_count = query.find_spec.expected_column_count();
-                let (cols, templates) = project_elements(column_count, elements, query)?;
-                RelProjector::combine(column_count, cols, templates).map(|p| p.flip_distinct_for_limit(&query.limit))
+                let elements = project_elements(column_count, elements, query)?;
+                RelProjector::combine(column_count, elements).map(|p| p.flip_distinct_for_limit(&query.limit, &query.offset))
             },
 
             FindTuple(ref elements) => {
                 let column_count = query.find_spec.expected_column_count();
-                let (cols, templates) = project_elements(column_count, elements, query)?;
-                TupleProjector::combine(column_count, cols, templates)
+                let elements = project_elements(column_count, elements, query)?;
+                TupleProjector::combine(column_count, elements).map(|p| p.flip_distinct_for_limit(&query.limit, &query.offset))
             },
-        }
+        };
+        projection.map(|p| add_pulls(&query.find_spec, p))
     }
 }
@@ -0,0 +1,242 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
+
+use rusqlite;
+
+use mentat_core::{
+    Binding,
+    Entid,
+    Schema,
+    SQLValueType,
+    StructuredMap,
+    TypedValue,
+    ValueType,
+};
+
+use mentat_db::{
+    TypedSQLValue,
+};
+
+use mentat_query::{
+    NamespacedKeyword,
+    Pull,
+    PullAttributeSpec,
+};
+
+use super::{
+    ErrorKind,
+    Result,
+};
+
+/// How many entities to fetch in a single SQL query.
+const PULL_BATCH_SIZE: usize = 500;
+
+/// A pull pattern, resolved against a schema.
+pub struct PullTemplate {
+    /// True if the pattern includes `*`.
+    wildcard: bool,
+
+    /// The attributes to pull, and the keyword under which to file each one.
+    attributes: BTreeMap<Entid, NamespacedKeyword>,
+
+    /// The ref attributes to follow backwards, keyed to their reversed keyword.
+    reversed: BTreeMap<Entid, NamespacedKeyword>,
+}
+
+impl PullTemplate {
+    pub fn new(schema: &Schema, pull: &Pull) -> Result<PullTemplate> {
+        let mut template = PullTemplate {
+            wildcard: false,
+            attributes: BTreeMap::new(),
+            reversed: BTreeMap::new(),
+        };
+
+        for spec in pull.patterns.iter() {
+            match spec {
+                &PullAttributeSpec::Wildcard => {
+                    template.wildcard = true;
+                },
+                &PullAttributeSpec::Attribute(ref keyword) if keyword.is_backward() => {
+                    let forward = keyword.unreversed().expect("A backward keyword can be unreversed");
+                    match schema.get_entid(&forward).and_then(|e| schema.attribute_for_entid(e).map(|a| (e, a))) {
+                        Some((e, attribute)) if attribute.value_type == ValueType::Ref => {
+                            template.reversed.insert(e, keyword.clone());
+                        },
+                        Some(_) => bail!(ErrorKind::CannotPullReverseNonRef(keyword.clone())),
+                        None => bail!(ErrorKind::UnknownPullAttribute(keyword.clone())),
+                    }
+                },
+                &PullAttributeSpec::Attribute(ref keyword) => {
+                    match schema.get_entid(keyword) {
+                        Some(e) if schema.is_attribute(e) => {
+                            template.attributes.insert(e, keyword.clone());
+                        },
+                        _ => bail!(ErrorKind::UnknownPullAttribute(keyword.clone())),
+                    }
+                },
+            }
+        }
+
+        Ok(template)
+    }
+
+    /// Fetch the pulled attributes of each of `entities`. Entities with none of the attributes --
+    /// and attributes an entity doesn't have -- are simply absent from the result.
+    pub fn pull(&self, schema: &Schema, sqlite: &rusqlite::Connection, entities: &BTreeSet<Entid>) -> Result<BTreeMap<Entid, StructuredMap>> {
+        let mut out = self.fetch(schema, sqlite, entities)?;
+
+        // A component is part of the entity that owns it, so a wildcard pulls it too.
+        if self.wildcard {
+            for (e, map) in out.iter_mut() {
+                let mut ancestors = vec![*e];
+                expand_components(schema, sqlite, map, &mut ancestors)?;
+            }
+        }
+
+        Ok(out)
+    }
+
+    /// The pulled attributes of each of `entities`, with components left as refs.
+    fn fetch(&self, schema: &Schema, sqlite: &rusqlite::Connection, entities: &BTreeSet<Entid>) -> Result<BTreeMap<Entid, StructuredMap>> {
+        let mut out: BTreeMap<Entid, StructuredMap> = BTreeMap::new();
+        let entities: Vec<Entid> = entities.iter().cloned().collect();
+
+        for batch in entities.chunks(PULL_BATCH_SIZE) {
+            // Entids are integers, so it's safe to interpolate them.
+            let entity_list = join_entids(batch.iter());
+
+            if self.wildcard || !self.attributes.is_empty() {
+                let sql = if self.wildcard {
+                    format!("SELECT e, a, v, value_type_tag FROM all_datoms WHERE e IN ({}) ORDER BY e, a, v",
+                            entity_list)
+                } else {
+                    format!("SELECT e, a, v, value_type_tag FROM all_datoms WHERE e IN ({}) AND a IN ({}) ORDER BY e, a, v",
+                            entity_list, join_entids(self.attributes.keys()))
+                };
+
+                let mut stmt = sqlite.prepare(sql.as_str())?;
+                let mut rows = stmt.query(&[])?;
+                while let Some(r) = rows.next() {
+                    let row = r?;
+                    let e: Entid = row.get(0);
+                    let a: Entid = row.get(1);
+                    let v: rusqlite::types::Value = row.get(2);
+                    let value_type_tag: i32 = row.get(3);
+
+                    let keyword = match self.attributes.get(&a) {
+                        Some(keyword) => keyword.clone(),
+                        None => match schema.get_ident(a) {
+                            Some(keyword) => keyword.clone(),
+                            // Nothing to call it, so nothing to pull.
+                            None => continue,
+                        },
+                    };
+                    let multival = schema.attribute_for_entid(a).map_or(false, |a| a.multival);
+                    let value = TypedValue::from_sql_value_pair(v, value_type_tag)?;
+
+                    let map = out.entry(e).or_insert_with(StructuredMap::default);
+                    if multival {
+                        push_value(map, keyword, value);
+                    } else {
+                        map.insert(keyword, value);
+                    }
+                }
+            }
+
+            if !self.reversed.is_empty() {
+                let sql = format!("SELECT v, a, e FROM datoms WHERE v IN ({}) AND a IN ({}) AND value_type_tag = {} ORDER BY v, a, e",
+                                  entity_list,
+                                  join_entids(self.reversed.keys()),
+                                  ValueType::Ref.value_type_tag());
+
+                let mut stmt = sqlite.prepare(sql.as_str())?;
+                let mut rows = stmt.query(&[])?;
+                while let Some(r) = rows.next() {
+                    let row = r?;
+                    let v: Entid = row.get(0);
+                    let a: Entid = row.get(1);
+                    let e: Entid = row.get(2);
+
+                    let keyword = self.reversed.get(&a).expect("Only reversed attributes were fetched").clone();
+                    let map = out.entry(v).or_insert_with(StructuredMap::default);
+
+                    // Any number of entities can refer to this one.
+                    push_value(map, keyword, TypedValue::Ref(e));
+                }
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+/// Replace each component ref in `map` with the wildcard pull of the component, recursively.
+/// `ancestors` are the entities we're already expanding: a component that refers back to one of
+/// them is left as a ref, so that a cycle of components can't expand forever.
+fn expand_components(schema: &Schema, sqlite: &rusqlite::Connection, map: &mut StructuredMap, ancestors: &mut Vec<Entid>) -> Result<()> {
+    for (keyword, binding) in map.0.iter_mut() {
+        let is_component = schema.get_entid(keyword)
+                                 .and_then(|e| schema.attribute_for_entid(e))
+                                 .map_or(false, |a| a.component);
+        if !is_component {
+            continue;
+        }
+        match binding {
+            &mut Binding::Vec(ref mut values) => {
+                for value in values.iter_mut() {
+                    expand_component(schema, sqlite, value, ancestors)?;
+                }
+            },
+            value => expand_component(schema, sqlite, value, ancestors)?,
+        }
+    }
+    Ok(())
+}
+
+fn expand_component(schema: &Schema, sqlite: &rusqlite::Connection, value: &mut Binding, ancestors: &mut Vec<Entid>) -> Result<()> {
+    let e = match value {
+        &mut Binding::Scalar(TypedValue::Ref(e)) if !ancestors.contains(&e) => e,
+        _ => return Ok(()),
+    };
+
+    let wildcard = PullTemplate {
+        wildcard: true,
+        attributes: BTreeMap::new(),
+        reversed: BTreeMap::new(),
+    };
+    let mut entities = BTreeSet::new();
+    entities.insert(e);
+
+    // An entity with no attributes at all is still just a ref.
+    if let Some(mut component) = wildcard.fetch(schema, sqlite, &entities)?.remove(&e) {
+        ancestors.push(e);
+        expand_components(schema, sqlite, &mut component, ancestors)?;
+        ancestors.pop();
+        *value = Binding::Map(component);
+    }
+    Ok(())
+}
+
+fn join_entids<'a, I>(entids: I) -> String where I: Iterator<Item=&'a Entid> {
+    entids.map(|e| e.to_string()).collect::<Vec<String>>().join(", ")
+}
+
+fn push_value(map: &mut StructuredMap, keyword: NamespacedKeyword, value: TypedValue) {
+    match map.0.entry(keyword).or_insert_with(|| Binding::Vec(vec![])) {
+        &mut Binding::Vec(ref mut values) => values.push(Binding::Scalar(value)),
+        // A keyword is only ever used for one attribute, and so is always multi-valued or not.
+        _ => unreachable!(),
+    }
+}
@@ -15,20 +15,25 @@ extern crate mentat_query_algebrizer;
 extern crate mentat_sql;
 
 use std::boxed::Box;
+use std::rc::Rc;
 
 use mentat_core::{
     Entid,
     TypedValue,
+    ValueType,
 };
 
 use mentat_query::{
     Direction,
     Limit,
+    Offset,
     Variable,
 };
 
 use mentat_query_algebrizer::{
     Column,
+    DatomsTable,
+    FulltextColumn,
     OrderBy,
     QualifiedAlias,
     QueryValue,
@@ -60,6 +65,47 @@ pub enum ColumnOrExpression {
     Integer(i32),       // We use these for type codes etc.
     Long(i64),
     Value(TypedValue),
+    Parameter(Variable), // An input, bound when the query is run.
+    Expression(Box<Expression>),
+}
+
+/// SQL expressions that aren't simple columns or values.
+pub enum Expression {
+    /// An aggregate function call, like `COUNT(DISTINCT datoms00.v)`.
+    Aggregate {
+        sql_op: &'static str,
+        distinct: bool,
+        arg: ColumnOrExpression,
+    },
+
+    /// The first of its arguments that isn't NULL, like `COALESCE(datoms01.v, 'N/A')`.
+    Coalesce(Vec<ColumnOrExpression>),
+
+    /// Its arguments joined end to end, like `(datoms00.v || ' ' || datoms01.v)`.
+    Concat(Vec<ColumnOrExpression>),
+
+    /// The string form of an argument of the given type, like `CAST(datoms00.v AS TEXT)`.
+    Text(ColumnOrExpression, ValueType),
+
+    /// A binary arithmetic operation, like `(datoms00.v * 1.5e0)`.
+    Arithmetic {
+        sql_op: &'static str,
+        left: ColumnOrExpression,
+        right: ColumnOrExpression,
+    },
+
+    /// An instant, in microseconds since the epoch, truncated by formatting it with `strftime` and
+    /// reading it back, like
+    /// `(CAST(strftime('%s', strftime('%Y-%m-%d', datoms00.v / 1000000.0, 'unixepoch')) AS INTEGER) * 1000000)`.
+    TruncateInstant(ColumnOrExpression, &'static str),
+
+    /// The depth of a row derived by the step of a recursive rule: one more than that of the
+    /// `derived` row it was derived from. Past `limit`, this fails, and so does the query.
+    RuleDepth {
+        derived: TableAlias,
+        limit: usize,
+        rule: String,
+    },
 }
 
 /// `QueryValue` and `ColumnOrExpression` are almost identical… merge somehow?
@@ -70,6 +116,7 @@ impl From<QueryValue> for ColumnOrExpression {
             QueryValue::Entid(e) => ColumnOrExpression::Entid(e),
             QueryValue::PrimitiveLong(v) => ColumnOrExpression::Long(v),
             QueryValue::TypedValue(v) => ColumnOrExpression::Value(v),
+            QueryValue::Parameter(var) => ColumnOrExpression::Parameter(var),
         }
     }
 }
@@ -105,7 +152,20 @@ pub enum Constraint {
     },
     NotExists {
         subquery: TableOrSubquery,
-    }
+    },
+    NotNull {
+        value: ColumnOrExpression,
+    },
+    Like {
+        value: ColumnOrExpression,
+        pattern: ColumnOrExpression,
+        escape: Option<ColumnOrExpression>,
+    },
+    Between {
+        value: ColumnOrExpression,
+        lower: ColumnOrExpression,
+        upper: ColumnOrExpression,
+    },
 }
 
 impl Constraint {
@@ -134,9 +194,9 @@ impl Constraint {
     }
 }
 
-#[allow(dead_code)]
-enum JoinOp {
+pub enum JoinOp {
     Inner,
+    LeftOuter,
 }
 
 // Short-hand for a list of tables all inner-joined.
@@ -148,19 +208,27 @@ impl TableList {
     }
 }
 
+/// A table joined to everything to its left, like `LEFT JOIN datoms AS datoms02 ON …`.
 pub struct Join {
-    left: TableOrSubquery,
-    op: JoinOp,
-    right: TableOrSubquery,
-    // TODO: constraints (ON, USING).
+    pub op: JoinOp,
+    pub right: TableOrSubquery,
+    pub on: Vec<Constraint>,
 }
 
 #[allow(dead_code)]
 pub enum TableOrSubquery {
     Table(SourceAlias),
+
+    /// A table in the attached database of the given name.
+    AttachedTable(String, SourceAlias),
     Union(Vec<SelectQuery>, TableAlias),
     Subquery(Box<SelectQuery>),
+    AliasedSubquery(Box<SelectQuery>, TableAlias),
     Values(Values, TableAlias),
+
+    /// The distinct rows of a recursive CTE with the given columns, to which each member adds
+    /// a depth. The members after the first can refer to the rows derived so far as `rule`.
+    RecursiveUnion(Vec<String>, Vec<SelectQuery>, TableAlias),
 }
 
 pub enum Values {
@@ -176,7 +244,7 @@ pub enum Values {
 
 pub enum FromClause {
     TableList(TableList),      // Short-hand for a pile of inner joins.
-    Join(Join),
+    Joins(TableList, Vec<Join>),
     Nothing,
 }
 
@@ -185,8 +253,22 @@ pub struct SelectQuery {
     pub projection: Projection,
     pub from: FromClause,
     pub constraints: Vec<Constraint>,
+    pub group_by: Vec<GroupBy>,
     pub order: Vec<OrderBy>,
+
+    /// Shuffle rows that `order` doesn't distinguish, with `ORDER BY random()`.
+    pub random_order: bool,
     pub limit: Limit,
+    pub offset: Offset,
+}
+
+/// Something to group by. We group by projected columns, so these are names.
+pub enum GroupBy {
+    ProjectedColumn(Name),
+
+    /// `GROUP BY NULL`: every row in one group. Unlike an ungrouped aggregate query, this has no
+    /// result at all, rather than a row of NULLs, when there are no rows.
+    Everything,
 }
 
 fn push_variable_column(qb: &mut QueryBuilder, vc: &VariableColumn) -> BuildQueryResult {
@@ -214,6 +296,18 @@ fn push_column(qb: &mut QueryBuilder, col: &Column) -> BuildQueryResult {
     }
 }
 
+/// FTS4 has neither `rank` nor `bm25`, so we score a match by counting the matched terms reported
+/// by `offsets`, which yields four integers per match. This is deterministic: identical matches
+/// always get identical scores.
+fn push_fulltext_score(qb: &mut QueryBuilder, table: &TableAlias) -> BuildQueryResult {
+    qb.push_sql("((length(offsets(");
+    qb.push_identifier(table.as_str())?;
+    qb.push_sql(")) - length(replace(offsets(");
+    qb.push_identifier(table.as_str())?;
+    qb.push_sql("), ' ', '')) + 1) / 4e0)");
+    Ok(())
+}
+
 //---------------------------------------------------------
 // Turn that representation into SQL.
 
@@ -257,6 +351,9 @@ impl QueryFragment for ColumnOrExpression {
     fn push_sql(&self, out: &mut QueryBuilder) -> BuildQueryResult {
         use self::ColumnOrExpression::*;
         match self {
+            &Column(QualifiedAlias(ref table, ::mentat_query_algebrizer::Column::Fulltext(FulltextColumn::Score))) => {
+                push_fulltext_score(out, table)
+            },
             &Column(QualifiedAlias(ref table, ref column)) => {
                 out.push_identifier(table.as_str())?;
                 out.push_sql(".");
@@ -277,6 +374,118 @@ impl QueryFragment for ColumnOrExpression {
             &Value(ref v) => {
                 out.push_typed_value(v)
             },
+            &Parameter(ref var) => {
+                push_variable_param(out, var)
+            },
+            &Expression(ref e) => {
+                e.push_sql(out)
+            },
+        }
+    }
+}
+
+impl QueryFragment for Expression {
+    fn push_sql(&self, out: &mut QueryBuilder) -> BuildQueryResult {
+        match self {
+            &Expression::Aggregate { sql_op, distinct, ref arg } => {
+                out.push_sql(sql_op);
+                if distinct {
+                    out.push_sql("(DISTINCT ");
+                } else {
+                    out.push_sql("(");
+                }
+                arg.push_sql(out)?;
+                out.push_sql(")");
+                Ok(())
+            },
+            &Expression::Coalesce(ref args) => {
+                out.push_sql("COALESCE(");
+                interpose!(arg, args,
+                           { arg.push_sql(out)? },
+                           { out.push_sql(", ") });
+                out.push_sql(")");
+                Ok(())
+            },
+            &Expression::Concat(ref args) => {
+                out.push_sql("(");
+                interpose!(arg, args,
+                           { arg.push_sql(out)? },
+                           { out.push_sql(" || ") });
+                out.push_sql(")");
+                Ok(())
+            },
+            &Expression::Text(ref arg, value_type) => {
+                match value_type {
+                    // Keywords are stored with their leading colon.
+                    ValueType::String |
+                    ValueType::Keyword => arg.push_sql(out)?,
+                    ValueType::Boolean => {
+                        out.push_sql("CASE WHEN ");
+                        arg.push_sql(out)?;
+                        out.push_sql(" THEN 'true' ELSE 'false' END");
+                    },
+                    // Instants are stored as microseconds since the epoch.
+                    ValueType::Instant => {
+                        out.push_sql("strftime('%Y-%m-%dT%H:%M:%fZ', ");
+                        arg.push_sql(out)?;
+                        out.push_sql(" / 1000000.0, 'unixepoch')");
+                    },
+                    _ => {
+                        out.push_sql("CAST(");
+                        arg.push_sql(out)?;
+                        out.push_sql(" AS TEXT)");
+                    },
+                }
+                Ok(())
+            },
+            &Expression::Arithmetic { sql_op, ref left, ref right } => {
+                out.push_sql("(");
+                left.push_sql(out)?;
+                out.push_sql(" ");
+                out.push_sql(sql_op);
+                out.push_sql(" ");
+                right.push_sql(out)?;
+                out.push_sql(")");
+                Ok(())
+            },
+            &Expression::TruncateInstant(ref arg, format) => {
+                // The formats are our own, and contain no quotes.
+                out.push_sql("(CAST(strftime('%s', strftime('");
+                out.push_sql(format);
+                out.push_sql("', ");
+                arg.push_sql(out)?;
+                out.push_sql(" / 1000000.0, 'unixepoch')) AS INTEGER) * 1000000)");
+                Ok(())
+            },
+            &Expression::RuleDepth { ref derived, limit, ref rule } => {
+                let limit = limit.to_string();
+                out.push_sql("CASE WHEN ");
+                out.push_identifier(derived.as_str())?;
+                out.push_sql(".depth < ");
+                out.push_sql(limit.as_str());
+                out.push_sql(" THEN ");
+                out.push_identifier(derived.as_str())?;
+                out.push_sql(".depth + 1 ELSE rule_depth_exceeded(");
+                out.push_typed_value(&TypedValue::String(Rc::new(rule.clone())))?;
+                out.push_sql(", ");
+                out.push_sql(limit.as_str());
+                out.push_sql(") END");
+                Ok(())
+            },
+        }
+    }
+}
+
+impl QueryFragment for GroupBy {
+    fn push_sql(&self, out: &mut QueryBuilder) -> BuildQueryResult {
+        match self {
+            &GroupBy::ProjectedColumn(ref name) => {
+                out.push_identifier(name.as_str())
+            },
+            &GroupBy::Everything => {
+                out.push_sql("NULL");
+                Ok(())
+            },
         }
     }
 }
@@ -367,14 +576,42 @@ impl QueryFragment for Constraint {
                 subquery.push_sql(out)?;
                 out.push_sql(")");
                 Ok(())
-            }
+            },
+
+            &NotNull { ref value } => {
+                value.push_sql(out)?;
+                out.push_sql(" IS NOT NULL");
+                Ok(())
+            },
+
+            &Like { ref value, ref pattern, ref escape } => {
+                value.push_sql(out)?;
+                out.push_sql(" LIKE ");
+                pattern.push_sql(out)?;
+                if let &Some(ref escape) = escape {
+                    out.push_sql(" ESCAPE ");
+                    escape.push_sql(out)?;
+                }
+                Ok(())
+            },
+
+            &Between { ref value, ref lower, ref upper } => {
+                value.push_sql(out)?;
+                out.push_sql(" BETWEEN ");
+                lower.push_sql(out)?;
+                out.push_sql(" AND ");
+                upper.push_sql(out)
+            },
         }
     }
 }
 
 impl QueryFragment for JoinOp {
     fn push_sql(&self, out: &mut QueryBuilder) -> BuildQueryResult {
-        out.push_sql(" JOIN ");
+        match self {
+            &JoinOp::Inner => out.push_sql(" JOIN "),
+            &JoinOp::LeftOuter => out.push_sql(" LEFT JOIN "),
+        }
         Ok(())
     }
 }
@@ -402,9 +639,15 @@ impl QueryFragment for TableList {
 
 impl QueryFragment for Join {
     fn push_sql(&self, out: &mut QueryBuilder) -> BuildQueryResult {
-        self.left.push_sql(out)?;
         self.op.push_sql(out)?;
-        self.right.push_sql(out)
+        self.right.push_sql(out)?;
+        if !self.on.is_empty() {
+            out.push_sql(" ON ");
+            interpose!(constraint, self.on,
+                       { constraint.push_sql(out)? },
+                       { out.push_sql(" AND ") });
+        }
+        Ok(())
     }
 }
 
@@ -413,6 +656,11 @@ impl QueryFragment for TableOrSubquery {
         use self::TableOrSubquery::*;
         match self {
             &Table(ref sa) => source_alias_push_sql(out, sa),
+            &AttachedTable(ref database, ref sa) => {
+                out.push_identifier(database.as_str())?;
+                out.push_sql(".");
+                source_alias_push_sql(out, sa)
+            },
             &Union(ref subqueries, ref table_alias) => {
                 out.push_sql("(");
                 interpose!(subquery, subqueries,
@@ -424,6 +672,12 @@ impl QueryFragment for TableOrSubquery {
             &Subquery(ref subquery) => {
                 subquery.push_sql(out)
             },
+            &AliasedSubquery(ref subquery, ref table_alias) => {
+                out.push_sql("(");
+                subquery.push_sql(out)?;
+                out.push_sql(") AS ");
+                out.push_identifier(table_alias.as_str())
+            },
             &Values(ref values, ref table_alias) => {
                 // XXX: does this work for Values::Unnamed?
                 out.push_sql("(");
@@ -431,6 +685,29 @@ impl QueryFragment for TableOrSubquery {
                 out.push_sql(") AS ");
                 out.push_identifier(table_alias.as_str())
             },
+            &RecursiveUnion(ref columns, ref members, ref table_alias) => {
+                // The CTE is only visible within this subquery, so recursive rules within recursive
+                // rules can all use the same name.
+                let name = DatomsTable::Rule.name();
+                out.push_sql("(WITH RECURSIVE ");
+                out.push_identifier(name)?;
+                out.push_sql("(");
+                interpose!(column, columns,
+                           { out.push_identifier(column.as_str())? },
+                           { out.push_sql(", ") });
+                out.push_sql(", depth) AS (");
+                interpose!(member, members,
+                           { member.push_sql(out)? },
+                           { out.push_sql(" UNION ") });
+                out.push_sql(") SELECT DISTINCT ");
+                interpose!(column, columns,
+                           { out.push_identifier(column.as_str())? },
+                           { out.push_sql(", ") });
+                out.push_sql(" FROM ");
+                out.push_identifier(name)?;
+                out.push_sql(") AS ");
+                out.push_identifier(table_alias.as_str())
+            },
         }
     }
 }
@@ -488,26 +765,32 @@ impl QueryFragment for FromClause {
                     table_list.push_sql(out)
                 }
             },
-            &Join(ref join) => {
+            &Joins(ref table_list, ref joins) => {
                 out.push_sql(" FROM ");
-                join.push_sql(out)
+                table_list.push_sql(out)?;
+                for join in joins.iter() {
+                    join.push_sql(out)?;
+                }
+                Ok(())
             },
             &Nothing => Ok(()),
         }
     }
 }
 
-impl SelectQuery {
-    fn push_variable_param(&self, var: &Variable, out: &mut QueryBuilder) -> BuildQueryResult {
-        // `var` is something like `?foo99-people`.
-        // Trim the `?` and escape the rest. Prepend `i` to distinguish from
-        // the inline value space `v`.
-        let re = regex::Regex::new("[^a-zA-Z_0-9]").unwrap();
-        let without_question = var.as_str().split_at(1).1;
-        let replaced = re.replace_all(without_question, "_");
-        let bind_param = format!("i{}", replaced);                 // We _could_ avoid this copying.
-        out.push_bind_param(bind_param.as_str())
-    }
+/// The name of the bind parameter for the input `var`, without its leading `$`.
+pub fn variable_param_name(var: &Variable) -> String {
+    // `var` is something like `?foo99-people`.
+    // Trim the `?` and escape the rest. Prepend `i` to distinguish from
+    // the inline value space `v`.
+    let re = regex::Regex::new("[^a-zA-Z_0-9]").unwrap();
+    let without_question = var.as_str().split_at(1).1;
+    let replaced = re.replace_all(without_question, "_");
+    format!("i{}", replaced)                                   // We _could_ avoid this copying.
+}
+
+fn push_variable_param(out: &mut QueryBuilder, var: &Variable) -> BuildQueryResult {
+    out.push_bind_param(variable_param_name(var).as_str())
 }
 
 impl QueryFragment for SelectQuery {
@@ -527,6 +810,13 @@ impl QueryFragment for SelectQuery {
                        { out.push_sql(" AND ") });
         }
 
+        if !self.group_by.is_empty() {
+            out.push_sql(" GROUP BY ");
+            interpose!(group, self.group_by,
+                       { group.push_sql(out)? },
+                       { out.push_sql(", ") });
+        }
+
         if !self.order.is_empty() {
             out.push_sql(" ORDER BY ");
             interpose!(&OrderBy(ref dir, ref var), self.order,
@@ -537,6 +827,11 @@ impl QueryFragment for SelectQuery {
                          };
                        },
                        { out.push_sql(", ") });
+            if self.random_order {
+                out.push_sql(", random()");
+            }
+        } else if self.random_order {
+            out.push_sql(" ORDER BY random()");
         }
 
         match &self.limit {
@@ -549,7 +844,24 @@ impl QueryFragment for SelectQuery {
             &Limit::Variable(ref var) => {
                 // Guess this wasn't bound yet. Produce an argument.
                 out.push_sql(" LIMIT ");
-                self.push_variable_param(var, out)?;
+                push_variable_param(out, var)?;
+            },
+        }
+
+        match &self.offset {
+            &Offset::None => (),
+            offset => {
+                // SQLite only accepts an offset as part of a limit clause. A negative limit is
+                // no limit at all.
+                if self.limit == Limit::None {
+                    out.push_sql(" LIMIT -1");
+                }
+                out.push_sql(" OFFSET ");
+                match offset {
+                    &Offset::Fixed(offset) => out.push_sql(offset.to_string().as_str()),
+                    &Offset::Variable(ref var) => push_variable_param(out, var)?,
+                    &Offset::None => unreachable!(),
+                }
             },
         }
 
@@ -641,6 +953,24 @@ mod tests {
         assert_eq!("((123 = 456 AND 789 = 246))", build(&c));
     }
 
+    #[test]
+    fn test_aggregate_expression() {
+        let column = || ColumnOrExpression::Column(QualifiedAlias::new("datoms01".to_string(), DatomsColumn::Value));
+        let count = ColumnOrExpression::Expression(Box::new(Expression::Aggregate {
+            sql_op: "COUNT",
+            distinct: false,
+            arg: column(),
+        }));
+        let count_distinct = ColumnOrExpression::Expression(Box::new(Expression::Aggregate {
+            sql_op: "COUNT",
+            distinct: true,
+            arg: column(),
+        }));
+
+        assert_eq!("COUNT(`datoms01`.v)", build(&count));
+        assert_eq!("COUNT(DISTINCT `datoms01`.v)", build(&count_distinct));
+    }
+
     #[test]
     fn test_unnamed_values() {
         let build = |len, values| build(&Values::Unnamed(len, values));
@@ -727,8 +1057,11 @@ mod tests {
                     right: ColumnOrExpression::Entid(65536),
                 },
             ],
+            group_by: vec![],
             order: vec![],
+            random_order: false,
             limit: Limit::None,
+            offset: Offset::None,
         };
 
         let SQLQuery { sql, args } = query.to_sql_query().unwrap();
@@ -743,5 +1076,13 @@ mod tests {
         assert_eq!("SELECT `datoms00`.e AS `x` FROM `datoms` AS `datoms00`, `datoms` AS `datoms01` WHERE `datoms01`.v = `datoms00`.v AND `datoms00`.a = 65537 AND `datoms01`.a = 65536", sql);
         assert!(args.is_empty());
 
+        // An offset needs a limit, even if there isn't one.
+        query.offset = Offset::Fixed(20);
+        let SQLQuery { sql, .. } = query.to_sql_query().unwrap();
+        assert!(sql.ends_with("`datoms01`.a = 65536 LIMIT -1 OFFSET 20"));
+
+        query.limit = Limit::Fixed(10);
+        let SQLQuery { sql, .. } = query.to_sql_query().unwrap();
+        assert!(sql.ends_with("`datoms01`.a = 65536 LIMIT 10 OFFSET 20"));
     }
 }
@@ -21,6 +21,7 @@ mod translate;
 
 pub use mentat_query_sql::{
     Projection,
+    variable_param_name,
 };
 
 pub use translate::{
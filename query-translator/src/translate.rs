@@ -14,7 +14,11 @@ use mentat_core::{
     ValueType,
 };
 
-use mentat_query::Limit;
+use mentat_query::{
+    Limit,
+    Offset,
+    Variable,
+};
 
 use mentat_query_algebrizer::{
     AlgebraicQuery,
@@ -28,6 +32,7 @@ use mentat_query_algebrizer::{
     DatomsColumn,
     DatomsTable,
     OrderBy,
+    OuterJoin,
     QualifiedAlias,
     QueryValue,
     SourceAlias,
@@ -37,6 +42,7 @@ use mentat_query_algebrizer::{
 
 use mentat_query_projector::{
     CombinedProjection,
+    PRE_AGGREGATE_ALIAS,
     Projector,
     projected_column_for_var,
     query_projection,
@@ -45,7 +51,11 @@ use mentat_query_projector::{
 use mentat_query_sql::{
     ColumnOrExpression,
     Constraint,
+    Expression,
     FromClause,
+    GroupBy,
+    Join,
+    JoinOp,
     Op,
     ProjectedColumn,
     Projection,
@@ -110,6 +120,9 @@ impl ToConstraint for ColumnConstraint {
             Equals(left, QueryValue::Column(right)) =>
                 Constraint::equal(left.to_column(), right.to_column()),
 
+            Equals(qa, QueryValue::Parameter(var)) =>
+                Constraint::equal(qa.to_column(), ColumnOrExpression::Parameter(var)),
+
             Equals(qa, QueryValue::PrimitiveLong(value)) => {
                 let tag_column = qa.for_type_tag().to_column();
                 let value_column = qa.to_column();
@@ -150,6 +163,33 @@ impl ToConstraint for ColumnConstraint {
                 }
             },
 
+            // BETWEEN only covers the inclusive case; otherwise, keep the two comparisons on the
+            // column together, which SQLite plans as a single range.
+            Range { column, lower, lower_inclusive: true, upper, upper_inclusive: true } => {
+                Constraint::Between {
+                    value: column.to_column(),
+                    lower: lower.into(),
+                    upper: upper.into(),
+                }
+            },
+
+            Range { column, lower, lower_inclusive, upper, upper_inclusive } => {
+                Constraint::And {
+                    constraints: vec![
+                        Constraint::Infix {
+                            op: Op(if lower_inclusive { ">=" } else { ">" }),
+                            left: column.clone().to_column(),
+                            right: lower.into(),
+                        },
+                        Constraint::Infix {
+                            op: Op(if upper_inclusive { "<=" } else { "<" }),
+                            left: column.to_column(),
+                            right: upper.into(),
+                        },
+                    ],
+                }
+            },
+
             Matches(left, right) => {
                 Constraint::Infix {
                     op: Op("MATCH"),
@@ -169,8 +209,69 @@ impl ToConstraint for ColumnConstraint {
                     subquery: subquery,
                 }
             },
+
+            NotNull(qa) => {
+                Constraint::NotNull {
+                    value: qa.to_column(),
+                }
+            },
+
+            // SQLite's LIKE ignores case, so case-sensitive matches use GLOB instead.
+            Like { column, pattern, escape, case_insensitive: true } => {
+                Constraint::Like {
+                    value: column.to_column(),
+                    pattern: ColumnOrExpression::Value(TypedValue::typed_string(pattern.as_str())),
+                    escape: escape.map(|e| ColumnOrExpression::Value(TypedValue::typed_string(e.to_string().as_str()))),
+                }
+            },
+
+            Regex(column, pattern) => {
+                Constraint::Infix {
+                    op: Op("REGEXP"),
+                    left: column.to_column(),
+                    right: ColumnOrExpression::Value(TypedValue::typed_string(pattern.as_str())),
+                }
+            },
+
+            Like { column, pattern, escape, case_insensitive: false } => {
+                Constraint::Infix {
+                    op: Op("GLOB"),
+                    left: column.to_column(),
+                    right: ColumnOrExpression::Value(TypedValue::typed_string(like_to_glob(pattern.as_str(), escape).as_str())),
+                }
+            },
+        }
+    }
+}
+
+/// Rewrite a `LIKE` pattern as the equivalent `GLOB` pattern, in which the characters that are
+/// special to `GLOB` -- but not to `LIKE` -- are matched literally by putting them in brackets.
+fn like_to_glob(pattern: &str, escape: Option<char>) -> String {
+    let mut glob = String::with_capacity(pattern.len());
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        let literal = if Some(c) == escape {
+            match chars.next() {
+                Some(escaped) => escaped,
+                None => break,
+            }
+        } else {
+            match c {
+                '%' => { glob.push('*'); continue; },
+                '_' => { glob.push('?'); continue; },
+                c => c,
+            }
+        };
+        match literal {
+            '*' | '?' | '[' => {
+                glob.push('[');
+                glob.push(literal);
+                glob.push(']');
+            },
+            c => glob.push(c),
         }
     }
+    glob
 }
 
 pub struct ProjectedSelect{
@@ -245,7 +346,7 @@ fn table_for_computed(computed: ComputedTable, alias: TableAlias) -> TableOrSubq
                         // Each arm simply turns into a subquery.
                         // The SQL translation will stuff "UNION" between each arm.
                         let projection = Projection::Columns(columns);
-                        cc_to_select_query(projection, cc, false, None, Limit::None)
+                        cc_to_select_query(projection, cc, false, vec![], None, Limit::None, Offset::None)
                   }).collect(),
                 alias)
         },
@@ -258,22 +359,52 @@ fn table_for_computed(computed: ComputedTable, alias: TableAlias) -> TableOrSubq
             // We assume column homogeneity, so we won't have any type tag columns.
             TableOrSubquery::Values(Values::Named(names, values), alias)
         },
+        ComputedTable::RecursiveRule {
+            name, vars, anchors, step, derived, max_depth,
+        } => {
+            // Every anchor row is at depth 1.
+            let mut members: Vec<SelectQuery> =
+                anchors.into_iter()
+                       .map(|cc| rule_member_query(&vars, cc, ColumnOrExpression::Long(1)))
+                       .collect();
+            if let Some(step) = step {
+                let depth = Expression::RuleDepth {
+                    derived: derived,
+                    limit: max_depth,
+                    rule: name.to_string(),
+                };
+                members.push(rule_member_query(&vars, *step, ColumnOrExpression::Expression(Box::new(depth))));
+            }
+            let columns = vars.iter().map(|var| VariableColumn::Variable(var.clone()).column_name()).collect();
+            TableOrSubquery::RecursiveUnion(columns, members, alias)
+        },
     }
 }
 
+/// Each member of a recursive rule's CTE projects the rule's variables, which we know to have a
+/// single type, and the depth of the row.
+fn rule_member_query(vars: &[Variable], cc: ConjoiningClauses, depth: ColumnOrExpression) -> SelectQuery {
+    let mut columns: Vec<ProjectedColumn> = vars.iter().map(|var| projected_column_for_var(var, &cc).0).collect();
+    columns.push(ProjectedColumn(depth, "depth".to_string()));
+    cc_to_select_query(Projection::Columns(columns), cc, false, vec![], None, Limit::None, Offset::None)
+}
+
 /// Returns a `SelectQuery` that queries for the provided `cc`. Note that this _always_ returns a
 /// query that runs SQL. The next level up the call stack can check for known-empty queries if
 /// needed.
 fn cc_to_select_query(projection: Projection,
                       cc: ConjoiningClauses,
                       distinct: bool,
+                      group_by: Vec<GroupBy>,
                       order: Option<Vec<OrderBy>>,
-                      limit: Limit) -> SelectQuery {
+                      limit: Limit,
+                      offset: Offset) -> SelectQuery {
     let from = if cc.from.is_empty() {
         FromClause::Nothing
     } else {
         // Move these out of the CC.
         let from = cc.from;
+        let mut table_sources = cc.table_sources;
         let mut computed: ConsumableVec<_> = cc.computed_tables.into();
 
         // Why do we put computed tables directly into the `FROM` clause? The alternative is to use
@@ -288,12 +419,27 @@ fn cc_to_select_query(projection: Projection,
                         table_for_computed(comp, alias)
                     },
                     _ => {
-                        TableOrSubquery::Table(source_alias)
+                        match table_sources.remove(&source_alias.1) {
+                            Some(database) => TableOrSubquery::AttachedTable(database, source_alias),
+                            None => TableOrSubquery::Table(source_alias),
+                        }
                     }
                 }
             });
 
-        FromClause::TableList(TableList(tables.collect()))
+        let tables = TableList(tables.collect());
+        if cc.outer_joins.is_empty() {
+            FromClause::TableList(tables)
+        } else {
+            let joins = cc.outer_joins.into_iter().map(|OuterJoin { table, on }| {
+                Join {
+                    op: JoinOp::LeftOuter,
+                    right: TableOrSubquery::Table(table),
+                    on: on.into_iter().map(|c| c.to_constraint()).collect(),
+                }
+            });
+            FromClause::Joins(tables, joins.collect())
+        }
     };
 
     let order = order.map_or(vec![], |vec| { vec.into_iter().map(|o| o.into()).collect() });
@@ -306,8 +452,11 @@ fn cc_to_select_query(projection: Projection,
                        .into_iter()
                        .map(|c| c.to_constraint())
                        .collect(),
+        group_by: group_by,
         order: order,
+        random_order: false,
         limit: limit,
+        offset: offset,
     }
 }
 
@@ -321,22 +470,64 @@ pub fn cc_to_exists(cc: ConjoiningClauses) -> SelectQuery {
             projection: Projection::One,
             from: FromClause::Nothing,
             constraints: vec![],
+            group_by: vec![],
             order: vec![],
+            random_order: false,
             limit: Limit::None,
+            offset: Offset::None,
         }
     } else {
-        cc_to_select_query(Projection::One, cc, false, None, Limit::None)
+        cc_to_select_query(Projection::One, cc, false, vec![], None, Limit::None, Offset::None)
     }
 }
 
 /// Consume a provided `AlgebraicQuery` to yield a new
 /// `ProjectedSelect`.
 pub fn query_to_select(query: AlgebraicQuery) -> Result<ProjectedSelect> {
-    // TODO: we can't pass `query.limit` here if we aggregate during projection.
-    // SQL-based aggregation -- `SELECT SUM(datoms00.e)` -- is fine.
-    let CombinedProjection { sql_projection, datalog_projector, distinct } = query_projection(&query)?;
+    let CombinedProjection {
+        sql_projection,
+        pre_aggregate_projection,
+        datalog_projector,
+        distinct,
+        group_by_cols,
+        projector_aggregates,
+        random_order,
+        row_limit,
+    } = query_projection(&query)?;
+
+    // If the projector computes aggregates, it needs every row, and applies the limit and offset
+    // itself. SQL-based aggregation -- `SELECT COUNT(datoms00.e)` -- can be limited in SQL.
+    let (limit, offset) = if projector_aggregates {
+        (row_limit.map_or(Limit::None, Limit::Fixed), Offset::None)
+    } else {
+        (query.limit, query.offset)
+    };
+
+    let mut select = match pre_aggregate_projection {
+        None => cc_to_select_query(sql_projection, query.cc, distinct, group_by_cols, query.order, limit, offset),
+        Some(pre_aggregate_projection) => {
+            // Find the distinct rows the aggregates apply to, then aggregate over those.
+            let inner = cc_to_select_query(pre_aggregate_projection, query.cc, true, vec![], None, Limit::None, Offset::None);
+            let alias = PRE_AGGREGATE_ALIAS.to_string();
+            let order = query.order.map_or(vec![], |vec| { vec.into_iter().map(|o| o.into()).collect() });
+            SelectQuery {
+                distinct: distinct,
+                projection: sql_projection,
+                from: FromClause::TableList(TableList(vec![TableOrSubquery::AliasedSubquery(Box::new(inner), alias)])),
+                constraints: vec![],
+                group_by: group_by_cols,
+                order: order,
+                random_order: false,
+                limit: limit,
+                offset: offset,
+            }
+        },
+    };
+
+    select.random_order = random_order;
+
     Ok(ProjectedSelect {
-        query: cc_to_select_query(sql_projection, query.cc, distinct, query.order, query.limit),
+        query: select,
         projector: datalog_projector,
     })
 }
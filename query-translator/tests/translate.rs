@@ -32,11 +32,16 @@ use mentat_core::{
     ValueType,
 };
 
-use mentat_query_parser::parse_find_string;
+use mentat_query_parser::{
+    parse_find_string,
+    parse_rules_string,
+};
 use mentat_query_algebrizer::{
     QueryInputs,
+    RuleSet,
     algebrize,
     algebrize_with_inputs,
+    algebrize_with_rules,
 };
 use mentat_query_translator::{
     query_to_select,
@@ -201,6 +206,55 @@ fn test_bound_variable_limit_affects_types() {
     assert_eq!(args, vec![]);
 }
 
+#[test]
+fn test_unbound_variable_offset() {
+    let schema = prepopulated_schema();
+
+    // SQLite needs a limit in order to apply an offset.
+    let query = r#"[:find ?x :in ?offset :where [?x :foo/bar "yyy"] :offset ?offset]"#;
+    let SQLQuery { sql, .. } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` \
+                     FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99 AND `datoms00`.v = $v0 \
+                     LIMIT -1 OFFSET $ioffset");
+}
+
+#[test]
+fn test_bound_variable_offset() {
+    let schema = prepopulated_schema();
+
+    let query = r#"[:find ?x :in ?offset :where [?x :foo/bar "yyy"] :offset ?offset]"#;
+    let inputs = QueryInputs::with_value_sequence(vec![(Variable::from_valid_name("?offset"), TypedValue::Long(20))]);
+    let SQLQuery { sql, .. } = translate_with_inputs(&schema, query, inputs);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` FROM `datoms` AS `datoms00` WHERE `datoms00`.a = 99 AND `datoms00`.v = $v0 LIMIT -1 OFFSET 20");
+
+    // An offset of zero is no offset at all.
+    let inputs = QueryInputs::with_value_sequence(vec![(Variable::from_valid_name("?offset"), TypedValue::Long(0))]);
+    let SQLQuery { sql, .. } = translate_with_inputs(&schema, query, inputs);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` FROM `datoms` AS `datoms00` WHERE `datoms00`.a = 99 AND `datoms00`.v = $v0");
+}
+
+#[test]
+fn test_bound_variable_limit_and_offset_with_order() {
+    let schema = prepopulated_schema();
+
+    let query = r#"[:find ?x
+                    :in ?limit ?offset
+                    :where [?x :foo/bar ?y]
+                    :order (desc ?y)
+                    :limit ?limit
+                    :offset ?offset]"#;
+    let inputs = QueryInputs::with_value_sequence(vec![(Variable::from_valid_name("?limit"), TypedValue::Long(10)),
+                                                       (Variable::from_valid_name("?offset"), TypedValue::Long(20))]);
+    let SQLQuery { sql, args } = translate_with_inputs(&schema, query, inputs);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x`, `datoms00`.v AS `?y` \
+                     FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99 \
+                     ORDER BY `?y` DESC \
+                     LIMIT 10 OFFSET 20");
+    assert_eq!(args, vec![]);
+}
+
 #[test]
 fn test_unknown_attribute_keyword_value() {
     let schema = Schema::default();
@@ -317,6 +371,33 @@ fn test_numeric_not_equals_known_attribute() {
     assert_eq!(args, vec![]);
 }
 
+#[test]
+fn test_not_equals_any_type() {
+    let mut schema = prepopulated_typed_schema(ValueType::Ref);
+    associate_ident(&mut schema, NamespacedKeyword::new("status", "closed"), 200);
+
+    // A keyword is an entid when it's compared with a ref.
+    let query = r#"[:find ?x :where [?x :foo/bar ?s] [(!= ?s :status/closed)]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` FROM `datoms` AS `datoms00` WHERE `datoms00`.a = 99 AND `datoms00`.v <> 200");
+    assert_eq!(args, vec![]);
+
+    // Two variables are compared column to column.
+    let schema = prepopulated_schema();
+    let query = r#"[:find ?x ?y :where [?x :foo/bar ?a] [?y :foo/bar ?b] [(!= ?a ?b)]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x`, `datoms01`.e AS `?y` \
+                     FROM `datoms` AS `datoms00`, `datoms` AS `datoms01` \
+                     WHERE `datoms00`.a = 99 AND `datoms01`.a = 99 AND `datoms00`.v <> `datoms01`.v");
+    assert_eq!(args, vec![]);
+
+    // A string is never a long, so this doesn't constrain anything.
+    let query = r#"[:find ?x :where [?x :foo/bar ?a] [(!= ?a 12)]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` FROM `datoms` AS `datoms00` WHERE `datoms00`.a = 99");
+    assert_eq!(args, vec![]);
+}
+
 #[test]
 fn test_compare_long_to_double_constants() {
     let schema = prepopulated_typed_schema(ValueType::Double);
@@ -579,6 +660,33 @@ fn test_order_by() {
     assert_eq!(args, vec![]);
 }
 
+#[test]
+fn test_order_by_multiple_keys() {
+    let mut schema = prepopulated_schema();
+    associate_ident(&mut schema, NamespacedKeyword::new("foo", "age"), 101);
+    add_attribute(&mut schema, 101, Attribute {
+        value_type: ValueType::Long,
+        ..Default::default()
+    });
+
+    let query = r#"[:find ?x ?name ?age
+                    :where [?x :foo/bar ?name] [?x :foo/age ?age]
+                    :order (asc ?name) (desc ?age)]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x`, `datoms00`.v AS `?name`, `datoms01`.v AS `?age` \
+                     FROM `datoms` AS `datoms00`, `datoms` AS `datoms01` \
+                     WHERE `datoms00`.a = 99 \
+                       AND `datoms01`.a = 101 \
+                       AND `datoms00`.e = `datoms01`.e \
+                     ORDER BY `?name` ASC, `?age` DESC");
+    assert_eq!(args, vec![]);
+
+    // Longs and doubles share a type tag, so numbers are ordered by value alone.
+    let query = r#"[:find ?x :with ?y :where [?x _ ?y] [(> ?y 10)] :order (desc ?y) ?x]"#;
+    let SQLQuery { sql, .. } = translate(&schema, query);
+    assert!(sql.ends_with("ORDER BY `?y` DESC, `?x` ASC"));
+}
+
 #[test]
 fn test_complex_nested_or_join_type_projection() {
     let mut schema = Schema::default();
@@ -796,7 +904,7 @@ fn test_fulltext() {
     assert_eq!(sql, "SELECT DISTINCT `datoms01`.e AS `?entity`, \
                                      `fulltext_values00`.text AS `?value`, \
                                      `datoms01`.tx AS `?tx`, \
-                                     0e0 AS `?score` \
+                                     ((length(offsets(`fulltext_values00`)) - length(replace(offsets(`fulltext_values00`), ' ', '')) + 1) / 4e0) AS `?score` \
                      FROM `fulltext_values` AS `fulltext_values00`, \
                           `datoms` AS `datoms01` \
                      WHERE `datoms01`.a = 100 \
@@ -842,8 +950,8 @@ fn test_fulltext() {
                        AND `datoms01`.v = `fulltext_values00`.rowid \
                        AND `fulltext_values00`.text MATCH $v0 \
                        AND `datoms02`.a = 99 \
-                       AND `datoms02`.v = 0e0 \
-                       AND `datoms01`.e = `datoms02`.e");
+                       AND `datoms01`.e = `datoms02`.e \
+                       AND ((length(offsets(`fulltext_values00`)) - length(replace(offsets(`fulltext_values00`), ' ', '')) + 1) / 4e0) = `datoms02`.v");
     assert_eq!(args, vec![make_arg("$v0", "needle"),]);
 
     let query = r#"[:find ?entity ?value ?tx :where [?entity :foo/bar ?score] [(fulltext $ :foo/fts "needle") [[?entity ?value ?tx ?score]]]]"#;
@@ -858,8 +966,8 @@ fn test_fulltext() {
                        AND `datoms02`.a = 100 \
                        AND `datoms02`.v = `fulltext_values01`.rowid \
                        AND `fulltext_values01`.text MATCH $v0 \
-                       AND `datoms00`.v = 0e0 \
-                       AND `datoms00`.e = `datoms02`.e");
+                       AND `datoms00`.e = `datoms02`.e \
+                       AND `datoms00`.v = ((length(offsets(`fulltext_values01`)) - length(replace(offsets(`fulltext_values01`), ' ', '')) + 1) / 4e0)");
     assert_eq!(args, vec![make_arg("$v0", "needle"),]);
 }
 
@@ -955,3 +1063,320 @@ fn test_instant_range() {
                        AND `datoms00`.v > 1497574601257000");
     assert_eq!(args, vec![]);
 }
+
+#[test]
+fn test_count_distinct() {
+    let schema = prepopulated_schema();
+
+    // Ungrouped.
+    let query = r#"[:find (count-distinct ?y) . :where [?x :foo/bar ?y]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT COUNT(DISTINCT `preag`.`?y`) AS `(count-distinct ?y)` \
+                     FROM (SELECT DISTINCT `datoms00`.v AS `?y` \
+                           FROM `datoms` AS `datoms00` \
+                           WHERE `datoms00`.a = 99) AS `preag` \
+                     LIMIT 1");
+    assert_eq!(args, vec![]);
+
+    // Grouped.
+    let query = r#"[:find ?x (count-distinct ?y) :where [?x :foo/bar ?y]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `preag`.`?x` AS `?x`, COUNT(DISTINCT `preag`.`?y`) AS `(count-distinct ?y)` \
+                     FROM (SELECT DISTINCT `datoms00`.e AS `?x`, `datoms00`.v AS `?y` \
+                           FROM `datoms` AS `datoms00` \
+                           WHERE `datoms00`.a = 99) AS `preag` \
+                     GROUP BY `?x`");
+    assert_eq!(args, vec![]);
+}
+
+#[test]
+fn test_max() {
+    let schema = prepopulated_typed_schema(ValueType::Instant);
+
+    // Ungrouped, we group by nothing, so that there's no row at all if there's no value.
+    let query = r#"[:find (max ?y) . :where [?x :foo/bar ?y]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT MAX(`preag`.`?y`) AS `(max ?y)` \
+                     FROM (SELECT DISTINCT `datoms00`.v AS `?y` \
+                           FROM `datoms` AS `datoms00` \
+                           WHERE `datoms00`.a = 99) AS `preag` \
+                     GROUP BY NULL \
+                     LIMIT 1");
+    assert_eq!(args, vec![]);
+
+    // Grouped.
+    let query = r#"[:find ?x (min ?y) :where [?x :foo/bar ?y]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `preag`.`?x` AS `?x`, MIN(`preag`.`?y`) AS `(min ?y)` \
+                     FROM (SELECT DISTINCT `datoms00`.e AS `?x`, `datoms00`.v AS `?y` \
+                           FROM `datoms` AS `datoms00` \
+                           WHERE `datoms00`.a = 99) AS `preag` \
+                     GROUP BY `?x`");
+    assert_eq!(args, vec![]);
+}
+
+#[test]
+fn test_count_with() {
+    let schema = prepopulated_schema();
+
+    // `:with` variables reach the subquery, but aren't grouped by.
+    let query = r#"[:find (count ?y) . :with ?x :where [?x :foo/bar ?y]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT COUNT(`preag`.`?y`) AS `(count ?y)` \
+                     FROM (SELECT DISTINCT `datoms00`.v AS `?y`, `datoms00`.e AS `?x` \
+                           FROM `datoms` AS `datoms00` \
+                           WHERE `datoms00`.a = 99) AS `preag` \
+                     LIMIT 1");
+    assert_eq!(args, vec![]);
+}
+
+#[test]
+fn test_median_is_computed_by_projector() {
+    let schema = prepopulated_typed_schema(ValueType::Double);
+
+    // Median isn't something SQLite can compute, so we fetch every distinct row, unlimited.
+    let query = r#"[:find (median ?y) . :where [?x :foo/bar ?y]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.v AS `?y` \
+                     FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99");
+    assert_eq!(args, vec![]);
+
+    let query = r#"[:find ?x (percentile ?y 95) :where [?x :foo/bar ?y]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x`, `datoms00`.v AS `?y` \
+                     FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99");
+    assert_eq!(args, vec![]);
+}
+
+#[test]
+fn test_sample() {
+    let schema = prepopulated_typed_schema(ValueType::Long);
+
+    // A lone sample only needs the first few shuffled rows.
+    let query = r#"[:find (sample 10 ?x) . :where [?x :foo/bar _]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` \
+                     FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99 \
+                     ORDER BY random() \
+                     LIMIT 10");
+    assert_eq!(args, vec![]);
+
+    // Grouped samples need every row.
+    let query = r#"[:find ?y (sample 2 ?x) :where [?x :foo/bar ?y] :order ?y]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.v AS `?y`, `datoms00`.e AS `?x` \
+                     FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99 \
+                     ORDER BY `?y` ASC, random()");
+    assert_eq!(args, vec![]);
+}
+
+#[test]
+fn test_median_requires_numbers() {
+    let schema = prepopulated_schema();
+
+    let query = r#"[:find (median ?y) . :where [?x :foo/bar ?y]]"#;
+    let parsed = parse_find_string(query).expect("parse to succeed");
+    let algebrized = algebrize(&schema, parsed).expect("algebrize to succeed");
+    assert!(query_to_select(algebrized).is_err());
+}
+
+#[test]
+fn test_missing() {
+    let mut schema = prepopulated_schema();
+    associate_ident(&mut schema, NamespacedKeyword::new("foo", "tag"), 101);
+    add_attribute(&mut schema, 101, Attribute {
+        value_type: ValueType::String,
+        multival: true,
+        ..Default::default()
+    });
+
+    let query = r#"[:find ?x :where [?x :foo/bar _] [(missing? $ ?x :foo/tag)]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99 \
+                     AND NOT EXISTS (SELECT 1 FROM `datoms` AS `datoms01` \
+                                     WHERE `datoms01`.a = 101 \
+                                     AND `datoms00`.e = `datoms01`.e)");
+    assert_eq!(args, vec![]);
+}
+
+#[test]
+fn test_get_else() {
+    let mut schema = prepopulated_schema();
+    associate_ident(&mut schema, NamespacedKeyword::new("foo", "nick"), 101);
+    add_attribute(&mut schema, 101, Attribute {
+        value_type: ValueType::String,
+        ..Default::default()
+    });
+
+    let query = r#"[:find ?x ?n :where [?x :foo/bar _] [(get-else $ ?x :foo/nick "N/A") ?n]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x`, COALESCE(`datoms01`.v, $v0) AS `?n` \
+                     FROM `datoms` AS `datoms00` \
+                     LEFT JOIN `datoms` AS `datoms01` ON `datoms01`.e = `datoms00`.e AND `datoms01`.a = 101 \
+                     WHERE `datoms00`.a = 99");
+    assert_eq!(args, vec![make_arg("$v0", "N/A")]);
+}
+
+#[test]
+fn test_get_some() {
+    let mut schema = prepopulated_schema();
+    associate_ident(&mut schema, NamespacedKeyword::new("foo", "nick"), 101);
+    add_attribute(&mut schema, 101, Attribute {
+        value_type: ValueType::String,
+        ..Default::default()
+    });
+
+    let query = r#"[:find ?x ?n :where [?x :foo/bar _] [(get-some $ ?x :foo/nick :foo/bar) ?n]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x`, COALESCE(`datoms01`.v, `datoms02`.v) AS `?n` \
+                     FROM `datoms` AS `datoms00` \
+                     LEFT JOIN `datoms` AS `datoms01` ON `datoms01`.e = `datoms00`.e AND `datoms01`.a = 101 \
+                     LEFT JOIN `datoms` AS `datoms02` ON `datoms02`.e = `datoms00`.e AND `datoms02`.a = 99 \
+                     WHERE `datoms00`.a = 99 \
+                     AND ((`datoms01`.v IS NOT NULL) OR (`datoms02`.v IS NOT NULL))");
+    assert_eq!(args, vec![]);
+}
+
+#[test]
+fn test_str() {
+    let schema = prepopulated_schema();
+
+    let query = r#"[:find ?s :where [?x :foo/bar ?v] [(str ?v "-" ?x true) ?s]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT (`datoms00`.v || $v0 || CAST(`datoms00`.e AS TEXT) || \
+                     CASE WHEN 1 THEN 'true' ELSE 'false' END) AS `?s` \
+                     FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99");
+    assert_eq!(args, vec![make_arg("$v0", "-")]);
+}
+
+#[test]
+fn test_arithmetic() {
+    let schema = prepopulated_typed_schema(ValueType::Long);
+
+    // The divisor can't be zero.
+    let query = r#"[:find ?q :where [?x :foo/bar ?v] [?x :foo/bar ?w] [(/ ?v ?w) ?q]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT (`datoms00`.v / `datoms01`.v) AS `?q` \
+                     FROM `datoms` AS `datoms00`, `datoms` AS `datoms01` \
+                     WHERE `datoms00`.a = 99 AND `datoms01`.a = 99 AND `datoms01`.v <> 0 \
+                     AND `datoms00`.e = `datoms01`.e");
+    assert_eq!(args, vec![]);
+}
+
+#[test]
+fn test_like() {
+    let schema = prepopulated_schema();
+
+    // Case-sensitive matches are made with GLOB, so `*` must be matched literally.
+    let query = r#"[:find ?x :where [?x :foo/bar ?v] [(like ?v "Ab%_*")]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99 AND `datoms00`.v GLOB $v0");
+    assert_eq!(args, vec![make_arg("$v0", "Ab*?[*]")]);
+
+    let query = r#"[:find ?x :where [?x :foo/bar ?v] [(like ?v "100!%" "!")]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99 AND `datoms00`.v GLOB $v0");
+    assert_eq!(args, vec![make_arg("$v0", "100%")]);
+
+    let query = r#"[:find ?x :where [?x :foo/bar ?v] [(like-ci ?v "100!%" "!")]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99 AND `datoms00`.v LIKE $v0 ESCAPE $v1");
+    assert_eq!(args, vec![make_arg("$v0", "100!%"), make_arg("$v1", "!")]);
+}
+
+#[test]
+fn test_regex() {
+    let schema = prepopulated_schema();
+
+    let query = r#"[:find ?x :where [?x :foo/bar ?v] [(re-find ?v "\\d{3}")]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99 AND `datoms00`.v REGEXP $v0");
+    assert_eq!(args, vec![make_arg("$v0", r"\d{3}")]);
+
+    // `re-matches` must match the whole string.
+    let query = r#"[:find ?x :where [?x :foo/bar ?v] [(re-matches ?v "\\d{3}")]]"#;
+    let SQLQuery { args, .. } = translate(&schema, query);
+    assert_eq!(args, vec![make_arg("$v0", r"\A(?:\d{3})\z")]);
+}
+
+#[test]
+fn test_numeric_range() {
+    let schema = prepopulated_typed_schema(ValueType::Long);
+
+    // Inclusive bounds become BETWEEN, in whichever order they're written.
+    let query = r#"[:find ?x :where [?x :foo/bar ?y] [(<= ?y 65)] [(>= ?y 18)]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99 AND `datoms00`.v BETWEEN 18 AND 65");
+    assert_eq!(args, vec![]);
+}
+
+#[test]
+fn test_numeric_exclusive_range() {
+    let schema = prepopulated_typed_schema(ValueType::Long);
+
+    // Exclusive bounds stay together. A bound written value-first is flipped to match.
+    let query = r#"[:find ?x :where [?x :foo/bar ?y] [(> ?y 18)] [(> 65 ?y)]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99 AND (`datoms00`.v > 18 AND `datoms00`.v < 65)");
+    assert_eq!(args, vec![]);
+
+    // Two lower bounds aren't a range.
+    let query = r#"[:find ?x :where [?x :foo/bar ?y] [(> ?y 18)] [(>= ?y 21)]]"#;
+    let SQLQuery { sql, .. } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` FROM `datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99 AND `datoms00`.v > 18 AND `datoms00`.v >= 21");
+}
+
+#[test]
+fn test_recursive_rule() {
+    let schema = prepopulated_typed_schema(ValueType::Ref);
+    let rules = parse_rules_string(r#"[[(reaches ?a ?b) [?a :foo/bar ?b]]
+                                       [(reaches ?a ?b) [?a :foo/bar ?c] (reaches ?c ?b)]]"#).unwrap();
+    let parsed = parse_find_string(r#"[:find ?x ?y :where (reaches ?x ?y)]"#).unwrap();
+    let algebrized = algebrize_with_rules(&schema, parsed, &RuleSet::new(rules).with_max_depth(5), QueryInputs::default()).unwrap();
+    let SQLQuery { sql, args } = query_to_select(algebrized).unwrap().query.to_sql_query().unwrap();
+    assert_eq!(sql, "SELECT DISTINCT `c00`.`?a` AS `?x`, `c00`.`?b` AS `?y` FROM \
+                     (WITH RECURSIVE `rule`(`?a`, `?b`, depth) AS \
+                      (SELECT `datoms00`.e AS `?a`, `datoms00`.v AS `?b`, 1 AS `depth` \
+                       FROM `datoms` AS `datoms00` WHERE `datoms00`.a = 99 \
+                       UNION \
+                       SELECT `datoms02`.e AS `?a`, `rule01`.`?b` AS `?b`, \
+                       CASE WHEN `rule01`.depth < 5 THEN `rule01`.depth + 1 ELSE rule_depth_exceeded($v0, 5) END AS `depth` \
+                       FROM `rule` AS `rule01`, `datoms` AS `datoms02` \
+                       WHERE `datoms02`.a = 99 AND `rule01`.`?a` = `datoms02`.v) \
+                     SELECT DISTINCT `?a`, `?b` FROM `rule`) AS `c00`");
+    assert_eq!(args, vec![make_arg("$v0", "reaches")]);
+}
+
+#[test]
+fn test_named_source() {
+    let schema = prepopulated_schema();
+
+    // A pattern against a named source reads from the attached database of that name.
+    let query = r#"[:find ?x :in $db2 :where [$db2 ?x :foo/bar "yyy"]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x` FROM `db2`.`datoms` AS `datoms00` \
+                     WHERE `datoms00`.a = 99 AND `datoms00`.v = $v0");
+    assert_eq!(args, vec![make_arg("$v0", "yyy")]);
+
+    // Joins across sources are joins like any other.
+    let query = r#"[:find ?x ?y :in $ $db2 :where [?x :foo/bar ?v] [$db2 ?y :foo/bar ?v]]"#;
+    let SQLQuery { sql, args } = translate(&schema, query);
+    assert_eq!(sql, "SELECT DISTINCT `datoms00`.e AS `?x`, `datoms01`.e AS `?y` \
+                     FROM `datoms` AS `datoms00`, `db2`.`datoms` AS `datoms01` \
+                     WHERE `datoms00`.a = 99 AND `datoms01`.a = 99 \
+                     AND `datoms00`.v = `datoms01`.v");
+    assert_eq!(args, vec![]);
+}
@@ -50,6 +50,7 @@ use edn::{
 };
 
 pub use edn::{
+    Keyword,
     NamespacedKeyword,
     PlainSymbol,
 };
@@ -195,17 +196,19 @@ pub enum NonIntegerConstant {
     Text(Rc<String>),
     Instant(DateTime<Utc>),
     Uuid(Uuid),
+    Bytes(Vec<u8>),
 }
 
 impl NonIntegerConstant {
     pub fn into_typed_value(self) -> TypedValue {
         match self {
-            NonIntegerConstant::BigInteger(_) => unimplemented!(),     // TODO: #280.
+            NonIntegerConstant::BigInteger(v) => TypedValue::BigInteger(v),
             NonIntegerConstant::Boolean(v) => TypedValue::Boolean(v),
             NonIntegerConstant::Float(v) => TypedValue::Double(v),
             NonIntegerConstant::Text(v) => TypedValue::String(v),
             NonIntegerConstant::Instant(v) => TypedValue::Instant(v),
             NonIntegerConstant::Uuid(v) => TypedValue::Uuid(v),
+            NonIntegerConstant::Bytes(v) => TypedValue::Bytes(v),
         }
     }
 }
@@ -216,10 +219,19 @@ pub enum FnArg {
     SrcVar(SrcVar),
     EntidOrInteger(i64),
     IdentOrKeyword(NamespacedKeyword),
+    // A keyword with no namespace, like `:day`, which can only name an option of a function.
+    Keyword(Keyword),
     Constant(NonIntegerConstant),
+    // `nil`, the absence of a value. It isn't a constant, because there's no `TypedValue` for it.
+    Nil,
     // The collection values representable in EDN.  There's no advantage to destructuring up front,
     // since consumers will need to handle arbitrarily nested EDN themselves anyway.
     Vector(Vec<FnArg>),
+    // The elements of a set, in EDN's order. These are distinct as EDN, but need not be distinct
+    // once converted to values: `#{65 :foo/bar}` might name the same entity twice.
+    Set(Vec<FnArg>),
+    // Map entries, in the order they were parsed.  A map with a single entry is a lookup ref.
+    Map(Vec<(FnArg, FnArg)>),
 }
 
 impl FromValue<FnArg> for FnArg {
@@ -235,10 +247,14 @@ impl FromValue<FnArg> for FnArg {
             PlainSymbol(_) => None,
             NamespacedKeyword(ref x) =>
                 Some(FnArg::IdentOrKeyword(x.clone())),
+            Keyword(ref x) =>
+                Some(FnArg::Keyword(x.clone())),
             Instant(x) =>
                 Some(FnArg::Constant(NonIntegerConstant::Instant(x))),
             Uuid(x) =>
                 Some(FnArg::Constant(NonIntegerConstant::Uuid(x))),
+            Bytes(ref x) =>
+                Some(FnArg::Constant(NonIntegerConstant::Bytes(x.clone()))),
             Boolean(x) =>
                 Some(FnArg::Constant(NonIntegerConstant::Boolean(x))),
             Float(x) =>
@@ -248,9 +264,9 @@ impl FromValue<FnArg> for FnArg {
             Text(ref x) =>
                 // TODO: intern strings. #398.
                 Some(FnArg::Constant(NonIntegerConstant::Text(Rc::new(x.clone())))),
-            Nil |
+            Nil =>
+                Some(FnArg::Nil),
             NamespacedSymbol(_) |
-            Keyword(_) |
             Vector(_) |
             List(_) |
             Set(_) |
@@ -370,6 +386,8 @@ impl FromValue<PatternValuePlace> for PatternValuePlace {
                 Some(PatternValuePlace::Constant(NonIntegerConstant::Text(Rc::new(x.clone())))),
             edn::SpannedValue::Uuid(ref u) =>
                 Some(PatternValuePlace::Constant(NonIntegerConstant::Uuid(u.clone()))),
+            edn::SpannedValue::Bytes(ref b) =>
+                Some(PatternValuePlace::Constant(NonIntegerConstant::Bytes(b.clone()))),
 
             // These don't appear in queries.
             edn::SpannedValue::Nil => None,
@@ -415,31 +433,50 @@ impl PatternValuePlace {
     }
 }
 
-/*
-pub enum PullPattern {
-    Constant(Constant),
-    Variable(Variable),
+/// One entry in a pull pattern: either `*`, for every attribute of the entity, or a single
+/// attribute. A reversed attribute like `:foo/_bar` pulls the entities that refer to this one.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PullAttributeSpec {
+    Wildcard,
+    Attribute(NamespacedKeyword),
 }
 
+/// A pull expression in a find spec, like `(pull ?person [:person/name :person/age])`.
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Pull {
-    pub src: SrcVar,
     pub var: Variable,
-    pub pattern: PullPattern,      // Constant, variable, or plain variable.
+    pub patterns: Vec<PullAttributeSpec>,
 }
-*/
 
-/*
+/// An aggregate function application in a find spec, like `(count ?x)`.
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct Aggregate {
-    pub fn_name: String,
+    pub func: QueryFunction,
     pub args: Vec<FnArg>,
 }
-*/
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Element {
     Variable(Variable),
-    // Aggregate(Aggregate),   // TODO
-    // Pull(Pull),             // TODO
+    Aggregate(Aggregate),
+    Pull(Pull),
+}
+
+impl Element {
+    pub fn is_aggregate(&self) -> bool {
+        match self {
+            &Element::Variable(_) => false,
+            &Element::Pull(_) => false,
+            &Element::Aggregate(_) => true,
+        }
+    }
+
+    pub fn is_pull(&self) -> bool {
+        match self {
+            &Element::Pull(_) => true,
+            _ => false,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -449,6 +486,14 @@ pub enum Limit {
     Variable(Variable),
 }
 
+/// The number of results to skip. Unlike a limit, an offset can be zero.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Offset {
+    None,
+    Fixed(u64),
+    Variable(Variable),
+}
+
 /// A definition of the first part of a find query: the
 /// `[:find ?foo ?bar…]` bit.
 ///
@@ -480,7 +525,7 @@ pub enum Limit {
 /// # }
 /// ```
 ///
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub enum FindSpec {
     /// Returns an array of arrays.
     FindRel(Vec<Element>),
@@ -542,6 +587,24 @@ impl FindSpec {
     pub fn requires_distinct(&self) -> bool {
         !self.is_unit_limited()
     }
+
+    /// Returns true if any element of this find spec is an aggregate.
+    pub fn has_aggregates(&self) -> bool {
+        use FindSpec::*;
+        match self {
+            &FindScalar(ref elem) | &FindColl(ref elem) => elem.is_aggregate(),
+            &FindTuple(ref elems) | &FindRel(ref elems) => elems.iter().any(|e| e.is_aggregate()),
+        }
+    }
+
+    /// Returns true if any element of this find spec is a pull expression.
+    pub fn has_pull(&self) -> bool {
+        use FindSpec::*;
+        match self {
+            &FindScalar(ref elem) | &FindColl(ref elem) => elem.is_pull(),
+            &FindTuple(ref elems) | &FindRel(ref elems) => elems.iter().any(|e| e.is_pull()),
+        }
+    }
 }
 
 // Datomic accepts variable or placeholder.  DataScript accepts recursive bindings.  Mentat sticks
@@ -694,6 +757,27 @@ pub struct WhereFn {
     pub binding: Binding,
 }
 
+/// An invocation of a rule, like `(ancestor ?a ?b)`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RuleExpr {
+    pub name: PlainSymbol,
+    pub args: Vec<FnArg>,
+}
+
+/// One definition of a rule, like
+///
+/// ```edn
+/// [(ancestor ?a ?b) [?a :person/parent ?b]]
+/// ```
+///
+/// A rule can have several definitions: an invocation matches if any of them does.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Rule {
+    pub name: PlainSymbol,
+    pub vars: Vec<Variable>,
+    pub clauses: Vec<WhereClause>,
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum UnifyVars {
     /// `Implicit` means the variables in an `or` or `not` are derived from the enclosed pattern.
@@ -770,19 +854,23 @@ pub enum WhereClause {
     OrJoin(OrJoin),
     Pred(Predicate),
     WhereFn(WhereFn),
-    RuleExpr,
+    RuleExpr(RuleExpr),
     Pattern(Pattern),
 }
 
 #[allow(dead_code)]
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 pub struct FindQuery {
     pub find_spec: FindSpec,
     pub default_source: SrcVar,
     pub with: BTreeSet<Variable>,
     pub in_vars: BTreeSet<Variable>,
+    /// The `:in` bindings that take more than one value, like `[?e ...]` and `[[?e ?name]]`, in
+    /// order. Their variables aren't in `in_vars`, which holds only those bound to a single value.
+    pub in_bindings: Vec<Binding>,
     pub in_sources: BTreeSet<SrcVar>,
     pub limit: Limit,
+    pub offset: Offset,
     pub where_clauses: Vec<WhereClause>,
     pub order: Option<Vec<Order>>,
     // TODO: in_rules;
@@ -836,7 +924,7 @@ impl ContainsVariables for WhereClause {
             &Pattern(ref p) => p.accumulate_mentioned_variables(acc),
             &NotJoin(ref n) => n.accumulate_mentioned_variables(acc),
             &WhereFn(ref f) => f.accumulate_mentioned_variables(acc),
-            &RuleExpr       => (),
+            &RuleExpr(ref r) => r.accumulate_mentioned_variables(acc),
         }
     }
 }
@@ -899,6 +987,16 @@ impl ContainsVariables for Predicate {
     }
 }
 
+impl ContainsVariables for RuleExpr {
+    fn accumulate_mentioned_variables(&self, acc: &mut BTreeSet<Variable>) {
+        for arg in &self.args {
+            if let &FnArg::Variable(ref v) = arg {
+                acc_ref(acc, v)
+            }
+        }
+    }
+}
+
 impl ContainsVariables for Binding {
     fn accumulate_mentioned_variables(&self, acc: &mut BTreeSet<Variable>) {
         match self {
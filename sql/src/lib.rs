@@ -181,6 +181,15 @@ impl QueryBuilder for SQLiteQueryBuilder {
                     self.byte_args.insert(bytes.clone().to_vec(), arg);
                 }
             },
+            &Bytes(ref bytes) => {
+                if let Some(arg) = self.byte_args.get(bytes).cloned() {
+                    self.push_named_arg(arg.as_str());
+                } else {
+                    let arg = self.next_argument_name();
+                    self.push_named_arg(arg.as_str());
+                    self.byte_args.insert(bytes.clone(), arg);
+                }
+            },
             // These are both `Rc`. Unfortunately, we can't use that fact when
             // turning these into rusqlite Values.
             // However, we can check to see whether there's an existing var that matches…
@@ -198,6 +207,11 @@ impl QueryBuilder for SQLiteQueryBuilder {
                 let v = Rc::new(rusqlite::types::Value::Text(s.as_ref().to_string()));
                 self.push_static_arg(v);
             },
+            &BigInteger(ref b) => {
+                // Stored as text; see `SQLValueType::value_type_tag`.
+                let v = Rc::new(rusqlite::types::Value::Text(b.to_string()));
+                self.push_static_arg(v);
+            },
         }
         Ok(())
     }
@@ -0,0 +1,88 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! A description of each attribute in a schema, for consumers that can't know the schema in
+//! advance. See `Conn::attributes`.
+
+use std::collections::BTreeMap;
+
+use rusqlite;
+
+use mentat_core::{
+    Entid,
+    Schema,
+    ValueType,
+};
+
+use mentat_core::attribute::Unique;
+
+use mentat_db::entids;
+
+use mentat_query::NamespacedKeyword;
+
+use errors::Result;
+
+/// The facets of an attribute, as they were transacted.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AttributeInfo {
+    pub entid: Entid,
+
+    /// `:db/ident`.
+    pub ident: NamespacedKeyword,
+
+    /// `:db/valueType`.
+    pub value_type: ValueType,
+
+    /// `true` for `:db/cardinality :db.cardinality/many`.
+    pub multival: bool,
+
+    /// `:db/unique`, if any.
+    pub unique: Option<Unique>,
+
+    /// `:db/index`.
+    pub index: bool,
+
+    /// `:db/fulltext`.
+    pub fulltext: bool,
+
+    /// `:db/isComponent`.
+    pub component: bool,
+
+    /// `:db/doc`, if any. It isn't part of the schema, so it's read from the store.
+    pub doc: Option<String>,
+}
+
+/// Every attribute of `schema` that has an ident, in order of entid.
+pub fn attributes(sqlite: &rusqlite::Connection, schema: &Schema) -> Result<Vec<AttributeInfo>> {
+    let mut docs: BTreeMap<Entid, String> = BTreeMap::new();
+    let mut stmt = sqlite.prepare("SELECT e, v FROM datoms WHERE a = ?")?;
+    let mut rows = stmt.query(&[&entids::DB_DOC])?;
+    while let Some(row) = rows.next() {
+        let row = row?;
+        docs.insert(row.get_checked(0)?, row.get_checked(1)?);
+    }
+
+    Ok(schema.schema_map
+             .iter()
+             .filter_map(|(&entid, attribute)| {
+                 schema.get_ident(entid).map(|ident| AttributeInfo {
+                     entid: entid,
+                     ident: ident.clone(),
+                     value_type: attribute.value_type,
+                     multival: attribute.multival,
+                     unique: attribute.unique.clone(),
+                     index: attribute.index,
+                     fulltext: attribute.fulltext,
+                     component: attribute.component,
+                     doc: docs.remove(&entid),
+                 })
+             })
+             .collect())
+}
@@ -10,7 +10,13 @@
 
 #![allow(dead_code)]
 
+use std::collections::BTreeSet;
+use std::ops::{
+    Range,
+    RangeInclusive,
+};
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 use rusqlite;
 use rusqlite::{
@@ -20,25 +26,65 @@ use rusqlite::{
 use edn;
 
 use mentat_core::{
+    Entid,
     Schema,
+    TypedValue,
 };
 
+use mentat_db;
 use mentat_db::db;
+use mentat_db::db::PartitionMapping;
 use mentat_db::{
+    bulk_import,
+    entid_for_lookup_ref,
     transact,
+    AttributeCaches,
+    CacheDirection,
     PartitionMap,
+    TxLog,
     TxReport,
+    transactions_after,
 };
 
 use mentat_tx;
 
 use mentat_tx_parser;
 
+use attributes::{
+    AttributeInfo,
+    attributes,
+};
+
+use entity_builder::TransactBuilder;
+
+use observer::{
+    TxObserver,
+    TxObservers,
+};
+
 use errors::*;
 use query::{
+    AsOf,
+    NamespacedKeyword,
+    QueryExplanation,
+    attribute_count,
+    explain,
     q_once,
+    q_once_with_cache,
+    q_once_with_deadline,
+    query_after,
+    query_as_of,
+    query_history,
+    query_iter,
+    QueryCursor,
+    QueryExecutionResult,
     QueryInputs,
-    QueryResults,
+    QueryPage,
+    QueryStream,
+};
+use query_cache::{
+    PreparedQuery,
+    QueryCache,
 };
 
 
@@ -47,11 +93,19 @@ use query::{
 /// Owned data for the volatile parts (generation and partition map), and `Arc` for the infrequently
 /// changing parts (schema) that we want to share across threads.
 ///
+/// `schema_generation` counts the committed transactions that changed the schema, so that anything
+/// derived from the schema -- like cached query plans -- can tell when it's stale.
+///
+/// `attribute_cache` holds the attributes pinned in memory by `Conn::cache_attribute`. Like the
+/// schema, an `InProgress` works on its own copy, which replaces this one when it commits.
+///
 /// See https://github.com/mozilla/mentat/wiki/Thoughts:-modeling-db-conn-in-Rust.
 pub struct Metadata {
     pub generation: u64,
+    pub schema_generation: u64,
     pub partition_map: PartitionMap,
     pub schema: Arc<Schema>,
+    pub attribute_cache: Arc<AttributeCaches>,
 }
 
 impl Metadata {
@@ -59,8 +113,10 @@ impl Metadata {
     fn new(generation: u64, partition_map: PartitionMap, schema: Arc<Schema>) -> Metadata {
         Metadata {
             generation: generation,
+            schema_generation: 0,
             partition_map: partition_map,
             schema: schema,
+            attribute_cache: Arc::new(AttributeCaches::default()),
         }
     }
 }
@@ -77,10 +133,11 @@ pub struct Conn {
     /// map and schema -- forward.
     metadata: Mutex<Metadata>,
 
-    // TODO: maintain set of change listeners or handles to transaction report queues. #298.
+    /// Told about transactions once they're committed. See `Conn::register_observer`.
+    observers: Mutex<TxObservers>,
 
     // TODO: maintain cache of query plans that could be shared across threads and invalidated when
-    // the schema changes. #315.
+    // the schema changes. #315. For now, see `Conn::q_cached`.
 }
 
 /// Represents an in-progress, not yet committed, set of changes to the store.
@@ -90,10 +147,12 @@ pub struct Conn {
 pub struct InProgress<'a, 'c> {
     transaction: rusqlite::Transaction<'c>,
     mutex: &'a Mutex<Metadata>,
+    observers: &'a Mutex<TxObservers>,
     generation: u64,
     partition_map: PartitionMap,
     schema: Schema,
-    last_report: Option<TxReport>,   // For now we track only the last, but we could accumulate all.
+    reports: Vec<TxReport>,
+    attribute_cache: Arc<AttributeCaches>,
 }
 
 impl<'a, 'c> InProgress<'a, 'c> {
@@ -103,14 +162,18 @@ impl<'a, 'c> InProgress<'a, 'c> {
         if let Some(schema) = next_schema {
             self.schema = schema;
         }
-        self.last_report = Some(report);
+        if !self.attribute_cache.is_empty() {
+            // Copied on the first write, so the committed cache is untouched until we commit.
+            Arc::make_mut(&mut self.attribute_cache).update(&report)?;
+        }
+        self.reports.push(report);
         Ok(self)
     }
 
     /// Query the Mentat store, using the given connection and the current metadata.
     pub fn q_once<T>(&self,
                      query: &str,
-                     inputs: T) -> Result<QueryResults>
+                     inputs: T) -> QueryExecutionResult
         where T: Into<Option<QueryInputs>>
         {
 
@@ -120,6 +183,19 @@ impl<'a, 'c> InProgress<'a, 'c> {
                inputs)
     }
 
+    /// Assert `datoms` in a single transaction, rebuilding the secondary indexes once at the end
+    /// rather than as each datom is inserted. The entities must already be allocated: see
+    /// `allocate_entids`. See `mentat_db::bulk_import`.
+    pub fn bulk_import<I>(mut self, datoms: I) -> Result<InProgress<'a, 'c>> where I: IntoIterator<Item=(Entid, Entid, TypedValue)> {
+        let (report, next_partition_map) = bulk_import(&self.transaction, self.partition_map, &self.schema, datoms)?;
+        self.partition_map = next_partition_map;
+        if !self.attribute_cache.is_empty() {
+            Arc::make_mut(&mut self.attribute_cache).update(&report)?;
+        }
+        self.reports.push(report);
+        Ok(self)
+    }
+
     pub fn transact(self, transaction: &str) -> Result<InProgress<'a, 'c>> {
         let assertion_vector = edn::parse::value(transaction)?;
         let entities = mentat_tx_parser::Tx::parse(&assertion_vector)?;
@@ -127,36 +203,103 @@ impl<'a, 'c> InProgress<'a, 'c> {
     }
 
     pub fn last_report(&self) -> Option<&TxReport> {
-        self.last_report.as_ref()
+        self.reports.last()
+    }
+
+    /// Add the partition `name`, from which the entids in `[start, end)` can be allocated.
+    pub fn add_partition(&mut self, name: &str, start: Entid, end: Entid) -> Result<()> {
+        db::add_partition(&self.transaction, &mut self.partition_map, name, start, end)?;
+        Ok(())
+    }
+
+    /// Allocate `n` fresh entids in `partition`.
+    pub fn allocate_entids(&mut self, partition: &str, n: usize) -> Result<Range<Entid>> {
+        let entids = self.partition_map.allocate_entids(partition, n)?;
+        db::update_partition_map(&self.transaction, &self.partition_map)?;
+        Ok(entids)
     }
 
     pub fn rollback(mut self) -> Result<()> {
-        self.last_report = None;
+        self.reports.clear();
         self.transaction.rollback().map_err(|e| e.into())
     }
 
     pub fn commit(self) -> Result<Option<TxReport>> {
-        // The mutex is taken during this entire method.
-        let mut metadata = self.mutex.lock().unwrap();
-
-        if self.generation != metadata.generation {
-            // Somebody else wrote!
-            // Retrying is tracked by https://github.com/mozilla/mentat/issues/357.
-            // This should not occur -- an attempt to take a competing IMMEDIATE transaction
-            // will fail with `SQLITE_BUSY`, causing this function to abort.
-            bail!("Lost the transact() race!");
-        }
+        self.commit_all().map(|mut reports| reports.pop())
+    }
 
-        // Commit the SQLite transaction while we hold the mutex.
-        self.transaction.commit()?;
+    /// Commit, returning the report of every transaction made, in order.
+    pub fn commit_all(mut self) -> Result<Vec<TxReport>> {
+        {
+            // The mutex is taken during this block.
+            let mut metadata = self.mutex.lock().unwrap();
+
+            if self.generation != metadata.generation {
+                // Somebody else wrote!
+                // Retrying is tracked by https://github.com/mozilla/mentat/issues/357.
+                // This should not occur -- an attempt to take a competing IMMEDIATE transaction
+                // will fail with `SQLITE_BUSY`, causing this function to abort.
+                bail!("Lost the transact() race!");
+            }
+
+            // Commit the SQLite transaction while we hold the mutex.
+            self.transaction.commit()?;
+
+            metadata.generation += 1;
+            metadata.attribute_cache = self.attribute_cache;
+            metadata.partition_map = self.partition_map;
+            if self.schema != *(metadata.schema) {
+                metadata.schema = Arc::new(self.schema);
+                metadata.schema_generation += 1;
+            }
+        }
 
-        metadata.generation += 1;
-        metadata.partition_map = self.partition_map;
-        if self.schema != *(metadata.schema) {
-            metadata.schema = Arc::new(self.schema);
+        // Observers may well want to query, so we notify them only once we've let go of the
+        // metadata, and of the observers themselves.
+        for report in self.reports.iter() {
+            let interested = self.observers.lock().unwrap().interested(report);
+            for observer in interested {
+                observer.notify(report);
+            }
         }
 
-        Ok(self.last_report)
+        Ok(self.reports)
+    }
+}
+
+/// A consistent, read-only view of the store, as it was when the snapshot was taken. Queries run
+/// against the snapshot see none of the transactions committed since, on any connection, and the
+/// schema is the one that was current at the time. Dropping the snapshot ends its SQLite read
+/// transaction.
+pub struct ReadSnapshot<'c> {
+    transaction: rusqlite::Transaction<'c>,
+    schema: Arc<Schema>,
+}
+
+impl<'c> ReadSnapshot<'c> {
+    /// Query the store as it was when the snapshot was taken.
+    pub fn q_once<T>(&self,
+                     query: &str,
+                     inputs: T) -> QueryExecutionResult
+        where T: Into<Option<QueryInputs>>
+        {
+
+        q_once(&*(self.transaction),
+               &*self.schema,
+               query,
+               inputs)
+    }
+
+    /// The schema as it was when the snapshot was taken.
+    pub fn schema(&self) -> Arc<Schema> {
+        self.schema.clone()
+    }
+}
+
+fn attribute_entid(schema: &Schema, attribute: &NamespacedKeyword) -> Result<Entid> {
+    match schema.get_entid(attribute) {
+        Some(a) if schema.is_attribute(a) => Ok(a),
+        _ => bail!(ErrorKind::DbError(mentat_db::ErrorKind::UnrecognizedIdent(attribute.to_string()))),
     }
 }
 
@@ -164,7 +307,8 @@ impl Conn {
     // Intentionally not public.
     fn new(partition_map: PartitionMap, schema: Schema) -> Conn {
         Conn {
-            metadata: Mutex::new(Metadata::new(0, partition_map, Arc::new(schema)))
+            metadata: Mutex::new(Metadata::new(0, partition_map, Arc::new(schema))),
+            observers: Mutex::new(TxObservers::default()),
         }
     }
 
@@ -191,18 +335,271 @@ impl Conn {
         self.metadata.lock().unwrap().schema.clone()
     }
 
-    /// Query the Mentat store, using the given connection and the current metadata.
+    /// The partitions from which entids are allocated, as of the last committed transaction.
+    pub fn partition_map(&self) -> PartitionMap {
+        self.metadata.lock().unwrap().partition_map.clone()
+    }
+
+    /// Add the partition `name`, from which the entids in `[start, end)` can be allocated. It
+    /// mustn't overlap any existing partition.
+    pub fn add_partition(&mut self,
+                         sqlite: &mut rusqlite::Connection,
+                         name: &str,
+                         start: Entid,
+                         end: Entid) -> Result<()> {
+        let mut in_progress = self.begin_transaction(sqlite)?;
+        in_progress.add_partition(name, start, end)?;
+        in_progress.commit()?;
+        Ok(())
+    }
+
+    /// Reserve `n` fresh entids in `partition`, so that data with ids assigned elsewhere can be
+    /// transacted against them directly. The transactor accepts an explicit entid only once it's
+    /// been allocated like this, or to a tempid.
+    pub fn allocate_entids(&mut self,
+                           sqlite: &mut rusqlite::Connection,
+                           partition: &str,
+                           n: usize) -> Result<RangeInclusive<Entid>> {
+        let mut in_progress = self.begin_transaction(sqlite)?;
+        let entids = in_progress.allocate_entids(partition, n)?;
+        in_progress.commit()?;
+        Ok(entids.start..=(entids.end - 1))
+    }
+
+    /// The number of committed transactions that have changed the schema.
+    pub fn current_schema_generation(&self) -> u64 {
+        self.metadata.lock().unwrap().schema_generation
+    }
+
+    /// Query the Mentat store, using the given connection and the current metadata. Patterns over
+    /// cached attributes are answered from the cache where possible.
     pub fn q_once<T>(&self,
                      sqlite: &rusqlite::Connection,
                      query: &str,
-                     inputs: T) -> Result<QueryResults>
+                     inputs: T) -> QueryExecutionResult
         where T: Into<Option<QueryInputs>>
         {
 
-        q_once(sqlite,
-               &*self.current_schema(),
-               query,
-               inputs)
+        let (schema, cache) = {
+            let metadata = self.metadata.lock().unwrap();
+            (metadata.schema.clone(), metadata.attribute_cache.clone())
+        };
+
+        if cache.is_empty() {
+            return q_once(sqlite, &*schema, query, inputs);
+        }
+        q_once_with_cache(sqlite,
+                          &*schema,
+                          cache,
+                          query,
+                          inputs)
+    }
+
+    /// Like `q_once`, but give up with `QueryInterrupted` if the query is still running at
+    /// `deadline`. The attribute cache isn't used. See `query::q_once_with_deadline`.
+    pub fn q_once_with_deadline<T>(&self,
+                                   sqlite: &rusqlite::Connection,
+                                   query: &str,
+                                   inputs: T,
+                                   deadline: Instant) -> QueryExecutionResult
+        where T: Into<Option<QueryInputs>> {
+        q_once_with_deadline(sqlite, &*self.current_schema(), query, inputs, deadline)
+    }
+
+    /// Prepare a query whose results are projected lazily as they're read from the store. See
+    /// `query::query_iter`.
+    pub fn query_iter<'sqlite, T>(&self,
+                                  sqlite: &'sqlite rusqlite::Connection,
+                                  query: &str,
+                                  inputs: T) -> Result<QueryStream<'sqlite>>
+        where T: Into<Option<QueryInputs>>
+    {
+        query_iter(sqlite, &*self.current_schema(), query, inputs)
+    }
+
+    /// Describe how the given query would be run against the current schema, without running it.
+    pub fn explain<T>(&self,
+                      sqlite: &rusqlite::Connection,
+                      query: &str,
+                      inputs: T) -> Result<QueryExplanation>
+        where T: Into<Option<QueryInputs>>
+    {
+        explain(sqlite, &*self.current_schema(), query, inputs)
+    }
+
+    /// Keep the datoms of `attribute` in memory, so that queries can look up its values by entity
+    /// (`CacheDirection::Forward`) or its entities by value (`CacheDirection::Reverse`) without
+    /// touching the store. Transactions committed through this `Conn` update the cache in place.
+    pub fn cache_attribute(&mut self,
+                           sqlite: &rusqlite::Connection,
+                           attribute: &NamespacedKeyword,
+                           direction: CacheDirection) -> Result<()> {
+        let mut metadata = self.metadata.lock().unwrap();
+        let a = attribute_entid(&metadata.schema, attribute)?;
+        Arc::make_mut(&mut metadata.attribute_cache).add(sqlite, a, direction)
+    }
+
+    /// Stop caching `attribute` in `direction`.
+    pub fn uncache_attribute(&mut self,
+                             attribute: &NamespacedKeyword,
+                             direction: CacheDirection) -> Result<()> {
+        let mut metadata = self.metadata.lock().unwrap();
+        let a = attribute_entid(&metadata.schema, attribute)?;
+        Arc::make_mut(&mut metadata.attribute_cache).remove(a, direction);
+        Ok(())
+    }
+
+    /// Call `notify` with the report of each committed transaction that changes any of
+    /// `attributes`, once it's committed. Registering another observer as `key` replaces this one.
+    pub fn register_observer<F>(&self,
+                                key: String,
+                                attributes: &[NamespacedKeyword],
+                                notify: F) -> Result<()>
+        where F: Fn(&TxReport) + Send + Sync + 'static {
+        let attributes = {
+            let schema = self.current_schema();
+            let mut entids = BTreeSet::new();
+            for attribute in attributes {
+                entids.insert(attribute_entid(&schema, attribute)?);
+            }
+            entids
+        };
+        self.observers.lock().unwrap().register(key, TxObserver::new(attributes, notify));
+        Ok(())
+    }
+
+    /// Returns `true` if there was an observer registered as `key`.
+    pub fn unregister_observer(&self, key: &str) -> bool {
+        self.observers.lock().unwrap().unregister(key)
+    }
+
+    /// Query the Mentat store, using the given connection and the current metadata, reusing a
+    /// plan from `cache` if one matches. See `query_cache::QueryCache::q`.
+    pub fn q_cached<T>(&self,
+                       sqlite: &rusqlite::Connection,
+                       cache: &mut QueryCache,
+                       query: &str,
+                       inputs: T) -> QueryExecutionResult
+        where T: Into<Option<QueryInputs>>
+        {
+
+        let (schema, schema_generation) = {
+            let metadata = self.metadata.lock().unwrap();
+            (metadata.schema.clone(), metadata.schema_generation)
+        };
+        cache.q(sqlite, &*schema, schema_generation, query, inputs)
+    }
+
+    /// Prepare `query` to be run repeatedly against this store with different inputs. See
+    /// `query_cache::PreparedQuery`.
+    pub fn prepare(&self, query: &str) -> Result<PreparedQuery> {
+        let (schema, schema_generation) = {
+            let metadata = self.metadata.lock().unwrap();
+            (metadata.schema.clone(), metadata.schema_generation)
+        };
+        PreparedQuery::new(schema, schema_generation, query)
+    }
+
+    /// Query the Mentat store as it was at `as_of`, using the given connection and the current
+    /// metadata. See `query::query_as_of`.
+    pub fn query_as_of<A, T>(&self,
+                             sqlite: &rusqlite::Connection,
+                             query: &str,
+                             as_of: A,
+                             inputs: T) -> QueryExecutionResult
+        where A: Into<AsOf>,
+              T: Into<Option<QueryInputs>>
+        {
+
+        query_as_of(sqlite,
+                    &*self.current_schema(),
+                    query,
+                    as_of,
+                    inputs)
+    }
+
+    /// One page of the results of an ordered query: those that follow `after`, if given. See
+    /// `query::query_after`.
+    pub fn query_after<T>(&self,
+                          sqlite: &rusqlite::Connection,
+                          query: &str,
+                          inputs: T,
+                          after: Option<&QueryCursor>) -> Result<QueryPage>
+        where T: Into<Option<QueryInputs>>
+    {
+        query_after(sqlite, &*self.current_schema(), query, inputs, after)
+    }
+
+    /// Every assertion and retraction of `attr` on `e`, in transaction order. See
+    /// `query::query_history`.
+    pub fn query_history(&self,
+                         sqlite: &rusqlite::Connection,
+                         e: Entid,
+                         attr: &NamespacedKeyword) -> Result<Vec<Vec<TypedValue>>> {
+        query_history(sqlite, &*self.current_schema(), e, attr)
+    }
+
+    /// The number of datoms currently asserted for `attr`. See `query::attribute_count`.
+    pub fn attribute_count(&self,
+                           sqlite: &rusqlite::Connection,
+                           attr: &NamespacedKeyword) -> Result<u64> {
+        attribute_count(sqlite, &*self.current_schema(), attr)
+    }
+
+    /// The entity that has `value` for the unique attribute `attr`, if any, just as the lookup ref
+    /// `(lookup-ref attr value)` is resolved when transacting. It's an error if `attr` isn't
+    /// `:db/unique`, or `value` isn't of its type.
+    pub fn entid_for_lookup_ref(&self,
+                                sqlite: &rusqlite::Connection,
+                                attr: &NamespacedKeyword,
+                                value: TypedValue) -> Result<Option<Entid>> {
+        let schema = self.current_schema();
+        let attribute = schema.get_entid(attr)
+                              .and_then(|a| schema.attribute_for_entid(a).map(|attribute| (a, attribute.value_type)));
+        let (a, value_type) = match attribute {
+            Some(attribute) => attribute,
+            None => bail!(ErrorKind::UnknownAttribute(attr.to_string())),
+        };
+        if value.value_type() != value_type {
+            bail!(ErrorKind::AttributeValueTypeMismatch(attr.to_string(), value_type, value.value_type()));
+        }
+        entid_for_lookup_ref(sqlite, &*schema, a, value).map_err(|e| e.into())
+    }
+
+    /// Every attribute in the current schema, with its facets. See `attributes::attributes`.
+    pub fn attributes(&self,
+                      sqlite: &rusqlite::Connection) -> Result<Vec<AttributeInfo>> {
+        attributes(sqlite, &*self.current_schema())
+    }
+
+    /// Every transaction committed after `tx_id`, in order, read from the transaction log as the
+    /// iterator advances. Transactions committed once the iterator exists aren't included. See
+    /// `mentat_db::transactions_after`.
+    pub fn transactions_after<'sqlite>(&self,
+                                       sqlite: &'sqlite rusqlite::Connection,
+                                       tx_id: Entid) -> Result<TxLog<'sqlite>> {
+        Ok(transactions_after(sqlite, tx_id)?)
+    }
+
+    /// Take a read-only snapshot of the store, against which any number of queries see the same
+    /// state. The SQLite transaction is DEFERRED, so it excludes nobody, and we read from it at
+    /// once: SQLite only fixes what a deferred transaction sees when it first reads. Other
+    /// connections can continue to commit while the snapshot is open, which they can do without
+    /// waiting for it because the store is in WAL mode.
+    pub fn begin_read<'conn>(&self, sqlite: &'conn mut rusqlite::Connection) -> Result<ReadSnapshot<'conn>> {
+        let tx = sqlite.transaction_with_behavior(TransactionBehavior::Deferred)?;
+
+        // Transactions commit while holding the mutex, so the schema we take matches what we read.
+        let schema = {
+            let metadata = self.metadata.lock().unwrap();
+            tx.query_row("SELECT COUNT(*) FROM sqlite_master", &[], |row| row.get::<_, i64>(0))?;
+            metadata.schema.clone()
+        };
+
+        Ok(ReadSnapshot {
+            transaction: tx,
+            schema: schema,
+        })
     }
 
     /// Take a SQLite transaction.
@@ -212,7 +609,7 @@ impl Conn {
     /// can start a transaction that's not `DEFERRED`, but we don't need exclusivity yet.
     pub fn begin_transaction<'m, 'conn>(&'m mut self, sqlite: &'conn mut rusqlite::Connection) -> Result<InProgress<'m, 'conn>> {
         let tx = sqlite.transaction_with_behavior(TransactionBehavior::Immediate)?;
-        let (current_generation, current_partition_map, current_schema) =
+        let (current_generation, current_partition_map, current_schema, current_attribute_cache) =
         {
             // The mutex is taken during this block.
             let ref current: Metadata = *self.metadata.lock().unwrap();
@@ -220,16 +617,20 @@ impl Conn {
              // Expensive, but the partition map is updated after every committed transaction.
              current.partition_map.clone(),
              // Cheap.
-             current.schema.clone())
+             current.schema.clone(),
+             // Cheap.
+             current.attribute_cache.clone())
         };
 
         Ok(InProgress {
             mutex: &self.metadata,
+            observers: &self.observers,
             transaction: tx,
             generation: current_generation,
             partition_map: current_partition_map,
             schema: (*current_schema).clone(),
-            last_report: None,
+            reports: vec![],
+            attribute_cache: current_attribute_cache,
         })
     }
 
@@ -244,7 +645,14 @@ impl Conn {
         // there's a race for the database (don't do that!) we are less likely to win it.
         let assertion_vector = edn::parse::value(transaction)?;
         let entities = mentat_tx_parser::Tx::parse(&assertion_vector)?;
+        self.transact_entities(sqlite, entities)
+    }
 
+    /// Transact already-parsed entities, such as those from a `TransactBuilder`.
+    pub fn transact_entities<I>(&mut self,
+                                sqlite: &mut rusqlite::Connection,
+                                entities: I) -> Result<TxReport>
+        where I: IntoIterator<Item=mentat_tx::entities::Entity> {
         let report = self.begin_transaction(sqlite)?
                          .transact_entities(entities)?
                          .commit()?
@@ -252,6 +660,48 @@ impl Conn {
 
         Ok(report)
     }
+
+    /// Transact each of `transactions` in turn, all within a single SQLite transaction, returning
+    /// a report for each. Each is still its own Mentat transaction, with its own tx entid, and sees
+    /// the changes of those before it. If any of them fails, none are committed.
+    pub fn transact_many<I, T>(&mut self,
+                               sqlite: &mut rusqlite::Connection,
+                               transactions: I) -> Result<Vec<TxReport>>
+        where I: IntoIterator<Item=T>,
+              T: AsRef<str> {
+        // As in `transact`, parse everything before we take a SQL transaction.
+        let mut parsed = vec![];
+        for transaction in transactions {
+            let assertion_vector = edn::parse::value(transaction.as_ref())?;
+            parsed.push(mentat_tx_parser::Tx::parse(&assertion_vector)?);
+        }
+
+        let mut in_progress = self.begin_transaction(sqlite)?;
+        for entities in parsed {
+            in_progress = in_progress.transact_entities(entities)?;
+        }
+        in_progress.commit_all()
+    }
+
+    /// Import `datoms` without maintaining the secondary indexes as we go. If any of them can't be
+    /// imported, or they'd violate uniqueness or cardinality, nothing is. See
+    /// `InProgress::bulk_import`.
+    pub fn bulk_import<I>(&mut self,
+                          sqlite: &mut rusqlite::Connection,
+                          datoms: I) -> Result<TxReport>
+        where I: IntoIterator<Item=(Entid, Entid, TypedValue)> {
+        let report = self.begin_transaction(sqlite)?
+                         .bulk_import(datoms)?
+                         .commit()?
+                         .expect("we always get a report");
+        Ok(report)
+    }
+
+    /// Start building a transaction against the current schema, checking values as they're added
+    /// rather than once the transaction is parsed.
+    pub fn transact_builder(&self) -> TransactBuilder {
+        TransactBuilder::new(self.current_schema())
+    }
 }
 
 #[cfg(test)]
@@ -259,12 +709,18 @@ mod tests {
     use super::*;
 
     extern crate mentat_parser_utils;
+    use std::collections::BTreeSet;
+
     use mentat_core::{
+        CachedAttributes,
         TypedValue,
+        ValueType,
     };
 
     use mentat_db::USER0;
 
+    use query::QueryResults;
+
     #[test]
     fn test_transact_does_not_collide_existing_entids() {
         let mut sqlite = db::new_connection("").unwrap();
@@ -350,7 +806,7 @@ mod tests {
 
             let during = in_progress.q_once("[:find ?x . :where [?x :db/ident :a/keyword1]]", None)
                                     .expect("query succeeded");
-            assert_eq!(during, QueryResults::Scalar(Some(TypedValue::Ref(one))));
+            assert_eq!(during.results, QueryResults::Scalar(Some(TypedValue::Ref(one).into())));
 
             let report = in_progress.transact(t2)
                                     .expect("t2 succeeded")
@@ -396,7 +852,7 @@ mod tests {
             let during = in_progress.q_once("[:find ?x . :where [?x :db/ident :a/keyword1]]", None)
                                     .expect("query succeeded");
 
-            assert_eq!(during, QueryResults::Scalar(Some(TypedValue::Ref(one))));
+            assert_eq!(during.results, QueryResults::Scalar(Some(TypedValue::Ref(one).into())));
 
             in_progress.rollback()
                        .expect("rollback succeeded");
@@ -404,13 +860,69 @@ mod tests {
 
         let after = conn.q_once(&mut sqlite, "[:find ?x . :where [?x :db/ident :a/keyword1]]", None)
                         .expect("query succeeded");
-        assert_eq!(after, QueryResults::Scalar(None));
+        assert_eq!(after.results, QueryResults::Scalar(None));
 
         // The DB part table is unchanged.
         let tempid_offset_after = get_next_entid(&conn);
         assert_eq!(tempid_offset, tempid_offset_after);
     }
 
+    #[test]
+    fn test_transact_many() {
+        let mut sqlite = db::new_connection("").unwrap();
+        let mut conn = Conn::connect(&mut sqlite).unwrap();
+
+        let tempid_offset = get_next_entid(&conn);
+
+        let reports = conn.transact_many(&mut sqlite, vec![
+            "[[:db/add \"one\" :db/ident :a/keyword1]]",
+            "[[:db/add \"two\" :db/ident :a/keyword2]]",
+            // Later transactions see the changes of earlier ones.
+            "[{:db.schema/attribute \"three\", :db/ident :a/keyword1}]",
+        ]).expect("transacted successfully");
+
+        assert_eq!(reports.len(), 3);
+        let tx_ids: BTreeSet<Entid> = reports.iter().map(|report| report.tx_id).collect();
+        assert_eq!(tx_ids.len(), 3);
+        assert_eq!(reports[0].tempids["one"], tempid_offset);
+        assert_eq!(reports[1].tempids["two"], tempid_offset + 1);
+        assert_eq!(reports[2].tempids["three"], tempid_offset);
+
+        let after = conn.q_once(&mut sqlite, "[:find ?x . :where [?x :db/ident :a/keyword2]]", None)
+                        .expect("query succeeded");
+        assert_eq!(after.results, QueryResults::Scalar(Some(TypedValue::Ref(tempid_offset + 1).into())));
+        assert_eq!(tempid_offset + 2, get_next_entid(&conn));
+    }
+
+    #[test]
+    fn test_transact_many_rolls_back() {
+        let mut sqlite = db::new_connection("").unwrap();
+        let mut conn = Conn::connect(&mut sqlite).unwrap();
+
+        let tempid_offset = get_next_entid(&conn);
+        let last_tx = conn.transact(&mut sqlite, "[]").expect("transacted").tx_id;
+
+        match conn.transact_many(&mut sqlite, vec![
+            "[[:db/add \"one\" :db/ident :a/keyword1]]",
+            "[[:db/add \"two\" :a/unknown 1]]",
+            "[[:db/add \"three\" :db/ident :a/keyword3]]",
+        ]).unwrap_err() {
+            Error(ErrorKind::DbError(::mentat_db::errors::ErrorKind::UnrecognizedIdent(ident)), _) => {
+                assert_eq!(ident, ":a/unknown");
+            },
+            x => panic!("expected transact error, got {:?}", x),
+        }
+
+        // Nothing from the batch was committed, not even the first transaction.
+        let after = conn.q_once(&mut sqlite, "[:find ?x . :where [?x :db/ident :a/keyword1]]", None)
+                        .expect("query succeeded");
+        assert_eq!(after.results, QueryResults::Scalar(None));
+        assert_eq!(tempid_offset, get_next_entid(&conn));
+
+        let report = conn.transact(&mut sqlite, "[]").expect("transacted");
+        assert_eq!(report.tx_id, last_tx + 1);
+    }
+
     #[test]
     fn test_transact_errors() {
         let mut sqlite = db::new_connection("").unwrap();
@@ -423,7 +935,9 @@ mod tests {
         // Bad EDN: missing closing ']'.
         let report = conn.transact(&mut sqlite, "[[:db/add \"t\" :db/ident :a/keyword]");
         match report.unwrap_err() {
-            Error(ErrorKind::EdnParseError(_), _) => { },
+            Error(ErrorKind::EdnParseError(e), _) => {
+                assert_eq!((e.line, e.column, e.offset), (1, 36, 35));
+            },
             x => panic!("expected EDN parse error, got {:?}", x),
         }
 
@@ -446,8 +960,365 @@ mod tests {
         let report = conn.transact(&mut sqlite, "[[:db/add \"u\" :db/ident :a/keyword]
                                                   [:db/add \"u\" :db/ident :b/keyword]]");
         match report.unwrap_err() {
-            Error(ErrorKind::DbError(::mentat_db::errors::ErrorKind::NotYetImplemented(_)), _) => { },
-            x => panic!("expected EDN parse error, got {:?}", x),
+            Error(ErrorKind::DbError(::mentat_db::errors::ErrorKind::ConflictingUpsert(_, _)), _) => { },
+            x => panic!("expected conflicting upsert error, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn test_attribute_cache_update() {
+        let mut sqlite = db::new_connection("").unwrap();
+        let mut conn = Conn::connect(&mut sqlite).unwrap();
+        conn.transact(&mut sqlite, r#"[
+            [:db/add "s" :db/ident :foo/name]
+            [:db/add "s" :db/valueType :db.type/string]
+            [:db/add "s" :db/cardinality :db.cardinality/one]
+            [:db/add "t" :db/ident :foo/tag]
+            [:db/add "t" :db/valueType :db.type/string]
+            [:db/add "t" :db/cardinality :db.cardinality/many]
+        ]"#).expect("transacted schema");
+        let report = conn.transact(&mut sqlite, r#"[
+            [:db/add "a" :foo/name "Alice"]
+            [:db/add "a" :foo/tag "red"]
+        ]"#).expect("transacted data");
+        let alice = report.tempids["a"];
+
+        let schema = conn.current_schema();
+        let name = schema.get_entid(&NamespacedKeyword::new("foo", "name")).unwrap();
+        let tag = schema.get_entid(&NamespacedKeyword::new("foo", "tag")).unwrap();
+        conn.cache_attribute(&sqlite, &NamespacedKeyword::new("foo", "name"), CacheDirection::Both).expect("cached");
+        conn.cache_attribute(&sqlite, &NamespacedKeyword::new("foo", "tag"), CacheDirection::Both).expect("cached");
+
+        let red = TypedValue::typed_string("red");
+        let blue = TypedValue::typed_string("blue");
+        let values = |conn: &Conn, a: Entid| -> Option<Vec<TypedValue>> {
+            conn.metadata.lock().unwrap().attribute_cache.get_values_for_entid(a, alice).cloned()
+        };
+        let entities = |conn: &Conn, a: Entid, v: &TypedValue| -> Option<BTreeSet<Entid>> {
+            conn.metadata.lock().unwrap().attribute_cache.get_entids_for_value(a, v).cloned()
+        };
+        assert_eq!(values(&conn, tag), Some(vec![red.clone()]));
+
+        // Asserting a new value of a cardinality-many attribute adds it.
+        conn.transact(&mut sqlite, format!(r#"[[:db/add {} :foo/tag "blue"]]"#, alice).as_str()).expect("transacted");
+        assert_eq!(values(&conn, tag), Some(vec![red.clone(), blue.clone()]));
+        assert_eq!(entities(&conn, tag, &blue), Some(vec![alice].into_iter().collect()));
+
+        // Retracting one removes just that one.
+        conn.transact(&mut sqlite, format!(r#"[[:db/retract {} :foo/tag "red"]]"#, alice).as_str()).expect("transacted");
+        assert_eq!(values(&conn, tag), Some(vec![blue.clone()]));
+        assert_eq!(entities(&conn, tag, &red), None);
+
+        // Asserting a different value of a cardinality-one attribute replaces the old one.
+        conn.transact(&mut sqlite, format!(r#"[[:db/add {} :foo/name "Alicia"]]"#, alice).as_str()).expect("transacted");
+        assert_eq!(values(&conn, name), Some(vec![TypedValue::typed_string("Alicia")]));
+        assert_eq!(entities(&conn, name, &TypedValue::typed_string("Alice")), None);
+        assert_eq!(entities(&conn, name, &TypedValue::typed_string("Alicia")), Some(vec![alice].into_iter().collect()));
+
+        // A rolled-back transaction leaves the cache alone.
+        {
+            let in_progress = conn.begin_transaction(&mut sqlite).expect("begun");
+            in_progress.transact(format!(r#"[[:db/retract {} :foo/tag "blue"]]"#, alice).as_str())
+                       .expect("transacted")
+                       .rollback()
+                       .expect("rolled back");
         }
+        assert_eq!(values(&conn, tag), Some(vec![blue.clone()]));
+    }
+
+    #[test]
+    fn test_observers() {
+        let mut sqlite = db::new_connection("").unwrap();
+        let mut conn = Conn::connect(&mut sqlite).unwrap();
+        conn.transact(&mut sqlite, r#"[
+            [:db/add "s" :db/ident :foo/name]
+            [:db/add "s" :db/valueType :db.type/string]
+            [:db/add "s" :db/cardinality :db.cardinality/one]
+            [:db/add "t" :db/ident :foo/tag]
+            [:db/add "t" :db/valueType :db.type/string]
+            [:db/add "t" :db/cardinality :db.cardinality/many]
+        ]"#).expect("transacted schema");
+
+        let seen: Arc<Mutex<Vec<Entid>>> = Arc::new(Mutex::new(vec![]));
+        let recorded = seen.clone();
+        conn.register_observer("names".to_string(), &[NamespacedKeyword::new("foo", "name")], move |report| {
+            recorded.lock().unwrap().push(report.tx_id);
+        }).expect("registered");
+
+        // Observers can only watch attributes.
+        assert!(conn.register_observer("nothing".to_string(), &[NamespacedKeyword::new("foo", "nothing")], |_| {}).is_err());
+
+        // A relevant transaction is observed once it's committed.
+        let report = conn.transact(&mut sqlite, r#"[[:db/add "a" :foo/name "Alice"]]"#).expect("transacted");
+        assert_eq!(*seen.lock().unwrap(), vec![report.tx_id]);
+
+        // An irrelevant one isn't.
+        conn.transact(&mut sqlite, r#"[[:db/add "a" :foo/tag "red"]]"#).expect("transacted");
+        assert_eq!(seen.lock().unwrap().len(), 1);
+
+        // Nor is a failed one, or one that's rolled back.
+        assert!(conn.transact(&mut sqlite, r#"[[:db/add "a" :foo/name 1]]"#).is_err());
+        conn.begin_transaction(&mut sqlite).expect("begun")
+            .transact(r#"[[:db/add "b" :foo/name "Bob"]]"#).expect("transacted")
+            .rollback().expect("rolled back");
+        assert_eq!(seen.lock().unwrap().len(), 1);
+
+        // Several transactions committed together are each observed.
+        let in_progress = conn.begin_transaction(&mut sqlite).expect("begun")
+            .transact(r#"[[:db/add "b" :foo/name "Bob"]]"#).expect("transacted")
+            .transact(r#"[[:db/add "c" :foo/name "Carol"]]"#).expect("transacted");
+        in_progress.commit().expect("committed");
+        assert_eq!(seen.lock().unwrap().len(), 3);
+
+        assert!(conn.unregister_observer("names"));
+        conn.transact(&mut sqlite, r#"[[:db/add "d" :foo/name "Dave"]]"#).expect("transacted");
+        assert_eq!(seen.lock().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn test_partitions() {
+        let mut sqlite = db::new_connection("").unwrap();
+        let mut conn = Conn::connect(&mut sqlite).unwrap();
+
+        let parts = conn.partition_map();
+        assert_eq!(parts[":db.part/user"].start, USER0);
+        assert_eq!(parts[":db.part/user"].end, mentat_db::TX0);
+
+        conn.add_partition(&mut sqlite, ":test.part/small", 0x20000000, 0x20000003).expect("added");
+        assert_eq!(conn.partition_map()[":test.part/small"].index, 0x20000000);
+
+        // Partitions can't overlap, or be added twice.
+        assert!(conn.add_partition(&mut sqlite, ":test.part/overlapping", USER0 + 10, USER0 + 20).is_err());
+        assert!(conn.add_partition(&mut sqlite, ":test.part/small", 0x30000000, 0x30000003).is_err());
+
+        // Allocations are committed along with everything else, and the new entids can be used.
+        let entids = {
+            let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun");
+            let entids = in_progress.allocate_entids(":test.part/small", 2).expect("allocated");
+            in_progress.commit().expect("committed");
+            entids
+        };
+        assert_eq!(entids, 0x20000000..0x20000002);
+        assert_eq!(conn.partition_map()[":test.part/small"].index, 0x20000002);
+        conn.transact(&mut sqlite, format!("[[:db/add {} :db/doc \"small\"]]", entids.start).as_str()).expect("transacted");
+
+        // The partition map survives reopening the store.
+        assert_eq!(Conn::connect(&mut sqlite).unwrap().partition_map(), conn.partition_map());
+
+        // Only one entid is left.
+        let mut in_progress = conn.begin_transaction(&mut sqlite).expect("begun");
+        match in_progress.allocate_entids(":test.part/small", 2) {
+            Err(Error(ErrorKind::DbError(mentat_db::ErrorKind::PartitionExhausted(name, 2, 1)), _)) => {
+                assert_eq!(name, ":test.part/small");
+            },
+            r => panic!("expected the partition to be exhausted: {:?}", r),
+        }
+        assert_eq!(in_progress.allocate_entids(":test.part/small", 1).expect("allocated"), 0x20000002..0x20000003);
+        assert!(in_progress.allocate_entids(":test.part/small", 1).is_err());
+        assert!(in_progress.allocate_entids(":test.part/nonexistent", 1).is_err());
+    }
+
+    #[test]
+    fn test_allocate_entids() {
+        let mut sqlite = db::new_connection("").unwrap();
+        let mut conn = Conn::connect(&mut sqlite).unwrap();
+        conn.transact(&mut sqlite, r#"[
+            [:db/add "s" :db/ident :foo/name]
+            [:db/add "s" :db/valueType :db.type/string]
+            [:db/add "s" :db/cardinality :db.cardinality/one]
+            [:db/add "r" :db/ident :foo/ref]
+            [:db/add "r" :db/valueType :db.type/ref]
+            [:db/add "r" :db/cardinality :db.cardinality/one]
+        ]"#).expect("transacted schema");
+
+        let block = conn.allocate_entids(&mut sqlite, ":db.part/user", 1000).expect("allocated");
+        let (first, last) = (*block.start(), *block.end());
+        assert_eq!(last - first, 999);
+
+        // Tempids are allocated after the reserved block.
+        let report = conn.transact(&mut sqlite, r#"[[:db/add "x" :foo/name "x"]]"#).expect("transacted");
+        assert_eq!(report.tempids["x"], last + 1);
+
+        // Reserved entids can be used without tempids.
+        conn.transact(&mut sqlite, format!(r#"[[:db/add {} :foo/name "first"]
+                                               [:db/add {} :foo/name "last"]
+                                               [:db/add {} :foo/ref {}]]"#, first, last, first, last).as_str())
+            .expect("transacted reserved entids");
+        let names = conn.q_once(&sqlite, format!(r#"[:find ?n . :where [{} :foo/ref ?r] [?r :foo/name ?n]]"#, first).as_str(), None)
+            .expect("queried").results;
+        assert_eq!(names, QueryResults::Scalar(Some(TypedValue::typed_string("last").into())));
+
+        // An entid that hasn't been allocated is rejected.
+        let unallocated = last + 1000;
+        match conn.transact(&mut sqlite, format!(r#"[[:db/add {} :foo/name "nope"]]"#, unallocated).as_str()) {
+            Err(Error(ErrorKind::DbError(mentat_db::ErrorKind::UnrecognizedEntid(e)), _)) => assert_eq!(e, unallocated),
+            r => panic!("expected an unrecognized entid: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_bulk_import() {
+        let mut sqlite = db::new_connection("").unwrap();
+        let mut conn = Conn::connect(&mut sqlite).unwrap();
+        conn.transact(&mut sqlite, r#"[
+            [:db/add "s" :db/ident :foo/email]
+            [:db/add "s" :db/valueType :db.type/string]
+            [:db/add "s" :db/cardinality :db.cardinality/one]
+            [:db/add "s" :db/unique :db.unique/identity]
+            [:db/add "t" :db/ident :foo/age]
+            [:db/add "t" :db/valueType :db.type/long]
+            [:db/add "t" :db/cardinality :db.cardinality/one]
+        ]"#).expect("transacted schema");
+        let email = conn.current_schema().get_entid(&NamespacedKeyword::new("foo", "email")).expect("email");
+        let age = conn.current_schema().get_entid(&NamespacedKeyword::new("foo", "age")).expect("age");
+
+        let count = |conn: &Conn, sqlite: &rusqlite::Connection| -> usize {
+            match conn.q_once(sqlite, "[:find [?e ...] :where [?e :foo/email _]]", None).expect("queried").results {
+                QueryResults::Coll(es) => es.len(),
+                r => panic!("unexpected results {:?}", r),
+            }
+        };
+        let indexes = |sqlite: &rusqlite::Connection| -> i64 {
+            sqlite.query_row("SELECT COUNT(*) FROM sqlite_master WHERE type = 'index' AND tbl_name = 'datoms'",
+                             &[], |row| row.get(0)).expect("counted indexes")
+        };
+        let all_indexes = indexes(&sqlite);
+
+        let block = conn.allocate_entids(&mut sqlite, ":db.part/user", 100).expect("allocated");
+        let first = *block.start();
+        let datoms = block.flat_map(|e| vec![(e, email, TypedValue::typed_string(&format!("{}@example.com", e))),
+                                             (e, age, TypedValue::Long(e - first))]);
+        let report = conn.bulk_import(&mut sqlite, datoms).expect("imported");
+
+        // Each datom is reported, along with the transaction's instant.
+        assert_eq!(report.datoms.len(), 201);
+        assert_eq!(count(&conn, &sqlite), 100);
+        assert_eq!(indexes(&sqlite), all_indexes);
+
+        // The unique index is back, so we can look entities up by email.
+        let found = conn.q_once(&sqlite, format!(r#"[:find ?e . :where [?e :foo/email "{}@example.com"]]"#, first + 5).as_str(), None)
+                        .expect("queried").results;
+        assert_eq!(found, QueryResults::Scalar(Some(TypedValue::Ref(first + 5).into())));
+
+        // Two new entities with the same email conflict with each other, and the second also
+        // conflicts with an existing entity. Nothing is imported.
+        let block = conn.allocate_entids(&mut sqlite, ":db.part/user", 2).expect("allocated");
+        let (x, y) = (*block.start(), *block.end());
+        let taken = TypedValue::typed_string(&format!("{}@example.com", first));
+        let fresh = TypedValue::typed_string("fresh@example.com");
+        match conn.bulk_import(&mut sqlite, vec![(x, email, fresh.clone()), (y, email, fresh.clone()), (y, age, TypedValue::Long(1))]) {
+            Err(Error(ErrorKind::DbError(mentat_db::ErrorKind::ConflictingImport(conflicts)), _)) => {
+                assert_eq!(conflicts, vec![(x, email, fresh.clone()), (y, email, fresh.clone())]);
+            },
+            r => panic!("expected a conflicting import: {:?}", r),
+        }
+        match conn.bulk_import(&mut sqlite, vec![(x, email, taken.clone())]) {
+            Err(Error(ErrorKind::DbError(mentat_db::ErrorKind::ConflictingImport(conflicts)), _)) => {
+                assert_eq!(conflicts, vec![(first, email, taken.clone()), (x, email, taken.clone())]);
+            },
+            r => panic!("expected a conflicting import: {:?}", r),
+        }
+        assert_eq!(count(&conn, &sqlite), 100);
+        assert_eq!(indexes(&sqlite), all_indexes);
+
+        // A second value for a cardinality-one attribute conflicts, too.
+        match conn.bulk_import(&mut sqlite, vec![(first, age, TypedValue::Long(99))]) {
+            Err(Error(ErrorKind::DbError(mentat_db::ErrorKind::ConflictingImport(conflicts)), _)) => {
+                assert_eq!(conflicts, vec![(first, age, TypedValue::Long(0)), (first, age, TypedValue::Long(99))]);
+            },
+            r => panic!("expected a conflicting import: {:?}", r),
+        }
+
+        // Entids must be allocated, and values must be of the attribute's type.
+        match conn.bulk_import(&mut sqlite, vec![(y + 1000, age, TypedValue::Long(1))]) {
+            Err(Error(ErrorKind::DbError(mentat_db::ErrorKind::UnrecognizedEntid(e)), _)) => assert_eq!(e, y + 1000),
+            r => panic!("expected an unrecognized entid: {:?}", r),
+        }
+        match conn.bulk_import(&mut sqlite, vec![(x, age, TypedValue::typed_string("old"))]) {
+            Err(Error(ErrorKind::DbError(mentat_db::ErrorKind::BadImport(_)), _)) => {},
+            r => panic!("expected a bad import: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_entid_for_lookup_ref() {
+        let mut sqlite = db::new_connection("").unwrap();
+        let mut conn = Conn::connect(&mut sqlite).unwrap();
+        conn.transact(&mut sqlite, r#"[
+            [:db/add "s" :db/ident :foo/email]
+            [:db/add "s" :db/valueType :db.type/string]
+            [:db/add "s" :db/cardinality :db.cardinality/one]
+            [:db/add "s" :db/unique :db.unique/identity]
+            [:db/add "t" :db/ident :foo/name]
+            [:db/add "t" :db/valueType :db.type/string]
+            [:db/add "t" :db/cardinality :db.cardinality/one]
+        ]"#).expect("transacted schema");
+        let report = conn.transact(&mut sqlite, r#"[
+            {:db/id "a" :foo/email "a@b.com" :foo/name "Alice"}
+        ]"#).expect("transacted data");
+        let alice = report.tempids.get("a").cloned().expect("alice");
+
+        let email = NamespacedKeyword::new("foo", "email");
+        let found = conn.entid_for_lookup_ref(&sqlite, &email, TypedValue::typed_string("a@b.com")).expect("looked up");
+        assert_eq!(found, Some(alice));
+
+        let missing = conn.entid_for_lookup_ref(&sqlite, &email, TypedValue::typed_string("c@b.com")).expect("looked up");
+        assert_eq!(missing, None);
+
+        let name = NamespacedKeyword::new("foo", "name");
+        match conn.entid_for_lookup_ref(&sqlite, &name, TypedValue::typed_string("Alice")) {
+            Err(Error(ErrorKind::DbError(mentat_db::ErrorKind::NonUniqueLookupRefAttribute(a)), _)) => {
+                assert_eq!(Some(a), conn.current_schema().get_entid(&name));
+            },
+            r => panic!("expected a non-unique attribute: {:?}", r),
+        }
+
+        match conn.entid_for_lookup_ref(&sqlite, &email, TypedValue::Long(5)) {
+            Err(Error(ErrorKind::AttributeValueTypeMismatch(_, ValueType::String, ValueType::Long), _)) => {},
+            r => panic!("expected a type mismatch: {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_transactions_after() {
+        // Two connections to the same file, so that we can commit while reading the log.
+        let path = ::std::env::temp_dir().join("mentat-test-transactions-after.db");
+        let _ = ::std::fs::remove_file(&path);
+        let mut sqlite = db::new_connection(&path).unwrap();
+        let reader = db::new_connection(&path).unwrap();
+        let mut conn = Conn::connect(&mut sqlite).unwrap();
+
+        let schema = conn.transact(&mut sqlite, r#"[
+            {:db/ident :foo/name :db/valueType :db.type/string :db/cardinality :db.cardinality/one}
+        ]"#).expect("transacted schema");
+        let first = conn.transact(&mut sqlite, r#"[{:db/id "a" :foo/name "Alice"}]"#).expect("transacted Alice");
+        let alice = first.tempids["a"];
+        let second = conn.transact(&mut sqlite, format!(r#"[[:db/add {} :foo/name "Alicia"]
+                                                            [:db/add "b" :foo/name "Bob"]]"#, alice).as_str())
+                         .expect("transacted Bob");
+
+        let mut log = conn.transactions_after(&reader, schema.tx_id).expect("opened log");
+
+        // A transaction committed once the log is open isn't part of it.
+        conn.transact(&mut sqlite, r#"[{:db/id "c" :foo/name "Carol"}]"#).expect("transacted Carol");
+
+        let reports: Vec<TxReport> = log.by_ref().collect::<mentat_db::Result<_>>().expect("read log");
+        assert_eq!(reports.len(), 2);
+        for (report, expected) in reports.iter().zip(vec![&first, &second]) {
+            assert_eq!(report.tx_id, expected.tx_id);
+            assert_eq!(report.tx_instant, expected.tx_instant);
+            assert_eq!(report.datoms, expected.datoms);
+        }
+
+        // Retractions come first.
+        let name = conn.current_schema().get_entid(&NamespacedKeyword::new("foo", "name")).unwrap();
+        assert_eq!(reports[1].datoms[0], (alice, name, TypedValue::typed_string("Alice"), false));
+        assert!(log.next().is_none());
+
+        // A new log sees Carol, and one that starts after the last transaction sees nothing.
+        assert_eq!(conn.transactions_after(&reader, second.tx_id).expect("opened log").count(), 1);
+        assert_eq!(conn.transactions_after(&reader, schema.tx_id + 1000000).expect("opened log").count(), 0);
+
+        let _ = ::std::fs::remove_file(&path);
     }
 }
@@ -0,0 +1,351 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Build a transaction in code, rather than writing it as EDN.
+//!
+//! ```ignore
+//! let mut builder = conn.transact_builder();
+//! let alice = builder.named_tempid("alice");
+//! builder.add(alice.clone(), kw!(:person/name), TypedValue::typed_string("Alice"))?
+//!        .add(alice, kw!(:person/age), TypedValue::Long(33))?;
+//! let report = conn.transact_entities(&mut sqlite, builder.build())?;
+//! ```
+//!
+//! The builder produces the same entities as parsing the equivalent EDN would, but checks each
+//! value against its attribute's type as it's added, so a mistake is reported where it was made.
+
+use std::sync::Arc;
+
+use edn::{
+    NamespacedKeyword,
+    SpannedValue,
+    Span,
+    ValueAndSpan,
+};
+
+use mentat_core::{
+    Attribute,
+    Entid,
+    Schema,
+    TypedValue,
+    ValueType,
+};
+
+use mentat_db;
+
+use mentat_tx::entities as entmod;
+use mentat_tx::entities::{
+    AtomOrLookupRefOrVectorOrMapNotation,
+    Entity,
+    EntidOrLookupRefOrTempId,
+    OpType,
+    TempId,
+};
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+
+/// The entity of an assertion or retraction.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum EntityPlace {
+    Entid(Entid),
+    Ident(NamespacedKeyword),
+    TempId(TempId),
+}
+
+impl From<Entid> for EntityPlace {
+    fn from(entid: Entid) -> EntityPlace {
+        EntityPlace::Entid(entid)
+    }
+}
+
+impl From<NamespacedKeyword> for EntityPlace {
+    fn from(ident: NamespacedKeyword) -> EntityPlace {
+        EntityPlace::Ident(ident)
+    }
+}
+
+impl From<TempId> for EntityPlace {
+    fn from(tempid: TempId) -> EntityPlace {
+        EntityPlace::TempId(tempid)
+    }
+}
+
+/// The attribute of an assertion or retraction, which must already be in the schema.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AttributePlace {
+    Entid(Entid),
+    Ident(NamespacedKeyword),
+}
+
+impl From<Entid> for AttributePlace {
+    fn from(entid: Entid) -> AttributePlace {
+        AttributePlace::Entid(entid)
+    }
+}
+
+impl From<NamespacedKeyword> for AttributePlace {
+    fn from(ident: NamespacedKeyword) -> AttributePlace {
+        AttributePlace::Ident(ident)
+    }
+}
+
+/// The value of an assertion or retraction: a value, or, for a ref attribute, a tempid.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ValuePlace {
+    Value(TypedValue),
+    TempId(TempId),
+}
+
+impl From<TypedValue> for ValuePlace {
+    fn from(value: TypedValue) -> ValuePlace {
+        ValuePlace::Value(value)
+    }
+}
+
+impl From<TempId> for ValuePlace {
+    fn from(tempid: TempId) -> ValuePlace {
+        ValuePlace::TempId(tempid)
+    }
+}
+
+fn atom(inner: SpannedValue) -> AtomOrLookupRefOrVectorOrMapNotation {
+    // There's no source text to point into.
+    AtomOrLookupRefOrVectorOrMapNotation::Atom(ValueAndSpan::new(inner, Span(0, 0)))
+}
+
+/// Accumulates the entities of a transaction against a particular schema. Get one from
+/// `Conn::transact_builder`.
+pub struct TransactBuilder {
+    schema: Arc<Schema>,
+    entities: Vec<Entity>,
+}
+
+impl TransactBuilder {
+    pub fn new(schema: Arc<Schema>) -> TransactBuilder {
+        TransactBuilder {
+            schema: schema,
+            entities: vec![],
+        }
+    }
+
+    /// A tempid that the transaction's report maps to the entid it resolves to. Using the same
+    /// name twice names the same entity.
+    pub fn named_tempid<S: Into<String>>(&self, name: S) -> TempId {
+        TempId::External(name.into())
+    }
+
+    pub fn add<E, A, V>(&mut self, e: E, a: A, v: V) -> Result<&mut TransactBuilder>
+    where E: Into<EntityPlace>, A: Into<AttributePlace>, V: Into<ValuePlace> {
+        self.add_or_retract(OpType::Add, e.into(), a.into(), v.into())
+    }
+
+    pub fn retract<E, A, V>(&mut self, e: E, a: A, v: V) -> Result<&mut TransactBuilder>
+    where E: Into<EntityPlace>, A: Into<AttributePlace>, V: Into<ValuePlace> {
+        self.add_or_retract(OpType::Retract, e.into(), a.into(), v.into())
+    }
+
+    /// Is anything to be transacted?
+    pub fn is_empty(&self) -> bool {
+        self.entities.is_empty()
+    }
+
+    /// The entities accumulated so far, ready for `InProgress::transact_entities`.
+    pub fn build(self) -> Vec<Entity> {
+        self.entities
+    }
+
+    fn add_or_retract(&mut self, op: OpType, e: EntityPlace, a: AttributePlace, v: ValuePlace) -> Result<&mut TransactBuilder> {
+        let (a, value) = {
+            let (entid, attribute) = self.attribute(&a)?;
+            (entid, self.value(&a, attribute, v)?)
+        };
+        let e = match e {
+            EntityPlace::Entid(entid) => EntidOrLookupRefOrTempId::Entid(entmod::Entid::Entid(entid)),
+            EntityPlace::Ident(ident) => EntidOrLookupRefOrTempId::Entid(entmod::Entid::Ident(ident)),
+            EntityPlace::TempId(tempid) => EntidOrLookupRefOrTempId::TempId(tempid),
+        };
+        self.entities.push(Entity::AddOrRetract {
+            op: op,
+            e: e,
+            a: entmod::Entid::Entid(a),
+            v: value,
+        });
+        Ok(self)
+    }
+
+    fn attribute(&self, a: &AttributePlace) -> Result<(Entid, &Attribute)> {
+        let entid = match a {
+            &AttributePlace::Entid(entid) => Some(entid),
+            &AttributePlace::Ident(ref ident) => self.schema.get_entid(ident),
+        };
+        match entid.and_then(|entid| self.schema.attribute_for_entid(entid).map(|attribute| (entid, attribute))) {
+            Some(found) => Ok(found),
+            None => bail!(ErrorKind::UnknownAttribute(attribute_name(a))),
+        }
+    }
+
+    /// Check `v` against the type of `attribute`, converting it where the transactor would have
+    /// done so had it been written in EDN: a long or a keyword can name an entity.
+    fn value(&self, a: &AttributePlace, attribute: &Attribute, v: ValuePlace) -> Result<AtomOrLookupRefOrVectorOrMapNotation> {
+        let value_type = attribute.value_type;
+        let inner = match (value_type, v) {
+            (ValueType::Ref, ValuePlace::TempId(TempId::External(name))) => SpannedValue::Text(name),
+            (ValueType::Ref, ValuePlace::Value(TypedValue::Ref(entid))) |
+            (ValueType::Ref, ValuePlace::Value(TypedValue::Long(entid))) => SpannedValue::Integer(entid),
+            (ValueType::Ref, ValuePlace::Value(TypedValue::Keyword(ident))) => {
+                if self.schema.get_entid(&ident).is_none() {
+                    bail!(ErrorKind::DbError(mentat_db::ErrorKind::UnrecognizedIdent(ident.to_string())));
+                }
+                SpannedValue::NamespacedKeyword((*ident).clone())
+            },
+            (ValueType::Double, ValuePlace::Value(TypedValue::Long(x))) => SpannedValue::Float((x as f64).into()),
+            (_, ValuePlace::Value(value)) => {
+                if !value.matches_type(value_type) {
+                    bail!(ErrorKind::AttributeValueTypeMismatch(attribute_name(a), value_type, value.value_type()));
+                }
+                match value {
+                    TypedValue::Boolean(x) => SpannedValue::Boolean(x),
+                    TypedValue::Long(x) => SpannedValue::Integer(x),
+                    TypedValue::Double(x) => SpannedValue::Float(x),
+                    TypedValue::Instant(x) => SpannedValue::Instant(x),
+                    TypedValue::String(x) => SpannedValue::Text((*x).clone()),
+                    TypedValue::Keyword(x) => SpannedValue::NamespacedKeyword((*x).clone()),
+                    TypedValue::Uuid(x) => SpannedValue::Uuid(x),
+                    TypedValue::BigInteger(x) => SpannedValue::BigInteger(x),
+                    TypedValue::Bytes(x) => SpannedValue::Bytes(x),
+                    TypedValue::Ref(_) => unreachable!("refs are handled above"),
+                }
+            },
+            (_, ValuePlace::TempId(_)) => bail!(ErrorKind::AttributeValueTypeMismatch(attribute_name(a), value_type, ValueType::Ref)),
+        };
+        Ok(atom(inner))
+    }
+}
+
+fn attribute_name(a: &AttributePlace) -> String {
+    match a {
+        &AttributePlace::Entid(entid) => entid.to_string(),
+        &AttributePlace::Ident(ref ident) => ident.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::rc::Rc;
+
+    use mentat_db::db;
+
+    use conn::Conn;
+
+    use errors::Error;
+
+    fn kw(namespace: &str, name: &str) -> NamespacedKeyword {
+        NamespacedKeyword::new(namespace, name)
+    }
+
+    fn string(s: &str) -> TypedValue {
+        TypedValue::String(Rc::new(s.to_string()))
+    }
+
+    const SCHEMA: &'static str = r#"[
+        [:db/add "n" :db/ident :foo/name]
+        [:db/add "n" :db/valueType :db.type/string]
+        [:db/add "n" :db/cardinality :db.cardinality/one]
+        [:db/add "n" :db/unique :db.unique/identity]
+        [:db/add "f" :db/ident :foo/friend]
+        [:db/add "f" :db/valueType :db.type/ref]
+        [:db/add "f" :db/cardinality :db.cardinality/many]
+        [:db/add "h" :db/ident :foo/height]
+        [:db/add "h" :db/valueType :db.type/double]
+        [:db/add "h" :db/cardinality :db.cardinality/one]
+    ]"#;
+
+    #[test]
+    fn test_builder_matches_edn() {
+        let mut sqlite = db::new_connection("").unwrap();
+        let mut conn = Conn::connect(&mut sqlite).unwrap();
+        conn.transact(&mut sqlite, SCHEMA).expect("transacted schema");
+
+        let mut other_sqlite = db::new_connection("").unwrap();
+        let mut other_conn = Conn::connect(&mut other_sqlite).unwrap();
+        other_conn.transact(&mut other_sqlite, SCHEMA).expect("transacted schema");
+
+        let edn_report = other_conn.transact(&mut other_sqlite, r#"[
+            [:db/add "a" :foo/name "Alice"]
+            [:db/add "a" :foo/height 1.7]
+            [:db/add "b" :foo/name "Bob"]
+            [:db/add "b" :foo/friend "a"]
+            [:db/add "b" :foo/friend :foo/name]
+        ]"#).expect("transacted EDN");
+
+        let mut builder = conn.transact_builder();
+        let a = builder.named_tempid("a");
+        let b = builder.named_tempid("b");
+        builder.add(a.clone(), kw("foo", "name"), string("Alice")).expect("added")
+               .add(a.clone(), kw("foo", "height"), TypedValue::Double(1.7.into())).expect("added")
+               .add(b.clone(), kw("foo", "name"), string("Bob")).expect("added")
+               .add(b.clone(), kw("foo", "friend"), a).expect("added")
+               .add(b, kw("foo", "friend"), TypedValue::Keyword(Rc::new(kw("foo", "name")))).expect("added");
+        let builder_report = conn.transact_entities(&mut sqlite, builder.build()).expect("transacted builder");
+
+        assert_eq!(builder_report.tx_id, edn_report.tx_id);
+        assert_eq!(builder_report.tempids, edn_report.tempids);
+
+        let query = r#"[:find ?e ?a ?v :where [?e ?a ?v] [?e :foo/name _]]"#;
+        assert_eq!(conn.q_once(&sqlite, query, None).expect("queried").results,
+                   other_conn.q_once(&other_sqlite, query, None).expect("queried").results);
+
+        // Retraction works the same way.
+        let alice = builder_report.tempids["a"];
+        let mut builder = conn.transact_builder();
+        builder.retract(alice, kw("foo", "height"), TypedValue::Long(2)).expect("a long is a double");
+        builder.retract(alice, kw("foo", "name"), string("Alice")).expect("retracted");
+        conn.transact_entities(&mut sqlite, builder.build()).expect("transacted retractions");
+        let names = conn.q_once(&sqlite, r#"[:find [?n ...] :where [_ :foo/name ?n]]"#, None).expect("queried");
+        assert_eq!(names.into_coll().expect("coll").len(), 1);
+    }
+
+    #[test]
+    fn test_builder_errors_early() {
+        let mut sqlite = db::new_connection("").unwrap();
+        let mut conn = Conn::connect(&mut sqlite).unwrap();
+        conn.transact(&mut sqlite, SCHEMA).expect("transacted schema");
+
+        let mut builder = conn.transact_builder();
+        let a = builder.named_tempid("a");
+
+        match builder.add(a.clone(), kw("foo", "name"), TypedValue::Long(1)) {
+            Err(Error(ErrorKind::AttributeValueTypeMismatch(name, ValueType::String, ValueType::Long), _)) => {
+                assert_eq!(name, ":foo/name");
+            },
+            r => panic!("expected a type mismatch: {:?}", r.map(|_| ())),
+        }
+        match builder.add(a.clone(), kw("foo", "name"), a.clone()) {
+            Err(Error(ErrorKind::AttributeValueTypeMismatch(_, ValueType::String, ValueType::Ref), _)) => {},
+            r => panic!("expected a type mismatch: {:?}", r.map(|_| ())),
+        }
+        match builder.add(a.clone(), kw("foo", "nickname"), string("Al")) {
+            Err(Error(ErrorKind::UnknownAttribute(name), _)) => assert_eq!(name, ":foo/nickname"),
+            r => panic!("expected an unknown attribute: {:?}", r.map(|_| ())),
+        }
+        match builder.add(a, kw("foo", "friend"), TypedValue::Keyword(Rc::new(kw("foo", "nobody")))) {
+            Err(Error(ErrorKind::DbError(mentat_db::ErrorKind::UnrecognizedIdent(ident)), _)) => assert_eq!(ident, ":foo/nobody"),
+            r => panic!("expected an unknown ident: {:?}", r.map(|_| ())),
+        }
+
+        // Nothing was added.
+        assert!(builder.is_empty());
+    }
+}
@@ -15,6 +15,10 @@ use rusqlite;
 use std::collections::BTreeSet;
 
 use edn;
+use mentat_core::{
+    ValueType,
+    ValueTypeSet,
+};
 use mentat_db;
 use mentat_query_algebrizer;
 use mentat_query_parser;
@@ -53,5 +57,60 @@ error_chain! {
             description("invalid argument name")
             display("invalid argument name: '{}'", name)
         }
+
+        NoTransactionAsOf(instant: String) {
+            description("no transaction at or before the given instant")
+            display("no transaction at or before {}", instant)
+        }
+
+        UnknownInputType(name: String) {
+            description("type of input variable unknown when preparing query")
+            display("cannot prepare query: the type of input variable {} is not known", name)
+        }
+
+        StalePreparedQuery {
+            description("prepared query is stale")
+            display("the schema has changed since the query was prepared")
+        }
+
+        UnknownAttribute(name: String) {
+            description("unknown attribute")
+            display("unknown attribute: '{}'", name)
+        }
+
+        AttributeValueTypeMismatch(name: String, expected: ValueType, provided: ValueType) {
+            description("value of the wrong type for attribute")
+            display("attribute {} expects a value of type {:?}, not {:?}", name, expected, provided)
+        }
+
+        CannotStreamQuery {
+            description("query cannot be streamed")
+            display("queries with aggregates or pull expressions cannot be streamed")
+        }
+
+        UnknownRowVariable(name: String) {
+            description("no such variable in the query's results")
+            display("no variable {} in the query's results", name)
+        }
+
+        RowValueTypeMismatch(name: String, requested: ValueType, types: ValueTypeSet) {
+            description("variable in the query's results is of the wrong type")
+            display("variable {} is not of type {:?}, but one of {:?}", name, requested, types)
+        }
+
+        CannotPageQuery {
+            description("query cannot be paged")
+            display("only queries that find a collection or a relation can be paged")
+        }
+
+        UnfoundCursorVariable(name: String) {
+            description("ordered variable not found by a paged query")
+            display("variable {} orders a paged query, so it must be found", name)
+        }
+
+        QueryInterrupted {
+            description("query interrupted")
+            display("the query was still running at its deadline, and so was interrupted")
+        }
     }
 }
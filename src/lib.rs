@@ -27,10 +27,15 @@ extern crate mentat_tx_parser;
 
 use rusqlite::Connection;
 
+pub mod attributes;
 pub mod errors;
 pub mod ident;
 pub mod conn;
+pub mod entity_builder;
+pub mod observer;
 pub mod query;
+pub mod query_cache;
+pub mod row;
 
 pub fn get_name() -> String {
     return String::from("mentat");
@@ -42,26 +47,73 @@ pub fn get_connection() -> Connection {
 }
 
 pub use mentat_core::{
+    Binding,
+    CoercionKind,
+    StructuredMap,
     TypedValue,
     ValueType,
 };
 
 pub use mentat_db::{
+    CacheDirection,
+    SchemaChange,
     new_connection,
+    schema_diff,
+    seed_random,
 };
 
 pub use query::{
+    AlgebrizedQuery,
+    AsOf,
     NamespacedKeyword,
     PlainSymbol,
     QueryInputs,
+    QueryCursor,
+    QueryInputsBuilder,
+    QueryExplanation,
+    QueryOutput,
+    QueryPage,
+    QueryPlanStep,
     QueryResults,
+    QueryRows,
+    QueryStream,
+    RuleSet,
     Variable,
+    algebrize_inspect,
+    attribute_count,
+    explain,
+    parse_rules_string,
     q_once,
+    q_once_with_deadline,
+    q_once_with_rules,
+    query_after,
+    query_as_of,
+    query_history,
+    query_iter,
+};
+
+pub use query_cache::{
+    PreparedQuery,
+    QueryCache,
 };
 
 pub use conn::{
     Conn,
     Metadata,
+    ReadSnapshot,
+};
+
+pub use attributes::AttributeInfo;
+
+pub use observer::TxObserver;
+
+pub use row::RowView;
+
+pub use entity_builder::{
+    AttributePlace,
+    EntityPlace,
+    TransactBuilder,
+    ValuePlace,
 };
 
 #[cfg(test)]
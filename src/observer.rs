@@ -0,0 +1,76 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Observers are told about committed transactions that changed the attributes they watch.
+//! See `Conn::register_observer`.
+
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
+
+use std::sync::Arc;
+
+use mentat_core::Entid;
+
+use mentat_db::TxReport;
+
+pub struct TxObserver {
+    attributes: BTreeSet<Entid>,
+    notify: Box<Fn(&TxReport) + Send + Sync>,
+}
+
+impl TxObserver {
+    pub fn new<F>(attributes: BTreeSet<Entid>, notify: F) -> TxObserver
+    where F: Fn(&TxReport) + Send + Sync + 'static {
+        TxObserver {
+            attributes: attributes,
+            notify: Box::new(notify),
+        }
+    }
+
+    /// Did the transaction of `report` change any of our attributes?
+    pub fn is_interested(&self, report: &TxReport) -> bool {
+        report.datoms.iter().any(|&(_, a, _, _)| self.attributes.contains(&a))
+    }
+
+    pub fn notify(&self, report: &TxReport) {
+        (self.notify)(report)
+    }
+}
+
+/// The observers registered with a `Conn`, by key.
+#[derive(Default)]
+pub struct TxObservers {
+    observers: BTreeMap<String, Arc<TxObserver>>,
+}
+
+impl TxObservers {
+    /// Register `observer` as `key`, replacing any observer already registered as `key`.
+    pub fn register(&mut self, key: String, observer: TxObserver) {
+        self.observers.insert(key, Arc::new(observer));
+    }
+
+    /// Returns `true` if there was an observer registered as `key`.
+    pub fn unregister(&mut self, key: &str) -> bool {
+        self.observers.remove(key).is_some()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.observers.is_empty()
+    }
+
+    /// The observers interested in `report`. They're returned, rather than notified here, so that
+    /// they can be notified without holding any lock on us: an observer is free to register or
+    /// unregister observers itself.
+    pub fn interested(&self, report: &TxReport) -> Vec<Arc<TxObserver>> {
+        self.observers.values().filter(|o| o.is_interested(report)).cloned().collect()
+    }
+}
@@ -8,33 +8,82 @@
 // CONDITIONS OF ANY KIND, either express or implied. See the License for the
 // specific language governing permissions and limitations under the License.
 
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
+
+use std::cell::Cell;
+use std::fmt;
+use std::os::raw::{
+    c_int,
+    c_void,
+};
+use std::ptr;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Instant;
+
 use rusqlite;
+use rusqlite::ffi;
 use rusqlite::types::ToSql;
 
 use mentat_core::{
+    Binding,
+    CachedAttributes,
+    DateTime,
+    Entid,
     Schema,
     TypedValue,
+    ToMicros,
+    Utc,
+    ValueType,
+    ValueTypeSet,
+};
+
+use mentat_db::{
+    TypedSQLValue,
+    entids,
 };
 
 use mentat_query_algebrizer::{
+    AlgebraicQuery,
+    algebrize,
+    algebrize_with_cache,
     algebrize_with_inputs,
+    algebrize_with_rules,
+    constrain_after,
 };
 
 pub use mentat_query_algebrizer::{
+    DEFAULT_MAX_RULE_DEPTH,
+    EmptyBecause,
+    QualifiedAlias,
     QueryInputs,
+    QueryInputsBuilder,
+    RuleSet,
+};
+
+use mentat_query::{
+    Element,
+    Order,
 };
 
 pub use mentat_query::{
+    FindQuery,
+    FindSpec,
     NamespacedKeyword,
     PlainSymbol,
     Variable,
 };
 
-use mentat_query_parser::{
+pub use mentat_query_parser::{
     parse_find_string,
+    parse_rules_string,
 };
 
 use mentat_sql::{
+    self,
     SQLQuery,
 };
 
@@ -42,46 +91,103 @@ use mentat_query_translator::{
     query_to_select,
 };
 
+use mentat_query_projector::{
+    RowProjector,
+    projected_columns,
+};
+
 pub use mentat_query_projector::{
+    QueryOutput,
     QueryResults,
 };
 
+use mentat_db;
+
 use errors::{
     ErrorKind,
     Result,
 };
 
-pub type QueryExecutionResult = Result<QueryResults>;
+pub type QueryExecutionResult = Result<QueryOutput>;
 
 pub trait IntoResult {
-    fn into_scalar_result(self) -> Result<Option<TypedValue>>;
-    fn into_coll_result(self) -> Result<Vec<TypedValue>>;
-    fn into_tuple_result(self) -> Result<Option<Vec<TypedValue>>>;
-    fn into_rel_result(self) -> Result<Vec<Vec<TypedValue>>>;
+    fn into_scalar_result(self) -> Result<Option<Binding>>;
+    fn into_coll_result(self) -> Result<Vec<Binding>>;
+    fn into_tuple_result(self) -> Result<Option<Vec<Binding>>>;
+    fn into_rel_result(self) -> Result<Vec<Vec<Binding>>>;
 }
 
 impl IntoResult for QueryExecutionResult {
-    fn into_scalar_result(self) -> Result<Option<TypedValue>> {
+    fn into_scalar_result(self) -> Result<Option<Binding>> {
         self?.into_scalar().map_err(|e| e.into())
     }
 
-    fn into_coll_result(self) -> Result<Vec<TypedValue>> {
+    fn into_coll_result(self) -> Result<Vec<Binding>> {
         self?.into_coll().map_err(|e| e.into())
     }
 
-    fn into_tuple_result(self) -> Result<Option<Vec<TypedValue>>> {
+    fn into_tuple_result(self) -> Result<Option<Vec<Binding>>> {
         self?.into_tuple().map_err(|e| e.into())
     }
 
-    fn into_rel_result(self) -> Result<Vec<Vec<TypedValue>>> {
+    fn into_rel_result(self) -> Result<Vec<Vec<Binding>>> {
         self?.into_rel().map_err(|e| e.into())
     }
 }
 
+/// A read-only view of an algebrized query. Tools can use this to find out what Mentat makes of a
+/// query -- whether it can ever return results, and which types its variables can take -- without
+/// running it.
+pub struct AlgebrizedQuery(AlgebraicQuery);
+
+impl AlgebrizedQuery {
+    pub fn find_spec(&self) -> &FindSpec {
+        &self.0.find_spec
+    }
+
+    /// Return true if the query can never return results against the schema it was algebrized
+    /// with.
+    pub fn is_known_empty(&self) -> bool {
+        self.0.is_known_empty()
+    }
+
+    /// If the query can never return results, the first reason we found.
+    pub fn empty_because(&self) -> Option<&EmptyBecause> {
+        self.0.cc.empty_because.as_ref()
+    }
+
+    /// The types that each variable might take. A variable that isn't present could have any type.
+    pub fn known_types(&self) -> &BTreeMap<Variable, ValueTypeSet> {
+        &self.0.cc.known_types
+    }
+
+    /// The type of `var`, if it can only take one.
+    pub fn known_type(&self, var: &Variable) -> Option<ValueType> {
+        self.0.cc.known_type(var)
+    }
+
+    /// The table columns to which each variable is bound. The first column for each variable is
+    /// the one that would be projected.
+    pub fn column_bindings(&self) -> &BTreeMap<Variable, Vec<QualifiedAlias>> {
+        &self.0.cc.column_bindings
+    }
+
+    /// The variables in `:in` that would need to be bound before the query could run.
+    pub fn unbound_variables(&self) -> BTreeSet<Variable> {
+        self.0.unbound_variables()
+    }
+}
+
+/// Algebrize `query` against `schema` without executing it. No inputs are bound, so any `:in`
+/// variables are reported by `AlgebrizedQuery::unbound_variables`.
+pub fn algebrize_inspect(schema: &Schema, query: &FindQuery) -> Result<AlgebrizedQuery> {
+    Ok(AlgebrizedQuery(algebrize(schema, query.clone())?))
+}
+
 /// Take an EDN query string, a reference to an open SQLite connection, a Mentat schema, and an
 /// optional collection of input bindings (which should be keyed by `"?varname"`), and execute the
 /// query immediately, blocking the current thread.
-/// Returns a structure that corresponds to the kind of input query, populated with `TypedValue`
+/// Returns a structure that corresponds to the kind of input query, populated with `Binding`
 /// instances.
 /// The caller is responsible for ensuring that the SQLite connection has an open transaction if
 /// isolation is required.
@@ -92,12 +198,288 @@ pub fn q_once<'sqlite, 'schema, 'query, T>
  inputs: T) -> QueryExecutionResult
         where T: Into<Option<QueryInputs>>
 {
+    run_query(sqlite, schema, None, None, query, None, inputs.into())
+}
+
+/// How many SQLite virtual machine instructions to run between checks of a query's deadline.
+const DEADLINE_CHECK_INTERVAL: c_int = 1000;
+
+struct Deadline {
+    at: Instant,
+    passed: Cell<bool>,
+}
+
+/// A SQLite progress handler that interrupts whatever `sqlite` is running once a deadline has
+/// passed. Interrupting a statement resets it, so nothing is left running. The handler is removed
+/// when the guard is dropped, however the query ends.
+struct DeadlineGuard<'sqlite> {
+    sqlite: &'sqlite rusqlite::Connection,
+    // Boxed so that its address, which SQLite holds, doesn't change when we move.
+    deadline: Box<Deadline>,
+}
+
+impl<'sqlite> DeadlineGuard<'sqlite> {
+    fn install(sqlite: &'sqlite rusqlite::Connection, at: Instant) -> DeadlineGuard<'sqlite> {
+        let deadline = Box::new(Deadline {
+            at: at,
+            passed: Cell::new(false),
+        });
+        unsafe {
+            ffi::sqlite3_progress_handler(sqlite.handle(),
+                                          DEADLINE_CHECK_INTERVAL,
+                                          Some(check_deadline),
+                                          &*deadline as *const Deadline as *mut c_void);
+        }
+        DeadlineGuard {
+            sqlite: sqlite,
+            deadline: deadline,
+        }
+    }
+
+    /// Did we interrupt anything?
+    fn passed(&self) -> bool {
+        self.deadline.passed.get()
+    }
+}
+
+impl<'sqlite> Drop for DeadlineGuard<'sqlite> {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::sqlite3_progress_handler(self.sqlite.handle(), 0, None, ptr::null_mut());
+        }
+    }
+}
+
+unsafe extern "C" fn check_deadline(deadline: *mut c_void) -> c_int {
+    let deadline = &*(deadline as *const Deadline);
+    if Instant::now() >= deadline.at {
+        deadline.passed.set(true);
+        1
+    } else {
+        0
+    }
+}
+
+/// Like `q_once`, but if the query is still running at `deadline`, it's interrupted, and we fail
+/// with `QueryInterrupted`.
+pub fn q_once_with_deadline<'sqlite, 'schema, 'query, T>
+(sqlite: &'sqlite rusqlite::Connection,
+ schema: &'schema Schema,
+ query: &'query str,
+ inputs: T,
+ deadline: Instant) -> QueryExecutionResult
+        where T: Into<Option<QueryInputs>>
+{
+    let guard = DeadlineGuard::install(sqlite, deadline);
+    let result = run_query(sqlite, schema, None, None, query, None, inputs.into());
+    if result.is_err() && guard.passed() {
+        bail!(ErrorKind::QueryInterrupted);
+    }
+    result
+}
+
+/// Like `q_once`, but the query can invoke the rules in `rules`.
+pub fn q_once_with_rules<'sqlite, 'schema, 'query, T>
+(sqlite: &'sqlite rusqlite::Connection,
+ schema: &'schema Schema,
+ rules: &RuleSet,
+ query: &'query str,
+ inputs: T) -> QueryExecutionResult
+        where T: Into<Option<QueryInputs>>
+{
+    run_query(sqlite, schema, None, Some(rules), query, None, inputs.into())
+}
+
+/// Like `q_once`, but answer what we can from the in-memory attribute `cache` rather than the
+/// store.
+pub fn q_once_with_cache<'sqlite, 'schema, 'query, T>
+(sqlite: &'sqlite rusqlite::Connection,
+ schema: &'schema Schema,
+ cache: Arc<CachedAttributes>,
+ query: &'query str,
+ inputs: T) -> QueryExecutionResult
+        where T: Into<Option<QueryInputs>>
+{
+    run_query(sqlite, schema, Some(cache), None, query, None, inputs.into())
+}
+
+/// The point in the store's history at which an `asOf` query is answered.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AsOf {
+    /// The state of the store just after the given transaction.
+    Tx(Entid),
+
+    /// The state of the store after the last transaction whose `:db/txInstant` is at or before the
+    /// given instant.
+    Instant(DateTime<Utc>),
+}
+
+impl From<Entid> for AsOf {
+    fn from(tx: Entid) -> AsOf {
+        AsOf::Tx(tx)
+    }
+}
+
+impl From<DateTime<Utc>> for AsOf {
+    fn from(instant: DateTime<Utc>) -> AsOf {
+        AsOf::Instant(instant)
+    }
+}
+
+impl AsOf {
+    /// The transaction whose state this refers to.
+    fn resolve_tx(&self, sqlite: &rusqlite::Connection) -> Result<Entid> {
+        match self {
+            &AsOf::Tx(tx) => Ok(tx),
+            &AsOf::Instant(instant) => {
+                let tx: Option<Entid> = sqlite.query_row(
+                    "SELECT max(tx) FROM transactions WHERE a = ? AND added = 1 AND v <= ?",
+                    &[&entids::DB_TX_INSTANT as &ToSql, &instant.to_micros()],
+                    |row| row.get(0))?;
+                match tx {
+                    Some(tx) => Ok(tx),
+                    None => bail!(ErrorKind::NoTransactionAsOf(instant.to_string())),
+                }
+            },
+        }
+    }
+}
+
+/// Like `q_once`, but answer the query against the store as it was at `as_of`: only assertions
+/// made in or before that transaction, and not retracted by then, are visible.
+///
+/// `as_of` is either a transaction entid or an instant, which is mapped to the last transaction
+/// at or before it.
+///
+/// Pull expressions are not yet time-travelled: they fetch the current attributes of the entities
+/// the query finds.
+pub fn query_as_of<'sqlite, 'schema, 'query, A, T>
+(sqlite: &'sqlite rusqlite::Connection,
+ schema: &'schema Schema,
+ query: &'query str,
+ as_of: A,
+ inputs: T) -> QueryExecutionResult
+        where A: Into<AsOf>,
+              T: Into<Option<QueryInputs>>
+{
+    let tx = as_of.into().resolve_tx(sqlite)?;
+    run_query(sqlite, schema, None, None, query, Some(tx), inputs.into())
+}
+
+/// A `WITH` clause that shadows the datoms tables with their contents as of `tx`, rebuilt from
+/// the transaction log.
+fn as_of_prefix(schema: &Schema, tx: Entid) -> String {
+    // `transactions` doesn't record which attributes are fulltext indexed, so we take that from
+    // the schema. Entids are integers, so it's safe to interpolate them.
+    let fulltext: Vec<String> = schema.schema_map
+                                      .iter()
+                                      .filter(|&(_, attribute)| attribute.fulltext)
+                                      .map(|(e, _)| e.to_string())
+                                      .collect();
+    let fulltext = fulltext.join(", ");
+
+    format!("WITH \
+             timeline AS (SELECT t.e, t.a, t.v, t.tx, t.value_type_tag FROM transactions AS t \
+                          WHERE t.added = 1 AND t.tx <= {tx} AND NOT EXISTS \
+                          (SELECT 1 FROM transactions AS r \
+                           WHERE r.e = t.e AND r.a = t.a AND r.v = t.v AND r.value_type_tag = t.value_type_tag \
+                           AND r.added = 0 AND r.tx > t.tx AND r.tx <= {tx})), \
+             datoms AS (SELECT e, a, v, tx, value_type_tag FROM timeline WHERE a NOT IN ({fulltext})), \
+             fulltext_datoms AS (SELECT e, a, fulltext_values.text AS v, tx, value_type_tag \
+                                 FROM timeline, fulltext_values \
+                                 WHERE timeline.a IN ({fulltext}) AND timeline.v = fulltext_values.rowid), \
+             all_datoms AS (SELECT e, a, v, tx, value_type_tag FROM datoms \
+                            UNION ALL \
+                            SELECT e, a, v, tx, value_type_tag FROM fulltext_datoms) ",
+            tx = tx,
+            fulltext = fulltext)
+}
+
+/// Return the full history of attribute `attr` of entity `e`: every `[e a v tx added]` that was
+/// ever transacted, in transaction order, with retractions included. `added` is a
+/// `TypedValue::Boolean`, and the entity, attribute, and transaction are `TypedValue::Ref`s.
+pub fn query_history(sqlite: &rusqlite::Connection,
+                     schema: &Schema,
+                     e: Entid,
+                     attr: &NamespacedKeyword) -> Result<Vec<Vec<TypedValue>>> {
+    let a = match schema.get_entid(attr) {
+        Some(a) if schema.is_attribute(a) => a,
+        _ => bail!(ErrorKind::DbError(mentat_db::ErrorKind::UnrecognizedIdent(attr.to_string()))),
+    };
+    let fulltext = schema.attribute_for_entid(a).map_or(false, |attribute| attribute.fulltext);
+
+    // Within a transaction, retractions come before assertions, just as the transactor applies
+    // them.
+    let sql = if fulltext {
+        "SELECT t.e, t.a, fulltext_values.text, t.value_type_tag, t.tx, t.added \
+         FROM transactions AS t, fulltext_values \
+         WHERE t.e = ? AND t.a = ? AND t.v = fulltext_values.rowid \
+         ORDER BY t.tx, t.added"
+    } else {
+        "SELECT e, a, v, value_type_tag, tx, added \
+         FROM transactions \
+         WHERE e = ? AND a = ? \
+         ORDER BY tx, added"
+    };
+
+    let mut stmt = sqlite.prepare(sql)?;
+    let mut rows = stmt.query(&[&e as &ToSql, &a])?;
+    let mut history = vec![];
+    while let Some(row) = rows.next() {
+        let row = row?;
+        let e: Entid = row.get_checked(0)?;
+        let a: Entid = row.get_checked(1)?;
+        let v: rusqlite::types::Value = row.get_checked(2)?;
+        let value_type_tag: i32 = row.get_checked(3)?;
+        let tx: Entid = row.get_checked(4)?;
+        let added: bool = row.get_checked(5)?;
+        history.push(vec![TypedValue::Ref(e),
+                          TypedValue::Ref(a),
+                          TypedValue::from_sql_value_pair(v, value_type_tag)?,
+                          TypedValue::Ref(tx),
+                          TypedValue::Boolean(added)]);
+    }
+    Ok(history)
+}
+
+/// The number of datoms currently asserted for `attr`, counted without reading any of them.
+pub fn attribute_count(sqlite: &rusqlite::Connection,
+                       schema: &Schema,
+                       attr: &NamespacedKeyword) -> Result<u64> {
+    let a = match schema.get_entid(attr) {
+        Some(a) if schema.is_attribute(a) => a,
+        _ => bail!(ErrorKind::DbError(mentat_db::ErrorKind::UnrecognizedIdent(attr.to_string()))),
+    };
+    let count: i64 = sqlite.query_row("SELECT COUNT(*) FROM datoms WHERE a = ?", &[&a], |row| row.get(0))?;
+    Ok(count as u64)
+}
+
+fn run_query(sqlite: &rusqlite::Connection,
+             schema: &Schema,
+             cache: Option<Arc<CachedAttributes>>,
+             rules: Option<&RuleSet>,
+             query: &str,
+             as_of: Option<Entid>,
+             inputs: Option<QueryInputs>) -> QueryExecutionResult {
     let parsed = parse_find_string(query)?;
-    let algebrized = algebrize_with_inputs(schema, parsed, 0, inputs.into().unwrap_or(QueryInputs::default()))?;
+    let inputs = inputs.unwrap_or(QueryInputs::default());
+    let algebrized = match (cache, rules) {
+        (Some(cache), _) => algebrize_with_cache(schema, cache, parsed, inputs)?,
+        (None, Some(rules)) => algebrize_with_rules(schema, parsed, rules, inputs)?,
+        (None, None) => algebrize_with_inputs(schema, parsed, 0, inputs)?,
+    };
+    run_algebrized_query(sqlite, schema, algebrized, as_of)
+}
 
-    if algebrized.is_known_empty() {
+fn run_algebrized_query(sqlite: &rusqlite::Connection,
+                        schema: &Schema,
+                        algebrized: AlgebraicQuery,
+                        as_of: Option<Entid>) -> QueryExecutionResult {
+    let columns = projected_columns(&algebrized);
+
+    if let Some(because) = algebrized.cc.empty_because.clone() {
         // We don't need to do any SQL work at all.
-        return Ok(QueryResults::empty(&algebrized.find_spec));
+        return Ok(QueryOutput::proven_empty(&algebrized.find_spec, because).with_columns(columns));
     }
 
     // Because this is q_once, we can check that all of our `:in` variables are bound at this point.
@@ -110,6 +492,11 @@ pub fn q_once<'sqlite, 'schema, 'query, T>
     let select = query_to_select(algebrized)?;
     let SQLQuery { sql, args } = select.query.to_sql_query()?;
 
+    let sql = match as_of {
+        Some(tx) => format!("{}{}", as_of_prefix(schema, tx), sql),
+        None => sql,
+    };
+
     let mut statement = sqlite.prepare(sql.as_str())?;
 
     let rows = if args.is_empty() {
@@ -123,6 +510,302 @@ pub fn q_once<'sqlite, 'schema, 'query, T>
     };
 
     select.projector
-          .project(rows)
+          .project(schema, sqlite, rows)
+          .map(|results| QueryOutput::from(results).with_columns(columns))
           .map_err(|e| e.into())
 }
+
+/// Where a page of a query's results ends: the value of each of the query's `:order` variables
+/// in the last row. See `query_after`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct QueryCursor {
+    values: BTreeMap<Variable, TypedValue>,
+}
+
+impl QueryCursor {
+    pub fn new(values: BTreeMap<Variable, TypedValue>) -> QueryCursor {
+        QueryCursor {
+            values: values,
+        }
+    }
+
+    pub fn get(&self, var: &Variable) -> Option<&TypedValue> {
+        self.values.get(var)
+    }
+}
+
+/// One page of a query's results, and the cursor from which to fetch the next, if this page
+/// has any rows at all.
+#[derive(Debug, PartialEq)]
+pub struct QueryPage {
+    pub output: QueryOutput,
+    pub next: Option<QueryCursor>,
+}
+
+/// Like `q_once`, but return only the results that follow `after` in the query's `:order`. The
+/// query's `:limit` is the size of a page, and the last row of each page gives the cursor for the
+/// next, so that
+///
+/// ```ignore
+/// let mut page = query_after(&sqlite, &schema, query, None, None)?;
+/// while let Some(next) = page.next.take() {
+///     page = query_after(&sqlite, &schema, query, None, Some(&next))?;
+/// }
+/// ```
+///
+/// visits every result once, provided the order is total, breaking every tie.
+///
+/// The query must find a collection or a relation, and each ordered variable must be found.
+pub fn query_after<'sqlite, 'schema, 'query, T>
+(sqlite: &'sqlite rusqlite::Connection,
+ schema: &'schema Schema,
+ query: &'query str,
+ inputs: T,
+ after: Option<&QueryCursor>) -> Result<QueryPage>
+        where T: Into<Option<QueryInputs>>
+{
+    let parsed = parse_find_string(query)?;
+
+    // Find where each ordered variable is in a row of results.
+    let mut keys: Vec<(Variable, usize)> = vec![];
+    {
+        let elements: Vec<&Element> = match parsed.find_spec {
+            FindSpec::FindColl(ref element) => vec![element],
+            FindSpec::FindRel(ref elements) => elements.iter().collect(),
+            FindSpec::FindScalar(_) | FindSpec::FindTuple(_) => bail!(ErrorKind::CannotPageQuery),
+        };
+        for &Order(_, ref var) in parsed.order.iter().flat_map(|order| order.iter()) {
+            let index = elements.iter().position(|element| match *element {
+                &Element::Variable(ref found) => found == var,
+                _ => false,
+            });
+            match index {
+                Some(index) => keys.push((var.clone(), index)),
+                None => bail!(ErrorKind::UnfoundCursorVariable(var.to_string())),
+            }
+        }
+    }
+
+    let inputs = inputs.into().unwrap_or(QueryInputs::default());
+    let mut algebrized = algebrize_with_inputs(schema, parsed, 0, inputs)?;
+    if let Some(after) = after {
+        constrain_after(&mut algebrized, &after.values)?;
+    }
+    let output = run_algebrized_query(sqlite, schema, algebrized, None)?;
+
+    let next = {
+        let last: Option<Vec<&Binding>> = match output.results {
+            QueryResults::Coll(ref values) => values.last().map(|v| vec![v]),
+            QueryResults::Rel(ref rows) => rows.last().map(|row| row.iter().collect()),
+            _ => unreachable!(),
+        };
+        match last {
+            None => None,
+            Some(row) => {
+                let mut values = BTreeMap::new();
+                for (var, index) in keys.into_iter() {
+                    match row[index] {
+                        &Binding::Scalar(ref value) => values.insert(var, value.clone()),
+                        _ => unreachable!(),
+                    };
+                }
+                Some(QueryCursor::new(values))
+            },
+        }
+    };
+
+    Ok(QueryPage {
+        output: output,
+        next: next,
+    })
+}
+
+/// A query that's ready to stream its results. Rows borrow the SQLite statement they come from,
+/// so this holds the statement and `rows` borrows it to produce an iterator.
+pub struct QueryStream<'sqlite> {
+    /// `None` if the query is known to return no results.
+    prepared: Option<(rusqlite::Statement<'sqlite>, RowProjector)>,
+    args: Vec<(String, Rc<mentat_sql::Value>)>,
+}
+
+impl<'sqlite> QueryStream<'sqlite> {
+    /// Run the query. Each row is fetched from SQLite and projected only when the iterator is
+    /// advanced, so dropping the iterator early avoids reading the rest of the results.
+    pub fn rows<'s>(&'s mut self) -> Result<QueryRows<'s>> {
+        let QueryStream { ref mut prepared, ref args } = *self;
+        let (statement, projector) = match prepared.as_mut() {
+            Some(&mut (ref mut statement, ref projector)) => (statement, projector),
+            None => return Ok(QueryRows { inner: None }),
+        };
+
+        let rows = if args.is_empty() {
+            statement.query(&[])?
+        } else {
+            let refs: Vec<(&str, &ToSql)> =
+                args.iter()
+                    .map(|&(ref k, ref v)| (k.as_str(), v.as_ref() as &ToSql))
+                    .collect();
+            statement.query_named(refs.as_slice())?
+        };
+        Ok(QueryRows { inner: Some((rows, projector)) })
+    }
+}
+
+/// An iterator over the results of a `QueryStream`. Each item holds the values of the find spec's
+/// elements, in order.
+pub struct QueryRows<'stmt> {
+    inner: Option<(rusqlite::Rows<'stmt>, &'stmt RowProjector)>,
+}
+
+impl<'stmt> Iterator for QueryRows<'stmt> {
+    type Item = Result<Vec<TypedValue>>;
+
+    fn next(&mut self) -> Option<Result<Vec<TypedValue>>> {
+        match self.inner {
+            Some((ref mut rows, projector)) => {
+                rows.next().map(|row| {
+                    let row = row?;
+                    projector.project_row(&row).map_err(|e| e.into())
+                })
+            },
+            None => None,
+        }
+    }
+}
+
+/// Prepare `query` to stream its results rather than collecting them, which is useful for queries
+/// that return a great many rows. Queries with aggregates or pull expressions need to see every
+/// row before they can project anything, so they can't be streamed.
+pub fn query_iter<'sqlite, T>(sqlite: &'sqlite rusqlite::Connection,
+                              schema: &Schema,
+                              query: &str,
+                              inputs: T) -> Result<QueryStream<'sqlite>>
+        where T: Into<Option<QueryInputs>>
+{
+    let parsed = parse_find_string(query)?;
+    let inputs = inputs.into().unwrap_or(QueryInputs::default());
+    let algebrized = algebrize_with_inputs(schema, parsed, 0, inputs)?;
+
+    if algebrized.is_known_empty() {
+        return Ok(QueryStream { prepared: None, args: vec![] });
+    }
+
+    let unbound = algebrized.unbound_variables();
+    if !unbound.is_empty() {
+        bail!(ErrorKind::UnboundVariables(unbound.into_iter().map(|v| v.to_string()).collect()));
+    }
+    let select = query_to_select(algebrized)?;
+    let projector = match select.projector.row_projector() {
+        Some(projector) => projector,
+        None => bail!(ErrorKind::CannotStreamQuery),
+    };
+    let SQLQuery { sql, args } = select.query.to_sql_query()?;
+    let statement = sqlite.prepare(sql.as_str())?;
+
+    Ok(QueryStream {
+        prepared: Some((statement, projector)),
+        args: args,
+    })
+}
+
+/// One step of SQLite's plan for a query, as reported by `EXPLAIN QUERY PLAN`. `detail` says
+/// what the step does, like `SEARCH TABLE datoms AS datoms00 USING INDEX idx_datoms_aevt (a=?)`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct QueryPlanStep {
+    pub id: i64,
+    pub parent: i64,
+    pub detail: String,
+}
+
+/// What Mentat would do to run a query. See `explain`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum QueryExplanation {
+    /// The query is known to return no results, so no SQL would be run at all.
+    KnownEmpty(EmptyBecause),
+
+    /// The query would run `sql` with `args`, which SQLite would execute by way of `steps`.
+    ExecutionPlan {
+        column_bindings: BTreeMap<Variable, Vec<QualifiedAlias>>,
+        sql: String,
+        args: Vec<(String, Rc<mentat_sql::Value>)>,
+        steps: Vec<QueryPlanStep>,
+    },
+}
+
+impl fmt::Display for QueryExplanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &QueryExplanation::KnownEmpty(ref because) => write!(f, "known to be empty: {:?}", because),
+            &QueryExplanation::ExecutionPlan { ref column_bindings, ref sql, ref args, ref steps } => {
+                writeln!(f, "bindings:")?;
+                for (var, columns) in column_bindings {
+                    let columns: Vec<String> = columns.iter().map(|c| format!("{:?}", c)).collect();
+                    writeln!(f, "  {} -> {}", var.as_str(), columns.join(", "))?;
+                }
+                writeln!(f, "sql:\n  {}", sql)?;
+                if !args.is_empty() {
+                    writeln!(f, "args:")?;
+                    for &(ref name, ref value) in args {
+                        writeln!(f, "  {} = {:?}", name, value)?;
+                    }
+                }
+                writeln!(f, "plan:")?;
+                for step in steps {
+                    writeln!(f, "  {}", step.detail)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+/// Translate `query` and ask SQLite how it would run the result, without running it.
+pub fn explain<T>(sqlite: &rusqlite::Connection,
+                  schema: &Schema,
+                  query: &str,
+                  inputs: T) -> Result<QueryExplanation>
+        where T: Into<Option<QueryInputs>>
+{
+    let parsed = parse_find_string(query)?;
+    let inputs = inputs.into().unwrap_or(QueryInputs::default());
+    let algebrized = algebrize_with_inputs(schema, parsed, 0, inputs)?;
+
+    if let Some(because) = algebrized.cc.empty_because.clone() {
+        return Ok(QueryExplanation::KnownEmpty(because));
+    }
+
+    let unbound = algebrized.unbound_variables();
+    if !unbound.is_empty() {
+        bail!(ErrorKind::UnboundVariables(unbound.into_iter().map(|v| v.to_string()).collect()));
+    }
+    let column_bindings = algebrized.cc.column_bindings.clone();
+    let select = query_to_select(algebrized)?;
+    let SQLQuery { sql, args } = select.query.to_sql_query()?;
+
+    let steps = {
+        let mut statement = sqlite.prepare(format!("EXPLAIN QUERY PLAN {}", sql).as_str())?;
+        let refs: Vec<(&str, &ToSql)> =
+            args.iter()
+                .map(|&(ref k, ref v)| (k.as_str(), v.as_ref() as &ToSql))
+                .collect();
+        let mut rows = statement.query_named(refs.as_slice())?;
+        let mut steps = vec![];
+        while let Some(row) = rows.next() {
+            let row = row?;
+            // The third column has meant different things in different versions of SQLite.
+            steps.push(QueryPlanStep {
+                id: row.get_checked(0)?,
+                parent: row.get_checked(1)?,
+                detail: row.get_checked(3)?,
+            });
+        }
+        steps
+    };
+
+    Ok(QueryExplanation::ExecutionPlan {
+        column_bindings: column_bindings,
+        sql: sql,
+        args: args,
+        steps: steps,
+    })
+}
@@ -0,0 +1,401 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! A cache of query plans.
+//!
+//! Algebrizing and translating a query is much more work than parsing it, and applications tend
+//! to run the same few queries over and over with different inputs. We key each plan on the
+//! parsed query and the types of its inputs, and algebrize it knowing only those types: each input
+//! becomes a SQL bind parameter, so the generated SQL and projector can be reused for any values.
+//!
+//! Keyword inputs are the exception. A keyword might name an attribute or an entity, which we
+//! need to resolve while algebrizing, so keyword values are part of the key.
+//!
+//! Plans depend on the schema, so the cache is emptied whenever the schema generation changes.
+//!
+//! A `PreparedQuery` is a single plan that the caller holds on to, rather than one looked up by
+//! query text every time.
+
+use std::collections::{
+    BTreeMap,
+    BTreeSet,
+};
+
+use std::rc::Rc;
+use std::sync::Arc;
+
+use rusqlite;
+use rusqlite::types::{
+    ToSql,
+    ToSqlOutput,
+};
+
+use mentat_core::{
+    Schema,
+    TypedValue,
+    ValueType,
+    ValueTypeSet,
+};
+
+use mentat_db::TypedSQLValue;
+
+use mentat_query::{
+    Element,
+    FindQuery,
+    FindSpec,
+    Variable,
+};
+
+use mentat_query_algebrizer::{
+    EmptyBecause,
+    QueryInputs,
+    algebrize,
+    algebrize_with_parameters,
+};
+
+use mentat_query_parser::parse_find_string;
+
+use mentat_query_projector::{
+    Projector,
+    QueryOutput,
+    projected_columns,
+};
+
+use mentat_query_translator::{
+    query_to_select,
+    variable_param_name,
+};
+
+use mentat_sql::{
+    self,
+    SQLQuery,
+};
+
+use conn::Conn;
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+
+use query::{
+    QueryExecutionResult,
+    q_once,
+};
+
+use row::{
+    RowColumns,
+    RowView,
+};
+
+/// The number of plans a `QueryCache` holds if you don't say otherwise.
+pub const DEFAULT_QUERY_CACHE_CAPACITY: usize = 64;
+
+#[derive(PartialEq)]
+struct CacheKey {
+    query: FindQuery,
+    types: BTreeMap<Variable, ValueType>,
+    keywords: BTreeMap<Variable, TypedValue>,
+}
+
+enum Plan {
+    KnownEmpty(FindSpec, EmptyBecause),
+    Select {
+        sql: String,
+        args: Vec<(String, Rc<mentat_sql::Value>)>,
+        // Each input that's bound when the query is run, with the name of its bind parameter.
+        parameters: Vec<(Variable, String)>,
+        projector: Box<Projector>,
+    },
+}
+
+struct CachedQuery {
+    key: CacheKey,
+    columns: Vec<(Variable, ValueTypeSet)>,
+    plan: Plan,
+}
+
+/// A least-recently-used cache of query plans for a single store.
+pub struct QueryCache {
+    capacity: usize,
+    schema_generation: u64,
+    entries: Vec<CachedQuery>,          // Least recently used first.
+    hits: usize,
+    misses: usize,
+}
+
+impl Default for QueryCache {
+    fn default() -> QueryCache {
+        QueryCache::with_capacity(DEFAULT_QUERY_CACHE_CAPACITY)
+    }
+}
+
+impl QueryCache {
+    pub fn with_capacity(capacity: usize) -> QueryCache {
+        QueryCache {
+            capacity: capacity,
+            schema_generation: 0,
+            entries: Vec::with_capacity(capacity),
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// The number of plans held.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The number of queries that were answered with a cached plan.
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+
+    /// The number of queries that had to be planned.
+    pub fn misses(&self) -> usize {
+        self.misses
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Execute `query` like `q_once`, reusing the plan from an earlier run if there is one.
+    /// `schema_generation` identifies `schema`: if it differs from the generation of the cached
+    /// plans, they're discarded.
+    ///
+    /// Queries whose inputs can't all be parameterized -- an input used only in a predicate, say --
+    /// aren't cached; they're simply run with `q_once`.
+    pub fn q<T>(&mut self,
+                sqlite: &rusqlite::Connection,
+                schema: &Schema,
+                schema_generation: u64,
+                query: &str,
+                inputs: T) -> QueryExecutionResult
+        where T: Into<Option<QueryInputs>>
+    {
+        if schema_generation != self.schema_generation {
+            self.clear();
+            self.schema_generation = schema_generation;
+        }
+
+        let inputs = inputs.into().unwrap_or(QueryInputs::default());
+        let parsed = parse_find_string(query)?;
+        let key = CacheKey {
+            query: parsed,
+            types: inputs.types.clone(),
+            keywords: inputs.values
+                            .iter()
+                            .filter(|&(_, v)| v.value_type() == ValueType::Keyword)
+                            .map(|(var, v)| (var.clone(), v.clone()))
+                            .collect(),
+        };
+
+        match self.entries.iter().position(|entry| entry.key == key) {
+            Some(index) => {
+                self.hits += 1;
+                let entry = self.entries.remove(index);
+                self.entries.push(entry);
+            },
+            None => {
+                self.misses += 1;
+                let (columns, plan) = match plan(schema, &key) {
+                    Ok(planned) => planned,
+                    Err(_) => return q_once(sqlite, schema, query, inputs),
+                };
+                if self.entries.len() >= self.capacity {
+                    self.entries.remove(0);
+                }
+                self.entries.push(CachedQuery { key: key, columns: columns, plan: plan });
+            },
+        }
+
+        match self.entries.last() {
+            Some(entry) => {
+                execute(sqlite, schema, &entry.plan, &inputs).map(|output| output.with_columns(entry.columns.clone()))
+            },
+
+            // Only with a capacity of zero.
+            None => q_once(sqlite, schema, query, inputs),
+        }
+    }
+}
+
+/// A query that has been parsed, algebrized, and translated to SQL once, to be run any number of
+/// times with different inputs. Each `:in` variable is a bind parameter of the SQL.
+///
+/// The plan is only valid for the schema it was prepared against. Once the schema changes, running
+/// it fails with `ErrorKind::StalePreparedQuery`, and the query must be prepared again.
+pub struct PreparedQuery {
+    schema: Arc<Schema>,
+    schema_generation: u64,
+    types: BTreeMap<Variable, ValueType>,
+    columns: RowColumns,
+    plan: Plan,
+}
+
+impl PreparedQuery {
+    /// Prepare `query` against `schema`, whose generation is `schema_generation`. The type of each
+    /// `:in` variable is inferred from the query, so every one of them must be constrained to a
+    /// single type -- by the attribute it's used with, say.
+    pub fn new(schema: Arc<Schema>, schema_generation: u64, query: &str) -> Result<PreparedQuery> {
+        let parsed = parse_find_string(query)?;
+        let algebrized = algebrize(&*schema, parsed.clone())?;
+
+        let mut types = BTreeMap::new();
+        if !algebrized.is_known_empty() {
+            for var in algebrized.cc.input_variables.iter() {
+                match algebrized.cc.known_type(var) {
+                    Some(t) => types.insert(var.clone(), t),
+                    None => bail!(ErrorKind::UnknownInputType(var.to_string())),
+                };
+            }
+        }
+
+        let elements = match algebrized.find_spec {
+            FindSpec::FindScalar(ref elem) | FindSpec::FindColl(ref elem) => vec![elem],
+            FindSpec::FindTuple(ref elems) | FindSpec::FindRel(ref elems) => elems.iter().collect(),
+        };
+        let columns = elements.into_iter()
+                              .map(|elem| match elem {
+                                  &Element::Variable(ref var) => Some((var.clone(), algebrized.cc.known_type_set(var))),
+                                  _ => None,
+                              })
+                              .collect();
+
+        let key = CacheKey {
+            query: parsed,
+            types: types.clone(),
+            keywords: BTreeMap::default(),
+        };
+        let (_, plan) = plan(&*schema, &key)?;
+        Ok(PreparedQuery {
+            schema: schema,
+            schema_generation: schema_generation,
+            types: types,
+            columns: Rc::new(columns),
+            plan: plan,
+        })
+    }
+
+    /// The generation of the schema this query was prepared against.
+    pub fn schema_generation(&self) -> u64 {
+        self.schema_generation
+    }
+
+    /// The types each variable in the find spec might take, in the order they're projected.
+    /// A variable whose type the query doesn't pin down has more than one type in its set.
+    /// Pull expressions and aggregates aren't included.
+    pub fn columns(&self) -> Vec<(Variable, ValueTypeSet)> {
+        self.columns.iter().filter_map(|column| column.clone()).collect()
+    }
+
+    /// Run the query against the store behind `conn`, binding each `:in` variable from `inputs`.
+    /// Each value must have the type inferred when the query was prepared.
+    pub fn run<T>(&self,
+                  conn: &Conn,
+                  sqlite: &rusqlite::Connection,
+                  inputs: T) -> QueryExecutionResult
+        where T: Into<Option<QueryInputs>>
+    {
+        if conn.current_schema_generation() != self.schema_generation {
+            bail!(ErrorKind::StalePreparedQuery);
+        }
+
+        let inputs = inputs.into().unwrap_or(QueryInputs::default());
+        let inputs = QueryInputs::new(self.types.clone(), inputs.values)?;
+        execute(sqlite, &*self.schema, &self.plan, &inputs).map(|output| output.with_columns(self.columns()))
+    }
+
+    /// Like `run`, but returns each row of the results as a `RowView`, so that values can be
+    /// retrieved by variable name: `row.get_string("?name")`.
+    pub fn rows<T>(&self,
+                   conn: &Conn,
+                   sqlite: &rusqlite::Connection,
+                   inputs: T) -> Result<Vec<RowView>>
+        where T: Into<Option<QueryInputs>>
+    {
+        let output = self.run(conn, sqlite, inputs)?;
+        Ok(RowView::from_results(self.columns.clone(), output.results))
+    }
+}
+
+/// The columns the query projects, and how to run it.
+fn plan(schema: &Schema, key: &CacheKey) -> Result<(Vec<(Variable, ValueTypeSet)>, Plan)> {
+    let inputs = QueryInputs::new(key.types.clone(), key.keywords.clone())?;
+    let algebrized = algebrize_with_parameters(schema, key.query.clone(), inputs)?;
+    let columns = projected_columns(&algebrized);
+
+    if let Some(because) = algebrized.cc.empty_because.clone() {
+        return Ok((columns, Plan::KnownEmpty(algebrized.find_spec.clone(), because)));
+    }
+
+    let parameters = algebrized.unbound_variables()
+                               .into_iter()
+                               .map(|var| {
+                                   let name = format!("${}", variable_param_name(&var));
+                                   (var, name)
+                               })
+                               .collect();
+    let select = query_to_select(algebrized)?;
+    let SQLQuery { sql, args } = select.query.to_sql_query()?;
+    Ok((columns, Plan::Select {
+        sql: sql,
+        args: args,
+        parameters: parameters,
+        projector: select.projector,
+    }))
+}
+
+fn execute(sqlite: &rusqlite::Connection,
+           schema: &Schema,
+           plan: &Plan,
+           inputs: &QueryInputs) -> QueryExecutionResult {
+    match plan {
+        &Plan::KnownEmpty(ref find_spec, ref because) => {
+            Ok(QueryOutput::proven_empty(find_spec, because.clone()))
+        },
+        &Plan::Select { ref sql, ref args, ref parameters, ref projector } => {
+            let unbound: BTreeSet<String> =
+                parameters.iter()
+                          .filter(|&&(ref var, _)| !inputs.values.contains_key(var))
+                          .map(|&(ref var, _)| var.to_string())
+                          .collect();
+            if !unbound.is_empty() {
+                bail!(ErrorKind::UnboundVariables(unbound));
+            }
+
+            let values: Vec<(&str, ToSqlOutput)> =
+                parameters.iter()
+                          .map(|&(ref var, ref name)| (name.as_str(), inputs.values[var].to_sql_value_pair().0))
+                          .collect();
+
+            let mut statement = sqlite.prepare(sql.as_str())?;
+            let refs: Vec<(&str, &ToSql)> =
+                args.iter()
+                    .map(|&(ref k, ref v)| (k.as_str(), v.as_ref() as &ToSql))
+                    .chain(values.iter().map(|&(k, ref v)| (k, v as &ToSql)))
+                    .collect();
+            let rows = if refs.is_empty() {
+                statement.query(&[])?
+            } else {
+                statement.query_named(refs.as_slice())?
+            };
+
+            projector.project(schema, sqlite, rows)
+                     .map(QueryOutput::from)
+                     .map_err(|e| e.into())
+        },
+    }
+}
@@ -0,0 +1,154 @@
+// Copyright 2016 Mozilla
+//
+// Licensed under the Apache License, Version 2.0 (the "License"); you may not use
+// this file except in compliance with the License. You may obtain a copy of the
+// License at http://www.apache.org/licenses/LICENSE-2.0
+// Unless required by applicable law or agreed to in writing, software distributed
+// under the License is distributed on an "AS IS" BASIS, WITHOUT WARRANTIES OR
+// CONDITIONS OF ANY KIND, either express or implied. See the License for the
+// specific language governing permissions and limitations under the License.
+
+//! Access to the values in a row of results by variable name, as Rust values of the right type.
+//! See `PreparedQuery::rows`.
+
+use std::rc::Rc;
+
+use mentat_core::{
+    Binding,
+    DateTime,
+    Entid,
+    TypedValue,
+    Utc,
+    Uuid,
+    ValueType,
+    ValueTypeSet,
+};
+
+use mentat_query::{
+    NamespacedKeyword,
+    Variable,
+};
+
+use mentat_query_projector::QueryResults;
+
+use errors::{
+    ErrorKind,
+    Result,
+};
+
+/// The variable projected by each element of a find spec, with the types it might take. Pull
+/// expressions and aggregates aren't named by a variable, so they're `None`.
+pub type RowColumns = Rc<Vec<Option<(Variable, ValueTypeSet)>>>;
+
+/// One row of a query's results.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RowView {
+    columns: RowColumns,
+    values: Vec<Binding>,
+}
+
+impl RowView {
+    pub fn new(columns: RowColumns, values: Vec<Binding>) -> RowView {
+        RowView {
+            columns: columns,
+            values: values,
+        }
+    }
+
+    /// Every row of `results`: one for a scalar or tuple, if there's a result at all, and one per
+    /// value of a collection.
+    pub fn from_results(columns: RowColumns, results: QueryResults) -> Vec<RowView> {
+        let rows = match results {
+            QueryResults::Scalar(value) => value.into_iter().map(|v| vec![v]).collect(),
+            QueryResults::Coll(values) => values.into_iter().map(|v| vec![v]).collect(),
+            QueryResults::Tuple(row) => row.into_iter().collect(),
+            QueryResults::Rel(rows) => rows,
+        };
+        rows.into_iter().map(|values| RowView::new(columns.clone(), values)).collect()
+    }
+
+    /// The value of the variable `name`, like `"?name"`.
+    pub fn get(&self, name: &str) -> Result<&Binding> {
+        self.column(name).map(|(index, _)| &self.values[index])
+    }
+
+    pub fn get_ref(&self, name: &str) -> Result<Entid> {
+        match self.get_typed(name, ValueType::Ref)? {
+            &TypedValue::Ref(e) => Ok(e),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn get_boolean(&self, name: &str) -> Result<bool> {
+        match self.get_typed(name, ValueType::Boolean)? {
+            &TypedValue::Boolean(b) => Ok(b),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn get_long(&self, name: &str) -> Result<i64> {
+        match self.get_typed(name, ValueType::Long)? {
+            &TypedValue::Long(x) => Ok(x),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn get_double(&self, name: &str) -> Result<f64> {
+        match self.get_typed(name, ValueType::Double)? {
+            &TypedValue::Double(x) => Ok(x.into_inner()),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn get_instant(&self, name: &str) -> Result<DateTime<Utc>> {
+        match self.get_typed(name, ValueType::Instant)? {
+            &TypedValue::Instant(ref t) => Ok(t.clone()),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn get_string(&self, name: &str) -> Result<Rc<String>> {
+        match self.get_typed(name, ValueType::String)? {
+            &TypedValue::String(ref s) => Ok(s.clone()),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn get_keyword(&self, name: &str) -> Result<Rc<NamespacedKeyword>> {
+        match self.get_typed(name, ValueType::Keyword)? {
+            &TypedValue::Keyword(ref k) => Ok(k.clone()),
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn get_uuid(&self, name: &str) -> Result<Uuid> {
+        match self.get_typed(name, ValueType::Uuid)? {
+            &TypedValue::Uuid(u) => Ok(u),
+            _ => unreachable!(),
+        }
+    }
+
+    fn column(&self, name: &str) -> Result<(usize, ValueTypeSet)> {
+        self.columns
+            .iter()
+            .position(|column| column.as_ref().map_or(false, |&(ref var, _)| var.as_str() == name))
+            .and_then(|index| self.columns[index].as_ref().map(|&(_, types)| (index, types)))
+            .ok_or_else(|| ErrorKind::UnknownRowVariable(name.to_string()).into())
+    }
+
+    /// The value of `name`, which must be of type `value_type`. We check the types the query
+    /// inferred for the variable, and then, because it might take more than one, the value itself.
+    fn get_typed(&self, name: &str, value_type: ValueType) -> Result<&TypedValue> {
+        let (index, types) = self.column(name)?;
+        if !types.contains(value_type) {
+            bail!(ErrorKind::RowValueTypeMismatch(name.to_string(), value_type, types));
+        }
+        match &self.values[index] {
+            &Binding::Scalar(ref value) if value.value_type() == value_type => Ok(value),
+            &Binding::Scalar(ref value) => {
+                bail!(ErrorKind::RowValueTypeMismatch(name.to_string(), value_type, ValueTypeSet::of_one(value.value_type())));
+            },
+            _ => bail!(ErrorKind::RowValueTypeMismatch(name.to_string(), value_type, types)),
+        }
+    }
+}
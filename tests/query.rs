@@ -9,6 +9,7 @@
 // specific language governing permissions and limitations under the License.
 
 extern crate chrono;
+extern crate edn;
 extern crate time;
 
 extern crate mentat;
@@ -16,25 +17,53 @@ extern crate mentat_core;
 extern crate mentat_db;
 extern crate mentat_query_algebrizer;       // For errors.
 
+use std::collections::BTreeSet;
 use std::str::FromStr;
+use std::time::{
+    Duration,
+    Instant,
+};
 
 use chrono::FixedOffset;
 
+use mentat_core::attribute::Unique;
+
 use mentat_core::{
+    DateTime,
     TypedValue,
     ValueType,
+    ValueTypeSet,
     Utc,
     Uuid,
 };
 
 use mentat::{
+    AsOf,
+    AttributeInfo,
+    Binding,
+    CacheDirection,
     NamespacedKeyword,
     PlainSymbol,
+    QueryCache,
+    QueryExplanation,
     QueryInputs,
+    QueryOutput,
     QueryResults,
+    RuleSet,
+    StructuredMap,
     Variable,
+    algebrize_inspect,
     new_connection,
+    parse_rules_string,
     q_once,
+    q_once_with_rules,
+    query_iter,
+    seed_random,
+};
+
+use mentat::query::{
+    EmptyBecause,
+    parse_find_string,
 };
 
 use mentat::conn::Conn;
@@ -60,7 +89,7 @@ fn test_rel() {
     assert_eq!(39, results.len());
 
     // Every row is a pair of a Ref and a Keyword.
-    if let QueryResults::Rel(ref rel) = results {
+    if let QueryResults::Rel(ref rel) = results.results {
         for r in rel {
             assert_eq!(r.len(), 2);
             assert!(r[0].matches_type(ValueType::Ref));
@@ -74,6 +103,22 @@ fn test_rel() {
     println!("Rel took {}µs", start.to(end).num_microseconds().unwrap());
 }
 
+#[test]
+fn test_empty_rel_columns() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let db = mentat_db::db::ensure_current_version(&mut c).expect("Couldn't open DB.");
+
+    // Nothing has this doc, but we still know what the relation would have held.
+    let results = q_once(&c, &db.schema,
+                         r#"[:find ?x ?ident :where [?x :db/ident ?ident] [?x :db/doc "No such doc."]]"#, None)
+        .expect("Query failed");
+    assert_eq!(results.results, QueryResults::Rel(vec![]));
+    assert_eq!(results.columns, vec![
+        (Variable::from_valid_name("?x"), ValueTypeSet::of_one(ValueType::Ref)),
+        (Variable::from_valid_name("?ident"), ValueTypeSet::of_one(ValueType::Keyword)),
+    ]);
+}
+
 #[test]
 fn test_failing_scalar() {
     let mut c = new_connection("").expect("Couldn't open conn.");
@@ -88,7 +133,7 @@ fn test_failing_scalar() {
 
     assert_eq!(0, results.len());
 
-    if let QueryResults::Scalar(None) = results {
+    if let QueryResults::Scalar(None) = results.results {
     } else {
         panic!("Expected failed scalar.");
     }
@@ -110,7 +155,7 @@ fn test_scalar() {
 
     assert_eq!(1, results.len());
 
-    if let QueryResults::Scalar(Some(TypedValue::Keyword(ref rc))) = results {
+    if let QueryResults::Scalar(Some(Binding::Scalar(TypedValue::Keyword(ref rc)))) = results.results {
         // Should be '24'.
         assert_eq!(&NamespacedKeyword::new("db.type", "keyword"), rc.as_ref());
         assert_eq!(24,
@@ -140,7 +185,7 @@ fn test_tuple() {
 
     assert_eq!(1, results.len());
 
-    if let QueryResults::Tuple(Some(ref tuple)) = results {
+    if let QueryResults::Tuple(Some(ref tuple)) = results.results {
         let cardinality_one = NamespacedKeyword::new("db.cardinality", "one");
         assert_eq!(tuple.len(), 2);
         assert_eq!(tuple[0], TypedValue::Boolean(true));
@@ -167,7 +212,7 @@ fn test_coll() {
 
     assert_eq!(39, results.len());
 
-    if let QueryResults::Coll(ref coll) = results {
+    if let QueryResults::Coll(ref coll) = results.results {
         assert!(coll.iter().all(|item| item.matches_type(ValueType::Ref)));
     } else {
         panic!("Expected coll.");
@@ -189,7 +234,7 @@ fn test_inputs() {
                          "[:find ?i . :in ?e :where [?e :db/ident ?i]]", inputs)
                         .expect("query to succeed");
 
-    if let QueryResults::Scalar(Some(TypedValue::Keyword(value))) = results {
+    if let QueryResults::Scalar(Some(Binding::Scalar(TypedValue::Keyword(value)))) = results.results {
         assert_eq!(value.as_ref(), &NamespacedKeyword::new("db.install", "valueType"));
     } else {
         panic!("Expected scalar.");
@@ -237,8 +282,8 @@ fn test_instants_and_uuids() {
                             :where [?x :foo/uuid ?u ?tx]
                                    [?tx :db/txInstant ?when]]"#, None);
     match r {
-        Result::Ok(QueryResults::Tuple(Some(vals))) => {
-            let mut vals = vals.into_iter();
+        Result::Ok(QueryOutput { results: QueryResults::Tuple(Some(vals)), .. }) => {
+            let mut vals = vals.into_iter().map(|b| b.into_scalar().expect("scalar results"));
             match (vals.next(), vals.next(), vals.next(), vals.next()) {
                 (Some(TypedValue::Ref(e)),
                  Some(TypedValue::Uuid(u)),
@@ -255,6 +300,45 @@ fn test_instants_and_uuids() {
     }
 }
 
+#[test]
+fn test_bytes() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    conn.transact(&mut c, r#"[
+        [:db/add "s" :db/ident :foo/hash]
+        [:db/add "s" :db/valueType :db.type/bytes]
+        [:db/add "s" :db/cardinality :db.cardinality/one]
+    ]"#).unwrap();
+    conn.transact(&mut c, r#"[
+        [:db/add "a" :foo/hash #bytes "deadbeef"]
+        [:db/add "b" :foo/hash #bytes "00ff"]
+    ]"#).unwrap();
+
+    // Bytes round-trip through the store.
+    let r = conn.q_once(&mut c,
+                        r#"[:find [?h ...]
+                            :where [_ :foo/hash ?h]
+                            :order ?h]"#, None);
+    match r {
+        Result::Ok(QueryOutput { results: QueryResults::Coll(vals), .. }) => {
+            assert_eq!(vals, vec![TypedValue::Bytes(vec![0x00, 0xff]),
+                                  TypedValue::Bytes(vec![0xde, 0xad, 0xbe, 0xef])]);
+        },
+        _ => panic!("Expected query to work."),
+    }
+
+    // And can be used as constants.
+    let r = conn.q_once(&mut c,
+                        r#"[:find ?e .
+                            :where [?e :foo/hash #bytes "00ff"]]"#, None);
+    match r {
+        Result::Ok(QueryOutput { results: QueryResults::Scalar(Some(Binding::Scalar(TypedValue::Ref(e)))), .. }) => {
+            assert!(e > 39);
+        },
+        _ => panic!("Expected query to work."),
+    }
+}
+
 #[test]
 fn test_tx() {
     let mut c = new_connection("").expect("Couldn't open conn.");
@@ -277,7 +361,7 @@ fn test_tx() {
                         r#"[:find ?tx 
                             :where [?x :foo/uuid #uuid "cf62d552-6569-4d1b-b667-04703041dfc4" ?tx]]"#, None);
     match r {
-        Result::Ok(QueryResults::Rel(ref v)) => {
+        Result::Ok(QueryOutput { results: QueryResults::Rel(ref v), .. }) => {
             assert_eq!(*v, vec![
                 vec![TypedValue::Ref(t.tx_id),]
             ]);
@@ -312,7 +396,7 @@ fn test_tx_as_input() {
                             :in ?tx
                             :where [?x :foo/uuid ?uuid ?tx]]"#, inputs);
     match r {
-        Result::Ok(QueryResults::Rel(ref v)) => {
+        Result::Ok(QueryOutput { results: QueryResults::Rel(ref v), .. }) => {
             assert_eq!(*v, vec![
                 vec![TypedValue::Uuid(Uuid::from_str("cf62d552-6569-4d1b-b667-04703041dfc4").expect("Valid UUID")),]
             ]);
@@ -347,8 +431,8 @@ fn test_fulltext() {
                         r#"[:find [?x ?val ?score]
                             :where [(fulltext $ :foo/fts "darkness") [[?x ?val _ ?score]]]]"#, None);
     match r {
-        Result::Ok(QueryResults::Tuple(Some(vals))) => {
-            let mut vals = vals.into_iter();
+        Result::Ok(QueryOutput { results: QueryResults::Tuple(Some(vals)), .. }) => {
+            let mut vals = vals.into_iter().map(|b| b.into_scalar().expect("scalar results"));
             match (vals.next(), vals.next(), vals.next(), vals.next()) {
                 (Some(TypedValue::Ref(x)),
                  Some(TypedValue::String(text)),
@@ -356,7 +440,7 @@ fn test_fulltext() {
                  None) => {
                      assert_eq!(x, v);
                      assert_eq!(text.as_str(), "hello darkness my old friend");
-                     assert_eq!(score, 0.0f64.into());
+                     assert_eq!(score, 1.0f64.into());
                  },
                  _ => panic!("Unexpected results."),
             }
@@ -410,7 +494,7 @@ fn test_fulltext() {
     let inputs = QueryInputs::with_value_sequence(vec![(Variable::from_valid_name("?a"), TypedValue::Ref(a))]);
     let r = conn.q_once(&mut c, query, inputs);
     match r {
-        Result::Ok(QueryResults::Rel(rels)) => {
+        Result::Ok(QueryOutput { results: QueryResults::Rel(rels), .. }) => {
             assert_eq!(rels, vec![
                 vec![TypedValue::Ref(v),
                      TypedValue::String("I've come to talk with you again".to_string().into()),
@@ -421,6 +505,63 @@ fn test_fulltext() {
     }
 }
 
+#[test]
+fn test_fulltext_scores() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        [:db/add "a" :db/ident :foo/term]
+        [:db/add "a" :db/valueType :db.type/string]
+        [:db/add "a" :db/fulltext false]
+
+        [:db/add "s" :db/ident :foo/fts]
+        [:db/add "s" :db/valueType :db.type/string]
+        [:db/add "s" :db/fulltext true]
+    ]"#).unwrap();
+
+    let report = conn.transact(&mut c, r#"[
+        [:db/add "once" :foo/fts "a dark night"]
+        [:db/add "again" :foo/fts "a dark night"]
+        [:db/add "thrice" :foo/fts "dark, dark, dark"]
+        [:db/add "twice" :foo/fts "dark clouds on a dark day"]
+        [:db/add "never" :foo/fts "a bright morning"]
+    ]"#).unwrap();
+    let entid = |tempid: &str| report.tempids.get(tempid).cloned().expect("tempid was mapped");
+
+    let r = conn.q_once(&mut c,
+                        r#"[:find ?x ?score
+                            :where [(fulltext $ :foo/fts "dark") [[?x _ _ ?score]]]
+                            :order (desc ?score)]"#, None);
+    let rels = match r {
+        Result::Ok(QueryOutput { results: QueryResults::Rel(rels), .. }) => rels,
+        _ => panic!("Expected query to work."),
+    };
+
+    // More matches score higher, and identical matches score identically.
+    let scores: Vec<Binding> = rels.iter().map(|row| row[1].clone()).collect();
+    assert_eq!(scores, vec![TypedValue::Double(3.0.into()),
+                            TypedValue::Double(2.0.into()),
+                            TypedValue::Double(1.0.into()),
+                            TypedValue::Double(1.0.into())]);
+    assert_eq!(rels[0][0], TypedValue::Ref(entid("thrice")));
+    assert_eq!(rels[1][0], TypedValue::Ref(entid("twice")));
+    let ties: BTreeSet<Binding> = rels[2..].iter().map(|row| row[0].clone()).collect();
+    assert_eq!(ties, vec![Binding::Scalar(TypedValue::Ref(entid("once"))),
+                          Binding::Scalar(TypedValue::Ref(entid("again")))].into_iter().collect());
+
+    // Scores can't come from an attribute that isn't fulltext-indexed.
+    let r = conn.q_once(&mut c,
+                        r#"[:find ?x ?score
+                            :where [(fulltext $ :foo/term "dark") [[?x _ _ ?score]]]]"#, None);
+    match r {
+        Err(Error(ErrorKind::QueryError(mentat_query_algebrizer::ErrorKind::NonFulltextAttribute(name)), _)) => {
+            assert_eq!(name, ":foo/term");
+        },
+        _ => panic!("Expected query to fail."),
+    }
+}
+
 #[test]
 fn test_instant_range_query() {
     let mut c = new_connection("").expect("Couldn't open conn.");
@@ -446,7 +587,7 @@ fn test_instant_range_query() {
                             [?x :foo/date ?date]
                             [(< ?date #inst "2017-01-01T11:00:02.000Z")]]"#, None);
     match r {
-        Result::Ok(QueryResults::Coll(vals)) => {
+        Result::Ok(QueryOutput { results: QueryResults::Coll(vals), .. }) => {
             assert_eq!(vals,
                        vec![TypedValue::Ref(*ids.get("b").unwrap()),
                             TypedValue::Ref(*ids.get("c").unwrap())]);
@@ -454,3 +595,2715 @@ fn test_instant_range_query() {
         _ => panic!("Expected query to work."),
     }
 }
+
+#[test]
+fn test_count_distinct() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        [:db/add "s" :db/ident :foo/tag]
+        [:db/add "s" :db/valueType :db.type/string]
+        [:db/add "s" :db/cardinality :db.cardinality/many]
+        [:db/add "t" :db/ident :foo/note]
+        [:db/add "t" :db/valueType :db.type/string]
+        [:db/add "t" :db/cardinality :db.cardinality/many]
+    ]"#).unwrap();
+
+    let ids = conn.transact(&mut c, r#"[
+        [:db/add "a" :foo/tag "x"]
+        [:db/add "a" :foo/tag "y"]
+        [:db/add "b" :foo/tag "x"]
+    ]"#).unwrap().tempids;
+    let a = *ids.get("a").unwrap();
+    let b = *ids.get("b").unwrap();
+
+    // Ungrouped.
+    let r = conn.q_once(&mut c,
+                        r#"[:find (count-distinct ?t) .
+                            :where [_ :foo/tag ?t]]"#, None);
+    match r {
+        Result::Ok(QueryOutput { results: QueryResults::Scalar(Some(v)), .. }) => assert_eq!(v, TypedValue::Long(2)),
+        _ => panic!("Expected query to work."),
+    }
+
+    // Grouped.
+    let r = conn.q_once(&mut c,
+                        r#"[:find ?e (count-distinct ?t)
+                            :where [?e :foo/tag ?t]
+                            :order ?e]"#, None);
+    match r {
+        Result::Ok(QueryOutput { results: QueryResults::Rel(rows), .. }) => {
+            let mut expected = vec![vec![TypedValue::Ref(a), TypedValue::Long(2)],
+                                    vec![TypedValue::Ref(b), TypedValue::Long(1)]];
+            expected.sort_by_key(|row| row[0].clone());
+            assert_eq!(rows, expected);
+        },
+        _ => panic!("Expected query to work."),
+    }
+
+    // Nothing to count.
+    let r = conn.q_once(&mut c,
+                        r#"[:find (count-distinct ?n) .
+                            :where [_ :foo/note ?n]]"#, None);
+    match r {
+        Result::Ok(QueryOutput { results: QueryResults::Scalar(Some(v)), .. }) => assert_eq!(v, TypedValue::Long(0)),
+        _ => panic!("Expected query to work."),
+    }
+
+    // Grouping over nothing yields no groups at all.
+    let r = conn.q_once(&mut c,
+                        r#"[:find ?e (count-distinct ?n)
+                            :where [?e :foo/note ?n]]"#, None);
+    match r {
+        Result::Ok(QueryOutput { results: QueryResults::Rel(rows), .. }) => assert!(rows.is_empty()),
+        _ => panic!("Expected query to work."),
+    }
+}
+
+#[test]
+fn test_median_and_percentile() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        [:db/add "s" :db/ident :foo/score]
+        [:db/add "s" :db/valueType :db.type/double]
+        [:db/add "s" :db/cardinality :db.cardinality/one]
+        [:db/add "g" :db/ident :foo/group]
+        [:db/add "g" :db/valueType :db.type/string]
+        [:db/add "g" :db/cardinality :db.cardinality/one]
+    ]"#).unwrap();
+
+    conn.transact(&mut c, r#"[
+        {:foo/group "odd" :foo/score 1.0}
+        {:foo/group "odd" :foo/score 3.0}
+        {:foo/group "odd" :foo/score 3.0}
+        {:foo/group "even" :foo/score 2.0}
+        {:foo/group "even" :foo/score 5.0}
+    ]"#).unwrap();
+
+    let cases = vec![
+        // `:with` keeps the duplicate 3.0, so the median of the odd group is 3.0, not 2.0.
+        (r#"[:find (median ?s) . :with ?e :where [?e :foo/group "odd"] [?e :foo/score ?s]]"#, 3f64),
+        (r#"[:find (median ?s) . :where [?e :foo/group "even"] [?e :foo/score ?s]]"#, 3.5f64),
+        (r#"[:find (percentile ?s 0) . :with ?e :where [?e :foo/score ?s]]"#, 1f64),
+        (r#"[:find (percentile ?s 100) . :with ?e :where [?e :foo/score ?s]]"#, 5f64),
+        (r#"[:find (percentile ?s 95) . :with ?e :where [?e :foo/score ?s]]"#, 5f64),
+    ];
+    for (query, expected) in cases {
+        match conn.q_once(&mut c, query, None) {
+            Result::Ok(QueryOutput { results: QueryResults::Scalar(Some(v)), .. }) => assert_eq!(v, TypedValue::from(expected)),
+            r => panic!("Expected query to work: {:?}", r),
+        }
+    }
+
+    // Grouped.
+    let r = conn.q_once(&mut c,
+                        r#"[:find ?g (median ?s)
+                            :with ?e
+                            :where [?e :foo/group ?g] [?e :foo/score ?s]
+                            :order ?g]"#, None);
+    match r {
+        Result::Ok(QueryOutput { results: QueryResults::Rel(rows), .. }) => {
+            assert_eq!(rows, vec![vec![TypedValue::typed_string("even"), TypedValue::from(3.5f64)],
+                                  vec![TypedValue::typed_string("odd"), TypedValue::from(3f64)]]);
+        },
+        _ => panic!("Expected query to work."),
+    }
+
+    // Strings aren't numbers.
+    let r = conn.q_once(&mut c, r#"[:find (median ?g) . :where [_ :foo/group ?g]]"#, None);
+    assert!(r.is_err());
+}
+
+#[test]
+fn test_sum_with() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        [:db/add "a" :db/ident :line/amount]
+        [:db/add "a" :db/valueType :db.type/long]
+        [:db/add "a" :db/cardinality :db.cardinality/one]
+        [:db/add "o" :db/ident :line/order]
+        [:db/add "o" :db/valueType :db.type/string]
+        [:db/add "o" :db/cardinality :db.cardinality/one]
+        [:db/add "p" :db/ident :line/price]
+        [:db/add "p" :db/valueType :db.type/double]
+        [:db/add "p" :db/cardinality :db.cardinality/one]
+    ]"#).unwrap();
+
+    conn.transact(&mut c, r#"[
+        {:line/order "x" :line/amount 10 :line/price 1.5}
+        {:line/order "x" :line/amount 10 :line/price 1.5}
+        {:line/order "x" :line/amount 5 :line/price 2.0}
+        {:line/order "y" :line/amount 7 :line/price 0.5}
+    ]"#).unwrap();
+
+    let sum = |query: &str| conn.q_once(&c, query, None).expect("results").into_scalar_value().expect("scalar");
+
+    // Without `:with`, the two lines of 10 are one row, and so count once…
+    assert_eq!(sum(r#"[:find (sum ?a) . :where [?l :line/order "x"] [?l :line/amount ?a]]"#),
+               Some(TypedValue::Long(15)));
+
+    // … and with it, each line counts.
+    assert_eq!(sum(r#"[:find (sum ?a) . :with ?l :where [?l :line/order "x"] [?l :line/amount ?a]]"#),
+               Some(TypedValue::Long(25)));
+    assert_eq!(sum(r#"[:find (sum ?p) . :with ?l :where [?l :line/price ?p]]"#),
+               Some(TypedValue::from(5.5f64)));
+
+    // Nothing to add up.
+    assert_eq!(sum(r#"[:find (sum ?a) . :where [?l :line/order "z"] [?l :line/amount ?a]]"#), None);
+
+    // Grouped.
+    let r = conn.q_once(&c,
+                        r#"[:find ?o (sum ?a)
+                            :with ?l
+                            :where [?l :line/order ?o] [?l :line/amount ?a]
+                            :order ?o]"#, None).expect("results").results;
+    assert_eq!(r, QueryResults::Rel(vec![vec![TypedValue::typed_string("x").into(), TypedValue::Long(25).into()],
+                                         vec![TypedValue::typed_string("y").into(), TypedValue::Long(7).into()]]));
+
+    assert!(conn.q_once(&c, r#"[:find (sum ?o) . :where [_ :line/order ?o]]"#, None).is_err());
+}
+
+#[test]
+fn test_max_and_min() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        [:db/add "w" :db/ident :foo/when]
+        [:db/add "w" :db/valueType :db.type/instant]
+        [:db/add "w" :db/cardinality :db.cardinality/one]
+        [:db/add "n" :db/ident :foo/name]
+        [:db/add "n" :db/valueType :db.type/string]
+        [:db/add "n" :db/cardinality :db.cardinality/one]
+        [:db/add "p" :db/ident :foo/parent]
+        [:db/add "p" :db/valueType :db.type/ref]
+        [:db/add "p" :db/cardinality :db.cardinality/one]
+        [:db/add "s" :db/ident :foo/score]
+        [:db/add "s" :db/valueType :db.type/long]
+        [:db/add "s" :db/cardinality :db.cardinality/one]
+    ]"#).unwrap();
+
+    let ids = conn.transact(&mut c, r#"[
+        [:db/add "a" :foo/when #inst "2016-01-01T11:00:00.000Z"]
+        [:db/add "a" :foo/name "banana"]
+        [:db/add "b" :foo/when #inst "2017-06-01T11:00:00.000Z"]
+        [:db/add "b" :foo/name "Cherry"]
+        [:db/add "b" :foo/parent "a"]
+        [:db/add "c" :foo/when #inst "2016-06-01T11:00:00.000Z"]
+        [:db/add "c" :foo/name "apple"]
+        [:db/add "c" :foo/parent "b"]
+    ]"#).unwrap().tempids;
+    let b = *ids.get("b").unwrap();
+
+    let scalar = |query: &str| conn.q_once(&c, query, None).expect("results").into_scalar_value().expect("scalar");
+    let instant = |s: &str| TypedValue::Instant(s.parse::<DateTime<Utc>>().expect("valid instant"));
+
+    // Each extreme is of the variable's own type.
+    assert_eq!(scalar(r#"[:find (max ?t) . :where [_ :foo/when ?t]]"#),
+               Some(instant("2017-06-01T11:00:00.000Z")));
+    assert_eq!(scalar(r#"[:find (min ?t) . :where [_ :foo/when ?t]]"#),
+               Some(instant("2016-01-01T11:00:00.000Z")));
+    assert_eq!(scalar(r#"[:find (max ?p) . :where [_ :foo/parent ?p]]"#),
+               Some(TypedValue::Ref(b)));
+
+    // Strings compare lexicographically, so capitals come first.
+    assert_eq!(scalar(r#"[:find (min ?n) . :where [_ :foo/name ?n]]"#),
+               Some(TypedValue::typed_string("Cherry")));
+    assert_eq!(scalar(r#"[:find (max ?n) . :where [_ :foo/name ?n]]"#),
+               Some(TypedValue::typed_string("banana")));
+
+    // There's no extreme of nothing.
+    assert_eq!(scalar(r#"[:find (max ?s) . :where [_ :foo/score ?s]]"#), None);
+    let r = conn.q_once(&c, r#"[:find (min ?s) (max ?s) :where [_ :foo/score ?s]]"#, None).expect("results").results;
+    assert_eq!(r, QueryResults::Rel(vec![]));
+
+    // Grouped.
+    let r = conn.q_once(&c,
+                        r#"[:find ?p (max ?n)
+                            :where [?e :foo/parent ?p] [?e :foo/name ?n]
+                            :order ?p]"#, None);
+    match r {
+        Result::Ok(QueryOutput { results: QueryResults::Rel(rows), .. }) => {
+            let mut expected = vec![vec![TypedValue::Ref(*ids.get("a").unwrap()), TypedValue::typed_string("Cherry")],
+                                    vec![TypedValue::Ref(b), TypedValue::typed_string("apple")]];
+            expected.sort_by_key(|row| row[0].clone());
+            assert_eq!(rows, expected);
+        },
+        _ => panic!("Expected query to work."),
+    }
+}
+
+fn sampled(results: QueryResults) -> Vec<TypedValue> {
+    match results {
+        QueryResults::Scalar(Some(Binding::Vec(values))) => {
+            values.into_iter().map(|v| match v {
+                Binding::Scalar(v) => v,
+                v => panic!("Expected a scalar: {:?}", v),
+            }).collect()
+        },
+        r => panic!("Expected a sample: {:?}", r),
+    }
+}
+
+#[test]
+fn test_sample_and_rand() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    seed_random(&c, 42).expect("seeded");
+
+    conn.transact(&mut c, r#"[
+        [:db/add "s" :db/ident :foo/score]
+        [:db/add "s" :db/valueType :db.type/long]
+        [:db/add "s" :db/cardinality :db.cardinality/one]
+    ]"#).unwrap();
+
+    let sample = r#"[:find (sample 3 ?e) . :where [?e :foo/score _]]"#;
+
+    // Sampling nothing yields nothing.
+    match conn.q_once(&mut c, sample, None) {
+        Result::Ok(QueryOutput { results: QueryResults::Scalar(None), .. }) => {},
+        r => panic!("Expected no sample: {:?}", r),
+    }
+    match conn.q_once(&mut c, r#"[:find (sample 3 ?e) :where [?e :foo/score _]]"#, None) {
+        Result::Ok(QueryOutput { results: QueryResults::Rel(rows), .. }) => assert!(rows.is_empty()),
+        r => panic!("Expected no sample: {:?}", r),
+    }
+
+    let report = conn.transact(&mut c, r#"[
+        {:db/id "a" :foo/score 1}
+        {:db/id "b" :foo/score 2}
+        {:db/id "c" :foo/score 2}
+        {:db/id "d" :foo/score 3}
+        {:db/id "e" :foo/score 5}
+    ]"#).unwrap();
+    let entities: BTreeSet<TypedValue> = report.tempids.values().map(|&e| TypedValue::Ref(e)).collect();
+
+    let first = sampled(conn.q_once(&mut c, sample, None).expect("sampled").results);
+    assert_eq!(first.len(), 3);
+    let distinct: BTreeSet<TypedValue> = first.iter().cloned().collect();
+    assert_eq!(distinct.len(), 3);
+    assert!(distinct.is_subset(&entities));
+
+    // The same seed gives the same sample.
+    seed_random(&c, 42).expect("seeded");
+    let second = sampled(conn.q_once(&mut c, sample, None).expect("sampled").results);
+    seed_random(&c, 42).expect("seeded");
+    assert_eq!(second, sampled(conn.q_once(&mut c, sample, None).expect("sampled").results));
+
+    // A sample larger than the data is all of the data.
+    match conn.q_once(&mut c, r#"[:find (sample 10 ?e) . :where [?e :foo/score _]]"#, None) {
+        Result::Ok(QueryOutput { results: QueryResults::Scalar(Some(Binding::Vec(values))), .. }) => {
+            assert_eq!(values.len(), 5);
+        },
+        r => panic!("Expected a sample: {:?}", r),
+    }
+
+    // Samples within groups.
+    match conn.q_once(&mut c, r#"[:find ?s (sample 1 ?e) :where [?e :foo/score ?s] :order ?s]"#, None) {
+        Result::Ok(QueryOutput { results: QueryResults::Rel(rows), .. }) => {
+            assert_eq!(rows.len(), 4);
+            for row in rows {
+                match row[1] {
+                    Binding::Vec(ref values) => assert_eq!(values.len(), 1),
+                    ref v => panic!("Expected a sample: {:?}", v),
+                }
+            }
+        },
+        r => panic!("Expected samples: {:?}", r),
+    }
+
+    // A random double for each result.
+    match conn.q_once(&mut c, r#"[:find ?e (rand) :where [?e :foo/score _]]"#, None) {
+        Result::Ok(QueryOutput { results: QueryResults::Rel(rows), .. }) => {
+            assert_eq!(rows.len(), 5);
+            for row in rows {
+                match row[1] {
+                    Binding::Scalar(TypedValue::Double(x)) => assert!(x.into_inner() >= 0f64 && x.into_inner() < 1f64),
+                    ref v => panic!("Expected a double: {:?}", v),
+                }
+            }
+        },
+        r => panic!("Expected random numbers: {:?}", r),
+    }
+}
+
+#[test]
+fn test_not_join_scopes_inner_variables() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        [:db/add "n" :db/ident :foo/name]
+        [:db/add "n" :db/valueType :db.type/string]
+        [:db/add "n" :db/cardinality :db.cardinality/one]
+        [:db/add "a" :db/ident :foo/author]
+        [:db/add "a" :db/valueType :db.type/ref]
+        [:db/add "a" :db/cardinality :db.cardinality/one]
+        [:db/add "y" :db/ident :foo/year]
+        [:db/add "y" :db/valueType :db.type/long]
+        [:db/add "y" :db/cardinality :db.cardinality/one]
+    ]"#).unwrap();
+
+    let ids = conn.transact(&mut c, r#"[
+        [:db/add "alice" :foo/name "Alice"]
+        [:db/add "bob" :foo/name "Bob"]
+        [:db/add "carol" :foo/name "Carol"]
+        [:db/add "b1" :foo/author "alice"]
+        [:db/add "b1" :foo/year 1970]
+        [:db/add "b2" :foo/author "bob"]
+        [:db/add "b2" :foo/year 1980]
+    ]"#).unwrap().tempids;
+    let bob = *ids.get("bob").unwrap();
+    let carol = *ids.get("carol").unwrap();
+
+    // With `not`, every variable in the clause must unify with the outer query: here `?b`
+    // is bound outside, so only authors of a book not from 1970 match.
+    let r = conn.q_once(&mut c,
+                        r#"[:find [?a ...]
+                            :where [?a :foo/name _]
+                                   [?b :foo/author ?a]
+                                   (not [?b :foo/year 1970])]"#, None);
+    match r {
+        Result::Ok(QueryOutput { results: QueryResults::Coll(vals), .. }) => assert_eq!(vals, vec![TypedValue::Ref(bob)]),
+        r => panic!("Expected query to work: {:?}", r),
+    }
+
+    // `not-join` correlates only on `?a`; `?b` is local to the subquery, so people who
+    // wrote nothing at all also match.
+    let r = conn.q_once(&mut c,
+                        r#"[:find [?a ...]
+                            :where [?a :foo/name _]
+                                   (not-join [?a]
+                                     [?b :foo/author ?a]
+                                     [?b :foo/year 1970])]"#, None);
+    match r {
+        Result::Ok(QueryOutput { results: QueryResults::Coll(mut vals), .. }) => {
+            vals.sort();
+            let mut expected = vec![TypedValue::Ref(bob), TypedValue::Ref(carol)];
+            expected.sort();
+            assert_eq!(vals, expected);
+        },
+        r => panic!("Expected query to work: {:?}", r),
+    }
+
+    // The same clause written with `not` would need `?b` bound in the outer query.
+    let r = conn.q_once(&mut c,
+                        r#"[:find [?a ...]
+                            :where [?a :foo/name _]
+                                   (not [?b :foo/author ?a]
+                                        [?b :foo/year 1970])]"#, None);
+    assert!(r.is_err());
+
+    // Joining on a variable the outer query doesn't bind is an error.
+    let r = conn.q_once(&mut c,
+                        r#"[:find [?a ...]
+                            :where [?a :foo/name _]
+                                   (not-join [?z]
+                                     [?z :foo/year 1970])]"#, None);
+    assert!(r.is_err());
+}
+
+#[test]
+fn test_pull() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        [:db/add "n" :db/ident :person/name]
+        [:db/add "n" :db/valueType :db.type/string]
+        [:db/add "n" :db/cardinality :db.cardinality/one]
+        [:db/add "a" :db/ident :person/age]
+        [:db/add "a" :db/valueType :db.type/long]
+        [:db/add "a" :db/cardinality :db.cardinality/one]
+        [:db/add "k" :db/ident :person/nickname]
+        [:db/add "k" :db/valueType :db.type/string]
+        [:db/add "k" :db/cardinality :db.cardinality/many]
+        [:db/add "f" :db/ident :person/friend]
+        [:db/add "f" :db/valueType :db.type/ref]
+        [:db/add "f" :db/cardinality :db.cardinality/many]
+    ]"#).unwrap();
+
+    let ids = conn.transact(&mut c, r#"[
+        [:db/add "alice" :person/name "Alice"]
+        [:db/add "alice" :person/age 32]
+        [:db/add "alice" :person/nickname "Al"]
+        [:db/add "alice" :person/nickname "Ally"]
+        [:db/add "alice" :person/friend "bob"]
+        [:db/add "bob" :person/name "Bob"]
+        [:db/add "carol" :person/name "Carol"]
+        [:db/add "carol" :person/friend "bob"]
+    ]"#).unwrap().tempids;
+    let alice = *ids.get("alice").unwrap();
+    let bob = *ids.get("bob").unwrap();
+    let carol = *ids.get("carol").unwrap();
+
+    let name = NamespacedKeyword::new("person", "name");
+    let age = NamespacedKeyword::new("person", "age");
+    let nickname = NamespacedKeyword::new("person", "nickname");
+    let friend = NamespacedKeyword::new("person", "friend");
+
+    let pull_one = |query: &str| -> StructuredMap {
+        match conn.q_once(&c, query, None) {
+            Result::Ok(QueryOutput { results: QueryResults::Scalar(Some(Binding::Map(m))), .. }) => m,
+            r => panic!("Expected a pulled map: {:?}", r),
+        }
+    };
+
+    // A simple attribute list.
+    let m = pull_one(r#"[:find (pull ?p [:person/name :person/age]) .
+                         :where [?p :person/name "Alice"]]"#);
+    let mut expected = StructuredMap::default();
+    expected.insert(name.clone(), TypedValue::typed_string("Alice"));
+    expected.insert(age.clone(), TypedValue::Long(32));
+    assert_eq!(m, expected);
+
+    // Missing attributes are omitted from the map.
+    let m = pull_one(r#"[:find (pull ?p [:person/name :person/age]) .
+                         :where [?p :person/name "Bob"]]"#);
+    let mut expected = StructuredMap::default();
+    expected.insert(name.clone(), TypedValue::typed_string("Bob"));
+    assert_eq!(m, expected);
+
+    // A cardinality-many attribute is a vector.
+    let m = pull_one(r#"[:find (pull ?p [:person/nickname]) .
+                         :where [?p :person/name "Alice"]]"#);
+    assert_eq!(m.get(&nickname),
+               Some(&Binding::Vec(vec![Binding::Scalar(TypedValue::typed_string("Al")),
+                                       Binding::Scalar(TypedValue::typed_string("Ally"))])));
+
+    // A wildcard pulls everything.
+    let m = pull_one(r#"[:find (pull ?p [*]) .
+                         :where [?p :person/name "Alice"]]"#);
+    assert_eq!(m.len(), 4);
+    assert_eq!(m.get(&name), Some(&Binding::Scalar(TypedValue::typed_string("Alice"))));
+    assert_eq!(m.get(&age), Some(&Binding::Scalar(TypedValue::Long(32))));
+    assert_eq!(m.get(&friend), Some(&Binding::Vec(vec![Binding::Scalar(TypedValue::Ref(bob))])));
+
+    // Reversed attributes pull incoming refs.
+    let m = pull_one(r#"[:find (pull ?p [:person/_friend]) .
+                         :where [?p :person/name "Bob"]]"#);
+    let mut friends = m.get(&friend.to_reversed()).cloned().and_then(|b| b.into_vec()).expect("incoming refs");
+    friends.sort();
+    let mut expected = vec![Binding::Scalar(TypedValue::Ref(alice)), Binding::Scalar(TypedValue::Ref(carol))];
+    expected.sort();
+    assert_eq!(friends, expected);
+
+    // Pull expressions can be mixed with variables.
+    let r = conn.q_once(&c,
+                        r#"[:find ?n (pull ?p [:person/age])
+                            :where [?p :person/name ?n]
+                            :order ?n]"#, None);
+    let mut alice_age = StructuredMap::default();
+    alice_age.insert(age.clone(), TypedValue::Long(32));
+    match r {
+        Result::Ok(QueryOutput { results: QueryResults::Rel(rows), .. }) => {
+            assert_eq!(rows, vec![
+                vec![Binding::Scalar(TypedValue::typed_string("Alice")), Binding::Map(alice_age)],
+                vec![Binding::Scalar(TypedValue::typed_string("Bob")), Binding::Map(StructuredMap::default())],
+                vec![Binding::Scalar(TypedValue::typed_string("Carol")), Binding::Map(StructuredMap::default())],
+            ]);
+        },
+        r => panic!("Expected query to work: {:?}", r),
+    }
+
+    // Only attributes can be pulled, and only entities can be pulled from.
+    assert!(conn.q_once(&c, r#"[:find (pull ?p [:person/height]) . :where [?p :person/name _]]"#, None).is_err());
+    assert!(conn.q_once(&c, r#"[:find (pull ?n [*]) . :where [_ :person/name ?n]]"#, None).is_err());
+}
+
+#[test]
+fn test_pull_to_edn() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        [:db/add "n" :db/ident :node/name]
+        [:db/add "n" :db/valueType :db.type/string]
+        [:db/add "n" :db/cardinality :db.cardinality/one]
+        [:db/add "t" :db/ident :node/tag]
+        [:db/add "t" :db/valueType :db.type/keyword]
+        [:db/add "t" :db/cardinality :db.cardinality/many]
+        [:db/add "p" :db/ident :node/part]
+        [:db/add "p" :db/valueType :db.type/ref]
+        [:db/add "p" :db/cardinality :db.cardinality/one]
+        [:db/add "p" :db/isComponent true]
+        [:db/add "l" :db/ident :node/link]
+        [:db/add "l" :db/valueType :db.type/ref]
+        [:db/add "l" :db/cardinality :db.cardinality/one]
+    ]"#).unwrap();
+
+    let ids = conn.transact(&mut c, r#"[
+        [:db/add "flat" :node/name "flat"]
+        [:db/add "flat" :node/tag :tag/a]
+        [:db/add "flat" :node/tag :tag/b]
+        [:db/add "flat" :node/link "owner"]
+        [:db/add "owner" :node/name "owner"]
+        [:db/add "owner" :node/part "part"]
+        [:db/add "part" :node/name "part"]
+        [:db/add "part" :node/part "owner"]
+        [:db/add "self" :node/name "self"]
+        [:db/add "self" :node/part "self"]
+    ]"#).unwrap().tempids;
+    let owner = *ids.get("owner").unwrap();
+    let self_ = *ids.get("self").unwrap();
+
+    let pull_edn = |name: &str| -> edn::Value {
+        let query = format!(r#"[:find (pull ?e [*]) . :where [?e :node/name "{}"]]"#, name);
+        match conn.q_once(&c, query.as_str(), None) {
+            Result::Ok(QueryOutput { results: QueryResults::Scalar(Some(binding)), .. }) => binding.to_edn(),
+            r => panic!("Expected a pulled map: {:?}", r),
+        }
+    };
+
+    // Cardinality-many values are vectors, and a ref that isn't a component is just its :db/id.
+    assert_eq!(pull_edn("flat"),
+               edn::parse::value(format!(r#"{{:node/name "flat"
+                                              :node/tag [:tag/a :tag/b]
+                                              :node/link {{:db/id {}}}}}"#, owner).as_str()).unwrap().without_spans());
+
+    // A component is pulled in full, and a component that refers back to its owner ends there.
+    assert_eq!(pull_edn("owner"),
+               edn::parse::value(format!(r#"{{:node/name "owner"
+                                              :node/part {{:node/name "part"
+                                                           :node/part {{:db/id {}}}}}}}"#, owner).as_str()).unwrap().without_spans());
+
+    // So does a component of itself.
+    assert_eq!(pull_edn("self"),
+               edn::parse::value(format!(r#"{{:node/name "self"
+                                              :node/part {{:db/id {}}}}}"#, self_).as_str()).unwrap().without_spans());
+}
+
+#[test]
+fn test_missing() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        [:db/add "n" :db/ident :foo/name]
+        [:db/add "n" :db/valueType :db.type/string]
+        [:db/add "n" :db/cardinality :db.cardinality/one]
+        [:db/add "t" :db/ident :foo/tag]
+        [:db/add "t" :db/valueType :db.type/string]
+        [:db/add "t" :db/cardinality :db.cardinality/many]
+    ]"#).unwrap();
+
+    let ids = conn.transact(&mut c, r#"[
+        [:db/add "a" :foo/name "tagged"]
+        [:db/add "a" :foo/tag "x"]
+        [:db/add "a" :foo/tag "y"]
+        [:db/add "b" :foo/name "untagged"]
+    ]"#).unwrap().tempids;
+    let b = *ids.get("b").unwrap();
+
+    // Entities with any number of values for the attribute are excluded; those without
+    // are included.
+    let r = conn.q_once(&mut c,
+                        r#"[:find [?e ...]
+                            :where [?e :foo/name _]
+                                   [(missing? $ ?e :foo/tag)]]"#, None);
+    match r {
+        Result::Ok(QueryOutput { results: QueryResults::Coll(vals), .. }) => assert_eq!(vals, vec![TypedValue::Ref(b)]),
+        r => panic!("Expected query to work: {:?}", r),
+    }
+
+    // Nobody lacks a name.
+    let r = conn.q_once(&mut c,
+                        r#"[:find [?e ...]
+                            :where [?e :foo/name _]
+                                   [(missing? $ ?e :foo/name)]]"#, None);
+    match r {
+        Result::Ok(QueryOutput { results: QueryResults::Coll(vals), .. }) => assert!(vals.is_empty()),
+        r => panic!("Expected query to work: {:?}", r),
+    }
+
+    // An unknown attribute produces no results.
+    let r = conn.q_once(&mut c,
+                        r#"[:find [?e ...]
+                            :where [?e :foo/name _]
+                                   [(missing? $ ?e :foo/nope)]]"#, None);
+    match r {
+        Result::Ok(QueryOutput { results: QueryResults::Coll(vals), .. }) => assert!(vals.is_empty()),
+        r => panic!("Expected query to work: {:?}", r),
+    }
+}
+
+#[test]
+fn test_ground_lookup_ref() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        [:db/add "e" :db/ident :person/email]
+        [:db/add "e" :db/valueType :db.type/string]
+        [:db/add "e" :db/cardinality :db.cardinality/one]
+        [:db/add "e" :db/unique :db.unique/identity]
+        [:db/add "n" :db/ident :person/name]
+        [:db/add "n" :db/valueType :db.type/string]
+        [:db/add "n" :db/cardinality :db.cardinality/one]
+    ]"#).unwrap();
+
+    let ids = conn.transact(&mut c, r#"[
+        [:db/add "a" :person/email "a@b.com"]
+        [:db/add "a" :person/name "Alice"]
+        [:db/add "b" :person/email "b@b.com"]
+        [:db/add "b" :person/name "Bob"]
+    ]"#).unwrap().tempids;
+    let a = *ids.get("a").unwrap();
+
+    let r = conn.q_once(&mut c,
+                        r#"[:find [?x ?name]
+                            :where [(ground {:person/email "a@b.com"}) ?x]
+                                   [?x :person/name ?name]]"#, None);
+    match r {
+        Result::Ok(QueryOutput { results: QueryResults::Tuple(Some(vals)), .. }) => {
+            assert_eq!(vals, vec![TypedValue::Ref(a), TypedValue::typed_string("Alice")]);
+        },
+        r => panic!("Expected query to work: {:?}", r),
+    }
+
+    // No entity has this email, so nothing matches.
+    let r = conn.q_once(&mut c,
+                        r#"[:find ?x
+                            :where [(ground {:person/email "c@b.com"}) ?x]]"#, None);
+    match r {
+        Result::Ok(QueryOutput { results: QueryResults::Rel(rels), .. }) => assert!(rels.is_empty()),
+        r => panic!("Expected query to work: {:?}", r),
+    }
+
+    // Names aren't unique, so they can't identify an entity.
+    let r = conn.q_once(&mut c,
+                        r#"[:find ?x
+                            :where [(ground {:person/name "Alice"}) ?x]]"#, None);
+    match r {
+        Err(Error(ErrorKind::QueryError(mentat_query_algebrizer::ErrorKind::NonUniqueLookupRefAttribute(_)), _)) => {},
+        r => panic!("Expected query to fail: {:?}", r),
+    }
+}
+
+#[test]
+fn test_order_by_multiple_keys() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        [:db/add "n" :db/ident :foo/name]
+        [:db/add "n" :db/valueType :db.type/string]
+        [:db/add "n" :db/cardinality :db.cardinality/one]
+        [:db/add "a" :db/ident :foo/age]
+        [:db/add "a" :db/valueType :db.type/long]
+        [:db/add "a" :db/cardinality :db.cardinality/one]
+        [:db/add "b" :db/ident :foo/born]
+        [:db/add "b" :db/valueType :db.type/instant]
+        [:db/add "b" :db/cardinality :db.cardinality/one]
+    ]"#).unwrap();
+
+    conn.transact(&mut c, r#"[
+        [:db/add "w" :foo/name "Alice"]
+        [:db/add "w" :foo/age 30]
+        [:db/add "w" :foo/born #inst "1987-03-01T00:00:00.000Z"]
+        [:db/add "x" :foo/name "Bob"]
+        [:db/add "x" :foo/age 25]
+        [:db/add "x" :foo/born #inst "1992-07-01T00:00:00.000Z"]
+        [:db/add "y" :foo/name "Alice"]
+        [:db/add "y" :foo/age 40]
+        [:db/add "y" :foo/born #inst "1977-11-01T00:00:00.000Z"]
+    ]"#).unwrap();
+
+    // Ties on the first key are broken by the second, in its own direction.
+    let r = conn.q_once(&mut c,
+                        r#"[:find ?name ?age
+                            :where [?x :foo/name ?name] [?x :foo/age ?age]
+                            :order (asc ?name) (desc ?age)]"#, None);
+    match r {
+        Result::Ok(QueryOutput { results: QueryResults::Rel(rels), .. }) => {
+            assert_eq!(rels, vec![
+                vec![TypedValue::typed_string("Alice"), TypedValue::Long(40)],
+                vec![TypedValue::typed_string("Alice"), TypedValue::Long(30)],
+                vec![TypedValue::typed_string("Bob"), TypedValue::Long(25)],
+            ]);
+        },
+        r => panic!("Expected query to work: {:?}", r),
+    }
+
+    // Instants order chronologically, even when they aren't projected.
+    let r = conn.q_once(&mut c,
+                        r#"[:find ?age
+                            :with ?born
+                            :where [?x :foo/age ?age] [?x :foo/born ?born]
+                            :order (desc ?born)]"#, None);
+    match r {
+        Result::Ok(QueryOutput { results: QueryResults::Rel(rels), .. }) => {
+            assert_eq!(rels, vec![
+                vec![TypedValue::Long(25)],
+                vec![TypedValue::Long(30)],
+                vec![TypedValue::Long(40)],
+            ]);
+        },
+        r => panic!("Expected query to work: {:?}", r),
+    }
+
+    // You can't order by a variable the query doesn't bind.
+    let r = conn.q_once(&mut c,
+                        r#"[:find ?name
+                            :where [?x :foo/name ?name]
+                            :order ?age]"#, None);
+    match r {
+        Err(Error(ErrorKind::QueryError(mentat_query_algebrizer::ErrorKind::UnboundVariable(var)), _)) => {
+            assert_eq!(var, PlainSymbol::new("?age"));
+        },
+        r => panic!("Expected query to fail: {:?}", r),
+    }
+}
+
+#[test]
+fn test_limit_and_offset_inputs() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        [:db/add "n" :db/ident :foo/rank]
+        [:db/add "n" :db/valueType :db.type/long]
+        [:db/add "n" :db/cardinality :db.cardinality/one]
+    ]"#).unwrap();
+
+    conn.transact(&mut c, r#"[
+        [:db/add "a" :foo/rank 1]
+        [:db/add "b" :foo/rank 2]
+        [:db/add "c" :foo/rank 3]
+        [:db/add "d" :foo/rank 4]
+        [:db/add "e" :foo/rank 5]
+    ]"#).unwrap();
+
+    let page_of_ranks = |query: &str, limit: i64, offset: i64| {
+        let inputs = QueryInputs::with_value_sequence(vec![
+            (Variable::from_valid_name("?limit"), TypedValue::Long(limit)),
+            (Variable::from_valid_name("?offset"), TypedValue::Long(offset)),
+        ]);
+        conn.q_once(&c, query, inputs)
+    };
+
+    let ascending = r#"[:find [?r ...]
+                        :in ?limit ?offset
+                        :where [_ :foo/rank ?r]
+                        :order ?r
+                        :limit ?limit
+                        :offset ?offset]"#;
+    let descending = r#"[:find [?r ...]
+                         :in ?limit ?offset
+                         :where [_ :foo/rank ?r]
+                         :order (desc ?r)
+                         :limit ?limit
+                         :offset ?offset]"#;
+
+    // Pages are taken after ordering.
+    match page_of_ranks(ascending, 2, 0) {
+        Result::Ok(QueryOutput { results: QueryResults::Coll(vals), .. }) => assert_eq!(vals, vec![TypedValue::Long(1), TypedValue::Long(2)]),
+        r => panic!("Expected query to work: {:?}", r),
+    }
+    match page_of_ranks(ascending, 2, 2) {
+        Result::Ok(QueryOutput { results: QueryResults::Coll(vals), .. }) => assert_eq!(vals, vec![TypedValue::Long(3), TypedValue::Long(4)]),
+        r => panic!("Expected query to work: {:?}", r),
+    }
+    match page_of_ranks(descending, 2, 2) {
+        Result::Ok(QueryOutput { results: QueryResults::Coll(vals), .. }) => assert_eq!(vals, vec![TypedValue::Long(3), TypedValue::Long(2)]),
+        r => panic!("Expected query to work: {:?}", r),
+    }
+
+    // Running off the end yields a short page, then an empty one.
+    match page_of_ranks(ascending, 2, 4) {
+        Result::Ok(QueryOutput { results: QueryResults::Coll(vals), .. }) => assert_eq!(vals, vec![TypedValue::Long(5)]),
+        r => panic!("Expected query to work: {:?}", r),
+    }
+    match page_of_ranks(ascending, 2, 6) {
+        Result::Ok(QueryOutput { results: QueryResults::Coll(vals), .. }) => assert!(vals.is_empty()),
+        r => panic!("Expected query to work: {:?}", r),
+    }
+
+    // Offsets can't be negative…
+    match page_of_ranks(ascending, 2, -1) {
+        Err(Error(ErrorKind::QueryError(mentat_query_algebrizer::ErrorKind::InvalidOffset(val, ValueType::Long)), _)) => {
+            assert_eq!(val, "-1");
+        },
+        r => panic!("Expected query to fail: {:?}", r),
+    }
+
+    // … and must be integers.
+    let inputs = QueryInputs::with_value_sequence(vec![
+        (Variable::from_valid_name("?offset"), TypedValue::Double(2.5.into())),
+    ]);
+    let r = conn.q_once(&c,
+                        r#"[:find [?r ...]
+                            :in ?offset
+                            :where [_ :foo/rank ?r]
+                            :offset ?offset]"#, inputs);
+    match r {
+        Err(Error(ErrorKind::QueryError(mentat_query_algebrizer::ErrorKind::InvalidOffset(_, ValueType::Double)), _)) => {},
+        r => panic!("Expected query to fail: {:?}", r),
+    }
+}
+
+#[test]
+fn test_query_after() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        [:db/add "n" :db/ident :foo/name]
+        [:db/add "n" :db/valueType :db.type/string]
+        [:db/add "n" :db/cardinality :db.cardinality/one]
+        [:db/add "a" :db/ident :foo/age]
+        [:db/add "a" :db/valueType :db.type/long]
+        [:db/add "a" :db/cardinality :db.cardinality/one]
+    ]"#).unwrap();
+
+    conn.transact(&mut c, r#"[
+        [:db/add "u" :foo/name "Alice"]
+        [:db/add "u" :foo/age 30]
+        [:db/add "v" :foo/name "Bob"]
+        [:db/add "v" :foo/age 25]
+        [:db/add "w" :foo/name "Alice"]
+        [:db/add "w" :foo/age 40]
+        [:db/add "x" :foo/name "Carol"]
+        [:db/add "x" :foo/age 35]
+        [:db/add "y" :foo/name "Alice"]
+        [:db/add "y" :foo/age 20]
+    ]"#).unwrap();
+
+    // Ties on the name are broken by the age, so the order is total, and each page resumes where
+    // the last left off, even in the middle of a run of Alices.
+    let query = r#"[:find ?name ?age
+                    :where [?x :foo/name ?name] [?x :foo/age ?age]
+                    :order (asc ?name) (desc ?age)
+                    :limit 2]"#;
+
+    let mut pages = vec![];
+    let mut page = conn.query_after(&c, query, None, None).expect("first page");
+    loop {
+        match page.output.results {
+            QueryResults::Rel(ref rows) => pages.push(rows.clone()),
+            ref r => panic!("Expected a relation: {:?}", r),
+        }
+        match page.next.take() {
+            Some(next) => page = conn.query_after(&c, query, None, Some(&next)).expect("next page"),
+            None => break,
+        }
+    }
+
+    // Three full or partial pages, and then an empty one that ends the loop.
+    assert_eq!(pages, vec![
+        vec![vec![TypedValue::typed_string("Alice"), TypedValue::Long(40)],
+             vec![TypedValue::typed_string("Alice"), TypedValue::Long(30)]],
+        vec![vec![TypedValue::typed_string("Alice"), TypedValue::Long(20)],
+             vec![TypedValue::typed_string("Bob"), TypedValue::Long(25)]],
+        vec![vec![TypedValue::typed_string("Carol"), TypedValue::Long(35)]],
+        vec![],
+    ]);
+
+    // The cursor holds the last row's ordered values, with their types.
+    let first = conn.query_after(&c, query, None, None).expect("first page");
+    let next = first.next.expect("a cursor");
+    assert_eq!(next.get(&Variable::from_valid_name("?name")), Some(&TypedValue::typed_string("Alice")));
+    assert_eq!(next.get(&Variable::from_valid_name("?age")), Some(&TypedValue::Long(30)));
+
+    // A query must be ordered by variables it finds.
+    match conn.query_after(&c, r#"[:find ?name :where [?x :foo/name ?name] [?x :foo/age ?age] :order ?age]"#, None, None) {
+        Err(Error(ErrorKind::UnfoundCursorVariable(var), _)) => assert_eq!(var, "?age"),
+        r => panic!("Expected query to fail: {:?}", r),
+    }
+
+    // And a cursor's values must have the types of the ordered variables.
+    let wrong = mentat::QueryCursor::new(vec![(Variable::from_valid_name("?name"), TypedValue::Long(5)),
+                                              (Variable::from_valid_name("?age"), TypedValue::Long(5))].into_iter().collect());
+    match conn.query_after(&c, query, None, Some(&wrong)) {
+        Err(Error(ErrorKind::QueryError(mentat_query_algebrizer::ErrorKind::InvalidCursorValue(var)), _)) => {
+            assert_eq!(var, PlainSymbol::new("?name"));
+        },
+        r => panic!("Expected query to fail: {:?}", r),
+    }
+}
+
+#[test]
+fn test_type_of() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    conn.transact(&mut c, r#"[
+        [:db/add "a" :db/ident :foo/count]
+        [:db/add "a" :db/valueType :db.type/long]
+        [:db/add "b" :db/ident :foo/label]
+        [:db/add "b" :db/valueType :db.type/string]
+        [:db/add "c" :db/ident :foo/ratio]
+        [:db/add "c" :db/valueType :db.type/double]
+    ]"#).unwrap();
+
+    let report = conn.transact(&mut c, r#"[
+        [:db/add "x" :foo/count 7]
+        [:db/add "x" :foo/label "seven"]
+        [:db/add "y" :foo/count 12]
+        [:db/add "y" :foo/label "twelve"]
+        [:db/add "y" :foo/ratio 1.5]
+    ]"#).unwrap();
+    let entid = |tempid: &str| report.tempids.get(tempid).cloned().expect("tempid was mapped");
+
+    let long = TypedValue::typed_ns_keyword("db.type", "long");
+    let string = TypedValue::typed_ns_keyword("db.type", "string");
+    let double = TypedValue::typed_ns_keyword("db.type", "double");
+
+    // A column holding values of several types gets the right type for each row.
+    let inputs = QueryInputs::with_value_sequence(vec![(Variable::from_valid_name("?e"), TypedValue::Ref(entid("y")))]);
+    let results: BTreeSet<Vec<Binding>> = conn.q_once(&c,
+                                                      r#"[:find ?v ?t
+                                                          :in ?e
+                                                          :where [?e ?a ?v]
+                                                                 [(type-of ?v) ?t]]"#, inputs)
+                                              .expect("results")
+                                              .into_rel()
+                                              .expect("a rel")
+                                              .into_iter()
+                                              .collect();
+    let expected: BTreeSet<Vec<Binding>> =
+        vec![vec![TypedValue::Long(12).into(), long.clone().into()],
+             vec![TypedValue::typed_string("twelve").into(), string.clone().into()],
+             vec![TypedValue::Double(1.5.into()).into(), double.clone().into()]].into_iter().collect();
+    assert_eq!(results, expected);
+
+    // Across entities, too. Long and double share a type tag, but are still told apart.
+    let results: BTreeSet<Vec<Binding>> = conn.q_once(&c,
+                                                      r#"[:find ?e ?t
+                                                          :where (or [?e :foo/count ?v]
+                                                                     [?e :foo/label ?v]
+                                                                     [?e :foo/ratio ?v])
+                                                                 [(type-of ?v) ?t]]"#, None)
+                                              .expect("results")
+                                              .into_rel()
+                                              .expect("a rel")
+                                              .into_iter()
+                                              .collect();
+    let expected: BTreeSet<Vec<Binding>> =
+        vec![vec![TypedValue::Ref(entid("x")).into(), long.clone().into()],
+             vec![TypedValue::Ref(entid("x")).into(), string.clone().into()],
+             vec![TypedValue::Ref(entid("y")).into(), long.clone().into()],
+             vec![TypedValue::Ref(entid("y")).into(), string.clone().into()],
+             vec![TypedValue::Ref(entid("y")).into(), double.clone().into()]].into_iter().collect();
+    assert_eq!(results, expected);
+
+    // When the type is known in advance, we don't need to look at the data.
+    let results = conn.q_once(&c,
+                              r#"[:find [?t ...]
+                                  :where [_ :foo/label ?v]
+                                         [(type-of ?v) ?t]]"#, None)
+                      .expect("results")
+                      .into_coll()
+                      .expect("a coll");
+    assert_eq!(results, vec![string.clone()]);
+
+    // The type binding can only be projected.
+    let r = conn.q_once(&c,
+                        r#"[:find ?e
+                            :with ?t
+                            :where [?e ?a ?v]
+                                   [(type-of ?v) ?t]]"#, None);
+    match r {
+        Err(Error(ErrorKind::QueryError(mentat_query_algebrizer::ErrorKind::TypeOfCanOnlyBeProjected(var)), _)) => {
+            assert_eq!(var, PlainSymbol::new("?t"));
+        },
+        x => panic!("Expected a type-of error, got {:?}", x),
+    }
+}
+
+#[test]
+fn test_algebrize_inspect() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let db = mentat_db::db::ensure_current_version(&mut c).expect("Couldn't open DB.");
+
+    // A well-typed query isn't empty, and we know its types without running it.
+    let parsed = parse_find_string(r#"[:find ?x ?ident
+                                        :in ?a
+                                        :where [?x :db/ident ?ident]
+                                               [?x :db/cardinality ?a]]"#).expect("parsed");
+    let inspected = algebrize_inspect(&db.schema, &parsed).expect("algebrized");
+    let vx = Variable::from_valid_name("?x");
+    let vident = Variable::from_valid_name("?ident");
+    let va = Variable::from_valid_name("?a");
+    assert!(!inspected.is_known_empty());
+    assert_eq!(inspected.empty_because(), None);
+    assert_eq!(inspected.known_type(&vx), Some(ValueType::Ref));
+    assert_eq!(inspected.known_type(&vident), Some(ValueType::Keyword));
+    assert_eq!(inspected.known_type(&va), Some(ValueType::Ref));
+    assert_eq!(inspected.column_bindings().get(&vx).map(|cols| cols.len()), Some(2));
+    assert_eq!(inspected.unbound_variables(), vec![va].into_iter().collect());
+
+    // An unknown attribute can never match, and we can say why.
+    let parsed = parse_find_string(r#"[:find ?x :where [?x :foo/nope "John"]]"#).expect("parsed");
+    let inspected = algebrize_inspect(&db.schema, &parsed).expect("algebrized");
+    assert!(inspected.is_known_empty());
+    assert_eq!(inspected.empty_because(),
+               Some(&EmptyBecause::UnresolvedIdent(NamespacedKeyword::new("foo", "nope"))));
+}
+
+#[test]
+fn test_proven_empty() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    // `?i` must be a keyword, so grounding it to an integer can never match.
+    let output = conn.q_once(&c, r#"[:find ?x
+                                     :where [?x :db/ident ?i]
+                                            [(ground 5) ?i]]"#, None)
+                     .expect("query succeeded");
+    assert_eq!(output.results, QueryResults::Rel(vec![]));
+    assert_eq!(output.proven_empty,
+               Some(EmptyBecause::TypeMismatch {
+                   var: Variable::from_valid_name("?i"),
+                   existing: ValueTypeSet::of_one(ValueType::Keyword),
+                   desired: ValueTypeSet::of_longs(),
+               }));
+
+    // A query that could match, but happens not to, isn't proven empty.
+    let output = conn.q_once(&c, r#"[:find ?x
+                                     :where [?x :db/ident :foo/nope]]"#, None)
+                     .expect("query succeeded");
+    assert_eq!(output.results, QueryResults::Rel(vec![]));
+    assert_eq!(output.proven_empty, None);
+}
+
+#[test]
+fn test_query_as_of() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    let schema = conn.transact(&mut c, r#"[
+        [:db/add "s" :db/ident :foo/name]
+        [:db/add "s" :db/valueType :db.type/string]
+        [:db/add "s" :db/cardinality :db.cardinality/one]
+    ]"#).expect("transacted schema");
+    let first = conn.transact(&mut c, r#"[
+        [:db/add "a" :db/ident :person/alice]
+        [:db/add "a" :foo/name "Alice"]
+    ]"#).expect("transacted first name");
+    let second = conn.transact(&mut c, r#"[
+        [:db/add :person/alice :foo/name "Alicia"]
+    ]"#).expect("transacted second name");
+
+    let query = r#"[:find ?n . :where [:person/alice :foo/name ?n]]"#;
+    let name_as_of = |as_of: AsOf| {
+        conn.query_as_of(&c, query, as_of, None)
+            .expect("query succeeded")
+            .results
+    };
+
+    assert_eq!(name_as_of(AsOf::Tx(second.tx_id)),
+               QueryResults::Scalar(Some(Binding::Scalar(TypedValue::String("Alicia".to_string().into())))));
+
+    // The retraction made in the second transaction isn't visible before it.
+    assert_eq!(name_as_of(AsOf::Tx(first.tx_id)),
+               QueryResults::Scalar(Some(Binding::Scalar(TypedValue::String("Alice".to_string().into())))));
+
+    // Nor is anything asserted later.
+    assert_eq!(name_as_of(AsOf::Tx(schema.tx_id)),
+               QueryResults::Scalar(None));
+
+    // An instant reads as of the last transaction at or before it.
+    assert_eq!(name_as_of(AsOf::Instant(second.tx_instant)),
+               QueryResults::Scalar(Some(Binding::Scalar(TypedValue::String("Alicia".to_string().into())))));
+
+    // The present is unchanged.
+    assert_eq!(conn.q_once(&c, query, None).expect("query succeeded").results,
+               QueryResults::Scalar(Some(Binding::Scalar(TypedValue::String("Alicia".to_string().into())))));
+}
+
+#[test]
+fn test_query_history() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    conn.transact(&mut c, r#"[
+        [:db/add "s" :db/ident :foo/name]
+        [:db/add "s" :db/valueType :db.type/string]
+        [:db/add "s" :db/cardinality :db.cardinality/one]
+    ]"#).expect("transacted schema");
+    let asserted = conn.transact(&mut c, r#"[
+        [:db/add "a" :foo/name "Alice"]
+    ]"#).expect("asserted");
+    let alice = *asserted.tempids.get("a").expect("allocated");
+    let retracted = conn.transact(&mut c, format!("[[:db/retract {} :foo/name \"Alice\"]]", alice).as_str())
+                        .expect("retracted");
+    let reasserted = conn.transact(&mut c, format!("[[:db/add {} :foo/name \"Alicia\"]]", alice).as_str())
+                         .expect("reasserted");
+
+    let name = NamespacedKeyword::new("foo", "name");
+    let a = conn.current_schema().get_entid(&name).expect("attribute");
+    let history = conn.query_history(&c, alice, &name).expect("history");
+    assert_eq!(history, vec![
+        vec![TypedValue::Ref(alice), TypedValue::Ref(a), TypedValue::typed_string("Alice"),
+             TypedValue::Ref(asserted.tx_id), TypedValue::Boolean(true)],
+        vec![TypedValue::Ref(alice), TypedValue::Ref(a), TypedValue::typed_string("Alice"),
+             TypedValue::Ref(retracted.tx_id), TypedValue::Boolean(false)],
+        vec![TypedValue::Ref(alice), TypedValue::Ref(a), TypedValue::typed_string("Alicia"),
+             TypedValue::Ref(reasserted.tx_id), TypedValue::Boolean(true)],
+    ]);
+
+    // Unknown attributes are an error.
+    assert!(conn.query_history(&c, alice, &NamespacedKeyword::new("foo", "nope")).is_err());
+}
+
+#[test]
+fn test_attribute_count() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    conn.transact(&mut c, r#"[
+        [:db/add "s" :db/ident :foo/email]
+        [:db/add "s" :db/valueType :db.type/string]
+        [:db/add "s" :db/cardinality :db.cardinality/one]
+        [:db/add "t" :db/ident :foo/tag]
+        [:db/add "t" :db/valueType :db.type/keyword]
+        [:db/add "t" :db/cardinality :db.cardinality/many]
+    ]"#).expect("transacted schema");
+
+    let email = NamespacedKeyword::new("foo", "email");
+    let tag = NamespacedKeyword::new("foo", "tag");
+    assert_eq!(conn.attribute_count(&c, &email).expect("counted"), 0);
+
+    let report = conn.transact(&mut c, r#"[
+        [:db/add "a" :foo/email "alice@example.com"]
+        [:db/add "b" :foo/email "bob@example.com"]
+        [:db/add "c" :foo/email "carol@example.com"]
+        [:db/add "a" :foo/tag :foo/admin]
+        [:db/add "a" :foo/tag :foo/staff]
+        [:db/add "b" :foo/tag :foo/staff]
+    ]"#).expect("transacted data");
+    assert_eq!(conn.attribute_count(&c, &email).expect("counted"), 3);
+    assert_eq!(conn.attribute_count(&c, &tag).expect("counted"), 3);
+
+    // Replacing a value leaves the count alone; retracting one decrements it.
+    let alice = *report.tempids.get("a").expect("allocated");
+    let bob = *report.tempids.get("b").expect("allocated");
+    conn.transact(&mut c, format!("[[:db/add {} :foo/email \"alice@example.org\"]
+                                     [:db/retract {} :foo/email \"bob@example.com\"]]", alice, bob).as_str())
+        .expect("transacted changes");
+    assert_eq!(conn.attribute_count(&c, &email).expect("counted"), 2);
+
+    // Each value of a cardinality-many attribute counts.
+    conn.transact(&mut c, format!("[[:db/retract {} :foo/tag :foo/staff]]", alice).as_str())
+        .expect("retracted tag");
+    assert_eq!(conn.attribute_count(&c, &tag).expect("counted"), 2);
+
+    // Unknown attributes are an error.
+    assert!(conn.attribute_count(&c, &NamespacedKeyword::new("foo", "nope")).is_err());
+}
+
+#[test]
+fn test_attributes() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    let report = conn.transact(&mut c, r#"[
+        [:db/add "s" :db/ident :foo/email]
+        [:db/add "s" :db/valueType :db.type/string]
+        [:db/add "s" :db/cardinality :db.cardinality/one]
+        [:db/add "t" :db/ident :foo/parts]
+        [:db/add "t" :db/valueType :db.type/ref]
+        [:db/add "t" :db/cardinality :db.cardinality/many]
+        [:db/add "t" :db/unique :db.unique/value]
+        [:db/add "t" :db/index true]
+        [:db/add "t" :db/isComponent true]
+        [:db/add "t" :db/doc "The parts of a thing."]
+    ]"#).expect("transacted schema");
+
+    let attributes = conn.attributes(&c).expect("introspected");
+    let email = attributes.iter().find(|a| a.ident == NamespacedKeyword::new("foo", "email")).expect("email");
+    let parts = attributes.iter().find(|a| a.ident == NamespacedKeyword::new("foo", "parts")).expect("parts");
+
+    // Facets that weren't transacted take their defaults.
+    assert_eq!(email, &AttributeInfo {
+        entid: *report.tempids.get("s").unwrap(),
+        ident: NamespacedKeyword::new("foo", "email"),
+        value_type: ValueType::String,
+        multival: false,
+        unique: None,
+        index: false,
+        fulltext: false,
+        component: false,
+        doc: None,
+    });
+    assert_eq!(parts, &AttributeInfo {
+        entid: *report.tempids.get("t").unwrap(),
+        ident: NamespacedKeyword::new("foo", "parts"),
+        value_type: ValueType::Ref,
+        multival: true,
+        unique: Some(Unique::Value),
+        index: true,
+        fulltext: false,
+        component: true,
+        doc: Some("The parts of a thing.".to_string()),
+    });
+
+    // The bootstrap attributes are described too.
+    let ident = attributes.iter().find(|a| a.ident == NamespacedKeyword::new("db", "ident")).expect(":db/ident");
+    assert_eq!(ident.value_type, ValueType::Keyword);
+    assert_eq!(ident.unique, Some(Unique::Identity));
+}
+
+#[test]
+fn test_tx_metadata() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    conn.transact(&mut c, r#"[
+        [:db/add "n" :db/ident :foo/name]
+        [:db/add "n" :db/valueType :db.type/string]
+        [:db/add "n" :db/cardinality :db.cardinality/one]
+        [:db/add "s" :db/ident :tx/source]
+        [:db/add "s" :db/valueType :db.type/string]
+        [:db/add "s" :db/cardinality :db.cardinality/one]
+    ]"#).expect("transacted schema");
+
+    let imported = conn.transact(&mut c, r#"[
+        [:db/add "a" :foo/name "alice"]
+        [:db/add :db/tx :tx/source "importer"]
+    ]"#).expect("transacted import");
+    conn.transact(&mut c, r#"[
+        [:db/add "b" :foo/name "bob"]
+        [:db/add :db/tx :tx/source "user"]
+    ]"#).expect("transacted edit");
+
+    // The transaction's metadata, by its id.
+    let inputs = QueryInputs::with_value_sequence(vec![(Variable::from_valid_name("?tx"), TypedValue::Ref(imported.tx_id))]);
+    let r = conn.q_once(&c, r#"[:find ?source . :in ?tx :where [?tx :tx/source ?source]]"#, inputs)
+                .expect("results")
+                .results;
+    assert_eq!(r, QueryResults::Scalar(Some(TypedValue::typed_string("importer").into())));
+
+    // The transactions that asserted a datom, by their metadata.
+    let r = conn.q_once(&c, r#"[:find ?name . :where [?e :foo/name ?name ?tx] [?tx :tx/source "importer"]]"#, None)
+                .expect("results")
+                .results;
+    assert_eq!(r, QueryResults::Scalar(Some(TypedValue::typed_string("alice").into())));
+}
+
+#[test]
+fn test_q_once_with_deadline() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+
+    // Joining the bootstrap idents thrice over takes SQLite a good deal of work, so a deadline
+    // that's already passed interrupts it.
+    let slow = r#"[:find ?a ?b ?c :where [?a :db/ident _] [?b :db/ident _] [?c :db/ident _]]"#;
+    match conn.q_once_with_deadline(&c, slow, None, Instant::now()) {
+        Err(Error(ErrorKind::QueryInterrupted, _)) => {},
+        r => panic!("Expected the query to be interrupted: {:?}", r),
+    }
+
+    // The connection is none the worse for it.
+    let fast = r#"[:find ?e . :where [?e :db/ident :db/ident]]"#;
+    let r = conn.q_once_with_deadline(&c, fast, None, Instant::now() + Duration::from_secs(60))
+                .expect("results")
+                .results;
+    assert_eq!(r, QueryResults::Scalar(Some(TypedValue::Ref(1).into())));
+    assert!(conn.q_once(&c, slow, None).is_ok());
+}
+
+#[test]
+fn test_find_spec_values() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    conn.transact(&mut c, r#"[
+        [:db/add "n" :db/ident :foo/name]
+        [:db/add "n" :db/valueType :db.type/string]
+        [:db/add "n" :db/cardinality :db.cardinality/one]
+        [:db/add "a" :db/ident :foo/age]
+        [:db/add "a" :db/valueType :db.type/long]
+        [:db/add "a" :db/cardinality :db.cardinality/one]
+    ]"#).expect("transacted schema");
+    conn.transact(&mut c, r#"[
+        [:db/add "x" :foo/name "Alice"]
+        [:db/add "x" :foo/age 32]
+        [:db/add "y" :foo/name "Bob"]
+        [:db/add "y" :foo/age 32]
+    ]"#).expect("transacted data");
+
+    let q = |query: &str| conn.q_once(&c, query, None).expect("results");
+
+    assert_eq!(q(r#"[:find ?a . :where [?e :foo/name "Carol"] [?e :foo/age ?a]]"#).into_scalar_value().expect("scalar"),
+               None);
+    assert_eq!(q(r#"[:find ?a . :where [_ :foo/age ?a]]"#).into_scalar_value().expect("scalar"),
+               Some(TypedValue::Long(32)));
+
+    // A collection has no duplicates.
+    assert_eq!(q(r#"[:find [?a ...] :where [_ :foo/age ?a]]"#).into_coll_values().expect("coll"),
+               vec![TypedValue::Long(32)]);
+    assert_eq!(q(r#"[:find [?a ...] :where [_ :foo/age ?a] [(> ?a 100)]]"#).into_coll_values().expect("coll"),
+               Vec::<TypedValue>::new());
+
+    assert_eq!(q(r#"[:find [?n ?a] :where [?e :foo/name "Bob"] [?e :foo/name ?n] [?e :foo/age ?a]]"#).into_tuple_values().expect("tuple"),
+               Some(vec![TypedValue::typed_string("Bob"), TypedValue::Long(32)]));
+    assert_eq!(q(r#"[:find [?n ?a] :where [?e :foo/name "Carol"] [?e :foo/name ?n] [?e :foo/age ?a]]"#).into_tuple_values().expect("tuple"),
+               None);
+
+    // The wrong shape, or a result that isn't a value, is an error.
+    assert!(q(r#"[:find [?a ...] :where [_ :foo/age ?a]]"#).into_scalar_value().is_err());
+    assert!(q(r#"[:find (pull ?e [:foo/name]) . :where [?e :foo/name "Bob"]]"#).into_scalar_value().is_err());
+}
+
+#[test]
+fn test_get_else() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    conn.transact(&mut c, r#"[
+        [:db/add "s" :db/ident :foo/name]
+        [:db/add "s" :db/valueType :db.type/string]
+        [:db/add "s" :db/cardinality :db.cardinality/one]
+        [:db/add "t" :db/ident :foo/nick]
+        [:db/add "t" :db/valueType :db.type/string]
+        [:db/add "t" :db/cardinality :db.cardinality/one]
+    ]"#).expect("transacted schema");
+    conn.transact(&mut c, r#"[
+        [:db/add "a" :foo/name "Alice"]
+        [:db/add "a" :foo/nick "Al"]
+        [:db/add "b" :foo/name "Bob"]
+    ]"#).expect("transacted data");
+
+    // Alice has a nickname, so we use it; Bob doesn't, so we get the default.
+    let results = conn.q_once(&c,
+                              r#"[:find ?name ?nick
+                                  :where [?p :foo/name ?name]
+                                         [(get-else $ ?p :foo/nick "N/A") ?nick]
+                                  :order ?name]"#, None)
+                      .expect("query succeeded")
+                      .results;
+    assert_eq!(results, QueryResults::Rel(vec![
+        vec![Binding::Scalar(TypedValue::typed_string("Alice")), Binding::Scalar(TypedValue::typed_string("Al"))],
+        vec![Binding::Scalar(TypedValue::typed_string("Bob")), Binding::Scalar(TypedValue::typed_string("N/A"))],
+    ]));
+
+    // The default must be of the attribute's type.
+    let r = conn.q_once(&c,
+                        r#"[:find ?name ?nick
+                            :where [?p :foo/name ?name]
+                                   [(get-else $ ?p :foo/nick 5) ?nick]]"#, None);
+    match r {
+        Err(Error(ErrorKind::QueryError(mentat_query_algebrizer::ErrorKind::InvalidGetElseDefault(attribute, value_type)), _)) => {
+            assert_eq!(attribute, ":foo/nick");
+            assert_eq!(value_type, ValueType::String);
+        },
+        _ => panic!("Expected query to fail."),
+    }
+}
+
+#[test]
+fn test_get_some() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    conn.transact(&mut c, r#"[
+        [:db/add "r" :db/ident :user/id]
+        [:db/add "r" :db/valueType :db.type/long]
+        [:db/add "r" :db/cardinality :db.cardinality/one]
+        [:db/add "s" :db/ident :user/display-name]
+        [:db/add "s" :db/valueType :db.type/string]
+        [:db/add "s" :db/cardinality :db.cardinality/one]
+        [:db/add "t" :db/ident :user/full-name]
+        [:db/add "t" :db/valueType :db.type/string]
+        [:db/add "t" :db/cardinality :db.cardinality/one]
+        [:db/add "u" :db/ident :user/email]
+        [:db/add "u" :db/valueType :db.type/string]
+        [:db/add "u" :db/cardinality :db.cardinality/one]
+    ]"#).expect("transacted schema");
+    conn.transact(&mut c, r#"[
+        [:db/add "a" :user/id 1]
+        [:db/add "a" :user/display-name "Ali"]
+        [:db/add "a" :user/full-name "Alice Smith"]
+        [:db/add "b" :user/id 2]
+        [:db/add "b" :user/full-name "Bob Jones"]
+        [:db/add "b" :user/email "bob@example.com"]
+        [:db/add "c" :user/id 3]
+    ]"#).expect("transacted data");
+
+    // The first user's display name comes first; the second has only the later attributes; the
+    // third has none of them, and so doesn't appear at all.
+    let results = conn.q_once(&c,
+                              r#"[:find ?id ?name
+                                  :where [?u :user/id ?id]
+                                         [(get-some $ ?u :user/display-name :user/full-name :user/email) ?name]
+                                  :order ?id]"#, None)
+                      .expect("query succeeded")
+                      .results;
+    assert_eq!(results, QueryResults::Rel(vec![
+        vec![Binding::Scalar(TypedValue::Long(1)), Binding::Scalar(TypedValue::typed_string("Ali"))],
+        vec![Binding::Scalar(TypedValue::Long(2)), Binding::Scalar(TypedValue::typed_string("Bob Jones"))],
+    ]));
+
+    // The attributes must all have the same type.
+    let r = conn.q_once(&c,
+                        r#"[:find ?name
+                            :where [?u :user/id _]
+                                   [(get-some $ ?u :user/display-name :user/id) ?name]]"#, None);
+    match r {
+        Err(Error(ErrorKind::QueryError(mentat_query_algebrizer::ErrorKind::IncompatibleFallbackAttributes(PlainSymbol(s), types)), _)) => {
+            assert_eq!(s, "get-some");
+            assert_eq!(types, ValueTypeSet::of_one(ValueType::String).union(&ValueTypeSet::of_one(ValueType::Long)));
+        },
+        _ => panic!("Expected query to fail."),
+    }
+}
+
+#[test]
+fn test_query_cache() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    conn.transact(&mut c, r#"[
+        [:db/add "s" :db/ident :foo/name]
+        [:db/add "s" :db/valueType :db.type/string]
+        [:db/add "s" :db/cardinality :db.cardinality/one]
+        [:db/add "t" :db/ident :foo/age]
+        [:db/add "t" :db/valueType :db.type/long]
+        [:db/add "t" :db/cardinality :db.cardinality/one]
+    ]"#).expect("transacted schema");
+    conn.transact(&mut c, r#"[
+        [:db/add "a" :foo/name "Alice"]
+        [:db/add "a" :foo/age 30]
+        [:db/add "b" :foo/name "Bob"]
+        [:db/add "b" :foo/age 40]
+    ]"#).expect("transacted data");
+
+    let mut cache = QueryCache::default();
+    let query = r#"[:find ?age . :in ?name :where [?p :foo/name ?name] [?p :foo/age ?age]]"#;
+    let name = |name: &str| QueryInputs::with_value_sequence(vec![(Variable::from_valid_name("?name"), TypedValue::typed_string(name))]);
+    let age = |age: i64| QueryResults::Scalar(Some(Binding::Scalar(TypedValue::Long(age))));
+
+    // The second run, with a different input, reuses the plan from the first.
+    assert_eq!(conn.q_cached(&c, &mut cache, query, name("Alice")).expect("query succeeded").results, age(30));
+    assert_eq!(conn.q_cached(&c, &mut cache, query, name("Bob")).expect("query succeeded").results, age(40));
+    assert_eq!(conn.q_cached(&c, &mut cache, query, name("Carol")).expect("query succeeded").results, QueryResults::Scalar(None));
+    assert_eq!((cache.misses(), cache.hits(), cache.len()), (1, 2, 1));
+
+    // Transacting data doesn't change the schema, so the plan survives.
+    conn.transact(&mut c, r#"[[:db/add "c" :foo/name "Carol"] [:db/add "c" :foo/age 50]]"#).expect("transacted data");
+    assert_eq!(conn.q_cached(&c, &mut cache, query, name("Carol")).expect("query succeeded").results, age(50));
+    assert_eq!((cache.misses(), cache.hits(), cache.len()), (1, 3, 1));
+
+    // Changing the schema evicts it.
+    conn.transact(&mut c, r#"[
+        [:db/add "u" :db/ident :foo/email]
+        [:db/add "u" :db/valueType :db.type/string]
+        [:db/add "u" :db/cardinality :db.cardinality/one]
+    ]"#).expect("transacted schema");
+    assert_eq!(conn.q_cached(&c, &mut cache, query, name("Alice")).expect("query succeeded").results, age(30));
+    assert_eq!((cache.misses(), cache.hits(), cache.len()), (2, 3, 1));
+}
+
+#[test]
+fn test_prepared_query() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    conn.transact(&mut c, r#"[
+        [:db/add "s" :db/ident :foo/name]
+        [:db/add "s" :db/valueType :db.type/string]
+        [:db/add "s" :db/cardinality :db.cardinality/one]
+        [:db/add "t" :db/ident :foo/age]
+        [:db/add "t" :db/valueType :db.type/long]
+        [:db/add "t" :db/cardinality :db.cardinality/one]
+    ]"#).expect("transacted schema");
+    conn.transact(&mut c, r#"[
+        [:db/add "a" :foo/name "Alice"]
+        [:db/add "a" :foo/age 30]
+        [:db/add "b" :foo/name "Bob"]
+        [:db/add "b" :foo/age 40]
+    ]"#).expect("transacted data");
+
+    let prepared = conn.prepare(r#"[:find ?age . :in ?name :where [?p :foo/name ?name] [?p :foo/age ?age]]"#)
+                       .expect("prepared");
+    let name = |name: &str| QueryInputs::with_value_sequence(vec![(Variable::from_valid_name("?name"), TypedValue::typed_string(name))]);
+    let age = |age: i64| QueryResults::Scalar(Some(Binding::Scalar(TypedValue::Long(age))));
+
+    assert_eq!(prepared.run(&conn, &c, name("Alice")).expect("query succeeded").results, age(30));
+    assert_eq!(prepared.run(&conn, &c, name("Bob")).expect("query succeeded").results, age(40));
+
+    // Inputs must be bound, and with the type the query expects.
+    match prepared.run(&conn, &c, None) {
+        Err(Error(ErrorKind::UnboundVariables(vars), _)) => {
+            assert_eq!(vars, vec!["?name".to_string()].into_iter().collect());
+        },
+        x => panic!("expected unbound variables, got {:?}", x),
+    }
+    let long = QueryInputs::with_value_sequence(vec![(Variable::from_valid_name("?name"), TypedValue::Long(30))]);
+    match prepared.run(&conn, &c, long) {
+        Err(Error(ErrorKind::QueryError(mentat_query_algebrizer::ErrorKind::InputTypeDisagreement(..)), _)) => {},
+        x => panic!("expected an input type disagreement, got {:?}", x),
+    }
+
+    // Transacting data doesn't invalidate the query.
+    conn.transact(&mut c, r#"[[:db/add "c" :foo/name "Carol"] [:db/add "c" :foo/age 50]]"#).expect("transacted data");
+    assert_eq!(prepared.run(&conn, &c, name("Carol")).expect("query succeeded").results, age(50));
+
+    // Changing the schema does.
+    conn.transact(&mut c, r#"[
+        [:db/add "u" :db/ident :foo/email]
+        [:db/add "u" :db/valueType :db.type/string]
+        [:db/add "u" :db/cardinality :db.cardinality/one]
+    ]"#).expect("transacted schema");
+    match prepared.run(&conn, &c, name("Alice")) {
+        Err(Error(ErrorKind::StalePreparedQuery, _)) => {},
+        x => panic!("expected a stale query, got {:?}", x),
+    }
+    let prepared = conn.prepare(r#"[:find ?age . :in ?name :where [?p :foo/name ?name] [?p :foo/age ?age]]"#)
+                       .expect("prepared");
+    assert_eq!(prepared.run(&conn, &c, name("Alice")).expect("query succeeded").results, age(30));
+
+    // Inputs must have a known type.
+    match conn.prepare(r#"[:find ?p :in ?v :where [?p _ ?v]]"#) {
+        Err(Error(ErrorKind::UnknownInputType(name), _)) => assert_eq!(name, "?v"),
+        x => panic!("expected an unknown input type, got {:?}", x.map(|_| ())),
+    }
+}
+
+#[test]
+fn test_prepared_query_columns() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    conn.transact(&mut c, r#"[
+        [:db/add "s" :db/ident :foo/name]
+        [:db/add "s" :db/valueType :db.type/string]
+        [:db/add "s" :db/cardinality :db.cardinality/one]
+        [:db/add "t" :db/ident :foo/age]
+        [:db/add "t" :db/valueType :db.type/long]
+        [:db/add "t" :db/cardinality :db.cardinality/one]
+    ]"#).expect("transacted schema");
+
+    let prepared = conn.prepare(r#"[:find ?p ?name :where [?p :foo/name ?name]]"#).expect("prepared");
+    assert_eq!(prepared.columns(),
+               vec![(Variable::from_valid_name("?p"), ValueTypeSet::of_one(ValueType::Ref)),
+                    (Variable::from_valid_name("?name"), ValueTypeSet::of_one(ValueType::String))]);
+
+    // ?v might be a name or an age.
+    let prepared = conn.prepare(r#"[:find ?v :where (or [_ :foo/name ?v] [_ :foo/age ?v])]"#).expect("prepared");
+    let columns = prepared.columns();
+    assert_eq!(columns.len(), 1);
+    assert_eq!(columns[0].0, Variable::from_valid_name("?v"));
+    assert!(columns[0].1.contains(ValueType::String));
+    assert!(columns[0].1.contains(ValueType::Long));
+    assert!(!columns[0].1.is_unit());
+}
+
+#[test]
+fn test_prepared_query_rows() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    conn.transact(&mut c, r#"[
+        [:db/add "s" :db/ident :foo/name]
+        [:db/add "s" :db/valueType :db.type/string]
+        [:db/add "s" :db/cardinality :db.cardinality/one]
+        [:db/add "t" :db/ident :foo/age]
+        [:db/add "t" :db/valueType :db.type/long]
+        [:db/add "t" :db/cardinality :db.cardinality/one]
+    ]"#).expect("transacted schema");
+    let report = conn.transact(&mut c, r#"[
+        [:db/add "a" :foo/name "Alice"]
+        [:db/add "a" :foo/age 30]
+    ]"#).expect("transacted data");
+    let alice = *report.tempids.get("a").expect("allocated");
+
+    let prepared = conn.prepare(r#"[:find ?p ?name ?age :where [?p :foo/name ?name] [?p :foo/age ?age]]"#)
+                       .expect("prepared");
+    let rows = prepared.rows(&conn, &c, None).expect("ran");
+    assert_eq!(rows.len(), 1);
+    let row = &rows[0];
+    assert_eq!(row.get_ref("?p").expect("ref"), alice);
+    assert_eq!(*row.get_string("?name").expect("string"), "Alice".to_string());
+    assert_eq!(row.get_long("?age").expect("long"), 30);
+    assert_eq!(row.get("?age").expect("binding"), &Binding::Scalar(TypedValue::Long(30)));
+
+    // ?age can only be a long.
+    match row.get_string("?age") {
+        Err(Error(ErrorKind::RowValueTypeMismatch(name, requested, types), _)) => {
+            assert_eq!(name, "?age");
+            assert_eq!(requested, ValueType::String);
+            assert_eq!(types, ValueTypeSet::of_one(ValueType::Long));
+        },
+        r => panic!("Unexpected result {:?}", r),
+    }
+
+    // There's no ?x.
+    match row.get_long("?x") {
+        Err(Error(ErrorKind::UnknownRowVariable(name), _)) => assert_eq!(name, "?x"),
+        r => panic!("Unexpected result {:?}", r),
+    }
+
+    // ?v might be a name or an age, so which we can ask for depends on each row's value.
+    let prepared = conn.prepare(r#"[:find [?v ...] :where (or [_ :foo/name ?v] [_ :foo/age ?v])]"#).expect("prepared");
+    let rows = prepared.rows(&conn, &c, None).expect("ran");
+    assert_eq!(rows.len(), 2);
+    for row in rows {
+        match row.get("?v").expect("binding") {
+            &Binding::Scalar(TypedValue::Long(_)) => {
+                assert_eq!(row.get_long("?v").expect("long"), 30);
+                assert!(row.get_string("?v").is_err());
+            },
+            _ => {
+                assert_eq!(*row.get_string("?v").expect("string"), "Alice".to_string());
+                assert!(row.get_long("?v").is_err());
+            },
+        }
+    }
+}
+
+#[test]
+fn test_cache_attribute() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    conn.transact(&mut c, r#"[
+        [:db/add "s" :db/ident :foo/name]
+        [:db/add "s" :db/valueType :db.type/string]
+        [:db/add "s" :db/cardinality :db.cardinality/one]
+        [:db/add "t" :db/ident :foo/friend]
+        [:db/add "t" :db/valueType :db.type/ref]
+        [:db/add "t" :db/cardinality :db.cardinality/many]
+    ]"#).expect("transacted schema");
+    let report = conn.transact(&mut c, r#"[
+        [:db/add "a" :foo/name "Alice"]
+        [:db/add "b" :foo/name "Bob"]
+        [:db/add "a" :foo/friend "b"]
+        [:db/add "c" :foo/friend "b"]
+    ]"#).expect("transacted data");
+    let alice = *report.tempids.get("a").unwrap();
+    let bob = *report.tempids.get("b").unwrap();
+    let carol = *report.tempids.get("c").unwrap();
+
+    conn.cache_attribute(&c, &NamespacedKeyword::new("foo", "name"), CacheDirection::Both).expect("cached");
+    conn.cache_attribute(&c, &NamespacedKeyword::new("foo", "friend"), CacheDirection::Reverse).expect("cached");
+
+    let queries = vec![
+        format!("[:find ?name . :where [{} :foo/name ?name]]", alice),
+        format!("[:find ?name . :where [{} :foo/name ?name]]", carol),
+        r#"[:find ?p . :where [?p :foo/name "Bob"]]"#.to_string(),
+        r#"[:find ?p . :where [?p :foo/name "Dan"]]"#.to_string(),
+        format!("[:find ?p :where [?p :foo/friend {}]]", bob),
+        r#"[:find ?p ?name :where [?p :foo/name ?name] :order ?name]"#.to_string(),
+    ];
+
+    // Cached reads agree with the store.
+    let schema = conn.current_schema();
+    for query in queries.iter() {
+        let cached = conn.q_once(&c, query.as_str(), None).expect("cached query succeeded").results;
+        let uncached = q_once(&c, &*schema, query.as_str(), None).expect("uncached query succeeded").results;
+        assert_eq!(cached, uncached);
+    }
+
+    // Carol has no name, and the cache knows it without asking the store.
+    let output = conn.q_once(&c, queries[1].as_str(), None).expect("query succeeded");
+    assert_eq!(output.results, QueryResults::Scalar(None));
+    assert!(output.proven_empty.is_some());
+
+    // Transacting refreshes the cache.
+    conn.transact(&mut c, format!(r#"[[:db/add {} :foo/name "Carol"] [:db/add {} :foo/name "Alicia"]]"#, carol, alice).as_str())
+        .expect("transacted data");
+    let name_of = |e: i64| {
+        conn.q_once(&c, format!("[:find ?name . :where [{} :foo/name ?name]]", e).as_str(), None)
+            .expect("query succeeded")
+            .results
+    };
+    assert_eq!(name_of(alice), QueryResults::Scalar(Some(Binding::Scalar(TypedValue::typed_string("Alicia")))));
+    assert_eq!(name_of(carol), QueryResults::Scalar(Some(Binding::Scalar(TypedValue::typed_string("Carol")))));
+
+    let results = conn.q_once(&c, r#"[:find ?p . :where [?p :foo/name "Alice"]]"#, None)
+                      .expect("query succeeded")
+                      .results;
+    assert_eq!(results, QueryResults::Scalar(None));
+}
+
+#[test]
+fn test_query_iter() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    conn.transact(&mut c, r#"[
+        [:db/add "s" :db/ident :foo/name]
+        [:db/add "s" :db/valueType :db.type/string]
+        [:db/add "s" :db/cardinality :db.cardinality/one]
+        [:db/add "t" :db/ident :foo/age]
+        [:db/add "t" :db/valueType :db.type/long]
+        [:db/add "t" :db/cardinality :db.cardinality/one]
+    ]"#).expect("transacted schema");
+    conn.transact(&mut c, r#"[
+        [:db/add "a" :foo/name "Alice"]
+        [:db/add "a" :foo/age 30]
+        [:db/add "b" :foo/name "Bob"]
+        [:db/add "b" :foo/age 40]
+        [:db/add "c" :foo/name "Carol"]
+    ]"#).expect("transacted data");
+
+    // Streaming yields the same rows, in the same order, as collecting.
+    let queries = vec![
+        r#"[:find ?p ?name :where [?p :foo/name ?name] :order ?name]"#,
+        r#"[:find ?name ?age :where [?p :foo/name ?name] [?p :foo/age ?age] :order (desc ?age)]"#,
+        r#"[:find [?name ...] :where [_ :foo/name ?name] :order ?name]"#,
+        r#"[:find ?name . :where [?p :foo/age 40] [?p :foo/name ?name]]"#,
+        r#"[:find ?p ?a ?v :where [?p :foo/name _] [?p ?a ?v] :order ?p ?a]"#,
+        r#"[:find ?p :where [?p :foo/name 5]]"#,
+    ];
+    for query in queries {
+        let collected: Vec<Vec<TypedValue>> =
+            match conn.q_once(&c, query, None).expect("query succeeded").results {
+                QueryResults::Scalar(v) => v.into_iter().map(|b| vec![b.into_scalar().unwrap()]).collect(),
+                QueryResults::Coll(vs) => vs.into_iter().map(|b| vec![b.into_scalar().unwrap()]).collect(),
+                QueryResults::Tuple(t) => t.into_iter().map(|row| row.into_iter().map(|b| b.into_scalar().unwrap()).collect()).collect(),
+                QueryResults::Rel(rows) => rows.into_iter().map(|row| row.into_iter().map(|b| b.into_scalar().unwrap()).collect()).collect(),
+            };
+
+        let mut stream = conn.query_iter(&c, query, None).expect("prepared query");
+        let streamed = stream.rows()
+                             .expect("ran query")
+                             .collect::<mentat::errors::Result<Vec<Vec<TypedValue>>>>()
+                             .expect("projected rows");
+        assert_eq!(streamed, collected);
+    }
+
+    // Aggregates need every row, so they can't be streamed.
+    match query_iter(&c, &*conn.current_schema(), r#"[:find (max ?age) . :where [_ :foo/age ?age]]"#, None) {
+        Err(Error(ErrorKind::CannotStreamQuery, _)) => {},
+        Err(e) => panic!("unexpected error {:?}", e),
+        Ok(_) => panic!("expected an error"),
+    }
+}
+
+#[test]
+fn test_query_iter_stops_early() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    conn.transact(&mut c, r#"[
+        [:db/add "s" :db/ident :foo/uuid]
+        [:db/add "s" :db/valueType :db.type/uuid]
+        [:db/add "s" :db/cardinality :db.cardinality/one]
+    ]"#).expect("transacted schema");
+    let report = conn.transact(&mut c, r#"[
+        [:db/add "u" :foo/uuid #uuid "cf62d552-6569-4d1b-b667-04703041dfc4"]
+        [:db/add "v" :foo/uuid #uuid "550e8400-e29b-41d4-a716-446655440000"]
+    ]"#).expect("transacted data");
+
+    // Write a datom that can't be read back, after all of the others.
+    let a = conn.current_schema().get_entid(&NamespacedKeyword::new("foo", "uuid")).expect("attribute");
+    let e = report.tempids.values().max().expect("an entity") + 1000;
+    c.execute(format!("INSERT INTO datoms (e, a, v, tx, value_type_tag) VALUES ({}, {}, X'00', {}, 11)",
+                      e, a, report.tx_id).as_str(), &[])
+     .expect("inserted datom");
+
+    let query = r#"[:find ?e ?u :where [?e :foo/uuid ?u] :order ?e]"#;
+    assert!(conn.q_once(&c, query, None).is_err());
+
+    // Taking only the first rows never reads the bad one.
+    let mut stream = conn.query_iter(&c, query, None).expect("prepared query");
+    let first: Vec<Vec<TypedValue>> = stream.rows()
+                                            .expect("ran query")
+                                            .take(2)
+                                            .collect::<mentat::errors::Result<_>>()
+                                            .expect("projected rows");
+    assert_eq!(first.len(), 2);
+
+    // Reading on finds it.
+    let mut rows = stream.rows().expect("ran query");
+    assert!(rows.nth(2).expect("a third row").is_err());
+}
+
+#[test]
+fn test_tx_ids() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    conn.transact(&mut c, r#"[
+        [:db/add "s" :db/ident :foo/name]
+        [:db/add "s" :db/valueType :db.type/string]
+        [:db/add "s" :db/cardinality :db.cardinality/one]
+    ]"#).expect("transacted schema");
+    let first = conn.transact(&mut c, r#"[[:db/add "a" :foo/name "Alice"]]"#).expect("transacted data");
+    let second = conn.transact(&mut c, r#"[[:db/add "b" :foo/name "Bob"]]"#).expect("transacted data");
+    let third = conn.transact(&mut c, r#"[[:db/add "c" :foo/name "Carol"]]"#).expect("transacted data");
+
+    let tx_ids = |start: TypedValue, end: TypedValue| -> Vec<Binding> {
+        let query = "[:find [?tx ...] :in ?start ?end :where [(tx-ids $ ?tx ?start ?end)] :order ?tx]";
+        let inputs = QueryInputs::with_value_sequence(vec![(Variable::from_valid_name("?start"), start),
+                                                           (Variable::from_valid_name("?end"), end)]);
+        conn.q_once(&c, query, inputs)
+            .expect("query succeeded")
+            .into_coll()
+            .expect("coll")
+    };
+    let refs = |txs: Vec<i64>| -> Vec<Binding> {
+        txs.into_iter().map(|tx| Binding::Scalar(TypedValue::Ref(tx))).collect()
+    };
+
+    // Both bounds are inclusive, whether they're entids or instants.
+    assert_eq!(tx_ids(TypedValue::Ref(first.tx_id), TypedValue::Ref(second.tx_id)),
+               refs(vec![first.tx_id, second.tx_id]));
+    assert_eq!(tx_ids(TypedValue::Instant(second.tx_instant), TypedValue::Instant(third.tx_instant)),
+               refs(vec![second.tx_id, third.tx_id]));
+    assert_eq!(tx_ids(TypedValue::Ref(first.tx_id), TypedValue::Instant(third.tx_instant)),
+               refs(vec![first.tx_id, second.tx_id, third.tx_id]));
+
+    // Nothing was transacted after the last transaction.
+    assert_eq!(tx_ids(TypedValue::Ref(third.tx_id + 1), TypedValue::Ref(third.tx_id + 100)), vec![]);
+
+    // A reversed range is empty.
+    assert_eq!(tx_ids(TypedValue::Ref(third.tx_id), TypedValue::Ref(first.tx_id)), vec![]);
+    assert_eq!(tx_ids(TypedValue::Instant(third.tx_instant), TypedValue::Instant(first.tx_instant)), vec![]);
+
+    // The bound variable joins like any other.
+    let results = conn.q_once(&c, format!(r#"[:find ?name . :where [(tx-ids $ ?tx {} {})] [?p :foo/name ?name ?tx]]"#,
+                                          second.tx_id, second.tx_id).as_str(), None)
+                      .expect("query succeeded")
+                      .results;
+    assert_eq!(results, QueryResults::Scalar(Some(Binding::Scalar(TypedValue::typed_string("Bob")))));
+}
+
+#[test]
+fn test_tx_data() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    conn.transact(&mut c, r#"[
+        [:db/add "s" :db/ident :foo/name]
+        [:db/add "s" :db/valueType :db.type/string]
+        [:db/add "s" :db/cardinality :db.cardinality/one]
+        [:db/add "t" :db/ident :foo/age]
+        [:db/add "t" :db/valueType :db.type/long]
+        [:db/add "t" :db/cardinality :db.cardinality/one]
+        [:db/add "u" :db/ident :foo/bio]
+        [:db/add "u" :db/valueType :db.type/string]
+        [:db/add "u" :db/cardinality :db.cardinality/one]
+        [:db/add "u" :db/fulltext true]
+    ]"#).expect("transacted schema");
+    let first = conn.transact(&mut c, r#"[
+        [:db/add "a" :foo/name "Alice"]
+        [:db/add "a" :foo/age 30]
+    ]"#).expect("transacted data");
+    let alice = first.tempids["a"];
+    let second = conn.transact(&mut c, format!(r#"[
+        [:db/retract {} :foo/age 30]
+        [:db/add {} :foo/age 31]
+        [:db/add {} :foo/bio "Likes cryptography"]
+    ]"#, alice, alice, alice).as_str()).expect("transacted data");
+
+    let schema = conn.current_schema();
+    let attr = |name: &str| TypedValue::Ref(schema.get_entid(&NamespacedKeyword::new("foo", name)).expect("attribute"));
+    let tx_instant = TypedValue::Ref(schema.get_entid(&NamespacedKeyword::new("db", "txInstant")).expect("attribute"));
+
+    let query = format!("[:find ?e ?a ?v ?added :where [(tx-data $ {} ?e ?a ?v ?added)] :order ?a ?added]", second.tx_id);
+    let results = conn.q_once(&c, query.as_str(), None).expect("query succeeded").into_rel().expect("rel");
+    let results: Vec<Vec<TypedValue>> =
+        results.into_iter()
+               .map(|row| row.into_iter().map(|b| b.into_scalar().expect("scalar")).collect())
+               .collect();
+
+    // The transaction's own instant is part of its data.
+    assert_eq!(results, vec![
+        vec![TypedValue::Ref(second.tx_id), tx_instant, TypedValue::Instant(second.tx_instant), TypedValue::Boolean(true)],
+        vec![TypedValue::Ref(alice), attr("age"), TypedValue::Long(30), TypedValue::Boolean(false)],
+        vec![TypedValue::Ref(alice), attr("age"), TypedValue::Long(31), TypedValue::Boolean(true)],
+        vec![TypedValue::Ref(alice), attr("bio"), TypedValue::typed_string("Likes cryptography"), TypedValue::Boolean(true)],
+    ]);
+
+    // The transaction can come from elsewhere in the query.
+    let query = r#"[:find ?v . :where [?e :foo/name "Alice" ?tx] [(tx-data $ ?tx ?e ?a ?v ?added)] [?a :db/ident :foo/name]]"#;
+    let results = conn.q_once(&c, query, None).expect("query succeeded").results;
+    assert_eq!(results, QueryResults::Scalar(Some(Binding::Scalar(TypedValue::typed_string("Alice")))));
+
+    // The value can't be used anywhere else.
+    let query = format!("[:find ?e :where [(tx-data $ {} ?e ?a ?v ?added)] [?e :foo/age ?v]]", second.tx_id);
+    match conn.q_once(&c, query.as_str(), None) {
+        Err(Error(ErrorKind::QueryError(mentat_query_algebrizer::ErrorKind::FallbackCanOnlyBeProjected(_)), _)) => {},
+        x => panic!("expected an error, got {:?}", x),
+    }
+}
+
+#[test]
+fn test_like() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    conn.transact(&mut c, r#"[
+        [:db/add "s" :db/ident :foo/name]
+        [:db/add "s" :db/valueType :db.type/string]
+        [:db/add "s" :db/cardinality :db.cardinality/one]
+    ]"#).expect("transacted schema");
+    conn.transact(&mut c, r#"[
+        [:db/add "a" :foo/name "Abigail"]
+        [:db/add "b" :foo/name "abe"]
+        [:db/add "c" :foo/name "Bob"]
+        [:db/add "d" :foo/name "100% Carol"]
+        [:db/add "e" :foo/name "1000 Dan"]
+    ]"#).expect("transacted data");
+
+    let names = |query: &str| -> Vec<String> {
+        conn.q_once(&c, query, None)
+            .expect("query succeeded")
+            .into_coll()
+            .expect("coll")
+            .into_iter()
+            .map(|b| match b.into_scalar() {
+                Some(TypedValue::String(s)) => (*s).clone(),
+                x => panic!("expected a string, got {:?}", x),
+            })
+            .collect()
+    };
+
+    // Prefix matches respect case unless asked not to.
+    assert_eq!(names(r#"[:find [?n ...] :where [_ :foo/name ?n] [(like ?n "Ab%")] :order ?n]"#),
+               vec!["Abigail".to_string()]);
+    assert_eq!(names(r#"[:find [?n ...] :where [_ :foo/name ?n] [(like-ci ?n "Ab%")] :order ?n]"#),
+               vec!["Abigail".to_string(), "abe".to_string()]);
+    assert_eq!(names(r#"[:find [?n ...] :where [_ :foo/name ?n] [(like ?n "B_b")] :order ?n]"#),
+               vec!["Bob".to_string()]);
+
+    // No match.
+    assert!(names(r#"[:find [?n ...] :where [_ :foo/name ?n] [(like ?n "Zed%")]]"#).is_empty());
+
+    // Unescaped, `%` matches anything; escaped, only itself.
+    assert_eq!(names(r#"[:find [?n ...] :where [_ :foo/name ?n] [(like ?n "100%")] :order ?n]"#),
+               vec!["100% Carol".to_string(), "1000 Dan".to_string()]);
+    assert_eq!(names(r#"[:find [?n ...] :where [_ :foo/name ?n] [(like ?n "100!%%" "!")] :order ?n]"#),
+               vec!["100% Carol".to_string()]);
+    assert_eq!(names(r#"[:find [?n ...] :where [_ :foo/name ?n] [(like-ci ?n "100!%%" "!")] :order ?n]"#),
+               vec!["100% Carol".to_string()]);
+}
+
+#[test]
+fn test_regex() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    conn.transact(&mut c, r#"[
+        [:db/add "s" :db/ident :foo/phone]
+        [:db/add "s" :db/valueType :db.type/string]
+        [:db/add "s" :db/cardinality :db.cardinality/one]
+    ]"#).expect("transacted schema");
+    conn.transact(&mut c, r#"[
+        [:db/add "a" :foo/phone "555-1234"]
+        [:db/add "b" :foo/phone "call 555-9876 after six"]
+        [:db/add "c" :foo/phone "unlisted"]
+    ]"#).expect("transacted data");
+
+    let phones = |query: &str| conn.q_once(&c, query, None).expect("query succeeded").into_coll().expect("coll");
+    let phone = |s: &str| Binding::Scalar(TypedValue::typed_string(s));
+
+    assert_eq!(phones(r#"[:find [?p ...] :where [_ :foo/phone ?p] [(re-matches ?p "\\d{3}-\\d{4}")]]"#),
+               vec![phone("555-1234")]);
+    assert_eq!(phones(r#"[:find [?p ...] :where [_ :foo/phone ?p] [(re-find ?p "\\d{3}-\\d{4}")] :order ?p]"#),
+               vec![phone("555-1234"), phone("call 555-9876 after six")]);
+    assert!(phones(r#"[:find [?p ...] :where [_ :foo/phone ?p] [(re-find ?p "^\\d{5}$")]]"#).is_empty());
+
+    // Bad patterns are caught before the query runs.
+    match conn.q_once(&c, r#"[:find ?p :where [_ :foo/phone ?p] [(re-find ?p "[0-9")]]"#, None) {
+        Err(Error(ErrorKind::QueryError(mentat_query_algebrizer::ErrorKind::InvalidRegex(..)), _)) => {},
+        x => panic!("expected an invalid regex, got {:?}", x),
+    }
+}
+
+#[test]
+fn test_numeric_range() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    conn.transact(&mut c, r#"[
+        [:db/add "s" :db/ident :foo/age]
+        [:db/add "s" :db/valueType :db.type/long]
+        [:db/add "s" :db/cardinality :db.cardinality/one]
+        [:db/add "s" :db/index true]
+    ]"#).expect("transacted schema");
+    conn.transact(&mut c, r#"[
+        [:db/add "a" :foo/age 17]
+        [:db/add "b" :foo/age 18]
+        [:db/add "c" :foo/age 40]
+        [:db/add "d" :foo/age 65]
+        [:db/add "e" :foo/age 66]
+    ]"#).expect("transacted data");
+
+    let ages = |query: &str| -> Vec<i64> {
+        conn.q_once(&c, query, None)
+            .expect("query succeeded")
+            .into_coll()
+            .expect("coll")
+            .into_iter()
+            .map(|b| match b.into_scalar() {
+                Some(TypedValue::Long(age)) => age,
+                x => panic!("expected a long, got {:?}", x),
+            })
+            .collect()
+    };
+
+    assert_eq!(ages("[:find [?age ...] :where [_ :foo/age ?age] [(> ?age 18)] [(< ?age 65)] :order ?age]"),
+               vec![40]);
+    assert_eq!(ages("[:find [?age ...] :where [_ :foo/age ?age] [(>= ?age 18)] [(<= ?age 65)] :order ?age]"),
+               vec![18, 40, 65]);
+    assert_eq!(ages("[:find [?age ...] :where [_ :foo/age ?age] [(>= ?age 18)] [(< ?age 65)] :order ?age]"),
+               vec![18, 40]);
+    assert!(ages("[:find [?age ...] :where [_ :foo/age ?age] [(> ?age 65)] [(< ?age 18)]]").is_empty());
+}
+
+#[test]
+fn test_recursive_rules() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    conn.transact(&mut c, r#"[
+        [:db/add "n" :db/ident :foo/name]
+        [:db/add "n" :db/valueType :db.type/string]
+        [:db/add "n" :db/cardinality :db.cardinality/one]
+        [:db/add "p" :db/ident :foo/parent]
+        [:db/add "p" :db/valueType :db.type/ref]
+        [:db/add "p" :db/cardinality :db.cardinality/one]
+        [:db/add "x" :db/ident :foo/next]
+        [:db/add "x" :db/valueType :db.type/ref]
+        [:db/add "x" :db/cardinality :db.cardinality/one]
+    ]"#).expect("transacted schema");
+
+    // a -> b -> d -> e, and a -> c.
+    conn.transact(&mut c, r#"[
+        [:db/add "a" :foo/name "a"]
+        [:db/add "b" :foo/name "b"]
+        [:db/add "c" :foo/name "c"]
+        [:db/add "d" :foo/name "d"]
+        [:db/add "e" :foo/name "e"]
+        [:db/add "b" :foo/parent "a"]
+        [:db/add "c" :foo/parent "a"]
+        [:db/add "d" :foo/parent "b"]
+        [:db/add "e" :foo/parent "d"]
+    ]"#).expect("transacted tree");
+
+    // x -> y -> z -> x.
+    conn.transact(&mut c, r#"[
+        [:db/add "x" :foo/name "x"]
+        [:db/add "y" :foo/name "y"]
+        [:db/add "z" :foo/name "z"]
+        [:db/add "x" :foo/next "y"]
+        [:db/add "y" :foo/next "z"]
+        [:db/add "z" :foo/next "x"]
+    ]"#).expect("transacted cycle");
+
+    let rules = parse_rules_string(r#"[[(ancestor ?a ?b) [?a :foo/parent ?b]]
+                                       [(ancestor ?a ?b) [?a :foo/parent ?p] (ancestor ?p ?b)]
+                                       [(reaches ?a ?b) [?a :foo/next ?b]]
+                                       [(reaches ?a ?b) [?a :foo/next ?c] (reaches ?c ?b)]]"#).expect("rules");
+    let rules = RuleSet::new(rules);
+    let schema = conn.current_schema();
+    let names = |rules: &RuleSet, query: &str| -> Vec<String> {
+        q_once_with_rules(&c, &schema, rules, query, None)
+            .expect("query succeeded")
+            .into_coll()
+            .expect("coll")
+            .into_iter()
+            .map(|b| match b.into_scalar() {
+                Some(TypedValue::String(name)) => name.to_string(),
+                x => panic!("expected a string, got {:?}", x),
+            })
+            .collect()
+    };
+
+    let ancestors = r#"[:find [?n ...] :where [?e :foo/name "e"] (ancestor ?e ?a) [?a :foo/name ?n] :order ?n]"#;
+    assert_eq!(names(&rules, ancestors), vec!["a", "b", "d"]);
+    assert_eq!(names(&rules, r#"[:find [?n ...] :where [?a :foo/name "a"] (ancestor ?d ?a) [?d :foo/name ?n] :order ?n]"#),
+               vec!["b", "c", "d", "e"]);
+
+    // e is three steps from a, so a depth of three is enough...
+    assert_eq!(names(&rules.clone().with_max_depth(3), ancestors), vec!["a", "b", "d"]);
+
+    // ... but two isn't.
+    let shallow = rules.clone().with_max_depth(2);
+    assert!(q_once_with_rules(&c, &schema, &shallow, ancestors, None).is_err());
+
+    // Around a cycle, every node reaches every other node, forever, until we give up.
+    let reachable = r#"[:find [?n ...] :where [?x :foo/name "x"] (reaches ?x ?y) [?y :foo/name ?n]]"#;
+    match q_once_with_rules(&c, &schema, &rules.with_max_depth(10), reachable, None) {
+        Err(e) => assert!(e.to_string().contains("rule reaches recursed more than 10 times"), "unexpected error: {}", e),
+        r => panic!("expected the query to fail: {:?}", r),
+    }
+}
+
+#[test]
+fn test_equality_predicate() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    conn.transact(&mut c, r#"[
+        [:db/add "s" :db/ident :foo/name]
+        [:db/add "s" :db/valueType :db.type/string]
+        [:db/add "s" :db/cardinality :db.cardinality/one]
+        [:db/add "t" :db/ident :foo/nick]
+        [:db/add "t" :db/valueType :db.type/string]
+        [:db/add "t" :db/cardinality :db.cardinality/one]
+    ]"#).expect("transacted schema");
+    conn.transact(&mut c, r#"[
+        [:db/add "a" :foo/name "Alice"]
+        [:db/add "a" :foo/nick "Alice"]
+        [:db/add "b" :foo/name "Robert"]
+        [:db/add "b" :foo/nick "Bob"]
+        [:db/add "c" :foo/name "Bob"]
+    ]"#).expect("transacted data");
+
+    let names = |query: &str| -> Vec<Binding> {
+        match conn.q_once(&c, query, None).expect("query succeeded").results {
+            QueryResults::Coll(vals) => vals,
+            r => panic!("Unexpected results {:?}", r),
+        }
+    };
+
+    // Only Alice's nickname is her name.
+    assert_eq!(names(r#"[:find [?name ...] :where [?p :foo/name ?name] [?p :foo/nick ?nick] [(= ?name ?nick)]]"#),
+               vec![TypedValue::typed_string("Alice")]);
+
+    // The values can come from different entities: Bob is both a nickname and a name.
+    assert_eq!(names(r#"[:find [?name ...] :where [?p :foo/name ?name] [?q :foo/nick ?nick] [(= ?name ?nick)] :order ?name]"#),
+               vec![TypedValue::typed_string("Alice"), TypedValue::typed_string("Bob")]);
+}
+
+#[test]
+fn test_not_equals_predicate() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    conn.transact(&mut c, r#"[
+        [:db/add "s" :db/ident :foo/name]
+        [:db/add "s" :db/valueType :db.type/string]
+        [:db/add "s" :db/cardinality :db.cardinality/one]
+        [:db/add "t" :db/ident :foo/status]
+        [:db/add "t" :db/valueType :db.type/ref]
+        [:db/add "t" :db/cardinality :db.cardinality/one]
+        [:db/add "u" :db/ident :foo/owner]
+        [:db/add "u" :db/valueType :db.type/string]
+        [:db/add "u" :db/cardinality :db.cardinality/one]
+        [:db/add "o" :db/ident :status/open]
+        [:db/add "x" :db/ident :status/closed]
+    ]"#).expect("transacted schema");
+    conn.transact(&mut c, r#"[
+        [:db/add "a" :foo/name "a"]
+        [:db/add "a" :foo/status :status/open]
+        [:db/add "a" :foo/owner "a"]
+        [:db/add "b" :foo/name "b"]
+        [:db/add "b" :foo/status :status/closed]
+        [:db/add "b" :foo/owner "someone else"]
+        [:db/add "c" :foo/name "c"]
+    ]"#).expect("transacted data");
+
+    let names = |query: &str| -> Vec<Binding> {
+        match conn.q_once(&c, query, None).expect("query succeeded").results {
+            QueryResults::Coll(vals) => vals,
+            r => panic!("Unexpected results {:?}", r),
+        }
+    };
+
+    // The keyword is taken to be the entid of the status...
+    assert_eq!(names(r#"[:find [?name ...] :where [?e :foo/name ?name] [?e :foo/status ?s] [(!= ?s :status/closed)]]"#),
+               vec![TypedValue::typed_string("a")]);
+
+    // ... and two variables can be compared.
+    assert_eq!(names(r#"[:find [?name ...] :where [?e :foo/name ?name] [?e :foo/owner ?owner] [(!= ?name ?owner)]]"#),
+               vec![TypedValue::typed_string("b")]);
+
+    // An entity with no status doesn't match the pattern, so `!=` can't make it a result. Asking
+    // for entities that aren't closed does.
+    assert_eq!(names(r#"[:find [?name ...] :where [?e :foo/name ?name] (not [?e :foo/status :status/closed]) :order ?name]"#),
+               vec![TypedValue::typed_string("a"), TypedValue::typed_string("c")]);
+}
+
+#[test]
+fn test_explain() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    conn.transact(&mut c, r#"[
+        [:db/add "s" :db/ident :foo/name]
+        [:db/add "s" :db/valueType :db.type/string]
+        [:db/add "s" :db/cardinality :db.cardinality/one]
+        [:db/add "s" :db/index true]
+    ]"#).expect("transacted schema");
+
+    let plan = |query: &str| -> (String, Vec<String>) {
+        match conn.explain(&c, query, None).expect("explained") {
+            QueryExplanation::ExecutionPlan { column_bindings, sql, steps, .. } => {
+                assert!(column_bindings.contains_key(&Variable::from_valid_name("?e")));
+                (sql, steps.into_iter().map(|step| step.detail).collect())
+            },
+            e => panic!("expected an execution plan: {:?}", e),
+        }
+    };
+
+    // Looking up an attribute's values uses an index...
+    let (sql, details) = plan(r#"[:find ?e :where [?e :foo/name "Alice"]]"#);
+    assert!(sql.starts_with("SELECT DISTINCT"));
+    assert!(details.iter().any(|d| d.contains("USING") && d.contains("INDEX idx_datoms_")), "{:?}", details);
+
+    // ... but looking for a value in any attribute has to look at every datom.
+    let (_, details) = plan(r#"[:find ?e :where [?e _ 12345]]"#);
+    assert!(details.iter().any(|d| d.starts_with("SCAN")), "{:?}", details);
+
+    // We know better than to ask SQLite about a query that can't match.
+    match conn.explain(&c, r#"[:find ?e :where [?e :foo/nope _]]"#, None).expect("explained") {
+        QueryExplanation::KnownEmpty(EmptyBecause::UnresolvedIdent(_)) => {},
+        e => panic!("expected a known-empty query: {:?}", e),
+    }
+
+    // The explanation prints the bindings, the SQL, and the plan.
+    let printed = conn.explain(&c, r#"[:find ?e :where [?e :foo/name "Alice"]]"#, None).expect("explained").to_string();
+    assert!(printed.contains("?e -> datoms00.e"), "{}", printed);
+    assert!(printed.contains("plan:"), "{}", printed);
+}
+
+#[test]
+fn test_unique_pattern_first() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    let report = conn.transact(&mut c, r#"[
+        [:db/add "s" :db/ident :foo/email]
+        [:db/add "s" :db/valueType :db.type/string]
+        [:db/add "s" :db/cardinality :db.cardinality/one]
+        [:db/add "s" :db/unique :db.unique/identity]
+        [:db/add "t" :db/ident :foo/age]
+        [:db/add "t" :db/valueType :db.type/long]
+        [:db/add "t" :db/cardinality :db.cardinality/one]
+    ]"#).expect("transacted schema");
+    let email = *report.tempids.get("s").unwrap();
+    conn.transact(&mut c, r#"[
+        [:db/add "a" :foo/email "alice@example.com"]
+        [:db/add "a" :foo/age 42]
+        [:db/add "b" :foo/email "bob@example.com"]
+        [:db/add "b" :foo/age 7]
+    ]"#).expect("transacted data");
+
+    // The unique pattern is written last, but it's joined first...
+    let query = r#"[:find ?age . :where [?p :foo/age ?age] [?p :foo/email "bob@example.com"]]"#;
+    match conn.explain(&c, query, None).expect("explained") {
+        QueryExplanation::ExecutionPlan { sql, .. } => {
+            assert!(sql.contains(&format!("`datoms00`.a = {}", email)), "{}", sql);
+        },
+        e => panic!("expected an execution plan: {:?}", e),
+    }
+
+    // ... and the results are just the same as if it were written first.
+    let first = r#"[:find ?age . :where [?p :foo/email "bob@example.com"] [?p :foo/age ?age]]"#;
+    let results = conn.q_once(&c, query, None).expect("query succeeded").results;
+    assert_eq!(results, QueryResults::Scalar(Some(TypedValue::Long(7).into())));
+    assert_eq!(results, conn.q_once(&c, first, None).expect("query succeeded").results);
+}
+
+#[test]
+fn test_str() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    conn.transact(&mut c, r#"[
+        [:db/add "s" :db/ident :foo/first]
+        [:db/add "s" :db/valueType :db.type/string]
+        [:db/add "s" :db/cardinality :db.cardinality/one]
+        [:db/add "t" :db/ident :foo/last]
+        [:db/add "t" :db/valueType :db.type/string]
+        [:db/add "t" :db/cardinality :db.cardinality/one]
+        [:db/add "u" :db/ident :foo/age]
+        [:db/add "u" :db/valueType :db.type/long]
+        [:db/add "u" :db/cardinality :db.cardinality/one]
+    ]"#).expect("transacted schema");
+    conn.transact(&mut c, r#"[
+        [:db/add "a" :foo/first "Alice"]
+        [:db/add "a" :foo/last "Smith"]
+        [:db/add "a" :foo/age 42]
+        [:db/add "b" :foo/first "Bob"]
+        [:db/add "b" :foo/age 7]
+    ]"#).expect("transacted data");
+
+    let strings = |conn: &Conn, query: &str| -> Vec<Binding> {
+        match conn.q_once(&c, query, None).expect("query succeeded").results {
+            QueryResults::Coll(vals) => vals,
+            r => panic!("Unexpected results {:?}", r),
+        }
+    };
+
+    // Two strings, with a constant between them. Bob has no last name, so he doesn't match.
+    assert_eq!(strings(&conn, r#"[:find [?name ...]
+                                   :where [?p :foo/first ?first]
+                                          [?p :foo/last ?last]
+                                          [(str ?first " " ?last) ?name]]"#),
+               vec![TypedValue::typed_string("Alice Smith")]);
+
+    // A string and a long.
+    assert_eq!(strings(&conn, r#"[:find [?s ...]
+                                   :where [?p :foo/first ?first]
+                                          [?p :foo/age ?age]
+                                          [(str ?first ": " ?age) ?s]
+                                   :order ?first]"#),
+               vec![TypedValue::typed_string("Alice: 42"), TypedValue::typed_string("Bob: 7")]);
+
+    // Constants are stringified just like the values of variables.
+    assert_eq!(strings(&conn, r#"[:find [?s ...]
+                                   :where [?p :foo/first "Bob"]
+                                          [(str :foo/bar 5 true #inst "2017-01-01T11:00:00.123Z") ?s]]"#),
+               vec![TypedValue::typed_string(":foo/bar5true2017-01-01T11:00:00.123Z")]);
+
+    // The result can't be used by anything but the projection.
+    match conn.q_once(&c, r#"[:find ?p
+                              :where [?p :foo/first ?first]
+                                     [(str ?first) ?name]
+                                     [?p :foo/last ?name]]"#, None) {
+        Err(Error(ErrorKind::QueryError(mentat_query_algebrizer::ErrorKind::ComputedCanOnlyBeProjected(var)), _)) => {
+            assert_eq!(var, PlainSymbol::new("?name"));
+        },
+        r => panic!("Expected query to fail: {:?}", r),
+    }
+
+    // Each variable's type must be known.
+    match conn.q_once(&c, r#"[:find ?s :where [?p _ ?v] [(str ?v) ?s]]"#, None) {
+        Err(Error(ErrorKind::QueryError(mentat_query_algebrizer::ErrorKind::UntypedOperand(_, var)), _)) => {
+            assert_eq!(var, PlainSymbol::new("?v"));
+        },
+        r => panic!("Expected query to fail: {:?}", r),
+    }
+}
+
+#[test]
+fn test_arithmetic() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    conn.transact(&mut c, r#"[
+        [:db/add "s" :db/ident :foo/name]
+        [:db/add "s" :db/valueType :db.type/string]
+        [:db/add "s" :db/cardinality :db.cardinality/one]
+        [:db/add "t" :db/ident :foo/price]
+        [:db/add "t" :db/valueType :db.type/long]
+        [:db/add "t" :db/cardinality :db.cardinality/one]
+        [:db/add "u" :db/ident :foo/tax]
+        [:db/add "u" :db/valueType :db.type/long]
+        [:db/add "u" :db/cardinality :db.cardinality/one]
+        [:db/add "v" :db/ident :foo/rate]
+        [:db/add "v" :db/valueType :db.type/double]
+        [:db/add "v" :db/cardinality :db.cardinality/one]
+    ]"#).expect("transacted schema");
+    conn.transact(&mut c, r#"[
+        [:db/add "a" :foo/name "apple"]
+        [:db/add "a" :foo/price 10]
+        [:db/add "a" :foo/tax 2]
+        [:db/add "a" :foo/rate 1.5]
+        [:db/add "b" :foo/name "banana"]
+        [:db/add "b" :foo/price 7]
+        [:db/add "b" :foo/tax 0]
+        [:db/add "b" :foo/rate 0.5]
+    ]"#).expect("transacted data");
+
+    let values = |conn: &Conn, query: &str| -> Vec<Binding> {
+        match conn.q_once(&c, query, None).expect("query succeeded").results {
+            QueryResults::Coll(vals) => vals,
+            r => panic!("Unexpected results {:?}", r),
+        }
+    };
+
+    // Two longs make a long.
+    assert_eq!(values(&conn, r#"[:find [?total ...]
+                                  :where [?p :foo/name ?name]
+                                         [?p :foo/price ?price]
+                                         [?p :foo/tax ?tax]
+                                         [(+ ?price ?tax) ?total]
+                                  :order ?name]"#),
+               vec![TypedValue::Long(12), TypedValue::Long(7)]);
+
+    // A long and a double make a double.
+    assert_eq!(values(&conn, r#"[:find [?cost ...]
+                                  :where [?p :foo/name ?name]
+                                         [?p :foo/price ?price]
+                                         [?p :foo/rate ?rate]
+                                         [(* ?price ?rate) ?cost]
+                                  :order ?name]"#),
+               vec![TypedValue::from(15f64), TypedValue::from(3.5f64)]);
+
+    // Dividing longs truncates; dividing by a double doesn't.
+    assert_eq!(values(&conn, r#"[:find [?q ...] :where [?p :foo/price 7] [?p :foo/price ?price] [(/ ?price 2) ?q]]"#),
+               vec![TypedValue::Long(3)]);
+    assert_eq!(values(&conn, r#"[:find [?q ...] :where [?p :foo/price 7] [?p :foo/price ?price] [(/ ?price 2.0) ?q]]"#),
+               vec![TypedValue::from(3.5f64)]);
+
+    // Rows in which the divisor is zero are excluded.
+    assert_eq!(values(&conn, r#"[:find [?q ...]
+                                  :where [?p :foo/price ?price]
+                                         [?p :foo/tax ?tax]
+                                         [(/ ?price ?tax) ?q]]"#),
+               vec![TypedValue::Long(5)]);
+    match conn.explain(&c, r#"[:find ?q :where [?p :foo/price ?price] [(/ ?price 0) ?q]]"#, None).expect("explained") {
+        QueryExplanation::KnownEmpty(EmptyBecause::DivisionByZero) => {},
+        e => panic!("expected a known-empty query: {:?}", e),
+    }
+
+    // Strings aren't numbers.
+    match conn.explain(&c, r#"[:find ?x :where [?p :foo/name ?name] [?p :foo/price ?price] [(+ ?name ?price) ?x]]"#, None).expect("explained") {
+        QueryExplanation::KnownEmpty(EmptyBecause::TypeMismatch { var, .. }) => {
+            assert_eq!(var, Variable::from_valid_name("?name"));
+        },
+        e => panic!("expected a known-empty query: {:?}", e),
+    }
+}
+
+#[test]
+fn test_instant_functions() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    conn.transact(&mut c, r#"[
+        [:db/add "s" :db/ident :foo/name]
+        [:db/add "s" :db/valueType :db.type/string]
+        [:db/add "s" :db/cardinality :db.cardinality/one]
+        [:db/add "t" :db/ident :foo/at]
+        [:db/add "t" :db/valueType :db.type/instant]
+        [:db/add "t" :db/cardinality :db.cardinality/one]
+    ]"#).expect("transacted schema");
+    conn.transact(&mut c, r#"[
+        [:db/add "a" :foo/name "a"]
+        [:db/add "a" :foo/at #inst "2017-03-12T13:45:10.123Z"]
+        [:db/add "b" :foo/name "b"]
+        [:db/add "b" :foo/at #inst "1969-12-31T23:59:59.500Z"]
+    ]"#).expect("transacted data");
+
+    let instant = |s: &str| TypedValue::Instant(s.parse::<DateTime<Utc>>().expect("valid instant"));
+    let at = |query: &str| -> TypedValue {
+        conn.q_once(&c, query, None)
+            .expect("query succeeded")
+            .into_scalar_value()
+            .expect("scalar")
+            .expect("a result")
+    };
+
+    let truncated = |name: &str, unit: &str| {
+        at(format!(r#"[:find ?d . :where [?p :foo/name "{}"] [?p :foo/at ?t] [(truncate-instant ?t {}) ?d]]"#,
+                   name, unit).as_str())
+    };
+    assert_eq!(truncated("a", ":minute"), instant("2017-03-12T13:45:00Z"));
+    assert_eq!(truncated("a", ":hour"), instant("2017-03-12T13:00:00Z"));
+    assert_eq!(truncated("a", ":day"), instant("2017-03-12T00:00:00Z"));
+    assert_eq!(truncated("a", ":month"), instant("2017-03-01T00:00:00Z"));
+
+    // Before the epoch, we still truncate towards the past.
+    assert_eq!(truncated("b", ":day"), instant("1969-12-31T00:00:00Z"));
+
+    assert_eq!(at(r#"[:find ?later . :where [?p :foo/name "a"] [?p :foo/at ?t] [(plus-instant ?t 3600000) ?later]]"#),
+               instant("2017-03-12T14:45:10.123Z"));
+    assert_eq!(at(r#"[:find ?earlier . :where [?p :foo/name "b"] [?p :foo/at ?t] [(plus-instant ?t -500) ?earlier]]"#),
+               instant("1969-12-31T23:59:59Z"));
+
+    // Units are checked while algebrizing…
+    assert!(conn.q_once(&c, r#"[:find ?d :where [_ :foo/at ?t] [(truncate-instant ?t :week) ?d]]"#, None).is_err());
+    assert!(conn.q_once(&c, r#"[:find ?d :where [_ :foo/at ?t] [(truncate-instant ?t :foo/day) ?d]]"#, None).is_err());
+
+    // … and so are types.
+    match conn.explain(&c, r#"[:find ?d :where [_ :foo/name ?n] [(truncate-instant ?n :day) ?d]]"#, None).expect("explained") {
+        QueryExplanation::KnownEmpty(EmptyBecause::TypeMismatch { var, .. }) => {
+            assert_eq!(var, Variable::from_valid_name("?n"));
+        },
+        e => panic!("expected a known-empty query: {:?}", e),
+    }
+    assert!(conn.q_once(&c, r#"[:find ?d :where [_ :foo/at ?t] [(plus-instant ?t 1.5) ?d]]"#, None).is_err());
+}
+
+#[test]
+fn test_named_sources() {
+    let schema = r#"[
+        [:db/add "n" :db/ident :foo/name]
+        [:db/add "n" :db/valueType :db.type/string]
+        [:db/add "n" :db/cardinality :db.cardinality/one]
+        [:db/add "a" :db/ident :foo/age]
+        [:db/add "a" :db/valueType :db.type/long]
+        [:db/add "a" :db/cardinality :db.cardinality/one]
+    ]"#;
+
+    // A second store, with the same schema, in a file that we can attach.
+    let path = std::env::temp_dir().join("mentat-test-named-sources.db");
+    let _ = std::fs::remove_file(&path);
+    {
+        let mut other = new_connection(&path).expect("Couldn't open other conn.");
+        let mut conn = Conn::connect(&mut other).expect("Couldn't open other DB.");
+        conn.transact(&mut other, schema).expect("transacted schema");
+        conn.transact(&mut other, r#"[
+            [:db/add "x" :foo/name "Alice"]
+            [:db/add "x" :foo/age 30]
+            [:db/add "y" :foo/name "Carol"]
+            [:db/add "y" :foo/age 50]
+        ]"#).expect("transacted data");
+    }
+
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    conn.transact(&mut c, schema).expect("transacted schema");
+    conn.transact(&mut c, r#"[
+        [:db/add "x" :foo/name "Alice"]
+        [:db/add "x" :foo/age 31]
+        [:db/add "y" :foo/name "Bob"]
+        [:db/add "y" :foo/age 40]
+    ]"#).expect("transacted data");
+    c.execute("ATTACH DATABASE ? AS db2", &[&path.to_str().expect("path")]).expect("attached");
+
+    let names = |conn: &Conn, query: &str| -> Vec<Binding> {
+        match conn.q_once(&c, query, None).expect("query succeeded").results {
+            QueryResults::Coll(vals) => vals,
+            r => panic!("Unexpected results {:?}", r),
+        }
+    };
+
+    // Each source on its own.
+    assert_eq!(names(&conn, r#"[:find [?name ...] :where [_ :foo/name ?name] :order ?name]"#),
+               vec![TypedValue::typed_string("Alice"), TypedValue::typed_string("Bob")]);
+    assert_eq!(names(&conn, r#"[:find [?name ...] :in $db2 :where [$db2 _ :foo/name ?name] :order ?name]"#),
+               vec![TypedValue::typed_string("Alice"), TypedValue::typed_string("Carol")]);
+
+    // A join across the two: the people in both, with each of their ages.
+    let results = conn.q_once(&c, r#"[:find ?name ?here ?there
+                                      :in $ $db2
+                                      :where [?p :foo/name ?name]
+                                             [?p :foo/age ?here]
+                                             [$db2 ?q :foo/name ?name]
+                                             [$db2 ?q :foo/age ?there]]"#, None)
+                      .expect("query succeeded")
+                      .into_rel()
+                      .expect("rel results");
+    assert_eq!(results, vec![vec![Binding::Scalar(TypedValue::typed_string("Alice")),
+                                  Binding::Scalar(TypedValue::Long(31)),
+                                  Binding::Scalar(TypedValue::Long(30))]]);
+
+    // Undeclared sources are an error.
+    assert!(conn.q_once(&c, r#"[:find ?x :where [$db3 ?x :foo/name _]]"#, None).is_err());
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_read_snapshot() {
+    // Two connections to the same file: one that reads, and one that writes.
+    let path = std::env::temp_dir().join("mentat-test-read-snapshot.db");
+    let _ = std::fs::remove_file(&path);
+    let mut w = new_connection(&path).expect("Couldn't open writer.");
+    let mut r = new_connection(&path).expect("Couldn't open reader.");
+    let mut conn = Conn::connect(&mut w).expect("Couldn't open DB.");
+    conn.transact(&mut w, r#"[
+        [:db/add "n" :db/ident :foo/name]
+        [:db/add "n" :db/valueType :db.type/string]
+        [:db/add "n" :db/cardinality :db.cardinality/one]
+    ]"#).expect("transacted schema");
+    conn.transact(&mut w, r#"[[:db/add "a" :foo/name "Alice"]]"#).expect("transacted Alice");
+
+    let query = r#"[:find [?name ...] :where [_ :foo/name ?name] :order ?name]"#;
+    let names = |results: QueryResults| -> Vec<Binding> {
+        match results {
+            QueryResults::Coll(vals) => vals,
+            r => panic!("Unexpected results {:?}", r),
+        }
+    };
+    let alice = vec![Binding::Scalar(TypedValue::typed_string("Alice"))];
+    let alice_and_bob = vec![Binding::Scalar(TypedValue::typed_string("Alice")),
+                             Binding::Scalar(TypedValue::typed_string("Bob"))];
+
+    {
+        let snapshot = conn.begin_read(&mut r).expect("began read");
+        assert_eq!(names(snapshot.q_once(query, None).expect("query succeeded").results), alice);
+
+        // A write committed while the snapshot is open is invisible within it.
+        conn.transact(&mut w, r#"[[:db/add "b" :foo/name "Bob"]]"#).expect("transacted Bob");
+        assert_eq!(names(snapshot.q_once(query, None).expect("query succeeded").results), alice);
+
+        // But a fresh query sees it.
+        assert_eq!(names(conn.q_once(&w, query, None).expect("query succeeded").results), alice_and_bob);
+    }
+
+    // Once the snapshot is dropped, the reader sees the write, too.
+    assert_eq!(names(conn.q_once(&r, query, None).expect("query succeeded").results), alice_and_bob);
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn test_in_collection() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    conn.transact(&mut c, r#"[
+        [:db/add "n" :db/ident :foo/name]
+        [:db/add "n" :db/valueType :db.type/string]
+        [:db/add "n" :db/cardinality :db.cardinality/one]
+    ]"#).expect("transacted schema");
+    let report = conn.transact(&mut c, r#"[
+        [:db/add "a" :foo/name "Alice"]
+        [:db/add "b" :foo/name "Bob"]
+        [:db/add "c" :foo/name "Carol"]
+    ]"#).expect("transacted data");
+    let a = *report.tempids.get("a").expect("a");
+    let c_ = *report.tempids.get("c").expect("c");
+
+    let query = r#"[:find [?name ...] :in [?e ...] :where [?e :foo/name ?name] :order ?name]"#;
+    let e = Variable::from_valid_name("?e");
+
+    // A list of refs. A long for an entity is taken to be an entid, just as in `ground`.
+    let inputs = QueryInputs::default().with_collection(e.clone(), vec![TypedValue::Ref(a), TypedValue::Long(c_)]);
+    let results = conn.q_once(&c, query, inputs).expect("query succeeded").results;
+    assert_eq!(results, QueryResults::Coll(vec![Binding::Scalar(TypedValue::typed_string("Alice")),
+                                                Binding::Scalar(TypedValue::typed_string("Carol"))]));
+
+    // An element that can't be a ref is an error.
+    let inputs = QueryInputs::default().with_collection(e.clone(), vec![TypedValue::Ref(a), TypedValue::typed_string("Bob")]);
+    match conn.q_once(&c, query, inputs) {
+        Err(Error(ErrorKind::QueryError(mentat_query_algebrizer::ErrorKind::InputTypeMismatch(var, expected, provided)), _)) => {
+            assert_eq!(var, PlainSymbol::new("?e"));
+            assert_eq!(expected, ValueTypeSet::of_one(ValueType::Ref));
+            assert_eq!(provided, ValueType::String);
+        },
+        x => panic!("Got unexpected results {:?}", x),
+    }
+
+    // An empty list matches nothing.
+    let inputs = QueryInputs::default().with_collection(e.clone(), vec![]);
+    let results = conn.q_once(&c, query, inputs).expect("query succeeded").results;
+    assert_eq!(results, QueryResults::Coll(vec![]));
+
+    // As does a list of values that name no entity.
+    let inputs = QueryInputs::default().with_collection(e.clone(), vec![TypedValue::Ref(a + 1000)]);
+    let results = conn.q_once(&c, query, inputs).expect("query succeeded").results;
+    assert_eq!(results, QueryResults::Coll(vec![]));
+}
+
+#[test]
+fn test_in_relation() {
+    let mut c = new_connection("").expect("Couldn't open conn.");
+    let mut conn = Conn::connect(&mut c).expect("Couldn't open DB.");
+    conn.transact(&mut c, r#"[
+        [:db/add "n" :db/ident :foo/name]
+        [:db/add "n" :db/valueType :db.type/string]
+        [:db/add "n" :db/cardinality :db.cardinality/one]
+        [:db/add "a" :db/ident :foo/age]
+        [:db/add "a" :db/valueType :db.type/long]
+        [:db/add "a" :db/cardinality :db.cardinality/one]
+    ]"#).expect("transacted schema");
+    let report = conn.transact(&mut c, r#"[
+        [:db/add "a" :foo/name "Alice"]
+        [:db/add "a" :foo/age 30]
+        [:db/add "b" :foo/name "Bob"]
+        [:db/add "b" :foo/age 40]
+        [:db/add "c" :foo/name "Carol"]
+        [:db/add "c" :foo/age 50]
+    ]"#).expect("transacted data");
+    let a = *report.tempids.get("a").expect("a");
+    let b = *report.tempids.get("b").expect("b");
+    let c_ = *report.tempids.get("c").expect("c");
+
+    let query = r#"[:find ?name ?age
+                    :in [[?e ?name]]
+                    :where [?e :foo/name ?name]
+                           [?e :foo/age ?age]
+                    :order ?name]"#;
+    let vars = vec![Variable::from_valid_name("?e"), Variable::from_valid_name("?name")];
+
+    // Only the pairs that agree with the store match.
+    let rows = vec![vec![TypedValue::Ref(a), TypedValue::typed_string("Alice")],
+                    vec![TypedValue::Ref(b), TypedValue::typed_string("Robert")],
+                    vec![TypedValue::Long(c_), TypedValue::typed_string("Carol")]];
+    let inputs = QueryInputs::default().with_relation(vars.clone(), rows);
+    let results = conn.q_once(&c, query, inputs).expect("query succeeded").into_rel().expect("rel");
+    assert_eq!(results, vec![vec![Binding::Scalar(TypedValue::typed_string("Alice")), Binding::Scalar(TypedValue::Long(30))],
+                             vec![Binding::Scalar(TypedValue::typed_string("Carol")), Binding::Scalar(TypedValue::Long(50))]]);
+
+    // Each column must have the type of its variable.
+    let rows = vec![vec![TypedValue::Ref(a), TypedValue::typed_string("Alice")],
+                    vec![TypedValue::Ref(b), TypedValue::Long(40)]];
+    let inputs = QueryInputs::default().with_relation(vars.clone(), rows);
+    match conn.q_once(&c, query, inputs) {
+        Err(Error(ErrorKind::QueryError(mentat_query_algebrizer::ErrorKind::InputTypeMismatch(var, expected, provided)), _)) => {
+            assert_eq!(var, PlainSymbol::new("?name"));
+            assert_eq!(expected, ValueTypeSet::of_one(ValueType::String));
+            assert_eq!(provided, ValueType::Long);
+        },
+        x => panic!("Got unexpected results {:?}", x),
+    }
+
+    // Every row must have a value for each variable.
+    let rows = vec![vec![TypedValue::Ref(a), TypedValue::typed_string("Alice")],
+                    vec![TypedValue::Ref(b)]];
+    let inputs = QueryInputs::default().with_relation(vars.clone(), rows);
+    match conn.q_once(&c, query, inputs) {
+        Err(Error(ErrorKind::QueryError(mentat_query_algebrizer::ErrorKind::InputRowLengthMismatch(2, 1)), _)) => {},
+        x => panic!("Got unexpected results {:?}", x),
+    }
+}
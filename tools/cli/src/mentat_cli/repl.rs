@@ -12,7 +12,10 @@ use std::collections::HashMap;
 use std::process;
 
 use mentat::query::QueryResults;
-use mentat_core::TypedValue;
+use mentat_core::{
+    Binding,
+    TypedValue,
+};
 
 use command_parser::{
     Command, 
@@ -172,22 +175,22 @@ impl Repl {
         let mut output:String = String::new();
         match results {
             QueryResults::Scalar(Some(val)) => { 
-                output.push_str(&self.typed_value_as_string(val) ); 
+                output.push_str(&self.binding_as_string(val) ); 
             },
             QueryResults::Tuple(Some(vals)) => { 
                 for val in vals {
-                    output.push_str(&format!("{}\t", self.typed_value_as_string(val)));
+                    output.push_str(&format!("{}\t", self.binding_as_string(val)));
                 }
             },
             QueryResults::Coll(vv) => { 
                 for val in vv {
-                    output.push_str(&format!("{}\n", self.typed_value_as_string(val)));
+                    output.push_str(&format!("{}\n", self.binding_as_string(val)));
                 }
             },
             QueryResults::Rel(vvv) => { 
                 for vv in vvv {
                     for v in vv {
-                        output.push_str(&format!("{}\t", self.typed_value_as_string(v)));
+                        output.push_str(&format!("{}\t", self.binding_as_string(v)));
                     }
                     output.push_str("\n");
                 }
@@ -204,6 +207,20 @@ impl Repl {
         }
     }
 
+    fn binding_as_string(&self, value: Binding) -> String {
+        match value {
+            Binding::Scalar(v) => self.typed_value_as_string(v),
+            Binding::Vec(vs) => {
+                let values: Vec<String> = vs.into_iter().map(|v| self.binding_as_string(v)).collect();
+                format!("[{}]", values.join(" "))
+            },
+            Binding::Map(m) => {
+                let pairs: Vec<String> = m.0.into_iter().map(|(k, v)| format!("{} {}", k, self.binding_as_string(v))).collect();
+                format!("{{{}}}", pairs.join(", "))
+            },
+        }
+    }
+
     fn typed_value_as_string(&self, value: TypedValue) -> String {
         match value {
             TypedValue::Boolean(b) => if b { "true".to_string() } else { "false".to_string() },
@@ -214,6 +231,8 @@ impl Repl {
             TypedValue::Ref(r) => format!("{}", r),
             TypedValue::String(s) => format!("{:?}", s.to_string()),
             TypedValue::Uuid(u) => format!("{}", u),
+            TypedValue::BigInteger(b) => format!("{}N", b),
+            TypedValue::Bytes(b) => format!("{}", edn::Value::Bytes(b)),
         }
     }
 }
@@ -55,7 +55,7 @@ impl Store {
     }
 
     pub fn query(&self, query: String) -> Result<QueryResults, cli::Error> {
-        Ok(self.conn.q_once(&self.handle, &query, None)?)
+        Ok(self.conn.q_once(&self.handle, &query, None)?.results)
     }
 
     pub fn transact(&mut self, transaction: String) -> Result<TxReport, cli::Error> {
@@ -140,6 +140,34 @@ def_parser!(Tx, add_or_retract, Entity, {
             }))
 });
 
+def_matches_namespaced_keyword!(Tx, literal_db_fn_cas, "db.fn", "cas");
+
+def_parser!(Tx, cas, Entity, {
+    vector().of_exactly(
+        Tx::literal_db_fn_cas()
+            .with((Tx::entid_or_lookup_ref_or_temp_id(),
+                   Tx::forward_entid(),
+                   Tx::atom(),
+                   Tx::atom()))
+            .map(|(e, a, old, new)| {
+                Entity::Cas {
+                    e: e,
+                    a: a,
+                    old: old.clone(),
+                    new: new.clone(),
+                }
+            }))
+});
+
+def_matches_namespaced_keyword!(Tx, literal_db_fn_retract_entity, "db.fn", "retractEntity");
+
+def_parser!(Tx, retract_entity, Entity, {
+    vector().of_exactly(
+        Tx::literal_db_fn_retract_entity()
+            .with(Tx::entid_or_lookup_ref_or_temp_id())
+            .map(|e| Entity::RetractEntity { e: e }))
+});
+
 def_parser!(Tx, map_notation, MapNotation, {
     map()
         .of_exactly(many((Tx::entid(), Tx::atom_or_lookup_ref_or_vector())))
@@ -149,7 +177,10 @@ def_parser!(Tx, map_notation, MapNotation, {
 });
 
 def_parser!(Tx, entity, Entity, {
-    Tx::add_or_retract()
+    // `add_or_retract` commits as soon as it sees a vector, so we need to be able to back out.
+    try(Tx::add_or_retract())
+        .or(try(Tx::cas()))
+        .or(Tx::retract_entity())
         .or(Tx::map_notation().map(Entity::MapNotation))
 });
 
@@ -340,4 +371,40 @@ mod tests {
         assert_eq!(result,
                    Ok(Entity::MapNotation(expected)));
     }
+
+    #[test]
+    fn test_cas() {
+        let input = Value::Vector(vec![kw("db.fn", "cas"),
+                                       Value::Integer(101),
+                                       kw("test", "a"),
+                                       Value::Nil,
+                                       Value::Text("v".into())]);
+
+        let input = input.with_spans();
+        let stream = input.atom_stream();
+        let result = Tx::entity().parse(stream).map(|x| x.0);
+
+        assert_eq!(result,
+                   Ok(Entity::Cas {
+                       e: EntidOrLookupRefOrTempId::Entid(Entid::Entid(101)),
+                       a: Entid::Ident(NamespacedKeyword::new("test", "a")),
+                       old: ValueAndSpan::new(SpannedValue::Nil, Span(24, 27)),
+                       new: ValueAndSpan::new(SpannedValue::Text("v".into()), Span(28, 31)),
+                   }));
+    }
+
+    #[test]
+    fn test_retract_entity() {
+        let input = Value::Vector(vec![kw("db.fn", "retractEntity"),
+                                       kw("test", "entid")]);
+
+        let input = input.with_spans();
+        let stream = input.atom_stream();
+        let result = Tx::entity().parse(stream).map(|x| x.0);
+
+        assert_eq!(result,
+                   Ok(Entity::RetractEntity {
+                       e: EntidOrLookupRefOrTempId::Entid(Entid::Ident(NamespacedKeyword::new("test", "entid"))),
+                   }));
+    }
 }
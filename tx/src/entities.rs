@@ -121,4 +121,15 @@ pub enum Entity {
     },
     // Like {:db/id "tempid" a1 v1 a2 v2}.
     MapNotation(MapNotation),
+    // Like [:db.fn/cas e a old new].  `old` is nil if `a` must currently have no value.
+    Cas {
+        e: EntidOrLookupRefOrTempId,
+        a: Entid,
+        old: edn::ValueAndSpan,
+        new: edn::ValueAndSpan,
+    },
+    // Like [:db.fn/retractEntity e].
+    RetractEntity {
+        e: EntidOrLookupRefOrTempId,
+    },
 }